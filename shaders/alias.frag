@@ -8,6 +8,7 @@ layout(set = 1, binding = 1) uniform sampler u_diffuse_sampler;
 
 // set 2: per-texture chain
 layout(set = 2, binding = 0) uniform texture2D u_diffuse_texture;
+layout(set = 2, binding = 1) uniform texture2D u_fullbright_texture;
 
 layout(location = 0) out vec4 diffuse_attachment;
 layout(location = 1) out vec4 normal_attachment;
@@ -19,9 +20,16 @@ void main() {
     f_diffuse
   );
 
-  // TODO: get ambient light from uniform
-  light_attachment = vec4(0.25);
+  float fullbright = texture(
+    sampler2D(u_fullbright_texture, u_diffuse_sampler),
+    f_diffuse
+  ).r;
+
+  // TODO: get ambient light from uniform. until then, models are drawn at
+  // their texture color (see UNLIT in brush.frag)
+  light_attachment = vec4(0.125);
 
-  // rescale normal to [0, 1]
-  normal_attachment = vec4(f_normal / 2.0 + 0.5, 1.0);
+  // rescale normal to [0, 1], marking fullbright pixels with an alpha of 0
+  float lit = fullbright != 0.0 ? 0.0 : 1.0;
+  normal_attachment = vec4(f_normal / 2.0 + 0.5, lit);
 }
@@ -1,13 +1,15 @@
 #version 450
 
+// position in the current and previous keyframes
 layout(location = 0) in vec3 a_position1;
-// layout(location = 1) in vec3 a_position2;
+layout(location = 1) in vec3 a_position2;
 layout(location = 2) in vec3 a_normal;
 layout(location = 3) in vec2 a_diffuse;
 
 layout(push_constant) uniform PushConstants {
   mat4 transform;
   mat4 model_view;
+  float blend;
 } push_constants;
 
 layout(location = 0) out vec3 f_normal;
@@ -21,5 +23,6 @@ vec3 convert(vec3 from) {
 void main() {
   f_normal = mat3(transpose(inverse(push_constants.model_view))) * convert(a_normal);
   f_diffuse = a_diffuse;
-  gl_Position = push_constants.transform * vec4(convert(a_position1), 1.0);
+  vec3 position = mix(a_position2, a_position1, push_constants.blend);
+  gl_Position = push_constants.transform * vec4(convert(position), 1.0);
 }
@@ -5,14 +5,22 @@ const uint TEXTURE_KIND_REGULAR = 0;
 const uint TEXTURE_KIND_WARP = 1;
 const uint TEXTURE_KIND_SKY = 2;
 
-const float WARP_AMPLITUDE = 0.15;
-const float WARP_FREQUENCY = 0.25;
-const float WARP_SCALE = 1.0;
+// turbulence on liquids and teleporters, as in GLQuake's EmitWaterPolys.
+// amplitude is in texels, frequency in radians per texel and speed in radians
+// per second.
+const float WARP_AMPLITUDE = 8.0;
+const float WARP_FREQUENCY = 0.125;
+const float WARP_SPEED = 1.0;
+
+// texture widths per second, as in Quake
+const float SKY_BACK_SPEED = 8.0 / 128.0;
+const float SKY_FRONT_SPEED = 16.0 / 128.0;
 
 layout(location = 0) in vec3 f_normal;
 layout(location = 1) in vec2 f_diffuse; // also used for fullbright
 layout(location = 2) in vec2 f_lightmap;
 flat layout(location = 3) in uvec4 f_lightmap_anim;
+layout(location = 4) in vec3 f_sky_dir;
 
 layout(push_constant) uniform PushConstants {
   layout(offset = 128) uint texture_kind;
@@ -24,7 +32,10 @@ layout(set = 0, binding = 0) uniform FrameUniforms {
     vec4 camera_pos;
     float time;
     bool r_lightmap;
+    bool r_skybox;
 } frame_uniforms;
+layout(set = 0, binding = 1) uniform texture2DArray u_skybox_texture;
+layout(set = 0, binding = 2) uniform sampler u_skybox_sampler;
 
 // set 1: per-entity
 layout(set = 1, binding = 1) uniform sampler u_diffuse_sampler; // also used for fullbright
@@ -37,19 +48,23 @@ layout(set = 2, binding = 2) uniform TextureUniforms {
     uint kind;
 } texture_uniforms;
 
-// set 3: per-face
-layout(set = 3, binding = 0) uniform texture2D u_lightmap_texture[4];
+// set 3: per-face lightmap atlas page, one layer per style
+layout(set = 3, binding = 0) uniform texture2DArray u_lightmap_texture;
 
 layout(location = 0) out vec4 diffuse_attachment;
 layout(location = 1) out vec4 normal_attachment;
 layout(location = 2) out vec4 light_attachment;
 
+// light for surfaces that ignore the lightmap. the deferred pass doubles each
+// style and sums them, so this comes to 1.0, the texture's own color.
+const vec4 UNLIT = vec4(0.125);
+
 vec4 calc_light() {
     vec4 light = vec4(0.0, 0.0, 0.0, 0.0);
     for (int i = 0; i < 4 && f_lightmap_anim[i] != LIGHTMAP_ANIM_END; i++) {
         float map = texture(
-            sampler2D(u_lightmap_texture[i], u_lightmap_sampler),
-            f_lightmap
+            sampler2DArray(u_lightmap_texture, u_lightmap_sampler),
+            vec3(f_lightmap, i)
         ).r;
 
         // range [0, 4]
@@ -60,7 +75,40 @@ vec4 calc_light() {
     return light;
 }
 
+// sample the skybox in `dir`, given in Quake coordinates.
+//
+// the faces are stored as layers in the order rt, lf, bk, ft, up, dn.
+vec4 skybox_color(vec3 dir) {
+    vec3 a = abs(dir);
+
+    // (s, t, depth) on the face that `dir` points at
+    vec3 face;
+    float layer;
+    if (a.x >= a.y && a.x >= a.z) {
+        face = dir.x > 0.0 ? vec3(-dir.y, dir.z, dir.x) : vec3(dir.y, dir.z, -dir.x);
+        layer = dir.x > 0.0 ? 0.0 : 1.0;
+    } else if (a.y >= a.z) {
+        face = dir.y > 0.0 ? vec3(dir.x, dir.z, dir.y) : vec3(-dir.x, dir.z, -dir.y);
+        layer = dir.y > 0.0 ? 2.0 : 3.0;
+    } else {
+        face = dir.z > 0.0 ? vec3(-dir.y, -dir.x, dir.z) : vec3(-dir.y, dir.x, -dir.z);
+        layer = dir.z > 0.0 ? 4.0 : 5.0;
+    }
+
+    // range [-1, 1] to [0, 1], with t pointing down
+    vec2 st = face.st / face.p;
+    vec2 texcoord = vec2(st.s + 1.0, 1.0 - st.t) * 0.5;
+
+    return texture(
+        sampler2DArray(u_skybox_texture, u_skybox_sampler),
+        vec3(texcoord, layer)
+    );
+}
+
 void main() {
+    // fullbright pixels are marked with a normal alpha of 0
+    float lit = 1.0;
+
     switch (push_constants.texture_kind) {
         case TEXTURE_KIND_REGULAR:
             diffuse_attachment = texture(
@@ -74,32 +122,47 @@ void main() {
             ).r;
 
             if (fullbright != 0.0) {
-                light_attachment = vec4(0.25);
+                light_attachment = UNLIT;
+                lit = 0.0;
             } else {
                 light_attachment = calc_light();
             }
             break;
 
         case TEXTURE_KIND_WARP:
-            // note the texcoord transpose here
-            vec2 wave1 = 3.14159265359
-                * (WARP_SCALE * f_diffuse.ts
-                    + WARP_FREQUENCY * frame_uniforms.time);
+            // the warp is computed in texels, so the waves are the same size
+            // whatever the texture resolution
+            vec2 tex_size = vec2(textureSize(
+                sampler2D(u_diffuse_texture, u_diffuse_sampler),
+                0
+            ));
+            vec2 st = f_diffuse * tex_size;
 
-            vec2 warp_texcoord = f_diffuse.st + WARP_AMPLITUDE
-                * vec2(sin(wave1.s), sin(wave1.t));
+            // each axis is displaced by a wave along the other, so note the
+            // texcoord transpose here
+            vec2 wave = sin(WARP_FREQUENCY * st.ts + WARP_SPEED * frame_uniforms.time);
+            vec2 warp_texcoord = (st + WARP_AMPLITUDE * wave) / tex_size;
 
             diffuse_attachment = texture(
                 sampler2D(u_diffuse_texture, u_diffuse_sampler),
                 warp_texcoord
             );
-            light_attachment = vec4(0.25);
+            light_attachment = UNLIT;
             break;
 
         case TEXTURE_KIND_SKY:
-            vec2 base = mod(f_diffuse + frame_uniforms.time, 1.0);
-            vec2 cloud_texcoord = vec2(base.s * 0.5, base.t);
-            vec2 sky_texcoord = vec2(base.s * 0.5 + 0.5, base.t);
+            if (frame_uniforms.r_skybox) {
+                diffuse_attachment = skybox_color(f_sky_dir);
+                light_attachment = UNLIT;
+                break;
+            }
+
+            // the back layer is the right half of the texture and the front
+            // layer is the left half, and they scroll at different speeds
+            vec2 back = mod(f_diffuse + SKY_BACK_SPEED * frame_uniforms.time, 1.0);
+            vec2 front = mod(f_diffuse + SKY_FRONT_SPEED * frame_uniforms.time, 1.0);
+            vec2 sky_texcoord = vec2(back.s * 0.5 + 0.5, back.t);
+            vec2 cloud_texcoord = vec2(front.s * 0.5, front.t);
 
             vec4 sky_color = texture(
                 sampler2D(u_diffuse_texture, u_diffuse_sampler),
@@ -118,7 +181,7 @@ void main() {
                 cloud_factor = 1.0;
             }
             diffuse_attachment = mix(sky_color, cloud_color, cloud_factor);
-            light_attachment = vec4(0.25);
+            light_attachment = UNLIT;
             break;
 
         // not possible
@@ -127,5 +190,5 @@ void main() {
     }
 
     // rescale normal to [0, 1]
-    normal_attachment = vec4(f_normal / 2.0 + 0.5, 1.0);
+    normal_attachment = vec4(f_normal / 2.0 + 0.5, lit);
 }
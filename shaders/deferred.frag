@@ -13,7 +13,7 @@ layout(set = 0, binding = 4) uniform texture2DMS u_depth;
 layout(set = 0, binding = 5) uniform DeferredUniforms {
   mat4 inv_projection;
   uint light_count;
-  uint _pad1;
+  float max_light;
   uvec2 _pad2;
   vec4 lights[MAX_LIGHTS];
 } u_deferred;
@@ -41,10 +41,13 @@ void main() {
   ivec2 texcoord = ivec2(vec2(dims) * a_texcoord);
   vec4 in_color = texelFetch(sampler2DMS(u_diffuse, u_sampler), texcoord, gl_SampleID);
 
+  vec4 normal_sample = texelFetch(sampler2DMS(u_normal, u_sampler), texcoord, gl_SampleID);
+
   // scale from [0, 1] to [-1, 1]
-  vec3 in_normal = 2.0
-    * texelFetch(sampler2DMS(u_normal, u_sampler), texcoord, gl_SampleID).xyz
-    - 1.0;
+  vec3 in_normal = 2.0 * normal_sample.xyz - 1.0;
+
+  // fullbright pixels are stored with a normal alpha of 0
+  bool fullbright = normal_sample.a < 0.5;
 
   // Double to restore overbright values.
   vec4 in_light = 2.0 * texelFetch(sampler2DMS(u_light, u_sampler), texcoord, gl_SampleID);
@@ -55,7 +58,8 @@ void main() {
   vec4 out_color = in_color;
 
   float light = in_light.x + in_light.y + in_light.z + in_light.w;
-  for (uint i = 0; i < u_deferred.light_count && i < MAX_LIGHTS; i++) {
+  uint light_count = fullbright ? 0u : u_deferred.light_count;
+  for (uint i = 0; i < light_count && i < MAX_LIGHTS; i++) {
     vec4 dlight = u_deferred.lights[i];
     vec3 dir = normalize(position - dlight_origin(dlight));
     float dist = abs(distance(dlight_origin(dlight), position));
@@ -67,5 +71,8 @@ void main() {
     }
   }
 
+  // 1.0 clamps to the texture color, 2.0 allows overbright
+  light = min(light, u_deferred.max_light);
+
   color_attachment = vec4(light * out_color.rgb, 1.0);
 }
@@ -24,5 +24,6 @@ void main() {
   }
 
   diffuse_attachment = tex_color;
-  light_attachment = vec4(0.25);
+  // unlit, see UNLIT in brush.frag
+  light_attachment = vec4(0.125);
 }
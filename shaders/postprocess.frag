@@ -8,13 +8,78 @@ layout(set = 0, binding = 0) uniform sampler u_sampler;
 layout(set = 0, binding = 1) uniform texture2DMS u_color;
 layout(set = 0, binding = 2) uniform PostProcessUniforms {
   vec4 color_shift;
+  // 0: nearest, 1: bilinear with contrast-adaptive sharpening; only used
+  // when upscaling, downscaling always averages
+  uint upscale_filter;
+  float sharpness;
 } postprocess_uniforms;
 
+vec4 fetch(ivec2 texcoord) {
+  ivec2 dims = textureSize(sampler2DMS(u_color, u_sampler));
+  texcoord = clamp(texcoord, ivec2(0), dims - 1);
+  return texelFetch(sampler2DMS(u_color, u_sampler), texcoord, gl_SampleID);
+}
+
+// multisampled textures can't be filtered by a sampler
+vec4 bilinear(vec2 pos) {
+  pos -= 0.5;
+  ivec2 i = ivec2(floor(pos));
+  vec2 f = fract(pos);
+  vec4 top = mix(fetch(i), fetch(i + ivec2(1, 0)), f.x);
+  vec4 bottom = mix(fetch(i + ivec2(0, 1)), fetch(i + ivec2(1, 1)), f.x);
+  return mix(top, bottom, f.y);
+}
+
+// bilinear upscale followed by contrast-adaptive sharpening, which restores
+// edges softened by the filter without ringing in high-contrast areas
+vec4 sharp_upscale(vec2 pos) {
+  vec4 c = bilinear(pos);
+  vec4 n = bilinear(pos + vec2(0.0, -1.0));
+  vec4 s = bilinear(pos + vec2(0.0, 1.0));
+  vec4 w = bilinear(pos + vec2(-1.0, 0.0));
+  vec4 e = bilinear(pos + vec2(1.0, 0.0));
+
+  vec3 mn = min(c.rgb, min(min(n.rgb, s.rgb), min(w.rgb, e.rgb)));
+  vec3 mx = max(c.rgb, max(max(n.rgb, s.rgb), max(w.rgb, e.rgb)));
+  vec3 amp = sqrt(clamp(min(mn, 2.0 - mx) / max(mx, 1.0 / 65536.0), 0.0, 1.0));
+  vec3 weight = amp * mix(-0.125, -0.2, postprocess_uniforms.sharpness);
+
+  vec3 rgb = (c.rgb + (n.rgb + s.rgb + w.rgb + e.rgb) * weight) / (1.0 + 4.0 * weight);
+  return vec4(clamp(rgb, 0.0, 1.0), c.a);
+}
+
+// average the source texels under an output pixel covering `footprint` texels,
+// so views rendered above the window size (r_scale > 1) don't alias
+vec4 box_downsample(vec2 pos, vec2 footprint) {
+  ivec2 first = ivec2(floor(pos - 0.5 * footprint));
+  ivec2 last = min(ivec2(ceil(pos + 0.5 * footprint)) - 1, first + 3);
+
+  vec4 sum = vec4(0.0);
+  for (int y = first.y; y <= last.y; y++) {
+    for (int x = first.x; x <= last.x; x++) {
+      sum += fetch(ivec2(x, y));
+    }
+  }
+
+  ivec2 count = last - first + 1;
+  return sum / float(count.x * count.y);
+}
+
 void main() {
   ivec2 dims = textureSize(sampler2DMS(u_color, u_sampler));
-  ivec2 texcoord = ivec2(vec2(dims) * a_texcoord);
+  vec2 pos = vec2(dims) * a_texcoord;
+
+  // source texels per output pixel
+  vec2 footprint = abs(vec2(dFdx(pos.x), dFdy(pos.y)));
 
-  vec4 in_color = texelFetch(sampler2DMS(u_color, u_sampler), texcoord, gl_SampleID);
+  vec4 in_color;
+  if (footprint.x > 1.001 || footprint.y > 1.001) {
+    in_color = box_downsample(pos, footprint);
+  } else if (postprocess_uniforms.upscale_filter == 1) {
+    in_color = sharp_upscale(pos);
+  } else {
+    in_color = fetch(ivec2(pos));
+  }
 
   float src_factor = postprocess_uniforms.color_shift.a;
   float dst_factor = 1.0 - src_factor;
@@ -14,9 +14,17 @@ layout(location = 1) out vec4 normal_attachment;
 layout(location = 2) out vec4 light_attachment;
 
 void main() {
-  diffuse_attachment = texture(sampler2D(u_diffuse_texture, u_diffuse_sampler), f_diffuse);
+  vec4 diffuse = texture(sampler2D(u_diffuse_texture, u_diffuse_sampler), f_diffuse);
+
+  // transparent texels are cut out rather than blended
+  if (diffuse.a < 0.5) {
+    discard;
+  }
+
+  diffuse_attachment = diffuse;
 
   // rescale normal to [0, 1]
   normal_attachment = vec4(f_normal / 2.0 + 0.5, 1.0);
-  light_attachment = vec4(1.0, 1.0, 1.0, 1.0);
+  // unlit, see UNLIT in brush.frag
+  light_attachment = vec4(0.125);
 }
@@ -0,0 +1,165 @@
+// Copyright © 2020 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Renders scripted views of a map and compares them against reference images.
+//!
+//! ```text
+//! golden --map maps/e1m1.bsp --script golden/e1m1.txt --reference golden/e1m1
+//! ```
+//!
+//! Pass `--update` to write new reference images instead of comparing. When a
+//! shot doesn't match, the rendered image is written next to its reference as
+//! `<name>.actual.png`. The exit status is nonzero if any shot failed.
+
+extern crate richter;
+
+use std::{fs, path::PathBuf, process::exit};
+
+use richter::{
+    client::render::golden::{parse_script, GoldenImage, GoldenRenderer, Tolerance},
+    common::{self, vfs::Vfs},
+};
+
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+struct Opt {
+    #[structopt(long)]
+    base_dir: Option<PathBuf>,
+
+    /// Load the map from this mod's subdirectory of the base directory
+    #[structopt(long)]
+    game: Option<String>,
+
+    /// The map to render, e.g. maps/e1m1.bsp
+    #[structopt(long)]
+    map: String,
+
+    /// The script listing the camera positions to render
+    #[structopt(long, parse(from_os_str))]
+    script: PathBuf,
+
+    /// The directory holding the reference images
+    #[structopt(long, parse(from_os_str))]
+    reference: PathBuf,
+
+    /// Write new reference images instead of comparing against them
+    #[structopt(long)]
+    update: bool,
+
+    #[structopt(long, default_value = "320")]
+    width: u32,
+
+    #[structopt(long, default_value = "240")]
+    height: u32,
+
+    /// Channel differences up to this value are ignored
+    #[structopt(long, default_value = "8")]
+    channel_tolerance: u8,
+
+    /// The fraction of pixels allowed to differ
+    #[structopt(long, default_value = "0.001")]
+    max_differing: f32,
+}
+
+fn main() {
+    env_logger::init();
+    let opt = Opt::from_args();
+
+    let shots = match fs::read_to_string(&opt.script)
+        .map_err(failure::Error::from)
+        .and_then(|s| parse_script(&s))
+    {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {}", opt.script.display(), e);
+            exit(1);
+        }
+    };
+
+    let base_dir = opt.base_dir.unwrap_or(common::default_base_dir());
+    let vfs = Vfs::with_base_dir(base_dir, opt.game.as_deref());
+    let mut renderer = match GoldenRenderer::new(&vfs, &opt.map, opt.width, opt.height) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Couldn't load {}: {}", opt.map, e);
+            exit(1);
+        }
+    };
+
+    if let Err(e) = fs::create_dir_all(&opt.reference) {
+        eprintln!("Couldn't create {}: {}", opt.reference.display(), e);
+        exit(1);
+    }
+
+    let tolerance = Tolerance {
+        channel: opt.channel_tolerance,
+        max_differing: opt.max_differing,
+    };
+
+    let mut failures = 0;
+    for shot in shots.iter() {
+        let image = renderer.render(shot);
+        let reference_path = opt.reference.join(format!("{}.png", shot.name));
+
+        if opt.update {
+            match image.write_png(&reference_path) {
+                Ok(()) => println!("{}: updated", shot.name),
+                Err(e) => {
+                    println!(
+                        "{}: couldn't write {}: {}",
+                        shot.name,
+                        reference_path.display(),
+                        e
+                    );
+                    failures += 1;
+                }
+            }
+            continue;
+        }
+
+        let reference = match GoldenImage::read_png(&reference_path) {
+            Ok(r) => r,
+            Err(e) => {
+                println!("{}: no reference image ({})", shot.name, e);
+                failures += 1;
+                continue;
+            }
+        };
+
+        let diff = image.compare(&reference, tolerance);
+        if diff.within(tolerance) {
+            println!("{}: ok", shot.name);
+        } else {
+            println!(
+                "{}: FAILED ({} of {} pixels differ, max channel difference {})",
+                shot.name, diff.differing_pixels, diff.total_pixels, diff.max_channel_diff
+            );
+            failures += 1;
+
+            let actual_path = opt.reference.join(format!("{}.actual.png", shot.name));
+            if let Err(e) = image.write_png(&actual_path) {
+                println!("couldn't write {}: {}", actual_path.display(), e);
+            }
+        }
+    }
+
+    if failures > 0 {
+        println!("{} of {} shots failed", failures, shots.len());
+        exit(1);
+    }
+}
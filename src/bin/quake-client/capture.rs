@@ -1,7 +1,7 @@
 use std::{
     cell::RefCell,
-    fs::File,
-    io::BufWriter,
+    fs::{self, File},
+    io::{self, BufWriter},
     num::NonZeroU32,
     path::{Path, PathBuf},
     rc::Rc,
@@ -9,7 +9,7 @@ use std::{
 
 use richter::client::render::Extent2d;
 
-use chrono::Utc;
+use chrono::{Duration, Utc};
 
 const BYTES_PER_PIXEL: u32 = 4;
 
@@ -36,6 +36,98 @@ pub fn cmd_screenshot(
     })
 }
 
+/// A request made by the "capture_start" or "capture_stop" commands.
+pub enum CaptureRequest {
+    Start(PathBuf),
+    Stop,
+}
+
+/// Implements the "capture_start" command.
+///
+/// This function returns a boxed closure which requests that a frame dump be
+/// started in the directory given as an argument.
+pub fn cmd_capture_start(
+    capture_request: Rc<RefCell<Option<CaptureRequest>>>,
+) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| {
+        let dir = match args.len() {
+            0 => PathBuf::from(format!(
+                "richter-capture-{}",
+                Utc::now().format("%FT%H-%M-%S")
+            )),
+            1 => PathBuf::from(args[0]),
+            _ => {
+                log::error!("Usage: capture_start [DIR]");
+                return "Usage: capture_start [DIR]".to_owned();
+            }
+        };
+
+        capture_request.replace(Some(CaptureRequest::Start(dir)));
+        String::new()
+    })
+}
+
+/// Implements the "capture_stop" command.
+pub fn cmd_capture_stop(
+    capture_request: Rc<RefCell<Option<CaptureRequest>>>,
+) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| {
+        capture_request.replace(Some(CaptureRequest::Stop));
+        String::new()
+    })
+}
+
+/// An in-progress frame dump.
+///
+/// While a frame dump is running, the game advances by a fixed `1 / fps`
+/// seconds per frame and every rendered frame is written to a numbered PNG in
+/// `dir`. Game audio is recorded alongside to `audio.wav`.
+pub struct FrameDump {
+    dir: PathBuf,
+    fps: u32,
+    frame: u64,
+}
+
+impl FrameDump {
+    pub fn new<P>(dir: P, fps: u32) -> io::Result<FrameDump>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+
+        Ok(FrameDump {
+            dir,
+            fps: fps.max(1),
+            frame: 0,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn fps(&self) -> u32 {
+        self.fps
+    }
+
+    pub fn audio_path(&self) -> PathBuf {
+        self.dir.join("audio.wav")
+    }
+
+    /// The amount of game time that passes in each frame.
+    pub fn frame_duration(&self) -> Duration {
+        Duration::nanoseconds(1_000_000_000 / self.fps as i64)
+    }
+
+    /// Return the path of the next frame's image and advance the frame count.
+    pub fn next_frame_path(&mut self) -> PathBuf {
+        let path = self.dir.join(format!("frame{:06}.png", self.frame));
+        self.frame += 1;
+        path
+    }
+}
+
 pub struct Capture {
     // size of the capture image
     capture_size: Extent2d,
@@ -0,0 +1,170 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The `-dedicated` server, which runs without a window or a client.
+
+use std::{
+    cell::{Cell, RefCell},
+    io::BufRead,
+    path::PathBuf,
+    process::exit,
+    rc::Rc,
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Instant,
+};
+
+use chrono::Duration;
+use richter::{
+    common::{
+        self,
+        console::{CmdRegistry, Console, CvarRegistry},
+        host,
+        plugin::{Plugins, ServerPlugin},
+        vfs::Vfs,
+    },
+    server::{self, host::ServerHost},
+};
+
+// how long to sleep between server frames
+const FRAME_MILLIS: u64 = 10;
+
+/// Runs a server until the `quit` command is given.
+///
+/// Command-line commands that set cvars run before the server starts
+/// listening, so `-port` and `+hostport` take effect; the rest (such as
+/// `+map`) run once the server is up. Console commands are also read from
+/// standard input.
+///
+/// `plugins` are registered before any commands run, so their cvars can be
+/// set from the command line.
+pub fn run(
+    base_dir: Option<PathBuf>,
+    game: Option<String>,
+    commands: Vec<String>,
+    plugins: Vec<Box<dyn ServerPlugin>>,
+) -> ! {
+    let base_dir = base_dir.unwrap_or_else(common::default_base_dir);
+    let vfs = Rc::new(Vfs::with_base_dir(base_dir, game.as_deref()));
+
+    let con_names = Rc::new(RefCell::new(Vec::new()));
+    let cvars = Rc::new(RefCell::new(CvarRegistry::new(con_names.clone())));
+    let cmds = Rc::new(RefCell::new(CmdRegistry::new(con_names)));
+    let console = Rc::new(RefCell::new(Console::new(cmds.clone(), cvars.clone())));
+
+    server::register_cvars(&cvars.borrow()).unwrap();
+
+    let shared_plugins = Rc::new(RefCell::new(Plugins::new()));
+    for plugin in plugins {
+        shared_plugins
+            .borrow_mut()
+            .add_server(plugin, &mut cmds.borrow_mut(), &cvars.borrow());
+    }
+    cmds.borrow_mut()
+        .insert_or_replace("plugins", host::cmd_plugins(shared_plugins.clone()))
+        .unwrap();
+
+    let quit = Rc::new(Cell::new(false));
+    let cmd_quit = quit.clone();
+    cmds.borrow_mut()
+        .insert_or_replace(
+            "quit",
+            Box::new(move |_| {
+                cmd_quit.set(true);
+                String::new()
+            }),
+        )
+        .unwrap();
+
+    let (settings, commands): (Vec<String>, Vec<String>) =
+        commands.into_iter().partition(|command| {
+            let name = command.split_whitespace().next().unwrap_or("");
+            cvars.borrow().contains(name)
+        });
+    for command in settings.iter() {
+        console.borrow().stuff_text(format!("{}\n", command));
+    }
+    execute(&console);
+
+    let mut host = match ServerHost::new(vfs, cvars, cmds, console.clone(), shared_plugins) {
+        Ok(h) => h,
+        Err(e) => {
+            eprintln!("Couldn't start the server: {}", e);
+            exit(1);
+        }
+    };
+
+    if !commands.iter().any(|c| c.starts_with("map ")) {
+        println!("No map given, use `map <name>` to start one");
+    }
+    for command in commands.iter() {
+        console.borrow().stuff_text(format!("{}\n", command));
+    }
+
+    let stdin = read_stdin();
+    let mut last_frame = Instant::now();
+    while !quit.get() {
+        while let Ok(line) = stdin.try_recv() {
+            console.borrow().stuff_text(format!("{}\n", line));
+        }
+        execute(&console);
+
+        let now = Instant::now();
+        let frame_time = Duration::from_std(now - last_frame).unwrap();
+        last_frame = now;
+        host.frame(frame_time);
+
+        thread::sleep(std::time::Duration::from_millis(FRAME_MILLIS));
+    }
+
+    host.shutdown();
+    exit(0);
+}
+
+// runs the command buffer and prints whatever the commands printed. player
+// names and chat can contain Quake charset glyphs, so they're shown as ASCII.
+fn execute(console: &Rc<RefCell<Console>>) {
+    let console = console.borrow();
+    console.begin_redirect();
+    console.execute();
+    print!(
+        "{}",
+        common::util::quake_string_to_ascii(&console.end_redirect())
+    );
+}
+
+fn read_stdin() -> Receiver<String> {
+    let (send, recv) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => return,
+            };
+
+            if send.send(line).is_err() {
+                return;
+            }
+        }
+    });
+
+    recv
+}
@@ -21,7 +21,9 @@
 use std::{cell::RefCell, path::PathBuf, rc::Rc};
 
 use crate::{
-    capture::{cmd_screenshot, Capture},
+    capture::{
+        cmd_capture_start, cmd_capture_stop, cmd_screenshot, Capture, CaptureRequest, FrameDump,
+    },
     trace::{cmd_trace_begin, cmd_trace_end},
 };
 
@@ -53,6 +55,12 @@ pub struct Game {
 
     // if Some(path), take a screenshot and save it to path
     screenshot_path: Rc<RefCell<Option<PathBuf>>>,
+
+    // set by the capture_start and capture_stop commands
+    capture_request: Rc<RefCell<Option<CaptureRequest>>>,
+
+    // if Some, a frame dump is in progress
+    frame_dump: Option<FrameDump>,
 }
 
 impl Game {
@@ -71,6 +79,15 @@ impl Game {
             .insert("screenshot", cmd_screenshot(screenshot_path.clone()))
             .unwrap();
 
+        // set up frame dumps
+        let capture_request = Rc::new(RefCell::new(None));
+        cmds.borrow_mut()
+            .insert("capture_start", cmd_capture_start(capture_request.clone()))
+            .unwrap();
+        cmds.borrow_mut()
+            .insert("capture_stop", cmd_capture_stop(capture_request.clone()))
+            .unwrap();
+
         // set up frame tracing
         let trace = Rc::new(RefCell::new(None));
         cmds.borrow_mut()
@@ -87,13 +104,64 @@ impl Game {
             client,
             trace,
             screenshot_path,
+            capture_request,
+            frame_dump: None,
         })
     }
 
+    fn start_frame_dump(&mut self, dir: PathBuf) {
+        self.stop_frame_dump();
+
+        let fps = self.cvars.borrow().get_value("capture_fps").unwrap_or(30.0) as u32;
+        let dump = match FrameDump::new(dir, fps) {
+            Ok(d) => d,
+            Err(e) => {
+                log::error!("Failed to start frame dump: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = self
+            .client
+            .start_audio_capture(dump.audio_path(), dump.fps())
+        {
+            log::error!("Failed to start audio capture: {}", e);
+            return;
+        }
+
+        info!(
+            "Capturing at {} fps to {}",
+            dump.fps(),
+            dump.dir().display()
+        );
+        self.frame_dump = Some(dump);
+    }
+
+    fn stop_frame_dump(&mut self) {
+        if self.frame_dump.take().is_some() {
+            if let Err(e) = self.client.stop_audio_capture() {
+                log::error!("Failed to finish audio capture: {}", e);
+            }
+        }
+    }
+
     // advance the simulation
     pub fn frame(&mut self, gfx_state: &GraphicsState, frame_duration: Duration) {
         use ClientError::*;
 
+        let request = self.capture_request.replace(None);
+        match request {
+            Some(CaptureRequest::Start(dir)) => self.start_frame_dump(dir),
+            Some(CaptureRequest::Stop) => self.stop_frame_dump(),
+            None => (),
+        }
+
+        // frame dumps run on a fixed timestep so that audio and video line up
+        let frame_duration = match self.frame_dump {
+            Some(ref dump) => dump.frame_duration(),
+            None => frame_duration,
+        };
+
         match self.client.frame(frame_duration, gfx_state) {
             Ok(()) => (),
             Err(e) => match e {
@@ -110,6 +178,7 @@ impl Game {
                 | Model(_)
                 | Network(_)
                 | Sound(_)
+                | TimedOut
                 | Vfs(_) => {
                     log::error!("{}", e);
                     self.client.disconnect();
@@ -161,8 +230,8 @@ impl Game {
             )
             .unwrap();
 
-        // screenshot setup
-        let capture = self.screenshot_path.borrow().as_ref().map(|_| {
+        // screenshot and frame dump setup
+        let capture = if self.screenshot_path.borrow().is_some() || self.frame_dump.is_some() {
             let cap = Capture::new(gfx_state.device(), Extent2d { width, height });
             cap.copy_from_texture(
                 &mut encoder,
@@ -172,8 +241,10 @@ impl Game {
                     origin: wgpu::Origin3d::ZERO,
                 },
             );
-            cap
-        });
+            Some(cap)
+        } else {
+            None
+        };
 
         // blit to swap chain
         {
@@ -189,6 +260,11 @@ impl Game {
             gfx_state.device().poll(wgpu::Maintain::Wait);
         }
 
+        if gfx_state.stats().enabled() {
+            gfx_state.stats().finish_frame(gfx_state.device());
+            console.println(gfx_state.stats().last_frame().summary());
+        }
+
         // write screenshot if requested and clear screenshot path
         self.screenshot_path.replace(None).map(|path| {
             capture
@@ -196,6 +272,18 @@ impl Game {
                 .unwrap()
                 .write_to_file(gfx_state.device(), path)
         });
+
+        // write this frame of the frame dump, if there is one
+        if let Some(ref mut dump) = self.frame_dump {
+            capture
+                .as_ref()
+                .unwrap()
+                .write_to_file(gfx_state.device(), dump.next_frame_path());
+
+            if let Err(e) = self.client.end_audio_capture_frame() {
+                log::error!("Audio capture failed: {}", e);
+            }
+        }
     }
 }
 
@@ -203,5 +291,10 @@ impl std::ops::Drop for Game {
     fn drop(&mut self) {
         let _ = self.cmds.borrow_mut().remove("trace_begin");
         let _ = self.cmds.borrow_mut().remove("trace_end");
+        let _ = self.cmds.borrow_mut().remove("capture_start");
+        let _ = self.cmds.borrow_mut().remove("capture_stop");
+
+        // make sure the WAV header is written
+        self.stop_frame_dump();
     }
 }
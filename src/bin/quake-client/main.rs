@@ -19,42 +19,52 @@
 // SOFTWARE.
 
 mod capture;
+mod dedicated;
 mod game;
 mod menu;
+mod settings;
 mod trace;
+mod video;
 
 use std::{
-    cell::{Ref, RefCell, RefMut},
+    cell::{Cell, Ref, RefCell, RefMut},
     fs::File,
-    io::{Cursor, Read, Write},
+    io::Write,
     net::SocketAddr,
     path::{Path, PathBuf},
-    process::exit,
     rc::Rc,
 };
 
 use game::Game;
+use video::VideoSettings;
 
 use chrono::Duration;
-use common::net::ServerCmd;
 use richter::{
     client::{
         self,
-        demo::DemoServer,
-        input::{Input, InputFocus},
+        addressbook::AddressBook,
+        browser::ServerBrowser,
+        demo::DemoReader,
+        input::{haptics::GamepadRumble, Input, InputFocus},
         menu::Menu,
-        render::{self, Extent2d, GraphicsState, UiRenderer, DIFFUSE_ATTACHMENT_FORMAT},
+        render::{
+            self, Extent2d, GraphicsState, RendererBackend, TextureCompression, TextureSettings,
+            UiRenderer, WindowSurface,
+        },
         Client,
     },
     common::{
         self,
-        console::{CmdRegistry, Console, CvarRegistry},
+        args::{self, CommandLine},
+        console::{config, CmdRegistry, Console, CvarRegistry},
         host::{Host, Program},
-        vfs::Vfs,
+        plugin::Plugins,
+        vfs::{self, Vfs},
     },
 };
 use structopt::StructOpt;
 use winit::{
+    dpi::PhysicalPosition,
     event::{Event, WindowEvent},
     event_loop::{ControlFlow, EventLoop, EventLoopWindowTarget},
     window::Window,
@@ -66,22 +76,48 @@ struct ClientProgram {
     cmds: Rc<RefCell<CmdRegistry>>,
     console: Rc<RefCell<Console>>,
     menu: Rc<RefCell<Menu>>,
+    browser: Rc<RefCell<ServerBrowser>>,
 
     window: Window,
     window_dimensions_changed: bool,
 
-    surface: wgpu::Surface,
-    swap_chain: RefCell<wgpu::SwapChain>,
+    // the display settings currently applied to the window
+    video: VideoSettings,
+
+    surface: WindowSurface,
     gfx_state: RefCell<GraphicsState>,
     ui_renderer: Rc<UiRenderer>,
 
     game: Game,
     input: Rc<RefCell<Input>>,
+
+    // where config.cfg is saved on exit
+    config_path: PathBuf,
+
+    // where console history is saved on exit
+    history_path: PathBuf,
+
+    // set by the quit command
+    quit: Rc<Cell<bool>>,
 }
 
 impl ClientProgram {
-    pub async fn new(window: Window, base_dir: Option<PathBuf>, trace: bool) -> ClientProgram {
-        let vfs = Vfs::with_base_dir(base_dir.unwrap_or(common::default_base_dir()));
+    pub async fn new(
+        window: Window,
+        base_dir: Option<PathBuf>,
+        game: Option<String>,
+        commands: Vec<String>,
+        trace: bool,
+        renderer: RendererBackend,
+        plugins: Rc<RefCell<Plugins>>,
+    ) -> ClientProgram {
+        let base_dir = base_dir.unwrap_or(common::default_base_dir());
+        let vfs = Vfs::with_base_dir(base_dir.clone(), game.as_deref());
+
+        // settings are saved to the mod directory when running a mod
+        let game_dir = base_dir.join(game.as_deref().unwrap_or(vfs::BASE_GAME));
+        let config_path = game_dir.join(config::CONFIG_FILE);
+        let history_path = game_dir.join(settings::HISTORY_FILE);
 
         let con_names = Rc::new(RefCell::new(Vec::new()));
 
@@ -93,7 +129,42 @@ impl ClientProgram {
         // TODO: register commands as other subsystems come online
 
         let console = Rc::new(RefCell::new(Console::new(cmds.clone(), cvars.clone())));
-        let menu = Rc::new(RefCell::new(menu::build_main_menu().unwrap()));
+        if let Err(e) = settings::read_history(&history_path, &mut console.borrow_mut()) {
+            log::warn!("Couldn't read {}: {}", history_path.display(), e);
+        }
+
+        let scrollback_console = Rc::downgrade(&console);
+        cvars
+            .borrow()
+            .add_hook(
+                "con_scrollback",
+                Box::new(move |_, value| {
+                    if let (Some(console), Ok(lines)) =
+                        (scrollback_console.upgrade(), value.parse::<f32>())
+                    {
+                        console.borrow().set_scrollback(lines as usize);
+                    }
+                }),
+            )
+            .unwrap();
+
+        let monitors = Rc::new(video::monitors(&window));
+        cmds.borrow_mut()
+            .insert_or_replace("vid_modes", video::cmd_vid_modes(monitors.clone()))
+            .unwrap();
+
+        let browser = Rc::new(RefCell::new(ServerBrowser::new(Some(game_dir.clone()))));
+        let address_book = Rc::new(RefCell::new(AddressBook::new(Some(game_dir.clone()))));
+        let menu = Rc::new(RefCell::new(
+            menu::build_main_menu(
+                &monitors,
+                console.clone(),
+                cvars.clone(),
+                browser.clone(),
+                address_book.clone(),
+            )
+            .unwrap(),
+        ));
 
         let input = Rc::new(RefCell::new(Input::new(
             InputFocus::Console,
@@ -118,7 +189,12 @@ impl ClientProgram {
                     features: wgpu::Features::PUSH_CONSTANTS
                         | wgpu::Features::SAMPLED_TEXTURE_BINDING_ARRAY
                         | wgpu::Features::SAMPLED_TEXTURE_ARRAY_DYNAMIC_INDEXING
-                        | wgpu::Features::SAMPLED_TEXTURE_ARRAY_NON_UNIFORM_INDEXING,
+                        | wgpu::Features::SAMPLED_TEXTURE_ARRAY_NON_UNIFORM_INDEXING
+                        // optional: textures are uploaded uncompressed and r_speeds
+                        // reports no GPU timings without these
+                        | (adapter.features()
+                            & (wgpu::Features::TEXTURE_COMPRESSION_BC
+                                | wgpu::Features::TIMESTAMP_QUERY)),
                     limits: wgpu::Limits {
                         max_sampled_textures_per_shader_stage: 256,
                         max_uniform_buffer_binding_size: 65536,
@@ -135,16 +211,8 @@ impl ClientProgram {
             .await
             .unwrap();
         let size: Extent2d = window.inner_size().into();
-        let swap_chain = RefCell::new(device.create_swap_chain(
-            &surface,
-            &wgpu::SwapChainDescriptor {
-                usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
-                format: DIFFUSE_ATTACHMENT_FORMAT,
-                width: size.width,
-                height: size.height,
-                present_mode: wgpu::PresentMode::Immediate,
-            },
-        ));
+        let vsync = cvars.borrow().get_value("vid_vsync").unwrap_or(0.0) != 0.0;
+        let surface = WindowSurface::new(&device, surface, size, render::present_mode(vsync));
 
         let vfs = Rc::new(vfs);
 
@@ -154,42 +222,53 @@ impl ClientProgram {
             sample_count = 2;
         }
 
-        let gfx_state = GraphicsState::new(device, queue, size, sample_count, vfs.clone()).unwrap();
+        let mut gfx_state =
+            GraphicsState::new(device, queue, size, sample_count, vfs.clone()).unwrap();
+        gfx_state.set_texture_cache_dir(game_dir.join("texcache"));
+        gfx_state.set_backend(renderer);
         let ui_renderer = Rc::new(UiRenderer::new(&gfx_state, &menu.borrow()));
 
-        // TODO: factor this out
-        // implements "exec" command
-        let exec_vfs = vfs.clone();
-        let exec_console = console.clone();
-        cmds.borrow_mut().insert_or_replace(
-            "exec",
-            Box::new(move |args| {
-                match args.len() {
-                    // exec (filename): execute a script file
-                    1 => {
-                        let mut script_file = match exec_vfs.open(args[0]) {
-                            Ok(s) => s,
-                            Err(e) => {
-                                return format!("Couldn't exec {}: {:?}", args[0], e);
-                            }
-                        };
-
-                        let mut script = String::new();
-                        script_file.read_to_string(&mut script).unwrap();
-
-                        exec_console.borrow().stuff_text(script);
-                        String::new()
-                    }
+        cmds.borrow_mut()
+            .insert_or_replace("exec", settings::cmd_exec(vfs.clone(), console.clone()))
+            .unwrap();
+        cmds.borrow_mut()
+            .insert_or_replace(
+                "host_writeconfig",
+                settings::cmd_host_writeconfig(game_dir.clone(), cvars.clone(), input.clone()),
+            )
+            .unwrap();
+        cmds.borrow_mut()
+            .insert_or_replace(
+                "saveconfig",
+                settings::cmd_saveconfig(game_dir.clone(), cvars.clone(), input.clone()),
+            )
+            .unwrap();
+        cmds.borrow_mut()
+            .insert_or_replace(
+                "loadconfig",
+                settings::cmd_loadconfig(game_dir.clone(), console.clone()),
+            )
+            .unwrap();
 
-                    _ => format!("exec (filename): execute a script file"),
-                }
-            }),
-        ).unwrap();
+        let quit = Rc::new(Cell::new(false));
+        let cmd_quit = quit.clone();
+        cmds.borrow_mut()
+            .insert_or_replace(
+                "quit",
+                Box::new(move |_| {
+                    cmd_quit.set(true);
+                    String::new()
+                }),
+            )
+            .unwrap();
 
-        // this will also execute config.cfg and autoexec.cfg (assuming an unmodified quake.rc)
-        console.borrow().stuff_text("exec quake.rc\n");
+        cmds.borrow_mut()
+            .insert_or_replace("stuffcmds", args::cmd_stuffcmds(console.clone(), commands))
+            .unwrap();
+
+        settings::exec_startup_configs(&vfs, &console.borrow());
 
-        let client = Client::new(
+        let mut client = match Client::new(
             vfs.clone(),
             cvars.clone(),
             cmds.clone(),
@@ -197,9 +276,21 @@ impl ClientProgram {
             input.clone(),
             &gfx_state,
             &menu.borrow(),
-        );
+            address_book,
+            plugins.clone(),
+        ) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("error starting client: {}", e);
+                std::process::exit(1);
+            }
+        };
+        if let Some(rumble) = GamepadRumble::new() {
+            client.set_rumble_device(Some(Box::new(rumble)));
+        }
 
         let game = Game::new(cvars.clone(), cmds.clone(), input.clone(), client).unwrap();
+        let video = VideoSettings::from_cvars(&cvars.borrow());
 
         ClientProgram {
             vfs,
@@ -207,41 +298,34 @@ impl ClientProgram {
             cmds,
             console,
             menu,
+            browser,
             window,
             window_dimensions_changed: false,
+            video,
             surface,
-            swap_chain,
             gfx_state: RefCell::new(gfx_state),
             ui_renderer,
             game,
             input,
+            config_path,
+            history_path,
+            quit,
         }
     }
 
-    /// Builds a new swap chain with the specified present mode and the window's current dimensions.
-    fn recreate_swap_chain(&self, present_mode: wgpu::PresentMode) {
-        let winit::dpi::PhysicalSize { width, height } = self.window.inner_size();
-        let swap_chain = self.gfx_state.borrow().device().create_swap_chain(
-            &self.surface,
-            &wgpu::SwapChainDescriptor {
-                usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
-                format: DIFFUSE_ATTACHMENT_FORMAT,
-                width,
-                height,
-                present_mode,
-            },
-        );
-        let _ = self.swap_chain.replace(swap_chain);
-    }
-
     fn render(&mut self) {
-        let swap_chain_output = self.swap_chain.borrow_mut().get_current_frame().unwrap();
-        let winit::dpi::PhysicalSize { width, height } = self.window.inner_size();
+        let gfx_state = self.gfx_state.borrow();
+        let frame = match self.surface.current_frame(gfx_state.device()) {
+            Some(f) => f,
+            None => return,
+        };
+
+        let size = self.surface.size();
         self.game.render(
-            &self.gfx_state.borrow(),
-            &swap_chain_output.output.view,
-            width,
-            height,
+            &gfx_state,
+            &frame.output.view,
+            size.width,
+            size.height,
             &self.console.borrow(),
             &self.menu.borrow(),
         );
@@ -268,11 +352,21 @@ impl Program for ClientProgram {
     }
 
     fn frame(&mut self, frame_duration: Duration) {
-        // recreate swapchain if needed
+        // switch monitor or fullscreen mode if the vid_* cvars changed
+        let video = VideoSettings::from_cvars(&self.cvars.borrow());
+        if video != self.video {
+            self.window.set_fullscreen(video.fullscreen(&self.window));
+            self.video = video;
+            self.window_dimensions_changed = true;
+        }
+
+        // the swap chain is rebuilt before the next frame if either of these changed
         if self.window_dimensions_changed {
             self.window_dimensions_changed = false;
-            self.recreate_swap_chain(wgpu::PresentMode::Immediate);
+            self.surface.resize(self.window.inner_size().into());
         }
+        let vsync = self.cvars.borrow().get_value("vid_vsync").unwrap_or(0.0) != 0.0;
+        self.surface.set_present_mode(render::present_mode(vsync));
 
         let size: Extent2d = self.window.inner_size().into();
 
@@ -286,27 +380,79 @@ impl Program for ClientProgram {
             sample_count = 2;
         }
 
+        // picked up by textures created on the next map load
+        let mipmaps = self.cvars.borrow().get_value("r_mipmap").unwrap_or(1.0) != 0.0;
+        let compression = TextureCompression::from_cvar(
+            self.cvars
+                .borrow()
+                .get_value("r_texturecompression")
+                .unwrap_or(0.0),
+        );
+        self.gfx_state
+            .borrow()
+            .set_texture_settings(TextureSettings {
+                mipmaps,
+                compression,
+            });
+
+        let speeds = self.cvars.borrow().get_value("r_speeds").unwrap_or(0.0) != 0.0;
+        self.gfx_state.borrow().stats().set_enabled(speeds);
+
         // recreate attachments and rebuild pipelines if necessary
-        self.gfx_state.borrow_mut().update(size, sample_count);
+        let render_scale = self.cvars.borrow().get_value("r_scale").unwrap_or(1.0);
+        self.gfx_state
+            .borrow_mut()
+            .update(size, sample_count, render_scale);
+
+        // replace the server list once a refresh started from the menu finishes
+        let refreshed = self.browser.borrow_mut().poll();
+        if let Some(Err(e)) = refreshed {
+            self.console
+                .borrow()
+                .println(format!("Couldn't refresh the server list: {}", e));
+        }
+
         self.game.frame(&self.gfx_state.borrow(), frame_duration);
 
-        match self.input.borrow().focus() {
-            InputFocus::Game => {
-                if let Err(e) = self.window.set_cursor_grab(true) {
-                    // This can happen if the window is running in another
-                    // workspace. It shouldn't be considered an error.
-                    log::debug!("Couldn't grab cursor: {}", e);
-                }
+        // let go of the mouse while another window has focus
+        let release = !self.input.borrow().window_focused()
+            && self.cvars.borrow().get_value("in_bgrelease").unwrap_or(1.0) != 0.0;
+
+        let m_rawinput = self.cvars.borrow().get_value("m_rawinput").unwrap_or(1.0);
+        self.input.borrow_mut().set_raw_mouse(m_rawinput != 0.0);
 
-                self.window.set_cursor_visible(false);
+        let focus = self.input.borrow().focus();
+        if focus.grabs_mouse() && !release {
+            if let Err(e) = self.window.set_cursor_grab(true) {
+                // This can happen if the window is running in another
+                // workspace. It shouldn't be considered an error.
+                log::debug!("Couldn't grab cursor: {}", e);
             }
 
-            _ => {
-                if let Err(e) = self.window.set_cursor_grab(false) {
-                    log::debug!("Couldn't release cursor: {}", e);
-                };
-                self.window.set_cursor_visible(true);
+            self.window.set_cursor_visible(false);
+
+            // without raw input, mouse motion is measured from the cursor
+            // position, so keep it away from the edges of the window
+            if m_rawinput == 0.0 {
+                let size = self.window.inner_size();
+                let center =
+                    PhysicalPosition::new(size.width as f64 / 2.0, size.height as f64 / 2.0);
+                match self.window.set_cursor_position(center) {
+                    Ok(()) => {
+                        if let Some(game_input) = self.input.borrow_mut().game_input_mut() {
+                            game_input.warp_cursor((center.x, center.y));
+                        }
+                    }
+
+                    // not supported on all platforms (e.g. Wayland)
+                    Err(e) => log::debug!("Couldn't center cursor: {}", e),
+                }
             }
+        } else {
+            if let Err(e) = self.window.set_cursor_grab(false) {
+                log::debug!("Couldn't release cursor: {}", e);
+            };
+            self.window.set_cursor_visible(true);
         }
 
         // run console commands
@@ -316,7 +462,21 @@ impl Program for ClientProgram {
     }
 
     fn shutdown(&mut self) {
-        // TODO: do cleanup things here
+        if let Err(e) = settings::write_config(
+            &self.config_path,
+            &self.cvars.borrow(),
+            &self.input.borrow(),
+        ) {
+            log::error!("Couldn't write {}: {}", self.config_path.display(), e);
+        }
+
+        if let Err(e) = settings::write_history(&self.history_path, &self.console.borrow()) {
+            log::error!("Couldn't write {}: {}", self.history_path.display(), e);
+        }
+    }
+
+    fn quit_requested(&self) -> bool {
+        self.quit.get()
     }
 
     fn cvars(&self) -> Ref<CvarRegistry> {
@@ -326,6 +486,10 @@ impl Program for ClientProgram {
     fn cvars_mut(&self) -> RefMut<CvarRegistry> {
         self.cvars.borrow_mut()
     }
+
+    fn cmds_mut(&self) -> RefMut<CmdRegistry> {
+        self.cmds.borrow_mut()
+    }
 }
 
 #[derive(StructOpt, Debug)]
@@ -344,11 +508,28 @@ struct Opt {
 
     #[structopt(long)]
     base_dir: Option<PathBuf>,
+
+    /// Run a mod from this subdirectory of the base directory
+    #[structopt(long)]
+    game: Option<String>,
+
+    /// Run a dedicated server without the client
+    #[structopt(long)]
+    dedicated: bool,
+
+    /// Draw the world with the "hardware" (default) or "software" renderer
+    #[structopt(long, default_value = "hardware")]
+    renderer: RendererBackend,
 }
 
 fn main() {
     env_logger::init();
-    let opt = Opt::from_args();
+    let cmdline = CommandLine::parse(std::env::args());
+    let opt = Opt::from_iter(cmdline.options);
+
+    if opt.dedicated {
+        dedicated::run(opt.base_dir, opt.game, cmdline.commands, Vec::new());
+    }
 
     let event_loop = EventLoop::new();
     let window = {
@@ -374,8 +555,16 @@ fn main() {
         }
     };
 
-    let client_program =
-        futures::executor::block_on(ClientProgram::new(window, opt.base_dir, opt.trace));
+    let plugins = Rc::new(RefCell::new(Plugins::new()));
+    let client_program = futures::executor::block_on(ClientProgram::new(
+        window,
+        opt.base_dir,
+        opt.game,
+        cmdline.commands,
+        opt.trace,
+        opt.renderer,
+        plugins.clone(),
+    ));
 
     // TODO: make dump_demo part of top-level binary and allow choosing file name
     if let Some(ref demo) = opt.dump_demo {
@@ -387,31 +576,26 @@ fn main() {
             }
         };
 
-        let mut demserv = match DemoServer::new(&mut demfile) {
+        let demo_reader = match DemoReader::new(&mut demfile) {
             Ok(d) => d,
             Err(e) => {
-                eprintln!("error starting demo server: {}", e);
+                eprintln!("error starting demo reader: {}", e);
                 std::process::exit(1);
             }
         };
 
         let mut outfile = File::create("demodump.txt").unwrap();
-        loop {
-            match demserv.next() {
-                Some(msg) => {
-                    let mut curs = Cursor::new(msg.message());
-                    loop {
-                        match ServerCmd::deserialize(&mut curs) {
-                            Ok(Some(cmd)) => write!(&mut outfile, "{:#?}\n", cmd).unwrap(),
-                            Ok(None) => break,
-                            Err(e) => {
-                                eprintln!("error processing demo: {}", e);
-                                std::process::exit(1);
-                            }
-                        }
+        for frame in demo_reader {
+            match frame {
+                Ok(frame) => {
+                    for cmd in frame.cmds() {
+                        write!(&mut outfile, "{:#?}\n", cmd).unwrap();
                     }
                 }
-                None => break,
+                Err(e) => {
+                    eprintln!("error processing demo: {}", e);
+                    std::process::exit(1);
+                }
             }
         }
 
@@ -429,7 +613,7 @@ fn main() {
             .stuff_text(format!("playdemo {}", demo));
     }
 
-    let mut host = Host::new(client_program);
+    let mut host = Host::new(client_program, plugins);
 
     event_loop.run(move |event, _target, control_flow| {
         host.handle_event(event, _target, control_flow);
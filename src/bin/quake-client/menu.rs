@@ -18,17 +18,72 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use richter::client::menu::{Menu, MenuBodyView, MenuBuilder, MenuView};
+use std::{cell::RefCell, net::SocketAddr, rc::Rc};
 
+use crate::video::{set_cvar, DisplayMode, MonitorInfo, VideoSettings};
+
+use richter::{
+    client::{
+        addressbook::{AddressBook, AddressEntry},
+        browser::{self, ServerBrowser, ServerFilter, SortColumn},
+        menu::{EnumItem, Menu, MenuBodyView, MenuBuilder, MenuView},
+    },
+    common::{
+        console::{self, Console, CvarRegistry},
+        net,
+    },
+};
+
+use chrono::Duration;
 use failure::Error;
 
-pub fn build_main_menu() -> Result<Menu, Error> {
+// the commands listed in the controls menu, in Quake's order
+const CONTROLS: &[(&str, &str)] = &[
+    ("Attack", "+attack"),
+    ("Change weapon", "impulse 10"),
+    ("Jump / swim up", "+jump"),
+    ("Walk forward", "+forward"),
+    ("Backpedal", "+back"),
+    ("Turn left", "+left"),
+    ("Turn right", "+right"),
+    ("Run", "+speed"),
+    ("Step left", "+moveleft"),
+    ("Step right", "+moveright"),
+    ("Sidestep", "+strafe"),
+    ("Look up", "+lookup"),
+    ("Look down", "+lookdown"),
+    ("Center view", "centerview"),
+    ("Mouse look", "+mlook"),
+    ("Keyboard look", "+klook"),
+    ("Swim up", "+moveup"),
+    ("Swim down", "+movedown"),
+];
+
+// an action that runs console commands
+fn command(console: &Rc<RefCell<Console>>, text: &'static str) -> Box<dyn Fn()> {
+    let console = console.clone();
+    Box::new(move || console.borrow().stuff_text(text))
+}
+
+pub fn build_main_menu(
+    monitors: &[MonitorInfo],
+    console: Rc<RefCell<Console>>,
+    cvars: Rc<RefCell<CvarRegistry>>,
+    browser: Rc<RefCell<ServerBrowser>>,
+    address_book: Rc<RefCell<AddressBook>>,
+) -> Result<Menu, Error> {
     Ok(MenuBuilder::new()
         .add_submenu("Single Player", build_menu_sp()?)
-        .add_submenu("Multiplayer", build_menu_mp()?)
-        .add_submenu("Options", build_menu_options()?)
+        .add_submenu(
+            "Multiplayer",
+            build_menu_mp(console.clone(), cvars.clone(), browser, address_book)?,
+        )
+        .add_submenu(
+            "Options",
+            build_menu_options(monitors, console.clone(), cvars)?,
+        )
         .add_action("Help/Ordering", Box::new(|| ()))
-        .add_action("Quit", Box::new(|| ()))
+        .add_action("Quit", command(&console, "quit\n"))
         .build(MenuView {
             draw_plaque: true,
             title_path: "gfx/ttl_main.lmp".to_string(),
@@ -40,6 +95,7 @@ pub fn build_main_menu() -> Result<Menu, Error> {
 
 fn build_menu_sp() -> Result<Menu, Error> {
     Ok(MenuBuilder::new()
+        // TODO: start a local server once the server can run in-process
         .add_action("New Game", Box::new(|| ()))
         // .add_submenu("Load", unimplemented!())
         // .add_submenu("Save", unimplemented!())
@@ -52,9 +108,17 @@ fn build_menu_sp() -> Result<Menu, Error> {
         }))
 }
 
-fn build_menu_mp() -> Result<Menu, Error> {
+fn build_menu_mp(
+    console: Rc<RefCell<Console>>,
+    cvars: Rc<RefCell<CvarRegistry>>,
+    browser: Rc<RefCell<ServerBrowser>>,
+    address_book: Rc<RefCell<AddressBook>>,
+) -> Result<Menu, Error> {
     Ok(MenuBuilder::new()
-        .add_submenu("Join a Game", build_menu_mp_join()?)
+        .add_submenu(
+            "Join a Game",
+            build_menu_mp_join(console, cvars, browser, address_book)?,
+        )
         // .add_submenu("New Game", unimplemented!())
         // .add_submenu("Setup", unimplemented!())
         .build(MenuView {
@@ -66,9 +130,17 @@ fn build_menu_mp() -> Result<Menu, Error> {
         }))
 }
 
-fn build_menu_mp_join() -> Result<Menu, Error> {
+fn build_menu_mp_join(
+    console: Rc<RefCell<Console>>,
+    cvars: Rc<RefCell<CvarRegistry>>,
+    browser: Rc<RefCell<ServerBrowser>>,
+    address_book: Rc<RefCell<AddressBook>>,
+) -> Result<Menu, Error> {
     Ok(MenuBuilder::new()
-        .add_submenu("TCP", build_menu_mp_join_tcp()?)
+        .add_submenu(
+            "TCP",
+            build_menu_mp_join_tcp(console, cvars, browser, address_book)?,
+        )
         // .add_textbox // description
         .build(MenuView {
             draw_plaque: true,
@@ -79,20 +151,207 @@ fn build_menu_mp_join() -> Result<Menu, Error> {
         }))
 }
 
-fn build_menu_mp_join_tcp() -> Result<Menu, Error> {
-    // Join Game - TCP/IP          // title
-    //
-    //  Address: 127.0.0.1         // label
-    //
-    //  Port     [26000]           // text field
-    //
-    //  Search for local games...  // menu
-    //
-    //  Join game at:              // label
-    //  [                        ] // text field
+// close the menu, connect to `addr` and remember it as a recent server
+fn join(console: &Console, browser: &RefCell<ServerBrowser>, addr: SocketAddr) {
+    browser.borrow_mut().add_recent(addr);
+    console.stuff_text(format!("togglemenu\nconnect {}\n", addr));
+}
+
+fn build_menu_mp_join_tcp(
+    console: Rc<RefCell<Console>>,
+    cvars: Rc<RefCell<CvarRegistry>>,
+    browser: Rc<RefCell<ServerBrowser>>,
+    address_book: Rc<RefCell<AddressBook>>,
+) -> Result<Menu, Error> {
+    let address = Rc::new(RefCell::new(String::new()));
+    let address_field = address.clone();
+    let save_address = address.clone();
+    let alias = Rc::new(RefCell::new(String::new()));
+    let alias_field = alias.clone();
+    let remove_alias = alias.clone();
+
+    let join_console = console.clone();
+    let join_browser = browser.clone();
+    let join_book = address_book.clone();
+    let save_book = address_book.clone();
+    let save_console = console.clone();
+    let refresh_browser = browser.clone();
+    let favorite_browser = browser.clone();
+    let select_console = console.clone();
+    let select_browser = browser.clone();
+
+    let filter_items = [
+        ("All", ServerFilter::All),
+        ("Favorites", ServerFilter::Favorites),
+        ("Recent", ServerFilter::Recent),
+    ]
+    .iter()
+    .map(|&(name, filter)| {
+        let browser = browser.clone();
+        EnumItem::new(
+            name,
+            Box::new(move || browser.borrow_mut().set_filter(filter)),
+        )
+    })
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let sort_init = browser.borrow().sort_column();
+    let sort_items = SortColumn::ALL
+        .iter()
+        .map(|&column| {
+            let browser = browser.clone();
+            EnumItem::new(
+                column.name(),
+                Box::new(move || browser.borrow_mut().set_sort_column(column)),
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let reverse_browser = browser.clone();
+
     Ok(MenuBuilder::new()
-        // .add
-        .add_toggle("placeholder", false, Box::new(|_| ()))
+        .add_text_field(
+            "Join game at",
+            None,
+            Some(64),
+            Box::new(move |text| {
+                address_field.replace(text.to_string());
+            }),
+        )?
+        .add_action(
+            "Join game",
+            Box::new(move || {
+                let address = address.borrow();
+                if address.is_empty() {
+                    return;
+                }
+
+                // connect to aliases by name so the entry's password and player name are used
+                if let Some(entry) = join_book.borrow().get(&address) {
+                    if !console::is_quotable(&entry.alias) {
+                        join_console
+                            .borrow()
+                            .println(format!("Can't connect to alias {}", entry.alias));
+                        return;
+                    }
+
+                    if let Ok(addr) = net::resolve_addr(&entry.address, net::DEFAULT_PORT) {
+                        join_browser.borrow_mut().add_recent(addr);
+                    }
+
+                    join_console
+                        .borrow()
+                        .stuff_text(format!("togglemenu\nconnect \"{}\"\n", entry.alias));
+                    return;
+                }
+
+                match net::resolve_addr(&*address, net::DEFAULT_PORT) {
+                    Ok(addr) => join(&join_console.borrow(), &join_browser, addr),
+                    Err(e) => join_console
+                        .borrow()
+                        .println(format!("Couldn't resolve {}: {}", address, e)),
+                }
+            }),
+        )
+        .add_text_field(
+            "Alias",
+            None,
+            Some(16),
+            Box::new(move |text| {
+                alias_field.replace(text.to_string());
+            }),
+        )?
+        .add_action(
+            "Save address",
+            Box::new(move || {
+                let alias = alias.borrow();
+                let address = save_address.borrow();
+                if alias.is_empty() || address.is_empty() {
+                    return;
+                }
+
+                // fields are tab-separated on disk and quoted on the command line
+                let fields = [alias.as_str(), address.as_str()];
+                if fields
+                    .iter()
+                    .any(|f| f.contains('\t') || !console::is_quotable(f))
+                {
+                    save_console.borrow().println(
+                        "address book fields can't contain tabs, newlines, quotes or semicolons",
+                    );
+                    return;
+                }
+
+                // keep the password and player name of an existing entry
+                let mut book = save_book.borrow_mut();
+                let entry = match book.get(&alias) {
+                    Some(e) => AddressEntry {
+                        address: address.clone(),
+                        ..e.clone()
+                    },
+                    None => AddressEntry {
+                        alias: alias.clone(),
+                        address: address.clone(),
+                        password: None,
+                        name: None,
+                    },
+                };
+                book.insert(entry);
+            }),
+        )
+        .add_action(
+            "Remove alias",
+            Box::new(move || {
+                address_book.borrow_mut().remove(&remove_alias.borrow());
+            }),
+        )
+        .add_action(
+            "Refresh",
+            Box::new(move || {
+                if refresh_browser.borrow().is_refreshing() {
+                    return;
+                }
+
+                let master = match cvars.borrow().get("cl_master") {
+                    Ok(m) => m,
+                    Err(e) => {
+                        console.borrow().println(format!("{}", e));
+                        return;
+                    }
+                };
+
+                // the list is replaced when the client polls the browser
+                let timeout = Duration::milliseconds(browser::REFRESH_TIMEOUT_MS);
+                refresh_browser.borrow_mut().refresh(&master, timeout);
+            }),
+        )
+        .add_enum("Show", filter_items, 0)?
+        .add_enum(
+            "Sort by",
+            sort_items,
+            SortColumn::ALL
+                .iter()
+                .position(|c| *c == sort_init)
+                .unwrap(),
+        )?
+        .add_toggle(
+            "Reverse order",
+            false,
+            Box::new(move |reverse| reverse_browser.borrow_mut().set_reverse(reverse)),
+        )
+        .add_action(
+            "Favorite",
+            Box::new(move || {
+                let selected = favorite_browser.borrow().selected_address();
+                if let Some(addr) = selected {
+                    favorite_browser.borrow_mut().toggle_favorite(addr);
+                }
+            }),
+        )
+        .add_server_list(
+            "Servers",
+            browser,
+            Box::new(move |addr| join(&select_console.borrow(), &select_browser, addr)),
+        )
         .build(MenuView {
             draw_plaque: true,
             title_path: "gfx/p_multi.lmp".to_string(),
@@ -100,22 +359,227 @@ fn build_menu_mp_join_tcp() -> Result<Menu, Error> {
         }))
 }
 
-fn build_menu_options() -> Result<Menu, Error> {
+fn build_menu_controls() -> Menu {
+    CONTROLS
+        .iter()
+        .fold(MenuBuilder::new(), |builder, (name, cmd)| {
+            builder.add_binding(*name, *cmd)
+        })
+        .build(MenuView {
+            draw_plaque: false,
+            title_path: "gfx/ttl_cstm.lmp".to_string(),
+            body: MenuBodyView::Dynamic,
+        })
+}
+
+fn build_menu_options(
+    monitors: &[MonitorInfo],
+    console: Rc<RefCell<Console>>,
+    cvars: Rc<RefCell<CvarRegistry>>,
+) -> Result<Menu, Error> {
+    let value = |name: &str, default: f32| cvars.borrow().get_value(name).unwrap_or(default);
+
+    // r_scale runs from 0.25 to 1 in steps of 0.25
+    let scale_init = ((value("r_scale", 1.0) - 0.25) / 0.25)
+        .round()
+        .max(0.0)
+        .min(3.0) as usize;
+    let scale_cvars = cvars.clone();
+
+    // viewsize runs from 30 to 120 in steps of 10
+    let viewsize = value("viewsize", 100.0);
+    let viewsize_init = ((viewsize - 30.0) / 10.0).round().max(0.0).min(9.0) as usize;
+    let viewsize_cvars = cvars.clone();
+
+    // sensitivity runs from 1 to 11 in steps of 1
+    let sensitivity_init = (value("sensitivity", 3.0) - 1.0).round().max(0.0).min(10.0) as usize;
+    let sensitivity_cvars = cvars.clone();
+
+    // running doubles the forward and back speeds
+    let always_run = value("cl_forwardspeed", 400.0) > 200.0;
+    let run_cvars = cvars.clone();
+
+    // inverting the mouse flips the sign of m_pitch
+    let m_pitch = value("m_pitch", 0.022);
+    let invert_cvars = cvars.clone();
+
     Ok(MenuBuilder::new()
-        // .add_submenu("Customize controls", unimplemented!())
-        .add_action("Go to console", Box::new(|| ()))
-        .add_action("Reset to defaults", Box::new(|| ()))
-        .add_slider("Render scale", 0.25, 1.0, 2, 0, Box::new(|_| ()))?
-        .add_slider("Screen Size", 0.0, 1.0, 10, 9, Box::new(|_| ()))?
-        .add_slider("Brightness", 0.0, 1.0, 10, 9, Box::new(|_| ()))?
-        .add_slider("Mouse Speed", 0.0, 1.0, 10, 9, Box::new(|_| ()))?
-        .add_slider("CD music volume", 0.0, 1.0, 10, 9, Box::new(|_| ()))?
-        .add_slider("Sound volume", 0.0, 1.0, 10, 9, Box::new(|_| ()))?
-        .add_toggle("Always run", true, Box::new(|_| ()))
-        .add_toggle("Invert mouse", false, Box::new(|_| ()))
-        .add_toggle("Lookspring", false, Box::new(|_| ()))
-        .add_toggle("Lookstrafe", false, Box::new(|_| ()))
-        // .add_submenu("Video options", unimplemented!())
+        .add_submenu("Customize controls", build_menu_controls())
+        .add_action("Go to console", command(&console, "toggleconsole\n"))
+        .add_action("Reset to defaults", command(&console, "exec default.cfg\n"))
+        .add_slider(
+            "Render scale",
+            0.25,
+            1.0,
+            4,
+            scale_init,
+            Box::new(move |scale| set_cvar(&scale_cvars, "r_scale", scale.to_string())),
+        )?
+        .add_slider(
+            "Screen Size",
+            30.0,
+            120.0,
+            10,
+            viewsize_init,
+            Box::new(move |size| set_cvar(&viewsize_cvars, "viewsize", size.to_string())),
+        )?
+        .add_slider(
+            "Mouse Speed",
+            1.0,
+            11.0,
+            11,
+            sensitivity_init,
+            Box::new(move |speed| set_cvar(&sensitivity_cvars, "sensitivity", speed.to_string())),
+        )?
+        .add_toggle(
+            "Always run",
+            always_run,
+            Box::new(move |run| {
+                // toggles are set once when they're built, which shouldn't
+                // change speeds that already match
+                let running = run_cvars
+                    .borrow()
+                    .get_value("cl_forwardspeed")
+                    .unwrap_or(400.0)
+                    > 200.0;
+                if run != running {
+                    let speed = if run { "400" } else { "200" };
+                    set_cvar(&run_cvars, "cl_forwardspeed", speed.to_string());
+                    set_cvar(&run_cvars, "cl_backspeed", speed.to_string());
+                }
+            }),
+        )
+        .add_toggle(
+            "Invert mouse",
+            m_pitch < 0.0,
+            Box::new(move |invert| {
+                let pitch = if invert {
+                    -m_pitch.abs()
+                } else {
+                    m_pitch.abs()
+                };
+                set_cvar(&invert_cvars, "m_pitch", pitch.to_string());
+            }),
+        )
+        .add_submenu("Video options", build_menu_video(monitors, cvars)?)
+        .build(MenuView {
+            draw_plaque: true,
+            title_path: "gfx/p_option.lmp".to_string(),
+            body: MenuBodyView::Dynamic,
+        }))
+}
+
+fn build_menu_video(
+    monitors: &[MonitorInfo],
+    cvars: Rc<RefCell<CvarRegistry>>,
+) -> Result<Menu, Error> {
+    let settings = VideoSettings::from_cvars(&cvars.borrow());
+
+    // display mode
+    let display_modes = [
+        ("Windowed", DisplayMode::Windowed),
+        ("Borderless", DisplayMode::Borderless),
+        ("Fullscreen", DisplayMode::Exclusive),
+    ];
+    let display_mode_init = display_modes
+        .iter()
+        .position(|(_, m)| *m == settings.mode)
+        .unwrap_or(0);
+    let display_mode_items = display_modes
+        .iter()
+        .enumerate()
+        .map(|(i, (name, _))| {
+            let cvars = cvars.clone();
+            EnumItem::new(
+                *name,
+                Box::new(move || set_cvar(&cvars, "vid_fullscreen", i.to_string())),
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // monitor
+    let monitor_items = monitors
+        .iter()
+        .enumerate()
+        .map(|(i, monitor)| {
+            let cvars = cvars.clone();
+            EnumItem::new(
+                monitor.name.as_str(),
+                Box::new(move || set_cvar(&cvars, "vid_monitor", i.to_string())),
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // resolution, from every monitor since the monitor can change
+    let mut sizes: Vec<(u32, u32)> = monitors
+        .iter()
+        .flat_map(|m| m.modes.iter().map(|mode| (mode.width, mode.height)))
+        .collect();
+    sizes.sort_by(|a, b| b.cmp(a));
+    sizes.dedup();
+    sizes.insert(0, (0, 0));
+    let size_init = sizes
+        .iter()
+        .position(|&s| s == (settings.width, settings.height))
+        .unwrap_or(0);
+    let size_items = sizes
+        .iter()
+        .map(|&(width, height)| {
+            let name = match width {
+                0 => "Native".to_string(),
+                _ => format!("{}x{}", width, height),
+            };
+            let cvars = cvars.clone();
+            EnumItem::new(
+                name,
+                Box::new(move || {
+                    set_cvar(&cvars, "vid_width", width.to_string());
+                    set_cvar(&cvars, "vid_height", height.to_string());
+                }),
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // refresh rate
+    let mut rates: Vec<u16> = monitors
+        .iter()
+        .flat_map(|m| m.modes.iter().map(|mode| mode.refresh_rate))
+        .collect();
+    rates.sort_by(|a, b| b.cmp(a));
+    rates.dedup();
+    rates.insert(0, 0);
+    let rate_init = rates
+        .iter()
+        .position(|&r| r == settings.refresh_rate)
+        .unwrap_or(0);
+    let rate_items = rates
+        .iter()
+        .map(|&rate| {
+            let name = match rate {
+                0 => "Fastest".to_string(),
+                _ => format!("{}Hz", rate),
+            };
+            let cvars = cvars.clone();
+            EnumItem::new(
+                name,
+                Box::new(move || set_cvar(&cvars, "vid_refreshrate", rate.to_string())),
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut builder =
+        MenuBuilder::new().add_enum("Display mode", display_mode_items, display_mode_init)?;
+    if !monitor_items.is_empty() {
+        builder = builder.add_enum(
+            "Monitor",
+            monitor_items,
+            settings.monitor.min(monitors.len() - 1),
+        )?;
+    }
+
+    Ok(builder
+        .add_enum("Resolution", size_items, size_init)?
+        .add_enum("Refresh rate", rate_items, rate_init)?
         .build(MenuView {
             draw_plaque: true,
             title_path: "gfx/p_option.lmp".to_string(),
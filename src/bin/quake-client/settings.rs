@@ -0,0 +1,221 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Saving and loading config files, named config profiles and console history.
+//!
+//! Configs are written to the active game directory, so a mod started with
+//! `--game` keeps its own `config.cfg` and profiles without touching the base
+//! game's.
+
+use std::{
+    cell::RefCell,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use richter::{
+    client::input::Input,
+    common::{
+        console::{config, Console, CvarRegistry},
+        vfs::Vfs,
+    },
+};
+
+const QUAKE_RC: &str = "quake.rc";
+const DEFAULT_CFG: &str = "default.cfg";
+const AUTOEXEC_CFG: &str = "autoexec.cfg";
+
+/// Console input history is kept here between runs.
+pub const HISTORY_FILE: &str = "history.txt";
+
+/// Write archived cvars and key bindings to `path`.
+pub fn write_config(path: &Path, cvars: &CvarRegistry, input: &Input) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = File::create(path)?;
+    file.write_all(config::write_config(cvars, &input.bindings()).as_bytes())
+}
+
+/// Load console input history saved by `write_history`.
+///
+/// A missing history file is not an error.
+pub fn read_history(path: &Path, console: &mut Console) -> io::Result<()> {
+    match fs::read_to_string(path) {
+        Ok(text) => {
+            console.load_history(text.lines());
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Write console input history to `path`, one line per entry.
+pub fn write_history(path: &Path, console: &Console) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = File::create(path)?;
+    for line in console.history() {
+        writeln!(file, "{}", line)?;
+    }
+
+    Ok(())
+}
+
+/// Queue the scripts that set up the game at startup.
+///
+/// quake.rc normally runs default.cfg, config.cfg and autoexec.cfg itself,
+/// followed by `stuffcmds`. If there's no quake.rc, they're run directly.
+pub fn exec_startup_configs(vfs: &Vfs, console: &Console) {
+    if vfs.open(QUAKE_RC).is_ok() {
+        console.stuff_text(format!("exec {}", QUAKE_RC));
+        return;
+    }
+
+    log::warn!("{} not found, running configs directly", QUAKE_RC);
+    for script in &[DEFAULT_CFG, config::CONFIG_FILE, AUTOEXEC_CFG] {
+        if vfs.open(script).is_ok() {
+            console.stuff_text(format!("exec {}", script));
+        }
+    }
+
+    // commands from the command line
+    console.stuff_text("stuffcmds");
+}
+
+/// Implements the `exec` command.
+///
+/// The script runs before anything else in the command buffer. Saved configs
+/// are migrated to the current version first.
+pub fn cmd_exec(vfs: Rc<Vfs>, console: Rc<RefCell<Console>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| {
+        if args.len() != 1 {
+            return "exec (filename): execute a script file".to_owned();
+        }
+
+        let mut script = String::new();
+        match vfs.open(args[0]) {
+            Ok(mut f) => {
+                if let Err(e) = f.read_to_string(&mut script) {
+                    return format!("Couldn't exec {}: {}", args[0], e);
+                }
+            }
+            Err(e) => return format!("Couldn't exec {}: {}", args[0], e),
+        }
+
+        let console = console.borrow();
+
+        // bring configs saved by older versions up to date
+        if args[0] == config::CONFIG_FILE {
+            script = migrate_config(&console, config::CONFIG_FILE, &script);
+        }
+
+        console.insert_text(script);
+        String::new()
+    })
+}
+
+/// Upgrade a saved config, printing a notice for each change.
+pub fn migrate_config(console: &Console, name: &str, script: &str) -> String {
+    let (migrated, notices) = config::migrate(script, config::MIGRATIONS);
+    for notice in notices {
+        console.println(format!("{}: {}", name, notice));
+    }
+
+    migrated
+}
+
+/// Implements the `host_writeconfig` command.
+pub fn cmd_host_writeconfig(
+    game_dir: PathBuf,
+    cvars: Rc<RefCell<CvarRegistry>>,
+    input: Rc<RefCell<Input>>,
+) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| {
+        let path = game_dir.join(config::CONFIG_FILE);
+        match write_config(&path, &cvars.borrow(), &input.borrow()) {
+            Ok(()) => String::new(),
+            Err(e) => format!("Couldn't write {}: {}", config::CONFIG_FILE, e),
+        }
+    })
+}
+
+/// Implements the `saveconfig` command.
+pub fn cmd_saveconfig(
+    game_dir: PathBuf,
+    cvars: Rc<RefCell<CvarRegistry>>,
+    input: Rc<RefCell<Input>>,
+) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| {
+        if args.len() != 1 {
+            return "usage: saveconfig <name>".to_owned();
+        }
+
+        let profile = match config::profile_path(args[0]) {
+            Some(p) => p,
+            None => return format!("Invalid profile name \"{}\"", args[0]),
+        };
+
+        match write_config(&game_dir.join(&profile), &cvars.borrow(), &input.borrow()) {
+            Ok(()) => format!("Saved profile \"{}\"", args[0]),
+            Err(e) => format!("Couldn't save profile \"{}\": {}", args[0], e),
+        }
+    })
+}
+
+/// Implements the `loadconfig` command.
+///
+/// Profiles are read straight from the directory `saveconfig` writes to, not
+/// through the VFS, so a file in a pak or in the base game can't stand in for
+/// one.
+pub fn cmd_loadconfig(
+    game_dir: PathBuf,
+    console: Rc<RefCell<Console>>,
+) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| {
+        if args.len() != 1 {
+            return "usage: loadconfig <name>".to_owned();
+        }
+
+        let profile = match config::profile_path(args[0]) {
+            Some(p) => p,
+            None => return format!("Invalid profile name \"{}\"", args[0]),
+        };
+
+        let script = match fs::read_to_string(game_dir.join(&profile)) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return format!("No profile named \"{}\"", args[0])
+            }
+            Err(e) => return format!("Couldn't read profile \"{}\": {}", args[0], e),
+        };
+
+        let console = console.borrow();
+        let script = migrate_config(&console, &profile, &script);
+        console.insert_text(script);
+        String::new()
+    })
+}
@@ -0,0 +1,225 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::{cell::RefCell, rc::Rc};
+
+use richter::common::console::CvarRegistry;
+
+use winit::{
+    monitor::{MonitorHandle, VideoMode},
+    window::{Fullscreen, Window},
+};
+
+/// How the game window is presented, selected by the `vid_fullscreen` cvar.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisplayMode {
+    Windowed,
+
+    /// A borderless window covering the whole monitor at its current mode.
+    Borderless,
+
+    /// Exclusive fullscreen, which can change the monitor's mode.
+    Exclusive,
+}
+
+impl DisplayMode {
+    pub fn from_value(value: f32) -> DisplayMode {
+        match value as i32 {
+            1 => DisplayMode::Borderless,
+            2 => DisplayMode::Exclusive,
+            _ => DisplayMode::Windowed,
+        }
+    }
+}
+
+/// A video mode supported by a monitor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ModeInfo {
+    pub width: u32,
+    pub height: u32,
+    pub refresh_rate: u16,
+}
+
+impl ModeInfo {
+    fn from_video_mode(mode: &VideoMode) -> ModeInfo {
+        ModeInfo {
+            width: mode.size().width,
+            height: mode.size().height,
+            refresh_rate: mode.refresh_rate(),
+        }
+    }
+}
+
+/// A monitor and the video modes it supports, largest and fastest first.
+#[derive(Clone, Debug)]
+pub struct MonitorInfo {
+    pub name: String,
+    pub modes: Vec<ModeInfo>,
+}
+
+/// List the monitors attached to the system.
+///
+/// Monitors are numbered by their position in this list, as in the
+/// `vid_monitor` cvar.
+pub fn monitors(window: &Window) -> Vec<MonitorInfo> {
+    window
+        .available_monitors()
+        .enumerate()
+        .map(|(i, monitor)| {
+            let mut modes: Vec<ModeInfo> = monitor
+                .video_modes()
+                .map(|m| ModeInfo::from_video_mode(&m))
+                .collect();
+            modes.sort_by(|a, b| {
+                (b.width, b.height, b.refresh_rate).cmp(&(a.width, a.height, a.refresh_rate))
+            });
+            modes.dedup();
+
+            MonitorInfo {
+                name: monitor.name().unwrap_or_else(|| format!("Monitor {}", i)),
+                modes,
+            }
+        })
+        .collect()
+}
+
+/// The display settings requested by the `vid_*` cvars.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VideoSettings {
+    pub mode: DisplayMode,
+    pub monitor: usize,
+
+    // 0 means the monitor's largest mode
+    pub width: u32,
+    pub height: u32,
+
+    // 0 means the fastest rate available at the chosen size
+    pub refresh_rate: u16,
+}
+
+impl VideoSettings {
+    pub fn from_cvars(cvars: &CvarRegistry) -> VideoSettings {
+        let value = |name: &str| cvars.get_value(name).unwrap_or(0.0).max(0.0);
+
+        VideoSettings {
+            mode: DisplayMode::from_value(value("vid_fullscreen")),
+            monitor: value("vid_monitor") as usize,
+            width: value("vid_width") as u32,
+            height: value("vid_height") as u32,
+            refresh_rate: value("vid_refreshrate") as u16,
+        }
+    }
+
+    /// Return the fullscreen setting to apply to `window`, or `None` for a
+    /// regular window.
+    ///
+    /// If the requested monitor doesn't exist, the first one is used. If it
+    /// doesn't support the requested size, its largest mode is used.
+    pub fn fullscreen(&self, window: &Window) -> Option<Fullscreen> {
+        if self.mode == DisplayMode::Windowed {
+            return None;
+        }
+
+        let monitor = self.monitor_handle(window)?;
+        if self.mode == DisplayMode::Borderless {
+            return Some(Fullscreen::Borderless(monitor));
+        }
+
+        let modes: Vec<VideoMode> = monitor.video_modes().collect();
+        let infos: Vec<ModeInfo> = modes.iter().map(ModeInfo::from_video_mode).collect();
+        match self.select_mode(&infos) {
+            Some(i) => Some(Fullscreen::Exclusive(modes[i].clone())),
+            None => Some(Fullscreen::Borderless(monitor)),
+        }
+    }
+
+    fn monitor_handle(&self, window: &Window) -> Option<MonitorHandle> {
+        let mut monitors: Vec<MonitorHandle> = window.available_monitors().collect();
+        if monitors.is_empty() {
+            return None;
+        }
+
+        if self.monitor >= monitors.len() {
+            log::warn!("No monitor {}, using monitor 0", self.monitor);
+            return Some(monitors.swap_remove(0));
+        }
+
+        Some(monitors.swap_remove(self.monitor))
+    }
+
+    /// Return the index of the mode in `modes` that best matches these
+    /// settings.
+    fn select_mode(&self, modes: &[ModeInfo]) -> Option<usize> {
+        let largest = modes.iter().max_by_key(|m| m.width * m.height)?;
+        let supported = modes
+            .iter()
+            .any(|m| m.width == self.width && m.height == self.height);
+        let (width, height) = if supported {
+            (self.width, self.height)
+        } else {
+            if self.width != 0 && self.height != 0 {
+                log::warn!(
+                    "Mode {}x{} not supported, using largest",
+                    self.width,
+                    self.height
+                );
+            }
+            (largest.width, largest.height)
+        };
+
+        let mut candidates: Vec<usize> = (0..modes.len())
+            .filter(|&i| modes[i].width == width && modes[i].height == height)
+            .collect();
+
+        // use the requested refresh rate if possible, otherwise the fastest
+        if let Some(&i) = candidates
+            .iter()
+            .find(|&&i| modes[i].refresh_rate == self.refresh_rate)
+        {
+            return Some(i);
+        }
+        candidates.sort_by_key(|&i| modes[i].refresh_rate);
+        candidates.last().copied()
+    }
+}
+
+/// Print the monitors and their supported modes.
+pub fn cmd_vid_modes(monitors: Rc<Vec<MonitorInfo>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| {
+        let mut out = String::new();
+        for (i, monitor) in monitors.iter().enumerate() {
+            out.push_str(&format!("{}: {}\n", i, monitor.name));
+            for mode in monitor.modes.iter() {
+                out.push_str(&format!(
+                    "    {}x{} @ {}Hz\n",
+                    mode.width, mode.height, mode.refresh_rate
+                ));
+            }
+        }
+        out
+    })
+}
+
+/// Set a cvar from a menu item.
+pub fn set_cvar(cvars: &Rc<RefCell<CvarRegistry>>, name: &str, value: String) {
+    if let Err(e) = cvars.borrow().set(name, value.as_str()) {
+        log::error!("Couldn't set {}: {}", name, e);
+    }
+}
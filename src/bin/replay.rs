@@ -0,0 +1,115 @@
+// Copyright © 2020 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Replays a server session recorded with `sv_record`.
+//!
+//! ```text
+//! replay --base-dir ~/quake session.rec "deathmatch 1" "fraglimit 20"
+//! ```
+//!
+//! The recording holds the map, the random seed and everything the clients sent, but not the
+//! server's settings, so any cvars the session was recorded with should be passed as console
+//! commands after the recording.
+
+extern crate richter;
+
+use std::{cell::RefCell, fs::File, io::BufReader, path::PathBuf, process::exit, rc::Rc};
+
+use richter::{
+    common::{
+        self,
+        console::{CmdRegistry, Console, CvarRegistry},
+        plugin::Plugins,
+        vfs::Vfs,
+    },
+    server::{self, host::ServerHost, record::SessionPlayback},
+};
+
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+struct Opt {
+    #[structopt(long)]
+    base_dir: Option<PathBuf>,
+
+    /// Run a mod from this subdirectory of the base directory
+    #[structopt(long)]
+    game: Option<String>,
+
+    #[structopt(name = "RECORDING", parse(from_os_str))]
+    recording: PathBuf,
+
+    /// Console commands to run before replaying, such as cvar settings
+    #[structopt(name = "COMMANDS")]
+    commands: Vec<String>,
+}
+
+fn main() {
+    env_logger::init();
+    let opt = Opt::from_args();
+
+    let playback = match File::open(&opt.recording)
+        .map_err(|e| e.to_string())
+        .and_then(|f| SessionPlayback::new(BufReader::new(f)).map_err(|e| e.to_string()))
+    {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("Couldn't read {}: {}", opt.recording.display(), e);
+            exit(1);
+        }
+    };
+
+    let base_dir = opt.base_dir.unwrap_or_else(common::default_base_dir);
+    let vfs = Rc::new(Vfs::with_base_dir(base_dir, opt.game.as_deref()));
+
+    let con_names = Rc::new(RefCell::new(Vec::new()));
+    let cvars = Rc::new(RefCell::new(CvarRegistry::new(con_names.clone())));
+    let cmds = Rc::new(RefCell::new(CmdRegistry::new(con_names)));
+    let console = Rc::new(RefCell::new(Console::new(cmds.clone(), cvars.clone())));
+
+    server::register_cvars(&cvars.borrow()).unwrap();
+
+    // the replay takes no clients, so any free port will do
+    cvars.borrow().set("hostport", "0").unwrap();
+
+    for command in opt.commands.iter() {
+        console.borrow().stuff_text(format!("{}\n", command));
+    }
+    console.borrow().execute();
+
+    // the replay runs without plugins
+    let plugins = Rc::new(RefCell::new(Plugins::new()));
+    let mut host = match ServerHost::new(vfs, cvars, cmds, console, plugins) {
+        Ok(h) => h,
+        Err(e) => {
+            eprintln!("Couldn't start the server: {}", e);
+            exit(1);
+        }
+    };
+
+    println!(
+        "replaying {} on {} (seed {})",
+        opt.recording.display(),
+        playback.map(),
+        playback.seed()
+    );
+    if let Err(e) = host.replay(playback) {
+        eprintln!("Replay failed: {}", e);
+        exit(1);
+    }
+    println!("replay finished");
+}
@@ -0,0 +1,145 @@
+// Copyright © 2020 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Generates synthetic maps for limits and performance testing.
+//!
+//! ```text
+//! stressmap --grid 200x200 --leaf-size 2 --textures 512 --entities 4000 id1/maps/stress.bsp
+//! ```
+//!
+//! Pass `--check` to load the generated map back with the engine's BSP loader and report
+//! whether it fits within the loader's limits.
+
+extern crate richter;
+
+use std::{fs, io::Cursor, path::PathBuf, process::exit, str::FromStr, time::Instant};
+
+use richter::common::bsp::{self, stress::StressMapSpec};
+
+use structopt::StructOpt;
+
+#[derive(Debug)]
+struct Grid {
+    width: usize,
+    height: usize,
+}
+
+impl FromStr for Grid {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Grid, String> {
+        let mut dims = s.split('x').map(|d| d.parse::<usize>());
+        match (dims.next(), dims.next(), dims.next()) {
+            (Some(Ok(width)), Some(Ok(height)), None) => Ok(Grid { width, height }),
+            _ => Err(format!("expected WIDTHxHEIGHT, found \"{}\"", s)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct Opt {
+    /// The number of faces in the floor grid, as WIDTHxHEIGHT
+    #[structopt(long, default_value = "16x16")]
+    grid: Grid,
+
+    /// The edge length of each face in world units
+    #[structopt(long, default_value = "128")]
+    face_size: usize,
+
+    /// The number of faces along each edge of a leaf
+    #[structopt(long, default_value = "1")]
+    leaf_size: usize,
+
+    #[structopt(long, default_value = "8")]
+    textures: usize,
+
+    #[structopt(long, default_value = "64")]
+    texture_size: u32,
+
+    /// The number of light styles on each face, up to 4
+    #[structopt(long, default_value = "1")]
+    light_styles: usize,
+
+    /// The number of point entities to add
+    #[structopt(long, default_value = "64")]
+    entities: usize,
+
+    #[structopt(long, default_value = "info_notnull")]
+    classname: String,
+
+    /// Load the generated map and exit with an error if it doesn't load
+    #[structopt(long)]
+    check: bool,
+
+    #[structopt(name = "OUTPUT", parse(from_os_str))]
+    output: PathBuf,
+}
+
+fn main() {
+    env_logger::init();
+    let opt = Opt::from_args();
+
+    let spec = StressMapSpec {
+        grid_width: opt.grid.width,
+        grid_height: opt.grid.height,
+        face_size: opt.face_size,
+        leaf_size: opt.leaf_size,
+        texture_count: opt.textures,
+        texture_size: opt.texture_size,
+        light_styles: opt.light_styles,
+        entity_count: opt.entities,
+        entity_classname: opt.classname.clone(),
+    };
+
+    let (data, stats) = match spec.generate() {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("Couldn't generate map: {}", e);
+            exit(1);
+        }
+    };
+
+    if let Err(e) = fs::write(&opt.output, &data) {
+        eprintln!("Couldn't write {}: {}", opt.output.display(), e);
+        exit(1);
+    }
+
+    println!("wrote {} ({} bytes)", opt.output.display(), data.len());
+    println!("  faces:     {}", stats.faces);
+    println!("  leaves:    {}", stats.leaves);
+    println!("  nodes:     {}", stats.nodes);
+    println!("  planes:    {}", stats.planes);
+    println!("  vertices:  {}", stats.vertices);
+    println!("  edges:     {}", stats.edges);
+    println!("  textures:  {}", stats.textures);
+    println!("  lightmaps: {} bytes", stats.lightmap_bytes);
+    println!(
+        "  entities:  {} ({} bytes)",
+        stats.entities, stats.entity_bytes
+    );
+
+    if opt.check {
+        let start = Instant::now();
+        match bsp::load(Cursor::new(data)) {
+            Ok(_) => println!("loaded in {} ms", start.elapsed().as_millis()),
+            Err(e) => {
+                println!("failed to load: {}", e);
+                exit(1);
+            }
+        }
+    }
+}
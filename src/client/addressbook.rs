@@ -0,0 +1,314 @@
+// Copyright © 2020 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Saved servers, each under a short alias.
+//!
+//! An entry can also hold the server's password and the player name to use
+//! there. `connect <alias>` connects to an entry with its password and name.
+//! The address book is saved to the game directory, one entry per line with
+//! tab-separated fields.
+
+use std::{
+    cell::RefCell,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::common::console;
+
+pub const ADDRESS_BOOK_FILE: &str = "addressbook.txt";
+
+/// A saved server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AddressEntry {
+    pub alias: String,
+
+    /// The server's address, as `host[:port]`.
+    pub address: String,
+
+    /// Sent to the server when connecting, if set.
+    pub password: Option<String>,
+
+    /// The player name to use on this server instead of the default, if set.
+    pub name: Option<String>,
+}
+
+impl AddressEntry {
+    fn parse(line: &str) -> Option<AddressEntry> {
+        let mut fields = line.split('\t');
+        let alias = fields.next().filter(|a| !a.is_empty())?;
+        let address = fields.next().filter(|a| !a.is_empty())?;
+        let mut optional = || {
+            fields
+                .next()
+                .filter(|f| !f.is_empty())
+                .map(|f| f.to_owned())
+        };
+
+        Some(AddressEntry {
+            alias: alias.to_owned(),
+            address: address.to_owned(),
+            password: optional(),
+            name: optional(),
+        })
+    }
+
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}",
+            self.alias,
+            self.address,
+            self.password.as_deref().unwrap_or(""),
+            self.name.as_deref().unwrap_or("")
+        )
+    }
+}
+
+pub struct AddressBook {
+    entries: Vec<AddressEntry>,
+
+    // entries are saved here if set
+    game_dir: Option<PathBuf>,
+}
+
+impl AddressBook {
+    pub fn new(game_dir: Option<PathBuf>) -> AddressBook {
+        let entries = match game_dir {
+            Some(ref dir) => read_entries(&dir.join(ADDRESS_BOOK_FILE)).unwrap_or_else(|e| {
+                warn!("Couldn't read {}: {}", ADDRESS_BOOK_FILE, e);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+
+        AddressBook { entries, game_dir }
+    }
+
+    /// The saved servers, sorted by alias.
+    pub fn entries(&self) -> &[AddressEntry] {
+        &self.entries
+    }
+
+    pub fn get(&self, alias: &str) -> Option<&AddressEntry> {
+        self.entries.iter().find(|e| e.alias == alias)
+    }
+
+    /// Add `entry`, replacing any entry with the same alias.
+    pub fn insert(&mut self, entry: AddressEntry) {
+        match self.entries.binary_search_by(|e| e.alias.cmp(&entry.alias)) {
+            Ok(i) => self.entries[i] = entry,
+            Err(i) => self.entries.insert(i, entry),
+        }
+
+        self.save();
+    }
+
+    /// Remove the entry for `alias`. Returns `false` if there wasn't one.
+    pub fn remove(&mut self, alias: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|e| e.alias != alias);
+        if self.entries.len() == len {
+            return false;
+        }
+
+        self.save();
+        true
+    }
+
+    fn save(&self) {
+        if let Some(ref dir) = self.game_dir {
+            if let Err(e) = write_entries(&dir.join(ADDRESS_BOOK_FILE), &self.entries) {
+                warn!("Couldn't write {}: {}", ADDRESS_BOOK_FILE, e);
+            }
+        }
+    }
+}
+
+/// Read address book entries, one per line, sorted by alias.
+///
+/// A missing file is an empty address book. Malformed lines are skipped.
+pub fn read_entries(path: &Path) -> io::Result<Vec<AddressEntry>> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries: Vec<AddressEntry> = text.lines().filter_map(AddressEntry::parse).collect();
+    entries.sort_by(|a, b| a.alias.cmp(&b.alias));
+    entries.dedup_by(|a, b| a.alias == b.alias);
+    Ok(entries)
+}
+
+/// Write address book entries, one per line.
+pub fn write_entries(path: &Path, entries: &[AddressEntry]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = File::create(path)?;
+    for entry in entries {
+        writeln!(file, "{}", entry.to_line())?;
+    }
+
+    Ok(())
+}
+
+pub fn cmd_addr_add(book: Rc<RefCell<AddressBook>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| {
+        if args.len() < 2 || args.len() > 4 {
+            return "usage: addr_add <alias> <address>[:<port>] [password] [name]".to_owned();
+        }
+
+        // fields are tab-separated on disk and quoted on the command line
+        if args
+            .iter()
+            .any(|a| a.contains('\t') || !console::is_quotable(a))
+        {
+            return "address book fields can't contain tabs, newlines, quotes or semicolons"
+                .to_owned();
+        }
+
+        let optional = |i: usize| args.get(i).filter(|a| !a.is_empty()).map(|a| a.to_string());
+        book.borrow_mut().insert(AddressEntry {
+            alias: args[0].to_owned(),
+            address: args[1].to_owned(),
+            password: optional(2),
+            name: optional(3),
+        });
+
+        String::new()
+    })
+}
+
+pub fn cmd_addr_remove(book: Rc<RefCell<AddressBook>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| {
+        if args.len() != 1 {
+            return "usage: addr_remove <alias>".to_owned();
+        }
+
+        match book.borrow_mut().remove(args[0]) {
+            true => String::new(),
+            false => format!("no address book entry for {}", args[0]),
+        }
+    })
+}
+
+pub fn cmd_addr_list(book: Rc<RefCell<AddressBook>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| {
+        let book = book.borrow();
+        if book.entries().is_empty() {
+            return "address book is empty".to_owned();
+        }
+
+        let mut out = String::new();
+        for entry in book.entries() {
+            out.push_str(&format!("{:<16} {}", entry.alias, entry.address));
+            if let Some(ref name) = entry.name {
+                out.push_str(&format!(" as {}", name));
+            }
+            // never show the password itself
+            if entry.password.is_some() {
+                out.push_str(" (password)");
+            }
+            out.push('\n');
+        }
+
+        out
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(alias: &str, address: &str, password: Option<&str>) -> AddressEntry {
+        AddressEntry {
+            alias: alias.to_owned(),
+            address: address.to_owned(),
+            password: password.map(|p| p.to_owned()),
+            name: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(
+            AddressEntry::parse("home\t192.168.0.2:26001\t\tRanger"),
+            Some(AddressEntry {
+                alias: String::from("home"),
+                address: String::from("192.168.0.2:26001"),
+                password: None,
+                name: Some(String::from("Ranger")),
+            })
+        );
+        assert_eq!(
+            AddressEntry::parse("work\tquake.example.com"),
+            Some(entry("work", "quake.example.com", None))
+        );
+        assert_eq!(AddressEntry::parse("nothing"), None);
+    }
+
+    #[test]
+    fn test_addr_add_rejects_unquotable_fields() {
+        let book = Rc::new(RefCell::new(AddressBook::new(None)));
+        let add = cmd_addr_add(book.clone());
+
+        assert_ne!(add(&["home", "10.0.0.1", "pw\";quit"]), "");
+        assert_ne!(add(&["home", "10.0.0.1", "", "a;b"]), "");
+        assert!(book.borrow().entries().is_empty());
+
+        assert_eq!(add(&["home", "10.0.0.1", "", "Ranger"]), "");
+        assert_eq!(
+            book.borrow().get("home").unwrap().name.as_deref(),
+            Some("Ranger")
+        );
+    }
+
+    #[test]
+    fn test_address_book_persists() {
+        let game_dir =
+            std::env::temp_dir().join(format!("richter-addressbook-{}", std::process::id()));
+
+        let mut book = AddressBook::new(Some(game_dir.clone()));
+        book.insert(entry("zeta", "10.0.0.2", None));
+        book.insert(entry("alpha", "10.0.0.1", Some("hunter2")));
+        book.insert(entry("zeta", "10.0.0.3", None));
+        assert!(!book.remove("beta"));
+
+        let mut book = AddressBook::new(Some(game_dir.clone()));
+        assert_eq!(
+            book.entries(),
+            &[
+                entry("alpha", "10.0.0.1", Some("hunter2")),
+                entry("zeta", "10.0.0.3", None)
+            ]
+        );
+
+        assert!(book.remove("alpha"));
+        let book = AddressBook::new(Some(game_dir.clone()));
+        assert_eq!(book.get("alpha"), None);
+        assert_eq!(book.get("zeta").unwrap().address, "10.0.0.3");
+
+        fs::remove_dir_all(&game_dir).unwrap();
+    }
+}
@@ -0,0 +1,581 @@
+// Copyright © 2020 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! The server list shown by the multiplayer menu.
+//!
+//! Servers come from the master server, plus the player's favorites and the
+//! servers they joined recently. Favorites and recent servers are saved to
+//! the game directory, one address per line. Refreshing the list queries
+//! every server, so it runs on a worker thread and the result is picked up by
+//! `ServerBrowser::poll`.
+
+use std::{
+    cmp::Ordering,
+    fs::{self, File},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+};
+
+use crate::common::net::{
+    self,
+    master::{self, DpMaster, ServerStatus},
+    NetError,
+};
+
+use chrono::Duration;
+
+pub const FAVORITES_FILE: &str = "favorites.txt";
+pub const RECENT_FILE: &str = "recent.txt";
+
+/// How long to wait for the master server, and then for the servers, when
+/// refreshing the list.
+pub const REFRESH_TIMEOUT_MS: i64 = 1500;
+
+/// The number of recent servers remembered.
+pub const MAX_RECENT: usize = 16;
+
+/// Pings below this many milliseconds are `PingClass::Good`.
+pub const GOOD_PING_MS: i64 = 80;
+
+/// Pings below this many milliseconds are `PingClass::Fair`.
+pub const FAIR_PING_MS: i64 = 200;
+
+/// A server in the list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerEntry {
+    pub address: SocketAddr,
+
+    /// The server's reply to a status query, if it sent one.
+    pub status: Option<ServerStatus>,
+
+    /// The round-trip time of the status query.
+    pub ping: Option<Duration>,
+}
+
+impl ServerEntry {
+    fn new(address: SocketAddr) -> ServerEntry {
+        ServerEntry {
+            address,
+            status: None,
+            ping: None,
+        }
+    }
+
+    /// The server's hostname, or its address if it hasn't replied.
+    pub fn name(&self) -> String {
+        match self.status {
+            Some(ref s) if !s.hostname.is_empty() => s.hostname.clone(),
+            _ => self.address.to_string(),
+        }
+    }
+
+    pub fn map(&self) -> &str {
+        self.status.as_ref().map(|s| s.map.as_str()).unwrap_or("")
+    }
+
+    pub fn players(&self) -> (u8, u8) {
+        self.status
+            .as_ref()
+            .map(|s| (s.clients, s.max_clients))
+            .unwrap_or((0, 0))
+    }
+
+    pub fn ping_class(&self) -> PingClass {
+        PingClass::from_ping(self.ping)
+    }
+}
+
+/// How a server's ping is colored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PingClass {
+    Good,
+    Fair,
+    Poor,
+
+    /// The server didn't reply.
+    Unknown,
+}
+
+impl PingClass {
+    pub fn from_ping(ping: Option<Duration>) -> PingClass {
+        match ping.map(|p| p.num_milliseconds()) {
+            Some(ms) if ms < GOOD_PING_MS => PingClass::Good,
+            Some(ms) if ms < FAIR_PING_MS => PingClass::Fair,
+            Some(_) => PingClass::Poor,
+            None => PingClass::Unknown,
+        }
+    }
+}
+
+/// A column the list can be sorted by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortColumn {
+    Name,
+    Map,
+    Players,
+    Ping,
+}
+
+impl SortColumn {
+    pub const ALL: [SortColumn; 4] = [
+        SortColumn::Name,
+        SortColumn::Map,
+        SortColumn::Players,
+        SortColumn::Ping,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SortColumn::Name => "Name",
+            SortColumn::Map => "Map",
+            SortColumn::Players => "Players",
+            SortColumn::Ping => "Ping",
+        }
+    }
+
+    fn compare(&self, a: &ServerEntry, b: &ServerEntry) -> Ordering {
+        match *self {
+            SortColumn::Name => a.name().to_lowercase().cmp(&b.name().to_lowercase()),
+            SortColumn::Map => a.map().cmp(b.map()),
+            // fullest first
+            SortColumn::Players => b.players().cmp(&a.players()),
+            // servers that didn't reply go last
+            SortColumn::Ping => match (a.ping, b.ping) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            },
+        }
+    }
+}
+
+/// Which servers the list shows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServerFilter {
+    All,
+    Favorites,
+    Recent,
+}
+
+pub struct ServerBrowser {
+    servers: Vec<ServerEntry>,
+    favorites: Vec<SocketAddr>,
+
+    // most recent first
+    recent: Vec<SocketAddr>,
+
+    // where the lists are saved, if anywhere
+    game_dir: Option<PathBuf>,
+
+    filter: ServerFilter,
+    sort: SortColumn,
+    reverse: bool,
+
+    // index into rows()
+    selected: usize,
+
+    // receives the new server list from the refresh thread, if one is running
+    refresh_rx: Option<Receiver<Result<Vec<ServerEntry>, NetError>>>,
+}
+
+impl ServerBrowser {
+    /// Create a browser that saves favorites and recent servers in `game_dir`.
+    ///
+    /// Lists saved by an earlier run are loaded. If `game_dir` is `None`,
+    /// nothing is loaded or saved.
+    pub fn new(game_dir: Option<PathBuf>) -> ServerBrowser {
+        let load = |name: &str| match game_dir {
+            Some(ref dir) => read_addresses(&dir.join(name)).unwrap_or_else(|e| {
+                warn!("Couldn't read {}: {}", name, e);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+
+        let favorites = load(FAVORITES_FILE);
+        let mut recent = load(RECENT_FILE);
+        recent.truncate(MAX_RECENT);
+
+        let mut browser = ServerBrowser {
+            servers: Vec::new(),
+            favorites,
+            recent,
+            game_dir,
+            filter: ServerFilter::All,
+            sort: SortColumn::Ping,
+            reverse: false,
+            selected: 0,
+            refresh_rx: None,
+        };
+        browser.set_servers(Vec::new());
+        browser
+    }
+
+    /// Replace the server list.
+    ///
+    /// Favorites and recent servers are always listed, even if the master
+    /// server doesn't know about them.
+    pub fn set_servers(&mut self, servers: Vec<ServerEntry>) {
+        self.servers = servers;
+
+        let known: Vec<SocketAddr> = self
+            .favorites
+            .iter()
+            .chain(self.recent.iter())
+            .cloned()
+            .collect();
+        for addr in known {
+            self.add_server(addr);
+        }
+
+        self.clamp_selection();
+    }
+
+    /// Start fetching the server list from `master` and querying every server
+    /// on it.
+    ///
+    /// The list is replaced when `poll` sees the refresh finish. A refresh
+    /// that is already running is abandoned.
+    pub fn refresh<S>(&mut self, master: S, timeout: Duration)
+    where
+        S: AsRef<str>,
+    {
+        let master = master.as_ref().to_owned();
+        let known: Vec<SocketAddr> = self
+            .favorites
+            .iter()
+            .chain(self.recent.iter())
+            .cloned()
+            .collect();
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            // the browser may have started another refresh by now
+            let _ = tx.send(fetch_servers(&master, &known, timeout));
+        });
+        self.refresh_rx = Some(rx);
+    }
+
+    /// Returns `true` if a refresh is running.
+    pub fn is_refreshing(&self) -> bool {
+        self.refresh_rx.is_some()
+    }
+
+    /// Pick up the result of a finished refresh.
+    ///
+    /// Returns `None` if no refresh finished since the last call.
+    pub fn poll(&mut self) -> Option<Result<(), NetError>> {
+        let result = match self.refresh_rx.as_ref()?.try_recv() {
+            Ok(r) => r,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => {
+                Err(NetError::with_msg("Server list refresh stopped"))
+            }
+        };
+
+        self.refresh_rx = None;
+        Some(result.map(|servers| self.set_servers(servers)))
+    }
+
+    /// The servers that pass the filter, in sorted order.
+    pub fn rows(&self) -> Vec<&ServerEntry> {
+        let mut rows: Vec<&ServerEntry> = match self.filter {
+            ServerFilter::All => self.servers.iter().collect(),
+            ServerFilter::Favorites => self.listed(&self.favorites),
+            ServerFilter::Recent => self.listed(&self.recent),
+        };
+
+        // the recent list keeps its own order
+        if self.filter != ServerFilter::Recent {
+            rows.sort_by(|a, b| self.sort.compare(a, b));
+            if self.reverse {
+                rows.reverse();
+            }
+        }
+
+        rows
+    }
+
+    pub fn filter(&self) -> ServerFilter {
+        self.filter
+    }
+
+    pub fn set_filter(&mut self, filter: ServerFilter) {
+        self.filter = filter;
+        self.selected = 0;
+    }
+
+    pub fn sort_column(&self) -> SortColumn {
+        self.sort
+    }
+
+    pub fn set_sort_column(&mut self, column: SortColumn) {
+        self.sort = column;
+    }
+
+    /// Returns `true` if the sort order is reversed.
+    pub fn reverse(&self) -> bool {
+        self.reverse
+    }
+
+    pub fn set_reverse(&mut self, reverse: bool) {
+        self.reverse = reverse;
+    }
+
+    /// The index of the selected row.
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    /// The address of the selected server.
+    pub fn selected_address(&self) -> Option<SocketAddr> {
+        self.rows().get(self.selected).map(|e| e.address)
+    }
+
+    /// Select the next row. Returns `false` if the last row was already
+    /// selected.
+    pub fn select_next(&mut self) -> bool {
+        if self.selected + 1 < self.rows().len() {
+            self.selected += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Select the previous row. Returns `false` if the first row was already
+    /// selected.
+    pub fn select_prev(&mut self) -> bool {
+        if self.selected > 0 {
+            self.selected -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn is_favorite(&self, addr: SocketAddr) -> bool {
+        self.favorites.contains(&addr)
+    }
+
+    pub fn favorites(&self) -> &[SocketAddr] {
+        &self.favorites
+    }
+
+    /// Add `addr` to the favorites, or remove it if it's already there.
+    pub fn toggle_favorite(&mut self, addr: SocketAddr) {
+        match self.favorites.iter().position(|a| *a == addr) {
+            Some(i) => {
+                self.favorites.remove(i);
+            }
+            None => {
+                self.favorites.push(addr);
+                self.add_server(addr);
+            }
+        }
+
+        self.clamp_selection();
+        self.save(FAVORITES_FILE, &self.favorites);
+    }
+
+    /// Recently joined servers, most recent first.
+    pub fn recent(&self) -> &[SocketAddr] {
+        &self.recent
+    }
+
+    /// Record that the player joined `addr`.
+    pub fn add_recent(&mut self, addr: SocketAddr) {
+        self.recent.retain(|a| *a != addr);
+        self.recent.insert(0, addr);
+        self.recent.truncate(MAX_RECENT);
+        self.add_server(addr);
+
+        self.save(RECENT_FILE, &self.recent);
+    }
+
+    fn add_server(&mut self, addr: SocketAddr) {
+        if !self.servers.iter().any(|e| e.address == addr) {
+            self.servers.push(ServerEntry::new(addr));
+        }
+    }
+
+    fn listed(&self, addresses: &[SocketAddr]) -> Vec<&ServerEntry> {
+        addresses
+            .iter()
+            .filter_map(|a| self.servers.iter().find(|e| e.address == *a))
+            .collect()
+    }
+
+    fn clamp_selection(&mut self) {
+        let len = self.rows().len();
+        if self.selected >= len {
+            self.selected = len.saturating_sub(1);
+        }
+    }
+
+    fn save(&self, name: &str, addresses: &[SocketAddr]) {
+        if let Some(ref dir) = self.game_dir {
+            if let Err(e) = write_addresses(&dir.join(name), addresses) {
+                warn!("Couldn't write {}: {}", name, e);
+            }
+        }
+    }
+}
+
+// fetch the server list from `master`, add `known` and query every server
+fn fetch_servers(
+    master: &str,
+    known: &[SocketAddr],
+    timeout: Duration,
+) -> Result<Vec<ServerEntry>, NetError> {
+    let protocol = DpMaster::default();
+    let master = net::resolve_addr(master, master::DEFAULT_MASTER_PORT)?;
+
+    let mut addresses = master::fetch_server_list(&protocol, master, timeout)?;
+    for addr in known {
+        if !addresses.contains(addr) {
+            addresses.push(*addr);
+        }
+    }
+
+    let replies = master::query_status(&protocol, &addresses, timeout)?;
+    let mut servers: Vec<ServerEntry> = addresses.into_iter().map(ServerEntry::new).collect();
+    for (addr, status, ping) in replies {
+        if let Some(entry) = servers.iter_mut().find(|e| e.address == addr) {
+            entry.status = Some(status);
+            entry.ping = Some(ping);
+        }
+    }
+
+    Ok(servers)
+}
+
+/// Read a list of addresses, one per line.
+///
+/// A missing file is an empty list. Lines that aren't addresses are skipped.
+pub fn read_addresses(path: &Path) -> io::Result<Vec<SocketAddr>> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    Ok(text
+        .lines()
+        .filter_map(|line| line.trim().parse().ok())
+        .collect())
+}
+
+/// Write a list of addresses, one per line.
+pub fn write_addresses(path: &Path, addresses: &[SocketAddr]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = File::create(path)?;
+    for addr in addresses {
+        writeln!(file, "{}", addr)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn server(port: u16, hostname: &str, clients: u8, ping_ms: Option<i64>) -> ServerEntry {
+        ServerEntry {
+            address: SocketAddr::from(([127, 0, 0, 1], port)),
+            status: Some(ServerStatus {
+                hostname: hostname.to_owned(),
+                map: String::from("dm4"),
+                clients,
+                max_clients: 16,
+            }),
+            ping: ping_ms.map(Duration::milliseconds),
+        }
+    }
+
+    fn names(browser: &ServerBrowser) -> Vec<String> {
+        browser.rows().iter().map(|e| e.name()).collect()
+    }
+
+    #[test]
+    fn test_sort_and_select() {
+        let mut browser = ServerBrowser::new(None);
+        browser.set_servers(vec![
+            server(26000, "bravo", 4, Some(150)),
+            server(26001, "alpha", 1, None),
+            server(26002, "charlie", 8, Some(30)),
+        ]);
+
+        assert_eq!(names(&browser), vec!["charlie", "bravo", "alpha"]);
+        assert_eq!(browser.rows()[0].ping_class(), PingClass::Good);
+        assert_eq!(browser.rows()[1].ping_class(), PingClass::Fair);
+        assert_eq!(browser.rows()[2].ping_class(), PingClass::Unknown);
+
+        browser.set_sort_column(SortColumn::Name);
+        assert_eq!(names(&browser), vec!["alpha", "bravo", "charlie"]);
+        browser.set_reverse(true);
+        assert_eq!(names(&browser), vec!["charlie", "bravo", "alpha"]);
+
+        assert!(!browser.select_prev());
+        assert!(browser.select_next());
+        assert!(browser.select_next());
+        assert!(!browser.select_next());
+        assert_eq!(
+            browser.selected_address(),
+            Some(SocketAddr::from(([127, 0, 0, 1], 26001)))
+        );
+    }
+
+    #[test]
+    fn test_favorites_and_recent_persist() {
+        let game_dir = std::env::temp_dir().join(format!("richter-browser-{}", std::process::id()));
+        let a = SocketAddr::from(([10, 0, 0, 1], 26000));
+        let b = SocketAddr::from(([10, 0, 0, 2], 26000));
+
+        let mut browser = ServerBrowser::new(Some(game_dir.clone()));
+        browser.toggle_favorite(a);
+        browser.add_recent(a);
+        browser.add_recent(b);
+        browser.add_recent(a);
+
+        let mut browser = ServerBrowser::new(Some(game_dir.clone()));
+        assert!(browser.is_favorite(a));
+        assert_eq!(browser.recent(), &[a, b]);
+
+        // saved servers are listed before the master server is queried
+        browser.set_filter(ServerFilter::Favorites);
+        assert_eq!(browser.selected_address(), Some(a));
+        browser.set_filter(ServerFilter::All);
+        assert_eq!(browser.rows().len(), 2);
+
+        browser.toggle_favorite(a);
+        assert!(!ServerBrowser::new(Some(game_dir.clone())).is_favorite(a));
+
+        let _ = fs::remove_dir_all(&game_dir);
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright © 2020 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Messages printed to the center of the screen.
+//!
+//! A center print stays up for `scr_centertime` seconds. Messages printed
+//! during an intermission are revealed a few characters at a time, at
+//! `scr_printspeed` characters per second, and stay up until the intermission
+//! ends or another message replaces them.
+
+use crate::common::engine;
+
+use chrono::Duration;
+
+/// The widest a line of centered text may be, in characters.
+pub const LINE_WIDTH: usize = 40;
+
+#[derive(Clone, Copy, Debug)]
+pub struct CenterPrintVars {
+    pub scr_centertime: f32,
+    pub scr_printspeed: f32,
+}
+
+impl Default for CenterPrintVars {
+    fn default() -> CenterPrintVars {
+        CenterPrintVars {
+            scr_centertime: 2.0,
+            scr_printspeed: 8.0,
+        }
+    }
+}
+
+/// Split `text` into lines no wider than `width` characters.
+///
+/// Lines are broken at newlines, and long lines are wrapped at the last space
+/// that fits, or mid-word if there is none.
+pub fn wrap_lines(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for line in text.trim_end_matches('\n').split('\n') {
+        let mut rest: Vec<char> = line.chars().collect();
+        while rest.len() > width {
+            let split = match rest[..=width].iter().rposition(|c| *c == ' ') {
+                Some(0) | None => width,
+                Some(i) => i,
+            };
+
+            lines.push(
+                rest[..split]
+                    .iter()
+                    .collect::<String>()
+                    .trim_end()
+                    .to_owned(),
+            );
+            rest.drain(..split);
+            while rest.first() == Some(&' ') {
+                rest.remove(0);
+            }
+        }
+
+        lines.push(rest.into_iter().collect());
+    }
+
+    lines
+}
+
+#[derive(Debug)]
+struct Message {
+    lines: Vec<String>,
+    start_time: Duration,
+    reveal: bool,
+}
+
+/// Tracks the message printed to the center of the screen.
+#[derive(Debug)]
+pub struct CenterPrint {
+    message: Option<Message>,
+    vars: CenterPrintVars,
+}
+
+impl CenterPrint {
+    pub fn new() -> CenterPrint {
+        CenterPrint {
+            message: None,
+            vars: CenterPrintVars::default(),
+        }
+    }
+
+    pub fn set_vars(&mut self, vars: CenterPrintVars) {
+        self.vars = vars;
+    }
+
+    /// Show `text` starting at `time`, replacing any message already on screen.
+    ///
+    /// If `reveal` is set, the text appears one character at a time and stays
+    /// up until it is cleared.
+    pub fn print(&mut self, text: &str, time: Duration, reveal: bool) {
+        self.message = Some(Message {
+            lines: wrap_lines(text, LINE_WIDTH),
+            start_time: time,
+            reveal,
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.message = None;
+    }
+
+    /// Remove the message if it has expired as of `time`.
+    pub fn update(&mut self, time: Duration) {
+        let expired = match self.message {
+            Some(ref m) => {
+                !m.reveal
+                    && time - m.start_time
+                        >= engine::duration_from_f32(self.vars.scr_centertime.max(0.0))
+            }
+            None => false,
+        };
+
+        if expired {
+            self.message = None;
+        }
+    }
+
+    /// Returns the lines on screen at `time`, cut short if they're still being
+    /// revealed.
+    pub fn visible_lines(&self, time: Duration) -> Vec<&str> {
+        let message = match self.message {
+            Some(ref m) => m,
+            None => return Vec::new(),
+        };
+
+        let mut remaining = match message.reveal {
+            true => {
+                let elapsed = engine::duration_to_f32(time - message.start_time).max(0.0);
+                (elapsed * self.vars.scr_printspeed.max(0.0)) as usize
+            }
+            false => usize::MAX,
+        };
+
+        let mut lines = Vec::new();
+        for line in message.lines.iter() {
+            if remaining == 0 {
+                break;
+            }
+
+            let end = line
+                .char_indices()
+                .nth(remaining)
+                .map(|(i, _)| i)
+                .unwrap_or(line.len());
+            remaining = remaining.saturating_sub(line.chars().count());
+            lines.push(&line[..end]);
+        }
+
+        lines
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wrap_lines() {
+        assert_eq!(wrap_lines("one\ntwo\n", 40), vec!["one", "two"]);
+        assert_eq!(
+            wrap_lines("the quick brown fox jumps", 10),
+            vec!["the quick", "brown fox", "jumps"]
+        );
+        assert_eq!(wrap_lines("abcdefghijkl", 5), vec!["abcde", "fghij", "kl"]);
+        assert_eq!(wrap_lines("", 40), vec![""]);
+    }
+
+    #[test]
+    fn test_center_print_expires() {
+        let mut cp = CenterPrint::new();
+        cp.print("You need the gold key", Duration::seconds(10), false);
+        assert_eq!(
+            cp.visible_lines(Duration::seconds(10)),
+            vec!["You need the gold key"]
+        );
+
+        cp.update(Duration::milliseconds(11900));
+        assert_eq!(cp.visible_lines(Duration::milliseconds(11900)).len(), 1);
+
+        cp.update(Duration::seconds(12));
+        assert!(cp.visible_lines(Duration::seconds(12)).is_empty());
+    }
+
+    #[test]
+    fn test_center_print_reveal() {
+        let mut cp = CenterPrint::new();
+        cp.print("abcd\nefgh", Duration::zero(), true);
+
+        assert!(cp.visible_lines(Duration::zero()).is_empty());
+        assert_eq!(cp.visible_lines(Duration::milliseconds(250)), vec!["ab"]);
+        assert_eq!(
+            cp.visible_lines(Duration::milliseconds(750)),
+            vec!["abcd", "ef"]
+        );
+
+        // revealed messages don't expire
+        cp.update(Duration::seconds(60));
+        assert_eq!(
+            cp.visible_lines(Duration::seconds(60)),
+            vec!["abcd", "efgh"]
+        );
+
+        cp.clear();
+        assert!(cp.visible_lines(Duration::seconds(60)).is_empty());
+    }
+}
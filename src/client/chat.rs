@@ -0,0 +1,193 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Player chat messages.
+//!
+//! The server relays `say` and `say_team` as ordinary print messages of the
+//! form `name: text`, usually prefixed with a `\x01` byte which asks the client
+//! to play the talk sound. Those messages are shown in their own notify area
+//! instead of among the console notifications.
+
+use std::collections::VecDeque;
+
+use chrono::Duration;
+
+/// Played when a chat message arrives.
+pub const TALK_SOUND: &str = "misc/talk.wav";
+
+// prefix the server adds to messages that should play the talk sound
+const TALK_PREFIX: char = '\u{1}';
+
+const MESSAGE_DURATION_SECS: i64 = 8;
+
+// most chat messages that can be displayed at once
+const MAX_ACTIVE_MESSAGES: usize = 4;
+
+/// Returns the chat message in `text`, if it is one.
+///
+/// A message is chat if it has the talk prefix or starts with the name of one
+/// of `players` followed by a colon. Team messages put the name in
+/// parentheses. The prefix and trailing newline are removed.
+pub fn parse_chat<'a, 'n, I>(text: &'a str, players: I) -> Option<&'a str>
+where
+    I: IntoIterator<Item = &'n str>,
+{
+    let line = text.trim_end_matches('\n');
+
+    if let Some(msg) = line.strip_prefix(TALK_PREFIX) {
+        return Some(msg);
+    }
+
+    let (sender, terminator) = match line.strip_prefix('(') {
+        Some(rest) => (rest, "):"),
+        None => (line, ":"),
+    };
+    let is_chat = players.into_iter().any(|name| {
+        !name.is_empty()
+            && sender
+                .strip_prefix(name)
+                .map(|rest| rest.starts_with(terminator))
+                .unwrap_or(false)
+    });
+
+    if is_chat {
+        Some(line)
+    } else {
+        None
+    }
+}
+
+/// Formats the command which sends `text` as chat.
+///
+/// The server strips the quotes around the message, so any in the message
+/// itself are removed to keep it in one piece.
+pub fn say_command(text: &str, team: bool) -> String {
+    let text: String = text.chars().filter(|c| *c != '"').collect();
+    format!(
+        "{} \"{}\"\n",
+        if team { "say_team" } else { "say" },
+        text.trim()
+    )
+}
+
+struct ActiveMessage {
+    text: String,
+    expire_time: Duration,
+}
+
+/// Tracks the chat messages currently on screen.
+pub struct ChatLog {
+    active: VecDeque<ActiveMessage>,
+
+    // set when a message arrives so the client can play the talk sound
+    sound_pending: bool,
+}
+
+impl ChatLog {
+    pub fn new() -> ChatLog {
+        ChatLog {
+            active: VecDeque::new(),
+            sound_pending: false,
+        }
+    }
+
+    /// Show a chat message received at `time`.
+    pub fn push(&mut self, text: &str, time: Duration) {
+        if self.active.len() >= MAX_ACTIVE_MESSAGES {
+            self.active.pop_front();
+        }
+
+        self.active.push_back(ActiveMessage {
+            text: text.to_owned(),
+            expire_time: time + Duration::seconds(MESSAGE_DURATION_SECS),
+        });
+        self.sound_pending = true;
+    }
+
+    /// Returns `true` once for each batch of messages received since the last call.
+    pub fn take_sound(&mut self) -> bool {
+        std::mem::replace(&mut self.sound_pending, false)
+    }
+
+    /// Remove messages that have expired as of `time`.
+    pub fn update(&mut self, time: Duration) {
+        self.active.retain(|m| m.expire_time > time);
+    }
+
+    /// Return an iterator over the messages on screen, oldest first.
+    pub fn active(&self) -> impl DoubleEndedIterator<Item = &str> {
+        self.active.iter().map(|m| m.text.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_chat() {
+        let players = ["player", "other"];
+        assert_eq!(
+            parse_chat("\u{1}player: hello\n", players.iter().copied()),
+            Some("player: hello")
+        );
+        assert_eq!(
+            parse_chat("other: hi there\n", players.iter().copied()),
+            Some("other: hi there")
+        );
+        assert_eq!(
+            parse_chat("(other): team only\n", players.iter().copied()),
+            Some("(other): team only")
+        );
+        assert_eq!(
+            parse_chat("player was gibbed by other\n", players.iter().copied()),
+            None
+        );
+        assert_eq!(parse_chat(": nobody\n", [""].iter().copied()), None);
+    }
+
+    #[test]
+    fn test_say_command() {
+        assert_eq!(say_command("hello", false), "say \"hello\"\n");
+        assert_eq!(
+            say_command(" \"quoted\" text ", true),
+            "say_team \"quoted text\"\n"
+        );
+    }
+
+    #[test]
+    fn test_chat_log() {
+        let mut log = ChatLog::new();
+        assert!(!log.take_sound());
+
+        for i in 0..MAX_ACTIVE_MESSAGES + 1 {
+            log.push(&format!("msg {}", i), Duration::seconds(i as i64));
+        }
+        assert!(log.take_sound());
+        assert!(!log.take_sound());
+
+        // the oldest message was pushed out
+        assert_eq!(log.active().next(), Some("msg 1"));
+        assert_eq!(log.active().count(), MAX_ACTIVE_MESSAGES);
+
+        log.update(Duration::seconds(MESSAGE_DURATION_SECS + 2));
+        assert_eq!(log.active().collect::<Vec<_>>(), vec!["msg 3", "msg 4"]);
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Teammate markers for coop games.
+//!
+//! Each teammate whose entity the server sent this frame gets a marker: a
+//! nameplate over their head if they're on screen, or an arrow on the edge of
+//! the screen pointing toward them if they're not. The server only sends
+//! entities that are potentially visible from the player's position, so
+//! teammates on the far side of the level are listed in the party HUD without
+//! a marker.
+
+use cgmath::{Matrix4, Vector3};
+
+use crate::common::net::PlayerColor;
+
+/// Nameplates are drawn this far above a player's origin.
+pub const NAMEPLATE_HEIGHT: f32 = 40.0;
+
+// off-screen markers are kept this far from the center of the screen, in
+// normalized device coordinates
+const EDGE_MARGIN: f32 = 0.9;
+
+/// Where a teammate's marker goes on the screen.
+///
+/// Coordinates are normalized device coordinates: `(-1, -1)` is the bottom
+/// left corner of the screen and `(1, 1)` the top right.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MarkerPosition {
+    /// The teammate is on screen at this position.
+    OnScreen { x: f32, y: f32 },
+
+    /// The teammate is off screen, in the direction of this point on the edge
+    /// of the screen.
+    OffScreen { x: f32, y: f32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Marker {
+    pub position: MarkerPosition,
+
+    /// The distance from the camera to the teammate, in world units.
+    pub distance: f32,
+}
+
+/// A member of the local player's party.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Teammate {
+    pub player_id: usize,
+    pub name: String,
+    pub colors: PlayerColor,
+
+    /// The teammate's marker, or `None` if the server didn't send their entity
+    /// this frame.
+    pub marker: Option<Marker>,
+}
+
+/// Find where on the screen to put the marker for a teammate at `origin`.
+///
+/// `view_projection` is the camera's view-projection matrix, which expects
+/// coordinates already converted from Quake's coordinate system.
+pub fn project_marker(view_projection: Matrix4<f32>, origin: Vector3<f32>) -> MarkerPosition {
+    let converted = Vector3::new(-origin.y, origin.z, -origin.x);
+    let clip = view_projection * converted.extend(1.0);
+
+    if clip.w > 0.0 {
+        let (x, y) = (clip.x / clip.w, clip.y / clip.w);
+        if x.abs() <= 1.0 && y.abs() <= 1.0 {
+            return MarkerPosition::OnScreen { x, y };
+        }
+    }
+
+    // dividing by a negative w would mirror points behind the camera, so only
+    // the signs of the clip coordinates are used to find the direction
+    let (x, y) = match clip.x.abs().max(clip.y.abs()) {
+        m if m <= std::f32::EPSILON => (0.0, -1.0),
+        m => (clip.x / m, clip.y / m),
+    };
+
+    MarkerPosition::OffScreen {
+        x: x * EDGE_MARGIN,
+        y: y * EDGE_MARGIN,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{client::render::Camera, common::math::Angles};
+
+    use cgmath::Deg;
+
+    fn camera() -> Camera {
+        Camera::new(
+            Vector3::new(0.0, 0.0, 0.0),
+            Angles {
+                pitch: Deg(0.0),
+                roll: Deg(0.0),
+                yaw: Deg(0.0),
+            },
+            cgmath::perspective(Deg(90.0), 1.0, 4.0, 4096.0),
+        )
+    }
+
+    #[test]
+    fn test_project_marker_on_screen() {
+        // the camera looks down the +x axis
+        let vp = camera().view_projection();
+        match project_marker(vp, Vector3::new(100.0, 0.0, 0.0)) {
+            MarkerPosition::OnScreen { x, y } => {
+                assert!(x.abs() < 0.001);
+                assert!(y.abs() < 0.001);
+            }
+            p => panic!("expected an on-screen marker, got {:?}", p),
+        }
+    }
+
+    #[test]
+    fn test_project_marker_off_screen() {
+        let vp = camera().view_projection();
+
+        // +y is to the left of the camera
+        match project_marker(vp, Vector3::new(10.0, 100.0, 0.0)) {
+            MarkerPosition::OffScreen { x, y } => {
+                assert_eq!(x, -EDGE_MARGIN);
+                assert!(y.abs() < 0.001);
+            }
+            p => panic!("expected an off-screen marker, got {:?}", p),
+        }
+
+        // behind and to the right of the camera, the marker still points right
+        match project_marker(vp, Vector3::new(-100.0, -50.0, 0.0)) {
+            MarkerPosition::OffScreen { x, .. } => assert_eq!(x, EDGE_MARGIN),
+            p => panic!("expected an off-screen marker, got {:?}", p),
+        }
+    }
+}
@@ -21,15 +21,23 @@
 use crate::common::console::{CvarRegistry, ConsoleError};
 
 pub fn register_cvars(cvars: &CvarRegistry) -> Result<(), ConsoleError> {
+    cvars.register("capture_fps", "30")?;
+    cvars.register_archive("cl_accept_addr", "")?;
     cvars.register("cl_anglespeedkey", "1.5")?;
     cvars.register_archive("cl_backspeed", "200")?;
+    cvars.register_archive("cl_bgpause", "1")?;
     cvars.register("cl_bob", "0.02")?;
     cvars.register("cl_bobcycle", "0.6")?;
     cvars.register("cl_bobup", "0.5")?;
+    cvars.register_archive("cl_captions", "0")?;
+    cvars.register_archive("cl_captions_distance", "1000")?;
     cvars.register_archive("_cl_color", "0")?;
     cvars.register("cl_crossx", "0")?;
     cvars.register("cl_crossy", "0")?;
+    cvars.register_archive("cl_download", "1")?;
     cvars.register_archive("cl_forwardspeed", "400")?;
+    cvars.register_archive("cl_manifest", "0")?;
+    cvars.register_archive("cl_master", "dpmaster.deathmask.net")?;
     cvars.register("cl_movespeedkey", "2.0")?;
     cvars.register_archive("_cl_name", "player")?;
     cvars.register("cl_nolerp", "0")?;
@@ -38,12 +46,35 @@ pub fn register_cvars(cvars: &CvarRegistry) -> Result<(), ConsoleError> {
     cvars.register("cl_rollspeed", "200")?;
     cvars.register("cl_shownet", "0")?;
     cvars.register("cl_sidespeed", "350")?;
+    cvars.register_archive("cl_teammarkers", "1")?;
     cvars.register("cl_upspeed", "200")?;
     cvars.register("cl_yawspeed", "140")?;
+    cvars.register_archive("clientport", "0")?;
     cvars.register("fov", "90")?;
+    cvars.register("host_framestep", "0")?;
+    cvars.register_archive("in_bgrelease", "1")?;
+    cvars.register_archive("joy_rumble", "1")?;
+    cvars.register_archive("joy_rumble_damage", "1")?;
+    cvars.register_archive("joy_rumble_fire", "0.5")?;
     cvars.register_archive("m_pitch", "0.022")?;
+    cvars.register_archive("m_rawinput", "1")?;
     cvars.register_archive("m_yaw", "0.022")?;
+    cvars.register("net_fakejitter", "0")?;
+    cvars.register("net_fakelag", "0")?;
+    cvars.register("net_fakeloss", "0")?;
+    cvars.register("net_fakereorder", "0")?;
+    cvars.register_archive("rate", "10000")?;
+    cvars.register("rcon_address", "")?;
+    cvars.register("rcon_password", "")?;
+    cvars.register("scr_centertime", "2")?;
+    cvars.register("scr_printspeed", "8")?;
     cvars.register_archive("sensitivity", "3")?;
+    cvars.register_archive("snd_bgvolume", "1")?;
+    cvars.register_archive("snd_device", "")?;
+    cvars.register_archive("snd_distance", "1")?;
+    cvars.register_archive("snd_output", "0")?;
+    cvars.register_archive("snd_rolloff", "0")?;
+    cvars.register_archive("snd_separation", "1")?;
     cvars.register("v_idlescale", "0")?;
     cvars.register("v_ipitch_cycle", "1")?;
     cvars.register("v_ipitch_level", "0.3")?;
@@ -58,6 +89,7 @@ pub fn register_cvars(cvars: &CvarRegistry) -> Result<(), ConsoleError> {
     // some server cvars are needed by the client, but if the server is running
     // in the same process they will have been set already, so we can ignore
     // the duplicate cvar error
+    let _ = cvars.register("net_messagetimeout", "300");
     let _ = cvars.register("sv_gravity", "800");
 
     Ok(())
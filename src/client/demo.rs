@@ -1,7 +1,11 @@
-use std::{io, ops::Range};
+use std::{
+    io::{self, Read},
+    ops::Range,
+};
 
 use crate::common::{
-    net::{self, NetError},
+    engine,
+    net::{self, NetError, Protocol, ServerCmd},
     util::read_f32_3,
     vfs::VirtualFile,
 };
@@ -9,6 +13,7 @@ use crate::common::{
 use arrayvec::ArrayVec;
 use byteorder::{LittleEndian, ReadBytesExt};
 use cgmath::{Deg, Vector3};
+use chrono::Duration;
 use io::BufReader;
 use thiserror::Error;
 
@@ -67,61 +72,15 @@ impl DemoServer {
     /// Construct a new `DemoServer` from the specified demo file.
     pub fn new(file: &mut VirtualFile) -> Result<DemoServer, DemoServerError> {
         let mut dem_reader = BufReader::new(file);
-
-        let mut buf = ArrayVec::<u8, 3>::new();
-        // copy CD track number (terminated by newline) into buffer
-        for i in 0..buf.capacity() {
-            match dem_reader.read_u8()? {
-                b'\n' => break,
-                // cannot panic because we won't exceed capacity with a loop this small
-                b => buf.push(b),
-            }
-
-            if i >= buf.capacity() - 1 {
-                // CD track would be more than 2 digits long, which is impossible
-                Err(DemoServerError::InvalidCdTrack)?;
-            }
-        }
-
-        let track_override = {
-            let track_str = match std::str::from_utf8(&buf) {
-                Ok(s) => s,
-                Err(_) => Err(DemoServerError::InvalidCdTrack)?,
-            };
-
-            match track_str {
-                // if track is empty, default to track 0
-                "" => Some(0),
-                s => match s.parse::<i32>() {
-                    Ok(track) => match track {
-                        // if track is -1, allow demo to specify tracks in messages
-                        -1 => None,
-                        t if t < -1 => Err(DemoServerError::InvalidCdTrack)?,
-                        _ => Some(track as u32),
-                    },
-                    Err(_) => Err(DemoServerError::InvalidCdTrack)?,
-                },
-            }
-        };
+        let track_override = read_track_override(&mut dem_reader)?;
 
         let mut message_data = Vec::new();
         let mut messages = Vec::new();
 
         // read all messages
-        while let Ok(msg_len) = dem_reader.read_u32::<LittleEndian>() {
-            // get view angles
-            let view_angles_f32 = read_f32_3(&mut dem_reader)?;
-            let view_angles = Vector3::new(
-                Deg(view_angles_f32[0]),
-                Deg(view_angles_f32[1]),
-                Deg(view_angles_f32[2]),
-            );
-
-            // read next message
+        while let Some((view_angles, msg)) = read_demo_message(&mut dem_reader)? {
             let msg_start = message_data.len();
-            for _ in 0..msg_len {
-                message_data.push(dem_reader.read_u8()?);
-            }
+            message_data.extend_from_slice(&msg);
             let msg_end = message_data.len();
 
             messages.push(DemoMessage {
@@ -164,3 +123,265 @@ impl DemoServer {
         self.track_override
     }
 }
+
+/// Reads the CD track number from the header of a demo file.
+///
+/// Returns `None` if the demo is allowed to specify its own tracks.
+fn read_track_override<R>(reader: &mut R) -> Result<Option<u32>, DemoServerError>
+where
+    R: Read,
+{
+    let mut buf = ArrayVec::<u8, 3>::new();
+    // copy CD track number (terminated by newline) into buffer
+    for i in 0..buf.capacity() {
+        match reader.read_u8()? {
+            b'\n' => break,
+            // cannot panic because we won't exceed capacity with a loop this small
+            b => buf.push(b),
+        }
+
+        if i >= buf.capacity() - 1 {
+            // CD track would be more than 2 digits long, which is impossible
+            Err(DemoServerError::InvalidCdTrack)?;
+        }
+    }
+
+    let track_str = match std::str::from_utf8(&buf) {
+        Ok(s) => s,
+        Err(_) => Err(DemoServerError::InvalidCdTrack)?,
+    };
+
+    Ok(match track_str {
+        // if track is empty, default to track 0
+        "" => Some(0),
+        s => match s.parse::<i32>() {
+            Ok(track) => match track {
+                // if track is -1, allow demo to specify tracks in messages
+                -1 => None,
+                t if t < -1 => Err(DemoServerError::InvalidCdTrack)?,
+                _ => Some(track as u32),
+            },
+            Err(_) => Err(DemoServerError::InvalidCdTrack)?,
+        },
+    })
+}
+
+/// Reads the view angles and server message of the next demo message.
+///
+/// Returns `None` once the end of the demo has been reached.
+fn read_demo_message<R>(
+    reader: &mut R,
+) -> Result<Option<(Vector3<Deg<f32>>, Vec<u8>)>, DemoServerError>
+where
+    R: Read,
+{
+    let msg_len = match reader.read_u32::<LittleEndian>() {
+        Ok(l) => l,
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => Err(e)?,
+    };
+
+    if msg_len as usize > net::MAX_MESSAGE {
+        Err(DemoServerError::MessageTooLong(msg_len))?;
+    }
+
+    // get view angles
+    let view_angles_f32 = read_f32_3(reader)?;
+    let view_angles = Vector3::new(
+        Deg(view_angles_f32[0]),
+        Deg(view_angles_f32[1]),
+        Deg(view_angles_f32[2]),
+    );
+
+    // read next message
+    let mut msg = vec![0; msg_len as usize];
+    reader.read_exact(&mut msg)?;
+
+    Ok(Some((view_angles, msg)))
+}
+
+/// A single message from a demo, parsed into server commands.
+#[derive(Debug)]
+pub struct DemoFrame {
+    time: Duration,
+    view_angles: Vector3<Deg<f32>>,
+    cmds: Vec<ServerCmd>,
+}
+
+impl DemoFrame {
+    /// Returns the most recent server time as of the end of this message.
+    ///
+    /// If the message contains no `Time` commands, this is the time of the
+    /// previous message.
+    pub fn time(&self) -> Duration {
+        self.time
+    }
+
+    /// Returns the view angles recorded for this demo message.
+    pub fn view_angles(&self) -> Vector3<Deg<f32>> {
+        self.view_angles
+    }
+
+    /// Returns the server commands contained in this demo message.
+    pub fn cmds(&self) -> &[ServerCmd] {
+        &self.cmds
+    }
+
+    /// Consumes the frame, returning its time, view angles and server commands.
+    pub fn into_parts(self) -> (Duration, Vector3<Deg<f32>>, Vec<ServerCmd>) {
+        (self.time, self.view_angles, self.cmds)
+    }
+}
+
+/// A streaming demo parser.
+///
+/// Unlike [`DemoServer`], a `DemoReader` requires no client state and reads
+/// messages lazily from any `Read` source, which makes it suitable for
+/// offline demo analysis.
+pub struct DemoReader<R>
+where
+    R: Read,
+{
+    reader: BufReader<R>,
+    track_override: Option<u32>,
+    protocol: Protocol,
+    time: Duration,
+    done: bool,
+}
+
+impl<R> DemoReader<R>
+where
+    R: Read,
+{
+    /// Constructs a new `DemoReader`, reading the demo header from `reader`.
+    pub fn new(reader: R) -> Result<DemoReader<R>, DemoServerError> {
+        let mut reader = BufReader::new(reader);
+        let track_override = read_track_override(&mut reader)?;
+
+        Ok(DemoReader {
+            reader,
+            track_override,
+            protocol: Protocol::NetQuake,
+            time: Duration::zero(),
+            done: false,
+        })
+    }
+
+    /// Returns the demo's music track override, if any.
+    pub fn track_override(&self) -> Option<u32> {
+        self.track_override
+    }
+
+    fn next_frame(&mut self) -> Result<Option<DemoFrame>, DemoServerError> {
+        let (view_angles, msg) = match read_demo_message(&mut self.reader)? {
+            Some(m) => m,
+            None => return Ok(None),
+        };
+
+        let mut cmds = Vec::new();
+        let mut msg_reader = BufReader::new(msg.as_slice());
+        while let Some(cmd) = ServerCmd::deserialize(&mut msg_reader, self.protocol)? {
+            match cmd {
+                ServerCmd::Time { time } => self.time = engine::duration_from_f32(time),
+
+                // later messages are parsed according to the recorded server's protocol
+                ServerCmd::ServerInfo {
+                    protocol_version, ..
+                } => {
+                    if let Some(p) = Protocol::from_version(protocol_version) {
+                        self.protocol = p;
+                    }
+                }
+
+                _ => (),
+            }
+
+            cmds.push(cmd);
+        }
+
+        Ok(Some(DemoFrame {
+            time: self.time,
+            view_angles,
+            cmds,
+        }))
+    }
+}
+
+impl<R> Iterator for DemoReader<R>
+where
+    R: Read,
+{
+    type Item = Result<DemoFrame, DemoServerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.next_frame() {
+            Ok(Some(frame)) => Some(Ok(frame)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+
+            // stop after the first error, since the stream is no longer in a
+            // known state
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use byteorder::WriteBytesExt;
+
+    fn write_demo_message(demo: &mut Vec<u8>, view_angles: [f32; 3], cmds: &[ServerCmd]) {
+        let mut msg = Vec::new();
+        for cmd in cmds {
+            cmd.serialize(&mut msg).unwrap();
+        }
+
+        demo.write_u32::<LittleEndian>(msg.len() as u32).unwrap();
+        for angle in view_angles.iter() {
+            demo.write_f32::<LittleEndian>(*angle).unwrap();
+        }
+        demo.extend_from_slice(&msg);
+    }
+
+    #[test]
+    fn test_demo_reader_frames() {
+        let mut demo = b"-1\n".to_vec();
+        write_demo_message(
+            &mut demo,
+            [0.0, 90.0, 0.0],
+            &[
+                ServerCmd::Time { time: 1.5 },
+                ServerCmd::Print {
+                    text: String::from("hello"),
+                },
+            ],
+        );
+        write_demo_message(&mut demo, [10.0, 180.0, 0.0], &[ServerCmd::NoOp]);
+
+        let mut reader = DemoReader::new(demo.as_slice()).unwrap();
+        assert_eq!(reader.track_override(), None);
+
+        let (time, view_angles, cmds) = reader.next().unwrap().unwrap().into_parts();
+        assert_eq!(time, Duration::milliseconds(1500));
+        assert_eq!(view_angles, Vector3::new(Deg(0.0), Deg(90.0), Deg(0.0)));
+        assert_eq!(cmds.len(), 2);
+
+        // time carries over from the previous message
+        let frame = reader.next().unwrap().unwrap();
+        assert_eq!(frame.time(), Duration::milliseconds(1500));
+        assert_eq!(frame.cmds(), &[ServerCmd::NoOp]);
+
+        assert!(reader.next().is_none());
+    }
+}
@@ -0,0 +1,651 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Downloading missing resources over HTTP.
+//!
+//! Servers can advertise a base URL in their `sv_downloadurl` rule. When a
+//! level needs models or sounds the client doesn't have, it asks the server
+//! for that rule and fetches each file from `<url>/<path>`, along with a
+//! checksum from `<url>/<path>.crc32` holding the file's CRC-32 in hex.
+//! Files that don't match their checksum are discarded. Only maps, models,
+//! sprites, sounds and colored lightmaps can be downloaded this way. Downloads
+//! are saved to the `downloads` subdirectory of the game directory, where the
+//! filesystem finds them when the level loads.
+//!
+//! Servers can also advertise a mod manifest in their `sv_manifesturl` rule
+//! (see the `manifest` module). If `cl_manifest` is set, every file in the
+//! manifest that the filesystem doesn't have is fetched first, and level
+//! resources the manifest covers aren't fetched again. Files that are already
+//! present are never replaced, though a warning is printed if they don't match
+//! the manifest.
+//!
+//! Asking the server for its rules can take a few seconds if it doesn't
+//! answer, so this is only done when files are missing or `cl_manifest` is
+//! set.
+
+use std::{
+    fs,
+    io::Read,
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread,
+    time::Duration as StdDuration,
+};
+
+use crate::{
+    client::manifest::{self, Manifest, ManifestEntry},
+    common::{console::Console, net::connect::ConnectSocket, vfs::Vfs},
+};
+
+use chrono::Duration;
+use failure::Error;
+
+/// The server rule naming the base URL for downloads.
+pub const DOWNLOAD_URL_RULE: &str = "sv_downloadurl";
+
+/// The server rule naming the URL of the mod manifest.
+pub const MANIFEST_URL_RULE: &str = "sv_manifesturl";
+
+// how long to wait for the server to list its rules
+const RULES_TIMEOUT_MS: i64 = 3000;
+
+// how long to wait for each HTTP request
+const HTTP_TIMEOUT: StdDuration = StdDuration::from_secs(30);
+
+// the largest file that will be downloaded
+const MAX_DOWNLOAD_SIZE: u64 = 64 * 1024 * 1024;
+
+// the directories and file types that level resources can be downloaded to
+const DOWNLOAD_DIRS: &[&str] = &["maps", "progs", "sound"];
+const DOWNLOAD_EXTENSIONS: &[&str] = &["bsp", "mdl", "spr", "wav", "lit"];
+
+/// Download settings, one field per cvar.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DownloadVars {
+    /// Whether to download missing level resources.
+    pub cl_download: f32,
+
+    /// Whether to check the server's mod manifest.
+    pub cl_manifest: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DownloadStatus {
+    /// The download hasn't been started.
+    Idle,
+
+    /// `done` of `total` files have been downloaded.
+    Running {
+        done: usize,
+        total: usize,
+    },
+
+    Finished,
+    Failed(String),
+}
+
+enum DownloadMsg {
+    // the manifest's entries, to be checked against the filesystem
+    Manifest(Vec<ManifestEntry>),
+
+    // the number of files to download, once the manifest has been checked
+    Total(usize),
+    Fetched(String),
+    Finished,
+    Failed(String),
+}
+
+/// Downloads a list of files on a background thread.
+pub struct Downloader {
+    files: Vec<String>,
+    status: DownloadStatus,
+    rx: Option<Receiver<DownloadMsg>>,
+
+    // answers Manifest messages with the entries that are missing
+    missing_tx: Option<Sender<Vec<ManifestEntry>>>,
+}
+
+impl Downloader {
+    pub fn new(files: Vec<String>) -> Downloader {
+        Downloader {
+            files,
+            status: DownloadStatus::Idle,
+            rx: None,
+            missing_tx: None,
+        }
+    }
+
+    /// Returns the status as of the last call to `poll`.
+    pub fn status(&self) -> &DownloadStatus {
+        &self.status
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.rx.is_some()
+    }
+
+    /// Start downloading from the server at `server`, saving files under
+    /// `dest`.
+    ///
+    /// If `check_manifest` is set, the server's mod manifest is checked too.
+    pub fn start(&mut self, server: SocketAddr, dest: PathBuf, check_manifest: bool) {
+        if self.is_started() {
+            return;
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let (missing_tx, missing_rx) = mpsc::channel();
+        let files = self.files.clone();
+        thread::spawn(move || download_all(server, files, check_manifest, dest, tx, missing_rx));
+
+        self.rx = Some(rx);
+        self.missing_tx = Some(missing_tx);
+        self.status = DownloadStatus::Running {
+            done: 0,
+            total: self.files.len(),
+        };
+    }
+
+    /// Check on the download, printing each file that arrives to `console`.
+    ///
+    /// Manifest entries are checked against `vfs`.
+    pub fn poll(&mut self, console: &Console, vfs: &Vfs) -> DownloadStatus {
+        let rx = match self.rx {
+            Some(ref rx) => rx,
+            None => return self.status.clone(),
+        };
+
+        loop {
+            let msg = match rx.try_recv() {
+                Ok(m) => m,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    if let DownloadStatus::Running { .. } = self.status {
+                        self.status = DownloadStatus::Failed(String::from("Download stopped"));
+                    }
+                    break;
+                }
+            };
+
+            match msg {
+                DownloadMsg::Manifest(entries) => {
+                    let missing = check_manifest(console, vfs, entries);
+                    if let Some(ref missing_tx) = self.missing_tx {
+                        let _ = missing_tx.send(missing);
+                    }
+                }
+                DownloadMsg::Total(n) => {
+                    if let DownloadStatus::Running { ref mut total, .. } = self.status {
+                        *total = n;
+                    }
+                }
+                DownloadMsg::Fetched(file) => {
+                    if let DownloadStatus::Running {
+                        ref mut done,
+                        total,
+                    } = self.status
+                    {
+                        *done += 1;
+                        console.println(format!("Downloaded {} ({}/{})", file, done, total));
+                    }
+                }
+                DownloadMsg::Finished => self.status = DownloadStatus::Finished,
+                DownloadMsg::Failed(e) => self.status = DownloadStatus::Failed(e),
+            }
+        }
+
+        self.status.clone()
+    }
+}
+
+// returns the manifest entries missing from `vfs`, warning about those that
+// are present but don't match
+fn check_manifest(console: &Console, vfs: &Vfs, entries: Vec<ManifestEntry>) -> Vec<ManifestEntry> {
+    let mut missing = Vec::new();
+    for entry in entries {
+        let mut data = Vec::new();
+        match vfs.open(&entry.path) {
+            Ok(mut f) => {
+                if f.read_to_end(&mut data).is_err() || !entry.verify(&data) {
+                    console.println(format!(
+                        "Warning: {} doesn't match the server's copy",
+                        entry.path
+                    ));
+                }
+            }
+            Err(_) => missing.push(entry),
+        }
+    }
+
+    missing
+}
+
+fn download_all(
+    server: SocketAddr,
+    files: Vec<String>,
+    check_manifest: bool,
+    dest: PathBuf,
+    tx: Sender<DownloadMsg>,
+    missing_rx: Receiver<Vec<ManifestEntry>>,
+) {
+    let result = download_files(server, files, check_manifest, &dest, &tx, &missing_rx);
+    let _ = tx.send(match result {
+        Ok(()) => DownloadMsg::Finished,
+        Err(e) => DownloadMsg::Failed(e.to_string()),
+    });
+}
+
+fn download_files(
+    server: SocketAddr,
+    mut files: Vec<String>,
+    check_manifest: bool,
+    dest: &Path,
+    tx: &Sender<DownloadMsg>,
+    missing_rx: &Receiver<Vec<ManifestEntry>>,
+) -> Result<(), Error> {
+    // don't keep the player waiting on the server's rules for nothing
+    if files.is_empty() && !check_manifest {
+        return Ok(());
+    }
+
+    let rules = match server_rules(server) {
+        Ok(r) => r,
+
+        // servers that don't answer can't have a manifest, and there's
+        // nothing else to fetch
+        Err(e) if files.is_empty() => {
+            debug!("Couldn't get server rules: {}", e);
+            return Ok(());
+        }
+
+        Err(e) => return Err(e),
+    };
+
+    let manifest_url = rules.manifest_url.filter(|_| check_manifest);
+    let mut missing = Vec::new();
+    if let Some(ref url) = manifest_url {
+        let manifest = fetch_manifest(url)?;
+        files.retain(|f| !manifest.contains(f));
+
+        // only the main thread can check the filesystem
+        let _ = tx.send(DownloadMsg::Manifest(manifest.entries().to_vec()));
+        missing = missing_rx.recv()?;
+    }
+
+    let base = match rules.download_url {
+        Some(ref base) => base.as_str(),
+        None if files.is_empty() => "",
+        None => bail!("Server doesn't offer downloads"),
+    };
+
+    let _ = tx.send(DownloadMsg::Total(missing.len() + files.len()));
+
+    if let Some(ref url) = manifest_url {
+        for entry in missing {
+            fetch_entry(manifest::base_url(url), &entry, dest)?;
+            let _ = tx.send(DownloadMsg::Fetched(entry.path));
+        }
+    }
+
+    for file in files {
+        fetch(base, &file, dest)?;
+        let _ = tx.send(DownloadMsg::Fetched(file));
+    }
+
+    Ok(())
+}
+
+struct DownloadRules {
+    download_url: Option<String>,
+    manifest_url: Option<String>,
+}
+
+// ask the server where to download from
+fn server_rules(server: SocketAddr) -> Result<DownloadRules, Error> {
+    let mut sock = ConnectSocket::bind_for(&server)?;
+    let rules = sock.query_rules(server, Duration::milliseconds(RULES_TIMEOUT_MS))?;
+    let find = |name: &str| {
+        rules
+            .iter()
+            .find(|r| r.cvar_name == name && !r.cvar_val.is_empty())
+            .map(|r| r.cvar_val.clone())
+    };
+
+    Ok(DownloadRules {
+        download_url: find(DOWNLOAD_URL_RULE),
+        manifest_url: find(MANIFEST_URL_RULE),
+    })
+}
+
+/// Returns `true` if `path` is a relative path that stays inside the
+/// directory it's joined to.
+///
+/// File names come from the server, so they have to be checked before
+/// anything is written.
+pub fn is_safe_path(path: &str) -> bool {
+    !path.is_empty()
+        && !path.contains('\\')
+        && Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Returns `true` if `path` is a level resource that can be downloaded.
+///
+/// Only files under `maps/`, `progs/` and `sound/` with the extension of a
+/// map, model, sprite, sound or colored lightmap are accepted, so servers
+/// can't replace configs or game code.
+pub fn is_downloadable(path: &str) -> bool {
+    if !is_safe_path(path) {
+        return false;
+    }
+
+    let path = Path::new(path);
+    let dir = path
+        .components()
+        .next()
+        .and_then(|c| c.as_os_str().to_str());
+    let ext = path.extension().and_then(|e| e.to_str());
+    match (dir, ext) {
+        (Some(dir), Some(ext)) => {
+            DOWNLOAD_DIRS.contains(&dir)
+                && DOWNLOAD_EXTENSIONS
+                    .iter()
+                    .any(|e| e.eq_ignore_ascii_case(ext))
+        }
+        _ => false,
+    }
+}
+
+/// Parse a checksum file, which holds a CRC-32 in hex, optionally followed by
+/// the file name as written by checksum tools.
+pub fn parse_checksum(text: &str) -> Result<u32, Error> {
+    let hex = text
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| format_err!("Empty checksum"))?;
+    u32::from_str_radix(hex, 16).map_err(|_| format_err!("Invalid checksum \"{}\"", hex))
+}
+
+fn http_get(url: &str) -> Result<Vec<u8>, Error> {
+    let response = ureq::get(url)
+        .timeout(HTTP_TIMEOUT)
+        .call()
+        .map_err(|e| format_err!("{}", e))?;
+
+    let mut data = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_DOWNLOAD_SIZE + 1)
+        .read_to_end(&mut data)?;
+    ensure!(
+        data.len() as u64 <= MAX_DOWNLOAD_SIZE,
+        "{} is larger than {} bytes",
+        url,
+        MAX_DOWNLOAD_SIZE
+    );
+
+    Ok(data)
+}
+
+fn fetch_manifest(url: &str) -> Result<Manifest, Error> {
+    debug!("Downloading manifest {}", url);
+    let data = http_get(url)?;
+    Manifest::parse(&String::from_utf8_lossy(&data))
+}
+
+// download `file` from `base` and save it under `dest`
+fn fetch(base: &str, file: &str, dest: &Path) -> Result<(), Error> {
+    ensure!(is_downloadable(file), "Refusing to download \"{}\"", file);
+
+    let url = format!("{}/{}", base.trim_end_matches('/'), file);
+    debug!("Downloading {}", url);
+    let data = http_get(&url)?;
+    let checksum = http_get(&format!("{}.crc32", url))?;
+
+    let expected = parse_checksum(&String::from_utf8_lossy(&checksum))?;
+    let actual = crc32fast::hash(&data);
+    ensure!(
+        actual == expected,
+        "Checksum mismatch for {} (expected {:08x}, got {:08x})",
+        file,
+        expected,
+        actual
+    );
+
+    save(dest, file, &data)
+}
+
+// download a manifest entry from `base` and save it under `dest`
+fn fetch_entry(base: &str, entry: &ManifestEntry, dest: &Path) -> Result<(), Error> {
+    ensure!(
+        is_downloadable(&entry.path),
+        "Refusing to download \"{}\"",
+        entry.path
+    );
+
+    let url = format!("{}/{}", base.trim_end_matches('/'), entry.path);
+    debug!("Downloading {}", url);
+    let data = http_get(&url)?;
+    ensure!(entry.verify(&data), "Checksum mismatch for {}", entry.path);
+
+    save(dest, &entry.path, &data)
+}
+
+fn save(dest: &Path, file: &str, data: &[u8]) -> Result<(), Error> {
+    // write to a temporary file first so an interrupted download doesn't
+    // leave a truncated file behind
+    let path = dest.join(file);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let part = dest.join(format!("{}.part", file));
+    fs::write(&part, data)?;
+    fs::rename(&part, &path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{
+        cell::RefCell,
+        io::Write,
+        net::{TcpListener, TcpStream},
+        rc::Rc,
+    };
+
+    use crate::common::console::{CmdRegistry, CvarRegistry};
+
+    // serve each (path, body) pair in order, one request per connection
+    fn serve(responses: Vec<(&'static str, Vec<u8>)>) -> (String, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}/q", listener.local_addr().unwrap());
+
+        let server = thread::spawn(move || {
+            for (path, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let request = read_request(&mut stream);
+                assert!(
+                    request.starts_with(&format!("GET /q/{} ", path)),
+                    "unexpected request {:?}",
+                    request
+                );
+
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+
+        (base, server)
+    }
+
+    fn read_request(stream: &mut TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(request).unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("richter-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_is_safe_path() {
+        assert!(is_safe_path("maps/e1m1.bsp"));
+        assert!(is_safe_path("sound/misc/talk.wav"));
+        assert!(!is_safe_path(""));
+        assert!(!is_safe_path("/etc/passwd"));
+        assert!(!is_safe_path("maps/../../config.cfg"));
+        assert!(!is_safe_path("..\\config.cfg"));
+    }
+
+    #[test]
+    fn test_is_downloadable() {
+        assert!(is_downloadable("maps/e1m1.bsp"));
+        assert!(is_downloadable("maps/e1m1.lit"));
+        assert!(is_downloadable("progs/player.mdl"));
+        assert!(is_downloadable("progs/s_light.spr"));
+        assert!(is_downloadable("sound/misc/talk.WAV"));
+        assert!(!is_downloadable("maps/../progs.dat"));
+        assert!(!is_downloadable("autoexec.cfg"));
+        assert!(!is_downloadable("progs.dat"));
+        assert!(!is_downloadable("maps/autoexec.cfg"));
+        assert!(!is_downloadable("gfx/player.mdl"));
+        assert!(!is_downloadable("maps"));
+    }
+
+    #[test]
+    fn test_parse_checksum() {
+        assert_eq!(parse_checksum("cbf43926\n").unwrap(), 0xcbf43926);
+        assert_eq!(
+            parse_checksum("CBF43926  maps/test.bsp\n").unwrap(),
+            0xcbf43926
+        );
+        assert!(parse_checksum("").is_err());
+        assert!(parse_checksum("not hex").is_err());
+    }
+
+    #[test]
+    fn test_fetch() {
+        let data = b"123456789".to_vec();
+        let (base, server) = serve(vec![
+            ("maps/test.bsp", data.clone()),
+            ("maps/test.bsp.crc32", b"cbf43926  test.bsp\n".to_vec()),
+        ]);
+
+        let dest = temp_dir("download");
+        fetch(&base, "maps/test.bsp", &dest).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(fs::read(dest.join("maps/test.bsp")).unwrap(), data);
+        assert!(!dest.join("maps/test.bsp.part").exists());
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_bad_checksum() {
+        let (base, server) = serve(vec![
+            ("progs/test.mdl", b"corrupted".to_vec()),
+            ("progs/test.mdl.crc32", b"cbf43926\n".to_vec()),
+        ]);
+
+        let dest = temp_dir("download-bad");
+        assert!(fetch(&base, "progs/test.mdl", &dest).is_err());
+        server.join().unwrap();
+
+        assert!(!dest.join("progs/test.mdl").exists());
+    }
+
+    #[test]
+    fn test_check_manifest() {
+        let dir = temp_dir("check-manifest");
+        fs::create_dir_all(&dir).unwrap();
+        fs::create_dir_all(dir.join("maps")).unwrap();
+        fs::write(dir.join("maps/good.bsp"), b"test").unwrap();
+        fs::write(dir.join("maps/bad.bsp"), b"old version").unwrap();
+
+        let mut vfs = Vfs::new();
+        vfs.add_directory(&dir).unwrap();
+        let names = Rc::new(RefCell::new(Vec::new()));
+        let cmds = Rc::new(RefCell::new(CmdRegistry::new(names.clone())));
+        let cvars = Rc::new(RefCell::new(CvarRegistry::new(names)));
+        let console = Console::new(cmds, cvars);
+
+        let manifest = Manifest::parse(
+            "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08  maps/good.bsp\n\
+             9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08  maps/bad.bsp\n\
+             9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08  maps/missing.bsp\n",
+        )
+        .unwrap();
+
+        // files that are present are never fetched, even if they don't match
+        let missing: Vec<String> = check_manifest(&console, &vfs, manifest.entries().to_vec())
+            .into_iter()
+            .map(|e| e.path)
+            .collect();
+        assert_eq!(missing, vec!["maps/missing.bsp"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_manifest_entry() {
+        let (base, server) = serve(vec![
+            (
+                "manifest.txt",
+                b"9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08  progs/flag.mdl\n"
+                    .to_vec(),
+            ),
+            ("progs/flag.mdl", b"test".to_vec()),
+        ]);
+
+        let dest = temp_dir("manifest");
+        let manifest = fetch_manifest(&format!("{}/manifest.txt", base)).unwrap();
+        assert_eq!(manifest.entries().len(), 1);
+        fetch_entry(&base, &manifest.entries()[0], &dest).unwrap();
+        server.join().unwrap();
+
+        assert!(manifest.entries()[0].verify(&fs::read(dest.join("progs/flag.mdl")).unwrap()));
+
+        // entries are checked again in case they didn't come from parse()
+        let config = ManifestEntry {
+            path: "autoexec.cfg".to_owned(),
+            sha256: [0; 32],
+        };
+        assert!(fetch_entry(&base, &config, &dest).is_err());
+        assert!(!dest.join("autoexec.cfg").exists());
+        fs::remove_dir_all(&dest).unwrap();
+    }
+}
@@ -19,22 +19,29 @@
 // SOFTWARE.
 
 pub mod particle;
+pub mod pool;
 
 use crate::common::{
-    alloc::LinkedSlab,
     engine,
     net::{EntityEffects, EntityState, EntityUpdate},
 };
 
-use cgmath::{Deg, Vector3};
+use cgmath::{Angle as _, Deg, InnerSpace as _, Rad, Vector3, Zero as _};
 use chrono::Duration;
+use pool::{EffectKey, EffectPool, EffectPriority};
 
 // if this is changed, it must also be changed in deferred.frag
 pub const MAX_LIGHTS: usize = 32;
 pub const MAX_BEAMS: usize = 24;
+
+/// The length of one bolt model in a beam.
+pub const BEAM_SEGMENT_LENGTH: f32 = 30.0;
 pub const MAX_TEMP_ENTITIES: usize = 64;
 pub const MAX_STATIC_ENTITIES: usize = 128;
 
+// models are animated at 10 frames per second
+const FRAME_INTERVAL: f32 = 0.1;
+
 #[derive(Debug)]
 pub struct ClientEntity {
     pub force_link: bool,
@@ -47,11 +54,14 @@ pub struct ClientEntity {
     pub model_id: usize,
     model_changed: bool,
     pub frame_id: usize,
+    // the frame being animated from, and when frame_id took over from it
+    prev_frame_id: usize,
+    frame_time: Duration,
     pub skin_id: usize,
     colormap: Option<u8>,
     pub sync_base: Duration,
     pub effects: EntityEffects,
-    pub light_id: Option<usize>,
+    pub light_id: Option<EffectKey>,
     // vis_frame: usize,
 }
 
@@ -71,6 +81,8 @@ impl ClientEntity {
             model_id: baseline.model_id,
             model_changed: false,
             frame_id: baseline.frame_id,
+            prev_frame_id: baseline.frame_id,
+            frame_time: Duration::zero(),
             skin_id: baseline.skin_id,
             colormap: None,
             sync_base: Duration::zero(),
@@ -94,6 +106,8 @@ impl ClientEntity {
             model_id: 0,
             model_changed: false,
             frame_id: 0,
+            prev_frame_id: 0,
+            frame_time: Duration::zero(),
             skin_id: 0,
             colormap: None,
             sync_base: Duration::zero(),
@@ -130,16 +144,62 @@ impl ClientEntity {
             self.model_id = new_state.model_id;
         }
 
-        self.frame_id = new_state.frame_id;
+        if new_state.frame_id != self.frame_id {
+            self.prev_frame_id = self.frame_id;
+            self.frame_time = msg_times[0];
+            self.frame_id = new_state.frame_id;
+        }
         self.skin_id = new_state.skin_id;
         self.effects = new_state.effects;
-        self.colormap = update.colormap;
+        self.colormap = match new_state.colormap {
+            0 => None,
+            c => Some(c),
+        };
 
         if self.force_link {
             self.msg_origins[1] = self.msg_origins[0];
             self.origin = self.msg_origins[0];
             self.msg_angles[1] = self.msg_angles[0];
             self.angles = self.msg_angles[0];
+            self.prev_frame_id = self.frame_id;
+        }
+    }
+
+    /// Interpolate the entity's origin and angles between its last two
+    /// updates.
+    ///
+    /// Entities that were force-linked or moved more than 100 units in one
+    /// update snap to their latest position instead.
+    pub fn lerp(&mut self, lerp_factor: f32) {
+        if self.force_link {
+            self.origin = self.msg_origins[0];
+            self.angles = self.msg_angles[0];
+            return;
+        }
+
+        let origin_delta = self.msg_origins[0] - self.msg_origins[1];
+        let lerp_factor = if origin_delta.magnitude2() > 10_000.0 {
+            // assume the entity was teleported and don't lerp anything
+            1.0
+        } else {
+            lerp_factor
+        };
+
+        self.origin = self.msg_origins[1] + lerp_factor * origin_delta;
+
+        // assume that entities will not whip around 180+ degrees in one frame
+        // and adjust the delta accordingly. this avoids a bug where small
+        // turns between 0 <-> 359 cause the demo camera to face backwards for
+        // one frame.
+        for i in 0..3 {
+            let mut angle_delta = self.msg_angles[0][i] - self.msg_angles[1][i];
+            if angle_delta > Deg(180.0) {
+                angle_delta = Deg(360.0) - angle_delta;
+            } else if angle_delta < Deg(-180.0) {
+                angle_delta = Deg(360.0) + angle_delta;
+            }
+
+            self.angles[i] = (self.msg_angles[1][i] + angle_delta * lerp_factor).normalize();
         }
     }
 
@@ -191,6 +251,14 @@ impl ClientEntity {
         self.frame_id
     }
 
+    /// Returns the frame this entity is animating from and how far it is into the change to
+    /// `frame_id` at `time`, between 0 and 1.
+    pub fn frame_blend(&self, time: Duration) -> (usize, f32) {
+        let elapsed = engine::duration_to_f32(time - self.frame_time);
+        let blend = (elapsed / FRAME_INTERVAL).max(0.0).min(1.0);
+        (self.prev_frame_id, blend)
+    }
+
     pub fn skin_id(&self) -> usize {
         self.skin_id
     }
@@ -268,57 +336,62 @@ impl Light {
 }
 
 /// A set of active dynamic lights.
+///
+/// The number of lights is bounded by the capacity. Once it is reached,
+/// lower-priority lights are evicted to make room for new ones.
 pub struct Lights {
-    slab: LinkedSlab<Light>,
+    pool: EffectPool<Light>,
 }
 
 impl Lights {
     /// Create an empty set of lights with the given capacity.
     pub fn with_capacity(capacity: usize) -> Lights {
         Lights {
-            slab: LinkedSlab::with_capacity(capacity),
+            pool: EffectPool::with_capacity(capacity),
         }
     }
 
     /// Return a reference to the light with the given key, or `None` if no
     /// such light exists.
-    pub fn get(&self, key: usize) -> Option<&Light> {
-        self.slab.get(key)
+    pub fn get(&self, key: EffectKey) -> Option<&Light> {
+        self.pool.get(key)
     }
 
     /// Return a mutable reference to the light with the given key, or `None`
     /// if no such light exists.
-    pub fn get_mut(&mut self, key: usize) -> Option<&mut Light> {
-        self.slab.get_mut(key)
+    pub fn get_mut(&mut self, key: EffectKey) -> Option<&mut Light> {
+        self.pool.get_mut(key)
     }
 
     /// Insert a new light into the set of lights.
     ///
-    /// Returns a key corresponding to the newly inserted light.
+    /// Returns a key corresponding to the newly inserted light, or `None` if
+    /// the set is full of higher-priority lights.
     ///
     /// If `key` is `Some` and there is an existing light with that key, then
-    /// the light will be overwritten with the new value.
-    pub fn insert(&mut self, time: Duration, desc: LightDesc, key: Option<usize>) -> usize {
-        if let Some(k) = key {
-            if let Some(key_light) = self.slab.get_mut(k) {
-                *key_light = Light::from_desc(time, desc);
-                return k;
-            }
-        }
-
-        self.slab.insert(Light::from_desc(time, desc))
+    /// the light will be overwritten with the new value. Keys to lights that
+    /// have since expired or been evicted are ignored.
+    pub fn insert(
+        &mut self,
+        time: Duration,
+        desc: LightDesc,
+        priority: EffectPriority,
+        key: Option<EffectKey>,
+    ) -> Option<EffectKey> {
+        self.pool
+            .replace(key, time, priority, Light::from_desc(time, desc))
     }
 
     /// Return an iterator over the active lights.
     pub fn iter(&self) -> impl Iterator<Item = &Light> {
-        self.slab.iter()
+        self.pool.iter()
     }
 
     /// Updates the set of dynamic lights for the specified time.
     ///
     /// This will deallocate any lights which have outlived their time-to-live.
     pub fn update(&mut self, time: Duration) {
-        self.slab.retain(|_, light| light.retain(time));
+        self.pool.retain(|light| light.retain(time));
     }
 }
 
@@ -330,3 +403,76 @@ pub struct Beam {
     pub start: Vector3<f32>,
     pub end: Vector3<f32>,
 }
+
+impl Beam {
+    /// Returns the pitch and yaw that point a bolt model from `start` to `end`.
+    pub fn angles(&self) -> (Deg<f32>, Deg<f32>) {
+        let vec = self.end - self.start;
+        let yaw = Deg::from(Rad(vec.y.atan2(vec.x))).normalize();
+        let forward = (vec.x.powi(2) + vec.y.powi(2)).sqrt();
+        let pitch = Deg::from(Rad(vec.z.atan2(forward))).normalize();
+        (pitch, yaw)
+    }
+
+    /// Returns the origin of each bolt model in the beam.
+    ///
+    /// Segments are placed every `BEAM_SEGMENT_LENGTH` units from `start`, so
+    /// the last one may reach past `end`.
+    pub fn segment_origins(&self) -> impl Iterator<Item = Vector3<f32>> {
+        let vec = self.end - self.start;
+        let len = vec.magnitude();
+        let direction = match len {
+            l if l > 0.0 => vec / l,
+            _ => Vector3::zero(),
+        };
+        let count = (len / BEAM_SEGMENT_LENGTH).ceil() as usize;
+
+        let start = self.start;
+        (0..count).map(move |i| start + BEAM_SEGMENT_LENGTH * i as f32 * direction)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn beam(end: Vector3<f32>) -> Beam {
+        Beam {
+            entity_id: 1,
+            model_id: 2,
+            expire: Duration::milliseconds(200),
+            start: Vector3::zero(),
+            end,
+        }
+    }
+
+    #[test]
+    fn test_beam_segments() {
+        let origins: Vec<_> = beam(Vector3::new(0.0, 100.0, 0.0))
+            .segment_origins()
+            .collect();
+        assert_eq!(
+            origins,
+            vec![
+                Vector3::new(0.0, 0.0, 0.0),
+                Vector3::new(0.0, 30.0, 0.0),
+                Vector3::new(0.0, 60.0, 0.0),
+                Vector3::new(0.0, 90.0, 0.0),
+            ]
+        );
+
+        assert_eq!(beam(Vector3::zero()).segment_origins().count(), 0);
+    }
+
+    #[test]
+    fn test_beam_angles() {
+        let close = |a: Deg<f32>, b: f32| (a.0 - b).abs() < 1e-4;
+
+        let (pitch, yaw) = beam(Vector3::new(0.0, 64.0, 0.0)).angles();
+        assert!(close(pitch, 0.0) && close(yaw, 90.0));
+
+        // straight up
+        let (pitch, yaw) = beam(Vector3::new(0.0, 0.0, 64.0)).angles();
+        assert!(close(pitch, 90.0) && close(yaw, 0.0));
+    }
+}
@@ -125,6 +125,9 @@ pub enum ParticleKind {
 /// Factor at which particles are affected by gravity.
 pub const PARTICLE_GRAVITY_FACTOR: f32 = 0.05;
 
+/// Rate at which blob particles speed up (or, without z-velocity, slow down).
+pub const BLOB_ACCELERATION: f32 = 4.0;
+
 /// A live particle.
 #[derive(Copy, Clone, Debug)]
 pub struct Particle {
@@ -186,11 +189,16 @@ impl Particle {
             },
 
             Blob { has_z_velocity } => {
+                let dvel = BLOB_ACCELERATION * velocity_factor;
+
                 if !has_z_velocity {
                     let xy_velocity = Vector3::new(self.velocity.x, self.velocity.y, 0.0);
                     self.origin += xy_velocity * velocity_factor;
+                    // the flat ring slows down as it spreads
+                    self.velocity -= xy_velocity * dvel;
                 } else {
                     self.origin += self.velocity * velocity_factor;
+                    self.velocity += self.velocity * dvel;
                     self.velocity.z -= gravity;
                 }
 
@@ -615,7 +623,11 @@ impl Particles {
                 _ => 0.0,
             };
 
-        let ttl = Duration::seconds(2);
+        let ttl = match kind {
+            TracerGreen | TracerRed => Duration::milliseconds(500),
+            Vore => Duration::milliseconds(300),
+            _ => Duration::seconds(2),
+        };
 
         for step in 0..(distance / interval) as i32 {
             let frame_skip = FRAME_SKIP_DISTRIBUTION.sample(&mut self.rng);
@@ -631,7 +643,7 @@ impl Particles {
             let scatter = self.random_vector3(&SCATTER_DISTRIBUTION);
 
             let origin = start
-                + direction * interval
+                + direction * interval * step as f32
                 + match kind {
                     // vore scatter is [-16, 15] in original
                     // this gives range of ~[-16, 16]
@@ -715,4 +727,25 @@ mod tests {
             .zip(expected.iter())
             .for_each(|(p1, p2)| assert!(particles_eq(p1, p2)));
     }
+
+    #[test]
+    fn test_trail_spans_path() {
+        let mut list = Particles::with_capacity(MAX_PARTICLES);
+        let start = Vector3::zero();
+        let end = Vector3::new(64.0, 0.0, 0.0);
+        list.create_trail(Duration::zero(), start, end, TrailKind::Vore, true);
+
+        // sparse trail: one particle every 3 units
+        assert_eq!(list.iter().count(), 21);
+
+        // vore scatter is at most 16 units in any direction
+        let max_x = list.iter().map(|p| p.origin().x).fold(f32::MIN, f32::max);
+        assert!(max_x > 60.0 - 16.0);
+        assert!(list.iter().all(|p| p.origin().x < 64.0 + 16.0));
+
+        // vore trails fade after 300ms
+        let time = Duration::milliseconds(300);
+        list.update(time, Duration::milliseconds(17), 800.0);
+        assert_eq!(list.iter().count(), 0);
+    }
 }
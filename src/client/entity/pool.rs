@@ -0,0 +1,305 @@
+// Copyright © 2020 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use chrono::Duration;
+
+/// The relative importance of a short-lived effect.
+///
+/// When an `EffectPool` is full, lower-priority effects are evicted first.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum EffectPriority {
+    /// Ambient effects that are refreshed every frame, like entity glows.
+    Low,
+
+    /// Ordinary effects like muzzle flashes and beams.
+    Normal,
+
+    /// Effects the player should never miss, like explosions.
+    High,
+}
+
+/// Identifies an effect in an `EffectPool`.
+///
+/// Keys carry the generation of their slot, so a key to an effect that has
+/// expired or been evicted doesn't refer to the effect that took its place.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct EffectKey {
+    index: usize,
+    generation: u32,
+}
+
+struct PoolEntry<T> {
+    priority: EffectPriority,
+    spawned: Duration,
+    value: T,
+}
+
+/// A fixed-capacity pool of short-lived effects.
+///
+/// Once the pool is full, inserting a new effect evicts the lowest-priority
+/// effect in the pool, choosing the oldest if there is a tie. If every effect
+/// in the pool has a higher priority than the new one, the new effect is
+/// dropped instead.
+pub struct EffectPool<T> {
+    slots: Vec<Option<PoolEntry<T>>>,
+
+    // incremented each time a slot's effect is removed, invalidating its key
+    generations: Vec<u32>,
+
+    len: usize,
+}
+
+impl<T> EffectPool<T> {
+    /// Create an empty pool which holds at most `capacity` effects.
+    pub fn with_capacity(capacity: usize) -> EffectPool<T> {
+        let mut slots = Vec::with_capacity(capacity);
+        slots.resize_with(capacity, || None);
+        EffectPool {
+            slots,
+            generations: vec![0; capacity],
+            len: 0,
+        }
+    }
+
+    /// Return the maximum number of effects the pool can hold.
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Return the number of active effects.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Return `true` if there are no active effects.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Remove all effects from the pool.
+    pub fn clear(&mut self) {
+        for index in 0..self.slots.len() {
+            if self.slots[index].is_some() {
+                self.remove_slot(index);
+            }
+        }
+    }
+
+    /// Return a reference to the effect with the given key, or `None` if no
+    /// such effect exists.
+    pub fn get(&self, key: EffectKey) -> Option<&T> {
+        if !self.is_current(key) {
+            return None;
+        }
+
+        self.slots[key.index].as_ref().map(|e| &e.value)
+    }
+
+    /// Return a mutable reference to the effect with the given key, or `None`
+    /// if no such effect exists.
+    pub fn get_mut(&mut self, key: EffectKey) -> Option<&mut T> {
+        if !self.is_current(key) {
+            return None;
+        }
+
+        self.slots[key.index].as_mut().map(|e| &mut e.value)
+    }
+
+    /// Insert a new effect into the pool, evicting an existing effect if the
+    /// pool is full.
+    ///
+    /// Returns the key of the new effect, or `None` if it was dropped.
+    pub fn insert(
+        &mut self,
+        time: Duration,
+        priority: EffectPriority,
+        value: T,
+    ) -> Option<EffectKey> {
+        let index = match self.slots.iter().position(|s| s.is_none()) {
+            Some(i) => i,
+            None => {
+                let victim = self.eviction_candidate()?;
+                if self.slots[victim].as_ref().unwrap().priority > priority {
+                    return None;
+                }
+
+                self.remove_slot(victim);
+                victim
+            }
+        };
+
+        self.slots[index] = Some(PoolEntry {
+            priority,
+            spawned: time,
+            value,
+        });
+        self.len += 1;
+
+        Some(EffectKey {
+            index,
+            generation: self.generations[index],
+        })
+    }
+
+    /// Replace the effect with the given key, or insert a new effect if no
+    /// such effect exists.
+    ///
+    /// A key whose effect has expired or been evicted doesn't replace the
+    /// effect that took its place.
+    pub fn replace(
+        &mut self,
+        key: Option<EffectKey>,
+        time: Duration,
+        priority: EffectPriority,
+        value: T,
+    ) -> Option<EffectKey> {
+        if let Some(k) = key {
+            if self.is_current(k) {
+                if let Some(ref mut entry) = self.slots[k.index] {
+                    *entry = PoolEntry {
+                        priority,
+                        spawned: time,
+                        value,
+                    };
+                    return Some(k);
+                }
+            }
+        }
+
+        self.insert(time, priority, value)
+    }
+
+    /// Return an iterator over the active effects.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.slots
+            .iter()
+            .filter_map(|s| s.as_ref().map(|e| &e.value))
+    }
+
+    /// Retain only the effects specified by the predicate.
+    ///
+    /// The predicate is permitted to modify effects in-place.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        for index in 0..self.slots.len() {
+            let retain = match self.slots[index] {
+                Some(ref mut e) => f(&mut e.value),
+                None => true,
+            };
+
+            if !retain {
+                self.remove_slot(index);
+            }
+        }
+    }
+
+    fn is_current(&self, key: EffectKey) -> bool {
+        self.generations.get(key.index) == Some(&key.generation)
+    }
+
+    fn remove_slot(&mut self, index: usize) {
+        self.slots[index] = None;
+        self.generations[index] = self.generations[index].wrapping_add(1);
+        self.len -= 1;
+    }
+
+    // lowest priority first, then oldest
+    fn eviction_candidate(&self) -> Option<usize> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(k, s)| s.as_ref().map(|e| (k, e)))
+            .min_by_key(|(_, e)| (e.priority, e.spawned))
+            .map(|(k, _)| k)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evict_lowest_priority() {
+        let mut pool = EffectPool::with_capacity(2);
+        let t = Duration::zero();
+        pool.insert(t, EffectPriority::High, "explosion").unwrap();
+        pool.insert(t, EffectPriority::Low, "spark").unwrap();
+
+        assert!(pool.insert(t, EffectPriority::Normal, "flash").is_some());
+        let mut active: Vec<_> = pool.iter().cloned().collect();
+        active.sort();
+        assert_eq!(active, vec!["explosion", "flash"]);
+    }
+
+    #[test]
+    fn test_evict_oldest_on_tie() {
+        let mut pool = EffectPool::with_capacity(2);
+        pool.insert(Duration::seconds(1), EffectPriority::High, 1)
+            .unwrap();
+        let newer = pool
+            .insert(Duration::seconds(2), EffectPriority::High, 2)
+            .unwrap();
+
+        let key = pool
+            .insert(Duration::seconds(3), EffectPriority::High, 3)
+            .unwrap();
+        assert_ne!(key, newer);
+        assert_eq!(pool.get(newer), Some(&2));
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn test_drop_lower_priority_when_full() {
+        let mut pool = EffectPool::with_capacity(1);
+        let t = Duration::zero();
+        pool.insert(t, EffectPriority::High, "explosion").unwrap();
+
+        assert_eq!(pool.insert(t, EffectPriority::Low, "spark"), None);
+        assert_eq!(pool.iter().next(), Some(&"explosion"));
+    }
+
+    #[test]
+    fn test_reject_stale_key() {
+        let mut pool = EffectPool::with_capacity(1);
+        let t = Duration::zero();
+        let glow = pool.insert(t, EffectPriority::Low, "glow").unwrap();
+
+        // evicted
+        let explosion = pool.insert(t, EffectPriority::High, "explosion").unwrap();
+        assert_ne!(explosion, glow);
+        assert_eq!(pool.get(glow), None);
+
+        // the stale key doesn't overwrite the explosion
+        assert_eq!(
+            pool.replace(Some(glow), t, EffectPriority::Low, "glow"),
+            None
+        );
+        assert_eq!(pool.get(explosion), Some(&"explosion"));
+
+        // expired
+        pool.retain(|_| false);
+        let flash = pool.insert(t, EffectPriority::Normal, "flash").unwrap();
+        assert_eq!(pool.get(explosion), None);
+        assert_eq!(pool.get(flash), Some(&"flash"));
+        assert_eq!(pool.len(), 1);
+    }
+}
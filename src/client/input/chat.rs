@@ -0,0 +1,104 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{client::chat, common::console::Console};
+
+use failure::Error;
+use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode as Key, WindowEvent};
+
+// longest message that can be typed
+const MAX_MESSAGE_LEN: usize = 100;
+
+/// Captures a chat message typed in `messagemode`.
+pub struct ChatInput {
+    console: Rc<RefCell<Console>>,
+    text: String,
+    team: bool,
+}
+
+impl ChatInput {
+    pub fn new(console: Rc<RefCell<Console>>) -> ChatInput {
+        ChatInput {
+            console,
+            text: String::new(),
+            team: false,
+        }
+    }
+
+    /// Start typing a new message, to the whole server or only to `team`.
+    pub fn begin(&mut self, team: bool) {
+        self.text.clear();
+        self.team = team;
+    }
+
+    /// The message typed so far.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn team(&self) -> bool {
+        self.team
+    }
+
+    /// Handle an input event, returning `true` once the message has been sent or cancelled.
+    pub fn handle_event<T>(&mut self, event: Event<T>) -> Result<bool, Error> {
+        match event {
+            Event::WindowEvent { event, .. } => match event {
+                WindowEvent::ReceivedCharacter(c) => match c {
+                    '\r' | '\n' => {
+                        if !self.text.trim().is_empty() {
+                            self.console
+                                .borrow()
+                                .stuff_text(chat::say_command(&self.text, self.team));
+                        }
+                        return Ok(true);
+                    }
+
+                    '\x08' => {
+                        self.text.pop();
+                    }
+
+                    c if c.is_control() => (),
+
+                    c => {
+                        if self.text.chars().count() < MAX_MESSAGE_LEN {
+                            self.text.push(c);
+                        }
+                    }
+                },
+
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            virtual_keycode: Some(Key::Escape),
+                            state: ElementState::Pressed,
+                            ..
+                        },
+                    ..
+                } => return Ok(true),
+
+                _ => (),
+            },
+
+            _ => (),
+        }
+
+        Ok(false)
+    }
+}
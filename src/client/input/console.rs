@@ -49,7 +49,10 @@ impl ConsoleInput {
                     Key::Down => self.console.borrow_mut().history_down(),
                     Key::Left => self.console.borrow_mut().cursor_left(),
                     Key::Right => self.console.borrow_mut().cursor_right(),
+                    Key::PageUp => self.console.borrow().page_up(),
+                    Key::PageDown => self.console.borrow().page_down(),
                     Key::Grave => self.console.borrow_mut().stuff_text("toggleconsole\n"),
+                    Key::Escape => self.console.borrow_mut().stuff_text("togglemenu\n"),
                     _ => (),
                 },
 
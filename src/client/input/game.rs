@@ -17,7 +17,7 @@
 
 use std::{
     cell::{Cell, RefCell},
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     rc::Rc,
     str::FromStr,
     string::ToString,
@@ -41,6 +41,9 @@ use winit::{
 
 const ACTION_COUNT: usize = 19;
 
+// aliases nested deeper than this aren't followed when listing bindings
+const MAX_BIND_ALIAS_DEPTH: usize = 16;
+
 static INPUT_NAMES: [&'static str; 79] = [
     ",",
     ".",
@@ -469,7 +472,7 @@ impl ToString for BindTarget {
                     + &action.to_string()
             }
 
-            BindTarget::ConsoleInput { ref text } => format!("\"{}\"", text.to_owned()),
+            BindTarget::ConsoleInput { ref text } => text.to_owned(),
         }
     }
 }
@@ -477,23 +480,53 @@ impl ToString for BindTarget {
 #[derive(Clone)]
 pub struct GameInput {
     console: Rc<RefCell<Console>>,
+    aliases: Rc<RefCell<HashMap<String, String>>>,
     bindings: Rc<RefCell<HashMap<BindInput, BindTarget>>>,
     action_states: Rc<RefCell<[bool; ACTION_COUNT]>>,
     mouse_delta: (f64, f64),
     impulse: Rc<Cell<u8>>,
+
+    // if true, mouse motion comes from raw device events and bypasses pointer
+    // acceleration. otherwise it's measured from the cursor position.
+    raw_mouse: bool,
+    cursor_pos: Option<(f64, f64)>,
 }
 
 impl GameInput {
     pub fn new(console: Rc<RefCell<Console>>) -> GameInput {
+        let aliases = console.borrow().aliases();
         GameInput {
             console,
+            aliases,
             bindings: Rc::new(RefCell::new(HashMap::new())),
             action_states: Rc::new(RefCell::new([false; ACTION_COUNT])),
             mouse_delta: (0.0, 0.0),
             impulse: Rc::new(Cell::new(0)),
+            raw_mouse: true,
+            cursor_pos: None,
         }
     }
 
+    /// Choose between raw mouse motion and cursor motion.
+    ///
+    /// Raw motion is unaffected by the OS pointer acceleration settings.
+    pub fn set_raw_mouse(&mut self, raw: bool) {
+        if raw != self.raw_mouse {
+            self.raw_mouse = raw;
+            self.cursor_pos = None;
+        }
+    }
+
+    pub fn raw_mouse(&self) -> bool {
+        self.raw_mouse
+    }
+
+    /// Record that the cursor was moved to `pos` by the program rather than
+    /// the user, so the move isn't counted as mouse motion.
+    pub fn warp_cursor(&mut self, pos: (f64, f64)) {
+        self.cursor_pos = Some(pos);
+    }
+
     pub fn mouse_delta(&self) -> (f64, f64) {
         self.mouse_delta
     }
@@ -516,6 +549,7 @@ impl GameInput {
         self.bind(Key::LControl, BindTarget::from_str("+attack").unwrap());
         self.bind(Key::E, BindTarget::from_str("+use").unwrap());
         self.bind(Key::Grave, BindTarget::from_str("toggleconsole").unwrap());
+        self.bind(Key::T, BindTarget::from_str("messagemode").unwrap());
         self.bind(Key::Key1, BindTarget::from_str("impulse 1").unwrap());
         self.bind(Key::Key2, BindTarget::from_str("impulse 2").unwrap());
         self.bind(Key::Key3, BindTarget::from_str("impulse 3").unwrap());
@@ -546,6 +580,57 @@ impl GameInput {
         self.bindings.borrow().get(&input.into()).map(|t| t.clone())
     }
 
+    /// Return every binding as a (key, command) pair, sorted by key.
+    pub fn bindings(&self) -> Vec<(String, String)> {
+        let mut bindings: Vec<_> = self
+            .bindings
+            .borrow()
+            .iter()
+            .map(|(i, t)| (i.to_string(), t.to_string()))
+            .collect();
+        bindings.sort();
+        bindings
+    }
+
+    /// Return the names of the inputs that run `target`, sorted.
+    ///
+    /// This includes inputs bound to aliases that run `target`.
+    pub fn inputs_bound_to(&self, target: &str) -> Vec<String> {
+        let aliases = self.aliases.borrow();
+        let mut inputs: Vec<_> = self
+            .bindings
+            .borrow()
+            .iter()
+            .filter(|(_, t)| runs_command(t, &aliases, target))
+            .map(|(i, _)| i.to_string())
+            .collect();
+        inputs.sort();
+        inputs
+    }
+
+    /// Return the names of the inputs that start more than one action, sorted.
+    ///
+    /// An input can only be bound to one command, so these are inputs bound
+    /// to aliases like `alias jumpfire "+jump; +attack"`.
+    pub fn conflicting_inputs(&self) -> Vec<String> {
+        expand_bindings(&self.bindings.borrow(), &self.aliases.borrow())
+            .into_iter()
+            .filter(|(_, commands)| is_conflict(commands))
+            .map(|(input, _)| input)
+            .collect()
+    }
+
+    /// Unbind every input that runs `target`.
+    ///
+    /// Like [`inputs_bound_to`](GameInput::inputs_bound_to), this includes
+    /// inputs bound to aliases that run `target`.
+    pub fn unbind_target(&mut self, target: &str) {
+        let aliases = self.aliases.borrow();
+        self.bindings
+            .borrow_mut()
+            .retain(|_, t| !runs_command(t, &aliases, target));
+    }
+
     pub fn handle_event<T>(&mut self, outer_event: Event<T>) {
         let (input, state): (BindInput, _) = match outer_event {
             Event::WindowEvent { event, .. } => match event {
@@ -561,11 +646,21 @@ impl GameInput {
 
                 WindowEvent::MouseInput { state, button, .. } => (button.into(), state),
                 WindowEvent::MouseWheel { delta, .. } => (delta.into(), ElementState::Pressed),
+
+                WindowEvent::CursorMoved { position, .. } if !self.raw_mouse => {
+                    if let Some((x, y)) = self.cursor_pos {
+                        self.mouse_delta.0 += position.x - x;
+                        self.mouse_delta.1 += position.y - y;
+                    }
+                    self.cursor_pos = Some((position.x, position.y));
+                    return;
+                }
+
                 _ => return,
             },
 
             Event::DeviceEvent { event, .. } => match event {
-                DeviceEvent::MouseMotion { delta } => {
+                DeviceEvent::MouseMotion { delta } if self.raw_mouse => {
                     self.mouse_delta.0 += delta.0;
                     self.mouse_delta.1 += delta.1;
                     return;
@@ -598,11 +693,16 @@ impl GameInput {
                     );
                 }
 
-                BindTarget::ConsoleInput { ref text } => {
-                    if state == ElementState::Pressed {
-                        self.console.borrow_mut().stuff_text(text);
+                BindTarget::ConsoleInput { ref text } => match state {
+                    ElementState::Pressed => self.console.borrow_mut().stuff_text(text),
+
+                    // a command starting with + gets its - counterpart on release
+                    ElementState::Released => {
+                        if let Some(release) = release_command(text) {
+                            self.console.borrow_mut().stuff_text(release);
+                        }
                     }
-                }
+                },
             }
         }
     }
@@ -647,20 +747,24 @@ impl GameInput {
                     },
 
                     // bind (key) [command]
-                    2 => match BindInput::from_str(args[0]) {
-                        Ok(input) => match BindTarget::from_str(args[1]) {
-                            Ok(target) => {
-                                bindings.borrow_mut().insert(input, target);
-                                debug!("Bound {:?} to {:?}", input, args[1]);
-                                String::new()
-                            }
-                            Err(_) => {
-                                format!("\"{}\" isn't a valid bind target", args[1])
-                            }
-                        },
-
-                        Err(_) => format!("\"{}\" isn't a valid key", args[0]),
-                    },
+                    // unquoted commands with arguments (bind 1 impulse 1) are joined
+                    n if n >= 2 => {
+                        let command = args[1..].join(" ");
+                        match BindInput::from_str(args[0]) {
+                            Ok(input) => match BindTarget::from_str(&command) {
+                                Ok(target) => {
+                                    bindings.borrow_mut().insert(input, target);
+                                    debug!("Bound {:?} to {:?}", input, command);
+                                    String::new()
+                                }
+                                Err(_) => {
+                                    format!("\"{}\" isn't a valid bind target", command)
+                                }
+                            },
+
+                            Err(_) => format!("\"{}\" isn't a valid key", args[0]),
+                        }
+                    }
 
                     _ => "bind [key] (command): attach a command to a key".to_owned(),
                 }
@@ -668,6 +772,35 @@ impl GameInput {
         )
         .unwrap();
 
+        // "bindlist"
+        let bindings = self.bindings.clone();
+        let aliases = self.aliases.clone();
+        cmds.insert_or_replace(
+            "bindlist",
+            Box::new(move |args| match args.len() {
+                0 => bind_list(&bindings.borrow(), &aliases.borrow()),
+                _ => "bindlist: list keybindings by the command they run".to_owned(),
+            }),
+        )
+        .unwrap();
+
+        // "unbind"
+        let bindings = self.bindings.clone();
+        cmds.insert_or_replace(
+            "unbind",
+            Box::new(move |args| match args.len() {
+                1 => match BindInput::from_str(args[0]) {
+                    Ok(i) => {
+                        bindings.borrow_mut().remove(&i);
+                        String::new()
+                    }
+                    Err(_) => format!("\"{}\" isn't a valid key", args[0]),
+                },
+                _ => "unbind [key]: remove the binding for a key".to_owned(),
+            }),
+        )
+        .unwrap();
+
         // "unbindall"
         let bindings = self.bindings.clone();
         cmds.insert_or_replace(
@@ -687,7 +820,6 @@ impl GameInput {
         cmds.insert_or_replace(
             "impulse",
             Box::new(move |args| {
-                println!("args: {}", args.len());
                 match args.len() {
                     1 => match u8::from_str(args[0]) {
                         Ok(i) => {
@@ -704,6 +836,12 @@ impl GameInput {
         .unwrap();
     }
 
+    /// Stop all actions, as if every key had been released.
+    pub fn release_all(&mut self) {
+        *self.action_states.borrow_mut() = [false; ACTION_COUNT];
+        self.clear_mouse();
+    }
+
     // must be called every frame!
     pub fn refresh(&mut self) {
         self.clear_mouse();
@@ -721,6 +859,117 @@ impl GameInput {
     }
 }
 
+// Return each command run by a binding to `text`, in order and with aliases
+// expanded.
+fn expand_commands(text: &str, aliases: &HashMap<String, String>) -> Vec<String> {
+    fn expand(text: &str, aliases: &HashMap<String, String>, depth: usize, out: &mut Vec<String>) {
+        for command in text.split(|c: char| c == ';' || c == '\n') {
+            let command = command.trim();
+            let name = match command.split_whitespace().next() {
+                Some(n) => n,
+                None => continue,
+            };
+
+            match aliases.get(name) {
+                // an alias that invokes itself is only followed so far
+                Some(script) => {
+                    if depth < MAX_BIND_ALIAS_DEPTH {
+                        expand(script, aliases, depth + 1, out);
+                    }
+                }
+
+                None => {
+                    if !out.iter().any(|c| c == command) {
+                        out.push(command.to_owned());
+                    }
+                }
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    expand(text, aliases, 0, &mut out);
+    out
+}
+
+// Return the name of every input and the commands it runs, sorted by name.
+fn expand_bindings(
+    bindings: &HashMap<BindInput, BindTarget>,
+    aliases: &HashMap<String, String>,
+) -> Vec<(String, Vec<String>)> {
+    let mut expanded: Vec<_> = bindings
+        .iter()
+        .map(|(i, t)| (i.to_string(), expand_commands(&t.to_string(), aliases)))
+        .collect();
+    expanded.sort();
+    expanded
+}
+
+// Returns `true` if `target` runs `command`, either directly or through an
+// alias.
+fn runs_command(target: &BindTarget, aliases: &HashMap<String, String>, command: &str) -> bool {
+    expand_commands(&target.to_string(), aliases)
+        .iter()
+        .any(|c| c == command)
+}
+
+// Returns `true` if `commands` start more than one action.
+fn is_conflict(commands: &[String]) -> bool {
+    commands.iter().filter(|c| c.starts_with('+')).count() > 1
+}
+
+// Output of the `bindlist` command: each command followed by the inputs that
+// run it, then any inputs that start several actions at once.
+fn bind_list(
+    bindings: &HashMap<BindInput, BindTarget>,
+    aliases: &HashMap<String, String>,
+) -> String {
+    let expanded = expand_bindings(bindings, aliases);
+
+    let mut by_command: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (input, commands) in expanded.iter() {
+        for command in commands.iter() {
+            by_command.entry(command).or_default().push(input);
+        }
+    }
+
+    let mut lines: Vec<String> = by_command
+        .iter()
+        .map(|(command, inputs)| format!("{:<20} {}", command, inputs.join(", ")))
+        .collect();
+
+    for (input, commands) in expanded.iter().filter(|(_, c)| is_conflict(c)) {
+        lines.push(format!(
+            "conflict: \"{}\" starts {}",
+            input,
+            commands
+                .iter()
+                .filter(|c| c.starts_with('+'))
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(" and ")
+        ));
+    }
+
+    lines.join("\n")
+}
+
+// Return the command to run when a key bound to `text` is released.
+//
+// As in the original engine, only the first command is released, so
+// "+zoom; echo zooming" releases with "-zoom".
+fn release_command(text: &str) -> Option<String> {
+    if !text.starts_with('+') {
+        return None;
+    }
+
+    let first = text[1..]
+        .split(|c: char| c == ';' || c.is_whitespace())
+        .next()
+        .unwrap_or("");
+    Some(format!("-{}", first))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -740,4 +989,81 @@ mod test {
 
         assert_eq!(target.to_string(), "+forward");
     }
+
+    #[test]
+    fn test_bind_target_from_str() {
+        match BindTarget::from_str("+attack").unwrap() {
+            BindTarget::Action { trigger, action } => {
+                assert_eq!(trigger, ElementState::Pressed);
+                assert_eq!(action, Action::Attack);
+            }
+            t => panic!("expected action, got {:?}", t),
+        }
+
+        // commands and unknown +commands are passed to the console
+        for text in &["impulse 7", "+zoom"] {
+            let target = BindTarget::from_str(text).unwrap();
+            assert_eq!(target.to_string(), *text);
+        }
+    }
+
+    #[test]
+    fn test_bind_list_conflicts() {
+        let mut aliases = HashMap::new();
+        aliases.insert("jumpfire".to_owned(), "+jump; +attack".to_owned());
+        aliases.insert("loop".to_owned(), "loop".to_owned());
+
+        let mut bindings = HashMap::new();
+        for (input, target) in &[
+            ("space", "+jump"),
+            ("mouse1", "+attack"),
+            ("e", "jumpfire"),
+            ("l", "loop"),
+        ] {
+            bindings.insert(
+                BindInput::from_str(input).unwrap(),
+                BindTarget::from_str(target).unwrap(),
+            );
+        }
+
+        let expanded = expand_bindings(&bindings, &aliases);
+        let conflicts: Vec<_> = expanded
+            .iter()
+            .filter(|(_, c)| is_conflict(c))
+            .map(|(i, _)| i.as_str())
+            .collect();
+        assert_eq!(conflicts, vec!["E"]);
+
+        let list = bind_list(&bindings, &aliases);
+        let lines: Vec<_> = list.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("+attack") && lines[0].ends_with("E, MOUSE1"));
+        assert!(lines[1].starts_with("+jump") && lines[1].ends_with("E, SPACE"));
+        assert_eq!(lines[2], "conflict: \"E\" starts +jump and +attack");
+    }
+
+    #[test]
+    fn test_runs_command_through_alias() {
+        let mut aliases = HashMap::new();
+        aliases.insert("jumpfire".to_owned(), "+jump; +attack".to_owned());
+
+        let alias = BindTarget::from_str("jumpfire").unwrap();
+        assert!(runs_command(&alias, &aliases, "+jump"));
+        assert!(runs_command(&alias, &aliases, "+attack"));
+        assert!(!runs_command(&alias, &aliases, "+forward"));
+
+        let action = BindTarget::from_str("+jump").unwrap();
+        assert!(runs_command(&action, &aliases, "+jump"));
+        assert!(!runs_command(&action, &aliases, "jumpfire"));
+    }
+
+    #[test]
+    fn test_release_command() {
+        assert_eq!(release_command("+zoom"), Some("-zoom".to_owned()));
+        assert_eq!(
+            release_command("+zoom; echo zooming"),
+            Some("-zoom".to_owned())
+        );
+        assert_eq!(release_command("impulse 7"), None);
+    }
 }
@@ -0,0 +1,303 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Controller rumble in response to game events.
+//!
+//! Rumble is only produced while a `RumbleDevice` is attached with
+//! `Haptics::set_device`. The frontend attaches a `GamepadRumble` at startup,
+//! which drives whichever force-feedback gamepad was used last.
+
+use chrono::Duration;
+use gilrs::{
+    ff::{BaseEffect, BaseEffectType, Effect, EffectBuilder, Replay, Ticks},
+    Event, EventType, GamepadId, Gilrs,
+};
+
+// damage (armor + health) that produces full-strength rumble
+const FULL_RUMBLE_DAMAGE: f32 = 30.0;
+
+const DAMAGE_RUMBLE_MIN_MS: i64 = 150;
+const DAMAGE_RUMBLE_MAX_MS: i64 = 400;
+const FIRE_RUMBLE_MS: i64 = 80;
+
+/// A controller capable of force feedback.
+pub trait RumbleDevice {
+    /// Start rumbling, replacing any effect already playing.
+    ///
+    /// `strong` drives the low-frequency motor and `weak` the high-frequency
+    /// motor, each from 0 to 1.
+    fn rumble(&mut self, strong: f32, weak: f32, duration: Duration);
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HapticsVars {
+    pub joy_rumble: f32,
+    pub joy_rumble_damage: f32,
+    pub joy_rumble_fire: f32,
+}
+
+impl Default for HapticsVars {
+    fn default() -> HapticsVars {
+        HapticsVars {
+            joy_rumble: 1.0,
+            joy_rumble_damage: 1.0,
+            joy_rumble_fire: 0.5,
+        }
+    }
+}
+
+/// A game event that can cause rumble.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HapticEvent {
+    /// The player took damage, as reported by the server's damage message.
+    Damage { armor: u8, health: u8 },
+
+    /// The player fired their weapon.
+    WeaponFire,
+}
+
+/// A single rumble effect.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rumble {
+    pub strong: f32,
+    pub weak: f32,
+    pub duration: Duration,
+}
+
+impl Rumble {
+    /// Return the rumble for `event`, or `None` if it's turned off.
+    pub fn for_event(event: HapticEvent, vars: HapticsVars) -> Option<Rumble> {
+        let scale = vars.joy_rumble.max(0.0).min(1.0);
+
+        let rumble = match event {
+            HapticEvent::Damage { armor, health } => {
+                let damage = armor as f32 + health as f32;
+                let amount = (damage / FULL_RUMBLE_DAMAGE).min(1.0);
+                let duration_ms = DAMAGE_RUMBLE_MIN_MS
+                    + ((DAMAGE_RUMBLE_MAX_MS - DAMAGE_RUMBLE_MIN_MS) as f32 * amount) as i64;
+                let strength = amount * vars.joy_rumble_damage.max(0.0).min(1.0) * scale;
+
+                Rumble {
+                    strong: strength,
+                    weak: strength * 0.5,
+                    duration: Duration::milliseconds(duration_ms),
+                }
+            }
+
+            HapticEvent::WeaponFire => Rumble {
+                strong: 0.0,
+                weak: vars.joy_rumble_fire.max(0.0).min(1.0) * scale,
+                duration: Duration::milliseconds(FIRE_RUMBLE_MS),
+            },
+        };
+
+        if rumble.strong <= 0.0 && rumble.weak <= 0.0 {
+            return None;
+        }
+
+        Some(rumble)
+    }
+}
+
+/// Rumble on gamepads through gilrs.
+pub struct GamepadRumble {
+    gilrs: Gilrs,
+    active: Option<GamepadId>,
+
+    // the effect playing on the active gamepad, stopped when dropped
+    effect: Option<Effect>,
+}
+
+impl GamepadRumble {
+    /// Open the platform's gamepad backend, or return `None` if there isn't one.
+    pub fn new() -> Option<GamepadRumble> {
+        match Gilrs::new() {
+            Ok(gilrs) => Some(GamepadRumble {
+                gilrs,
+                active: None,
+                effect: None,
+            }),
+            Err(e) => {
+                warn!("Gamepad rumble unavailable: {}", e);
+                None
+            }
+        }
+    }
+
+    // make the last force-feedback gamepad with any input the active one
+    fn update_active(&mut self) {
+        while let Some(Event { id, event, .. }) = self.gilrs.next_event() {
+            match event {
+                EventType::Disconnected => {
+                    if self.active == Some(id) {
+                        self.active = None;
+                        self.effect = None;
+                    }
+                }
+                _ => {
+                    if self.gilrs.gamepad(id).is_ff_supported() {
+                        self.active = Some(id);
+                    }
+                }
+            }
+        }
+
+        if self.active.is_none() {
+            self.active = self
+                .gilrs
+                .gamepads()
+                .find(|(_, pad)| pad.is_ff_supported())
+                .map(|(id, _)| id);
+        }
+    }
+}
+
+impl RumbleDevice for GamepadRumble {
+    fn rumble(&mut self, strong: f32, weak: f32, duration: Duration) {
+        self.update_active();
+        let id = match self.active {
+            Some(id) => id,
+            None => return,
+        };
+
+        let scheduling = Replay {
+            play_for: Ticks::from_ms(duration.num_milliseconds().max(0) as u32),
+            ..Default::default()
+        };
+        let magnitude = |f: f32| (f.max(0.0).min(1.0) * u16::MAX as f32) as u16;
+
+        let effect = EffectBuilder::new()
+            .add_effect(BaseEffect {
+                kind: BaseEffectType::Strong {
+                    magnitude: magnitude(strong),
+                },
+                scheduling,
+                ..Default::default()
+            })
+            .add_effect(BaseEffect {
+                kind: BaseEffectType::Weak {
+                    magnitude: magnitude(weak),
+                },
+                scheduling,
+                ..Default::default()
+            })
+            .gamepads(&[id])
+            .finish(&mut self.gilrs)
+            .and_then(|effect| effect.play().map(|_| effect));
+
+        match effect {
+            // replaces (and so stops) the previous effect
+            Ok(effect) => self.effect = Some(effect),
+            Err(e) => warn!("Couldn't rumble gamepad: {}", e),
+        }
+    }
+}
+
+/// Turns game events into rumble on the active controller.
+pub struct Haptics {
+    device: Option<Box<dyn RumbleDevice>>,
+    vars: HapticsVars,
+}
+
+impl Haptics {
+    pub fn new() -> Haptics {
+        Haptics {
+            device: None,
+            vars: HapticsVars::default(),
+        }
+    }
+
+    /// Attach the controller to rumble, or detach it with `None`.
+    pub fn set_device(&mut self, device: Option<Box<dyn RumbleDevice>>) {
+        self.device = device;
+    }
+
+    pub fn has_device(&self) -> bool {
+        self.device.is_some()
+    }
+
+    pub fn set_vars(&mut self, vars: HapticsVars) {
+        self.vars = vars;
+    }
+
+    /// Rumble the controller in response to `event`.
+    pub fn trigger(&mut self, event: HapticEvent) {
+        let device = match self.device {
+            Some(ref mut d) => d,
+            None => return,
+        };
+
+        if let Some(rumble) = Rumble::for_event(event, self.vars) {
+            device.rumble(rumble.strong, rumble.weak, rumble.duration);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_damage_rumble_scales_with_damage() {
+        let vars = HapticsVars::default();
+
+        let light = Rumble::for_event(
+            HapticEvent::Damage {
+                armor: 0,
+                health: 3,
+            },
+            vars,
+        )
+        .unwrap();
+        let heavy = Rumble::for_event(
+            HapticEvent::Damage {
+                armor: 20,
+                health: 40,
+            },
+            vars,
+        )
+        .unwrap();
+
+        assert!(light.strong < heavy.strong);
+        assert!(light.duration < heavy.duration);
+        assert_eq!(heavy.strong, 1.0);
+        assert_eq!(heavy.duration, Duration::milliseconds(DAMAGE_RUMBLE_MAX_MS));
+    }
+
+    #[test]
+    fn test_rumble_disabled() {
+        let vars = HapticsVars {
+            joy_rumble: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(Rumble::for_event(HapticEvent::WeaponFire, vars), None);
+
+        let vars = HapticsVars {
+            joy_rumble_fire: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(Rumble::for_event(HapticEvent::WeaponFire, vars), None);
+        assert!(Rumble::for_event(
+            HapticEvent::Damage {
+                armor: 5,
+                health: 5
+            },
+            vars
+        )
+        .is_some());
+    }
+}
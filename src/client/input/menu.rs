@@ -15,9 +15,15 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, rc::Rc, str::FromStr};
 
-use crate::{client::menu::Menu, common::console::Console};
+use crate::{
+    client::{
+        input::game::{BindInput, BindTarget, GameInput},
+        menu::{Item, Menu},
+    },
+    common::console::Console,
+};
 
 use failure::Error;
 use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode as Key, WindowEvent};
@@ -32,10 +38,98 @@ impl MenuInput {
         MenuInput { menu, console }
     }
 
-    pub fn handle_event<T>(&self, event: Event<T>) -> Result<(), Error> {
+    /// Update the keys shown next to each binding in the menu.
+    pub fn refresh_bindings(&self, game_input: &GameInput) {
+        let conflicts = game_input.conflicting_inputs();
+        for binding in self.menu.borrow().bindings() {
+            let keys = game_input.inputs_bound_to(binding.command());
+            binding.set_conflicts(
+                keys.iter()
+                    .filter(|k| conflicts.contains(k))
+                    .cloned()
+                    .collect(),
+            );
+            binding.set_keys(keys);
+            binding.set_listening(false);
+        }
+    }
+
+    // Bind the next key or mouse button pressed to the selected binding.
+    //
+    // Returns `true` if the event was used.
+    fn handle_listening<T>(
+        &self,
+        event: &Event<T>,
+        game_input: &mut GameInput,
+    ) -> Result<bool, Error> {
+        let menu = self.menu.borrow();
+        let binding = match menu.selected()? {
+            Item::Binding(ref b) if b.listening() => b,
+            _ => return Ok(false),
+        };
+
+        let input: BindInput = match *event {
+            Event::WindowEvent { ref event, .. } => match *event {
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            virtual_keycode: Some(key),
+                            state: ElementState::Pressed,
+                            ..
+                        },
+                    ..
+                } => match key {
+                    // escape cancels
+                    Key::Escape => {
+                        binding.set_listening(false);
+                        return Ok(true);
+                    }
+
+                    k => k.into(),
+                },
+
+                WindowEvent::MouseInput {
+                    state: ElementState::Pressed,
+                    button,
+                    ..
+                } => button.into(),
+
+                _ => return Ok(true),
+            },
+
+            _ => return Ok(true),
+        };
+
+        // inputs without a name can't be saved to the config
+        if !input.to_string().is_empty() {
+            game_input.bind(input, BindTarget::from_str(binding.command())?);
+        }
+
+        binding.set_listening(false);
+        drop(menu);
+        self.refresh_bindings(game_input);
+
+        Ok(true)
+    }
+
+    pub fn handle_event<T>(
+        &self,
+        event: Event<T>,
+        game_input: &mut GameInput,
+    ) -> Result<(), Error> {
+        if self.handle_listening(&event, game_input)? {
+            return Ok(());
+        }
+
         match event {
             Event::WindowEvent { event, .. } => match event {
-                WindowEvent::ReceivedCharacter(_) => (),
+                WindowEvent::ReceivedCharacter(c) => {
+                    if let Item::TextField(ref text) = self.menu.borrow().selected()? {
+                        if !c.is_control() {
+                            text.insert(c);
+                        }
+                    }
+                }
 
                 WindowEvent::KeyboardInput {
                     input:
@@ -56,10 +150,39 @@ impl MenuInput {
 
                     Key::Up => self.menu.borrow().prev()?,
                     Key::Down => self.menu.borrow().next()?,
-                    Key::Return => self.menu.borrow().activate()?,
+                    Key::Return => match self.menu.borrow().selected()? {
+                        Item::Binding(ref b) => b.set_listening(true),
+                        _ => self.menu.borrow().activate()?,
+                    },
                     Key::Left => self.menu.borrow().left()?,
                     Key::Right => self.menu.borrow().right()?,
 
+                    Key::Back | Key::Delete => match self.menu.borrow().selected()? {
+                        Item::Binding(ref b) => {
+                            game_input.unbind_target(b.command());
+                            b.set_keys(Vec::new());
+                        }
+
+                        Item::TextField(ref text) => match key {
+                            Key::Back => text.backspace(),
+                            _ => text.delete(),
+                        },
+
+                        _ => (),
+                    },
+
+                    Key::Home => {
+                        if let Item::TextField(ref text) = self.menu.borrow().selected()? {
+                            text.home();
+                        }
+                    }
+
+                    Key::End => {
+                        if let Item::TextField(ref text) = self.menu.borrow().selected()? {
+                            text.end();
+                        }
+                    }
+
                     _ => (),
                 },
 
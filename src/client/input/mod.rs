@@ -15,8 +15,10 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+pub mod chat;
 pub mod console;
 pub mod game;
+pub mod haptics;
 pub mod menu;
 
 use std::{cell::RefCell, rc::Rc};
@@ -27,28 +29,107 @@ use crate::{
 };
 
 use failure::Error;
-use winit::event::{Event, WindowEvent};
+use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode as Key, WindowEvent};
 
 use self::{
+    chat::ChatInput,
     console::ConsoleInput,
     game::{BindInput, BindTarget, GameInput},
     menu::MenuInput,
 };
 
+// Scancodes of the key below Escape, which opens the console whatever it's
+// labeled. Non-US layouts put other characters there (`^`, `§`, `½`...) which
+// don't always map to `VirtualKeyCode::Grave`.
+#[cfg(target_os = "macos")]
+const CONSOLE_KEY_SCANCODES: &[u32] = &[
+    0x32, // ANSI grave
+    0x0A, // ISO section
+];
+#[cfg(not(target_os = "macos"))]
+const CONSOLE_KEY_SCANCODES: &[u32] = &[0x29];
+
+/// Returns `true` if `input` is from the console key.
+pub fn is_console_key(input: &KeyboardInput) -> bool {
+    input.virtual_keycode == Some(Key::Grave) || CONSOLE_KEY_SCANCODES.contains(&input.scancode)
+}
+
+// returns the scancode of the key whose character shouldn't reach the console
+// after `input`, given the key that was waiting before it
+fn key_to_swallow(swallow: Option<u32>, input: &KeyboardInput) -> Option<u32> {
+    match input.state {
+        ElementState::Pressed => match is_console_key(input) {
+            true => Some(input.scancode),
+            false => None,
+        },
+
+        // a dead key types nothing by itself, so stop waiting for its
+        // character once it's released
+        ElementState::Released if swallow == Some(input.scancode) => None,
+        ElementState::Released => swallow,
+    }
+}
+
+/// Which consumer receives keyboard and mouse input.
+///
+/// Input goes to exactly one consumer at a time. Held game buttons are
+/// released whenever focus leaves the game.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum InputFocus {
     Game,
     Console,
     Menu,
+
+    /// Typing a chat message with `messagemode` or `messagemode2`.
+    Chat,
+}
+
+impl InputFocus {
+    /// Returns the focus after `toggleconsole`.
+    ///
+    /// Without a connection there's no game to go back to, so closing the
+    /// console opens the menu instead.
+    pub fn toggle_console(self, connected: bool) -> InputFocus {
+        match (self, connected) {
+            (InputFocus::Console, true) => InputFocus::Game,
+            (InputFocus::Console, false) => InputFocus::Menu,
+            _ => InputFocus::Console,
+        }
+    }
+
+    /// Returns the focus after `togglemenu`.
+    ///
+    /// Without a connection, closing the menu drops to the console.
+    pub fn toggle_menu(self, connected: bool) -> InputFocus {
+        match (self, connected) {
+            (InputFocus::Menu, true) => InputFocus::Game,
+            (InputFocus::Menu, false) => InputFocus::Console,
+            _ => InputFocus::Menu,
+        }
+    }
+
+    /// Returns `true` if the mouse should be grabbed and hidden.
+    ///
+    /// Only the game uses mouse motion; everywhere else the cursor is
+    /// released so it can leave the window.
+    pub fn grabs_mouse(self) -> bool {
+        self == InputFocus::Game
+    }
 }
 
 pub struct Input {
+    console: Rc<RefCell<Console>>,
     window_focused: bool,
     focus: InputFocus,
 
+    // set to the console key's scancode when it's pressed so the character it
+    // types isn't sent to the console
+    swallow_char: Option<u32>,
+
     game_input: GameInput,
     console_input: ConsoleInput,
     menu_input: MenuInput,
+    chat_input: ChatInput,
 }
 
 impl Input {
@@ -58,29 +139,85 @@ impl Input {
         menu: Rc<RefCell<Menu>>,
     ) -> Input {
         Input {
+            console: console.clone(),
             window_focused: true,
             focus: init_focus,
+            swallow_char: None,
 
             game_input: GameInput::new(console.clone()),
             console_input: ConsoleInput::new(console.clone()),
             menu_input: MenuInput::new(menu.clone(), console.clone()),
+            chat_input: ChatInput::new(console.clone()),
         }
     }
 
-    pub fn handle_event<T>(&mut self, event: Event<T>) -> Result<(), Error> {
+    pub fn handle_event<T>(&mut self, mut event: Event<T>) -> Result<(), Error> {
+        match event {
+            Event::WindowEvent {
+                event: WindowEvent::KeyboardInput { ref mut input, .. },
+                ..
+            } => {
+                self.swallow_char = key_to_swallow(self.swallow_char, input);
+
+                // the console key is always handled as grave, so bindings
+                // work on any keyboard layout
+                if is_console_key(input) {
+                    input.virtual_keycode = Some(Key::Grave);
+                }
+
+                // keys bound to toggleconsole also work outside the game
+                if self.focus != InputFocus::Game && input.state == ElementState::Pressed {
+                    if let Some(BindTarget::ConsoleInput { text }) = input
+                        .virtual_keycode
+                        .and_then(|k| self.game_input.binding(k))
+                    {
+                        if text.trim() == "toggleconsole" {
+                            self.swallow_char = Some(input.scancode);
+                            self.console.borrow().stuff_text("toggleconsole");
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+
+            Event::WindowEvent {
+                event: WindowEvent::ReceivedCharacter(_),
+                ..
+            } if self.swallow_char.is_some() => {
+                self.swallow_char = None;
+                return Ok(());
+            }
+
+            _ => (),
+        }
+
         match event {
             // we're polling for hardware events, so we have to check window focus ourselves
             Event::WindowEvent {
                 event: WindowEvent::Focused(focused),
                 ..
-            } => self.window_focused = focused,
+            } => {
+                // we won't see key releases while another window has focus
+                if !focused {
+                    self.game_input.release_all();
+                }
+
+                self.window_focused = focused;
+            }
 
             _ => {
                 if self.window_focused {
                     match self.focus {
                         InputFocus::Game => self.game_input.handle_event(event),
                         InputFocus::Console => self.console_input.handle_event(event)?,
-                        InputFocus::Menu => self.menu_input.handle_event(event)?,
+                        InputFocus::Menu => {
+                            self.menu_input.handle_event(event, &mut self.game_input)?
+                        }
+                        InputFocus::Chat => {
+                            if self.chat_input.handle_event(event)? {
+                                self.focus = InputFocus::Game;
+                            }
+                        }
                     }
                 }
             }
@@ -89,14 +226,60 @@ impl Input {
         Ok(())
     }
 
+    /// Choose between raw mouse motion and cursor motion for game input.
+    pub fn set_raw_mouse(&mut self, raw: bool) {
+        self.game_input.set_raw_mouse(raw);
+    }
+
+    /// Returns `true` if the game window has keyboard focus.
+    pub fn window_focused(&self) -> bool {
+        self.window_focused
+    }
+
     pub fn focus(&self) -> InputFocus {
         self.focus
     }
 
     pub fn set_focus(&mut self, new_focus: InputFocus) {
+        // keys held down when the game loses focus won't see their release
+        // events, so let go of everything now
+        if self.focus == InputFocus::Game && new_focus != InputFocus::Game {
+            self.game_input.release_all();
+        }
+
+        // bindings may have changed since the menu was last open
+        if new_focus == InputFocus::Menu {
+            self.menu_input.refresh_bindings(&self.game_input);
+        }
+
         self.focus = new_focus;
     }
 
+    /// Open or close the console.
+    pub fn toggle_console(&mut self, connected: bool) {
+        self.set_focus(self.focus.toggle_console(connected));
+    }
+
+    /// Open or close the menu.
+    pub fn toggle_menu(&mut self, connected: bool) {
+        self.set_focus(self.focus.toggle_menu(connected));
+    }
+
+    /// Start typing a chat message, to everyone or only to `team`.
+    pub fn begin_chat(&mut self, team: bool) {
+        self.chat_input.begin(team);
+        self.set_focus(InputFocus::Chat);
+    }
+
+    /// Returns the chat message being typed, if any.
+    pub fn chat_input(&self) -> Option<&ChatInput> {
+        if let InputFocus::Chat = self.focus {
+            Some(&self.chat_input)
+        } else {
+            None
+        }
+    }
+
     /// Bind a `BindInput` to a `BindTarget`.
     pub fn bind<I, T>(&mut self, input: I, target: T) -> Option<BindTarget>
     where
@@ -110,6 +293,11 @@ impl Input {
         self.game_input.bind_defaults();
     }
 
+    /// Return every key binding as a (key, command) pair.
+    pub fn bindings(&self) -> Vec<(String, String)> {
+        self.game_input.bindings()
+    }
+
     pub fn game_input(&self) -> Option<&GameInput> {
         if let InputFocus::Game = self.focus {
             Some(&self.game_input)
@@ -130,3 +318,69 @@ impl Input {
         self.game_input.register_cmds(cmds);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_toggle_console() {
+        use InputFocus::*;
+
+        for from in [Game, Menu, Chat].iter() {
+            assert_eq!(from.toggle_console(true), Console);
+            assert_eq!(from.toggle_console(false), Console);
+        }
+
+        assert_eq!(Console.toggle_console(true), Game);
+        assert_eq!(Console.toggle_console(false), Menu);
+    }
+
+    #[test]
+    fn test_toggle_menu() {
+        use InputFocus::*;
+
+        for from in [Game, Console, Chat].iter() {
+            assert_eq!(from.toggle_menu(true), Menu);
+            assert_eq!(from.toggle_menu(false), Menu);
+        }
+
+        assert_eq!(Menu.toggle_menu(true), Game);
+        assert_eq!(Menu.toggle_menu(false), Console);
+
+        // only the game holds on to the mouse
+        assert!(Game.grabs_mouse());
+        assert!(!Chat.grabs_mouse());
+    }
+
+    #[allow(deprecated)]
+    fn key(scancode: u32, state: ElementState, keycode: Option<Key>) -> KeyboardInput {
+        KeyboardInput {
+            scancode,
+            state,
+            virtual_keycode: keycode,
+            modifiers: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_dead_console_key_is_forgotten() {
+        use ElementState::*;
+
+        let console = Some(Key::Grave);
+        let swallow = key_to_swallow(None, &key(0x29, Pressed, console));
+        assert_eq!(swallow, Some(0x29));
+
+        // releasing another key doesn't matter
+        assert_eq!(key_to_swallow(swallow, &key(0x11, Released, None)), swallow);
+
+        // a dead key sends no character, so its release ends the wait
+        assert_eq!(key_to_swallow(swallow, &key(0x29, Released, console)), None);
+
+        // as does the next key pressed
+        assert_eq!(
+            key_to_swallow(swallow, &key(0x1E, Pressed, Some(Key::A))),
+            None
+        );
+    }
+}
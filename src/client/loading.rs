@@ -0,0 +1,237 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Incremental loading of the precache lists sent in `ServerInfo`.
+//!
+//! Loading every model and sound for a level can take seconds, so it's spread
+//! over several frames. This keeps the window responsive and lets the loading
+//! screen show progress. The server isn't asked for the sign-on data until
+//! everything has loaded, since that data refers to the precached models.
+
+use std::{collections::VecDeque, time::Instant};
+
+use crate::{
+    client::{download, sound::AudioSource, state::ClientState, ClientError},
+    common::{bsp, model::Model, parse, vfs::Vfs},
+};
+
+/// How much of the precache lists have been loaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoadProgress {
+    pub models_loaded: usize,
+    pub models_total: usize,
+    pub sounds_loaded: usize,
+    pub sounds_total: usize,
+}
+
+impl LoadProgress {
+    /// Returns the fraction of all precached resources that have loaded.
+    pub fn fraction(&self) -> f32 {
+        let total = self.models_total + self.sounds_total;
+        match total {
+            0 => 1.0,
+            t => (self.models_loaded + self.sounds_loaded) as f32 / t as f32,
+        }
+    }
+}
+
+/// Loads precached models and sounds into a `ClientState` a few at a time.
+pub struct PrecacheLoader {
+    models: VecDeque<String>,
+    sounds: VecDeque<String>,
+    progress: LoadProgress,
+}
+
+impl PrecacheLoader {
+    pub fn new(model_precache: Vec<String>, sound_precache: Vec<String>) -> PrecacheLoader {
+        PrecacheLoader {
+            progress: LoadProgress {
+                models_loaded: 0,
+                models_total: model_precache.len(),
+                sounds_loaded: 0,
+                sounds_total: sound_precache.len(),
+            },
+            models: model_precache.into(),
+            sounds: sound_precache.into(),
+        }
+    }
+
+    pub fn progress(&self) -> LoadProgress {
+        self.progress
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.models.is_empty() && self.sounds.is_empty()
+    }
+
+    /// Returns the paths of the resources that aren't in `vfs` and can be
+    /// downloaded.
+    pub fn missing(&self, vfs: &Vfs) -> Vec<String> {
+        self.models
+            .iter()
+            // model names starting with * are loaded from the world BSP
+            .filter(|m| !m.starts_with('*'))
+            .cloned()
+            .chain(self.sounds.iter().map(|s| format!("sound/{}", s)))
+            .filter(|path| download::is_downloadable(path) && !vfs.exists(path))
+            .collect()
+    }
+
+    /// Load resources into `state` until `deadline` passes.
+    ///
+    /// At least one resource is loaded per call. Returns `true` once
+    /// everything has been loaded.
+    pub fn load(
+        &mut self,
+        vfs: &Vfs,
+        state: &mut ClientState,
+        deadline: Instant,
+    ) -> Result<bool, ClientError> {
+        loop {
+            if let Some(mod_name) = self.models.pop_front() {
+                load_model(vfs, state, mod_name)?;
+                self.progress.models_loaded += 1;
+            } else if let Some(snd_name) = self.sounds.pop_front() {
+                debug!("Loading sound {}: {}", state.sounds.len(), snd_name);
+                state.sounds.push(AudioSource::load(vfs, &snd_name)?);
+                self.progress.sounds_loaded += 1;
+            }
+
+            if self.is_done() {
+                return Ok(true);
+            }
+
+            if Instant::now() >= deadline {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+fn load_model(vfs: &Vfs, state: &mut ClientState, mod_name: String) -> Result<(), ClientError> {
+    // TODO: validate submodel names
+    if mod_name.ends_with(".bsp") {
+        // BSPs can have more than one model
+        let bsp_data = vfs.open(&mod_name)?;
+        let (mut brush_models, ent_string) = bsp::load(bsp_data).unwrap();
+
+        // the world is always the first model in the precache
+        if state.models.len() == 1 {
+            state.sky_name = worldspawn_sky(&ent_string);
+        }
+
+        for bmodel in brush_models.drain(..) {
+            let id = state.models.len();
+            let name = bmodel.name().to_owned();
+            state.models.push(bmodel);
+            state.model_names.insert(name, id);
+        }
+    } else if !mod_name.starts_with("*") {
+        // model names starting with * are loaded from the world BSP
+        debug!("Loading model {}", mod_name);
+        let id = state.models.len();
+        state.models.push(Model::load(vfs, &mod_name)?);
+        state.model_names.insert(mod_name, id);
+    }
+
+    Ok(())
+}
+
+/// Returns the skybox named by the worldspawn entity, or an empty string if there isn't one.
+fn worldspawn_sky(ent_string: &str) -> String {
+    let entities = match parse::map::entities(ent_string) {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("Couldn't parse entities: {}", e);
+            return String::new();
+        }
+    };
+
+    entities
+        .iter()
+        .find(|e| e.get("classname") == Some(&"worldspawn"))
+        .and_then(|w| w.get("sky").or_else(|| w.get("skyname")))
+        .map(|s| s.to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_progress_fraction() {
+        let mut progress = LoadProgress {
+            models_loaded: 0,
+            models_total: 6,
+            sounds_loaded: 0,
+            sounds_total: 2,
+        };
+        assert_eq!(progress.fraction(), 0.0);
+
+        progress.models_loaded = 6;
+        assert_eq!(progress.fraction(), 0.75);
+
+        progress.sounds_loaded = 2;
+        assert_eq!(progress.fraction(), 1.0);
+
+        // nothing to load
+        let empty = PrecacheLoader::new(Vec::new(), Vec::new());
+        assert!(empty.is_done());
+        assert_eq!(empty.progress().fraction(), 1.0);
+    }
+
+    #[test]
+    fn test_missing() {
+        let dir = std::env::temp_dir().join(format!("richter-missing-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("maps")).unwrap();
+        std::fs::write(dir.join("maps/start.bsp"), b"").unwrap();
+
+        let mut vfs = Vfs::new();
+        vfs.add_directory(&dir).unwrap();
+
+        let loader = PrecacheLoader::new(
+            vec![
+                String::from("maps/start.bsp"),
+                String::from("*1"),
+                String::from("progs/player.mdl"),
+                String::from("progs.dat"),
+            ],
+            vec![String::from("misc/talk.wav")],
+        );
+        assert_eq!(
+            loader.missing(&vfs),
+            vec![
+                String::from("progs/player.mdl"),
+                String::from("sound/misc/talk.wav")
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_worldspawn_sky() {
+        let ents = "{\n\"classname\" \"worldspawn\"\n\"sky\" \"unit1_\"\n}\n\
+                    {\n\"classname\" \"light\"\n}\n";
+        assert_eq!(worldspawn_sky(ents), "unit1_");
+        assert_eq!(worldspawn_sky("{\n\"classname\" \"worldspawn\"\n}\n"), "");
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Mod manifests.
+//!
+//! A server running a mod can advertise a manifest listing the files the mod
+//! needs in its `sv_manifesturl` rule. Each line of the manifest holds a
+//! file's SHA-256 and its path, as written by `sha256sum`:
+//!
+//! ```text
+//! # comments and blank lines are ignored
+//! 9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08  progs/flag.mdl
+//! 60303ae22b998861bce3b28f33eec1be758a213c86c93c076dbe9f558c11c752  maps/start.bsp
+//! ```
+//!
+//! Paths are relative to the directory holding the manifest on the server and
+//! to the game directory on the client. Files that are missing are downloaded
+//! to the game's download directory before sign-on. Manifests may only list
+//! level resources, the same files a server can send with `cl_download`, so a
+//! server can't replace configs or game code.
+
+use crate::client::download::is_downloadable;
+
+use failure::Error;
+use sha2::{Digest, Sha256};
+
+/// A SHA-256 digest.
+pub type Sha256Sum = [u8; 32];
+
+/// Returns the SHA-256 of `data`.
+pub fn sha256(data: &[u8]) -> Sha256Sum {
+    let mut sum = [0; 32];
+    sum.copy_from_slice(&Sha256::digest(data));
+    sum
+}
+
+/// Parse a SHA-256 written as 64 hex digits.
+pub fn parse_sha256(hex: &str) -> Result<Sha256Sum, Error> {
+    ensure!(
+        hex.len() == 64 && hex.is_ascii(),
+        "Invalid SHA-256 \"{}\"",
+        hex
+    );
+
+    let mut sum = [0; 32];
+    for (i, byte) in sum.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .map_err(|_| format_err!("Invalid SHA-256 \"{}\"", hex))?;
+    }
+
+    Ok(sum)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub sha256: Sha256Sum,
+}
+
+impl ManifestEntry {
+    /// Returns `true` if `data` matches this entry's checksum.
+    pub fn verify(&self, data: &[u8]) -> bool {
+        sha256(data) == self.sha256
+    }
+}
+
+/// The files required by a mod.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn parse(text: &str) -> Result<Manifest, Error> {
+        let mut entries = Vec::new();
+
+        for (line_num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (hex, path) = match line.find(char::is_whitespace) {
+                Some(i) => (&line[..i], line[i..].trim_start()),
+                None => bail!("Line {} of manifest has no file name", line_num + 1),
+            };
+
+            // sha256sum marks files hashed in binary mode with a *
+            let path = path.trim_start_matches('*');
+            ensure!(
+                is_downloadable(path),
+                "Invalid path \"{}\" in manifest",
+                path
+            );
+
+            entries.push(ManifestEntry {
+                path: path.to_owned(),
+                sha256: parse_sha256(hex)?,
+            });
+        }
+
+        Ok(Manifest { entries })
+    }
+
+    pub fn entries(&self) -> &[ManifestEntry] {
+        &self.entries
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.entries.iter().any(|e| e.path == path)
+    }
+}
+
+/// Returns the URL of the directory holding the manifest at `manifest_url`.
+pub fn base_url(manifest_url: &str) -> &str {
+    match manifest_url.rfind('/') {
+        Some(i) => &manifest_url[..i],
+        None => manifest_url,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // SHA-256 of "test"
+    const TEST_SHA256: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    #[test]
+    fn test_parse_manifest() {
+        let text = format!(
+            "# mod files\n\n{}  progs/flag.mdl\n{} *maps/start.bsp\n",
+            TEST_SHA256, TEST_SHA256
+        );
+        let manifest = Manifest::parse(&text).unwrap();
+
+        let paths: Vec<&str> = manifest.entries().iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["progs/flag.mdl", "maps/start.bsp"]);
+        assert!(manifest.entries()[0].verify(b"test"));
+        assert!(!manifest.entries()[0].verify(b"tset"));
+
+        assert!(Manifest::parse("progs/flag.mdl\n").is_err());
+        assert!(Manifest::parse(&format!("{}  ../progs/flag.mdl\n", TEST_SHA256)).is_err());
+        assert!(Manifest::parse("abcd  progs/flag.mdl\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_manifest_rejects_configs_and_code() {
+        for path in &["autoexec.cfg", "progs.dat", "maps/autoexec.cfg", "quake.rc"] {
+            let text = format!(
+                "{}  maps/start.bsp\n{}  {}\n",
+                TEST_SHA256, TEST_SHA256, path
+            );
+            assert!(Manifest::parse(&text).is_err(), "{} was accepted", path);
+        }
+    }
+
+    #[test]
+    fn test_base_url() {
+        assert_eq!(
+            base_url("http://example.com/mods/ctf/manifest.txt"),
+            "http://example.com/mods/ctf"
+        );
+    }
+}
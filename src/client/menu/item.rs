@@ -18,9 +18,13 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use std::cell::{Cell, RefCell};
+use std::{
+    cell::{Cell, RefCell},
+    net::SocketAddr,
+    rc::Rc,
+};
 
-use crate::client::menu::Menu;
+use crate::client::{browser::ServerBrowser, menu::Menu};
 
 use failure::Error;
 
@@ -31,6 +35,8 @@ pub enum Item {
     Enum(Enum),
     Slider(Slider),
     TextField(TextField),
+    Binding(Binding),
+    ServerList(ServerList),
 }
 
 pub struct Toggle {
@@ -239,6 +245,10 @@ impl TextField {
         self.chars.borrow().len()
     }
 
+    pub fn cursor(&self) -> usize {
+        self.cursor.get()
+    }
+
     pub fn set_cursor(&self, cursor: usize) -> Result<(), Error> {
         ensure!(cursor <= self.len(), "Index out of range");
 
@@ -264,7 +274,7 @@ impl TextField {
 
     pub fn cursor_left(&self) {
         let curs = self.cursor.get();
-        if curs > 1 {
+        if curs > 0 {
             self.cursor.set(curs - 1);
         }
     }
@@ -277,12 +287,14 @@ impl TextField {
         }
 
         self.chars.borrow_mut().insert(self.cursor.get(), c);
+        self.cursor.set(self.cursor.get() + 1);
         (self.on_update)(&self.text());
     }
 
     pub fn backspace(&self) {
-        if self.cursor.get() > 1 {
+        if self.cursor.get() > 0 {
             self.chars.borrow_mut().remove(self.cursor.get() - 1);
+            self.cursor.set(self.cursor.get() - 1);
             (self.on_update)(&self.text());
         }
     }
@@ -295,6 +307,103 @@ impl TextField {
     }
 }
 
+/// A command that can be bound to keys from the menu.
+///
+/// The keys bound to the command are cached here for display and refreshed by
+/// the menu input handler whenever the bindings may have changed.
+pub struct Binding {
+    command: String,
+    keys: RefCell<Vec<String>>,
+    conflicts: RefCell<Vec<String>>,
+    listening: Cell<bool>,
+}
+
+impl Binding {
+    pub fn new<S>(command: S) -> Binding
+    where
+        S: AsRef<str>,
+    {
+        Binding {
+            command: command.as_ref().to_string(),
+            keys: RefCell::new(Vec::new()),
+            conflicts: RefCell::new(Vec::new()),
+            listening: Cell::new(false),
+        }
+    }
+
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.keys.borrow().clone()
+    }
+
+    pub fn set_keys(&self, keys: Vec<String>) {
+        self.keys.replace(keys);
+    }
+
+    /// Returns `true` if `key` also starts another action.
+    pub fn is_conflict(&self, key: &str) -> bool {
+        self.conflicts.borrow().iter().any(|k| k == key)
+    }
+
+    pub fn set_conflicts(&self, conflicts: Vec<String>) {
+        self.conflicts.replace(conflicts);
+    }
+
+    /// Returns `true` if the next key pressed should be bound to this command.
+    pub fn listening(&self) -> bool {
+        self.listening.get()
+    }
+
+    pub fn set_listening(&self, listening: bool) {
+        self.listening.set(listening);
+    }
+}
+
+/// The rows of a `ServerBrowser`.
+///
+/// Up and down move through the rows before moving on to the neighboring
+/// items, and activating the item calls `on_select` with the selected server.
+pub struct ServerList {
+    browser: Rc<RefCell<ServerBrowser>>,
+    on_select: Box<dyn Fn(SocketAddr)>,
+}
+
+impl ServerList {
+    pub fn new(
+        browser: Rc<RefCell<ServerBrowser>>,
+        on_select: Box<dyn Fn(SocketAddr)>,
+    ) -> ServerList {
+        ServerList { browser, on_select }
+    }
+
+    pub fn browser(&self) -> &Rc<RefCell<ServerBrowser>> {
+        &self.browser
+    }
+
+    /// Select the next row. Returns `false` if the last row was already
+    /// selected.
+    pub fn select_next(&self) -> bool {
+        self.browser.borrow_mut().select_next()
+    }
+
+    /// Select the previous row. Returns `false` if the first row was already
+    /// selected.
+    pub fn select_prev(&self) -> bool {
+        self.browser.borrow_mut().select_prev()
+    }
+
+    pub fn activate(&self) {
+        // release the browser before calling out, since on_select may use it
+        let selected = self.browser.borrow().selected_address();
+        if let Some(addr) = selected {
+            (self.on_select)(addr);
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -409,6 +518,7 @@ mod test {
         tf.insert('e');
         tf.insert('r');
 
+        assert_eq!(tf.text(), "after");
         assert_eq!(tf.text(), *s.borrow());
 
         for _ in 0..2 * MAX_LEN {
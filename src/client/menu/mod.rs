@@ -20,11 +20,17 @@
 
 mod item;
 
-use std::cell::Cell;
+use std::{
+    cell::{Cell, RefCell},
+    net::SocketAddr,
+    rc::Rc,
+};
+
+use crate::client::browser::ServerBrowser;
 
 use failure::Error;
 
-pub use self::item::{Enum, EnumItem, Item, Slider, TextField, Toggle};
+pub use self::item::{Binding, Enum, EnumItem, Item, ServerList, Slider, TextField, Toggle};
 
 #[derive(Clone, Copy, Debug)]
 pub enum MenuState {
@@ -117,6 +123,12 @@ impl Menu {
 
         let s = m.state.get().clone();
         if let MenuState::Active { index } = s {
+            if let Item::ServerList(ref list) = m.items[index].item {
+                if list.select_next() {
+                    return Ok(());
+                }
+            }
+
             m.state.replace(MenuState::Active {
                 index: (index + 1) % m.items.len(),
             });
@@ -133,8 +145,14 @@ impl Menu {
 
         let s = m.state.get().clone();
         if let MenuState::Active { index } = s {
+            if let Item::ServerList(ref list) = m.items[index].item {
+                if list.select_prev() {
+                    return Ok(());
+                }
+            }
+
             m.state.replace(MenuState::Active {
-                index: (index - 1) % m.items.len(),
+                index: (index + m.items.len() - 1) % m.items.len(),
             });
         } else {
             bail!("Selected menu is inactive (invariant violation)");
@@ -163,6 +181,8 @@ impl Menu {
     /// If this item is an `Action`, executes the function contained in the
     /// `Action`.
     ///
+    /// If this item is a `ServerList`, connects to the selected server.
+    ///
     /// Otherwise, this has no effect.
     pub fn activate(&self) -> Result<(), Error> {
         let m = self.active_submenu()?;
@@ -176,6 +196,8 @@ impl Menu {
 
                 Item::Action(ref action) => (action)(),
 
+                Item::ServerList(ref list) => list.activate(),
+
                 _ => (),
             }
         }
@@ -247,6 +269,24 @@ impl Menu {
         Ok(())
     }
 
+    /// Returns every `Binding` in this menu and its submenus.
+    pub fn bindings(&self) -> Vec<&Binding> {
+        let mut bindings = Vec::new();
+        let mut menus = vec![self];
+
+        while let Some(m) = menus.pop() {
+            for item in m.items.iter() {
+                match item.item {
+                    Item::Submenu(ref sub) => menus.push(sub),
+                    Item::Binding(ref b) => bindings.push(b),
+                    _ => (),
+                }
+            }
+        }
+
+        bindings
+    }
+
     pub fn items(&self) -> &[NamedMenuItem] {
         &self.items
     }
@@ -348,6 +388,17 @@ impl MenuBuilder {
         Ok(self)
     }
 
+    pub fn add_binding<S>(mut self, name: S, command: S) -> MenuBuilder
+    where
+        S: AsRef<str>,
+    {
+        self.items.push(NamedMenuItem::new(
+            name,
+            Item::Binding(Binding::new(command)),
+        ));
+        self
+    }
+
     pub fn add_text_field<S>(
         mut self,
         name: S,
@@ -364,6 +415,22 @@ impl MenuBuilder {
         ));
         Ok(self)
     }
+
+    pub fn add_server_list<S>(
+        mut self,
+        name: S,
+        browser: Rc<RefCell<ServerBrowser>>,
+        on_select: Box<dyn Fn(SocketAddr)>,
+    ) -> MenuBuilder
+    where
+        S: AsRef<str>,
+    {
+        self.items.push(NamedMenuItem::new(
+            name,
+            Item::ServerList(ServerList::new(browser, on_select)),
+        ));
+        self
+    }
 }
 
 pub struct NamedMenuItem {
@@ -394,7 +461,6 @@ impl NamedMenuItem {
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::{cell::Cell, rc::Rc};
 
     fn view() -> MenuView {
         MenuView {
@@ -487,4 +553,79 @@ mod test {
         assert!(is_inactive(&m1.state.get()));
         assert!(is_active(&m2.state.get()));
     }
+
+    #[test]
+    fn test_menu_prev_wraps() {
+        let menu = MenuBuilder::new()
+            .add_action("action_1", Box::new(|| ()))
+            .add_action("action_2", Box::new(|| ()))
+            .add_action("action_3", Box::new(|| ()))
+            .build(view());
+
+        menu.prev().unwrap();
+        match menu.state.get() {
+            MenuState::Active { index } => assert_eq!(index, 2),
+            s => panic!("expected an active menu, got {:?}", s),
+        }
+    }
+
+    #[test]
+    fn test_menu_bindings() {
+        let menu = MenuBuilder::new()
+            .add_binding("Attack", "+attack")
+            .add_submenu(
+                "Movement",
+                MenuBuilder::new()
+                    .add_binding("Jump", "+jump")
+                    .add_action("action", Box::new(|| ()))
+                    .build(view()),
+            )
+            .build(view());
+
+        let mut commands: Vec<&str> = menu.bindings().iter().map(|b| b.command()).collect();
+        commands.sort();
+        assert_eq!(commands, vec!["+attack", "+jump"]);
+    }
+
+    #[test]
+    fn test_menu_server_list() {
+        use crate::client::browser::ServerEntry;
+
+        let addrs: Vec<SocketAddr> = (0..2)
+            .map(|i| SocketAddr::from(([127, 0, 0, 1], 26000 + i)))
+            .collect();
+        let browser = Rc::new(RefCell::new(ServerBrowser::new(None)));
+        browser.borrow_mut().set_servers(
+            addrs
+                .iter()
+                .map(|a| ServerEntry {
+                    address: *a,
+                    status: None,
+                    ping: None,
+                })
+                .collect(),
+        );
+
+        let joined = Rc::new(Cell::new(None));
+        let joined_handle = joined.clone();
+        let menu = MenuBuilder::new()
+            .add_action("Refresh", Box::new(|| ()))
+            .add_server_list(
+                "Servers",
+                browser,
+                Box::new(move |addr| joined_handle.set(Some(addr))),
+            )
+            .build(view());
+
+        // up and down move through the rows before leaving the list
+        menu.next().unwrap();
+        menu.next().unwrap();
+        menu.activate().unwrap();
+        assert_eq!(joined.get(), Some(addrs[1]));
+        menu.next().unwrap();
+        match menu.state.get() {
+            MenuState::Active { index } => assert_eq!(index, 0),
+            s => panic!("expected an active menu, got {:?}", s),
+        }
+    }
 }
@@ -18,12 +18,24 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+pub mod addressbook;
+pub mod browser;
+pub mod centerprint;
+pub mod chat;
+pub mod coop;
 mod cvars;
 pub mod demo;
+pub mod download;
 pub mod entity;
 pub mod input;
+pub mod loading;
+pub mod manifest;
 pub mod menu;
+pub mod qw;
 pub mod render;
+pub mod replay;
+#[cfg(feature = "scripting")]
+pub mod script;
 pub mod sound;
 pub mod state;
 pub mod trace;
@@ -35,40 +47,61 @@ use std::{
     cell::RefCell,
     collections::{HashMap, VecDeque},
     io::BufReader,
-    net::ToSocketAddrs,
+    net::SocketAddr,
+    path::Path,
     rc::Rc,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::Instant,
 };
 
 use crate::{
     client::{
+        addressbook::{cmd_addr_add, cmd_addr_list, cmd_addr_remove, AddressBook},
+        centerprint::CenterPrintVars,
         demo::{DemoServer, DemoServerError},
         entity::{ClientEntity, MAX_STATIC_ENTITIES},
-        input::{game::GameInput, Input},
-        sound::{MusicPlayer, StaticSound},
+        input::{
+            game::{Action, GameInput},
+            haptics::{Haptics, HapticsVars, RumbleDevice},
+            Input,
+        },
+        sound::{
+            AudioCapture, AudioOutput, AudioSource, CaptionVars, DeviceWatcher, LocalSoundPlayer,
+            MusicPlayer, OutputMode, SpatialVars, StaticSound,
+        },
         state::{ClientState, PlayerInfo},
         trace::{TraceEntity, TraceFrame},
         view::{IdleVars, KickVars, MouseVars, RollVars},
     },
     common::{
-        console::{CmdRegistry, Console, ConsoleError, CvarRegistry},
-        engine,
+        console::{self, CmdRegistry, Console, ConsoleError, CvarRegistry},
+        engine, host,
         model::ModelError,
         net::{
             self,
             connect::{ConnectSocket, Request, Response, CONNECT_PROTOCOL_VERSION},
+            master::{self, DpMaster},
+            sim::NetSimVars,
             BlockingMode, ClientCmd, ClientStat, ColorShift, EntityEffects, EntityState, GameType,
-            NetError, PlayerColor, QSocket, ServerCmd, SignOnStage,
+            NetError, PlayerColor, PrecacheKind, Protocol, QSocket, ServerCmd, SignOnStage,
+            MAX_DATAGRAM, MAX_ENTITY_UPDATE_LEN,
         },
+        plugin::Plugins,
+        util::quake_string_to_ascii,
         vfs::{Vfs, VfsError},
     },
 };
 
-use cgmath::Deg;
+use cgmath::{Deg, InnerSpace};
 use chrono::Duration;
+use download::{DownloadStatus, DownloadVars, Downloader};
 use input::InputFocus;
+use loading::PrecacheLoader;
 use menu::Menu;
-use render::{ClientRenderer, GraphicsState, WorldRenderer};
-use rodio::{OutputStream, OutputStreamHandle};
+use qw::QwSession;
+use render::{ClientRenderer, GraphicsState, HudText, WorldRenderer};
+use rodio::OutputStreamHandle;
 use sound::SoundError;
 use thiserror::Error;
 use view::BobVars;
@@ -78,6 +111,25 @@ use view::BobVars;
 const MAX_CONNECT_ATTEMPTS: usize = 3;
 const MAX_STATS: usize = 32;
 
+// how often to check whether the audio device has gone away
+const AUDIO_DEVICE_CHECK_INTERVAL: u64 = 1000;
+
+// how long to wait for the master server's list, in seconds
+const MASTER_TIMEOUT: i64 = 3;
+
+// how long to go without sending anything before sending a keepalive, in
+// seconds
+const KEEPALIVE_INTERVAL: i64 = 5;
+
+// marks a server address as QuakeWorld when it isn't on the default port
+const QW_SCHEME: &str = "qw://";
+
+// how long to wait for the output of an rcon command, in seconds
+const RCON_TIMEOUT: i64 = 3;
+
+// how long to spend loading precached resources each frame, in milliseconds
+const LOAD_FRAME_BUDGET: u64 = 50;
+
 const DEFAULT_SOUND_PACKET_VOLUME: u8 = 255;
 const DEFAULT_SOUND_PACKET_ATTENUATION: f32 = 1.0;
 
@@ -101,10 +153,18 @@ pub enum ClientError {
     InvalidConnectResponse,
     #[error("Invalid server address")]
     InvalidServerAddress,
+    #[error("Invalid client port ({0})")]
+    InvalidClientPort(f32),
+    #[error("Player names and passwords can't contain quotes, semicolons or newlines")]
+    UnquotableUserInfo,
     #[error("No response from server")]
     NoResponse,
+    #[error("Server timed out")]
+    TimedOut,
     #[error("Unrecognized protocol: {0}")]
     UnrecognizedProtocol(i32),
+    #[error("Unsupported protocol: {0:?}")]
+    UnsupportedProtocol(Protocol),
     #[error("Client is not connected")]
     NotConnected,
     #[error("Client has already signed on")]
@@ -123,15 +183,19 @@ pub enum ClientError {
     InvalidViewEntity(usize),
     #[error("Too many static entities")]
     TooManyStaticEntities,
+    #[error("Invalid precache index: {0}")]
+    InvalidPrecacheId(usize),
     #[error("No such lightmap animation: {0}")]
     NoSuchLightmapAnimation(usize),
     // TODO: wrap PlayError
-    #[error("Failed to open audio output stream")]
-    OutputStream,
+    #[error("Failed to open audio output stream: {0}")]
+    OutputStream(SoundError),
     #[error("Demo server error: {0}")]
     DemoServer(#[from] DemoServerError),
     #[error("Model error: {0}")]
     Model(#[from] ModelError),
+    #[error("Download failed: {0}")]
+    Download(String),
     #[error("Network error: {0}")]
     Network(#[from] NetError),
     #[error("Failed to load sound: {0}")]
@@ -202,12 +266,61 @@ enum ConnectionKind {
 
         /// The client's packet composition buffer.
         compose: Vec<u8>,
+
+        /// The address the client connected to, which answers server queries.
+        server_addr: SocketAddr,
+
+        /// The player name to send during sign-on instead of the default.
+        player_name: Option<String>,
+
+        /// Sent to the server during sign-on, before the client info.
+        password: Option<String>,
+
+        /// The bandwidth to ask the server for, in bytes per second.
+        rate: Option<u32>,
     },
 
+    /// A QuakeWorld server.
+    QuakeWorld(QwSession),
+
     /// A demo server.
     Demo(DemoServer),
 }
 
+/// Everything a connection needs from the client to run a frame.
+struct FrameContext<'a> {
+    frame_time: Duration,
+
+    /// Set while frame-stepping is waiting for the next step.
+    paused: bool,
+
+    vfs: &'a Vfs,
+    gfx_state: &'a GraphicsState,
+    cmds: &'a mut CmdRegistry,
+    console: &'a mut Console,
+    music_player: &'a mut MusicPlayer,
+    plugins: &'a mut Plugins,
+
+    /// Set while game audio is being recorded.
+    capture: Option<&'a AudioCapture>,
+
+    /// How long the server may go quiet before the connection is dropped.
+    timeout: Option<Duration>,
+
+    idle_vars: IdleVars,
+    kick_vars: KickVars,
+    roll_vars: RollVars,
+    bob_vars: BobVars,
+    spatial_vars: SpatialVars,
+    caption_vars: CaptionVars,
+    center_print_vars: CenterPrintVars,
+    net_sim_vars: NetSimVars,
+    download_vars: DownloadVars,
+    output_mode: OutputMode,
+    cl_nolerp: f32,
+    sv_gravity: f32,
+}
+
 /// A connection to a game server of some kind.
 ///
 /// The exact nature of the connected server is specified by [`ConnectionKind`].
@@ -215,6 +328,18 @@ pub struct Connection {
     state: ClientState,
     conn_state: ConnectionState,
     kind: ConnectionKind,
+
+    // selected by the server's ServerInfo message
+    protocol: Protocol,
+
+    // loads the resources listed in the server's ServerInfo message
+    loader: Option<PrecacheLoader>,
+
+    // set if the server asked for prespawn before loading finished
+    prespawn_pending: bool,
+
+    // fetches resources the level needs that aren't in the filesystem
+    downloader: Option<Downloader>,
 }
 
 impl Connection {
@@ -229,11 +354,18 @@ impl Connection {
             // TODO: validate stage transition
             ConnectionState::SignOn(ref mut _stage) => {
                 if let ConnectionKind::Server {
-                    ref mut compose, ..
+                    ref mut compose,
+                    ref player_name,
+                    ref password,
+                    rate,
+                    ..
                 } = self.kind
                 {
                     match new_stage {
                         Not => (), // TODO this is an error (invalid value)
+                        // the sign-on data refers to precached models, so
+                        // wait until they're loaded to ask for it
+                        Prespawn if self.loader.is_some() => self.prespawn_pending = true,
                         Prespawn => {
                             ClientCmd::StringCmd {
                                 cmd: String::from("prespawn"),
@@ -241,9 +373,26 @@ impl Connection {
                             .serialize(compose)?;
                         }
                         ClientInfo => {
+                            if let Some(ref password) = *password {
+                                ClientCmd::StringCmd {
+                                    cmd: format!("password \"{}\"\n", password),
+                                }
+                                .serialize(compose)?;
+                            }
+
+                            if let Some(rate) = rate {
+                                ClientCmd::StringCmd {
+                                    cmd: format!("rate {}\n", rate),
+                                }
+                                .serialize(compose)?;
+                            }
+
                             // TODO: fill in client info here
                             ClientCmd::StringCmd {
-                                cmd: format!("name \"{}\"\n", "UNNAMED"),
+                                cmd: format!(
+                                    "name \"{}\"\n",
+                                    player_name.as_deref().unwrap_or("UNNAMED")
+                                ),
                             }
                             .serialize(compose)?;
                             ClientCmd::StringCmd {
@@ -264,12 +413,23 @@ impl Connection {
                         }
                         SignOnStage::Done => {
                             debug!("SignOn complete");
-                            // TODO: end load screen
                             self.state.start_time = self.state.time;
                         }
                     }
                 }
 
+                // QuakeWorld servers run most of the sign-on with stuffed
+                // commands, so only these stages need an answer
+                if let ConnectionKind::QuakeWorld(ref mut session) = self.kind {
+                    match new_stage {
+                        Prespawn if self.loader.is_some() => self.prespawn_pending = true,
+                        Prespawn => session.prespawn()?,
+                        Begin => session.begin()?,
+                        Done => self.state.start_time = self.state.time,
+                        Not | ClientInfo => (),
+                    }
+                }
+
                 match new_stage {
                     // TODO proper error
                     Not => panic!("SignOnStage::Not in handle_signon"),
@@ -301,22 +461,29 @@ impl Connection {
         cmds: &mut CmdRegistry,
         console: &mut Console,
         music_player: &mut MusicPlayer,
+        plugins: &mut Plugins,
         kick_vars: KickVars,
     ) -> Result<ConnectionStatus, ClientError> {
         use ConnectionStatus::*;
 
-        let (msg, demo_view_angles, track_override) = match self.kind {
+        let (msg, demo_view_angles, track_override, mut qw_cmds) = match self.kind {
             ConnectionKind::Server { ref mut qsock, .. } => {
-                let msg = qsock.recv_msg(match self.conn_state {
-                    // if we're in the game, don't block waiting for messages
-                    ConnectionState::Connected(_) => BlockingMode::NonBlocking,
+                // never block waiting for messages, so the window keeps
+                // responding while the server sends the sign-on data. the
+                // connection times out after net_messagetimeout.
+                let msg = qsock.recv_msg(BlockingMode::NonBlocking)?;
 
-                    // otherwise, give the server some time to respond
-                    // TODO: might make sense to make this a future or something
-                    ConnectionState::SignOn(_) => BlockingMode::Timeout(Duration::seconds(5)),
-                })?;
+                (msg, None, None, None)
+            }
+
+            // QuakeWorld commands arrive already translated
+            ConnectionKind::QuakeWorld(ref mut session) => {
+                let cmds = session.recv(vfs)?;
+                if cmds.is_empty() {
+                    return Ok(Maintain);
+                }
 
-                (msg, None, None)
+                (Vec::new(), None, None, Some(cmds.into_iter()))
             }
 
             ConnectionKind::Demo(ref mut demo_srv) => {
@@ -342,21 +509,35 @@ impl Connection {
                         msg_view.message().to_owned(),
                         Some(view_angles),
                         demo_srv.track_override(),
+                        None,
                     )
                 } else {
-                    (Vec::new(), None, demo_srv.track_override())
+                    (Vec::new(), None, demo_srv.track_override(), None)
                 }
             }
         };
 
         // no data available at this time
-        if msg.is_empty() {
+        if msg.is_empty() && qw_cmds.is_none() {
             return Ok(Maintain);
         }
 
         let mut reader = BufReader::new(msg.as_slice());
 
-        while let Some(cmd) = ServerCmd::deserialize(&mut reader)? {
+        loop {
+            let cmd = match qw_cmds {
+                Some(ref mut cmds) => cmds.next(),
+                None => ServerCmd::deserialize(&mut reader, self.protocol)?,
+            };
+            let cmd = match cmd {
+                Some(c) => c,
+                None => break,
+            };
+
+            if !plugins.filter_server_cmd(&cmd) {
+                continue;
+            }
+
             match cmd {
                 // TODO: have an error for this instead of panicking
                 // once all other commands have placeholder handlers, just error
@@ -373,16 +554,36 @@ impl Connection {
                 }
 
                 ServerCmd::CenterPrint { text } => {
-                    // TODO: print to center of screen
-                    warn!("Center print not yet implemented!");
-                    println!("{}", text);
+                    // messages printed during an intermission are revealed slowly
+                    let reveal = self.state.intermission.is_some();
+                    self.state
+                        .center_print
+                        .print(&text, self.state.time, reveal);
+                    console.println(&text);
                 }
 
                 ServerCmd::PlayerData(player_data) => self.state.update_player(player_data),
 
+                ServerCmd::Precache { kind, id, name } => {
+                    let id = id as usize;
+                    match kind {
+                        PrecacheKind::Model => {
+                            self.state.precache_model(vfs, id, &name)?;
+
+                            // if the world renderer is already built, it
+                            // needs a renderer for the new model too
+                            if let ConnectionState::Connected(ref mut world) = self.conn_state {
+                                world.set_model(gfx_state, id, &self.state.models()[id]);
+                            }
+                        }
+
+                        PrecacheKind::Sound => self.state.precache_sound(vfs, id, &name)?,
+                    }
+                }
+
                 ServerCmd::Cutscene { text } => {
-                    self.state.intermission = Some(IntermissionKind::Cutscene { text });
-                    self.state.completion_time = Some(self.state.time);
+                    self.state
+                        .start_intermission(IntermissionKind::Cutscene { text });
                 }
 
                 ServerCmd::Damage {
@@ -394,7 +595,7 @@ impl Connection {
                 ServerCmd::Disconnect => {
                     return Ok(match self.kind {
                         ConnectionKind::Demo(_) => NextDemo,
-                        ConnectionKind::Server { .. } => Disconnect,
+                        ConnectionKind::Server { .. } | ConnectionKind::QuakeWorld(_) => Disconnect,
                     })
                 }
 
@@ -414,14 +615,14 @@ impl Connection {
                 }
 
                 ServerCmd::Finale { text } => {
-                    self.state.intermission = Some(IntermissionKind::Finale { text });
-                    self.state.completion_time = Some(self.state.time);
+                    self.state
+                        .start_intermission(IntermissionKind::Finale { text });
                 }
 
                 ServerCmd::FoundSecret => self.state.stats[ClientStat::FoundSecrets as usize] += 1,
                 ServerCmd::Intermission => {
-                    self.state.intermission = Some(IntermissionKind::Intermission);
-                    self.state.completion_time = Some(self.state.time);
+                    self.state
+                        .start_intermission(IntermissionKind::Intermission);
                 }
                 ServerCmd::KilledMonster => {
                     self.state.stats[ClientStat::KilledMonsters as usize] += 1
@@ -456,7 +657,17 @@ impl Connection {
                     }
                 }
 
-                ServerCmd::Print { text } => console.print_alert(&text),
+                ServerCmd::Print { text } => {
+                    let players = self.state.player_info.iter().flatten();
+                    match chat::parse_chat(&text, players.map(|p| p.name.as_str())) {
+                        Some(msg) => {
+                            // chat goes in its own notify area, but stays in the console history
+                            console.println(msg);
+                            self.state.chat.push(msg, self.state.time);
+                        }
+                        None => console.print_alert(&text),
+                    }
+                }
 
                 ServerCmd::ServerInfo {
                     protocol_version,
@@ -466,9 +677,27 @@ impl Connection {
                     model_precache,
                     sound_precache,
                 } => {
-                    // check protocol version
-                    if protocol_version != net::PROTOCOL_VERSION as i32 {
-                        Err(ClientError::UnrecognizedProtocol(protocol_version))?;
+                    // a QuakeWorld server info can only come from a session's
+                    // translation, never over the NetQuake protocol
+                    let qw = match self.kind {
+                        ConnectionKind::QuakeWorld(_) => true,
+                        _ => false,
+                    };
+
+                    // select the protocol for the rest of the connection
+                    self.protocol = match Protocol::from_version(protocol_version) {
+                        Some(Protocol::QuakeWorld) if !qw => {
+                            Err(ClientError::UnsupportedProtocol(Protocol::QuakeWorld))?
+                        }
+                        Some(p) => p,
+                        None => Err(ClientError::UnrecognizedProtocol(protocol_version))?,
+                    };
+                    debug!("Using protocol {:?}", self.protocol);
+
+                    // the session answers QuakeWorld's reconnect command
+                    // itself, so the sign-on restarts here
+                    if qw {
+                        self.conn_state = ConnectionState::SignOn(SignOnStage::Prespawn);
                     }
 
                     console.println(CONSOLE_DIVIDER);
@@ -484,9 +713,18 @@ impl Connection {
                         vfs,
                         self.state.mixer.stream(),
                         max_clients,
-                        model_precache,
-                        sound_precache,
+                        game_type,
                     )?;
+                    let loader = PrecacheLoader::new(model_precache, sound_precache);
+                    let missing = loader.missing(vfs);
+                    // even with nothing missing, the server's mod manifest may
+                    // list files we don't have
+                    self.downloader = match self.kind {
+                        ConnectionKind::Server { .. } => Some(Downloader::new(missing)),
+                        _ => None,
+                    };
+                    self.loader = Some(loader);
+                    self.prespawn_pending = false;
 
                     let bonus_cshift =
                         self.state.color_shifts[ColorShiftCode::Bonus as usize].clone();
@@ -505,6 +743,8 @@ impl Connection {
 
                 ServerCmd::SetAngle { angles } => self.state.set_view_angles(angles),
 
+                ServerCmd::SetPause { paused } => self.state.paused = paused,
+
                 ServerCmd::SetView { ent_id } => {
                     if ent_id <= 0 {
                         Err(ClientError::InvalidViewEntity(ent_id as usize))?;
@@ -538,6 +778,11 @@ impl Connection {
                         break;
                     }
 
+                    if sound_id as usize >= self.state.sounds.len() {
+                        warn!("server tried to start nonexistent sound {}", sound_id);
+                        continue;
+                    }
+
                     let volume = volume.unwrap_or(DEFAULT_SOUND_PACKET_VOLUME);
                     let attenuation = attenuation.unwrap_or(DEFAULT_SOUND_PACKET_ATTENUATION);
                     // TODO: apply volume, attenuation, spatialization
@@ -551,6 +796,13 @@ impl Connection {
                         position,
                         &self.state.listener,
                     );
+
+                    let distance = (position - self.state.listener.origin()).magnitude();
+                    self.state.captions.sound_started(
+                        self.state.sounds[sound_id as usize].name(),
+                        distance,
+                        self.state.time,
+                    );
                 }
 
                 ServerCmd::SpawnBaseline {
@@ -606,6 +858,14 @@ impl Connection {
                     volume,
                     attenuation,
                 } => {
+                    if sound_id as usize >= self.state.sounds.len() {
+                        warn!(
+                            "server tried to spawn nonexistent static sound {}",
+                            sound_id
+                        );
+                        continue;
+                    }
+
                     self.state.static_sounds.push(StaticSound::new(
                         &self.state.mixer.stream(),
                         origin,
@@ -623,6 +883,7 @@ impl Connection {
                 ServerCmd::Time { time } => {
                     self.state.msg_times[1] = self.state.msg_times[0];
                     self.state.msg_times[0] = engine::duration_from_f32(time);
+                    self.state.msg_full = msg.len() + MAX_ENTITY_UPDATE_LEN > MAX_DATAGRAM;
                 }
 
                 ServerCmd::UpdateColors {
@@ -689,15 +950,24 @@ impl Connection {
 
                     if let Some(ref mut info) = self.state.player_info[player_id] {
                         // if this player is already connected, it's a name change
-                        debug!("Player {} has changed name to {}", &info.name, &new_name);
+                        debug!(
+                            "Player {} has changed name to {}",
+                            quake_string_to_ascii(&info.name),
+                            quake_string_to_ascii(&new_name)
+                        );
                         info.name = new_name.to_owned();
                     } else {
                         // if this player is not connected, it's a join
-                        debug!("Player {} with ID {} has joined", &new_name, player_id);
+                        debug!(
+                            "Player {} with ID {} has joined",
+                            quake_string_to_ascii(&new_name),
+                            player_id
+                        );
                         self.state.player_info[player_id] = Some(PlayerInfo {
                             name: new_name.to_owned(),
                             colors: PlayerColor::new(0, 0),
                             frags: 0,
+                            join_time: self.state.time,
                         });
                     }
                 }
@@ -711,17 +981,31 @@ impl Connection {
                 }
 
                 ServerCmd::Version { version } => {
-                    if version != net::PROTOCOL_VERSION as i32 {
-                        // TODO: handle with an error
-                        error!(
+                    if version != self.protocol.version() {
+                        Err(NetError::InvalidData(format!(
                             "Incompatible server version: server's is {}, client's is {}",
                             version,
-                            net::PROTOCOL_VERSION,
-                        );
-                        panic!("bad version number");
+                            self.protocol.version(),
+                        )))?;
                     }
                 }
 
+                ServerCmd::Bf => {
+                    self.state.color_shifts[ColorShiftCode::Bonus as usize].replace(ColorShift {
+                        dest_color: [215, 186, 69],
+                        percent: 50,
+                    });
+                }
+
+                // TODO: render fog
+                ServerCmd::Fog {
+                    density,
+                    color,
+                    time,
+                } => debug!("Fog: density {} color {:?} time {}", density, color, time),
+
+                ServerCmd::SkyBox { name } => self.state.sky_name = name,
+
                 x => {
                     debug!("{:?}", x);
                     unimplemented!();
@@ -732,31 +1016,164 @@ impl Connection {
         Ok(Maintain)
     }
 
-    fn frame(
+    /// Apply the focus loss settings while the window is in the background.
+    ///
+    /// If `cl_bgpause` is set, a single-player game is paused when the window
+    /// loses focus and unpaused when it regains focus. Sound volume is scaled
+    /// by `volume`.
+    fn set_background(
         &mut self,
-        frame_time: Duration,
-        vfs: &Vfs,
-        gfx_state: &GraphicsState,
-        cmds: &mut CmdRegistry,
-        console: &mut Console,
-        music_player: &mut MusicPlayer,
-        idle_vars: IdleVars,
-        kick_vars: KickVars,
-        roll_vars: RollVars,
-        bob_vars: BobVars,
-        cl_nolerp: f32,
-        sv_gravity: f32,
-    ) -> Result<ConnectionStatus, ClientError> {
+        background: bool,
+        cl_bgpause: f32,
+        volume: f32,
+    ) -> Result<(), ClientError> {
+        self.state.listener.set_volume(volume);
+
+        let compose = match self.kind {
+            ConnectionKind::Server {
+                ref mut compose, ..
+            } => compose,
+            ConnectionKind::QuakeWorld(_) | ConnectionKind::Demo(_) => return Ok(()),
+        };
+
+        // the server ignores pause requests during sign-on
+        if let ConnectionState::SignOn(_) = self.conn_state {
+            return Ok(());
+        }
+
+        let toggle = if background {
+            cl_bgpause != 0.0
+                && self.state.max_players == 1
+                && !self.state.paused
+                && !self.state.background_paused
+        } else {
+            // pause toggles, so this undoes our pause even if the server
+            // hasn't confirmed it yet
+            self.state.background_paused
+        };
+
+        if toggle {
+            ClientCmd::StringCmd {
+                cmd: String::from("pause"),
+            }
+            .serialize(compose)?;
+            self.state.background_paused = background;
+        }
+
+        Ok(())
+    }
+
+    fn frame(&mut self, ctx: FrameContext) -> Result<ConnectionStatus, ClientError> {
+        let FrameContext {
+            frame_time,
+            paused,
+            vfs,
+            gfx_state,
+            cmds,
+            console,
+            music_player,
+            plugins,
+            capture,
+            timeout,
+            idle_vars,
+            kick_vars,
+            roll_vars,
+            bob_vars,
+            spatial_vars,
+            caption_vars,
+            center_print_vars,
+            net_sim_vars,
+            download_vars,
+            output_mode,
+            cl_nolerp,
+            sv_gravity,
+        } = ctx;
+
         debug!("frame time: {}ms", frame_time.num_milliseconds());
 
+        // set these before any sounds are started this frame
+        self.state.listener.set_vars(spatial_vars);
+        self.state.captions.set_vars(caption_vars);
+        self.state.center_print.set_vars(center_print_vars);
+        if let ConnectionKind::Server { ref mut qsock, .. } = self.kind {
+            qsock.set_sim_vars(net_sim_vars);
+        }
+        if self.state.listener.output_mode() != output_mode
+            || !self.state.listener.is_capturing(capture)
+        {
+            // sounds must be restarted to change their channel layout or
+            // start recording them
+            self.state.listener.set_output_mode(output_mode);
+            self.state.listener.set_capture(capture.cloned());
+            let stream = self.state.mixer.stream();
+            self.state.set_stream(stream);
+        }
+
         // do this _before_ parsing server messages so that we know when to
         // request the next message from the demo server.
         self.state.advance_time(frame_time);
-        match self.parse_server_msg(vfs, gfx_state, cmds, console, music_player, kick_vars)? {
-            ConnectionStatus::Maintain => (),
-            // if Disconnect or NextDemo, delegate up the chain
-            s => return Ok(s),
+
+        // fetch anything the level needs that we don't have before loading it
+        let downloading = match self.downloader {
+            Some(ref mut downloader) => {
+                if !downloader.is_started() && download_vars.cl_download != 0.0 {
+                    if let (ConnectionKind::Server { server_addr, .. }, Some(dir)) =
+                        (&self.kind, vfs.download_dir())
+                    {
+                        let check_manifest = download_vars.cl_manifest != 0.0;
+                        downloader.start(*server_addr, dir, check_manifest);
+                    }
+                }
+
+                match downloader.poll(console, vfs) {
+                    DownloadStatus::Running { .. } => true,
+                    DownloadStatus::Failed(e) => return Err(ClientError::Download(e)),
+
+                    // if downloads are disabled, the loader reports the
+                    // missing files
+                    DownloadStatus::Idle | DownloadStatus::Finished => false,
+                }
+            }
+            None => false,
         };
+        if !downloading {
+            self.downloader = None;
+        }
+
+        // finish loading the level before reading anything else from the server
+        match self.loader {
+            Some(ref mut loader) if !downloading => {
+                let deadline = Instant::now() + std::time::Duration::from_millis(LOAD_FRAME_BUDGET);
+                if loader.load(vfs, &mut self.state, deadline)? {
+                    debug!("Loaded {:?}", loader.progress());
+                    self.loader = None;
+
+                    if self.prespawn_pending {
+                        self.prespawn_pending = false;
+                        self.handle_signon(SignOnStage::Prespawn, gfx_state)?;
+                    }
+                }
+            }
+
+            _ => (),
+        }
+
+        // messages wait in the socket while frame-stepping is paused
+        if self.loader.is_none() && !paused {
+            match self.parse_server_msg(
+                vfs,
+                gfx_state,
+                cmds,
+                console,
+                music_player,
+                plugins,
+                kick_vars,
+            )? {
+                ConnectionStatus::Maintain => (),
+                // if Disconnect or NextDemo, delegate up the chain
+                s => return Ok(s),
+            };
+        }
 
         self.state.update_interp_ratio(cl_nolerp);
 
@@ -769,6 +1186,11 @@ impl Connection {
         // remove expired lights
         self.state.lights.update(self.state.time);
 
+        // remove expired captions, chat messages and center prints
+        self.state.captions.update(self.state.time);
+        self.state.chat.update(self.state.time);
+        self.state.center_print.update(self.state.time);
+
         // apply particle physics and remove expired particles
         self.state
             .particles
@@ -777,15 +1199,45 @@ impl Connection {
         if let ConnectionKind::Server {
             ref mut qsock,
             ref mut compose,
+            ..
         } = self.kind
         {
+            if let (Some(t), false) = (timeout, paused) {
+                if qsock.time_since_recv() > t {
+                    return Err(ClientError::TimedOut);
+                }
+            }
+
+            // let the server know we're still here if we have nothing else
+            // to say
+            if compose.is_empty()
+                && qsock.can_send()
+                && qsock.time_since_send() > Duration::seconds(KEEPALIVE_INTERVAL)
+            {
+                ClientCmd::NoOp.serialize(compose)?;
+            }
+
             // respond to the server
-            if qsock.can_send() && !compose.is_empty() {
-                qsock.begin_send_msg(&compose)?;
-                compose.clear();
+            if !compose.is_empty() {
+                if qsock.can_send() {
+                    qsock.begin_send_msg(&compose)?;
+                    compose.clear();
+                } else {
+                    qsock.record_choke();
+                }
             }
         }
 
+        if let ConnectionKind::QuakeWorld(ref mut session) = self.kind {
+            if let (Some(t), false) = (timeout, paused) {
+                if session.time_since_recv() > t {
+                    return Err(ClientError::TimedOut);
+                }
+            }
+
+            session.send()?;
+        }
+
         // these all require the player entity to have spawned
         if let ConnectionState::Connected(_) = self.conn_state {
             // update view
@@ -812,12 +1264,29 @@ pub struct Client {
     cmds: Rc<RefCell<CmdRegistry>>,
     console: Rc<RefCell<Console>>,
     input: Rc<RefCell<Input>>,
-    _output_stream: OutputStream,
-    output_stream_handle: OutputStreamHandle,
+    audio: Rc<RefCell<AudioOutput>>,
+    // reports changes to the list of audio devices
+    audio_devices: DeviceWatcher,
+    // if Some, game audio is being recorded
+    audio_capture: Option<AudioCapture>,
     music_player: Rc<RefCell<MusicPlayer>>,
+    local_sounds: Rc<RefCell<LocalSoundPlayer>>,
     conn: Rc<RefCell<Option<Connection>>>,
     renderer: ClientRenderer,
+    haptics: Haptics,
     demo_queue: Rc<RefCell<VecDeque<String>>>,
+
+    // number of frames left to run while in frame-step mode
+    framestep: Rc<RefCell<usize>>,
+
+    // console output from commands that wait on the network in the
+    // background, such as rcon and masterlist
+    background_output: Receiver<String>,
+
+    plugins: Rc<RefCell<Plugins>>,
+
+    #[cfg(feature = "scripting")]
+    scripts: Rc<RefCell<script::ScriptHost>>,
 }
 
 impl Client {
@@ -829,14 +1298,19 @@ impl Client {
         input: Rc<RefCell<Input>>,
         gfx_state: &GraphicsState,
         menu: &Menu,
-    ) -> Client {
+        address_book: Rc<RefCell<AddressBook>>,
+        plugins: Rc<RefCell<Plugins>>,
+    ) -> Result<Client, ClientError> {
         let conn = Rc::new(RefCell::new(None));
 
-        let (stream, handle) = match OutputStream::try_default() {
-            Ok(o) => o,
-            // TODO: proceed without sound and allow configuration in menu
-            Err(_) => Err(ClientError::OutputStream).unwrap(),
-        };
+        let snd_device = cvars.borrow().get("snd_device").unwrap();
+        // TODO: proceed without sound and allow configuration in menu
+        let audio = Rc::new(RefCell::new(
+            AudioOutput::open_or_default(snd_device).map_err(ClientError::OutputStream)?,
+        ));
+        let audio_devices = DeviceWatcher::spawn(std::time::Duration::from_millis(
+            AUDIO_DEVICE_CHECK_INTERVAL,
+        ));
 
         // set up overlay/ui toggles
         cmds.borrow_mut()
@@ -849,13 +1323,48 @@ impl Client {
             .insert_or_replace("togglemenu", cmd_togglemenu(conn.clone(), input.clone()))
             .unwrap();
 
+        // set up chat
+        cmds.borrow_mut()
+            .insert_or_replace(
+                "messagemode",
+                cmd_messagemode(conn.clone(), input.clone(), false),
+            )
+            .unwrap();
+        cmds.borrow_mut()
+            .insert_or_replace(
+                "messagemode2",
+                cmd_messagemode(conn.clone(), input.clone(), true),
+            )
+            .unwrap();
+        cmds.borrow_mut()
+            .insert_or_replace("say", cmd_say(conn.clone(), "say"))
+            .unwrap();
+        cmds.borrow_mut()
+            .insert_or_replace("say_team", cmd_say(conn.clone(), "say_team"))
+            .unwrap();
+
         // set up connection console commands
         cmds.borrow_mut()
             .insert_or_replace(
                 "connect",
-                cmd_connect(conn.clone(), input.clone(), handle.clone()),
+                cmd_connect(
+                    conn.clone(),
+                    input.clone(),
+                    audio.clone(),
+                    cvars.clone(),
+                    address_book.clone(),
+                ),
             )
             .unwrap();
+        cmds.borrow_mut()
+            .insert_or_replace("addr_add", cmd_addr_add(address_book.clone()))
+            .unwrap();
+        cmds.borrow_mut()
+            .insert_or_replace("addr_remove", cmd_addr_remove(address_book.clone()))
+            .unwrap();
+        cmds.borrow_mut()
+            .insert_or_replace("addr_list", cmd_addr_list(address_book))
+            .unwrap();
         cmds.borrow_mut()
             .insert_or_replace("reconnect", cmd_reconnect(conn.clone(), input.clone()))
             .unwrap();
@@ -867,7 +1376,7 @@ impl Client {
         cmds.borrow_mut()
             .insert_or_replace(
                 "playdemo",
-                cmd_playdemo(conn.clone(), vfs.clone(), input.clone(), handle.clone()),
+                cmd_playdemo(conn.clone(), vfs.clone(), input.clone(), audio.clone()),
             )
             .unwrap();
 
@@ -879,13 +1388,16 @@ impl Client {
                     conn.clone(),
                     vfs.clone(),
                     input.clone(),
-                    handle.clone(),
+                    audio.clone(),
                     demo_queue.clone(),
                 ),
             )
             .unwrap();
 
-        let music_player = Rc::new(RefCell::new(MusicPlayer::new(vfs.clone(), handle.clone())));
+        let music_player = Rc::new(RefCell::new(MusicPlayer::new(
+            vfs.clone(),
+            audio.borrow().handle(),
+        )));
         cmds.borrow_mut()
             .insert_or_replace("music", cmd_music(music_player.clone()))
             .unwrap();
@@ -899,19 +1411,88 @@ impl Client {
             .insert_or_replace("music_resume", cmd_music_resume(music_player.clone()))
             .unwrap();
 
-        Client {
+        let local_sounds = Rc::new(RefCell::new(LocalSoundPlayer::new(
+            vfs.clone(),
+            audio.borrow().handle(),
+        )));
+        cmds.borrow_mut()
+            .insert_or_replace("play", cmd_play(local_sounds.clone()))
+            .unwrap();
+        cmds.borrow_mut()
+            .insert_or_replace("playvol", cmd_playvol(local_sounds.clone()))
+            .unwrap();
+        cmds.borrow_mut()
+            .insert_or_replace(
+                "soundlist",
+                cmd_soundlist(conn.clone(), local_sounds.clone()),
+            )
+            .unwrap();
+
+        cmds.borrow_mut()
+            .insert_or_replace("snd_devices", cmd_snd_devices(audio.clone()))
+            .unwrap();
+
+        let (output_tx, background_output) = mpsc::channel();
+        cmds.borrow_mut()
+            .insert_or_replace(
+                "masterlist",
+                cmd_masterlist(cvars.clone(), output_tx.clone()),
+            )
+            .unwrap();
+
+        cmds.borrow_mut()
+            .insert_or_replace("rcon", cmd_rcon(conn.clone(), cvars.clone(), output_tx))
+            .unwrap();
+
+        let framestep = Rc::new(RefCell::new(0));
+        cmds.borrow_mut()
+            .insert_or_replace("framestep", host::cmd_framestep(framestep.clone()))
+            .unwrap();
+
+        #[cfg(feature = "scripting")]
+        let scripts = {
+            let scripts = Rc::new(RefCell::new(script::ScriptHost::new(cvars.clone())));
+            cmds.borrow_mut()
+                .insert_or_replace(
+                    "script_load",
+                    script::cmd_script_load(scripts.clone(), vfs.clone()),
+                )
+                .unwrap();
+            cmds.borrow_mut()
+                .insert_or_replace("script_unload", script::cmd_script_unload(scripts.clone()))
+                .unwrap();
+            cmds.borrow_mut()
+                .insert_or_replace("script_list", script::cmd_script_list(scripts.clone()))
+                .unwrap();
+            scripts
+        };
+
+        Ok(Client {
             vfs,
             cvars,
             cmds,
             console,
             input,
-            _output_stream: stream,
-            output_stream_handle: handle,
+            audio,
+            audio_devices,
+            audio_capture: None,
             music_player,
+            local_sounds,
             conn,
             renderer: ClientRenderer::new(gfx_state, menu),
+            haptics: Haptics::new(),
             demo_queue,
-        }
+            framestep,
+            background_output,
+            plugins,
+            #[cfg(feature = "scripting")]
+            scripts,
+        })
+    }
+
+    /// Attach the gamepad used for rumble, or detach it with `None`.
+    pub fn set_rumble_device(&mut self, device: Option<Box<dyn RumbleDevice>>) {
+        self.haptics.set_device(device);
     }
 
     pub fn disconnect(&mut self) {
@@ -930,25 +1511,130 @@ impl Client {
         let kick_vars = self.kick_vars()?;
         let roll_vars = self.roll_vars()?;
         let bob_vars = self.bob_vars()?;
+        let spatial_vars = self.spatial_vars()?;
+        let caption_vars = self.caption_vars()?;
+        let center_print_vars = self.center_print_vars()?;
+        let net_sim_vars = self.net_sim_vars()?;
+        let timeout = match self.cvar_value("net_messagetimeout")? {
+            t if t > 0.0 => Some(engine::duration_from_f32(t)),
+            _ => None,
+        };
+        let output_mode = self.output_mode()?;
+        let haptics_vars = self.haptics_vars()?;
+        let download_vars = self.download_vars()?;
+        let r_skybox = self
+            .cvars
+            .borrow()
+            .get("r_skybox")
+            .map_err(ClientError::Cvar)?;
+
+        self.update_audio_output()?;
+        self.haptics.set_vars(haptics_vars);
+
+        while let Ok(output) = self.background_output.try_recv() {
+            self.console.borrow().print(output);
+        }
+
+        self.music_player.borrow_mut().update()?;
+
+        // be quiet and stay out of the way while another window has focus
+        let background = !self.input.borrow().window_focused();
+        let cl_bgpause = self.cvar_value("cl_bgpause")?;
+        let volume = match background {
+            true => self.cvar_value("snd_bgvolume")?.max(0.0).min(1.0),
+            false => 1.0,
+        };
+        self.music_player.borrow_mut().set_volume(volume);
+        if let Some(ref mut conn) = *self.conn.borrow_mut() {
+            conn.set_background(background, cl_bgpause, volume)?;
+        }
+
+        // in frame-step mode, the simulation only advances when requested, one
+        // server frame at a time. rendering is unaffected, and the connection
+        // still runs with no time passing so it doesn't go quiet, but server
+        // messages are held until the next step.
+        let (frame_time, paused) = if self.cvar_value("host_framestep")? != 0.0 {
+            let mut steps = self.framestep.borrow_mut();
+            if *steps == 0 {
+                (Duration::zero(), true)
+            } else {
+                *steps -= 1;
+
+                let frame_time = match *self.conn.borrow() {
+                    Some(ref conn) if conn.state.msg_interval() > Duration::zero() => {
+                        conn.state.msg_interval()
+                    }
+                    _ => frame_time,
+                };
+                (frame_time, false)
+            }
+        } else {
+            (frame_time, false)
+        };
 
         let status = match *self.conn.borrow_mut() {
-            Some(ref mut conn) => conn.frame(
+            Some(ref mut conn) => conn.frame(FrameContext {
                 frame_time,
-                &self.vfs,
+                paused,
+                vfs: &self.vfs,
                 gfx_state,
-                &mut self.cmds.borrow_mut(),
-                &mut self.console.borrow_mut(),
-                &mut self.music_player.borrow_mut(),
+                cmds: &mut self.cmds.borrow_mut(),
+                console: &mut self.console.borrow_mut(),
+                music_player: &mut self.music_player.borrow_mut(),
+                plugins: &mut self.plugins.borrow_mut(),
+                capture: self.audio_capture.as_ref(),
+                timeout,
                 idle_vars,
                 kick_vars,
                 roll_vars,
                 bob_vars,
+                spatial_vars,
+                caption_vars,
+                center_print_vars,
+                net_sim_vars,
+                download_vars,
+                output_mode,
                 cl_nolerp,
                 sv_gravity,
-            )?,
+            })?,
             None => ConnectionStatus::Disconnect,
         };
 
+        if let Some(ref mut conn) = *self.conn.borrow_mut() {
+            // the recorded player's damage isn't ours to feel
+            let demo = match conn.kind {
+                ConnectionKind::Demo(_) => true,
+                _ => false,
+            };
+
+            for event in conn.state.haptic_events.drain(..) {
+                if !demo {
+                    self.haptics.trigger(event);
+                }
+            }
+
+            if conn.state.chat.take_sound() {
+                if let Err(e) = self.local_sounds.borrow_mut().play(chat::TALK_SOUND, 1.0) {
+                    warn!("Couldn't play {}: {}", chat::TALK_SOUND, e);
+                }
+            }
+
+            // r_skybox overrides the map's choice
+            if let ConnectionState::Connected(ref mut world) = conn.conn_state {
+                let sky_name = match r_skybox.as_str() {
+                    "" => conn.state.sky_name.as_str(),
+                    name => name,
+                };
+                world.set_skybox(
+                    gfx_state,
+                    sky_name,
+                    conn.state.view.final_angles(),
+                    conn.state.time(),
+                );
+                world.update_player_skins(gfx_state, conn.state.player_skins());
+            }
+        }
+
         use ConnectionStatus::*;
         match status {
             Maintain => (),
@@ -973,8 +1659,12 @@ impl Client {
                             demo_file.as_mut().and_then(|df| match DemoServer::new(df) {
                                 Ok(d) => Some(Connection {
                                     kind: ConnectionKind::Demo(d),
-                                    state: ClientState::new(self.output_stream_handle.clone()),
+                                    state: ClientState::new(self.audio.borrow().handle()),
                                     conn_state: ConnectionState::SignOn(SignOnStage::Prespawn),
+                                    protocol: Protocol::NetQuake,
+                                    loader: None,
+                                    prespawn_pending: false,
+                                    downloader: None,
                                 }),
                                 Err(e) => {
                                     self.console.borrow_mut().println(format!("{}", e));
@@ -1003,9 +1693,60 @@ impl Client {
             }
         }
 
+        #[cfg(feature = "scripting")]
+        self.run_scripts(frame_time);
+
         Ok(())
     }
 
+    /// Send this frame's events to scripts and carry out what they asked for.
+    #[cfg(feature = "scripting")]
+    fn run_scripts(&mut self, frame_time: Duration) {
+        let connected = match *self.conn.borrow() {
+            Some(ref conn) => matches!(conn.conn_state, ConnectionState::Connected(_)),
+            None => false,
+        };
+
+        let mut scripts = self.scripts.borrow_mut();
+        scripts.set_connected(connected);
+        scripts.dispatch(script::ScriptEvent::Frame(frame_time));
+
+        for (name, function) in scripts.take_registrations() {
+            // don't let scripts replace engine commands
+            let cmd = script::cmd_script_function(self.scripts.clone(), function);
+            if let Err(e) = self.cmds.borrow_mut().insert(&name, cmd) {
+                self.console
+                    .borrow()
+                    .println(format!("Script can't register {}: {}", name, e));
+            }
+        }
+
+        let console = self.console.borrow();
+        for line in scripts.take_lines() {
+            console.println(line);
+        }
+
+        let commands = scripts.take_commands();
+        if !commands.is_empty() {
+            console.stuff_text(commands);
+        }
+    }
+
+    /// Collect the text plugins and client scripts draw on the HUD.
+    fn custom_hud_text(&self, width: u32, height: u32) -> Vec<HudText> {
+        let scale = render::hud_scale(&self.cvars.borrow(), width, height);
+        let hud_width = (width as f32 / scale) as i32;
+        let hud_height = (height as f32 / scale) as i32;
+
+        #[allow(unused_mut)]
+        let mut text = self.plugins.borrow_mut().hud(hud_width, hud_height);
+
+        #[cfg(feature = "scripting")]
+        text.extend(self.scripts.borrow_mut().hud(hud_width, hud_height));
+
+        text
+    }
+
     pub fn render(
         &mut self,
         gfx_state: &GraphicsState,
@@ -1016,8 +1757,10 @@ impl Client {
         focus: InputFocus,
     ) -> Result<(), ClientError> {
         let fov = Deg(self.cvar_value("fov")?);
+        let hud_text = self.custom_hud_text(width, height);
         let cvars = self.cvars.borrow();
         let console = self.console.borrow();
+        let input = self.input.borrow();
 
         self.renderer.render(
             gfx_state,
@@ -1030,11 +1773,104 @@ impl Client {
             &console,
             menu,
             focus,
+            input.chat_input(),
+            input
+                .game_input()
+                .map(|g| g.action_state(Action::ShowScores))
+                .unwrap_or(false),
+            &hud_text,
         );
 
         Ok(())
     }
 
+    /// Reopen the audio output if `snd_device` changed or the device in use
+    /// went away.
+    fn update_audio_output(&mut self) -> Result<(), ClientError> {
+        let snd_device = self
+            .cvars
+            .borrow()
+            .get("snd_device")
+            .map_err(ClientError::Cvar)?;
+
+        if snd_device == self.audio.borrow().requested() {
+            let names = match self.audio_devices.poll() {
+                Some(n) => n,
+                None => return Ok(()),
+            };
+
+            if !self.audio.borrow().needs_reopen(&names) {
+                return Ok(());
+            }
+        }
+
+        let output = match AudioOutput::open_or_default(&snd_device) {
+            Ok(o) => o,
+            Err(e) => {
+                // keep the old output and try again when the devices change
+                warn!("Failed to reopen audio output: {}", e);
+                return Ok(());
+            }
+        };
+
+        self.console.borrow().println(format!(
+            "Audio device: {} ({} channels)",
+            output.device_name(),
+            output.channels()
+        ));
+
+        let handle = output.handle();
+        self.audio.replace(output);
+        self.music_player.borrow_mut().set_stream(handle.clone())?;
+        self.local_sounds.borrow_mut().set_stream(handle.clone());
+        if let Some(ref mut conn) = *self.conn.borrow_mut() {
+            conn.state.set_stream(handle);
+        }
+
+        Ok(())
+    }
+
+    /// Begin recording game audio to a WAV file at `path`.
+    ///
+    /// The recording advances by `1 / fps` seconds each time
+    /// `end_audio_capture_frame()` is called, so it stays in sync with a frame
+    /// dump captured at the same rate. Any capture already in progress is
+    /// finished first.
+    pub fn start_audio_capture<P>(&mut self, path: P, fps: u32) -> Result<(), ClientError>
+    where
+        P: AsRef<Path>,
+    {
+        self.stop_audio_capture()?;
+
+        let audio = self.audio.borrow();
+        let capture = AudioCapture::create(path, audio.channels(), audio.sample_rate(), fps)?;
+        self.music_player
+            .borrow_mut()
+            .set_capture(Some(capture.clone()))?;
+        self.audio_capture = Some(capture);
+
+        Ok(())
+    }
+
+    /// Advance the audio capture by one frame, if one is in progress.
+    pub fn end_audio_capture_frame(&self) -> Result<(), ClientError> {
+        if let Some(ref capture) = self.audio_capture {
+            capture.end_frame()?;
+        }
+
+        Ok(())
+    }
+
+    /// Finish the audio capture, if one is in progress.
+    pub fn stop_audio_capture(&mut self) -> Result<(), ClientError> {
+        if let Some(capture) = self.audio_capture.take() {
+            self.music_player.borrow_mut().set_capture(None)?;
+            capture.finish()?;
+        }
+
+        Ok(())
+    }
+
     pub fn cvar_value<S>(&self, name: S) -> Result<f32, ClientError>
     where
         S: AsRef<str>,
@@ -1069,6 +1905,17 @@ impl Client {
                 game_input.refresh();
             }
 
+            // QuakeWorld moves go out with the next packet
+            Some(Connection {
+                ref mut state,
+                kind: ConnectionKind::QuakeWorld(ref mut session),
+                ..
+            }) => {
+                let move_cmd = state.handle_input(game_input, frame_time, move_vars, mouse_vars);
+                session.set_move(&move_cmd, frame_time);
+                game_input.refresh();
+            }
+
             _ => (),
         }
 
@@ -1131,6 +1978,63 @@ impl Client {
         })
     }
 
+    fn spatial_vars(&self) -> Result<SpatialVars, ClientError> {
+        Ok(SpatialVars {
+            snd_distance: self.cvar_value("snd_distance")?,
+            snd_rolloff: self.cvar_value("snd_rolloff")?,
+            snd_separation: self.cvar_value("snd_separation")?,
+        })
+    }
+
+    fn caption_vars(&self) -> Result<CaptionVars, ClientError> {
+        Ok(CaptionVars {
+            cl_captions: self.cvar_value("cl_captions")?,
+            cl_captions_distance: self.cvar_value("cl_captions_distance")?,
+        })
+    }
+
+    fn center_print_vars(&self) -> Result<CenterPrintVars, ClientError> {
+        Ok(CenterPrintVars {
+            scr_centertime: self.cvar_value("scr_centertime")?,
+            scr_printspeed: self.cvar_value("scr_printspeed")?,
+        })
+    }
+
+    fn net_sim_vars(&self) -> Result<NetSimVars, ClientError> {
+        Ok(NetSimVars {
+            net_fakelag: self.cvar_value("net_fakelag")?,
+            net_fakejitter: self.cvar_value("net_fakejitter")?,
+            net_fakeloss: self.cvar_value("net_fakeloss")?,
+            net_fakereorder: self.cvar_value("net_fakereorder")?,
+        })
+    }
+
+    fn download_vars(&self) -> Result<DownloadVars, ClientError> {
+        Ok(DownloadVars {
+            cl_download: self.cvar_value("cl_download")?,
+            cl_manifest: self.cvar_value("cl_manifest")?,
+        })
+    }
+
+    fn haptics_vars(&self) -> Result<HapticsVars, ClientError> {
+        Ok(HapticsVars {
+            joy_rumble: self.cvar_value("joy_rumble")?,
+            joy_rumble_damage: self.cvar_value("joy_rumble_damage")?,
+            joy_rumble_fire: self.cvar_value("joy_rumble_fire")?,
+        })
+    }
+
+    fn output_mode(&self) -> Result<OutputMode, ClientError> {
+        let mode = OutputMode::from_value(self.cvar_value("snd_output")?);
+
+        // fall back to stereo if the device can't play every channel
+        if mode.channels() > self.audio.borrow().channels() {
+            return Ok(OutputMode::Stereo);
+        }
+
+        Ok(mode)
+    }
+
     pub fn view_entity_id(&self) -> Option<usize> {
         match *self.conn.borrow() {
             Some(Connection { ref state, .. }) => Some(state.view_entity_id()),
@@ -1202,19 +2106,8 @@ fn cmd_toggleconsole(
     input: Rc<RefCell<Input>>,
 ) -> Box<dyn Fn(&[&str]) -> String> {
     Box::new(move |_| {
-        let focus = input.borrow().focus();
-        match *conn.borrow() {
-            Some(_) => match focus {
-                InputFocus::Game => input.borrow_mut().set_focus(InputFocus::Console),
-                InputFocus::Console => input.borrow_mut().set_focus(InputFocus::Game),
-                InputFocus::Menu => input.borrow_mut().set_focus(InputFocus::Console),
-            },
-            None => match focus {
-                InputFocus::Console => input.borrow_mut().set_focus(InputFocus::Menu),
-                InputFocus::Game => unreachable!(),
-                InputFocus::Menu => input.borrow_mut().set_focus(InputFocus::Console),
-            },
-        }
+        let connected = conn.borrow().is_some();
+        input.borrow_mut().toggle_console(connected);
         String::new()
     })
 }
@@ -1225,32 +2118,163 @@ fn cmd_togglemenu(
     input: Rc<RefCell<Input>>,
 ) -> Box<dyn Fn(&[&str]) -> String> {
     Box::new(move |_| {
-        let focus = input.borrow().focus();
-        match *conn.borrow() {
-            Some(_) => match focus {
-                InputFocus::Game => input.borrow_mut().set_focus(InputFocus::Menu),
-                InputFocus::Console => input.borrow_mut().set_focus(InputFocus::Menu),
-                InputFocus::Menu => input.borrow_mut().set_focus(InputFocus::Game),
-            },
-            None => match focus {
-                InputFocus::Console => input.borrow_mut().set_focus(InputFocus::Menu),
-                InputFocus::Game => unreachable!(),
-                InputFocus::Menu => input.borrow_mut().set_focus(InputFocus::Console),
-            },
+        let connected = conn.borrow().is_some();
+        input.borrow_mut().toggle_menu(connected);
+        String::new()
+    })
+}
+
+// implements the "messagemode" and "messagemode2" commands
+fn cmd_messagemode(
+    conn: Rc<RefCell<Option<Connection>>>,
+    input: Rc<RefCell<Input>>,
+    team: bool,
+) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| {
+        // there's nobody to talk to in a demo
+        if let Some(Connection {
+            kind: ConnectionKind::Server { .. },
+            ..
+        })
+        | Some(Connection {
+            kind: ConnectionKind::QuakeWorld(_),
+            ..
+        }) = *conn.borrow()
+        {
+            input.borrow_mut().begin_chat(team);
         }
         String::new()
     })
 }
 
-fn connect<A>(server_addrs: A, stream: OutputStreamHandle) -> Result<Connection, ClientError>
+// implements the "say" and "say_team" commands
+fn cmd_say(
+    conn: Rc<RefCell<Option<Connection>>>,
+    name: &'static str,
+) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| {
+        if args.is_empty() {
+            return format!("usage: {} <message>", name);
+        }
+
+        match *conn.borrow_mut() {
+            Some(Connection {
+                kind:
+                    ConnectionKind::Server {
+                        ref mut compose, ..
+                    },
+                ..
+            }) => {
+                let cmd = ClientCmd::StringCmd {
+                    cmd: format!("{} \"{}\"", name, args.join(" ")),
+                };
+                match cmd.serialize(compose) {
+                    Ok(()) => String::new(),
+                    Err(e) => format!("{}", e),
+                }
+            }
+            Some(Connection {
+                kind: ConnectionKind::QuakeWorld(ref mut session),
+                ..
+            }) => match session.string_cmd(format!("{} \"{}\"", name, args.join(" "))) {
+                Ok(()) => String::new(),
+                Err(e) => format!("{}", e),
+            },
+            Some(_) => "Can't chat during demo playback".to_owned(),
+            None => "Not connected".to_owned(),
+        }
+    })
+}
+
+/// Options controlling how the client connects to a server.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    /// The local port to send from, or 0 for any port.
+    pub client_port: u16,
+
+    /// The address to use for the game connection instead of the one the
+    /// server advertises in its accept response.
+    ///
+    /// Servers behind NAT may advertise a port that isn't reachable from
+    /// outside. If no port is given, the advertised port is used.
+    pub accept_addr: Option<String>,
+
+    /// The player name to use instead of the default.
+    pub player_name: Option<String>,
+
+    /// The server's password, if it needs one.
+    pub password: Option<String>,
+
+    /// The bandwidth to ask the server for, or `None` for the server's default.
+    pub rate: Option<u32>,
+}
+
+impl ConnectOptions {
+    fn from_cvars(cvars: &CvarRegistry) -> Result<ConnectOptions, ClientError> {
+        let client_port = cvars.get_value("clientport").map_err(ClientError::Cvar)?;
+        if client_port < 0.0 || client_port > std::u16::MAX as f32 {
+            return Err(ClientError::InvalidClientPort(client_port));
+        }
+
+        let accept_addr = cvars.get("cl_accept_addr").map_err(ClientError::Cvar)?;
+        let rate = cvars.get_value("rate").map_err(ClientError::Cvar)?;
+
+        Ok(ConnectOptions {
+            client_port: client_port as u16,
+            accept_addr: match accept_addr.is_empty() {
+                true => None,
+                false => Some(accept_addr),
+            },
+            player_name: None,
+            password: None,
+            rate: match rate {
+                r if r >= 1.0 => Some(r as u32),
+                _ => None,
+            },
+        })
+    }
+}
+
+fn connect<S>(
+    server_addr: S,
+    stream: OutputStreamHandle,
+    options: &ConnectOptions,
+) -> Result<Connection, ClientError>
 where
-    A: ToSocketAddrs,
+    S: AsRef<str>,
 {
-    let mut con_sock = ConnectSocket::bind("0.0.0.0:0")?;
-    let server_addr = match server_addrs.to_socket_addrs() {
-        Ok(ref mut a) => a.next().ok_or(ClientError::InvalidServerAddress),
-        Err(_) => Err(ClientError::InvalidServerAddress),
-    }?;
+    // these are sent quoted in string commands, which the server would split
+    let mut user_info = options.player_name.iter().chain(options.password.iter());
+    if user_info.any(|s| !console::is_quotable(s)) {
+        Err(ClientError::UnquotableUserInfo)?;
+    }
+
+    // QuakeWorld servers are picked out by scheme or by their default port
+    let server_addr = server_addr.as_ref();
+    let (server_addr, qw) = match server_addr.strip_prefix(QW_SCHEME) {
+        Some(addr) => (addr, true),
+        None => (server_addr, false),
+    };
+    let default_port = match qw {
+        true => net::qw::DEFAULT_PORT,
+        false => net::DEFAULT_PORT,
+    };
+    let server_addr = net::resolve_addr(server_addr, default_port)
+        .map_err(|_| ClientError::InvalidServerAddress)?;
+
+    if qw || server_addr.port() == net::qw::DEFAULT_PORT {
+        return Ok(Connection {
+            state: ClientState::new(stream),
+            kind: ConnectionKind::QuakeWorld(QwSession::connect(server_addr, options)?),
+            conn_state: ConnectionState::SignOn(SignOnStage::Prespawn),
+            protocol: Protocol::QuakeWorld,
+            loader: None,
+            prespawn_pending: false,
+            downloader: None,
+        });
+    }
+
+    let mut con_sock = ConnectSocket::bind_for_port(&server_addr, options.client_port)?;
 
     let mut response = None;
 
@@ -1309,8 +2333,17 @@ where
         _ => Err(ClientError::InvalidConnectResponse)?,
     };
 
-    let mut new_addr = server_addr;
-    new_addr.set_port(port);
+    let new_addr = match options.accept_addr {
+        Some(ref addr) => {
+            net::resolve_addr(addr, port).map_err(|_| ClientError::InvalidServerAddress)?
+        }
+        None => {
+            let mut a = server_addr;
+            a.set_port(port);
+            a
+        }
+    };
+    debug!("Game connection address is {}", new_addr);
 
     // we're done with the connection socket, so turn it into a QSocket with the new address
     let qsock = con_sock.into_qsocket(new_addr);
@@ -1320,29 +2353,50 @@ where
         kind: ConnectionKind::Server {
             qsock,
             compose: Vec::new(),
+            server_addr,
+            player_name: options.player_name.clone(),
+            password: options.password.clone(),
+            rate: options.rate,
         },
         conn_state: ConnectionState::SignOn(SignOnStage::Prespawn),
+        protocol: Protocol::NetQuake,
+        loader: None,
+        prespawn_pending: false,
+        downloader: None,
     })
 }
 
-// TODO: when an audio device goes down, every command with an
-// OutputStreamHandle needs to be reconstructed so it doesn't pass out
-// references to a dead output stream
-
 // TODO: this will hang while connecting. ideally, input should be handled in a
 // separate thread so the OS doesn't think the client has gone unresponsive.
 fn cmd_connect(
     conn: Rc<RefCell<Option<Connection>>>,
     input: Rc<RefCell<Input>>,
-    stream: OutputStreamHandle,
+    audio: Rc<RefCell<AudioOutput>>,
+    cvars: Rc<RefCell<CvarRegistry>>,
+    address_book: Rc<RefCell<AddressBook>>,
 ) -> Box<dyn Fn(&[&str]) -> String> {
     Box::new(move |args| {
         if args.len() < 1 {
             // TODO: print to console
-            return "usage: connect <server_ip>:<server_port>".to_owned();
+            return "usage: connect <address>[:<port>] | <alias>".to_owned();
         }
 
-        match connect(args[0], stream.clone()) {
+        let mut options = match ConnectOptions::from_cvars(&cvars.borrow()) {
+            Ok(o) => o,
+            Err(e) => return format!("{}", e),
+        };
+
+        // aliases take precedence over hostnames
+        let address = match address_book.borrow().get(args[0]) {
+            Some(entry) => {
+                options.player_name = entry.name.clone();
+                options.password = entry.password.clone();
+                entry.address.clone()
+            }
+            None => args[0].to_owned(),
+        };
+
+        match connect(address, audio.borrow().handle(), &options) {
             Ok(new_conn) => {
                 conn.replace(Some(new_conn));
                 input.borrow_mut().set_focus(InputFocus::Game);
@@ -1391,7 +2445,7 @@ fn cmd_playdemo(
     conn: Rc<RefCell<Option<Connection>>>,
     vfs: Rc<Vfs>,
     input: Rc<RefCell<Input>>,
-    stream: OutputStreamHandle,
+    audio: Rc<RefCell<AudioOutput>>,
 ) -> Box<dyn Fn(&[&str]) -> String> {
     Box::new(move |args| {
         if args.len() != 1 {
@@ -1409,9 +2463,13 @@ fn cmd_playdemo(
         };
 
         conn.replace(Some(Connection {
-            state: ClientState::new(stream.clone()),
+            state: ClientState::new(audio.borrow().handle()),
             kind: ConnectionKind::Demo(demo_server),
             conn_state: ConnectionState::SignOn(SignOnStage::Prespawn),
+            protocol: Protocol::NetQuake,
+            loader: None,
+            prespawn_pending: false,
+            downloader: None,
         }));
 
         input.borrow_mut().set_focus(InputFocus::Game);
@@ -1423,7 +2481,7 @@ fn cmd_startdemos(
     conn: Rc<RefCell<Option<Connection>>>,
     vfs: Rc<Vfs>,
     input: Rc<RefCell<Input>>,
-    stream: OutputStreamHandle,
+    audio: Rc<RefCell<AudioOutput>>,
     demo_queue: Rc<RefCell<VecDeque<String>>>,
 ) -> Box<dyn Fn(&[&str]) -> String> {
     Box::new(move |args| {
@@ -1449,9 +2507,13 @@ fn cmd_startdemos(
         };
 
         conn.replace(Some(Connection {
-            state: ClientState::new(stream.clone()),
+            state: ClientState::new(audio.borrow().handle()),
             kind: ConnectionKind::Demo(demo_server),
             conn_state: ConnectionState::SignOn(SignOnStage::Prespawn),
+            protocol: Protocol::NetQuake,
+            loader: None,
+            prespawn_pending: false,
+            downloader: None,
         }));
 
         input.borrow_mut().set_focus(InputFocus::Game);
@@ -1460,6 +2522,231 @@ fn cmd_startdemos(
     })
 }
 
+fn cmd_snd_devices(audio: Rc<RefCell<AudioOutput>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| {
+        let audio = audio.borrow();
+        let mut out = String::new();
+        for name in AudioOutput::device_names() {
+            let marker = if name == audio.device_name() {
+                "*"
+            } else {
+                " "
+            };
+            out.push_str(&format!("{} {}\n", marker, name));
+        }
+
+        out
+    })
+}
+
+// the list is printed when it arrives, so the game keeps running meanwhile
+fn cmd_masterlist(
+    cvars: Rc<RefCell<CvarRegistry>>,
+    output: Sender<String>,
+) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| {
+        let master = match args.len() {
+            0 => match cvars.borrow().get("cl_master") {
+                Ok(m) => m,
+                Err(e) => return format!("{}", e),
+            },
+            1 => args[0].to_owned(),
+            _ => return "usage: masterlist [address]".to_owned(),
+        };
+
+        let output = output.clone();
+        thread::spawn(move || {
+            let servers =
+                net::resolve_addr(&master, master::DEFAULT_MASTER_PORT).and_then(|addr| {
+                    master::fetch_server_list(
+                        &DpMaster::default(),
+                        addr,
+                        Duration::seconds(MASTER_TIMEOUT),
+                    )
+                });
+
+            let text = match servers {
+                Ok(servers) => {
+                    let mut out = String::new();
+                    for server in servers.iter() {
+                        out.push_str(&format!("{}\n", server));
+                    }
+                    out.push_str(&format!("{} servers\n", servers.len()));
+                    out
+                }
+                Err(e) => format!("Couldn't fetch server list from {}: {}\n", master, e),
+            };
+
+            // the client may have shut down while we waited
+            let _ = output.send(text);
+        });
+
+        String::new()
+    })
+}
+
+// the reply is printed when it arrives, so the game keeps running meanwhile
+fn cmd_rcon(
+    conn: Rc<RefCell<Option<Connection>>>,
+    cvars: Rc<RefCell<CvarRegistry>>,
+    output: Sender<String>,
+) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| {
+        if args.is_empty() {
+            return "usage: rcon <command>".to_owned();
+        }
+
+        let (address, password) = {
+            let cvars = cvars.borrow();
+            match (cvars.get("rcon_address"), cvars.get("rcon_password")) {
+                (Ok(a), Ok(p)) => (a, p),
+                (Err(e), _) | (_, Err(e)) => return format!("{}", e),
+            }
+        };
+
+        if password.is_empty() {
+            return "rcon_password is not set".to_owned();
+        }
+
+        // send to the current server unless rcon_address says otherwise
+        let remote = if address.is_empty() {
+            match *conn.borrow() {
+                Some(Connection {
+                    kind: ConnectionKind::Server { ref qsock, .. },
+                    ..
+                }) => qsock.remote(),
+                _ => return "not connected to a server; set rcon_address".to_owned(),
+            }
+        } else {
+            match net::resolve_addr(&address, net::DEFAULT_PORT) {
+                Ok(a) => a,
+                Err(e) => return format!("Couldn't resolve {}: {}", address, e),
+            }
+        };
+
+        // the console has already split the command up, so put quotes back
+        // around arguments that need them
+        let command = args
+            .iter()
+            .map(|a| match a.contains(char::is_whitespace) {
+                true => format!("\"{}\"", a),
+                false => a.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let output = output.clone();
+        thread::spawn(move || {
+            let reply = ConnectSocket::bind_for(&remote).and_then(|mut sock| {
+                sock.send_rcon(remote, password, command, Duration::seconds(RCON_TIMEOUT))
+            });
+            let text = match reply {
+                Ok(Some(text)) => text,
+                Ok(None) => format!("No rcon reply from {}\n", remote),
+                Err(e) => format!("rcon failed: {}\n", e),
+            };
+
+            // the client may have shut down while we waited
+            let _ = output.send(text);
+        });
+
+        String::new()
+    })
+}
+
+fn cmd_play(local_sounds: Rc<RefCell<LocalSoundPlayer>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| {
+        let volume = match args.len() {
+            1 => 1.0,
+            2 => match args[1].parse::<f32>() {
+                Ok(v) => v,
+                Err(_) => return format!("Invalid volume: {}", args[1]),
+            },
+            _ => return "usage: play <sound> [volume]".to_owned(),
+        };
+
+        match local_sounds.borrow_mut().play(args[0], volume) {
+            Ok(()) => String::new(),
+            Err(e) => format!("Couldn't play {}: {}", args[0], e),
+        }
+    })
+}
+
+fn cmd_playvol(local_sounds: Rc<RefCell<LocalSoundPlayer>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| {
+        if args.is_empty() || args.len() % 2 != 0 {
+            return "usage: playvol <sound> <volume> [<sound> <volume> ...]".to_owned();
+        }
+
+        let mut out = String::new();
+        for pair in args.chunks(2) {
+            let volume = match pair[1].parse::<f32>() {
+                Ok(v) => v,
+                Err(_) => {
+                    out.push_str(&format!("Invalid volume: {}\n", pair[1]));
+                    continue;
+                }
+            };
+
+            if let Err(e) = local_sounds.borrow_mut().play(pair[0], volume) {
+                out.push_str(&format!("Couldn't play {}: {}\n", pair[0], e));
+            }
+        }
+
+        out
+    })
+}
+
+fn cmd_soundlist(
+    conn: Rc<RefCell<Option<Connection>>>,
+    local_sounds: Rc<RefCell<LocalSoundPlayer>>,
+) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| {
+        fn describe(id: Option<usize>, src: &AudioSource) -> String {
+            let id = match id {
+                Some(i) => format!("{:3}", i),
+                None => "  -".to_owned(),
+            };
+            let duration = match src.duration() {
+                Some(d) => format!("{:6.2}s", d.as_secs_f32()),
+                None => "      ?".to_owned(),
+            };
+
+            format!(
+                "{} {} {:5}Hz {}ch {}\n",
+                id,
+                duration,
+                src.sample_rate(),
+                src.channels(),
+                src.name()
+            )
+        }
+
+        let mut out = String::new();
+        let mut count = 0;
+
+        // sounds precached by the server, indexed by sound ID
+        if let Some(ref conn) = *conn.borrow() {
+            for (id, src) in conn.state.sounds.iter().enumerate() {
+                out.push_str(&describe(Some(id), src));
+                count += 1;
+            }
+        }
+
+        // sounds loaded by play or playvol
+        let local = local_sounds.borrow();
+        let mut local: Vec<_> = local.sounds().collect();
+        local.sort_by(|a, b| a.name().cmp(b.name()));
+        for src in local {
+            out.push_str(&describe(None, src));
+            count += 1;
+        }
+
+        out.push_str(&format!("{} sounds\n", count));
+        out
+    })
+}
+
 fn cmd_music(music_player: Rc<RefCell<MusicPlayer>>) -> Box<dyn Fn(&[&str]) -> String> {
     Box::new(move |args| {
         if args.len() != 1 {
@@ -0,0 +1,825 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! QuakeWorld server connections.
+//!
+//! A [`QwSession`] runs the QuakeWorld sign-on and translates what the server
+//! sends into the NetQuake commands the rest of the client understands, so a
+//! QuakeWorld game is played through the same [`ClientState`] as any other.
+//!
+//! [`ClientState`]: crate::client::state::ClientState
+
+use std::{
+    collections::HashMap,
+    io::{BufReader, Read},
+    net::{SocketAddr, UdpSocket},
+    time::Instant,
+};
+
+use crate::{
+    client::{ClientError, ConnectOptions},
+    common::{
+        net::{
+            self,
+            qw::{
+                self, apply_packet_entities, QwChannel, QwClientCmd, QwEntityState, QwPlayerInfo,
+                QwServerCmd, QwTempEntity, UserCmd, Userinfo,
+            },
+            ClientCmd, ClientStat, EntityEffects, EntityUpdate, GameType, ItemFlags, NetError,
+            PlayerColor, PlayerData, PointEntityKind, ServerCmd, SignOnStage, TempEntity,
+        },
+        vfs::Vfs,
+    },
+};
+
+use cgmath::{Deg, Vector3, Zero};
+use chrono::Duration;
+
+// how long to wait for each step of the handshake
+const HANDSHAKE_TIMEOUT_MS: i64 = 2500;
+
+// the server only sends a frame in response to a packet, so this sets the
+// update rate
+const SEND_INTERVAL_MS: i64 = 1000 / 72;
+
+// servers won't simulate more than this much time for one move command
+const MAX_MOVE_MSEC: i64 = 250;
+
+// stats that only QuakeWorld sends
+const STAT_ITEMS: usize = 15;
+const STAT_VIEWHEIGHT: usize = 16;
+const MAX_STATS: usize = 32;
+
+const PLAYER_MODEL: &str = "progs/player.mdl";
+
+// particle colors of the effects that are temp entities in QuakeWorld
+const BLOOD_COLOR: u8 = 73;
+const LIGHTNING_BLOOD_COLOR: u8 = 225;
+
+/// The client's side of a QuakeWorld game.
+pub struct QwSession {
+    chan: QwChannel,
+
+    // identifies the level, so stale sign-on replies can be ignored
+    server_count: i32,
+    player_id: u8,
+    level_name: String,
+
+    model_precache: Vec<String>,
+    sound_precache: Vec<String>,
+    player_model: u16,
+    map_checksum: i32,
+
+    baselines: HashMap<u16, QwEntityState>,
+
+    // the entity lists of recent frames, which updates are delta'd against
+    frames: Vec<Vec<QwEntityState>>,
+    stats: [i32; MAX_STATS],
+
+    // the last two movement commands sent and the one being built, oldest
+    // first
+    cmds: [UserCmd; 3],
+
+    start_time: Instant,
+}
+
+impl QwSession {
+    /// Performs the handshake with the server at `server_addr` and starts
+    /// signing on.
+    pub fn connect(server_addr: SocketAddr, options: &ConnectOptions) -> Result<Self, ClientError> {
+        let mut userinfo = Userinfo::new();
+        userinfo.set("name", options.player_name.as_deref().unwrap_or("unnamed"))?;
+        if let Some(ref password) = options.password {
+            userinfo.set("password", password)?;
+        }
+        if let Some(rate) = options.rate {
+            userinfo.set("rate", rate.to_string())?;
+        }
+        userinfo.set("topcolor", "0")?;
+        userinfo.set("bottomcolor", "0")?;
+
+        let local_addr = match server_addr {
+            SocketAddr::V4(_) => format!("0.0.0.0:{}", options.client_port),
+            SocketAddr::V6(_) => format!("[::]:{}", options.client_port),
+        };
+        let socket = UdpSocket::bind(local_addr).map_err(NetError::from)?;
+
+        // lets the server tell us apart from other clients behind the same NAT
+        let qport = rand::random::<u16>();
+
+        println!("Connecting to QuakeWorld server {}...", server_addr);
+        qw::handshake(
+            &socket,
+            server_addr,
+            qport,
+            &userinfo,
+            Duration::milliseconds(HANDSHAKE_TIMEOUT_MS),
+        )?;
+
+        let mut session = QwSession {
+            chan: QwChannel::new(socket, server_addr, qport)?,
+            server_count: 0,
+            player_id: 0,
+            level_name: String::new(),
+            model_precache: Vec::new(),
+            sound_precache: Vec::new(),
+            player_model: 0,
+            map_checksum: 0,
+            baselines: HashMap::new(),
+            frames: vec![Vec::new(); qw::UPDATE_BACKUP],
+            stats: [0; MAX_STATS],
+            cmds: [UserCmd::NULL; 3],
+            start_time: Instant::now(),
+        };
+        session.string_cmd("new")?;
+
+        Ok(session)
+    }
+
+    pub fn time_since_recv(&self) -> Duration {
+        self.chan.time_since_recv()
+    }
+
+    /// Queues a console command for the server.
+    pub fn string_cmd<S>(&mut self, cmd: S) -> Result<(), NetError>
+    where
+        S: AsRef<str>,
+    {
+        let mut msg = Vec::new();
+        QwClientCmd::StringCmd {
+            cmd: cmd.as_ref().to_owned(),
+        }
+        .serialize(&mut msg)?;
+        self.chan.send_reliable(&msg)
+    }
+
+    /// Asks for the level's sign-on data, once its models are loaded.
+    pub fn prespawn(&mut self) -> Result<(), NetError> {
+        let cmd = format!("prespawn {} 0 {}", self.server_count, self.map_checksum);
+        self.string_cmd(cmd)
+    }
+
+    /// Tells the server the client is ready to play.
+    pub fn begin(&mut self) -> Result<(), NetError> {
+        let cmd = format!("begin {}", self.server_count);
+        self.string_cmd(cmd)
+    }
+
+    /// Sets the movement for the next packet from a frame's input.
+    ///
+    /// Frames run faster than packets are sent, so the frames in between are
+    /// merged: their time adds up, and buttons and impulses are kept until
+    /// they've been sent.
+    pub fn set_move(&mut self, cmd: &ClientCmd, frame_time: Duration) {
+        if let ClientCmd::Move {
+            angles,
+            fwd_move,
+            side_move,
+            up_move,
+            button_flags,
+            impulse,
+            ..
+        } = *cmd
+        {
+            let pending = &self.cmds[2];
+            let msec = (pending.msec as i64 + frame_time.num_milliseconds()).min(MAX_MOVE_MSEC);
+            self.cmds[2] = UserCmd {
+                angles,
+                forward: fwd_move,
+                side: side_move,
+                up: up_move,
+                buttons: pending.buttons | button_flags.bits(),
+                impulse: match impulse {
+                    0 => pending.impulse,
+                    i => i,
+                },
+                msec: msec as u8,
+            };
+        }
+    }
+
+    /// Sends a packet with the current movement if one is due.
+    pub fn send(&mut self) -> Result<(), NetError> {
+        if self.chan.time_since_send() < Duration::milliseconds(SEND_INTERVAL_MS) {
+            return Ok(());
+        }
+
+        let mut msg = Vec::new();
+        QwClientCmd::Move {
+            sequence: self.chan.outgoing_sequence(),
+            loss: 0,
+            cmds: self.cmds,
+        }
+        .serialize(&mut msg)?;
+        self.chan.transmit(&msg)?;
+
+        // keep moving the same way until the next frame's input arrives
+        let current = self.cmds[2];
+        self.cmds = [
+            self.cmds[1],
+            current,
+            UserCmd {
+                buttons: 0,
+                impulse: 0,
+                msec: 0,
+                ..current
+            },
+        ];
+
+        Ok(())
+    }
+
+    /// Reads every packet waiting from the server, returning the equivalent
+    /// NetQuake commands.
+    pub fn recv(&mut self, vfs: &Vfs) -> Result<Vec<ServerCmd>, NetError> {
+        let mut cmds = Vec::new();
+
+        while let Some(packet) = self.chan.recv()? {
+            let mut reader = BufReader::new(packet.as_slice());
+            let mut updates = Vec::new();
+            let mut own_info = None;
+
+            while let Some(cmd) = QwServerCmd::deserialize(&mut reader)? {
+                self.translate(vfs, cmd, &mut cmds, &mut updates, &mut own_info)?;
+            }
+
+            // entities that aren't updated are removed, so the whole frame
+            // shares one timestamp
+            if !updates.is_empty() {
+                cmds.push(ServerCmd::Time {
+                    time: self.start_time.elapsed().as_secs_f32(),
+                });
+                if let Some(info) = own_info {
+                    cmds.push(ServerCmd::PlayerData(self.player_data(&info)));
+                }
+                cmds.extend(updates.into_iter().map(ServerCmd::FastUpdate));
+            }
+        }
+
+        Ok(cmds)
+    }
+
+    fn translate(
+        &mut self,
+        vfs: &Vfs,
+        cmd: QwServerCmd,
+        cmds: &mut Vec<ServerCmd>,
+        updates: &mut Vec<EntityUpdate>,
+        own_info: &mut Option<QwPlayerInfo>,
+    ) -> Result<(), NetError> {
+        match cmd {
+            QwServerCmd::NoOp => (),
+            QwServerCmd::Disconnect => cmds.push(ServerCmd::Disconnect),
+
+            QwServerCmd::ServerData {
+                server_count,
+                player_id,
+                level_name,
+                ..
+            } => {
+                debug!("QuakeWorld level {} (count {})", level_name, server_count);
+                self.server_count = server_count;
+                self.player_id = player_id;
+                self.level_name = level_name;
+                self.model_precache.clear();
+                self.sound_precache.clear();
+                self.baselines.clear();
+                self.stats = [0; MAX_STATS];
+                self.string_cmd(format!("soundlist {} 0", server_count))?;
+            }
+
+            QwServerCmd::SoundList { names, next, .. } => {
+                self.sound_precache.extend(names);
+                match next {
+                    0 => self.string_cmd(format!("modellist {} 0", self.server_count))?,
+                    n => self.string_cmd(format!("soundlist {} {}", self.server_count, n))?,
+                }
+            }
+
+            QwServerCmd::ModelList { names, next, .. } => {
+                self.model_precache.extend(names);
+                match next {
+                    0 => self.start_level(vfs, cmds),
+                    n => self.string_cmd(format!("modellist {} {}", self.server_count, n))?,
+                }
+            }
+
+            QwServerCmd::StuffText { text } => self.stuff_text(&text, cmds)?,
+
+            QwServerCmd::Print { text, .. } => cmds.push(ServerCmd::Print { text }),
+            QwServerCmd::CenterPrint { text } => cmds.push(ServerCmd::CenterPrint { text }),
+            QwServerCmd::SetPause { paused } => cmds.push(ServerCmd::SetPause { paused }),
+
+            QwServerCmd::UpdateStat { stat, value } => self.set_stat(stat, value as i32, cmds),
+            QwServerCmd::UpdateStatLong { stat, value } => self.set_stat(stat, value, cmds),
+
+            QwServerCmd::Sound {
+                entity_id,
+                channel,
+                sound_id,
+                volume,
+                attenuation,
+                position,
+            } => cmds.push(ServerCmd::Sound {
+                volume,
+                attenuation: attenuation.map(|a| a as f32 / 64.0),
+                entity_id,
+                channel: channel as i8,
+                sound_id: sound_id as u16,
+                position,
+            }),
+
+            QwServerCmd::StopSound { entity_id, channel } => {
+                cmds.push(ServerCmd::StopSound { entity_id, channel })
+            }
+
+            QwServerCmd::SetAngle { angles } => cmds.push(ServerCmd::SetAngle { angles }),
+            QwServerCmd::LightStyle { id, value } => cmds.push(ServerCmd::LightStyle { id, value }),
+
+            QwServerCmd::UpdateFrags { player_id, frags } => {
+                if (player_id as usize) < net::MAX_CLIENTS {
+                    cmds.push(ServerCmd::UpdateFrags {
+                        player_id,
+                        new_frags: frags,
+                    });
+                }
+            }
+
+            QwServerCmd::UpdateUserinfo {
+                player_id,
+                userinfo,
+                ..
+            } => {
+                // the scoreboard only has room for NetQuake's player count
+                if (player_id as usize) < net::MAX_CLIENTS {
+                    let color = |key| {
+                        userinfo
+                            .get(key)
+                            .and_then(|c| c.parse::<u8>().ok())
+                            .unwrap_or(0)
+                    };
+                    cmds.push(ServerCmd::UpdateName {
+                        player_id,
+                        new_name: userinfo.get("name").unwrap_or("").to_owned(),
+                    });
+                    cmds.push(ServerCmd::UpdateColors {
+                        player_id,
+                        new_colors: PlayerColor::new(color("topcolor"), color("bottomcolor")),
+                    });
+                }
+            }
+
+            QwServerCmd::Damage {
+                armor,
+                blood,
+                source,
+            } => cmds.push(ServerCmd::Damage {
+                armor,
+                blood,
+                source,
+            }),
+
+            QwServerCmd::SpawnStatic { state } => cmds.push(ServerCmd::SpawnStatic {
+                model_id: state.model_id as u16,
+                frame_id: state.frame_id as u16,
+                colormap: state.colormap,
+                skin_id: state.skin_id,
+                origin: state.origin,
+                angles: state.angles,
+            }),
+
+            QwServerCmd::SpawnBaseline { baseline } => {
+                self.baselines.insert(baseline.ent_id, baseline);
+                cmds.push(ServerCmd::SpawnBaseline {
+                    ent_id: baseline.ent_id,
+                    model_id: baseline.model_id as u16,
+                    frame_id: baseline.frame_id as u16,
+                    colormap: baseline.colormap,
+                    skin_id: baseline.skin_id,
+                    origin: baseline.origin,
+                    angles: baseline.angles,
+                });
+            }
+
+            QwServerCmd::SpawnStaticSound {
+                origin,
+                sound_id,
+                volume,
+                attenuation,
+            } => cmds.push(ServerCmd::SpawnStaticSound {
+                origin,
+                sound_id: sound_id as u16,
+                volume,
+                attenuation,
+            }),
+
+            QwServerCmd::TempEntity { temp_entity } => cmds.push(match temp_entity {
+                QwTempEntity::Shared(temp_entity) => ServerCmd::TempEntity { temp_entity },
+                QwTempEntity::Gunshot { origin, .. } => ServerCmd::TempEntity {
+                    temp_entity: TempEntity::Point {
+                        kind: PointEntityKind::Gunshot,
+                        origin,
+                    },
+                },
+
+                // a count of 255 would be an explosion
+                QwTempEntity::Blood { count, origin } => ServerCmd::Particle {
+                    origin,
+                    direction: Vector3::zero(),
+                    count: (count as u16 * 20).min(254) as u8,
+                    color: BLOOD_COLOR,
+                },
+                QwTempEntity::LightningBlood { origin } => ServerCmd::Particle {
+                    origin,
+                    direction: Vector3::zero(),
+                    count: 50,
+                    color: LIGHTNING_BLOOD_COLOR,
+                },
+            }),
+
+            QwServerCmd::Intermission { angles, .. } => {
+                cmds.push(ServerCmd::SetAngle { angles });
+                cmds.push(ServerCmd::Intermission);
+            }
+
+            QwServerCmd::Finale { text } => cmds.push(ServerCmd::Finale { text }),
+            QwServerCmd::CdTrack { track } => cmds.push(ServerCmd::CdTrack {
+                track,
+                loop_: track,
+            }),
+            QwServerCmd::SellScreen => cmds.push(ServerCmd::SellScreen),
+            QwServerCmd::KilledMonster => cmds.push(ServerCmd::KilledMonster),
+            QwServerCmd::FoundSecret => cmds.push(ServerCmd::FoundSecret),
+
+            QwServerCmd::PlayerInfo(info) => {
+                updates.push(self.player_update(&info));
+                if info.player_id == self.player_id {
+                    *own_info = Some(info);
+                }
+            }
+
+            QwServerCmd::PacketEntities { delta_from, deltas } => {
+                let from = match delta_from {
+                    Some(f) => self.frames[f as usize & qw::UPDATE_MASK].clone(),
+                    None => Vec::new(),
+                };
+                let baselines = &self.baselines;
+                let entities = apply_packet_entities(&from, &deltas, |id| {
+                    baselines
+                        .get(&id)
+                        .copied()
+                        .unwrap_or_else(|| QwEntityState::empty(id))
+                })?;
+
+                updates.extend(entities.iter().map(entity_update));
+                let frame = self.chan.incoming_sequence() as usize & qw::UPDATE_MASK;
+                self.frames[frame] = entities;
+            }
+
+            // nothing in the client uses these yet
+            cmd => trace!("Ignoring QuakeWorld command {:?}", cmd.code()),
+        }
+
+        Ok(())
+    }
+
+    // the model and sound lists are complete, so the level can be loaded
+    fn start_level(&mut self, vfs: &Vfs, cmds: &mut Vec<ServerCmd>) {
+        self.player_model = self
+            .model_precache
+            .iter()
+            .position(|m| m == PLAYER_MODEL)
+            .map(|i| i as u16 + 1)
+            .unwrap_or(0);
+
+        // the world is the first model
+        self.map_checksum = match self.model_precache.first() {
+            Some(map) => match read_map_checksum(vfs, map) {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("Couldn't checksum {}: {}", map, e);
+                    0
+                }
+            },
+            None => 0,
+        };
+
+        cmds.push(ServerCmd::ServerInfo {
+            protocol_version: qw::PROTOCOL_VERSION,
+            max_clients: net::MAX_CLIENTS as u8,
+            game_type: GameType::Deathmatch,
+            message: self.level_name.clone(),
+            model_precache: self.model_precache.clone(),
+            sound_precache: self.sound_precache.clone(),
+        });
+        cmds.push(ServerCmd::SignOnStage {
+            stage: SignOnStage::Prespawn,
+        });
+    }
+
+    // the server drives the sign-on with stuffed commands
+    fn stuff_text(&mut self, text: &str, cmds: &mut Vec<ServerCmd>) -> Result<(), NetError> {
+        let mut console_text = String::new();
+
+        for line in text.lines() {
+            let line = line.trim();
+
+            if let Some(cmd) = line.strip_prefix("cmd ") {
+                self.string_cmd(cmd)?;
+            } else if line == "skins" {
+                // the sign-on data is all in, including the player entities
+                cmds.push(ServerCmd::SetView {
+                    ent_id: self.player_id as i16 + 1,
+                });
+                cmds.push(ServerCmd::SignOnStage {
+                    stage: SignOnStage::Begin,
+                });
+            } else if line == "reconnect" {
+                // the level is changing, so sign on again
+                self.string_cmd("new")?;
+            } else if line == "changing" || line.starts_with("fullserverinfo") {
+                continue;
+            } else if !line.is_empty() {
+                console_text.push_str(line);
+                console_text.push('\n');
+            }
+        }
+
+        if !console_text.is_empty() {
+            cmds.push(ServerCmd::StuffText { text: console_text });
+        }
+
+        Ok(())
+    }
+
+    fn set_stat(&mut self, stat: u8, value: i32, cmds: &mut Vec<ServerCmd>) {
+        let stat = stat as usize;
+        if stat >= MAX_STATS {
+            warn!("Invalid QuakeWorld stat {}", stat);
+            return;
+        }
+        self.stats[stat] = value;
+
+        // level totals aren't part of the player data
+        let total = match stat {
+            11 => Some(ClientStat::TotalSecrets),
+            12 => Some(ClientStat::TotalMonsters),
+            13 => Some(ClientStat::FoundSecrets),
+            14 => Some(ClientStat::KilledMonsters),
+            _ => None,
+        };
+        if let Some(stat) = total {
+            cmds.push(ServerCmd::UpdateStat { stat, value });
+        }
+    }
+
+    // the player's own stats are sent separately from their state
+    fn player_data(&self, info: &QwPlayerInfo) -> PlayerData {
+        let stat = |s: ClientStat| self.stats[s as usize];
+        let velocity = |i: usize| info.velocity[i].map(|v| v as f32);
+
+        PlayerData {
+            view_height: match self.stats[STAT_VIEWHEIGHT] {
+                0 => None,
+                h => Some(h as f32),
+            },
+            ideal_pitch: None,
+            punch_pitch: None,
+            velocity_x: velocity(0),
+            punch_yaw: None,
+            velocity_y: velocity(1),
+            punch_roll: None,
+            velocity_z: velocity(2),
+            items: ItemFlags::from_bits_truncate(self.stats[STAT_ITEMS] as u32),
+            on_ground: false,
+            in_water: false,
+            weapon_frame: Some(stat(ClientStat::WeaponFrame) as u16),
+            armor: Some(stat(ClientStat::Armor) as u16),
+            weapon: Some(stat(ClientStat::Weapon) as u16),
+            health: stat(ClientStat::Health) as i16,
+            ammo: stat(ClientStat::Ammo) as u16,
+            ammo_shells: stat(ClientStat::Shells) as u16,
+            ammo_nails: stat(ClientStat::Nails) as u16,
+            ammo_rockets: stat(ClientStat::Rockets) as u16,
+            ammo_cells: stat(ClientStat::Cells) as u16,
+            active_weapon: stat(ClientStat::ActiveWeapon) as u8,
+        }
+    }
+
+    // players are entities 1 through MAX_CLIENTS
+    fn player_update(&self, info: &QwPlayerInfo) -> EntityUpdate {
+        // other players' view angles come with their last command, with the
+        // pitch scaled down so the model doesn't lean too far
+        let angles = info.command.map(|c| c.angles);
+
+        EntityUpdate {
+            ent_id: info.player_id as u16 + 1,
+            model_id: Some(info.model_id.map(|m| m as u16).unwrap_or(self.player_model)),
+            frame_id: Some(info.frame_id as u16),
+            colormap: match (info.player_id as usize) < net::MAX_CLIENTS {
+                true => Some(info.player_id + 1),
+                false => None,
+            },
+            skin_id: info.skin_id,
+            effects: info.effects.map(EntityEffects::from_bits_truncate),
+            origin_x: Some(info.origin.x),
+            pitch: angles.map(|a| Deg(-a.x.0 / 3.0)),
+            origin_y: Some(info.origin.y),
+            yaw: angles.map(|a| a.y),
+            origin_z: Some(info.origin.z),
+            roll: angles.map(|a| a.z),
+            no_lerp: false,
+        }
+    }
+}
+
+impl Drop for QwSession {
+    fn drop(&mut self) {
+        // the disconnect isn't acknowledged, so send it a few times
+        let drop = QwClientCmd::StringCmd {
+            cmd: String::from("drop"),
+        };
+        let mut msg = Vec::new();
+        if drop.serialize(&mut msg).is_ok() {
+            for _ in 0..3 {
+                let _ = self.chan.transmit(&msg);
+            }
+        }
+    }
+}
+
+fn entity_update(state: &QwEntityState) -> EntityUpdate {
+    EntityUpdate {
+        ent_id: state.ent_id,
+        model_id: Some(state.model_id as u16),
+        frame_id: Some(state.frame_id as u16),
+        // only players have their own colors
+        colormap: match state.colormap {
+            0 => None,
+            c => Some(c),
+        },
+        skin_id: Some(state.skin_id),
+        effects: Some(EntityEffects::from_bits_truncate(state.effects)),
+        origin_x: Some(state.origin.x),
+        pitch: Some(state.angles.x),
+        origin_y: Some(state.origin.y),
+        yaw: Some(state.angles.y),
+        origin_z: Some(state.origin.z),
+        roll: Some(state.angles.z),
+        no_lerp: false,
+    }
+}
+
+fn read_map_checksum(vfs: &Vfs, name: &str) -> Result<i32, failure::Error> {
+    let mut bsp = Vec::new();
+    vfs.open(name)?.read_to_end(&mut bsp)?;
+    Ok(qw::map_checksum(&bsp)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::common::net::ButtonFlags;
+
+    fn session() -> QwSession {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        QwSession {
+            chan: QwChannel::new(client, server.local_addr().unwrap(), 1234).unwrap(),
+            server_count: 3,
+            player_id: 1,
+            level_name: String::new(),
+            model_precache: Vec::new(),
+            sound_precache: Vec::new(),
+            player_model: 0,
+            map_checksum: 0,
+            baselines: HashMap::new(),
+            frames: vec![Vec::new(); qw::UPDATE_BACKUP],
+            stats: [0; MAX_STATS],
+            cmds: [UserCmd::NULL; 3],
+            start_time: Instant::now(),
+        }
+    }
+
+    fn translate(session: &mut QwSession, cmd: QwServerCmd) -> Vec<ServerCmd> {
+        let mut cmds = Vec::new();
+        session
+            .translate(&Vfs::new(), cmd, &mut cmds, &mut Vec::new(), &mut None)
+            .unwrap();
+        cmds
+    }
+
+    #[test]
+    fn test_model_list_starts_level() {
+        let mut session = session();
+        let names = vec![
+            String::from("maps/dm4.bsp"),
+            String::from("progs/player.mdl"),
+        ];
+        let cmds = translate(
+            &mut session,
+            QwServerCmd::ModelList {
+                start: 0,
+                names: names.clone(),
+                next: 0,
+            },
+        );
+
+        assert_eq!(session.player_model, 2);
+        match cmds[0] {
+            ServerCmd::ServerInfo {
+                ref model_precache, ..
+            } => assert_eq!(*model_precache, names),
+            ref c => panic!("expected server info, got {:?}", c),
+        }
+        assert_eq!(
+            cmds[1],
+            ServerCmd::SignOnStage {
+                stage: SignOnStage::Prespawn
+            }
+        );
+    }
+
+    #[test]
+    fn test_stuff_text_drives_sign_on() {
+        let mut session = session();
+        let cmds = translate(
+            &mut session,
+            QwServerCmd::StuffText {
+                text: String::from(
+                    "fullserverinfo \"\\maxclients\\8\"\ncmd spawn 3 0\nskins\nbf\n",
+                ),
+            },
+        );
+
+        assert_eq!(
+            cmds,
+            vec![
+                ServerCmd::SetView { ent_id: 2 },
+                ServerCmd::SignOnStage {
+                    stage: SignOnStage::Begin
+                },
+                ServerCmd::StuffText {
+                    text: String::from("bf\n")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_moves_between_packets_are_merged() {
+        let mut session = session();
+        let input = |fwd_move, button_flags, impulse| ClientCmd::Move {
+            send_time: Duration::zero(),
+            angles: Vector3::new(Deg(0.0), Deg(90.0), Deg(0.0)),
+            fwd_move,
+            side_move: 0,
+            up_move: 0,
+            button_flags,
+            impulse,
+        };
+
+        session.set_move(
+            &input(200, ButtonFlags::ATTACK, 7),
+            Duration::milliseconds(5),
+        );
+        session.set_move(
+            &input(400, ButtonFlags::empty(), 0),
+            Duration::milliseconds(6),
+        );
+        assert_eq!(session.cmds[2].forward, 400);
+        assert_eq!(session.cmds[2].buttons, ButtonFlags::ATTACK.bits());
+        assert_eq!(session.cmds[2].impulse, 7);
+        assert_eq!(session.cmds[2].msec, 11);
+
+        // the sent command becomes the newest of the resent ones
+        let sent = session.cmds[2];
+        std::thread::sleep(std::time::Duration::from_millis(
+            SEND_INTERVAL_MS as u64 + 5,
+        ));
+        session.send().unwrap();
+        assert_eq!(session.cmds[1], sent);
+        assert_eq!(session.cmds[2].forward, 400);
+        assert_eq!(session.cmds[2].buttons, 0);
+        assert_eq!(session.cmds[2].impulse, 0);
+        assert_eq!(session.cmds[2].msec, 0);
+    }
+}
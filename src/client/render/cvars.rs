@@ -21,6 +21,28 @@
 use crate::common::console::CvarRegistry;
 
 pub fn register_cvars(cvars: &CvarRegistry) {
+    cvars.register_archive("con_font", "").unwrap();
+    cvars.register_archive("con_scale", "0").unwrap();
+    cvars.register_archive("con_scrollback", "1024").unwrap();
+    cvars.register_archive("r_cshiftpercent", "100").unwrap();
+    cvars.register_archive("r_lerpmodels", "1").unwrap();
     cvars.register("r_lightmap", "0").unwrap();
+    cvars.register_archive("r_mipmap", "1").unwrap();
     cvars.register("r_msaa_samples", "4").unwrap();
+    cvars.register_archive("r_overbright", "1").unwrap();
+    cvars.register_archive("r_scale", "1").unwrap();
+    cvars.register_archive("r_sharpness", "0.5").unwrap();
+    cvars.register("r_skybox", "").unwrap();
+    cvars.register("r_speeds", "0").unwrap();
+    cvars.register_archive("r_texturecompression", "0").unwrap();
+    cvars.register_archive("r_upscale", "0").unwrap();
+    cvars.register_archive("r_wateralpha", "1").unwrap();
+    cvars.register_archive("sbar_size", "0").unwrap();
+    cvars.register_archive("vid_fullscreen", "0").unwrap();
+    cvars.register_archive("vid_height", "0").unwrap();
+    cvars.register_archive("vid_monitor", "0").unwrap();
+    cvars.register_archive("vid_refreshrate", "0").unwrap();
+    cvars.register_archive("vid_vsync", "0").unwrap();
+    cvars.register_archive("vid_width", "0").unwrap();
+    cvars.register_archive("viewsize", "100").unwrap();
 }
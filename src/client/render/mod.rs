@@ -49,19 +49,31 @@ mod cvars;
 mod error;
 mod palette;
 mod pipeline;
+mod soft;
+mod stats;
+mod surface;
 mod target;
+mod texture;
 mod ui;
 mod uniform;
 mod warp;
 mod world;
 
+pub use crate::common::plugin::HudText;
 pub use cvars::register_cvars;
 pub use error::{RenderError, RenderErrorKind};
 pub use palette::Palette;
 pub use pipeline::Pipeline;
 pub use postprocess::PostProcessRenderer;
+pub use soft::{golden, Colormap, DynamicLight, SoftwareRenderer};
+pub use stats::{FrameStats, RenderStats, Section, SectionStats};
+pub use surface::{present_mode, WindowSurface};
 pub use target::{RenderTarget, RenderTargetResolve, SwapChainTarget};
-pub use ui::{hud::HudState, UiOverlay, UiRenderer, UiState};
+pub use texture::{TextureCache, TextureCompression, TextureSettings};
+pub use ui::{
+    hud::{HudState, SbarLines},
+    UiOverlay, UiRenderer, UiState,
+};
 pub use world::{
     deferred::{DeferredRenderer, DeferredUniforms, PointLight},
     Camera, WorldRenderer,
@@ -72,35 +84,43 @@ use std::{
     cell::{Cell, Ref, RefCell, RefMut},
     mem::size_of,
     num::{NonZeroU32, NonZeroU64, NonZeroU8},
+    path::Path,
     rc::Rc,
+    str::FromStr,
 };
 
 use crate::{
     client::{
+        download::DownloadStatus,
         entity::MAX_LIGHTS,
-        input::InputFocus,
+        input::{chat::ChatInput, InputFocus},
         menu::Menu,
         render::{
             blit::BlitPipeline,
             target::{DeferredPassTarget, FinalPassTarget, InitialPassTarget},
-            ui::{glyph::GlyphPipeline, quad::QuadPipeline},
+            ui::{
+                glyph::{GlyphPipeline, GlyphRendererCommand},
+                layout::{Anchor, Layout, ScreenPosition, Size},
+                quad::{QuadPipeline, QuadRendererCommand, QuadTexture},
+            },
             uniform::DynamicUniformBuffer,
             world::{
                 alias::AliasPipeline,
                 brush::BrushPipeline,
                 deferred::DeferredPipeline,
                 particle::ParticlePipeline,
-                postprocess::{self, PostProcessPipeline},
+                postprocess::{self, PostProcessPipeline, UpscaleFilter},
                 sprite::SpritePipeline,
                 EntityUniforms,
             },
         },
+        state::ClientState,
         Connection, ConnectionKind,
     },
     common::{
         console::{Console, CvarRegistry},
-        model::Model,
-        net::SignOnStage,
+        model::{Model, ModelKind},
+        net::{GameType, SignOnStage},
         vfs::Vfs,
         wad::Wad,
     },
@@ -120,6 +140,18 @@ const LIGHT_ATTACHMENT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8U
 const DIFFUSE_TEXTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
 const FULLBRIGHT_TEXTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
 const LIGHTMAP_TEXTURE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+// limits of r_scale
+const MIN_RENDER_SCALE: f32 = 0.25;
+const MAX_RENDER_SCALE: f32 = 2.0;
+
+/// Returns the texture format used for diffuse textures with the given compression.
+fn compressed_diffuse_format(compression: TextureCompression) -> Option<wgpu::TextureFormat> {
+    match compression {
+        TextureCompression::None => None,
+        TextureCompression::Bc1 => Some(wgpu::TextureFormat::Bc1RgbaUnorm),
+        TextureCompression::Bc7 => Some(wgpu::TextureFormat::Bc7RgbaUnorm),
+    }
+}
 
 /// Create a `wgpu::TextureDescriptor` appropriate for the provided texture data.
 pub fn texture_descriptor<'a>(
@@ -127,6 +159,17 @@ pub fn texture_descriptor<'a>(
     width: u32,
     height: u32,
     format: wgpu::TextureFormat,
+) -> wgpu::TextureDescriptor {
+    mipmapped_texture_descriptor(label, width, height, 1, format)
+}
+
+/// Create a `wgpu::TextureDescriptor` with room for `mip_level_count` mip levels.
+pub fn mipmapped_texture_descriptor<'a>(
+    label: Option<&'a str>,
+    width: u32,
+    height: u32,
+    mip_level_count: u32,
+    format: wgpu::TextureFormat,
 ) -> wgpu::TextureDescriptor {
     wgpu::TextureDescriptor {
         label,
@@ -135,7 +178,7 @@ pub fn texture_descriptor<'a>(
             height,
             depth_or_array_layers: 1,
         },
-        mip_level_count: 1,
+        mip_level_count,
         sample_count: 1,
         dimension: wgpu::TextureDimension::D2,
         format,
@@ -180,6 +223,70 @@ pub fn create_texture<'a>(
     texture
 }
 
+/// Create a texture and upload every level of the provided mip chain.
+///
+/// `levels` holds the data for each mip level in order, starting with the base level. For
+/// block-compressed formats, each level holds tightly packed 4x4 blocks.
+pub fn create_mipmapped_texture<'a>(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    label: Option<&'a str>,
+    width: u32,
+    height: u32,
+    format: wgpu::TextureFormat,
+    levels: &[Vec<u8>],
+) -> wgpu::Texture {
+    trace!(
+        "Creating mipmapped texture ({:?}: {}x{}, {} levels)",
+        format,
+        width,
+        height,
+        levels.len()
+    );
+    let level_count = levels.len() as u32;
+    let texture = device.create_texture(&mipmapped_texture_descriptor(
+        label,
+        width,
+        height,
+        level_count,
+        format,
+    ));
+
+    for (level, data) in levels.iter().enumerate() {
+        let (w, h) = texture::mip_level_size(width, height, level as u32);
+        let bytes_per_row = match format {
+            wgpu::TextureFormat::Bc1RgbaUnorm => {
+                w / texture::BC_BLOCK_SIZE * texture::BC1_BLOCK_BYTES as u32
+            }
+            wgpu::TextureFormat::Bc7RgbaUnorm => {
+                w / texture::BC_BLOCK_SIZE * texture::BC7_BLOCK_BYTES as u32
+            }
+            _ => data.len() as u32 / h,
+        };
+
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level: level as u32,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            data,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(bytes_per_row),
+                rows_per_image: None,
+            },
+            wgpu::Extent3d {
+                width: w,
+                height: h,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    texture
+}
+
 pub struct DiffuseData<'a> {
     pub rgba: Cow<'a, [u8]>,
 }
@@ -234,6 +341,45 @@ pub struct Extent2d {
     pub height: u32,
 }
 
+impl Extent2d {
+    /// Returns this extent scaled by `scale`, with each dimension at least 1.
+    pub fn scaled(&self, scale: f32) -> Extent2d {
+        Extent2d {
+            width: ((self.width as f32 * scale).round() as u32).max(1),
+            height: ((self.height as f32 * scale).round() as u32).max(1),
+        }
+    }
+}
+
+/// Selects how the world is drawn. This is fixed at startup.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RendererBackend {
+    /// Draw everything on the GPU with deferred lighting.
+    Hardware,
+
+    /// Rasterize the world on the CPU with the 8-bit palette and colormap, then display the
+    /// result on the GPU.
+    Software,
+}
+
+impl Default for RendererBackend {
+    fn default() -> Self {
+        RendererBackend::Hardware
+    }
+}
+
+impl FromStr for RendererBackend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "hardware" | "gl" => Ok(RendererBackend::Hardware),
+            "software" | "soft" => Ok(RendererBackend::Software),
+            _ => bail!("\"{}\" isn't a renderer (expected hardware or software)", s),
+        }
+    }
+}
+
 impl std::convert::Into<wgpu::Extent3d> for Extent2d {
     fn into(self) -> wgpu::Extent3d {
         wgpu::Extent3d {
@@ -267,6 +413,7 @@ pub struct GraphicsState {
     entity_uniform_buffer: RefCell<DynamicUniformBuffer<EntityUniforms>>,
     diffuse_sampler: wgpu::Sampler,
     lightmap_sampler: wgpu::Sampler,
+    skybox_sampler: wgpu::Sampler,
 
     sample_count: Cell<u32>,
 
@@ -282,6 +429,14 @@ pub struct GraphicsState {
 
     default_lightmap: wgpu::Texture,
     default_lightmap_view: wgpu::TextureView,
+    default_skybox: wgpu::Texture,
+    default_skybox_view: wgpu::TextureView,
+
+    texture_settings: Cell<TextureSettings>,
+    texture_cache: Option<TextureCache>,
+
+    stats: RenderStats,
+    backend: RendererBackend,
 
     vfs: Rc<Vfs>,
     palette: Palette,
@@ -345,24 +500,35 @@ impl GraphicsState {
             ..Default::default()
         });
 
+        let skybox_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("skybox sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        // bound when no skybox is loaded, never sampled
+        let black: &[u8] = &[0, 0, 0, 0xFF];
+        let default_skybox = world::sky::create_skybox_texture(&device, &queue, 1, &[black; 6]);
+        let default_skybox_view = world::sky::create_skybox_view(&default_skybox);
+
         let world_bind_group_layouts: Vec<wgpu::BindGroupLayout> =
             world::BIND_GROUP_LAYOUT_DESCRIPTORS
                 .iter()
                 .map(|desc| device.create_bind_group_layout(desc))
                 .collect();
         let world_bind_groups = vec![
-            device.create_bind_group(&wgpu::BindGroupDescriptor {
-                label: Some("per-frame bind group"),
-                layout: &world_bind_group_layouts[world::BindGroupLayoutId::PerFrame as usize],
-                entries: &[wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
-                        buffer: &frame_uniform_buffer,
-                        offset: 0,
-                        size: None,
-                    }),
-                }],
-            }),
+            world::create_per_frame_bind_group(
+                &device,
+                &world_bind_group_layouts[world::BindGroupLayoutId::PerFrame as usize],
+                &frame_uniform_buffer,
+                &default_skybox_view,
+                &skybox_sampler,
+            ),
             device.create_bind_group(&wgpu::BindGroupDescriptor {
                 label: Some("brush per-entity bind group"),
                 layout: &world_bind_group_layouts[world::BindGroupLayoutId::PerEntity as usize],
@@ -429,6 +595,8 @@ impl GraphicsState {
         );
         let default_lightmap_view = default_lightmap.create_view(&Default::default());
 
+        let stats = RenderStats::new(&device, &queue);
+
         Ok(GraphicsState {
             device,
             queue,
@@ -455,8 +623,15 @@ impl GraphicsState {
 
             diffuse_sampler,
             lightmap_sampler,
+            skybox_sampler,
             default_lightmap,
             default_lightmap_view,
+            default_skybox,
+            default_skybox_view,
+            texture_settings: Cell::new(TextureSettings::default()),
+            texture_cache: None,
+            stats,
+            backend: RendererBackend::default(),
             vfs,
             palette,
             gfx_wad,
@@ -474,28 +649,174 @@ impl GraphicsState {
         create_texture(&self.device, &self.queue, label, width, height, data)
     }
 
-    /// Update graphics state with the new framebuffer size and sample count.
+    /// Create a world or model texture according to the current texture settings.
+    ///
+    /// This generates a full mip chain when mipmapping is enabled. Diffuse textures are also
+    /// compressed to BC1 or BC7 if compression is enabled, the device supports it and the texture
+    /// size allows it; compressed mip chains are kept in the texture cache if one is configured.
+    pub fn create_mipmapped_texture<'a>(
+        &self,
+        label: Option<&'a str>,
+        width: u32,
+        height: u32,
+        data: &TextureData,
+    ) -> wgpu::Texture {
+        let settings = self.texture_settings.get();
+        if !settings.mipmaps {
+            return self.create_texture(label, width, height, data);
+        }
+
+        if let TextureData::Diffuse(ref diffuse) = data {
+            let bc_levels = texture::bc_mip_level_count(width, height);
+            if let Some(format) = compressed_diffuse_format(settings.compression) {
+                if self.supports_texture_compression() && bc_levels > 0 {
+                    let levels = self.compress_diffuse(
+                        settings.compression,
+                        width,
+                        height,
+                        bc_levels,
+                        &diffuse.rgba,
+                    );
+                    return create_mipmapped_texture(
+                        &self.device,
+                        &self.queue,
+                        label,
+                        width,
+                        height,
+                        format,
+                        &levels,
+                    );
+                }
+            }
+        }
+
+        let levels = texture::generate_mipmaps(
+            width,
+            height,
+            data.stride() as usize,
+            data.data(),
+            texture::mip_level_count(width, height),
+        );
+        create_mipmapped_texture(
+            &self.device,
+            &self.queue,
+            label,
+            width,
+            height,
+            data.format(),
+            &levels,
+        )
+    }
+
+    fn compress_diffuse(
+        &self,
+        compression: TextureCompression,
+        width: u32,
+        height: u32,
+        level_count: u32,
+        rgba: &[u8],
+    ) -> Vec<Vec<u8>> {
+        let block_bytes = compression.block_bytes().unwrap();
+        let level_sizes: Vec<usize> = (0..level_count)
+            .map(|level| {
+                let (w, h) = texture::mip_level_size(width, height, level);
+                (w * h) as usize / 16 * block_bytes
+            })
+            .collect();
+        let total = level_sizes.iter().sum();
+        let key = TextureCache::key(compression, width, height, level_count, rgba);
+
+        let packed = match self
+            .texture_cache
+            .as_ref()
+            .and_then(|cache| cache.load(key, total))
+        {
+            Some(packed) => packed,
+            None => {
+                let mut packed = Vec::with_capacity(total);
+                let levels = texture::generate_mipmaps(width, height, 4, rgba, level_count);
+                for (level, data) in levels.iter().enumerate() {
+                    let (w, h) = texture::mip_level_size(width, height, level as u32);
+                    packed.extend(compression.compress(w, h, data));
+                }
+
+                if let Some(ref cache) = self.texture_cache {
+                    cache.store(key, &packed);
+                }
+
+                packed
+            }
+        };
+
+        let mut levels = Vec::with_capacity(level_sizes.len());
+        let mut ofs = 0;
+        for size in level_sizes {
+            levels.push(packed[ofs..ofs + size].to_owned());
+            ofs += size;
+        }
+        levels
+    }
+
+    /// Returns whether the device can sample BCn-compressed textures.
+    pub fn supports_texture_compression(&self) -> bool {
+        self.device
+            .features()
+            .contains(wgpu::Features::TEXTURE_COMPRESSION_BC)
+    }
+
+    /// Set the options used by `create_mipmapped_texture`.
+    ///
+    /// These take effect for textures created after the call, i.e. on the next map load.
+    pub fn set_texture_settings(&self, settings: TextureSettings) {
+        self.texture_settings.set(settings);
+    }
+
+    /// Store compressed textures under the given directory.
+    pub fn set_texture_cache_dir<P>(&mut self, dir: P)
+    where
+        P: AsRef<Path>,
+    {
+        self.texture_cache = Some(TextureCache::new(dir));
+    }
+
+    /// Select the renderer backend. This must be called before any `ClientRenderer` is created.
+    pub fn set_backend(&mut self, backend: RendererBackend) {
+        self.backend = backend;
+    }
+
+    pub fn backend(&self) -> RendererBackend {
+        self.backend
+    }
+
+    /// Update graphics state with the new framebuffer size, sample count and render scale.
     ///
     /// If the framebuffer size has changed, this recreates all render targets with the new size.
+    /// The 3D view is rendered at `render_scale` times the framebuffer size and scaled to fit by
+    /// the postprocess pass.
     ///
     /// If the framebuffer sample count has changed, this recreates all render targets with the
     /// new sample count and rebuilds the render pipelines to output that number of samples.
-    pub fn update(&mut self, size: Extent2d, sample_count: u32) {
+    pub fn update(&mut self, size: Extent2d, sample_count: u32, render_scale: f32) {
         if self.sample_count.get() != sample_count {
             self.sample_count.set(sample_count);
             self.recreate_pipelines(sample_count);
         }
 
-        if self.initial_pass_target.size() != size
+        let render_scale = render_scale.max(MIN_RENDER_SCALE).min(MAX_RENDER_SCALE);
+        let scene_size = size.scaled(render_scale);
+
+        if self.initial_pass_target.size() != scene_size
             || self.initial_pass_target.sample_count() != sample_count
         {
-            self.initial_pass_target = InitialPassTarget::new(self.device(), size, sample_count);
+            self.initial_pass_target =
+                InitialPassTarget::new(self.device(), scene_size, sample_count);
         }
 
-        if self.deferred_pass_target.size() != size
+        if self.deferred_pass_target.size() != scene_size
             || self.deferred_pass_target.sample_count() != sample_count
         {
-            self.deferred_pass_target = DeferredPassTarget::new(self.device(), size, sample_count);
+            self.deferred_pass_target =
+                DeferredPassTarget::new(self.device(), scene_size, sample_count);
         }
 
         if self.final_pass_target.size() != size
@@ -599,6 +920,18 @@ impl GraphicsState {
         &self.lightmap_sampler
     }
 
+    pub fn skybox_sampler(&self) -> &wgpu::Sampler {
+        &self.skybox_sampler
+    }
+
+    pub fn default_skybox(&self) -> &wgpu::Texture {
+        &self.default_skybox
+    }
+
+    pub fn default_skybox_view(&self) -> &wgpu::TextureView {
+        &self.default_skybox_view
+    }
+
     pub fn world_bind_group_layouts(&self) -> &[wgpu::BindGroupLayout] {
         &self.world_bind_group_layouts
     }
@@ -653,17 +986,50 @@ impl GraphicsState {
         &self.palette
     }
 
+    pub fn stats(&self) -> &RenderStats {
+        &self.stats
+    }
+
     pub fn gfx_wad(&self) -> &Wad {
         &self.gfx_wad
     }
 }
 
+/// Returns the scale of console text.
+///
+/// A `con_scale` of 0 picks a scale based on the display height so that the
+/// console stays readable at high resolutions.
+fn console_scale(cvars: &CvarRegistry, height: u32) -> f32 {
+    match cvars.get_value("con_scale").unwrap() {
+        s if s > 0.0 => s.max(1.0),
+        _ => (height as f32 / 540.0).floor().max(2.0),
+    }
+}
+
+/// Returns the scale of the status bar and other HUD elements.
+///
+/// A `sbar_size` of 0 picks a scale based on the display height, as `con_scale` does, but never
+/// one so large that the status bar doesn't fit across the display.
+pub fn hud_scale(cvars: &CvarRegistry, width: u32, height: u32) -> f32 {
+    match cvars.get_value("sbar_size").unwrap() {
+        s if s > 0.0 => s.max(1.0),
+        _ => (height as f32 / 540.0)
+            .floor()
+            .max(2.0)
+            .min((width / 320).max(1) as f32),
+    }
+}
+
 pub struct ClientRenderer {
     deferred_renderer: DeferredRenderer,
     postprocess_renderer: PostProcessRenderer,
     ui_renderer: UiRenderer,
     bump: Bump,
     start_time: DateTime<Utc>,
+
+    // only present when using the software backend
+    software_renderer: Option<SoftwareRenderer>,
+    software_frame: Option<QuadTexture>,
 }
 
 impl ClientRenderer {
@@ -683,6 +1049,65 @@ impl ClientRenderer {
             ui_renderer: UiRenderer::new(state, menu),
             bump: Bump::new(),
             start_time: Utc::now(),
+            software_renderer: match state.backend() {
+                RendererBackend::Hardware => None,
+                RendererBackend::Software => {
+                    match Colormap::load(state.vfs(), "gfx/colormap.lmp") {
+                        Ok(colormap) => Some(SoftwareRenderer::new(colormap)),
+                        Err(e) => {
+                            error!("Couldn't load colormap, using hardware renderer: {}", e);
+                            None
+                        }
+                    }
+                }
+            },
+            software_frame: None,
+        }
+    }
+
+    /// Draw the world with the software renderer and upload the result for display.
+    fn render_software(
+        &mut self,
+        gfx_state: &GraphicsState,
+        cl_state: &ClientState,
+        camera: &Camera,
+    ) {
+        let soft = match self.software_renderer {
+            Some(ref mut s) => s,
+            None => return,
+        };
+
+        // the initial pass target is already sized according to r_scale
+        let size = gfx_state.initial_pass_target().size();
+        soft.resize(size.width, size.height);
+
+        if let Some(ModelKind::Brush(ref bsp_model)) = cl_state.models().get(1).map(|m| m.kind()) {
+            let lights: Vec<DynamicLight> = cl_state
+                .iter_lights()
+                .map(|l| DynamicLight {
+                    origin: l.origin(),
+                    radius: l.radius(cl_state.time()),
+                })
+                .collect();
+
+            soft.render_world(
+                bsp_model,
+                camera,
+                cl_state.lightstyle_values().unwrap().as_slice(),
+                &lights,
+            );
+        }
+
+        let frame_size = self.software_frame.as_ref().map(|f| Extent2d {
+            width: f.width(),
+            height: f.height(),
+        });
+        if frame_size != Some(size) {
+            self.software_frame = Some(QuadTexture::new(gfx_state, size.width, size.height));
+        }
+
+        if let Some(ref frame) = self.software_frame {
+            frame.write(gfx_state, &soft.rgba(gfx_state.palette()));
         }
     }
 
@@ -698,13 +1123,19 @@ impl ClientRenderer {
         console: &Console,
         menu: &Menu,
         focus: InputFocus,
+        chat_input: Option<&ChatInput>,
+        show_scores: bool,
+        custom_hud_text: &[HudText],
     ) {
         self.bump.reset();
+        self.ui_renderer
+            .set_font(gfx_state, &cvars.get("con_font").unwrap());
 
         if let Some(Connection {
             state: ref cl_state,
             ref conn_state,
             ref kind,
+            ..
         }) = conn
         {
             match conn_state {
@@ -714,108 +1145,159 @@ impl ClientRenderer {
                         ConnectionKind::Demo(_) => {
                             cl_state.demo_camera(width as f32 / height as f32, fov)
                         }
-                        ConnectionKind::Server { .. } => {
+                        ConnectionKind::Server { .. } | ConnectionKind::QuakeWorld(_) => {
                             cl_state.camera(width as f32 / height as f32, fov)
                         }
                     };
 
-                    // initial render pass
-                    {
-                        let init_pass_builder =
-                            gfx_state.initial_pass_target().render_pass_builder();
-
-                        let mut init_pass =
-                            encoder.begin_render_pass(&init_pass_builder.descriptor());
-
-                        world.render_pass(
-                            gfx_state,
-                            &mut init_pass,
-                            &self.bump,
-                            &camera,
-                            cl_state.time(),
-                            cl_state.iter_visible_entities(),
-                            cl_state.iter_particles(),
-                            cl_state.lightstyle_values().unwrap().as_slice(),
-                            cl_state.viewmodel_id(),
-                            cvars,
-                        );
-                    }
-
-                    // deferred lighting pass
-                    {
-                        let deferred_pass_builder =
-                            gfx_state.deferred_pass_target().render_pass_builder();
-                        let mut deferred_pass =
-                            encoder.begin_render_pass(&deferred_pass_builder.descriptor());
-
-                        let mut lights = [PointLight {
-                            origin: Vector3::zero(),
-                            radius: 0.0,
-                        }; MAX_LIGHTS];
-
-                        let mut light_count = 0;
-                        for (light_id, light) in cl_state.iter_lights().enumerate() {
-                            light_count += 1;
-                            let light_origin = light.origin();
-                            let converted_origin =
-                                Vector3::new(-light_origin.y, light_origin.z, -light_origin.x);
-                            lights[light_id].origin =
-                                (camera.view() * converted_origin.extend(1.0)).truncate();
-                            lights[light_id].radius = light.radius(cl_state.time());
+                    if self.software_renderer.is_some() {
+                        self.render_software(gfx_state, cl_state, &camera);
+                    } else {
+                        // initial render pass
+                        {
+                            let init_pass_builder =
+                                gfx_state.initial_pass_target().render_pass_builder();
+
+                            let mut init_pass =
+                                encoder.begin_render_pass(&init_pass_builder.descriptor());
+
+                            world.render_pass(
+                                gfx_state,
+                                &mut init_pass,
+                                &self.bump,
+                                &camera,
+                                cl_state.time(),
+                                cl_state.iter_visible_entities(),
+                                cl_state.iter_particles(),
+                                cl_state.lightstyle_values().unwrap().as_slice(),
+                                cl_state.viewmodel(),
+                                cvars,
+                            );
                         }
 
-                        let uniforms = DeferredUniforms {
-                            inv_projection: camera.inverse_projection().into(),
-                            light_count,
-                            _pad: [0; 3],
-                            lights,
-                        };
-
-                        self.deferred_renderer.rebuild(
-                            gfx_state,
-                            gfx_state.initial_pass_target().diffuse_view(),
-                            gfx_state.initial_pass_target().normal_view(),
-                            gfx_state.initial_pass_target().light_view(),
-                            gfx_state.initial_pass_target().depth_view(),
-                        );
-
-                        self.deferred_renderer
-                            .record_draw(gfx_state, &mut deferred_pass, uniforms);
+                        // deferred lighting pass
+                        {
+                            let deferred_pass_builder =
+                                gfx_state.deferred_pass_target().render_pass_builder();
+                            let mut deferred_pass =
+                                encoder.begin_render_pass(&deferred_pass_builder.descriptor());
+                            gfx_state
+                                .stats()
+                                .begin_section(&mut deferred_pass, Section::Lighting);
+
+                            let mut lights = [PointLight {
+                                origin: Vector3::zero(),
+                                radius: 0.0,
+                            }; MAX_LIGHTS];
+
+                            let mut light_count = 0;
+                            for (light_id, light) in cl_state.iter_lights().enumerate() {
+                                light_count += 1;
+                                let light_origin = light.origin();
+                                let converted_origin =
+                                    Vector3::new(-light_origin.y, light_origin.z, -light_origin.x);
+                                lights[light_id].origin =
+                                    (camera.view() * converted_origin.extend(1.0)).truncate();
+                                lights[light_id].radius = light.radius(cl_state.time());
+                            }
+
+                            let uniforms = DeferredUniforms {
+                                inv_projection: camera.inverse_projection().into(),
+                                light_count,
+                                max_light: world::deferred::max_light(cvars),
+                                _pad: [0; 2],
+                                lights,
+                            };
+
+                            self.deferred_renderer.rebuild(
+                                gfx_state,
+                                gfx_state.initial_pass_target().diffuse_view(),
+                                gfx_state.initial_pass_target().normal_view(),
+                                gfx_state.initial_pass_target().light_view(),
+                                gfx_state.initial_pass_target().depth_view(),
+                            );
+
+                            self.deferred_renderer.record_draw(
+                                gfx_state,
+                                &mut deferred_pass,
+                                uniforms,
+                            );
+                        }
                     }
                 }
 
-                // if client is still signing on, draw the loading screen
-                ConnectionState::SignOn(_) => {
-                    // TODO: loading screen
-                }
+                // if client is still signing on, the HUD draws the loading screen
+                ConnectionState::SignOn(_) => (),
             }
         }
 
         let ui_state = match conn {
             Some(Connection {
                 state: ref cl_state,
+                kind: ref conn_kind,
+                conn_state: ref ui_conn_state,
+                ref loader,
+                ref downloader,
                 ..
             }) => UiState::InGame {
                 hud: match cl_state.intermission() {
+                    _ if matches!(ui_conn_state, ConnectionState::SignOn(_)) => HudState::Loading {
+                        progress: loader.as_ref().map(|l| l.progress()),
+                        download: downloader.as_ref().and_then(|d| match d.status() {
+                            DownloadStatus::Running { done, total } => Some((*done, *total)),
+                            _ => None,
+                        }),
+                    },
                     Some(kind) => HudState::Intermission {
                         kind,
                         completion_duration: cl_state.completion_time().unwrap()
                             - cl_state.start_time(),
                         stats: cl_state.stats(),
                         console,
+                        center_print: cl_state.center_print.visible_lines(cl_state.time),
+                        scoreboard: match cl_state.game_type {
+                            GameType::Deathmatch => Some(cl_state.scoreboard()),
+                            GameType::CoOp => None,
+                        },
+                        party: match cl_state.max_players {
+                            1 => Vec::new(),
+                            _ => cl_state.scoreboard(),
+                        },
                     },
 
                     None => HudState::InGame {
+                        sbar_lines: SbarLines::from_viewsize(cvars.get_value("viewsize").unwrap()),
                         items: cl_state.items(),
                         item_pickup_time: cl_state.item_pickup_times(),
                         stats: cl_state.stats(),
                         face_anim_time: cl_state.face_anim_time(),
                         console,
+                        captions: &cl_state.captions,
+                        chat: &cl_state.chat,
+                        chat_input,
+                        center_print: cl_state.center_print.visible_lines(cl_state.time),
+                        scoreboard: match show_scores {
+                            true => Some(cl_state.scoreboard()),
+                            false => None,
+                        },
+                        teammates: match cvars.get_value("cl_teammarkers").unwrap() != 0.0 {
+                            true => {
+                                let aspect = width as f32 / height as f32;
+                                let camera = match conn_kind {
+                                    ConnectionKind::Demo(_) => cl_state.demo_camera(aspect, fov),
+                                    ConnectionKind::Server { .. }
+                                    | ConnectionKind::QuakeWorld(_) => cl_state.camera(aspect, fov),
+                                };
+                                cl_state.teammates(&camera)
+                            }
+                            false => Vec::new(),
+                        },
                     },
                 },
 
                 overlay: match focus {
-                    InputFocus::Game => None,
+                    // the HUD draws the chat prompt
+                    InputFocus::Game | InputFocus::Chat => None,
                     InputFocus::Console => Some(UiOverlay::Console(console)),
                     InputFocus::Menu => Some(UiOverlay::Menu(menu)),
                 },
@@ -823,7 +1305,7 @@ impl ClientRenderer {
 
             None => UiState::Title {
                 overlay: match focus {
-                    InputFocus::Console => UiOverlay::Console(console),
+                    InputFocus::Console | InputFocus::Chat => UiOverlay::Console(console),
                     InputFocus::Menu => UiOverlay::Menu(menu),
                     InputFocus::Game => unreachable!(),
                 },
@@ -838,6 +1320,9 @@ impl ClientRenderer {
 
             let final_pass_builder = gfx_state.final_pass_target().render_pass_builder();
             let mut final_pass = encoder.begin_render_pass(&final_pass_builder.descriptor());
+            gfx_state
+                .stats()
+                .begin_section(&mut final_pass, Section::Ui);
 
             if let Some(Connection {
                 state: ref cl_state,
@@ -847,16 +1332,42 @@ impl ClientRenderer {
             {
                 // only postprocess if client is in the game
                 if let ConnectionState::Connected(_) = conn_state {
-                    self.postprocess_renderer
-                        .rebuild(gfx_state, gfx_state.deferred_pass_target.color_view());
-                    self.postprocess_renderer.record_draw(
-                        gfx_state,
-                        &mut final_pass,
-                        cl_state.color_shift(),
-                    );
+                    if let Some(ref frame) = self.software_frame {
+                        // the software renderer's output goes underneath the UI
+                        quad_commands.push(QuadRendererCommand {
+                            texture: frame,
+                            layout: Layout {
+                                position: ScreenPosition::Absolute(Anchor::BOTTOM_LEFT),
+                                anchor: Anchor::BOTTOM_LEFT,
+                                size: Size::DisplayScale { ratio: 1.0 },
+                            },
+                        });
+                    } else {
+                        self.postprocess_renderer
+                            .rebuild(gfx_state, gfx_state.deferred_pass_target.color_view());
+                        self.postprocess_renderer.record_draw(
+                            gfx_state,
+                            &mut final_pass,
+                            cl_state.color_shift(cvars.get_value("r_cshiftpercent").unwrap()),
+                            UpscaleFilter::from_cvar(cvars.get_value("r_upscale").unwrap()),
+                            cvars.get_value("r_sharpness").unwrap(),
+                        );
+                    }
                 }
             }
 
+            let hud_scale = hud_scale(cvars, width, height);
+            glyph_commands.extend(custom_hud_text.iter().map(|t| GlyphRendererCommand::Text {
+                text: t.text.clone(),
+                position: ScreenPosition::Relative {
+                    anchor: Anchor::TOP_LEFT,
+                    x_ofs: t.x,
+                    y_ofs: -t.y,
+                },
+                anchor: Anchor::TOP_LEFT,
+                scale: hud_scale,
+            }));
+
             self.ui_renderer.render_pass(
                 &gfx_state,
                 &mut final_pass,
@@ -867,9 +1378,44 @@ impl ClientRenderer {
                     None => Utc::now().signed_duration_since(self.start_time),
                 },
                 &ui_state,
+                hud_scale,
+                console_scale(cvars, height),
                 &mut quad_commands,
                 &mut glyph_commands,
             );
         }
+
+        gfx_state.stats().end_frame(encoder);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extent_scaled() {
+        let size = Extent2d {
+            width: 1920,
+            height: 1080,
+        };
+        assert_eq!(
+            size.scaled(0.5),
+            Extent2d {
+                width: 960,
+                height: 540
+            }
+        );
+        assert_eq!(
+            Extent2d {
+                width: 3,
+                height: 1
+            }
+            .scaled(0.25),
+            Extent2d {
+                width: 1,
+                height: 1
+            }
+        );
     }
 }
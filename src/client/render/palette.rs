@@ -2,11 +2,37 @@ use std::{borrow::Cow, io::BufReader};
 
 use crate::{
     client::render::{DiffuseData, FullbrightData},
-    common::vfs::Vfs,
+    common::{net::PlayerColor, vfs::Vfs},
 };
 
 use byteorder::ReadBytesExt;
 
+// the palette rows player skins use for their shirt and pants
+const TOP_RANGE: usize = 16;
+const BOTTOM_RANGE: usize = 96;
+
+/// Returns a table that remaps the shirt and pants rows of a player skin to
+/// the player's colors.
+///
+/// The first 8 rows of the palette go from dark to light like the skin's own
+/// rows; the rest go from light to dark, so they are copied in reverse.
+pub fn player_translation(colors: PlayerColor) -> [u8; 256] {
+    let mut table = [0; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        *entry = i as u8;
+    }
+
+    for (range, color) in [(TOP_RANGE, colors.top()), (BOTTOM_RANGE, colors.bottom())].iter() {
+        let row = (*color as usize & 0xF) * 16;
+        for j in 0..16 {
+            let index = if *color < 8 { row + j } else { row + 15 - j };
+            table[range + j] = index as u8;
+        }
+    }
+
+    table
+}
+
 pub struct Palette {
     rgb: [[u8; 3]; 256],
 }
@@ -44,6 +70,11 @@ impl Palette {
         Palette { rgb }
     }
 
+    /// Returns the RGB value of a palette index.
+    pub fn rgb(&self, index: u8) -> [u8; 3] {
+        self.rgb[index as usize]
+    }
+
     // TODO: this will not render console characters correctly, as they use index 0 (black) to
     // indicate transparency.
     /// Translates a set of indices into a list of RGBA values and a list of fullbright values.
@@ -56,8 +87,8 @@ impl Palette {
                 0xFF => {
                     for _ in 0..4 {
                         rgba.push(0);
-                        fullbright.push(0);
                     }
+                    fullbright.push(0);
                 }
 
                 i => {
@@ -80,3 +111,37 @@ impl Palette {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_player_translation() {
+        // orange shirt (row 4), blue pants (row 13)
+        let table = player_translation(PlayerColor::new(4, 13));
+        assert_eq!(table[0], 0);
+        assert_eq!(table[16], 64);
+        assert_eq!(table[31], 79);
+        assert_eq!(table[32], 32);
+
+        // rows 8 and up are reversed
+        assert_eq!(table[96], 223);
+        assert_eq!(table[111], 208);
+        assert_eq!(table[112], 112);
+    }
+
+    #[test]
+    fn test_translate_fullbright() {
+        let data: Vec<u8> = (0..768).map(|i| (i / 3) as u8).collect();
+        let palette = Palette::new(&data);
+
+        let (diffuse, fullbright) = palette.translate(&[0, 223, 224, 0xFF]);
+        assert_eq!(diffuse.rgba.len(), 16);
+        assert_eq!(&diffuse.rgba[8..12], &[224, 224, 224, 0xFF]);
+        assert_eq!(&diffuse.rgba[12..], &[0, 0, 0, 0]);
+
+        // one mask value per pixel, set for indices 224 through 254
+        assert_eq!(&fullbright.fullbright[..], &[0, 0, 0xFF, 0]);
+    }
+}
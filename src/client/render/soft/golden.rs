@@ -0,0 +1,451 @@
+// Copyright © 2020 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Golden-image tests for the renderer.
+//!
+//! A script lists camera positions in a map, one shot per line:
+//!
+//! ```text
+//! // name      x      y      z     pitch  yaw  roll
+//! start       480    -352    88    0      90   0
+//! ```
+//!
+//! Each shot is rendered with the software renderer, which doesn't depend on
+//! the GPU or the clock, and compared against a reference PNG with a
+//! tolerance for small differences. Only the software renderer is covered:
+//! the hardware renderer's output depends on the GPU and driver.
+//!
+//! Maps need game data, so their scripts and references are kept by whoever
+//! runs the `golden` tool. The tests in this module render a synthetic scene
+//! instead and compare it against `golden/scene.png` in the repository.
+
+use std::{fs::File, io::BufWriter, path::Path};
+
+use crate::{
+    client::render::{Camera, Palette, SoftwareRenderer},
+    common::{
+        bsp,
+        math::{self, Angles},
+        model::{Model, ModelKind},
+        vfs::Vfs,
+    },
+};
+
+use super::Colormap;
+
+use cgmath::{Deg, Vector3};
+use failure::Error;
+
+const FOV: Deg<f32> = Deg(90.0);
+
+// every light style is held at "m", the normal light level
+const LIGHTSTYLE_VALUE: f32 = ('m' as u8 - 'a' as u8) as f32 / 12.5;
+
+/// A named camera position to render.
+#[derive(Clone, Debug)]
+pub struct GoldenShot {
+    pub name: String,
+    pub origin: Vector3<f32>,
+    pub angles: Angles,
+}
+
+/// Parse a golden-image script.
+pub fn parse_script(src: &str) -> Result<Vec<GoldenShot>, Error> {
+    let mut shots = Vec::new();
+
+    for (line_id, line) in src.lines().enumerate() {
+        let line = match line.find("//") {
+            Some(i) => &line[..i],
+            None => line,
+        };
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+
+        ensure!(
+            fields.len() == 7,
+            "line {}: expected name x y z pitch yaw roll",
+            line_id + 1
+        );
+
+        let mut values = [0.0; 6];
+        for (value, field) in values.iter_mut().zip(&fields[1..]) {
+            *value = field
+                .parse()
+                .map_err(|_| format_err!("line {}: bad number \"{}\"", line_id + 1, field))?;
+        }
+
+        shots.push(GoldenShot {
+            name: fields[0].to_owned(),
+            origin: Vector3::new(values[0], values[1], values[2]),
+            angles: Angles {
+                pitch: Deg(values[3]),
+                yaw: Deg(values[4]),
+                roll: Deg(values[5]),
+            },
+        });
+    }
+
+    Ok(shots)
+}
+
+/// How far a rendered image may stray from its reference.
+#[derive(Copy, Clone, Debug)]
+pub struct Tolerance {
+    /// Channel differences up to this value are ignored.
+    pub channel: u8,
+
+    /// The fraction of pixels that may differ by more than `channel`.
+    pub max_differing: f32,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance {
+            channel: 8,
+            max_differing: 0.001,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageDiff {
+    pub differing_pixels: usize,
+    pub total_pixels: usize,
+    pub max_channel_diff: u8,
+}
+
+impl ImageDiff {
+    pub fn within(&self, tolerance: Tolerance) -> bool {
+        self.differing_pixels as f32 <= self.total_pixels as f32 * tolerance.max_differing
+    }
+}
+
+/// An 8-bit RGBA image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GoldenImage {
+    pub width: u32,
+    pub height: u32,
+    pub rgba: Vec<u8>,
+}
+
+impl GoldenImage {
+    pub fn read_png<P>(path: P) -> Result<GoldenImage, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let decoder = png::Decoder::new(File::open(path)?);
+        let (info, mut reader) = decoder.read_info()?;
+        let mut data = vec![0; info.buffer_size()];
+        reader.next_frame(&mut data)?;
+
+        let rgba = match (info.color_type, info.bit_depth) {
+            (png::ColorType::RGBA, png::BitDepth::Eight) => data,
+            (png::ColorType::RGB, png::BitDepth::Eight) => data
+                .chunks_exact(3)
+                .flat_map(|rgb| vec![rgb[0], rgb[1], rgb[2], 0xFF])
+                .collect(),
+            (color, depth) => bail!("unsupported pixel format {:?} ({:?})", color, depth),
+        };
+
+        Ok(GoldenImage {
+            width: info.width,
+            height: info.height,
+            rgba,
+        })
+    }
+
+    pub fn write_png<P>(&self, path: P) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let mut encoder =
+            png::Encoder::new(BufWriter::new(File::create(path)?), self.width, self.height);
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&self.rgba)?;
+        Ok(())
+    }
+
+    /// Compare this image against `reference`, counting pixels where any channel differs by
+    /// more than `tolerance.channel`.
+    ///
+    /// Images of different sizes differ at every pixel.
+    pub fn compare(&self, reference: &GoldenImage, tolerance: Tolerance) -> ImageDiff {
+        let total_pixels = (reference.width * reference.height) as usize;
+        if self.width != reference.width || self.height != reference.height {
+            return ImageDiff {
+                differing_pixels: total_pixels,
+                total_pixels,
+                max_channel_diff: 0xFF,
+            };
+        }
+
+        let mut diff = ImageDiff {
+            differing_pixels: 0,
+            total_pixels,
+            max_channel_diff: 0,
+        };
+        for (a, b) in self
+            .rgba
+            .chunks_exact(4)
+            .zip(reference.rgba.chunks_exact(4))
+        {
+            let max = a
+                .iter()
+                .zip(b.iter())
+                .map(|(x, y)| (*x as i16 - *y as i16).abs() as u8)
+                .max()
+                .unwrap_or(0);
+            diff.max_channel_diff = diff.max_channel_diff.max(max);
+            if max > tolerance.channel {
+                diff.differing_pixels += 1;
+            }
+        }
+
+        diff
+    }
+}
+
+/// Renders the world of a map from scripted camera positions.
+pub struct GoldenRenderer {
+    renderer: SoftwareRenderer,
+    palette: Palette,
+    world: Model,
+    width: u32,
+    height: u32,
+}
+
+impl GoldenRenderer {
+    pub fn new(vfs: &Vfs, map: &str, width: u32, height: u32) -> Result<GoldenRenderer, Error> {
+        let (mut models, _) = bsp::load(vfs.open(map)?)?;
+        ensure!(!models.is_empty(), "{} has no world model", map);
+        let world = models.swap_remove(0);
+
+        let mut renderer = SoftwareRenderer::new(Colormap::load(vfs, "gfx/colormap.lmp")?);
+        renderer.resize(width, height);
+
+        Ok(GoldenRenderer {
+            renderer,
+            palette: Palette::load(vfs, "gfx/palette.lmp"),
+            world,
+            width,
+            height,
+        })
+    }
+
+    pub fn render(&mut self, shot: &GoldenShot) -> GoldenImage {
+        let aspect = self.width as f32 / self.height as f32;
+        let fov_y = math::fov_x_to_fov_y(FOV, aspect).unwrap();
+        let camera = Camera::new(
+            shot.origin,
+            shot.angles,
+            cgmath::perspective(fov_y, aspect, 4.0, 4096.0),
+        );
+
+        if let ModelKind::Brush(ref bsp_model) = self.world.kind() {
+            self.renderer
+                .render_world(bsp_model, &camera, &[LIGHTSTYLE_VALUE; 64], &[]);
+        }
+
+        GoldenImage {
+            width: self.width,
+            height: self.height,
+            rgba: self.renderer.rgba(&self.palette),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::client::render::soft::raster::{
+        clip_near, draw_polygon, ClipVertex, Framebuffer, Surface, SurfaceLightmap, SurfaceTexture,
+        COLORMAP_ROWS, IDENTITY_ROW,
+    };
+
+    use cgmath::{Matrix4, Vector4};
+
+    // draw a lit floor and an unlit wall that crosses the near plane, so that
+    // perspective texture mapping, dithered lighting, clipping and depth
+    // testing all show up in the image
+    fn render_scene(width: u32, height: u32) -> GoldenImage {
+        let projection: Matrix4<f32> =
+            cgmath::perspective(Deg(90.0), width as f32 / height as f32, 1.0, 256.0);
+        let vertex = |x: f32, y: f32, z: f32, s: f32, t: f32| ClipVertex {
+            position: projection * Vector4::new(x, y, z, 1.0),
+            texcoord: [s, t],
+            lightmap_texcoord: [s / 16.0, t / 16.0],
+        };
+
+        // 8x8 checkerboard of two palette indices
+        let checker: Vec<u8> = (0..64)
+            .map(|i| match (i % 8 < 4) ^ (i / 8 < 4) {
+                true => 200,
+                false => 80,
+            })
+            .collect();
+
+        // light falls off with distance along the floor
+        let light: Vec<u8> = (0..16).map(|i| 255 - i * 15).collect();
+
+        let floor = Surface {
+            texture: SurfaceTexture {
+                width: 8,
+                height: 8,
+                indices: &checker,
+            },
+            lightmap: Some(SurfaceLightmap {
+                width: 1,
+                height: 16,
+                light: &light,
+            }),
+        };
+        let wall = Surface {
+            texture: SurfaceTexture {
+                width: 8,
+                height: 8,
+                indices: &checker,
+            },
+            lightmap: None,
+        };
+
+        let floor_poly = [
+            vertex(-64.0, -16.0, -2.0, 0.0, 0.0),
+            vertex(64.0, -16.0, -2.0, 128.0, 0.0),
+            vertex(64.0, -16.0, -240.0, 128.0, 238.0),
+            vertex(-64.0, -16.0, -240.0, 0.0, 238.0),
+        ];
+        let wall_poly = [
+            vertex(8.0, -16.0, 0.0, 0.0, 0.0),
+            vertex(8.0, -16.0, -96.0, 96.0, 0.0),
+            vertex(8.0, 32.0, -96.0, 96.0, 48.0),
+            vertex(8.0, 32.0, 0.0, 0.0, 48.0),
+        ];
+
+        // colormap rows darken linearly, with IDENTITY_ROW leaving indices as they are
+        let shade = |index: u8, row: usize| {
+            (index as usize * (COLORMAP_ROWS - row) / (COLORMAP_ROWS - IDENTITY_ROW)).min(255) as u8
+        };
+
+        let mut fb = Framebuffer::new(width, height);
+        fb.clear(0);
+        draw_polygon(&mut fb, &clip_near(&floor_poly), &floor, shade);
+        draw_polygon(&mut fb, &clip_near(&wall_poly), &wall, shade);
+
+        // palette indices as gray levels
+        GoldenImage {
+            width,
+            height,
+            rgba: fb
+                .color()
+                .iter()
+                .flat_map(|&i| vec![i, i, i, 0xFF])
+                .collect(),
+        }
+    }
+
+    /// Renders the synthetic scene and compares it against `golden/scene.png`.
+    ///
+    /// A missing or different reference fails the test. When
+    /// `RICHTER_BLESS_GOLDEN` is set, the reference is written instead.
+    #[test]
+    fn test_reference_scene() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("golden")
+            .join("scene.png");
+        let actual = render_scene(64, 48);
+
+        if std::env::var_os("RICHTER_BLESS_GOLDEN").is_some() {
+            actual.write_png(&path).unwrap();
+            return;
+        }
+
+        let reference = GoldenImage::read_png(&path).unwrap();
+        let diff = actual.compare(&reference, Tolerance::default());
+        assert!(
+            diff.within(Tolerance::default()),
+            "{} differs from the rendered scene: {:?}",
+            path.display(),
+            diff
+        );
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let shots = parse_script(
+            "// name x y z pitch yaw roll\n\
+             \n\
+             start 480 -352 88 0 90 0 // looking north\n\
+             hall  1.5 2 3 -10 180 5\n",
+        )
+        .unwrap();
+
+        assert_eq!(shots.len(), 2);
+        assert_eq!(shots[0].name, "start");
+        assert_eq!(shots[0].origin, Vector3::new(480.0, -352.0, 88.0));
+        assert_eq!(shots[1].angles.pitch, Deg(-10.0));
+        assert_eq!(shots[1].angles.roll, Deg(5.0));
+
+        assert!(parse_script("start 1 2 3\n").is_err());
+        assert!(parse_script("start 1 2 three 0 0 0\n").is_err());
+    }
+
+    #[test]
+    fn test_compare() {
+        let reference = GoldenImage {
+            width: 2,
+            height: 2,
+            rgba: vec![100; 16],
+        };
+
+        let mut actual = reference.clone();
+        actual.rgba[0] = 104;
+        actual.rgba[4] = 150;
+
+        let tolerance = Tolerance {
+            channel: 8,
+            max_differing: 0.25,
+        };
+        let diff = actual.compare(&reference, tolerance);
+        assert_eq!(
+            diff,
+            ImageDiff {
+                differing_pixels: 1,
+                total_pixels: 4,
+                max_channel_diff: 50,
+            }
+        );
+        assert!(diff.within(tolerance));
+
+        actual.rgba[8] = 0;
+        assert!(!actual.compare(&reference, tolerance).within(tolerance));
+
+        let small = GoldenImage {
+            width: 1,
+            height: 1,
+            rgba: vec![100; 4],
+        };
+        assert!(!small.compare(&reference, tolerance).within(tolerance));
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright © 2020 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! A palette-indexed software renderer.
+//!
+//! Textures stay as palette indices all the way through: lighting selects a row of the
+//! colormap for each pixel, with an ordered dither between rows, and the finished frame is only
+//! converted to RGBA when it's handed to the GPU for display. This reproduces the look of the
+//! original software renderer and doesn't depend on the GPU for its output, which makes it
+//! suitable for golden-image tests.
+//!
+//! Only the world model is drawn. Entities, particles and sky scrolling are left to the
+//! hardware renderer.
+
+pub mod golden;
+mod raster;
+
+pub use raster::Framebuffer;
+
+use std::{collections::HashMap, io::Read};
+
+use crate::{
+    client::render::{
+        soft::raster::{
+            clip_near, draw_polygon, ClipVertex, Surface, SurfaceLightmap, SurfaceTexture,
+            COLORMAP_ROWS,
+        },
+        Camera, Palette,
+    },
+    common::{
+        bsp::{BspData, BspFaceSide, BspModel, BspTextureKind, BspTextureMipmap},
+        vfs::Vfs,
+    },
+};
+
+use cgmath::{InnerSpace as _, Vector3};
+use failure::Error;
+
+/// Maps a palette index and light level to a shaded palette index.
+pub struct Colormap {
+    rows: Vec<[u8; 256]>,
+}
+
+impl Colormap {
+    pub fn from_bytes(data: &[u8]) -> Result<Colormap, Error> {
+        ensure!(
+            data.len() >= COLORMAP_ROWS * 256,
+            "Bad colormap length {}",
+            data.len()
+        );
+
+        let mut rows = Vec::with_capacity(COLORMAP_ROWS);
+        for chunk in data.chunks_exact(256).take(COLORMAP_ROWS) {
+            let mut row = [0; 256];
+            row.copy_from_slice(chunk);
+            rows.push(row);
+        }
+
+        Ok(Colormap { rows })
+    }
+
+    pub fn load<S>(vfs: &Vfs, path: S) -> Result<Colormap, Error>
+    where
+        S: AsRef<str>,
+    {
+        let mut data = Vec::new();
+        vfs.open(path)?.read_to_end(&mut data)?;
+        Colormap::from_bytes(&data)
+    }
+
+    /// Returns `index` as it appears at the light level of `row`.
+    pub fn shade(&self, index: u8, row: usize) -> u8 {
+        self.rows[row][index as usize]
+    }
+}
+
+/// A dynamic light as it stands this frame.
+#[derive(Clone, Copy, Debug)]
+pub struct DynamicLight {
+    pub origin: Vector3<f32>,
+    pub radius: f32,
+}
+
+pub struct SoftwareRenderer {
+    colormap: Colormap,
+    framebuffer: Framebuffer,
+
+    // scratch space for each face's combined lightmap
+    light: Vec<u8>,
+}
+
+impl SoftwareRenderer {
+    pub fn new(colormap: Colormap) -> SoftwareRenderer {
+        SoftwareRenderer {
+            colormap,
+            framebuffer: Framebuffer::new(1, 1),
+            light: Vec::new(),
+        }
+    }
+
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.framebuffer
+    }
+
+    /// Resize the framebuffer if its dimensions have changed.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        let (width, height) = (width.max(1), height.max(1));
+        if self.framebuffer.width() != width || self.framebuffer.height() != height {
+            self.framebuffer = Framebuffer::new(width, height);
+        }
+    }
+
+    /// Draw the faces of a brush model as seen from `camera`.
+    ///
+    /// Only faces in the potentially visible set of the camera are drawn. The framebuffer is
+    /// cleared first. `lightstyle_values` holds the current value of each
+    /// light style, as returned by `ClientState::lightstyle_values`, and `lights` are added to
+    /// the lightmaps of the faces they reach.
+    pub fn render_world(
+        &mut self,
+        model: &BspModel,
+        camera: &Camera,
+        lightstyle_values: &[f32],
+        lights: &[DynamicLight],
+    ) {
+        self.framebuffer.clear(0);
+
+        let mut face_lights: HashMap<usize, Vec<DynamicLight>> = HashMap::new();
+        for light in lights.iter().filter(|l| l.radius > 0.0) {
+            for face_id in model.light_faces(light.origin - model.origin(), light.radius) {
+                face_lights.entry(face_id).or_default().push(*light);
+            }
+        }
+
+        let bsp_data = model.bsp_data();
+        for face_id in model.visible_faces(camera.origin() - model.origin()) {
+            let lights = face_lights
+                .get(&face_id)
+                .map(|l| l.as_slice())
+                .unwrap_or(&[]);
+            self.draw_face(&bsp_data, face_id, camera, lightstyle_values, lights);
+        }
+    }
+
+    fn draw_face(
+        &mut self,
+        bsp_data: &BspData,
+        face_id: usize,
+        camera: &Camera,
+        lightstyle_values: &[f32],
+        lights: &[DynamicLight],
+    ) {
+        let face = &bsp_data.faces()[face_id];
+        let plane = &bsp_data.planes()[face.plane_id];
+
+        // cull faces pointing away from the camera
+        let dist = plane.point_dist(camera.origin());
+        let facing = match face.side {
+            BspFaceSide::Front => dist > 0.0,
+            BspFaceSide::Back => dist < 0.0,
+        };
+        if !facing {
+            return;
+        }
+
+        let texinfo = &bsp_data.texinfo()[face.texinfo_id];
+        let tex = &bsp_data.textures()[texinfo.tex_id];
+        let frame = match tex.kind() {
+            BspTextureKind::Static(frame) => frame,
+            BspTextureKind::Animated { primary, .. } => &primary[0],
+        };
+
+        let lightmaps = if texinfo.special {
+            Vec::new()
+        } else {
+            bsp_data.face_lightmaps(face_id)
+        };
+
+        let mins = [
+            (face.texture_mins[0] as f32 / 16.0).floor() * 16.0,
+            (face.texture_mins[1] as f32 / 16.0).floor() * 16.0,
+        ];
+
+        // combine the face's light styles into a single lightmap
+        let lightmap_size = lightmaps.first().map(|lm| (lm.width(), lm.height()));
+        if let Some((w, h)) = lightmap_size {
+            let mut light = vec![0.0f32; (w * h) as usize];
+            let styles = face.light_styles.iter().filter(|s| **s != 255);
+            for (lightmap, style) in lightmaps.iter().zip(styles) {
+                let scale = lightstyle_values
+                    .get(*style as usize)
+                    .copied()
+                    .unwrap_or(1.0);
+                for (l, value) in light.iter_mut().zip(lightmap.data()) {
+                    *l += *value as f32 * scale;
+                }
+            }
+
+            // dynamic lights fall off linearly with distance from the luxel
+            for dlight in lights {
+                let dist = plane.point_dist(dlight.origin);
+                let radius = dlight.radius - dist.abs();
+                let impact = dlight.origin - plane.normal() * dist;
+                let local = [
+                    impact.dot(texinfo.s_vector) + texinfo.s_offset - mins[0],
+                    impact.dot(texinfo.t_vector) + texinfo.t_offset - mins[1],
+                ];
+
+                for t in 0..h {
+                    let td = local[1] - t as f32 * 16.0;
+                    for s in 0..w {
+                        let sd = local[0] - s as f32 * 16.0;
+                        let luxel_dist = (sd * sd + td * td).sqrt();
+                        if luxel_dist < radius {
+                            light[(t * w + s) as usize] += radius - luxel_dist;
+                        }
+                    }
+                }
+            }
+
+            self.light.clear();
+            self.light.extend(light.iter().map(|l| l.min(255.0) as u8));
+        }
+
+        let view_projection = camera.view_projection();
+        let polygon: Vec<ClipVertex> = bsp_data
+            .face_iter_vertices(face_id)
+            .map(|v| {
+                let s = v.dot(texinfo.s_vector) + texinfo.s_offset;
+                let t = v.dot(texinfo.t_vector) + texinfo.t_offset;
+
+                // convert to wgpu coordinates, as the camera expects
+                let converted = Vector3::new(-v.y, v.z, -v.x);
+                ClipVertex {
+                    position: view_projection * converted.extend(1.0),
+                    texcoord: [s, t],
+                    lightmap_texcoord: [(s - mins[0]) / 16.0, (t - mins[1]) / 16.0],
+                }
+            })
+            .collect();
+
+        let clipped = clip_near(&polygon);
+        let surface = Surface {
+            texture: SurfaceTexture {
+                width: tex.width(),
+                height: tex.height(),
+                indices: frame.mipmap(BspTextureMipmap::Full),
+            },
+            lightmap: lightmap_size.map(|(width, height)| SurfaceLightmap {
+                width,
+                height,
+                light: &self.light,
+            }),
+        };
+
+        let colormap = &self.colormap;
+        draw_polygon(&mut self.framebuffer, &clipped, &surface, |index, row| {
+            colormap.shade(index, row)
+        });
+    }
+
+    /// Converts the framebuffer to RGBA using `palette`.
+    pub fn rgba(&self, palette: &Palette) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(self.framebuffer.color().len() * 4);
+        for index in self.framebuffer.color() {
+            rgba.extend_from_slice(&palette.rgb(*index));
+            rgba.push(0xFF);
+        }
+        rgba
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::render::soft::raster::IDENTITY_ROW;
+
+    #[test]
+    fn test_colormap_shade() {
+        let mut data = vec![0u8; COLORMAP_ROWS * 256 + 1];
+        for (row, chunk) in data.chunks_exact_mut(256).enumerate() {
+            for (index, value) in chunk.iter_mut().enumerate() {
+                *value = if row == IDENTITY_ROW {
+                    index as u8
+                } else {
+                    row as u8
+                };
+            }
+        }
+
+        let colormap = Colormap::from_bytes(&data).unwrap();
+        assert_eq!(colormap.shade(200, IDENTITY_ROW), 200);
+        assert_eq!(colormap.shade(200, 10), 10);
+
+        assert!(Colormap::from_bytes(&data[..256]).is_err());
+    }
+}
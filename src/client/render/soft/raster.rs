@@ -0,0 +1,421 @@
+// Copyright © 2020 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Clipping and span rasterization of textured, lightmapped polygons.
+
+use cgmath::Vector4;
+
+/// Polygons are clipped against this `w` value rather than the projection's near plane so that
+/// `1 / w` stays well-behaved.
+const NEAR_W: f32 = 1.0;
+
+// 4x4 ordered dither matrix, in sixteenths of a colormap row
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// The colormap row which leaves palette indices unchanged.
+pub const IDENTITY_ROW: usize = 32;
+
+/// The number of light levels, or rows, in the colormap.
+pub const COLORMAP_ROWS: usize = 64;
+
+/// An 8-bit palette-indexed framebuffer with a depth buffer.
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    color: Vec<u8>,
+
+    // 1 / w of the nearest surface drawn so far, or 0 if nothing has been drawn
+    depth: Vec<f32>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32) -> Framebuffer {
+        let len = width as usize * height as usize;
+        Framebuffer {
+            width,
+            height,
+            color: vec![0; len],
+            depth: vec![0.0; len],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Returns the palette indices of the framebuffer in row-major order.
+    pub fn color(&self) -> &[u8] {
+        &self.color
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> u8 {
+        self.color[(y * self.width + x) as usize]
+    }
+
+    /// Fill the framebuffer with `index` and reset the depth buffer.
+    pub fn clear(&mut self, index: u8) {
+        for c in self.color.iter_mut() {
+            *c = index;
+        }
+
+        for d in self.depth.iter_mut() {
+            *d = 0.0;
+        }
+    }
+}
+
+/// A polygon vertex in clip space with its texture and lightmap coordinates.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ClipVertex {
+    pub position: Vector4<f32>,
+
+    /// Texture coordinates in texels.
+    pub texcoord: [f32; 2],
+
+    /// Lightmap coordinates in luxels.
+    pub lightmap_texcoord: [f32; 2],
+}
+
+impl ClipVertex {
+    fn lerp(&self, other: &ClipVertex, t: f32) -> ClipVertex {
+        let mix = |a: f32, b: f32| a + (b - a) * t;
+        ClipVertex {
+            position: self.position + (other.position - self.position) * t,
+            texcoord: [
+                mix(self.texcoord[0], other.texcoord[0]),
+                mix(self.texcoord[1], other.texcoord[1]),
+            ],
+            lightmap_texcoord: [
+                mix(self.lightmap_texcoord[0], other.lightmap_texcoord[0]),
+                mix(self.lightmap_texcoord[1], other.lightmap_texcoord[1]),
+            ],
+        }
+    }
+}
+
+/// Clip a convex polygon against the near plane.
+///
+/// Returns an empty list if the polygon is entirely behind the viewer.
+pub fn clip_near(polygon: &[ClipVertex]) -> Vec<ClipVertex> {
+    let mut out = Vec::with_capacity(polygon.len() + 1);
+
+    for (i, cur) in polygon.iter().enumerate() {
+        let next = &polygon[(i + 1) % polygon.len()];
+        let cur_dist = cur.position.w - NEAR_W;
+        let next_dist = next.position.w - NEAR_W;
+
+        if cur_dist >= 0.0 {
+            out.push(*cur);
+        }
+
+        if (cur_dist >= 0.0) != (next_dist >= 0.0) {
+            out.push(cur.lerp(next, cur_dist / (cur_dist - next_dist)));
+        }
+    }
+
+    out
+}
+
+/// A vertex in screen space. Every attribute except the position is divided by `w` so that it
+/// can be interpolated linearly across the screen.
+#[derive(Copy, Clone, Debug)]
+struct ScreenVertex {
+    x: f32,
+    y: f32,
+    attribs: [f32; ATTRIB_COUNT],
+}
+
+// 1 / w, s / w, t / w, light s / w, light t / w
+const ATTRIB_COUNT: usize = 5;
+
+impl ScreenVertex {
+    fn project(v: &ClipVertex, width: u32, height: u32) -> ScreenVertex {
+        let inv_w = 1.0 / v.position.w;
+        ScreenVertex {
+            x: (v.position.x * inv_w * 0.5 + 0.5) * width as f32,
+            y: (0.5 - v.position.y * inv_w * 0.5) * height as f32,
+            attribs: [
+                inv_w,
+                v.texcoord[0] * inv_w,
+                v.texcoord[1] * inv_w,
+                v.lightmap_texcoord[0] * inv_w,
+                v.lightmap_texcoord[1] * inv_w,
+            ],
+        }
+    }
+}
+
+/// A palette-indexed texture.
+pub struct SurfaceTexture<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub indices: &'a [u8],
+}
+
+/// A surface's combined lightmap, with one light value per luxel.
+///
+/// Light values are in the range `[0, 255]`, where 255 is full brightness.
+pub struct SurfaceLightmap<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub light: &'a [u8],
+}
+
+pub struct Surface<'a> {
+    pub texture: SurfaceTexture<'a>,
+
+    /// The surface's lightmap. Surfaces without a lightmap are drawn at full brightness.
+    pub lightmap: Option<SurfaceLightmap<'a>>,
+}
+
+impl<'a> Surface<'a> {
+    fn texel(&self, s: f32, t: f32) -> u8 {
+        let tex = &self.texture;
+        let x = (s.floor() as i32).rem_euclid(tex.width as i32) as u32;
+        let y = (t.floor() as i32).rem_euclid(tex.height as i32) as u32;
+        tex.indices[(y * tex.width + x) as usize]
+    }
+
+    fn light(&self, s: f32, t: f32) -> Option<u32> {
+        let lm = self.lightmap.as_ref()?;
+
+        // sample the nearest luxel; dithering hides most of the banding
+        let x = (s.round().max(0.0) as u32).min(lm.width - 1);
+        let y = (t.round().max(0.0) as u32).min(lm.height - 1);
+        Some(lm.light[(y * lm.width + x) as usize] as u32)
+    }
+}
+
+/// Select the colormap row for a light value at a pixel.
+///
+/// The fractional part of the light level is converted to an ordered dither so that gradients
+/// between rows don't band.
+pub fn dithered_row(light: u32, x: u32, y: u32) -> usize {
+    // each row covers 4 light values, so work in sixteenths of a row
+    let threshold = BAYER_4X4[(y % 4) as usize][(x % 4) as usize] as u32;
+    let bright = ((light * 4 + threshold) / 16) as usize;
+    COLORMAP_ROWS - 1 - bright.min(COLORMAP_ROWS - 1)
+}
+
+/// Draw a convex polygon that has already been clipped against the near plane.
+///
+/// `shade` maps a palette index and colormap row to the final palette index.
+pub fn draw_polygon<F>(fb: &mut Framebuffer, polygon: &[ClipVertex], surface: &Surface, shade: F)
+where
+    F: Fn(u8, usize) -> u8,
+{
+    if polygon.len() < 3 {
+        return;
+    }
+
+    let screen: Vec<ScreenVertex> = polygon
+        .iter()
+        .map(|v| ScreenVertex::project(v, fb.width, fb.height))
+        .collect();
+
+    // draw the polygon as a triangle fan
+    for i in 1..screen.len() - 1 {
+        draw_triangle(fb, [screen[0], screen[i], screen[i + 1]], surface, &shade);
+    }
+}
+
+fn draw_triangle<F>(fb: &mut Framebuffer, tri: [ScreenVertex; 3], surface: &Surface, shade: &F)
+where
+    F: Fn(u8, usize) -> u8,
+{
+    let [v0, v1, v2] = tri;
+    let area = (v1.x - v0.x) * (v2.y - v0.y) - (v2.x - v0.x) * (v1.y - v0.y);
+    if area.abs() < std::f32::EPSILON {
+        return;
+    }
+
+    // screen-space gradients of each attribute
+    let mut ddx = [0.0; ATTRIB_COUNT];
+    let mut ddy = [0.0; ATTRIB_COUNT];
+    for (a, (dx, dy)) in ddx.iter_mut().zip(ddy.iter_mut()).enumerate() {
+        let d1 = v1.attribs[a] - v0.attribs[a];
+        let d2 = v2.attribs[a] - v0.attribs[a];
+        *dx = (d1 * (v2.y - v0.y) - d2 * (v1.y - v0.y)) / area;
+        *dy = (d2 * (v1.x - v0.x) - d1 * (v2.x - v0.x)) / area;
+    }
+
+    let min_y = tri.iter().map(|v| v.y).fold(f32::INFINITY, f32::min);
+    let max_y = tri.iter().map(|v| v.y).fold(f32::NEG_INFINITY, f32::max);
+    let y_start = (min_y - 0.5).ceil().max(0.0) as u32;
+    let y_end = ((max_y - 0.5).ceil().max(0.0) as u32).min(fb.height);
+
+    for y in y_start..y_end {
+        let yc = y as f32 + 0.5;
+
+        // find where the scanline crosses the triangle's edges
+        let mut x_min = f32::INFINITY;
+        let mut x_max = f32::NEG_INFINITY;
+        for i in 0..3 {
+            let a = tri[i];
+            let b = tri[(i + 1) % 3];
+            if (a.y <= yc && yc < b.y) || (b.y <= yc && yc < a.y) {
+                let x = a.x + (yc - a.y) / (b.y - a.y) * (b.x - a.x);
+                x_min = x_min.min(x);
+                x_max = x_max.max(x);
+            }
+        }
+
+        if x_min > x_max {
+            continue;
+        }
+
+        let x_start = (x_min - 0.5).ceil().max(0.0) as u32;
+        let x_end = ((x_max - 0.5).ceil().max(0.0) as u32).min(fb.width);
+        if x_start >= x_end {
+            continue;
+        }
+
+        // attribute values at the center of the first pixel of the span
+        let mut attribs = v0.attribs;
+        for (a, attrib) in attribs.iter_mut().enumerate() {
+            *attrib += ddx[a] * (x_start as f32 + 0.5 - v0.x) + ddy[a] * (yc - v0.y);
+        }
+
+        let row_start = (y * fb.width) as usize;
+        for x in x_start..x_end {
+            let i = row_start + x as usize;
+            let inv_w = attribs[0];
+
+            if inv_w > fb.depth[i] {
+                let w = 1.0 / inv_w;
+                let texel = surface.texel(attribs[1] * w, attribs[2] * w);
+                let row = match surface.light(attribs[3] * w, attribs[4] * w) {
+                    Some(light) => dithered_row(light, x, y),
+                    None => IDENTITY_ROW,
+                };
+
+                fb.color[i] = shade(texel, row);
+                fb.depth[i] = inv_w;
+            }
+
+            for (attrib, dx) in attribs.iter_mut().zip(ddx.iter()) {
+                *attrib += dx;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn vertex(x: f32, y: f32, w: f32) -> ClipVertex {
+        ClipVertex {
+            position: Vector4::new(x * w, y * w, 0.0, w),
+            texcoord: [0.0, 0.0],
+            lightmap_texcoord: [0.0, 0.0],
+        }
+    }
+
+    #[test]
+    fn test_clip_near() {
+        let polygon = [
+            vertex(0.0, 0.0, 2.0),
+            vertex(1.0, 0.0, 2.0),
+            ClipVertex {
+                position: Vector4::new(0.0, 0.0, 0.0, 0.0),
+                ..vertex(0.0, 0.0, 2.0)
+            },
+        ];
+        let clipped = clip_near(&polygon);
+        assert_eq!(clipped.len(), 4);
+        assert!(clipped.iter().all(|v| v.position.w >= NEAR_W));
+
+        let behind = [
+            vertex(0.0, 0.0, 0.5),
+            vertex(1.0, 0.0, 0.5),
+            vertex(0.0, 1.0, 0.5),
+        ];
+        assert!(clip_near(&behind).is_empty());
+    }
+
+    #[test]
+    fn test_dithered_row() {
+        // full brightness and darkness don't dither
+        assert_eq!(dithered_row(255, 0, 0), 0);
+        assert_eq!(dithered_row(0, 0, 0), COLORMAP_ROWS - 1);
+
+        // a light value between two rows is spread across both
+        let rows: Vec<usize> = (0..4)
+            .flat_map(|y| (0..4).map(move |x| dithered_row(130, x, y)))
+            .collect();
+        let lo = *rows.iter().min().unwrap();
+        let hi = *rows.iter().max().unwrap();
+        assert_eq!(hi - lo, 1);
+    }
+
+    #[test]
+    fn test_draw_polygon() {
+        let indices = [7u8; 4];
+        let surface = Surface {
+            texture: SurfaceTexture {
+                width: 2,
+                height: 2,
+                indices: &indices,
+            },
+            lightmap: None,
+        };
+
+        // a quad covering the left half of the screen
+        let quad = [
+            vertex(-1.0, -1.0, 2.0),
+            vertex(0.0, -1.0, 2.0),
+            vertex(0.0, 1.0, 2.0),
+            vertex(-1.0, 1.0, 2.0),
+        ];
+
+        let mut fb = Framebuffer::new(8, 4);
+        fb.clear(0);
+        draw_polygon(&mut fb, &quad, &surface, |index, row| {
+            assert_eq!(row, IDENTITY_ROW);
+            index
+        });
+
+        for y in 0..4 {
+            for x in 0..8 {
+                assert_eq!(fb.pixel(x, y), if x < 4 { 7 } else { 0 });
+            }
+        }
+
+        // a nearer polygon wins the depth test, a farther one doesn't
+        let far: Vec<ClipVertex> = quad
+            .iter()
+            .map(|v| vertex(v.position.x / 2.0, v.position.y / 2.0, 4.0))
+            .collect();
+        draw_polygon(&mut fb, &far, &surface, |_, _| 9);
+        assert_eq!(fb.pixel(0, 0), 7);
+
+        let near: Vec<ClipVertex> = quad
+            .iter()
+            .map(|v| vertex(v.position.x / 2.0, v.position.y / 2.0, 1.5))
+            .collect();
+        draw_polygon(&mut fb, &near, &surface, |_, _| 9);
+        assert_eq!(fb.pixel(0, 0), 9);
+    }
+}
@@ -0,0 +1,350 @@
+// Copyright © 2020 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Per-pass GPU timings and draw counters, reported by `r_speeds`.
+//!
+//! A frame is split into sections in the order they're recorded. Each section
+//! starts with a timestamp query, so its GPU time is the difference between
+//! its timestamp and the next one. Timings are only available if the device
+//! supports `wgpu::Features::TIMESTAMP_QUERY`; draw counts always are.
+
+use std::{
+    cell::{Cell, Ref, RefCell},
+    fmt::Write as _,
+    mem::size_of,
+    time::Duration,
+};
+
+pub const SECTION_COUNT: usize = 5;
+
+// one timestamp at the start of each section plus one at the end of the frame
+const TIMESTAMP_COUNT: u32 = SECTION_COUNT as u32 + 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Section {
+    World = 0,
+    Entities = 1,
+    Particles = 2,
+    Lighting = 3,
+    Ui = 4,
+}
+
+impl Section {
+    pub const ALL: [Section; SECTION_COUNT] = [
+        Section::World,
+        Section::Entities,
+        Section::Particles,
+        Section::Lighting,
+        Section::Ui,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Section::World => "world",
+            Section::Entities => "entities",
+            Section::Particles => "particles",
+            Section::Lighting => "lighting",
+            Section::Ui => "2d",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SectionStats {
+    pub draw_calls: u32,
+    pub triangles: u32,
+
+    /// GPU time spent in the section, if timestamp queries are supported.
+    pub gpu_time: Option<Duration>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    pub sections: [SectionStats; SECTION_COUNT],
+}
+
+impl FrameStats {
+    pub fn section(&self, section: Section) -> &SectionStats {
+        &self.sections[section as usize]
+    }
+
+    /// Returns the sum of all sections' statistics.
+    pub fn total(&self) -> SectionStats {
+        let mut total = SectionStats::default();
+        for s in self.sections.iter() {
+            total.draw_calls += s.draw_calls;
+            total.triangles += s.triangles;
+            total.gpu_time = match (total.gpu_time, s.gpu_time) {
+                (Some(a), Some(b)) => Some(a + b),
+                (a, b) => a.or(b),
+            };
+        }
+        total
+    }
+
+    /// Formats the statistics as a single line of console output.
+    pub fn summary(&self) -> String {
+        fn ms(time: Option<Duration>) -> String {
+            match time {
+                Some(t) => format!("{:.2}", t.as_secs_f64() * 1000.0),
+                None => "-".to_owned(),
+            }
+        }
+
+        let total = self.total();
+        let mut out = format!(
+            "{} ms {} draws {} tris |",
+            ms(total.gpu_time),
+            total.draw_calls,
+            total.triangles
+        );
+        for section in Section::ALL.iter() {
+            write!(
+                &mut out,
+                " {} {}",
+                section.name(),
+                ms(self.section(*section).gpu_time)
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+/// Converts raw timestamps to section durations.
+///
+/// `written` has bit `i` set if section `i` was started this frame. A section
+/// ends where the next started section begins, or at the end of the frame.
+/// Timestamp values are in ticks of `period` nanoseconds.
+fn section_times(
+    timestamps: &[u64],
+    written: u32,
+    period: f32,
+) -> [Option<Duration>; SECTION_COUNT] {
+    let mut times = [None; SECTION_COUNT];
+    for i in 0..SECTION_COUNT {
+        if written & (1 << i) == 0 {
+            continue;
+        }
+
+        let end = (i + 1..SECTION_COUNT)
+            .find(|j| written & (1 << j) != 0)
+            .map(|j| timestamps[j])
+            .unwrap_or(timestamps[SECTION_COUNT]);
+        let ticks = end.saturating_sub(timestamps[i]);
+        times[i] = Some(Duration::from_nanos((ticks as f64 * period as f64) as u64));
+    }
+    times
+}
+
+struct GpuTimer {
+    query_set: wgpu::QuerySet,
+    buffer: wgpu::Buffer,
+
+    // nanoseconds per timestamp tick
+    period: f32,
+}
+
+pub struct RenderStats {
+    enabled: Cell<bool>,
+    current: Cell<Section>,
+
+    // bit i is set once section i has written its timestamp this frame
+    written: Cell<u32>,
+    resolved: Cell<bool>,
+
+    counters: RefCell<FrameStats>,
+    last_frame: RefCell<FrameStats>,
+    timer: Option<GpuTimer>,
+}
+
+impl RenderStats {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> RenderStats {
+        let timer = if device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            Some(GpuTimer {
+                query_set: device.create_query_set(&wgpu::QuerySetDescriptor {
+                    count: TIMESTAMP_COUNT,
+                    ty: wgpu::QueryType::Timestamp,
+                }),
+                buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("timestamp buffer"),
+                    size: (TIMESTAMP_COUNT as usize * size_of::<u64>()) as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+                    mapped_at_creation: false,
+                }),
+                period: queue.get_timestamp_period(),
+            })
+        } else {
+            None
+        };
+
+        RenderStats {
+            enabled: Cell::new(false),
+            current: Cell::new(Section::World),
+            written: Cell::new(0),
+            resolved: Cell::new(false),
+            counters: RefCell::new(FrameStats::default()),
+            last_frame: RefCell::new(FrameStats::default()),
+            timer,
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.set(enabled);
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    /// Attribute subsequent draws in `pass` to `section`.
+    pub fn begin_section(&self, pass: &mut wgpu::RenderPass, section: Section) {
+        if !self.enabled.get() {
+            return;
+        }
+
+        self.current.set(section);
+        if let Some(ref timer) = self.timer {
+            pass.write_timestamp(&timer.query_set, section as u32);
+            self.written.set(self.written.get() | 1 << section as u32);
+        }
+    }
+
+    /// Count a draw call of `vertices` triangle list vertices and `instances` instances.
+    pub fn count_draw(&self, vertices: u32, instances: u32) {
+        if !self.enabled.get() {
+            return;
+        }
+
+        let mut counters = self.counters.borrow_mut();
+        let section = &mut counters.sections[self.current.get() as usize];
+        section.draw_calls += 1;
+        section.triangles += vertices / 3 * instances;
+    }
+
+    /// Record the end-of-frame timestamp and resolve this frame's queries.
+    ///
+    /// Sections that weren't started get the end timestamp so every query is
+    /// written before it's resolved.
+    pub fn end_frame(&self, encoder: &mut wgpu::CommandEncoder) {
+        if !self.enabled.get() {
+            return;
+        }
+
+        if let Some(ref timer) = self.timer {
+            for i in 0..TIMESTAMP_COUNT {
+                if i == TIMESTAMP_COUNT - 1 || self.written.get() & 1 << i == 0 {
+                    encoder.write_timestamp(&timer.query_set, i);
+                }
+            }
+            encoder.resolve_query_set(&timer.query_set, 0..TIMESTAMP_COUNT, &timer.buffer, 0);
+            self.resolved.set(true);
+        }
+    }
+
+    /// Collect this frame's statistics once its commands have been submitted.
+    ///
+    /// This waits for the GPU to finish the frame if timings were recorded.
+    pub fn finish_frame(&self, device: &wgpu::Device) {
+        let mut frame = self.counters.replace(FrameStats::default());
+        let written = self.written.replace(0);
+
+        if let Some(ref timer) = self.timer {
+            if self.resolved.replace(false) {
+                let slice = timer.buffer.slice(..);
+                let map_future = slice.map_async(wgpu::MapMode::Read);
+                device.poll(wgpu::Maintain::Wait);
+                if futures::executor::block_on(map_future).is_ok() {
+                    let timestamps: Vec<u64> = slice
+                        .get_mapped_range()
+                        .chunks_exact(size_of::<u64>())
+                        .map(|c| {
+                            let mut bytes = [0; 8];
+                            bytes.copy_from_slice(c);
+                            u64::from_le_bytes(bytes)
+                        })
+                        .collect();
+                    timer.buffer.unmap();
+
+                    let times = section_times(&timestamps, written, timer.period);
+                    for (section, time) in frame.sections.iter_mut().zip(times.iter()) {
+                        section.gpu_time = *time;
+                    }
+                }
+            }
+        }
+
+        self.last_frame.replace(frame);
+    }
+
+    /// Returns the statistics of the last finished frame.
+    pub fn last_frame(&self) -> Ref<FrameStats> {
+        self.last_frame.borrow()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_section_times() {
+        // entities and lighting weren't drawn, so their timestamps were
+        // written at the end of the frame
+        let timestamps = [100, 900, 300, 900, 500, 900];
+        let written =
+            1 << Section::World as u32 | 1 << Section::Particles as u32 | 1 << Section::Ui as u32;
+        let times = section_times(&timestamps, written, 2.0);
+        assert_eq!(
+            times[Section::World as usize],
+            Some(Duration::from_nanos(400))
+        );
+        assert_eq!(times[Section::Entities as usize], None);
+        assert_eq!(
+            times[Section::Particles as usize],
+            Some(Duration::from_nanos(400))
+        );
+        assert_eq!(times[Section::Ui as usize], Some(Duration::from_nanos(800)));
+    }
+
+    #[test]
+    fn test_frame_stats_total() {
+        let mut stats = FrameStats::default();
+        stats.sections[Section::World as usize] = SectionStats {
+            draw_calls: 10,
+            triangles: 200,
+            gpu_time: Some(Duration::from_micros(500)),
+        };
+        stats.sections[Section::Ui as usize] = SectionStats {
+            draw_calls: 3,
+            triangles: 6,
+            gpu_time: Some(Duration::from_micros(250)),
+        };
+
+        let total = stats.total();
+        assert_eq!(total.draw_calls, 13);
+        assert_eq!(total.triangles, 206);
+        assert_eq!(total.gpu_time, Some(Duration::from_micros(750)));
+        assert_eq!(
+            stats.summary(),
+            "0.75 ms 13 draws 206 tris | world 0.50 entities - particles - lighting - 2d 0.25"
+        );
+    }
+}
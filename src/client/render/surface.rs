@@ -0,0 +1,135 @@
+// Copyright © 2020 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Swap chain management for the window surface.
+//!
+//! The swap chain is rebuilt lazily: resizing the window, changing the present
+//! mode or losing the surface only marks it stale, and the next call to
+//! `WindowSurface::current_frame` rebuilds it. No frames are presented while
+//! the window has zero area (e.g. when it is minimized).
+
+use crate::client::render::{Extent2d, DIFFUSE_ATTACHMENT_FORMAT};
+
+/// Returns the present mode selected by `vid_vsync`.
+pub fn present_mode(vsync: bool) -> wgpu::PresentMode {
+    match vsync {
+        true => wgpu::PresentMode::Fifo,
+        false => wgpu::PresentMode::Immediate,
+    }
+}
+
+/// A window surface and the swap chain used to present to it.
+pub struct WindowSurface {
+    surface: wgpu::Surface,
+    swap_chain: Option<wgpu::SwapChain>,
+    size: Extent2d,
+    present_mode: wgpu::PresentMode,
+    stale: bool,
+}
+
+impl WindowSurface {
+    pub fn new(
+        device: &wgpu::Device,
+        surface: wgpu::Surface,
+        size: Extent2d,
+        present_mode: wgpu::PresentMode,
+    ) -> WindowSurface {
+        let mut s = WindowSurface {
+            surface,
+            swap_chain: None,
+            size,
+            present_mode,
+            stale: true,
+        };
+        s.rebuild(device);
+        s
+    }
+
+    pub fn size(&self) -> Extent2d {
+        self.size
+    }
+
+    /// Resize the swap chain to `size` before the next frame.
+    pub fn resize(&mut self, size: Extent2d) {
+        if size != self.size {
+            self.size = size;
+            self.stale = true;
+        }
+    }
+
+    /// Switch to `present_mode` before the next frame.
+    pub fn set_present_mode(&mut self, present_mode: wgpu::PresentMode) {
+        if present_mode != self.present_mode {
+            self.present_mode = present_mode;
+            self.stale = true;
+        }
+    }
+
+    /// Returns the next frame to draw to, or `None` if no frame should be drawn.
+    ///
+    /// A swap chain that has gone out of date or been lost is rebuilt on the
+    /// following call.
+    pub fn current_frame(&mut self, device: &wgpu::Device) -> Option<wgpu::SwapChainFrame> {
+        if self.stale {
+            self.rebuild(device);
+        }
+
+        let frame = match self.swap_chain.as_mut()?.get_current_frame() {
+            Ok(f) => f,
+            Err(wgpu::SwapChainError::Timeout) => {
+                debug!("Timed out waiting for swap chain frame");
+                return None;
+            }
+            Err(e @ wgpu::SwapChainError::Outdated) | Err(e @ wgpu::SwapChainError::Lost) => {
+                debug!("Rebuilding swap chain: {}", e);
+                self.stale = true;
+                return None;
+            }
+            Err(wgpu::SwapChainError::OutOfMemory) => panic!("Out of memory for swap chain"),
+        };
+
+        if frame.suboptimal {
+            self.stale = true;
+        }
+
+        Some(frame)
+    }
+
+    fn rebuild(&mut self, device: &wgpu::Device) {
+        self.stale = false;
+
+        // drop the old swap chain before creating its replacement
+        self.swap_chain = None;
+        if self.size.width == 0 || self.size.height == 0 {
+            return;
+        }
+
+        self.swap_chain = Some(device.create_swap_chain(
+            &self.surface,
+            &wgpu::SwapChainDescriptor {
+                usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
+                format: DIFFUSE_ATTACHMENT_FORMAT,
+                width: self.size.width,
+                height: self.size.height,
+                present_mode: self.present_mode,
+            },
+        ));
+    }
+}
@@ -0,0 +1,616 @@
+// Copyright © 2020 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Mipmap generation and block compression for world and model textures.
+//!
+//! Diffuse textures can be compressed to BC1, which is small but has only
+//! 1-bit alpha and visible banding, or BC7, which is twice the size but much
+//! closer to the original. The BC7 encoder only uses mode 6 (a single pair of
+//! RGBA endpoints with 16 interpolation steps), which suits Quake's small,
+//! low-contrast textures.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Texel block dimension of all BCn formats.
+pub const BC_BLOCK_SIZE: u32 = 4;
+
+/// Size in bytes of a single BC1 block.
+pub const BC1_BLOCK_BYTES: usize = 8;
+
+/// Size in bytes of a single BC7 block.
+pub const BC7_BLOCK_BYTES: usize = 16;
+
+// BC7 interpolation weights for 4-bit indices, in 64ths
+const BC7_WEIGHTS: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+/// Bumped whenever the encoder output changes so stale cache entries are ignored.
+const CACHE_VERSION: u64 = 1;
+
+/// The block compression applied to diffuse textures.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TextureCompression {
+    None,
+    Bc1,
+    Bc7,
+}
+
+impl TextureCompression {
+    /// Returns the compression selected by `r_texturecompression`: 0 is none, 1 is BC1 and 2 is
+    /// BC7.
+    pub fn from_cvar(value: f32) -> TextureCompression {
+        match value as i32 {
+            1 => TextureCompression::Bc1,
+            2 => TextureCompression::Bc7,
+            _ => TextureCompression::None,
+        }
+    }
+
+    /// Returns the size in bytes of a compressed block, or `None` if textures aren't compressed.
+    pub fn block_bytes(&self) -> Option<usize> {
+        match *self {
+            TextureCompression::None => None,
+            TextureCompression::Bc1 => Some(BC1_BLOCK_BYTES),
+            TextureCompression::Bc7 => Some(BC7_BLOCK_BYTES),
+        }
+    }
+
+    /// Compresses an RGBA image whose dimensions are multiples of the block size.
+    ///
+    /// Uncompressed images are returned as they are.
+    pub fn compress(&self, width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+        match *self {
+            TextureCompression::None => rgba.to_owned(),
+            TextureCompression::Bc1 => compress_bc1(width, height, rgba),
+            TextureCompression::Bc7 => compress_bc7(width, height, rgba),
+        }
+    }
+}
+
+/// Options controlling how textures are uploaded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TextureSettings {
+    /// Generate a full mip chain instead of uploading only the base level.
+    pub mipmaps: bool,
+
+    /// Compress diffuse textures when the device supports it.
+    pub compression: TextureCompression,
+}
+
+impl std::default::Default for TextureSettings {
+    fn default() -> TextureSettings {
+        TextureSettings {
+            mipmaps: true,
+            compression: TextureCompression::None,
+        }
+    }
+}
+
+/// Returns the number of levels in a full mip chain for a texture of the given size.
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+/// Returns the number of mip levels that can be block-compressed.
+///
+/// Every level must have dimensions divisible by the block size, so the chain stops at the first
+/// level that does not. Returns 0 if the base level itself cannot be compressed.
+pub fn bc_mip_level_count(width: u32, height: u32) -> u32 {
+    let mut count = 0;
+    let (mut w, mut h) = (width, height);
+    while w > 0 && h > 0 && w % BC_BLOCK_SIZE == 0 && h % BC_BLOCK_SIZE == 0 {
+        count += 1;
+        w /= 2;
+        h /= 2;
+    }
+    count
+}
+
+/// Returns the size of the given mip level of a texture.
+pub fn mip_level_size(width: u32, height: u32, level: u32) -> (u32, u32) {
+    ((width >> level).max(1), (height >> level).max(1))
+}
+
+/// Generates `level_count` mip levels from `data` using a 2x2 box filter.
+///
+/// `data` holds the base level with `channels` bytes per texel. The returned list includes a copy
+/// of the base level as its first element.
+pub fn generate_mipmaps(
+    width: u32,
+    height: u32,
+    channels: usize,
+    data: &[u8],
+    level_count: u32,
+) -> Vec<Vec<u8>> {
+    assert_eq!(data.len(), width as usize * height as usize * channels);
+
+    let mut levels = vec![data.to_owned()];
+    let (mut w, mut h) = (width as usize, height as usize);
+
+    for _ in 1..level_count.max(1) {
+        let src = levels.last().unwrap();
+        let next_w = (w / 2).max(1);
+        let next_h = (h / 2).max(1);
+        let mut dst = Vec::with_capacity(next_w * next_h * channels);
+
+        for y in 0..next_h {
+            let y0 = (y * 2).min(h - 1);
+            let y1 = (y * 2 + 1).min(h - 1);
+            for x in 0..next_w {
+                let x0 = (x * 2).min(w - 1);
+                let x1 = (x * 2 + 1).min(w - 1);
+                for c in 0..channels {
+                    let sum = src[(y0 * w + x0) * channels + c] as u32
+                        + src[(y0 * w + x1) * channels + c] as u32
+                        + src[(y1 * w + x0) * channels + c] as u32
+                        + src[(y1 * w + x1) * channels + c] as u32;
+                    dst.push(((sum + 2) / 4) as u8);
+                }
+            }
+        }
+
+        levels.push(dst);
+        w = next_w;
+        h = next_h;
+    }
+
+    levels
+}
+
+fn pack_565(rgb: [u8; 3]) -> u16 {
+    (rgb[0] as u16 >> 3) << 11 | (rgb[1] as u16 >> 2) << 5 | rgb[2] as u16 >> 3
+}
+
+fn unpack_565(c: u16) -> [u8; 3] {
+    let r = (c >> 11 & 0x1F) as u8;
+    let g = (c >> 5 & 0x3F) as u8;
+    let b = (c & 0x1F) as u8;
+    [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+}
+
+fn lerp_rgb(a: [u8; 3], b: [u8; 3], wa: u32, wb: u32) -> [u8; 3] {
+    let mut out = [0; 3];
+    for i in 0..3 {
+        out[i] = ((a[i] as u32 * wa + b[i] as u32 * wb) / (wa + wb)) as u8;
+    }
+    out
+}
+
+fn distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+    (0..3)
+        .map(|i| {
+            let d = a[i] as i32 - b[i] as i32;
+            (d * d) as u32
+        })
+        .sum()
+}
+
+/// Encodes a 4x4 block of RGBA texels as a single BC1 block.
+///
+/// Texels with alpha below 128 are encoded as transparent using BC1's three-color mode.
+pub fn encode_bc1_block(texels: &[[u8; 4]; 16]) -> [u8; BC1_BLOCK_BYTES] {
+    let transparent = texels.iter().any(|t| t[3] < 128);
+
+    let mut min = [0xFF; 3];
+    let mut max = [0; 3];
+    let mut opaque = 0;
+    for t in texels.iter().filter(|t| t[3] >= 128) {
+        opaque += 1;
+        for i in 0..3 {
+            min[i] = min[i].min(t[i]);
+            max[i] = max[i].max(t[i]);
+        }
+    }
+
+    if opaque == 0 {
+        // three-color mode with every index pointing at the transparent entry
+        return [0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF];
+    }
+
+    let mut c0 = pack_565(max);
+    let mut c1 = pack_565(min);
+
+    // the endpoint order selects the block mode: c0 > c1 is four-color, c0 <= c1 is three-color
+    if transparent == (c0 > c1) {
+        std::mem::swap(&mut c0, &mut c1);
+    }
+
+    let e0 = unpack_565(c0);
+    let e1 = unpack_565(c1);
+    let palette = if c0 > c1 {
+        [e0, e1, lerp_rgb(e0, e1, 2, 1), lerp_rgb(e0, e1, 1, 2)]
+    } else {
+        [e0, e1, lerp_rgb(e0, e1, 1, 1), [0; 3]]
+    };
+    let candidates = if c0 > c1 { 4 } else { 3 };
+
+    let mut indices = 0u32;
+    for (i, t) in texels.iter().enumerate() {
+        let index = if t[3] < 128 {
+            3
+        } else {
+            let rgb = [t[0], t[1], t[2]];
+            (0..candidates)
+                .min_by_key(|&c| distance(rgb, palette[c]))
+                .unwrap() as u32
+        };
+        indices |= index << (2 * i);
+    }
+
+    let mut block = [0; BC1_BLOCK_BYTES];
+    block[0..2].copy_from_slice(&c0.to_le_bytes());
+    block[2..4].copy_from_slice(&c1.to_le_bytes());
+    block[4..8].copy_from_slice(&indices.to_le_bytes());
+    block
+}
+
+fn interpolate_bc7(e0: [u8; 4], e1: [u8; 4], weight: u32) -> [u8; 4] {
+    let mut out = [0; 4];
+    for i in 0..4 {
+        out[i] = (((64 - weight) * e0[i] as u32 + weight * e1[i] as u32 + 32) >> 6) as u8;
+    }
+    out
+}
+
+fn distance_rgba(a: [u8; 4], b: [u8; 4]) -> u32 {
+    (0..4)
+        .map(|i| {
+            let d = a[i] as i32 - b[i] as i32;
+            (d * d) as u32
+        })
+        .sum()
+}
+
+// Quantizes an endpoint to 7 bits per channel plus a shared low bit (the p-bit).
+//
+// Returns the 7-bit channels, the p-bit and the color they decode to. Opaque endpoints always use
+// an odd p-bit so that their alpha stays exactly 255.
+fn quantize_bc7_endpoint(color: [u8; 4]) -> ([u8; 4], u8, [u8; 4]) {
+    let quantize = |p: u8| {
+        let mut quantized = [0; 4];
+        let mut decoded = [0; 4];
+        for i in 0..4 {
+            quantized[i] = ((color[i] as u32 + 1 - p as u32) / 2).min(127) as u8;
+            decoded[i] = quantized[i] << 1 | p;
+        }
+        (quantized, p, decoded)
+    };
+
+    let (even, odd) = (quantize(0), quantize(1));
+    if color[3] == 0xFF || distance_rgba(color, odd.2) < distance_rgba(color, even.2) {
+        odd
+    } else {
+        even
+    }
+}
+
+fn put_bits(block: &mut u128, pos: &mut u32, value: u8, bits: u32) {
+    *block |= (value as u128) << *pos;
+    *pos += bits;
+}
+
+/// Encodes a 4x4 block of RGBA texels as a single BC7 mode 6 block.
+pub fn encode_bc7_block(texels: &[[u8; 4]; 16]) -> [u8; BC7_BLOCK_BYTES] {
+    let mut min = [0xFF; 4];
+    let mut max = [0; 4];
+    let mut mean = [0; 4];
+    for t in texels.iter() {
+        for i in 0..4 {
+            min[i] = min[i].min(t[i]);
+            max[i] = max[i].max(t[i]);
+            mean[i] += t[i] as i32;
+        }
+    }
+
+    // the endpoints span the bounding box along the diagonal that best fits the texels: channels
+    // that fall as the widest channel rises run from max to min
+    let widest = (0..4).max_by_key(|&i| max[i] - min[i]).unwrap();
+    for i in 0..4 {
+        let covariance: i32 = texels
+            .iter()
+            .map(|t| (t[i] as i32 * 16 - mean[i]) * (t[widest] as i32 * 16 - mean[widest]))
+            .sum();
+        if covariance < 0 {
+            std::mem::swap(&mut min[i], &mut max[i]);
+        }
+    }
+
+    let (mut q0, mut p0, e0) = quantize_bc7_endpoint(min);
+    let (mut q1, mut p1, e1) = quantize_bc7_endpoint(max);
+    let palette: Vec<[u8; 4]> = BC7_WEIGHTS
+        .iter()
+        .map(|&w| interpolate_bc7(e0, e1, w))
+        .collect();
+
+    let mut indices = [0u8; 16];
+    for (index, t) in indices.iter_mut().zip(texels.iter()) {
+        *index = (0..16)
+            .min_by_key(|&c| distance_rgba(*t, palette[c]))
+            .unwrap() as u8;
+    }
+
+    // the first index is stored without its high bit, so swap the endpoints if it's set
+    if indices[0] >= 8 {
+        std::mem::swap(&mut q0, &mut q1);
+        std::mem::swap(&mut p0, &mut p1);
+        for index in indices.iter_mut() {
+            *index = 15 - *index;
+        }
+    }
+
+    // mode 6 is selected by a single set bit after six clear ones
+    let mut block = 1u128 << 6;
+    let mut pos = 7;
+    for i in 0..4 {
+        put_bits(&mut block, &mut pos, q0[i], 7);
+        put_bits(&mut block, &mut pos, q1[i], 7);
+    }
+    put_bits(&mut block, &mut pos, p0, 1);
+    put_bits(&mut block, &mut pos, p1, 1);
+    for (i, &index) in indices.iter().enumerate() {
+        put_bits(&mut block, &mut pos, index, if i == 0 { 3 } else { 4 });
+    }
+
+    block.to_le_bytes()
+}
+
+// split an RGBA image into 4x4 blocks and encode each one
+fn compress_blocks<F, B>(width: u32, height: u32, rgba: &[u8], encode: F) -> Vec<u8>
+where
+    F: Fn(&[[u8; 4]; 16]) -> B,
+    B: AsRef<[u8]>,
+{
+    assert!(width % BC_BLOCK_SIZE == 0 && height % BC_BLOCK_SIZE == 0);
+    assert_eq!(rgba.len(), width as usize * height as usize * 4);
+
+    let (w, h) = (width as usize, height as usize);
+    let mut out = Vec::new();
+
+    for by in (0..h).step_by(4) {
+        for bx in (0..w).step_by(4) {
+            let mut texels = [[0; 4]; 16];
+            for y in 0..4 {
+                for x in 0..4 {
+                    let ofs = ((by + y) * w + bx + x) * 4;
+                    texels[y * 4 + x].copy_from_slice(&rgba[ofs..ofs + 4]);
+                }
+            }
+            out.extend_from_slice(encode(&texels).as_ref());
+        }
+    }
+
+    out
+}
+
+/// Compresses an RGBA image to BC1.
+///
+/// The image dimensions must be multiples of the block size.
+pub fn compress_bc1(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    compress_blocks(width, height, rgba, encode_bc1_block)
+}
+
+/// Compresses an RGBA image to BC7.
+///
+/// The image dimensions must be multiples of the block size.
+pub fn compress_bc7(width: u32, height: u32, rgba: &[u8]) -> Vec<u8> {
+    compress_blocks(width, height, rgba, encode_bc7_block)
+}
+
+/// Computes a 64-bit FNV-1a hash of the given bytes.
+pub fn content_hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// An on-disk cache of compressed mip chains, keyed by the hash of the source image.
+#[derive(Debug)]
+pub struct TextureCache {
+    dir: PathBuf,
+}
+
+impl TextureCache {
+    pub fn new<P>(dir: P) -> TextureCache
+    where
+        P: AsRef<Path>,
+    {
+        TextureCache {
+            dir: dir.as_ref().to_owned(),
+        }
+    }
+
+    /// Returns the cache key for an image with the given compression, size and contents.
+    pub fn key(
+        compression: TextureCompression,
+        width: u32,
+        height: u32,
+        level_count: u32,
+        rgba: &[u8],
+    ) -> u64 {
+        let mut header = Vec::with_capacity(21);
+        header.extend_from_slice(&CACHE_VERSION.to_le_bytes());
+        header.push(compression as u8);
+        header.extend_from_slice(&width.to_le_bytes());
+        header.extend_from_slice(&height.to_le_bytes());
+        header.extend_from_slice(&level_count.to_le_bytes());
+        content_hash(&header) ^ content_hash(rgba).rotate_left(1)
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.bcn", key))
+    }
+
+    /// Loads a cached entry, returning `None` if it is missing or not `len` bytes long.
+    pub fn load(&self, key: u64, len: usize) -> Option<Vec<u8>> {
+        match fs::read(self.path(key)) {
+            Ok(data) if data.len() == len => Some(data),
+            _ => None,
+        }
+    }
+
+    pub fn store(&self, key: u64, data: &[u8]) {
+        let result = fs::create_dir_all(&self.dir).and_then(|_| fs::write(self.path(key), data));
+        if let Err(e) = result {
+            warn!("Failed to write texture cache entry {:016x}: {}", key, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mip_level_count() {
+        assert_eq!(mip_level_count(1, 1), 1);
+        assert_eq!(mip_level_count(64, 64), 7);
+        assert_eq!(mip_level_count(64, 16), 7);
+        assert_eq!(mip_level_count(320, 200), 9);
+    }
+
+    #[test]
+    fn test_bc_mip_level_count() {
+        assert_eq!(bc_mip_level_count(64, 64), 5);
+        assert_eq!(bc_mip_level_count(64, 48), 3);
+        assert_eq!(bc_mip_level_count(30, 32), 0);
+    }
+
+    #[test]
+    fn test_generate_mipmaps_box_filter() {
+        #[rustfmt::skip]
+        let data = [
+            0, 4, 8, 8,
+            4, 8, 8, 8,
+        ];
+        let levels = generate_mipmaps(4, 2, 1, &data, mip_level_count(4, 2));
+        assert_eq!(levels.len(), 3);
+        assert_eq!(levels[1], vec![4, 8]);
+        assert_eq!(levels[2], vec![6]);
+    }
+
+    #[test]
+    fn test_encode_bc1_solid() {
+        let block = encode_bc1_block(&[[0xFF, 0, 0, 0xFF]; 16]);
+        assert_eq!(u16::from_le_bytes([block[0], block[1]]), 0xF800);
+        assert_eq!(&block[4..8], &[0; 4]);
+    }
+
+    #[test]
+    fn test_encode_bc1_transparent() {
+        let mut texels = [[0xFF; 4]; 16];
+        texels[5] = [0; 4];
+        let block = encode_bc1_block(&texels);
+        let c0 = u16::from_le_bytes([block[0], block[1]]);
+        let c1 = u16::from_le_bytes([block[2], block[3]]);
+        let indices = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+        assert!(c0 <= c1);
+        assert_eq!(indices >> 10 & 0b11, 3);
+        assert_eq!(indices & 0b11, 0);
+    }
+
+    #[test]
+    fn test_compress_bc1_size() {
+        let rgba = vec![0x80; 8 * 4 * 4];
+        assert_eq!(compress_bc1(8, 4, &rgba).len(), 2 * BC1_BLOCK_BYTES);
+    }
+
+    // decode a BC7 mode 6 block
+    fn decode_bc7_block(block: [u8; BC7_BLOCK_BYTES]) -> [[u8; 4]; 16] {
+        let bits = u128::from_le_bytes(block);
+        assert_eq!(bits & 0x7F, 1 << 6);
+
+        let mut pos = 7;
+        let mut get = |n: u32| {
+            let value = (bits >> pos) as u32 & ((1 << n) - 1);
+            pos += n;
+            value as u8
+        };
+
+        let mut q = [[0; 4]; 2];
+        for i in 0..4 {
+            q[0][i] = get(7);
+            q[1][i] = get(7);
+        }
+        let p = [get(1), get(1)];
+        let mut e = [[0; 4]; 2];
+        for i in 0..4 {
+            e[0][i] = q[0][i] << 1 | p[0];
+            e[1][i] = q[1][i] << 1 | p[1];
+        }
+
+        let mut texels = [[0; 4]; 16];
+        for (i, t) in texels.iter_mut().enumerate() {
+            let index = get(if i == 0 { 3 } else { 4 });
+            *t = interpolate_bc7(e[0], e[1], BC7_WEIGHTS[index as usize]);
+        }
+        texels
+    }
+
+    #[test]
+    fn test_encode_bc7_round_trip() {
+        // the p-bit is shared between channels, so only alpha is exact
+        let solid = [[200, 100, 50, 255]; 16];
+        for t in decode_bc7_block(encode_bc7_block(&solid)).iter() {
+            assert_eq!(t[3], 255);
+            for c in 0..3 {
+                assert!((t[c] as i32 - solid[0][c] as i32).abs() <= 1);
+            }
+        }
+
+        // a gradient from opaque red to translucent green
+        let mut gradient = [[0; 4]; 16];
+        for (i, t) in gradient.iter_mut().enumerate() {
+            let f = (i * 17) as u8;
+            *t = [255 - f, f, 0, 255 - f / 2];
+        }
+
+        let decoded = decode_bc7_block(encode_bc7_block(&gradient));
+        for (a, b) in gradient.iter().zip(decoded.iter()) {
+            for c in 0..4 {
+                assert!((a[c] as i32 - b[c] as i32).abs() <= 8, "{:?} != {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compress_bc7_size() {
+        let rgba = vec![0x80; 8 * 4 * 4];
+        assert_eq!(compress_bc7(8, 4, &rgba).len(), 2 * BC7_BLOCK_BYTES);
+    }
+
+    #[test]
+    fn test_cache_key_depends_on_size() {
+        let rgba = vec![0; 64];
+        let key = |c, w, h| TextureCache::key(c, w, h, 1, &rgba);
+        assert_ne!(
+            key(TextureCompression::Bc1, 4, 4),
+            key(TextureCompression::Bc1, 8, 2)
+        );
+        assert_ne!(
+            key(TextureCompression::Bc1, 4, 4),
+            key(TextureCompression::Bc7, 4, 4)
+        );
+    }
+}
@@ -35,9 +35,8 @@ impl ConsoleRenderer {
         quad_cmds: &mut Vec<QuadRendererCommand<'a>>,
         glyph_cmds: &mut Vec<GlyphRendererCommand>,
         proportion: f32,
+        scale: f32,
     ) {
-        // TODO: take scale as cvar
-        let scale = 2.0;
         let console_anchor = Anchor {
             x: AnchorCoord::Zero,
             y: AnchorCoord::Proportion(1.0 - proportion),
@@ -98,9 +97,27 @@ impl ConsoleRenderer {
             });
         }
 
-        // draw previous output
-        for (line_id, line) in console.output().lines().enumerate() {
-            // TODO: implement scrolling
+        // draw previous output, leaving a row for the scrollback marker if the
+        // view isn't at the newest line
+        let output = console.output();
+        let first_row = if output.scroll() > 0 {
+            glyph_cmds.push(GlyphRendererCommand::Text {
+                text: "^   ^   ^   ^   ^   ^   ^   ^".to_owned(),
+                position: ScreenPosition::Relative {
+                    anchor: console_anchor,
+                    x_ofs: PAD_LEFT,
+                    y_ofs: GLYPH_HEIGHT as i32,
+                },
+                anchor: Anchor::BOTTOM_LEFT,
+                scale,
+            });
+            1
+        } else {
+            0
+        };
+
+        for (line_id, line) in output.visible_lines().enumerate() {
+            let line_id = line_id + first_row;
             if line_id > 100 {
                 break;
             }
@@ -1,4 +1,4 @@
-use std::{mem::size_of, num::NonZeroU32};
+use std::{borrow::Cow, mem::size_of, num::NonZeroU32};
 
 use crate::{
     client::render::{
@@ -7,12 +7,13 @@ use crate::{
             quad::{QuadPipeline, QuadVertex},
             screen_space_vertex_scale, screen_space_vertex_translate,
         },
-        Extent2d, GraphicsState, Pipeline, TextureData,
+        DiffuseData, Extent2d, GraphicsState, Pipeline, RenderError, RenderErrorKind, TextureData,
     },
     common::util::any_slice_as_bytes,
 };
 
 use cgmath::Vector2;
+use failure::ResultExt as _;
 
 pub const GLYPH_WIDTH: usize = 8;
 pub const GLYPH_HEIGHT: usize = 8;
@@ -195,9 +196,13 @@ pub struct GlyphRenderer {
     #[allow(dead_code)]
     texture_views: Vec<wgpu::TextureView>,
     const_bind_group: wgpu::BindGroup,
+
+    // the replacement font in use, or an empty string for conchars
+    font: String,
 }
 
 impl GlyphRenderer {
+    /// Create a glyph renderer using the `conchars` font from `gfx.wad`.
     pub fn new(state: &GraphicsState) -> GlyphRenderer {
         let conchars = state.gfx_wad().open_conchars().unwrap();
 
@@ -235,6 +240,91 @@ impl GlyphRenderer {
             })
             .collect::<Vec<_>>();
 
+        GlyphRenderer::from_textures(state, textures, String::new())
+    }
+
+    /// Create a glyph renderer using a replacement font.
+    ///
+    /// `name` is the path of a PNG image in the virtual filesystem. Like
+    /// `conchars`, it holds a 16x16 grid of glyphs, but each glyph may be any
+    /// size, so a high-resolution font stays sharp at large text scales.
+    /// Glyphs still take up the same space on screen as `conchars` glyphs.
+    pub fn with_font<S>(state: &GraphicsState, name: S) -> Result<GlyphRenderer, RenderError>
+    where
+        S: AsRef<str>,
+    {
+        let name = name.as_ref();
+        let file = state.vfs().open(name)?;
+
+        let decoder = png::Decoder::new(file);
+        let (info, mut reader) = decoder
+            .read_info()
+            .context(RenderErrorKind::ResourceNotLoaded)?;
+        let mut data = vec![0; info.buffer_size()];
+        reader
+            .next_frame(&mut data)
+            .context(RenderErrorKind::ResourceNotLoaded)?;
+
+        let rgba = match (info.color_type, info.bit_depth) {
+            (png::ColorType::RGBA, png::BitDepth::Eight) => data,
+            (png::ColorType::RGB, png::BitDepth::Eight) => data
+                .chunks_exact(3)
+                .flat_map(|rgb| vec![rgb[0], rgb[1], rgb[2], 0xFF])
+                .collect(),
+            (color, depth) => {
+                warn!(
+                    "{}: unsupported pixel format {:?} ({:?})",
+                    name, color, depth
+                );
+                return Err(RenderErrorKind::ResourceNotLoaded.into());
+            }
+        };
+
+        let width = info.width as usize;
+        let height = info.height as usize;
+        if width == 0 || width % GLYPH_COLS != 0 || height % GLYPH_ROWS != 0 {
+            warn!(
+                "{}: dimensions ({}x{}) are not a multiple of the glyph grid",
+                name, width, height
+            );
+            return Err(RenderErrorKind::ResourceNotLoaded.into());
+        }
+
+        let glyph_w = width / GLYPH_COLS;
+        let glyph_h = height / GLYPH_ROWS;
+        let textures = (0..GLYPH_COUNT)
+            .map(|glyph_id| {
+                let mut glyph = Vec::with_capacity(glyph_w * glyph_h * 4);
+                for glyph_r in 0..glyph_h {
+                    let atlas_r = glyph_h * (glyph_id / GLYPH_COLS) + glyph_r;
+                    let atlas_c = glyph_w * (glyph_id % GLYPH_COLS);
+                    let start = 4 * (atlas_r * width + atlas_c);
+                    glyph.extend_from_slice(&rgba[start..start + 4 * glyph_w]);
+                }
+
+                state.create_texture(
+                    Some(&format!("{}[{}]", name, glyph_id)),
+                    glyph_w as u32,
+                    glyph_h as u32,
+                    &TextureData::Diffuse(DiffuseData {
+                        rgba: Cow::Owned(glyph),
+                    }),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        Ok(GlyphRenderer::from_textures(
+            state,
+            textures,
+            name.to_owned(),
+        ))
+    }
+
+    fn from_textures(
+        state: &GraphicsState,
+        textures: Vec<wgpu::Texture>,
+        font: String,
+    ) -> GlyphRenderer {
         let texture_views = textures
             .iter()
             .map(|tex| tex.create_view(&Default::default()))
@@ -262,9 +352,16 @@ impl GlyphRenderer {
             textures,
             texture_views,
             const_bind_group,
+            font,
         }
     }
 
+    /// Returns the name of the replacement font in use, or an empty string if
+    /// `conchars` is in use.
+    pub fn font(&self) -> &str {
+        &self.font
+    }
+
     pub fn generate_instances(
         &self,
         commands: &[GlyphRendererCommand],
@@ -372,5 +469,6 @@ impl GlyphRenderer {
         pass.set_vertex_buffer(1, state.glyph_pipeline().instance_buffer().slice(..));
         pass.set_bind_group(0, &self.const_bind_group, &[]);
         pass.draw(0..6, 0..commands.len() as u32);
+        state.stats().count_draw(6, commands.len() as u32);
     }
 }
@@ -2,14 +2,20 @@ use std::{collections::HashMap, iter::FromIterator};
 
 use crate::{
     client::{
+        chat::ChatLog,
+        coop::{MarkerPosition, Teammate},
+        input::chat::ChatInput,
+        loading::LoadProgress,
         render::{
             ui::{
                 glyph::GlyphRendererCommand,
-                layout::{Anchor, Layout, ScreenPosition, Size},
+                layout::{Anchor, AnchorCoord, Layout, ScreenPosition, Size},
                 quad::{QuadRendererCommand, QuadTexture},
             },
             GraphicsState,
         },
+        sound::Captions,
+        state::ScoreboardEntry,
         IntermissionKind,
     },
     common::{
@@ -34,19 +40,112 @@ const OVERLAY_Y_OFS: i32 = -OVERLAY_HEIGHT / 2;
 
 const OVERLAY_ANCHOR: Anchor = Anchor::CENTER;
 
+// captions are drawn just above the status bar and inventory
+const CAPTION_Y_OFS: i32 = 56;
+const CAPTION_LINE_HEIGHT: i32 = 10;
+
+// center prints start a third of the way down the overlay, or near the top if
+// they're too long to fit
+const CENTER_PRINT_Y: i32 = 70;
+const CENTER_PRINT_LONG_Y: i32 = 48;
+const CENTER_PRINT_MAX_SHORT_LINES: usize = 4;
+const CENTER_PRINT_LINE_HEIGHT: i32 = 8;
+
+// scoreboard layout, relative to the top-left corner of the overlay
+const SCOREBOARD_Y: i32 = 40;
+const SCOREBOARD_ROW_HEIGHT: i32 = 10;
+const SCOREBOARD_MAX_ROWS: usize = 16;
+const SCOREBOARD_COLOR_X: i32 = 80;
+const SCOREBOARD_COLOR_WIDTH: u32 = 40;
+const SCOREBOARD_COLOR_HEIGHT: u32 = 4;
+const SCOREBOARD_FRAGS_X: i32 = 88;
+const SCOREBOARD_NAME_X: i32 = 128;
+const SCOREBOARD_TIME_X: i32 = 40;
+
+// the coop party list, drawn below the level stats at intermission
+const PARTY_Y: i32 = 28;
+const PARTY_ROW_HEIGHT: i32 = 8;
+const PARTY_MAX_ROWS: usize = 4;
+
+// the coop party HUD, in the top-right corner of the screen
+const PARTY_HUD_ROW_HEIGHT: i32 = 8;
+
+// the loading progress bar, drawn with the menu slider glyphs
+const LOADING_BAR_LEFT: u8 = 128;
+const LOADING_BAR_MIDDLE: u8 = 129;
+const LOADING_BAR_RIGHT: u8 = 130;
+const LOADING_BAR_HANDLE: u8 = 131;
+const LOADING_BAR_WIDTH: i32 = 20;
+
+/// How much of the status bar is drawn, as selected by `viewsize`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SbarLines {
+    /// The status bar and the inventory bar above it.
+    Full,
+
+    /// Only the status bar.
+    StatusOnly,
+
+    /// Nothing but the crosshair.
+    Hidden,
+}
+
+impl SbarLines {
+    pub fn from_viewsize(viewsize: f32) -> SbarLines {
+        if viewsize >= 120.0 {
+            SbarLines::Hidden
+        } else if viewsize >= 110.0 {
+            SbarLines::StatusOnly
+        } else {
+            SbarLines::Full
+        }
+    }
+}
+
 pub enum HudState<'a> {
     InGame {
+        sbar_lines: SbarLines,
         items: ItemFlags,
         item_pickup_time: &'a [Duration],
         stats: &'a [i32],
         face_anim_time: Duration,
         console: &'a Console,
+        captions: &'a Captions,
+        chat: &'a ChatLog,
+
+        /// The chat message being typed, if any.
+        chat_input: Option<&'a ChatInput>,
+
+        /// The lines of the center print on screen.
+        center_print: Vec<&'a str>,
+
+        /// The scoreboard, if it should be shown.
+        scoreboard: Option<Vec<ScoreboardEntry>>,
+
+        /// The other players in a coop game, if their markers should be shown.
+        teammates: Vec<Teammate>,
     },
     Intermission {
         kind: &'a IntermissionKind,
         completion_duration: Duration,
         stats: &'a [i32],
         console: &'a Console,
+        center_print: Vec<&'a str>,
+
+        /// In deathmatch, the scoreboard is shown instead of the level stats.
+        scoreboard: Option<Vec<ScoreboardEntry>>,
+
+        /// In coop, the players who shared the level stats.
+        party: Vec<ScoreboardEntry>,
+    },
+    Loading {
+        /// How much of the level has loaded, or `None` while waiting for the
+        /// server.
+        progress: Option<LoadProgress>,
+
+        /// How many of the missing files have been downloaded, out of the
+        /// total, while downloads are in progress.
+        download: Option<(usize, usize)>,
     },
 }
 
@@ -69,6 +168,9 @@ enum HudTextureId {
     // these are not in gfx.wad
     Complete,
     Intermission,
+    Ranking,
+    Finale,
+    Loading,
 }
 
 impl std::fmt::Display for HudTextureId {
@@ -92,6 +194,9 @@ impl std::fmt::Display for HudTextureId {
             // these are not in gfx.wad
             Complete => write!(f, "gfx/complete.lmp"),
             Intermission => write!(f, "gfx/inter.lmp"),
+            Ranking => write!(f, "gfx/ranking.lmp"),
+            Finale => write!(f, "gfx/finale.lmp"),
+            Loading => write!(f, "gfx/loading.lmp"),
         }
     }
 }
@@ -189,8 +294,30 @@ impl std::fmt::Display for FaceId {
     }
 }
 
+// weapon icons flash for a second after the weapon is picked up
+const WEAPON_FLASH_DURATION_MS: i64 = 1000;
+const WEAPON_FLASH_FRAME_MS: i64 = 100;
+
+fn weapon_frame(time: Duration, pickup_time: Duration, active: bool) -> WeaponFrame {
+    let delta = (time - pickup_time).num_milliseconds();
+    if delta >= WEAPON_FLASH_DURATION_MS {
+        if active {
+            WeaponFrame::Active
+        } else {
+            WeaponFrame::Inactive
+        }
+    } else {
+        WeaponFrame::Pickup {
+            frame: (delta.max(0) / WEAPON_FLASH_FRAME_MS) as usize % 5,
+        }
+    }
+}
+
 pub struct HudRenderer {
     textures: HashMap<HudTextureId, QuadTexture>,
+
+    // solid bars in each player color, drawn behind the scoreboard frags
+    player_colors: Vec<QuadTexture>,
 }
 
 impl HudRenderer {
@@ -256,14 +383,32 @@ impl HudRenderer {
         }
 
         // new id list for textures not in gfx.wad
-        let ids = vec![Complete, Intermission];
+        let ids = vec![Complete, Intermission, Ranking, Finale, Loading];
         for id in ids.into_iter() {
             debug!("Opening {}", id);
             let qpic = QPic::load(state.vfs().open(&format!("{}", id)).unwrap()).unwrap();
             textures.insert(id, QuadTexture::from_qpic(state, &qpic));
         }
 
-        HudRenderer { textures }
+        let player_colors = (0..16)
+            .map(|color| {
+                let texture =
+                    QuadTexture::new(state, SCOREBOARD_COLOR_WIDTH, SCOREBOARD_COLOR_HEIGHT);
+                let mut rgba = Vec::new();
+                for _ in 0..SCOREBOARD_COLOR_WIDTH * SCOREBOARD_COLOR_HEIGHT {
+                    // use the middle shade of the color's palette row
+                    rgba.extend_from_slice(&state.palette().rgb(color * 16 + 8));
+                    rgba.push(0xFF);
+                }
+                texture.write(state, &rgba);
+                texture
+            })
+            .collect();
+
+        HudRenderer {
+            textures,
+            player_colors,
+        }
     }
 
     fn cmd_number<'a>(
@@ -372,6 +517,7 @@ impl HudRenderer {
     // Draw the status bar.
     fn cmd_sbar<'a>(
         &'a self,
+        sbar_lines: SbarLines,
         time: Duration,
         items: ItemFlags,
         item_pickup_time: &'a [Duration],
@@ -383,12 +529,111 @@ impl HudRenderer {
     ) {
         use HudTextureId::*;
 
-        let sbar = self.textures.get(&StatusBar).unwrap();
-        let sbar_x_ofs = -(sbar.width() as i32) / 2;
+        // crosshair
+        glyph_cmds.push(GlyphRendererCommand::Glyph {
+            glyph_id: '+' as u8,
+            position: ScreenPosition::Absolute(Anchor::CENTER),
+            anchor: Anchor::TOP_LEFT,
+            scale,
+        });
+
+        if sbar_lines == SbarLines::Hidden {
+            return;
+        }
 
         // status bar background
         self.cmd_sbar_quad(StatusBar, 0, 0, scale, quad_cmds);
 
+        if sbar_lines == SbarLines::Full {
+            self.cmd_inventory(
+                time,
+                items,
+                item_pickup_time,
+                stats,
+                scale,
+                quad_cmds,
+                glyph_cmds,
+            );
+        }
+
+        // armor
+        let armor_width = self.textures.get(&Armor { id: 0 }).unwrap().width() as i32;
+        if items.contains(ItemFlags::INVULNERABILITY) {
+            self.cmd_sbar_number(666, true, 3, armor_width, 0, scale, quad_cmds);
+        // TODO draw_disc
+        } else {
+            let armor = stats[ClientStat::Armor as usize];
+            self.cmd_sbar_number(armor, armor <= 25, 3, armor_width, 0, scale, quad_cmds);
+
+            let mut armor_id = None;
+            for i in (0..3).rev() {
+                if items.contains(ItemFlags::from_bits(ItemFlags::ARMOR_1.bits() << i).unwrap()) {
+                    armor_id = Some(Armor { id: i });
+                    break;
+                }
+            }
+
+            if let Some(a) = armor_id {
+                self.cmd_sbar_quad(a, 0, 0, scale, quad_cmds);
+            }
+        }
+
+        // health
+        let health = stats[ClientStat::Health as usize];
+        self.cmd_sbar_number(health, health <= 25, 3, 136, 0, scale, quad_cmds);
+
+        // icon for the type of ammo the current weapon uses
+        let ammo_id = AmmoId::iter().find(|id| {
+            items.contains(ItemFlags::from_bits(ItemFlags::SHELLS.bits() << *id as u32).unwrap())
+        });
+        if let Some(id) = ammo_id {
+            self.cmd_sbar_quad(Ammo { id }, 224, 0, scale, quad_cmds);
+        }
+
+        let ammo = stats[ClientStat::Ammo as usize];
+        self.cmd_sbar_number(ammo, ammo <= 10, 3, 248, 0, scale, quad_cmds);
+
+        let face = if items.contains(ItemFlags::INVISIBILITY | ItemFlags::INVULNERABILITY) {
+            FaceId::InvisibleInvulnerable
+        } else if items.contains(ItemFlags::QUAD) {
+            FaceId::QuadDamage
+        } else if items.contains(ItemFlags::INVISIBILITY) {
+            FaceId::Invisible
+        } else if items.contains(ItemFlags::INVULNERABILITY) {
+            FaceId::Invulnerable
+        } else {
+            let health = stats[ClientStat::Health as usize];
+            let frame = 4 - if health >= 100 {
+                4
+            } else {
+                health.max(0) as usize / 20
+            };
+
+            FaceId::Normal {
+                pain: face_anim_time > time,
+                frame,
+            }
+        };
+
+        self.cmd_sbar_quad(Face { id: face }, 112, 0, scale, quad_cmds);
+    }
+
+    // Draw the inventory bar above the status bar.
+    fn cmd_inventory<'a>(
+        &'a self,
+        time: Duration,
+        items: ItemFlags,
+        item_pickup_time: &'a [Duration],
+        stats: &'a [i32],
+        scale: f32,
+        quad_cmds: &mut Vec<QuadRendererCommand<'a>>,
+        glyph_cmds: &mut Vec<GlyphRendererCommand>,
+    ) {
+        use HudTextureId::*;
+
+        let sbar = self.textures.get(&StatusBar).unwrap();
+        let sbar_x_ofs = -(sbar.width() as i32) / 2;
+
         // inventory bar background
         self.cmd_sbar_quad(InvBar, 0, sbar.height() as i32, scale, quad_cmds);
 
@@ -396,21 +641,9 @@ impl HudRenderer {
         for i in 0..7 {
             if items.contains(ItemFlags::from_bits(ItemFlags::SHOTGUN.bits() << i).unwrap()) {
                 let id = WeaponId::from_usize(i).unwrap();
-                let pickup_time = item_pickup_time[i];
-                let delta = time - pickup_time;
-                let frame = if delta >= Duration::milliseconds(100) {
-                    if stats[ClientStat::ActiveWeapon as usize] as u32
-                        == ItemFlags::SHOTGUN.bits() << i
-                    {
-                        WeaponFrame::Active
-                    } else {
-                        WeaponFrame::Inactive
-                    }
-                } else {
-                    WeaponFrame::Pickup {
-                        frame: (delta.num_milliseconds() * 100) as usize % 5,
-                    }
-                };
+                let active = stats[ClientStat::ActiveWeapon as usize] as u32
+                    == ItemFlags::SHOTGUN.bits() << i;
+                let frame = weapon_frame(time, item_pickup_time[i], active);
 
                 self.cmd_sbar_quad(
                     Weapon { id, frame },
@@ -481,67 +714,6 @@ impl HudRenderer {
                 });
             }
         }
-
-        // armor
-        let armor_width = self.textures.get(&Armor { id: 0 }).unwrap().width() as i32;
-        if items.contains(ItemFlags::INVULNERABILITY) {
-            self.cmd_sbar_number(666, true, 3, armor_width, 0, scale, quad_cmds);
-        // TODO draw_disc
-        } else {
-            let armor = stats[ClientStat::Armor as usize];
-            self.cmd_sbar_number(armor, armor <= 25, 3, armor_width, 0, scale, quad_cmds);
-
-            let mut armor_id = None;
-            for i in (0..3).rev() {
-                if items.contains(ItemFlags::from_bits(ItemFlags::ARMOR_1.bits() << i).unwrap()) {
-                    armor_id = Some(Armor { id: i });
-                    break;
-                }
-            }
-
-            if let Some(a) = armor_id {
-                self.cmd_sbar_quad(a, 0, 0, scale, quad_cmds);
-            }
-        }
-
-        // health
-        let health = stats[ClientStat::Health as usize];
-        self.cmd_sbar_number(health, health <= 25, 3, 136, 0, scale, quad_cmds);
-
-        let ammo = stats[ClientStat::Ammo as usize];
-        self.cmd_sbar_number(ammo, ammo <= 10, 3, 248, 0, scale, quad_cmds);
-
-        let face = if items.contains(ItemFlags::INVISIBILITY | ItemFlags::INVULNERABILITY) {
-            FaceId::InvisibleInvulnerable
-        } else if items.contains(ItemFlags::QUAD) {
-            FaceId::QuadDamage
-        } else if items.contains(ItemFlags::INVISIBILITY) {
-            FaceId::Invisible
-        } else if items.contains(ItemFlags::INVULNERABILITY) {
-            FaceId::Invulnerable
-        } else {
-            let health = stats[ClientStat::Health as usize];
-            let frame = 4 - if health >= 100 {
-                4
-            } else {
-                health.max(0) as usize / 20
-            };
-
-            FaceId::Normal {
-                pain: face_anim_time > time,
-                frame,
-            }
-        };
-
-        self.cmd_sbar_quad(Face { id: face }, 112, 0, scale, quad_cmds);
-
-        // crosshair
-        glyph_cmds.push(GlyphRendererCommand::Glyph {
-            glyph_id: '+' as u8,
-            position: ScreenPosition::Absolute(Anchor::CENTER),
-            anchor: Anchor::TOP_LEFT,
-            scale,
-        });
     }
 
     // Draw a quad on the intermission overlay.
@@ -612,13 +784,24 @@ impl HudRenderer {
         self.cmd_intermission_quad(Complete, 64, OVERLAY_HEIGHT - 24, scale, quad_cmds);
         self.cmd_intermission_quad(Intermission, 0, OVERLAY_HEIGHT - 56, scale, quad_cmds);
 
-        // TODO: zero-pad number of seconds
+        // seconds are always drawn with two digits
         let time_y_ofs = OVERLAY_HEIGHT - 64;
         let minutes = completion_duration.num_minutes() as i32;
-        let seconds = completion_duration.num_seconds() as i32 - 60 * minutes;
+        let seconds = (completion_duration.num_seconds() as i32 - 60 * minutes) as usize;
         self.cmd_intermission_number(minutes, 3, 160, time_y_ofs, scale, quad_cmds);
         self.cmd_intermission_quad(Colon, 234, time_y_ofs, scale, quad_cmds);
-        self.cmd_intermission_number(seconds, 2, 246, time_y_ofs, scale, quad_cmds);
+        for (i, digit) in [seconds / 10, seconds % 10].iter().enumerate() {
+            self.cmd_intermission_quad(
+                Digit {
+                    alt: false,
+                    value: *digit,
+                },
+                246 + 20 * i as i32,
+                time_y_ofs,
+                scale,
+                quad_cmds,
+            );
+        }
 
         // secrets
         let secrets_y_ofs = OVERLAY_HEIGHT - 104;
@@ -637,27 +820,318 @@ impl HudRenderer {
         self.cmd_intermission_number(monsters_total, 3, 240, monsters_y_ofs, scale, quad_cmds);
     }
 
+    // Draw the scoreboard.
+    fn cmd_scoreboard<'a>(
+        &'a self,
+        entries: &[ScoreboardEntry],
+        time: Duration,
+        scale: f32,
+        quad_cmds: &mut Vec<QuadRendererCommand<'a>>,
+        glyph_cmds: &mut Vec<GlyphRendererCommand>,
+    ) {
+        use HudTextureId::*;
+
+        let ranking_width = self.textures.get(&Ranking).unwrap().width() as i32;
+        self.cmd_intermission_quad(
+            Ranking,
+            (OVERLAY_WIDTH - ranking_width) / 2,
+            OVERLAY_HEIGHT - 8,
+            scale,
+            quad_cmds,
+        );
+
+        let text = |text: String, x_ofs: i32, y_ofs: i32| GlyphRendererCommand::Text {
+            text,
+            position: ScreenPosition::Relative {
+                anchor: OVERLAY_ANCHOR,
+                x_ofs: OVERLAY_X_OFS + x_ofs,
+                y_ofs: OVERLAY_Y_OFS + y_ofs,
+            },
+            anchor: Anchor::TOP_LEFT,
+            scale,
+        };
+
+        for (row, entry) in entries.iter().take(SCOREBOARD_MAX_ROWS).enumerate() {
+            let y_ofs = OVERLAY_HEIGHT - SCOREBOARD_Y - SCOREBOARD_ROW_HEIGHT * row as i32;
+
+            // shirt color on top, pants color below
+            let colors = [entry.colors.top(), entry.colors.bottom()];
+            for (i, color) in colors.iter().enumerate() {
+                quad_cmds.push(QuadRendererCommand {
+                    texture: &self.player_colors[*color as usize & 0xF],
+                    layout: Layout {
+                        position: ScreenPosition::Relative {
+                            anchor: OVERLAY_ANCHOR,
+                            x_ofs: OVERLAY_X_OFS + SCOREBOARD_COLOR_X,
+                            y_ofs: OVERLAY_Y_OFS + y_ofs
+                                - SCOREBOARD_COLOR_HEIGHT as i32 * i as i32,
+                        },
+                        anchor: Anchor::TOP_LEFT,
+                        size: Size::Scale { factor: scale },
+                    },
+                });
+            }
+
+            let minutes = (time - entry.join_time).num_minutes().max(0);
+            glyph_cmds.push(text(format!("{:>3}", minutes), SCOREBOARD_TIME_X, y_ofs));
+            glyph_cmds.push(text(
+                format!("{:>3}", entry.frags),
+                SCOREBOARD_FRAGS_X,
+                y_ofs,
+            ));
+            glyph_cmds.push(text(entry.name.clone(), SCOREBOARD_NAME_X, y_ofs));
+        }
+    }
+
+    // Draw the coop party below the level stats on the intermission overlay.
+    //
+    // The level stats count every player's kills and secrets, so this is just
+    // a list of who was there.
+    fn cmd_party<'a>(
+        &'a self,
+        entries: &[ScoreboardEntry],
+        scale: f32,
+        quad_cmds: &mut Vec<QuadRendererCommand<'a>>,
+        glyph_cmds: &mut Vec<GlyphRendererCommand>,
+    ) {
+        for (row, entry) in entries.iter().take(PARTY_MAX_ROWS).enumerate() {
+            let y_ofs = PARTY_Y - PARTY_ROW_HEIGHT * row as i32;
+
+            let colors = [entry.colors.top(), entry.colors.bottom()];
+            for (i, color) in colors.iter().enumerate() {
+                quad_cmds.push(QuadRendererCommand {
+                    texture: &self.player_colors[*color as usize & 0xF],
+                    layout: Layout {
+                        position: ScreenPosition::Relative {
+                            anchor: OVERLAY_ANCHOR,
+                            x_ofs: OVERLAY_X_OFS + SCOREBOARD_COLOR_X,
+                            y_ofs: OVERLAY_Y_OFS + y_ofs
+                                - SCOREBOARD_COLOR_HEIGHT as i32 * i as i32,
+                        },
+                        anchor: Anchor::TOP_LEFT,
+                        size: Size::Scale { factor: scale },
+                    },
+                });
+            }
+
+            glyph_cmds.push(GlyphRendererCommand::Text {
+                text: entry.name.clone(),
+                position: ScreenPosition::Relative {
+                    anchor: OVERLAY_ANCHOR,
+                    x_ofs: OVERLAY_X_OFS + SCOREBOARD_NAME_X,
+                    y_ofs: OVERLAY_Y_OFS + y_ofs,
+                },
+                anchor: Anchor::TOP_LEFT,
+                scale,
+            });
+        }
+    }
+
+    // Draw teammate markers and the party HUD.
+    fn cmd_teammates(
+        &self,
+        teammates: &[Teammate],
+        scale: f32,
+        glyph_cmds: &mut Vec<GlyphRendererCommand>,
+    ) {
+        for (row, teammate) in teammates.iter().enumerate() {
+            let distance = match teammate.marker {
+                Some(ref m) => format!("{:>5}", m.distance as i32),
+                None => "  ---".to_owned(),
+            };
+            glyph_cmds.push(GlyphRendererCommand::Text {
+                text: format!("{} {}", teammate.name, distance),
+                position: ScreenPosition::Relative {
+                    anchor: Anchor::TOP_RIGHT,
+                    x_ofs: 0,
+                    y_ofs: -PARTY_HUD_ROW_HEIGHT * row as i32,
+                },
+                anchor: Anchor::TOP_RIGHT,
+                scale,
+            });
+
+            let marker = match teammate.marker {
+                Some(ref m) => m,
+                None => continue,
+            };
+
+            // map normalized device coordinates to a proportion of the screen
+            let screen_anchor = |x: f32, y: f32| Anchor {
+                x: AnchorCoord::Proportion((x + 1.0) / 2.0),
+                y: AnchorCoord::Proportion((y + 1.0) / 2.0),
+            };
+
+            let (text, position, anchor) = match marker.position {
+                MarkerPosition::OnScreen { x, y } => (
+                    teammate.name.clone(),
+                    screen_anchor(x, y),
+                    Anchor::BOTTOM_CENTER,
+                ),
+
+                // point toward the teammate from the nearest edge, keeping the
+                // name on screen
+                MarkerPosition::OffScreen { x, y } => {
+                    let (text, anchor) = if x.abs() >= y.abs() {
+                        match x > 0.0 {
+                            true => (format!("{} >", teammate.name), Anchor::CENTER_RIGHT),
+                            false => (format!("< {}", teammate.name), Anchor::CENTER_LEFT),
+                        }
+                    } else {
+                        match y > 0.0 {
+                            true => (format!("^ {}", teammate.name), Anchor::TOP_CENTER),
+                            false => (format!("v {}", teammate.name), Anchor::BOTTOM_CENTER),
+                        }
+                    };
+
+                    (text, screen_anchor(x, y), anchor)
+                }
+            };
+
+            glyph_cmds.push(GlyphRendererCommand::Text {
+                text,
+                position: ScreenPosition::Relative {
+                    anchor: position,
+                    x_ofs: 0,
+                    y_ofs: 0,
+                },
+                anchor,
+                scale,
+            });
+        }
+    }
+
+    // Draw the loading plaque and a progress bar below it.
+    fn cmd_loading<'a>(
+        &'a self,
+        progress: Option<LoadProgress>,
+        download: Option<(usize, usize)>,
+        scale: f32,
+        quad_cmds: &mut Vec<QuadRendererCommand<'a>>,
+        glyph_cmds: &mut Vec<GlyphRendererCommand>,
+    ) {
+        let plaque = self.textures.get(&HudTextureId::Loading).unwrap();
+        let (width, height) = (plaque.width() as i32, plaque.height() as i32);
+        self.cmd_intermission_quad(
+            HudTextureId::Loading,
+            (OVERLAY_WIDTH - width) / 2,
+            (OVERLAY_HEIGHT + height) / 2,
+            scale,
+            quad_cmds,
+        );
+
+        let (text, fraction) = match (download, progress) {
+            (Some((done, total)), _) => (
+                format!("downloading {}/{}", done, total),
+                done as f32 / total.max(1) as f32,
+            ),
+            (None, Some(p)) => (
+                format!(
+                    "models {}/{}  sounds {}/{}",
+                    p.models_loaded, p.models_total, p.sounds_loaded, p.sounds_total
+                ),
+                p.fraction(),
+            ),
+            (None, None) => return,
+        };
+
+        let text_y = (OVERLAY_HEIGHT - height) / 2 - 8;
+        glyph_cmds.push(GlyphRendererCommand::Text {
+            text,
+            position: ScreenPosition::Relative {
+                anchor: OVERLAY_ANCHOR,
+                x_ofs: 0,
+                y_ofs: OVERLAY_Y_OFS + text_y,
+            },
+            anchor: Anchor::TOP_CENTER,
+            scale,
+        });
+
+        let bar_x = (OVERLAY_WIDTH - 8 * (LOADING_BAR_WIDTH + 2)) / 2;
+        let bar_y = text_y - 12;
+        let glyph = |glyph_id: u8, x_ofs: i32| GlyphRendererCommand::Glyph {
+            glyph_id,
+            position: ScreenPosition::Relative {
+                anchor: OVERLAY_ANCHOR,
+                x_ofs: OVERLAY_X_OFS + x_ofs,
+                y_ofs: OVERLAY_Y_OFS + bar_y,
+            },
+            anchor: Anchor::TOP_LEFT,
+            scale,
+        };
+
+        glyph_cmds.push(glyph(LOADING_BAR_LEFT, bar_x));
+        for i in 0..LOADING_BAR_WIDTH {
+            glyph_cmds.push(glyph(LOADING_BAR_MIDDLE, bar_x + 8 * (i + 1)));
+        }
+        glyph_cmds.push(glyph(
+            LOADING_BAR_RIGHT,
+            bar_x + 8 * (LOADING_BAR_WIDTH + 1),
+        ));
+
+        let handle_x = bar_x + 8 + ((8 * (LOADING_BAR_WIDTH - 1)) as f32 * fraction) as i32;
+        glyph_cmds.push(glyph(LOADING_BAR_HANDLE, handle_x));
+    }
+
+    // Draw the center print, one centered line at a time.
+    fn cmd_center_print(
+        &self,
+        lines: &[&str],
+        scale: f32,
+        glyph_cmds: &mut Vec<GlyphRendererCommand>,
+    ) {
+        let top_y = match lines.len() {
+            l if l <= CENTER_PRINT_MAX_SHORT_LINES => CENTER_PRINT_Y,
+            _ => CENTER_PRINT_LONG_Y,
+        };
+
+        for (id, line) in lines.iter().enumerate() {
+            glyph_cmds.push(GlyphRendererCommand::Text {
+                text: (*line).to_owned(),
+                position: ScreenPosition::Relative {
+                    anchor: OVERLAY_ANCHOR,
+                    x_ofs: 0,
+                    y_ofs: OVERLAY_Y_OFS + OVERLAY_HEIGHT
+                        - top_y
+                        - CENTER_PRINT_LINE_HEIGHT * id as i32,
+                },
+                anchor: Anchor::TOP_CENTER,
+                scale,
+            });
+        }
+    }
+
     /// Generate render commands to draw the HUD in the specified state.
     pub fn generate_commands<'state, 'a>(
         &'a self,
         hud_state: &HudState<'a>,
         time: Duration,
+        scale: f32,
         quad_cmds: &mut Vec<QuadRendererCommand<'a>>,
         glyph_cmds: &mut Vec<GlyphRendererCommand>,
     ) {
-        // TODO: get from cvar
-        let scale = 2.0;
         let console_timeout = Duration::seconds(3);
 
         match hud_state {
+            HudState::Loading { progress, download } => {
+                self.cmd_loading(*progress, *download, scale, quad_cmds, glyph_cmds);
+            }
+
             HudState::InGame {
+                sbar_lines,
                 items,
                 item_pickup_time,
                 stats,
                 face_anim_time,
                 console,
+                captions,
+                chat,
+                chat_input,
+                center_print,
+                scoreboard,
+                teammates,
             } => {
                 self.cmd_sbar(
+                    *sbar_lines,
                     time,
                     *items,
                     item_pickup_time,
@@ -668,6 +1142,15 @@ impl HudRenderer {
                     glyph_cmds,
                 );
 
+                match scoreboard {
+                    Some(entries) => {
+                        self.cmd_scoreboard(entries, time, scale, quad_cmds, glyph_cmds)
+                    }
+                    None => self.cmd_teammates(teammates, scale, glyph_cmds),
+                }
+
+                self.cmd_center_print(center_print, scale, glyph_cmds);
+
                 let output = console.output();
                 for (id, line) in output.recent_lines(console_timeout, 100, 10).enumerate() {
                     for (chr_id, chr) in line.into_iter().enumerate() {
@@ -683,14 +1166,84 @@ impl HudRenderer {
                         });
                     }
                 }
+
+                // chat goes below the console notifications, followed by the
+                // message being typed
+                let notify_lines = output.recent_lines(console_timeout, 100, 10).count();
+                let prompt = chat_input.map(|input| {
+                    let mode = if input.team() { "say_team" } else { "say" };
+                    format!("{}: {}_", mode, input.text())
+                });
+                let chat_lines = chat.active().map(str::to_owned).chain(prompt);
+                for (id, text) in chat_lines.enumerate() {
+                    glyph_cmds.push(GlyphRendererCommand::Text {
+                        text,
+                        position: ScreenPosition::Relative {
+                            anchor: Anchor::TOP_LEFT,
+                            x_ofs: 0,
+                            y_ofs: -8 * (notify_lines + id) as i32,
+                        },
+                        anchor: Anchor::TOP_LEFT,
+                        scale,
+                    });
+                }
+
+                // newest caption goes on the bottom
+                for (id, caption) in captions.active().rev().enumerate() {
+                    glyph_cmds.push(GlyphRendererCommand::Text {
+                        text: caption.to_owned(),
+                        position: ScreenPosition::Relative {
+                            anchor: Anchor::BOTTOM_CENTER,
+                            x_ofs: 0,
+                            y_ofs: CAPTION_Y_OFS + CAPTION_LINE_HEIGHT * id as i32,
+                        },
+                        anchor: Anchor::BOTTOM_CENTER,
+                        scale,
+                    });
+                }
             }
             HudState::Intermission {
                 kind,
                 completion_duration,
                 stats,
                 console,
+                center_print,
+                scoreboard,
+                party,
             } => {
-                self.cmd_intermission_overlay(kind, *completion_duration, stats, scale, quad_cmds);
+                match (kind, scoreboard) {
+                    (IntermissionKind::Intermission, Some(entries)) => {
+                        self.cmd_scoreboard(entries, time, scale, quad_cmds, glyph_cmds)
+                    }
+                    (IntermissionKind::Intermission, None) => {
+                        self.cmd_intermission_overlay(
+                            kind,
+                            *completion_duration,
+                            stats,
+                            scale,
+                            quad_cmds,
+                        );
+                        self.cmd_party(party, scale, quad_cmds, glyph_cmds);
+                    }
+
+                    // the finale text itself is drawn as a center print
+                    (IntermissionKind::Finale { .. }, _) => {
+                        let finale_width =
+                            self.textures.get(&HudTextureId::Finale).unwrap().width() as i32;
+                        self.cmd_intermission_quad(
+                            HudTextureId::Finale,
+                            (OVERLAY_WIDTH - finale_width) / 2,
+                            OVERLAY_HEIGHT - 16,
+                            scale,
+                            quad_cmds,
+                        );
+                    }
+
+                    // cutscenes show nothing but the text
+                    (IntermissionKind::Cutscene { .. }, _) => (),
+                }
+
+                self.cmd_center_print(center_print, scale, glyph_cmds);
 
                 // TODO: dedup this code
                 let output = console.output();
@@ -712,3 +1265,36 @@ impl HudRenderer {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sbar_lines_from_viewsize() {
+        assert_eq!(SbarLines::from_viewsize(100.0), SbarLines::Full);
+        assert_eq!(SbarLines::from_viewsize(110.0), SbarLines::StatusOnly);
+        assert_eq!(SbarLines::from_viewsize(120.0), SbarLines::Hidden);
+    }
+
+    #[test]
+    fn test_weapon_frame() {
+        let pickup = Duration::seconds(10);
+        let at = |ms| pickup + Duration::milliseconds(ms);
+
+        assert_eq!(
+            weapon_frame(at(0), pickup, true),
+            WeaponFrame::Pickup { frame: 0 }
+        );
+        assert_eq!(
+            weapon_frame(at(250), pickup, true),
+            WeaponFrame::Pickup { frame: 2 }
+        );
+        assert_eq!(
+            weapon_frame(at(650), pickup, false),
+            WeaponFrame::Pickup { frame: 1 }
+        );
+        assert_eq!(weapon_frame(at(1000), pickup, true), WeaponFrame::Active);
+        assert_eq!(weapon_frame(at(1000), pickup, false), WeaponFrame::Inactive);
+    }
+}
@@ -2,6 +2,7 @@ use std::collections::HashMap;
 
 use crate::{
     client::{
+        browser::{PingClass, ServerBrowser, SortColumn},
         menu::{Item, Menu, MenuBodyView, MenuState, NamedMenuItem},
         render::{
             ui::{
@@ -27,6 +28,42 @@ const SLIDER_RIGHT: u8 = 130;
 const SLIDER_HANDLE: u8 = 131;
 const SLIDER_WIDTH: i32 = 10;
 
+const CURSOR_GLYPH: u8 = 11;
+
+// the '0' of the gold digits used for fair pings
+const GOLD_DIGIT_0: u8 = 18;
+
+// server list layout, in glyphs from the left edge of the list
+const SERVER_LIST_X: i32 = 8;
+const SERVER_LIST_ROWS: usize = 12;
+const COLUMN_NAME: (usize, usize) = (1, 15);
+const COLUMN_MAP: (usize, usize) = (17, 8);
+const COLUMN_PLAYERS: (usize, usize) = (26, 5);
+const COLUMN_PING: (usize, usize) = (32, 4);
+
+// draw text in the alternate (bronze) half of the character set
+fn alt_text(text: &str) -> String {
+    text.bytes().map(|b| char::from(b | 0x80)).collect()
+}
+
+fn ping_text(ping_ms: i64, class: PingClass) -> String {
+    let digits = format!("{}", ping_ms.min(9999));
+    match class {
+        PingClass::Good => digits,
+        PingClass::Fair => digits
+            .bytes()
+            .map(|b| char::from(GOLD_DIGIT_0 + (b - b'0')))
+            .collect(),
+        PingClass::Poor => alt_text(&digits),
+        PingClass::Unknown => "---".to_string(),
+    }
+}
+
+// the first row shown when `selected` is selected
+fn first_visible_row(selected: usize) -> usize {
+    selected.saturating_sub(SERVER_LIST_ROWS - 1)
+}
+
 #[derive(Clone, Copy, Debug)]
 enum Align {
     Left,
@@ -244,6 +281,71 @@ impl MenuRenderer {
         self.cmd_draw_glyph(SLIDER_HANDLE, handle_x, y, scale, glyph_cmds);
     }
 
+    fn cmd_draw_server_list(
+        &self,
+        browser: &ServerBrowser,
+        y: i32,
+        scale: f32,
+        glyph_cmds: &mut Vec<GlyphRendererCommand>,
+    ) {
+        let mut draw = |(column, width): (usize, usize), y: i32, text: String| {
+            let text: String = text.chars().take(width).collect();
+            self.cmd_draw_item_text(
+                SERVER_LIST_X + (column * GLYPH_WIDTH) as i32,
+                y,
+                text,
+                scale,
+                glyph_cmds,
+            );
+        };
+
+        // the sorted column is highlighted
+        for (column, sort) in [COLUMN_NAME, COLUMN_MAP, COLUMN_PLAYERS, COLUMN_PING]
+            .iter()
+            .zip(SortColumn::ALL.iter())
+        {
+            let title = match *sort == browser.sort_column() {
+                true => alt_text(sort.name()),
+                false => sort.name().to_string(),
+            };
+            draw(*column, y, title);
+        }
+
+        let rows = browser.rows();
+        if rows.is_empty() {
+            draw(
+                COLUMN_NAME,
+                y - GLYPH_HEIGHT as i32,
+                "no servers".to_string(),
+            );
+            return;
+        }
+
+        let first = first_visible_row(browser.selected());
+        for (i, entry) in rows.iter().skip(first).take(SERVER_LIST_ROWS).enumerate() {
+            let row_y = y - (GLYPH_HEIGHT * (i + 1)) as i32;
+
+            if browser.is_favorite(entry.address) {
+                draw((0, 1), row_y, "*".to_string());
+            }
+
+            draw(COLUMN_NAME, row_y, entry.name());
+            draw(COLUMN_MAP, row_y, entry.map().to_string());
+
+            if entry.status.is_some() {
+                let (clients, max_clients) = entry.players();
+                draw(
+                    COLUMN_PLAYERS,
+                    row_y,
+                    format!("{}/{}", clients, max_clients),
+                );
+            }
+
+            let ping_ms = entry.ping.map(|p| p.num_milliseconds()).unwrap_or(0);
+            draw(COLUMN_PING, row_y, ping_text(ping_ms, entry.ping_class()));
+        }
+    }
+
     fn cmd_draw_body_dynamic(
         &self,
         items: &[NamedMenuItem],
@@ -255,6 +357,13 @@ impl MenuRenderer {
         for (item_id, item) in items.iter().enumerate() {
             let y = MENU_HEIGHT - 32 - (GLYPH_HEIGHT * item_id) as i32;
             let x = 16 + 24 * GLYPH_WIDTH as i32;
+
+            // the server list draws its own column titles in place of a name
+            if let Item::ServerList(list) = item.item() {
+                self.cmd_draw_server_list(&list.browser().borrow(), y, scale, glyph_cmds);
+                continue;
+            }
+
             self.cmd_draw_item_name(x, y, item.name(), scale, glyph_cmds);
 
             match item.item() {
@@ -271,19 +380,55 @@ impl MenuRenderer {
                 Item::Slider(slider) => {
                     self.cmd_draw_slider(x, y, slider.position(), scale, glyph_cmds)
                 }
-                Item::TextField(_) => (),
+                Item::TextField(text) => {
+                    self.cmd_draw_item_text(x, y, text.text(), scale, glyph_cmds);
+
+                    // blinking cursor
+                    if time.num_milliseconds() / 250 % 2 == 0 {
+                        self.cmd_draw_glyph(
+                            CURSOR_GLYPH,
+                            x + GLYPH_WIDTH as i32 * (text.cursor() as i32 + 1),
+                            y,
+                            scale,
+                            glyph_cmds,
+                        );
+                    }
+                }
+                Item::Binding(binding) => {
+                    let keys = match (binding.listening(), binding.keys()) {
+                        (true, _) => "press a key".to_string(),
+                        (false, ref k) if k.is_empty() => "???".to_string(),
+                        // keys that start other actions too are highlighted
+                        (false, k) => k
+                            .iter()
+                            .map(|k| {
+                                if binding.is_conflict(k) {
+                                    alt_text(k)
+                                } else {
+                                    k.clone()
+                                }
+                            })
+                            .collect::<Vec<_>>()
+                            .join(" or "),
+                    };
+                    self.cmd_draw_item_text(x, y, keys, scale, glyph_cmds);
+                }
                 _ => (),
             }
         }
 
+        // in the server list, the cursor points at the selected row
+        let (cursor_x, cursor_y) = match items.get(cursor_pos).map(|i| i.item()) {
+            Some(Item::ServerList(list)) => {
+                let selected = list.browser().borrow().selected();
+                let row = selected - first_visible_row(selected) + 1;
+                (0, MENU_HEIGHT - 32 - 8 * (cursor_pos + row) as i32)
+            }
+            _ => (200, MENU_HEIGHT - 32 - 8 * cursor_pos as i32),
+        };
+
         if time.num_milliseconds() / 250 % 2 == 0 {
-            self.cmd_draw_glyph(
-                141,
-                200,
-                MENU_HEIGHT - 32 - 8 * cursor_pos as i32,
-                scale,
-                glyph_cmds,
-            );
+            self.cmd_draw_glyph(141, cursor_x, cursor_y, scale, glyph_cmds);
         }
     }
 
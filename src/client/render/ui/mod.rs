@@ -94,6 +94,9 @@ pub struct UiRenderer {
     hud_renderer: HudRenderer,
     glyph_renderer: GlyphRenderer,
     quad_renderer: QuadRenderer,
+
+    // the last font requested with set_font, which may have failed to load
+    requested_font: String,
 }
 
 impl UiRenderer {
@@ -104,6 +107,34 @@ impl UiRenderer {
             hud_renderer: HudRenderer::new(state),
             glyph_renderer: GlyphRenderer::new(state),
             quad_renderer: QuadRenderer::new(state),
+            requested_font: String::new(),
+        }
+    }
+
+    /// Switch to the replacement font `name`, or back to `conchars` if `name`
+    /// is empty.
+    ///
+    /// If the font can't be loaded, `conchars` is used instead. Loading isn't
+    /// retried until a different font is requested.
+    pub fn set_font(&mut self, state: &GraphicsState, name: &str) {
+        if name == self.requested_font {
+            return;
+        }
+        self.requested_font = name.to_owned();
+
+        if name.is_empty() {
+            self.glyph_renderer = GlyphRenderer::new(state);
+            return;
+        }
+
+        match GlyphRenderer::with_font(state, name) {
+            Ok(g) => self.glyph_renderer = g,
+            Err(e) => {
+                warn!("Couldn't load font {}: {}", name, e);
+                if !self.glyph_renderer.font().is_empty() {
+                    self.glyph_renderer = GlyphRenderer::new(state);
+                }
+            }
         }
     }
 
@@ -114,6 +145,8 @@ impl UiRenderer {
         target_size: Extent2d,
         time: Duration,
         ui_state: &UiState<'pass>,
+        hud_scale: f32,
+        console_scale: f32,
         quad_commands: &'pass mut Vec<QuadRendererCommand<'pass>>,
         glyph_commands: &'pass mut Vec<GlyphRendererCommand>,
     ) {
@@ -123,8 +156,13 @@ impl UiRenderer {
         };
 
         if let Some(hstate) = hud_state {
-            self.hud_renderer
-                .generate_commands(hstate, time, quad_commands, glyph_commands);
+            self.hud_renderer.generate_commands(
+                hstate,
+                time,
+                hud_scale,
+                quad_commands,
+                glyph_commands,
+            );
         }
 
         if let Some(o) = overlay {
@@ -146,6 +184,7 @@ impl UiRenderer {
                         quad_commands,
                         glyph_commands,
                         proportion,
+                        console_scale,
                     );
                 }
             }
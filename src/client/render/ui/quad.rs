@@ -1,7 +1,8 @@
 use std::{
+    borrow::Cow,
     cell::{Ref, RefCell, RefMut},
     mem::size_of,
-    num::NonZeroU64,
+    num::{NonZeroU32, NonZeroU64},
 };
 
 use crate::{
@@ -11,7 +12,7 @@ use crate::{
             screen_space_vertex_transform,
         },
         uniform::{self, DynamicUniformBuffer, DynamicUniformBufferBlock},
-        Extent2d, GraphicsState, Pipeline, TextureData, DIFFUSE_ATTACHMENT_FORMAT,
+        DiffuseData, Extent2d, GraphicsState, Pipeline, TextureData, DIFFUSE_ATTACHMENT_FORMAT,
     },
     common::{util::any_slice_as_bytes, wad::QPic},
 };
@@ -270,7 +271,6 @@ pub struct QuadUniforms {
 }
 
 pub struct QuadTexture {
-    #[allow(dead_code)]
     texture: wgpu::Texture,
     #[allow(dead_code)]
     texture_view: wgpu::TextureView,
@@ -282,12 +282,25 @@ pub struct QuadTexture {
 impl QuadTexture {
     pub fn from_qpic(state: &GraphicsState, qpic: &QPic) -> QuadTexture {
         let (diffuse_data, _) = state.palette().translate(qpic.indices());
-        let texture = state.create_texture(
-            None,
-            qpic.width(),
-            qpic.height(),
-            &TextureData::Diffuse(diffuse_data),
-        );
+        QuadTexture::from_diffuse(state, qpic.width(), qpic.height(), diffuse_data)
+    }
+
+    /// Create a black texture whose contents will be provided later with `write`.
+    pub fn new(state: &GraphicsState, width: u32, height: u32) -> QuadTexture {
+        let diffuse_data = DiffuseData {
+            rgba: Cow::Owned(vec![0; (width * height * 4) as usize]),
+        };
+        QuadTexture::from_diffuse(state, width, height, diffuse_data)
+    }
+
+    fn from_diffuse(
+        state: &GraphicsState,
+        width: u32,
+        height: u32,
+        diffuse_data: DiffuseData,
+    ) -> QuadTexture {
+        let texture =
+            state.create_texture(None, width, height, &TextureData::Diffuse(diffuse_data));
         let texture_view = texture.create_view(&Default::default());
         let bind_group = state
             .device()
@@ -304,11 +317,34 @@ impl QuadTexture {
             texture,
             texture_view,
             bind_group,
-            width: qpic.width(),
-            height: qpic.height(),
+            width,
+            height,
         }
     }
 
+    /// Replace the contents of the texture with `rgba`, which must match its dimensions.
+    pub fn write(&self, state: &GraphicsState, rgba: &[u8]) {
+        assert_eq!(rgba.len(), (self.width * self.height * 4) as usize);
+        state.queue().write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            rgba,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(self.width * 4),
+                rows_per_image: None,
+            },
+            wgpu::Extent3d {
+                width: self.width,
+                height: self.height,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
     pub fn width(&self) -> u32 {
         self.width
     }
@@ -449,6 +485,7 @@ impl QuadRenderer {
             pass.set_bind_group(1, &cmd.texture.bind_group, &[]);
             pass.set_bind_group(2, &self.transform_bind_group, &[block.offset()]);
             pass.draw(0..6, 0..1);
+            state.stats().count_draw(6, 1);
         }
     }
 }
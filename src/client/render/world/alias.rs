@@ -2,7 +2,7 @@ use std::{mem::size_of, ops::Range};
 
 use crate::{
     client::render::{
-        world::{BindGroupLayoutId, WorldPipelineBase},
+        world::{group_frame, BindGroupLayoutId, WorldPipelineBase},
         GraphicsState, Pipeline, TextureData,
     },
     common::{
@@ -64,20 +64,28 @@ impl AliasPipeline {
 pub struct VertexPushConstants {
     pub transform: Matrix4<f32>,
     pub model_view: Matrix4<f32>,
+
+    /// How far to move from the previous keyframe to the current one, between 0 and 1.
+    pub blend: f32,
 }
 
 lazy_static! {
     static ref VERTEX_ATTRIBUTES: [wgpu::VertexAttribute; 3] =
         wgpu::vertex_attr_array![
-            // frame 0 position
+            // current keyframe position
             0 => Float32x3,
-            // frame 1 position
-            // 1 => Float32x3,
             // normal
             2 => Float32x3,
             // texcoord
             3 => Float32x2,
         ];
+
+    // read from the same buffer at the previous keyframe's offset
+    static ref PREV_VERTEX_ATTRIBUTES: [wgpu::VertexAttribute; 1] =
+        wgpu::vertex_attr_array![
+            // previous keyframe position
+            1 => Float32x3,
+        ];
 }
 
 impl Pipeline for AliasPipeline {
@@ -114,6 +122,17 @@ impl Pipeline for AliasPipeline {
                         },
                         count: None,
                     },
+                    // fullbright texture
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStage::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
                 ],
             },
         ]
@@ -133,11 +152,18 @@ impl Pipeline for AliasPipeline {
 
     // NOTE: if the vertex format is changed, this descriptor must also be changed accordingly.
     fn vertex_buffer_layouts() -> Vec<wgpu::VertexBufferLayout<'static>> {
-        vec![wgpu::VertexBufferLayout {
-            array_stride: size_of::<AliasVertex>() as u64,
-            step_mode: wgpu::InputStepMode::Vertex,
-            attributes: &VERTEX_ATTRIBUTES[..],
-        }]
+        vec![
+            wgpu::VertexBufferLayout {
+                array_stride: size_of::<AliasVertex>() as u64,
+                step_mode: wgpu::InputStepMode::Vertex,
+                attributes: &VERTEX_ATTRIBUTES[..],
+            },
+            wgpu::VertexBufferLayout {
+                array_stride: size_of::<AliasVertex>() as u64,
+                step_mode: wgpu::InputStepMode::Vertex,
+                attributes: &PREV_VERTEX_ATTRIBUTES[..],
+            },
+        ]
     }
 }
 
@@ -173,32 +199,70 @@ impl Keyframe {
                 vertex_ranges,
                 total_duration,
                 durations,
-            } => {
-                let mut time_ms = time.num_milliseconds() % total_duration.num_milliseconds();
+            } => vertex_ranges[group_frame(durations, *total_duration, time)].clone(),
+        }
+    }
+}
 
-                for (frame_id, frame_duration) in durations.iter().enumerate() {
-                    time_ms -= frame_duration.num_milliseconds();
-                    if time_ms <= 0 {
-                        return vertex_ranges[frame_id].clone();
-                    }
-                }
+// a single frame of a skin
+struct Skin {
+    diffuse_texture: wgpu::Texture,
+    fullbright_texture: wgpu::Texture,
+    diffuse_view: wgpu::TextureView,
+    fullbright_view: wgpu::TextureView,
+    bind_group: wgpu::BindGroup,
+}
 
-                unreachable!()
-            }
+impl Skin {
+    fn new(state: &GraphicsState, width: u32, height: u32, indices: &[u8]) -> Skin {
+        let (diffuse_data, fullbright_data) = state.palette.translate(indices);
+        let diffuse_texture = state.create_mipmapped_texture(
+            None,
+            width,
+            height,
+            &TextureData::Diffuse(diffuse_data),
+        );
+        let fullbright_texture = state.create_mipmapped_texture(
+            None,
+            width,
+            height,
+            &TextureData::Fullbright(fullbright_data),
+        );
+        let diffuse_view = diffuse_texture.create_view(&Default::default());
+        let fullbright_view = fullbright_texture.create_view(&Default::default());
+        let bind_group = state
+            .device()
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                // TODO: per-pipeline bind group layout ids
+                layout: &state.alias_pipeline().bind_group_layouts()
+                    [BindGroupLayoutId::PerTexture as usize - 2],
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&diffuse_view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&fullbright_view),
+                    },
+                ],
+            });
+
+        Skin {
+            diffuse_texture,
+            fullbright_texture,
+            diffuse_view,
+            fullbright_view,
+            bind_group,
         }
     }
 }
 
 enum Texture {
-    Static {
-        diffuse_texture: wgpu::Texture,
-        diffuse_view: wgpu::TextureView,
-        bind_group: wgpu::BindGroup,
-    },
+    Static(Skin),
     Animated {
-        diffuse_textures: Vec<wgpu::Texture>,
-        diffuse_views: Vec<wgpu::TextureView>,
-        bind_groups: Vec<wgpu::BindGroup>,
+        skins: Vec<Skin>,
         total_duration: Duration,
         durations: Vec<Duration>,
     },
@@ -207,33 +271,30 @@ enum Texture {
 impl Texture {
     fn animate(&self, time: Duration) -> &wgpu::BindGroup {
         match self {
-            Texture::Static { ref bind_group, .. } => bind_group,
+            Texture::Static(ref skin) => &skin.bind_group,
             Texture::Animated {
-                diffuse_textures,
-                diffuse_views,
-                bind_groups,
+                skins,
                 total_duration,
                 durations,
-            } => {
-                let mut time_ms = time.num_milliseconds() % total_duration.num_milliseconds();
-
-                for (frame_id, frame_duration) in durations.iter().enumerate() {
-                    time_ms -= frame_duration.num_milliseconds();
-                    if time_ms <= 0 {
-                        return &bind_groups[frame_id];
-                    }
-                }
-
-                unreachable!()
-            }
+            } => &skins[group_frame(durations, *total_duration, time)].bind_group,
         }
     }
 }
 
+/// A skin recolored with a player's shirt and pants colors.
+pub struct TranslatedSkin {
+    skin: Skin,
+}
+
 pub struct AliasRenderer {
     keyframes: Vec<Keyframe>,
     textures: Vec<Texture>,
     vertex_buffer: wgpu::Buffer,
+
+    // the palette indices of the first frame of each skin, for translation
+    skin_width: u32,
+    skin_height: u32,
+    skin_indices: Vec<Vec<u8>>,
 }
 
 impl AliasRenderer {
@@ -338,69 +399,32 @@ impl AliasRenderer {
             });
 
         let mut textures = Vec::new();
+        let mut skin_indices = Vec::new();
         for texture in alias_model.textures() {
             match *texture {
                 mdl::Texture::Static(ref tex) => {
-                    let (diffuse_data, _fullbright_data) = state.palette.translate(tex.indices());
-                    let diffuse_texture =
-                        state.create_texture(None, w, h, &TextureData::Diffuse(diffuse_data));
-                    let diffuse_view = diffuse_texture.create_view(&Default::default());
-                    let bind_group = state
-                        .device()
-                        .create_bind_group(&wgpu::BindGroupDescriptor {
-                            label: None,
-                            // TODO: per-pipeline bind group layout ids
-                            layout: &state.alias_pipeline().bind_group_layouts()
-                                [BindGroupLayoutId::PerTexture as usize - 2],
-                            entries: &[wgpu::BindGroupEntry {
-                                binding: 0,
-                                resource: wgpu::BindingResource::TextureView(&diffuse_view),
-                            }],
-                        });
-                    textures.push(Texture::Static {
-                        diffuse_texture,
-                        diffuse_view,
-                        bind_group,
-                    });
+                    textures.push(Texture::Static(Skin::new(state, w, h, tex.indices())));
+                    skin_indices.push(tex.indices().to_owned());
                 }
                 mdl::Texture::Animated(ref tex) => {
                     let mut total_duration = Duration::zero();
                     let mut durations = Vec::new();
-                    let mut diffuse_textures = Vec::new();
-                    let mut diffuse_views = Vec::new();
-                    let mut bind_groups = Vec::new();
+                    let mut skins = Vec::new();
 
                     for frame in tex.frames() {
                         total_duration = total_duration + frame.duration();
                         durations.push(frame.duration());
-
-                        let (diffuse_data, _fullbright_data) =
-                            state.palette.translate(frame.indices());
-                        let diffuse_texture =
-                            state.create_texture(None, w, h, &TextureData::Diffuse(diffuse_data));
-                        let diffuse_view = diffuse_texture.create_view(&Default::default());
-                        let bind_group =
-                            state
-                                .device()
-                                .create_bind_group(&wgpu::BindGroupDescriptor {
-                                    label: None,
-                                    layout: &state.alias_pipeline().bind_group_layouts()
-                                        [BindGroupLayoutId::PerTexture as usize - 2],
-                                    entries: &[wgpu::BindGroupEntry {
-                                        binding: 0,
-                                        resource: wgpu::BindingResource::TextureView(&diffuse_view),
-                                    }],
-                                });
-
-                        diffuse_textures.push(diffuse_texture);
-                        diffuse_views.push(diffuse_view);
-                        bind_groups.push(bind_group);
+                        skins.push(Skin::new(state, w, h, frame.indices()));
                     }
+                    skin_indices.push(
+                        tex.frames()
+                            .first()
+                            .map(|f| f.indices().to_owned())
+                            .unwrap_or_default(),
+                    );
 
                     textures.push(Texture::Animated {
-                        diffuse_textures,
-                        diffuse_views,
-                        bind_groups,
+                        skins,
                         total_duration,
                         durations,
                     });
@@ -412,25 +436,79 @@ impl AliasRenderer {
             keyframes,
             textures,
             vertex_buffer,
+            skin_width: w,
+            skin_height: h,
+            skin_indices,
+        })
+    }
+
+    /// Build a copy of skin `texture_id` remapped through `translation`.
+    ///
+    /// Animated skins are translated from their first frame. Returns `None` if
+    /// the model has no such skin.
+    pub fn translate_skin(
+        &self,
+        state: &GraphicsState,
+        texture_id: usize,
+        translation: &[u8; 256],
+    ) -> Option<TranslatedSkin> {
+        let indices: Vec<u8> = self
+            .skin_indices
+            .get(texture_id)?
+            .iter()
+            .map(|i| translation[*i as usize])
+            .collect();
+
+        Some(TranslatedSkin {
+            skin: Skin::new(state, self.skin_width, self.skin_height, &indices),
         })
     }
 
+    /// Draw the model, blending from `prev_keyframe_id` to `keyframe_id`.
+    ///
+    /// The blend factor is passed in the vertex push constants. If
+    /// `translated` is given, it's drawn in place of skin `texture_id`.
     pub fn record_draw<'a>(
         &'a self,
         state: &'a GraphicsState,
         pass: &mut wgpu::RenderPass<'a>,
         time: Duration,
         keyframe_id: usize,
+        prev_keyframe_id: usize,
         texture_id: usize,
+        translated: Option<&'a TranslatedSkin>,
     ) {
         pass.set_pipeline(state.alias_pipeline().pipeline());
-        pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
 
-        pass.set_bind_group(
-            BindGroupLayoutId::PerTexture as u32,
-            self.textures[texture_id].animate(time),
-            &[],
-        );
-        pass.draw(self.keyframes[keyframe_id].animate(time), 0..1)
+        let keyframe_id = if keyframe_id < self.keyframes.len() {
+            keyframe_id
+        } else {
+            debug!("No such keyframe: {}", keyframe_id);
+            0
+        };
+
+        // the previous frame may be from before a model change
+        let prev_keyframe_id = if prev_keyframe_id < self.keyframes.len() {
+            prev_keyframe_id
+        } else {
+            keyframe_id
+        };
+
+        let vertices = self.keyframes[keyframe_id].animate(time);
+        let prev_vertices = self.keyframes[prev_keyframe_id].animate(time);
+        let byte_range = |r: Range<u32>| {
+            let stride = size_of::<AliasVertex>() as u64;
+            r.start as u64 * stride..r.end as u64 * stride
+        };
+        pass.set_vertex_buffer(0, self.vertex_buffer.slice(byte_range(vertices.clone())));
+        pass.set_vertex_buffer(1, self.vertex_buffer.slice(byte_range(prev_vertices)));
+
+        let bind_group = match translated {
+            Some(t) => &t.skin.bind_group,
+            None => self.textures[texture_id].animate(time),
+        };
+        pass.set_bind_group(BindGroupLayoutId::PerTexture as u32, bind_group, &[]);
+        state.stats().count_draw(vertices.len() as u32, 1);
+        pass.draw(0..vertices.len() as u32, 0..1)
     }
 }
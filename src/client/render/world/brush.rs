@@ -19,11 +19,9 @@
 // SOFTWARE.
 
 use std::{
-    borrow::Cow,
     cell::{Cell, RefCell},
     collections::HashMap,
     mem::size_of,
-    num::NonZeroU32,
     ops::Range,
     rc::Rc,
 };
@@ -32,8 +30,11 @@ use crate::{
     client::render::{
         pipeline::PushConstantUpdate,
         warp,
-        world::{BindGroupLayoutId, WorldPipelineBase},
-        Camera, GraphicsState, LightmapData, Pipeline, TextureData,
+        world::{
+            lightmap::{LightmapAtlas, LightmapRegion},
+            BindGroupLayoutId, WorldPipelineBase,
+        },
+        Camera, GraphicsState, Pipeline, TextureData,
     },
     common::{
         bsp::{
@@ -52,6 +53,7 @@ use failure::Error;
 
 pub struct BrushPipeline {
     pipeline: wgpu::RenderPipeline,
+    translucent_pipeline: wgpu::RenderPipeline,
     bind_group_layouts: Vec<wgpu::BindGroupLayout>,
 }
 
@@ -65,9 +67,16 @@ impl BrushPipeline {
     ) -> BrushPipeline {
         let (pipeline, bind_group_layouts) =
             BrushPipeline::create(device, compiler, world_bind_group_layouts, sample_count);
+        let layout_refs: Vec<_> = world_bind_group_layouts
+            .iter()
+            .chain(bind_group_layouts.iter())
+            .collect();
+        let translucent_pipeline =
+            TranslucentBrushPipeline::recreate(device, compiler, &layout_refs, sample_count);
 
         BrushPipeline {
             pipeline,
+            translucent_pipeline,
             // TODO: pick a starting capacity
             bind_group_layouts,
         }
@@ -85,12 +94,21 @@ impl BrushPipeline {
             .chain(self.bind_group_layouts.iter())
             .collect();
         self.pipeline = BrushPipeline::recreate(device, compiler, &layout_refs, sample_count);
+        self.translucent_pipeline =
+            TranslucentBrushPipeline::recreate(device, compiler, &layout_refs, sample_count);
     }
 
     pub fn pipeline(&self) -> &wgpu::RenderPipeline {
         &self.pipeline
     }
 
+    /// Returns the pipeline used to draw translucent liquids.
+    ///
+    /// The opacity is taken from the render pass's blend color.
+    pub fn translucent_pipeline(&self) -> &wgpu::RenderPipeline {
+        &self.translucent_pipeline
+    }
+
     pub fn bind_group_layouts(&self) -> &[wgpu::BindGroupLayout] {
         &self.bind_group_layouts
     }
@@ -140,13 +158,13 @@ const BIND_GROUP_LAYOUT_ENTRIES: &[&[wgpu::BindGroupLayoutEntry]] = &[
         },
     ],
     &[
-        // lightmap texture array
+        // lightmap atlas page, one layer per light style
         wgpu::BindGroupLayoutEntry {
-            count: NonZeroU32::new(4),
+            count: None,
             binding: 0,
             visibility: wgpu::ShaderStage::FRAGMENT,
             ty: wgpu::BindingType::Texture {
-                view_dimension: wgpu::TextureViewDimension::D2,
+                view_dimension: wgpu::TextureViewDimension::D2Array,
                 sample_type: wgpu::TextureSampleType::Float { filterable: true },
                 multisampled: false,
             },
@@ -226,6 +244,72 @@ impl Pipeline for BrushPipeline {
     }
 }
 
+/// The brush pipeline with blending for translucent liquids.
+///
+/// Color is blended with what's already been drawn using the blend color as the opacity, and depth
+/// isn't written. The normal attachment is left alone so dynamic lights still reach surfaces below
+/// the liquid.
+pub struct TranslucentBrushPipeline;
+
+impl Pipeline for TranslucentBrushPipeline {
+    type VertexPushConstants = VertexPushConstants;
+    type SharedPushConstants = SharedPushConstants;
+    type FragmentPushConstants = ();
+
+    fn name() -> &'static str {
+        "translucent brush"
+    }
+
+    fn vertex_shader() -> &'static str {
+        BrushPipeline::vertex_shader()
+    }
+
+    fn fragment_shader() -> &'static str {
+        BrushPipeline::fragment_shader()
+    }
+
+    fn bind_group_layout_descriptors() -> Vec<wgpu::BindGroupLayoutDescriptor<'static>> {
+        BrushPipeline::bind_group_layout_descriptors()
+    }
+
+    fn primitive_state() -> wgpu::PrimitiveState {
+        BrushPipeline::primitive_state()
+    }
+
+    fn color_target_states() -> Vec<wgpu::ColorTargetState> {
+        let blend = wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::BlendColor,
+                dst_factor: wgpu::BlendFactor::OneMinusBlendColor,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::BlendColor,
+                dst_factor: wgpu::BlendFactor::OneMinusBlendColor,
+                operation: wgpu::BlendOperation::Add,
+            },
+        };
+
+        // diffuse, normal and light attachments, in that order
+        let mut states = WorldPipelineBase::color_target_states();
+        states[0].blend = Some(blend);
+        states[1].write_mask = wgpu::ColorWrite::empty();
+        states[2].blend = Some(blend);
+        states
+    }
+
+    fn depth_stencil_state() -> Option<wgpu::DepthStencilState> {
+        WorldPipelineBase::depth_stencil_state().map(|mut state| {
+            state.depth_write_enabled = false;
+            state
+        })
+    }
+
+    fn vertex_buffer_layouts() -> Vec<wgpu::VertexBufferLayout<'static>> {
+        BrushPipeline::vertex_buffer_layouts()
+    }
+}
+
 fn calculate_lightmap_texcoords(
     position: Vector3<f32>,
     face: &BspFace,
@@ -299,6 +383,10 @@ impl BrushTexture {
             BrushTexture::Animated { ref primary, .. } => primary[0].kind,
         }
     }
+
+    fn is_liquid(&self) -> bool {
+        matches!(self.kind(), TextureKind::Warp)
+    }
 }
 
 #[derive(Debug)]
@@ -309,7 +397,7 @@ struct BrushFace {
 
     texture_id: usize,
 
-    lightmap_ids: Vec<usize>,
+    lightmap: Option<LightmapRegion>,
     light_styles: [u8; 4],
 
     /// Indicates whether the face should be drawn this frame.
@@ -343,14 +431,12 @@ pub struct BrushRendererBuilder {
     leaves: Option<Vec<BrushLeaf>>,
 
     per_texture_bind_groups: RefCell<Vec<wgpu::BindGroup>>,
-    per_face_bind_groups: Vec<wgpu::BindGroup>,
 
     vertices: Vec<BrushVertex>,
     faces: Vec<BrushFace>,
     texture_chains: HashMap<usize, Vec<usize>>,
     textures: Vec<BrushTexture>,
-    lightmaps: Vec<wgpu::Texture>,
-    //lightmap_views: Vec<wgpu::TextureView>,
+    lightmap_atlas: LightmapAtlas,
 }
 
 impl BrushRendererBuilder {
@@ -369,22 +455,54 @@ impl BrushRendererBuilder {
                 None
             },
             per_texture_bind_groups: RefCell::new(Vec::new()),
-            per_face_bind_groups: Vec::new(),
             vertices: Vec::new(),
             faces: Vec::new(),
             texture_chains: HashMap::new(),
             textures: Vec::new(),
-            lightmaps: Vec::new(),
-            //lightmap_views: Vec::new(),
+            lightmap_atlas: LightmapAtlas::new(),
         }
     }
 
-    fn create_face(&mut self, state: &GraphicsState, face_id: usize) -> BrushFace {
+    fn create_face(&mut self, face_id: usize) -> Result<BrushFace, Error> {
         let face = &self.bsp_data.faces()[face_id];
         let face_vert_id = self.vertices.len();
         let texinfo = &self.bsp_data.texinfo()[face.texinfo_id];
         let tex = &self.bsp_data.textures()[texinfo.tex_id];
 
+        // place the lightmaps first so vertices can address them in the atlas
+        let lightmaps = if !texinfo.special {
+            self.bsp_data.face_lightmaps(face_id)
+        } else {
+            Vec::new()
+        };
+
+        let lightmap = match lightmaps.first() {
+            Some(first) => {
+                let region = self
+                    .lightmap_atlas
+                    .allocate(first.width(), first.height())
+                    .ok_or_else(|| {
+                        format_err!(
+                            "Lightmap for face {} is too large ({}x{})",
+                            face_id,
+                            first.width(),
+                            first.height()
+                        )
+                    })?;
+                for (slot, lm) in lightmaps.iter().enumerate() {
+                    self.lightmap_atlas.write(&region, slot, lm.data());
+                }
+                Some(region)
+            }
+            None => None,
+        };
+
+        let atlas = &self.lightmap_atlas;
+        let lightmap_texcoord = |st: [f32; 2]| match lightmap {
+            Some(ref region) => atlas.texcoord(region, st),
+            None => [0.0, 0.0],
+        };
+
         let mut min = Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
         let mut max = Vector3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
 
@@ -410,7 +528,11 @@ impl BrushRendererBuilder {
                         ((vert.dot(texinfo.s_vector) + texinfo.s_offset) / tex.width() as f32),
                         ((vert.dot(texinfo.t_vector) + texinfo.t_offset) / tex.height() as f32),
                     ],
-                    lightmap_texcoord: calculate_lightmap_texcoords(vert.into(), face, texinfo),
+                    lightmap_texcoord: lightmap_texcoord(calculate_lightmap_texcoords(
+                        vert.into(),
+                        face,
+                        texinfo,
+                    )),
                     lightmap_anim: face.light_styles,
                 })
             }
@@ -441,11 +563,11 @@ impl BrushRendererBuilder {
                             ((vert.dot(texinfo.s_vector) + texinfo.s_offset) / tex.width() as f32),
                             ((vert.dot(texinfo.t_vector) + texinfo.t_offset) / tex.height() as f32),
                         ],
-                        lightmap_texcoord: calculate_lightmap_texcoords(
+                        lightmap_texcoord: lightmap_texcoord(calculate_lightmap_texcoords(
                             (*vert).into(),
                             face,
                             texinfo,
-                        ),
+                        )),
                         lightmap_anim: face.light_styles,
                     });
                 }
@@ -454,38 +576,15 @@ impl BrushRendererBuilder {
             }
         }
 
-        // build the lightmaps
-        let lightmaps = if !texinfo.special {
-            self.bsp_data.face_lightmaps(face_id)
-        } else {
-            Vec::new()
-        };
-
-        let mut lightmap_ids = Vec::new();
-        for lightmap in lightmaps {
-            let lightmap_data = TextureData::Lightmap(LightmapData {
-                lightmap: Cow::Borrowed(lightmap.data()),
-            });
-
-            let texture =
-                state.create_texture(None, lightmap.width(), lightmap.height(), &lightmap_data);
-
-            let id = self.lightmaps.len();
-            self.lightmaps.push(texture);
-            //self.lightmap_views
-            //.push(self.lightmaps[id].create_view(&Default::default()));
-            lightmap_ids.push(id);
-        }
-
-        BrushFace {
+        Ok(BrushFace {
             vertices: face_vert_id as u32..self.vertices.len() as u32,
             min,
             max,
             texture_id: texinfo.tex_id as usize,
-            lightmap_ids,
+            lightmap,
             light_styles: face.light_styles,
             draw_flag: Cell::new(true),
-        }
+        })
     }
 
     fn create_per_texture_bind_group(
@@ -513,27 +612,20 @@ impl BrushRendererBuilder {
         state.device().create_bind_group(&desc)
     }
 
-    fn create_per_face_bind_group(&self, state: &GraphicsState, face_id: usize) -> wgpu::BindGroup {
-        let mut lightmap_views: Vec<_> = self.faces[face_id]
-            .lightmap_ids
-            .iter()
-            .map(|id| self.lightmaps[*id].create_view(&Default::default()))
-            .collect();
-        lightmap_views.resize_with(4, || {
-            state.default_lightmap().create_view(&Default::default())
-        });
-
-        let lightmap_view_refs = lightmap_views.iter().collect::<Vec<_>>();
-
+    fn create_lightmap_bind_group(
+        &self,
+        state: &GraphicsState,
+        view: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
         let layout = &state
             .brush_pipeline()
             .bind_group_layout(BindGroupLayoutId::PerFace);
         let desc = wgpu::BindGroupDescriptor {
-            label: Some("per-face bind group"),
+            label: Some("lightmap atlas bind group"),
             layout,
             entries: &[wgpu::BindGroupEntry {
                 binding: 0,
-                resource: wgpu::BindingResource::TextureViewArray(&lightmap_view_refs[..]),
+                resource: wgpu::BindingResource::TextureView(view),
             }],
         };
         state.device().create_bind_group(&desc)
@@ -553,9 +645,13 @@ impl BrushRendererBuilder {
         let name = name.as_ref();
 
         let (diffuse_data, fullbright_data) = state.palette().translate(mipmap);
-        let diffuse =
-            state.create_texture(None, width, height, &TextureData::Diffuse(diffuse_data));
-        let fullbright = state.create_texture(
+        let diffuse = state.create_mipmapped_texture(
+            None,
+            width,
+            height,
+            &TextureData::Diffuse(diffuse_data),
+        );
+        let fullbright = state.create_mipmapped_texture(
             None,
             width,
             height,
@@ -656,7 +752,7 @@ impl BrushRendererBuilder {
         // face_id is the new id of the face in the renderer
         for bsp_face_id in self.face_range.start..self.face_range.end {
             let face_id = self.faces.len();
-            let face = self.create_face(state, bsp_face_id);
+            let face = self.create_face(bsp_face_id)?;
             self.faces.push(face);
 
             let face_tex_id = self.faces[face_id].texture_id;
@@ -665,12 +761,27 @@ impl BrushRendererBuilder {
                 .entry(face_tex_id)
                 .or_insert(Vec::new())
                 .push(face_id);
-
-            // generate face bind group
-            let per_face_bind_group = self.create_per_face_bind_group(state, face_id);
-            self.per_face_bind_groups.push(per_face_bind_group);
         }
 
+        self.lightmap_atlas.upload(state.device(), state.queue());
+        let lightmap_bind_groups = (0..self.lightmap_atlas.page_count())
+            .map(|page| {
+                self.create_lightmap_bind_group(state, self.lightmap_atlas.view(page).unwrap())
+            })
+            .collect();
+
+        // faces without a lightmap (and models with no lightmaps at all) use
+        // the default lightmap
+        let default_lightmap_view =
+            state
+                .default_lightmap()
+                .create_view(&wgpu::TextureViewDescriptor {
+                    dimension: Some(wgpu::TextureViewDimension::D2Array),
+                    ..Default::default()
+                });
+        let default_lightmap_bind_group =
+            self.create_lightmap_bind_group(state, &default_lightmap_view);
+
         use wgpu::util::DeviceExt as _;
         let vertex_buffer = state
             .device()
@@ -685,12 +796,12 @@ impl BrushRendererBuilder {
             vertex_buffer,
             leaves: self.leaves,
             per_texture_bind_groups: self.per_texture_bind_groups.into_inner(),
-            per_face_bind_groups: self.per_face_bind_groups,
+            lightmap_bind_groups,
+            default_lightmap_bind_group,
             texture_chains: self.texture_chains,
             faces: self.faces,
             textures: self.textures,
-            lightmaps: self.lightmaps,
-            //lightmap_views: self.lightmap_views,
+            lightmap_atlas: RefCell::new(self.lightmap_atlas),
         })
     }
 }
@@ -702,19 +813,34 @@ pub struct BrushRenderer {
 
     vertex_buffer: wgpu::Buffer,
     per_texture_bind_groups: Vec<wgpu::BindGroup>,
-    per_face_bind_groups: Vec<wgpu::BindGroup>,
+
+    // one per lightmap atlas page
+    lightmap_bind_groups: Vec<wgpu::BindGroup>,
+    default_lightmap_bind_group: wgpu::BindGroup,
 
     // faces are grouped by texture to reduce the number of texture rebinds
     // texture_chains maps texture ids to face ids
     texture_chains: HashMap<usize, Vec<usize>>,
     faces: Vec<BrushFace>,
     textures: Vec<BrushTexture>,
-    lightmaps: Vec<wgpu::Texture>,
-    //lightmap_views: Vec<wgpu::TextureView>,
+    lightmap_atlas: RefCell<LightmapAtlas>,
 }
 
 impl BrushRenderer {
+    /// Replace the lightmap for light style slot `slot` of face `face_id`.
+    ///
+    /// `data` must match the dimensions of the face's lightmap. Only the
+    /// face's region of the atlas is uploaded, on the next draw.
+    pub fn update_lightmap(&self, face_id: usize, slot: usize, data: &[u8]) {
+        if let Some(ref region) = self.faces[face_id].lightmap {
+            self.lightmap_atlas.borrow_mut().write(region, slot, data);
+        }
+    }
+
     /// Record the draw commands for this brush model to the given `wgpu::RenderPass`.
+    ///
+    /// If `skip_liquids` is set, liquid faces are left marked for a following call to
+    /// `record_translucent_draw`.
     pub fn record_draw<'a>(
         &'a self,
         state: &'a GraphicsState,
@@ -723,64 +849,36 @@ impl BrushRenderer {
         time: Duration,
         camera: &Camera,
         frame_id: usize,
+        skip_liquids: bool,
     ) {
+        self.lightmap_atlas
+            .borrow_mut()
+            .upload(state.device(), state.queue());
+
         pass.set_pipeline(state.brush_pipeline().pipeline());
         pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
 
         // if this is a worldmodel, mark faces to be drawn
         if let Some(ref leaves) = self.leaves {
-            let pvs = self
+            let vis = self
                 .bsp_data
-                .get_pvs(self.bsp_data.find_leaf(camera.origin), leaves.len());
+                .decompress_vis(self.bsp_data.find_leaf(camera.origin), leaves.len());
 
-            // only draw faces in pvs
-            for leaf_id in pvs {
+            // only draw faces in the pvs and the view frustum
+            for leaf_id in self.bsp_data.visible_leaves(&vis, camera.frustum()) {
                 for facelist_id in leaves[leaf_id].facelist_ids.clone() {
                     let face = &self.faces[self.bsp_data.facelist()[facelist_id]];
-
-                    // TODO: frustum culling
                     face.draw_flag.set(true);
                 }
             }
         }
 
         for (tex_id, face_ids) in self.texture_chains.iter() {
-            use PushConstantUpdate::*;
-            BrushPipeline::set_push_constants(
-                pass,
-                Retain,
-                Update(bump.alloc(SharedPushConstants {
-                    texture_kind: self.textures[*tex_id].kind() as u32,
-                })),
-                Retain,
-            );
-
-            let bind_group_id = match &self.textures[*tex_id] {
-                BrushTexture::Static(ref frame) => frame.bind_group_id,
-                BrushTexture::Animated { primary, alternate } => {
-                    // if frame is not zero and this texture has an alternate
-                    // animation, use it
-                    let anim = if frame_id == 0 {
-                        primary
-                    } else if let Some(a) = alternate {
-                        a
-                    } else {
-                        primary
-                    };
-
-                    let time_ms = time.num_milliseconds();
-                    let total_ms = (bsp::frame_duration() * anim.len() as i32).num_milliseconds();
-                    let anim_ms = if total_ms == 0 { 0 } else { time_ms % total_ms };
-                    anim[(anim_ms / bsp::frame_duration().num_milliseconds()) as usize]
-                        .bind_group_id
-                }
-            };
+            if skip_liquids && self.textures[*tex_id].is_liquid() {
+                continue;
+            }
 
-            pass.set_bind_group(
-                BindGroupLayoutId::PerTexture as u32,
-                &self.per_texture_bind_groups[bind_group_id],
-                &[],
-            );
+            self.bind_texture(pass, bump, *tex_id, time, frame_id);
 
             for face_id in face_ids.iter() {
                 let face = &self.faces[*face_id];
@@ -790,14 +888,112 @@ impl BrushRenderer {
                     continue;
                 }
 
-                pass.set_bind_group(
-                    BindGroupLayoutId::PerFace as u32,
-                    &self.per_face_bind_groups[*face_id],
-                    &[],
-                );
+                self.draw_face(state, pass, face);
+            }
+        }
+    }
 
-                pass.draw(face.vertices.clone(), 0..1);
+    /// Record the draw commands for the liquid faces left by `record_draw`.
+    ///
+    /// Faces are drawn back to front with the translucent brush pipeline. The vertex push
+    /// constants and per-entity bind group must be set for this model, and the opacity is taken
+    /// from the pass's blend color.
+    pub fn record_translucent_draw<'a>(
+        &'a self,
+        state: &'a GraphicsState,
+        pass: &mut wgpu::RenderPass<'a>,
+        bump: &'a Bump,
+        time: Duration,
+        camera: &Camera,
+    ) {
+        let mut faces = Vec::new();
+        for (tex_id, face_ids) in self.texture_chains.iter() {
+            if !self.textures[*tex_id].is_liquid() {
+                continue;
             }
+
+            for face_id in face_ids.iter() {
+                let face = &self.faces[*face_id];
+                if self.leaves.is_some() && !face.draw_flag.replace(false) {
+                    continue;
+                }
+
+                let center = (face.min + face.max) / 2.0;
+                faces.push(((center - camera.origin).magnitude2(), face));
+            }
+        }
+
+        // farthest first
+        faces.sort_unstable_by(|(a, _), (b, _)| b.partial_cmp(a).unwrap());
+
+        pass.set_pipeline(state.brush_pipeline().translucent_pipeline());
+        pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        let mut bound_texture = None;
+        for (_, face) in faces {
+            if bound_texture != Some(face.texture_id) {
+                self.bind_texture(pass, bump, face.texture_id, time, 0);
+                bound_texture = Some(face.texture_id);
+            }
+
+            self.draw_face(state, pass, face);
         }
     }
+
+    fn bind_texture<'a>(
+        &'a self,
+        pass: &mut wgpu::RenderPass<'a>,
+        bump: &'a Bump,
+        tex_id: usize,
+        time: Duration,
+        frame_id: usize,
+    ) {
+        use PushConstantUpdate::*;
+        BrushPipeline::set_push_constants(
+            pass,
+            Retain,
+            Update(bump.alloc(SharedPushConstants {
+                texture_kind: self.textures[tex_id].kind() as u32,
+            })),
+            Retain,
+        );
+
+        let bind_group_id = match &self.textures[tex_id] {
+            BrushTexture::Static(ref frame) => frame.bind_group_id,
+            BrushTexture::Animated { primary, alternate } => {
+                // if frame is not zero and this texture has an alternate
+                // animation, use it
+                let anim = if frame_id == 0 {
+                    primary
+                } else if let Some(a) = alternate {
+                    a
+                } else {
+                    primary
+                };
+
+                anim[bsp::animation_frame(time, anim.len())].bind_group_id
+            }
+        };
+
+        pass.set_bind_group(
+            BindGroupLayoutId::PerTexture as u32,
+            &self.per_texture_bind_groups[bind_group_id],
+            &[],
+        );
+    }
+
+    fn draw_face<'a>(
+        &'a self,
+        state: &'a GraphicsState,
+        pass: &mut wgpu::RenderPass<'a>,
+        face: &BrushFace,
+    ) {
+        let bind_group = match face.lightmap {
+            Some(ref l) => &self.lightmap_bind_groups[l.page],
+            None => &self.default_lightmap_bind_group,
+        };
+        pass.set_bind_group(BindGroupLayoutId::PerFace as u32, bind_group, &[]);
+
+        pass.draw(face.vertices.clone(), 0..1);
+        state.stats().count_draw(face.vertices.len() as u32, 1);
+    }
 }
@@ -7,7 +7,7 @@ use crate::{
         entity::MAX_LIGHTS,
         render::{pipeline::Pipeline, ui::quad::QuadPipeline, GraphicsState},
     },
-    common::util::any_as_bytes,
+    common::{console::CvarRegistry, util::any_as_bytes},
 };
 
 #[repr(C)]
@@ -17,12 +17,27 @@ pub struct PointLight {
     pub radius: f32,
 }
 
+/// The light limit for overbright lighting. See `DeferredUniforms::max_light`.
+pub const OVERBRIGHT_MAX_LIGHT: f32 = 2.0;
+
+/// Returns the light limit selected by the `r_overbright` cvar.
+pub fn max_light(cvars: &CvarRegistry) -> f32 {
+    match cvars.get_value("r_overbright").unwrap_or(1.0) {
+        o if o != 0.0 => OVERBRIGHT_MAX_LIGHT,
+        _ => 1.0,
+    }
+}
+
 #[repr(C, align(256))]
 #[derive(Clone, Copy, Debug)]
 pub struct DeferredUniforms {
     pub inv_projection: [[f32; 4]; 4],
     pub light_count: u32,
-    pub _pad: [u32; 3],
+
+    /// The brightest the light on a pixel can be, 2.0 for overbright lighting
+    /// or 1.0 for classic clamped lighting.
+    pub max_light: f32,
+    pub _pad: [u32; 2],
     pub lights: [PointLight; MAX_LIGHTS],
 }
 
@@ -48,7 +63,8 @@ impl DeferredPipeline {
                 any_as_bytes(&DeferredUniforms {
                     inv_projection: Matrix4::identity().into(),
                     light_count: 0,
-                    _pad: [0; 3],
+                    max_light: OVERBRIGHT_MAX_LIGHT,
+                    _pad: [0; 2],
                     lights: [PointLight {
                         origin: Vector3::zero(),
                         radius: 0.0,
@@ -315,5 +331,6 @@ impl DeferredRenderer {
         pass.set_vertex_buffer(0, state.quad_pipeline().vertex_buffer().slice(..));
         pass.set_bind_group(0, &self.bind_group, &[]);
         pass.draw(0..6, 0..1);
+        state.stats().count_draw(6, 1);
     }
 }
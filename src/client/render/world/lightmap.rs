@@ -0,0 +1,431 @@
+// Copyright © 2020 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Lightmap atlases.
+//!
+//! Brush model lightmaps are packed into a few large pages rather than given a
+//! texture each. Every page is a texture array with one layer per light style
+//! slot; a face's lightmap occupies the same rectangle in every layer, so one
+//! texture coordinate addresses all four of its styles.
+//!
+//! A copy of each page is kept in memory. Rewriting a face's lightmap (for
+//! example, to add dynamic lights) marks the affected rectangle dirty, and
+//! only dirty rectangles are re-uploaded.
+
+use std::num::NonZeroU32;
+
+use crate::client::render::LIGHTMAP_TEXTURE_FORMAT;
+
+/// The width and height of an atlas page in texels.
+pub const ATLAS_SIZE: u32 = 512;
+
+/// The number of light styles a face can have.
+pub const STYLE_SLOTS: usize = 4;
+
+// lightmaps are surrounded by a copy of their edge texels so filtering doesn't
+// pick up their neighbours
+const PADDING: u32 = 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AtlasRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl AtlasRect {
+    /// Returns the smallest rectangle containing both `self` and `other`.
+    pub fn union(&self, other: &AtlasRect) -> AtlasRect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        AtlasRect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shelf {
+    y: u32,
+    height: u32,
+
+    // the left edge of the free space on this shelf
+    x: u32,
+}
+
+/// Packs rectangles into a square page, left to right along horizontal shelves.
+#[derive(Debug)]
+pub struct ShelfAllocator {
+    size: u32,
+    shelves: Vec<Shelf>,
+}
+
+impl ShelfAllocator {
+    pub fn new(size: u32) -> ShelfAllocator {
+        ShelfAllocator {
+            size,
+            shelves: Vec::new(),
+        }
+    }
+
+    /// Find space for a `width` by `height` rectangle, returning its top left
+    /// corner.
+    pub fn allocate(&mut self, width: u32, height: u32) -> Option<[u32; 2]> {
+        let size = self.size;
+
+        // use the shelf that wastes the least height
+        if let Some(shelf) = self
+            .shelves
+            .iter_mut()
+            .filter(|s| s.height >= height && s.x + width <= size)
+            .min_by_key(|s| s.height - height)
+        {
+            let x = shelf.x;
+            shelf.x += width;
+            return Some([x, shelf.y]);
+        }
+
+        let y = self.shelves.last().map(|s| s.y + s.height).unwrap_or(0);
+        if width > size || y + height > size {
+            return None;
+        }
+
+        self.shelves.push(Shelf {
+            y,
+            height,
+            x: width,
+        });
+        Some([0, y])
+    }
+}
+
+/// The space allocated to one face's lightmaps.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LightmapRegion {
+    pub page: usize,
+
+    /// The lightmap's texels, not including padding.
+    pub rect: AtlasRect,
+}
+
+struct Page {
+    allocator: ShelfAllocator,
+
+    // STYLE_SLOTS layers of ATLAS_SIZE * ATLAS_SIZE texels
+    data: Vec<u8>,
+    dirty: [Option<AtlasRect>; STYLE_SLOTS],
+
+    texture: Option<wgpu::Texture>,
+    view: Option<wgpu::TextureView>,
+}
+
+impl Page {
+    fn new() -> Page {
+        Page {
+            allocator: ShelfAllocator::new(ATLAS_SIZE),
+            data: vec![0; (ATLAS_SIZE * ATLAS_SIZE) as usize * STYLE_SLOTS],
+            dirty: [None; STYLE_SLOTS],
+            texture: None,
+            view: None,
+        }
+    }
+
+    fn layer_mut(&mut self, slot: usize) -> &mut [u8] {
+        let len = (ATLAS_SIZE * ATLAS_SIZE) as usize;
+        &mut self.data[slot * len..(slot + 1) * len]
+    }
+
+    fn mark_dirty(&mut self, slot: usize, rect: AtlasRect) {
+        self.dirty[slot] = Some(match self.dirty[slot] {
+            Some(d) => d.union(&rect),
+            None => rect,
+        });
+    }
+}
+
+/// The lightmaps of a brush model.
+pub struct LightmapAtlas {
+    pages: Vec<Page>,
+}
+
+impl LightmapAtlas {
+    pub fn new() -> LightmapAtlas {
+        // models without lightmaps still need something to bind
+        LightmapAtlas {
+            pages: vec![Page::new()],
+        }
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Allocate space for a `width` by `height` lightmap.
+    ///
+    /// Returns `None` if the lightmap is too large to fit on a page.
+    pub fn allocate(&mut self, width: u32, height: u32) -> Option<LightmapRegion> {
+        let padded_w = width + 2 * PADDING;
+        let padded_h = height + 2 * PADDING;
+        if padded_w > ATLAS_SIZE || padded_h > ATLAS_SIZE {
+            return None;
+        }
+
+        let found = self
+            .pages
+            .iter_mut()
+            .enumerate()
+            .find_map(|(i, p)| p.allocator.allocate(padded_w, padded_h).map(|xy| (i, xy)));
+        let (page, [x, y]) = match found {
+            Some(found) => found,
+            None => {
+                let mut page = Page::new();
+                let xy = page.allocator.allocate(padded_w, padded_h)?;
+                self.pages.push(page);
+                (self.pages.len() - 1, xy)
+            }
+        };
+
+        Some(LightmapRegion {
+            page,
+            rect: AtlasRect {
+                x: x + PADDING,
+                y: y + PADDING,
+                width,
+                height,
+            },
+        })
+    }
+
+    /// Convert a texture coordinate within a lightmap to one within its page.
+    pub fn texcoord(&self, region: &LightmapRegion, st: [f32; 2]) -> [f32; 2] {
+        let AtlasRect {
+            x,
+            y,
+            width,
+            height,
+        } = region.rect;
+        [
+            (x as f32 + st[0] * width as f32) / ATLAS_SIZE as f32,
+            (y as f32 + st[1] * height as f32) / ATLAS_SIZE as f32,
+        ]
+    }
+
+    /// Replace the lightmap for style slot `slot` in `region`.
+    ///
+    /// The new data is uploaded on the next call to `upload`.
+    pub fn write(&mut self, region: &LightmapRegion, slot: usize, data: &[u8]) {
+        let AtlasRect {
+            x,
+            y,
+            width,
+            height,
+        } = region.rect;
+        assert_eq!(data.len(), (width * height) as usize);
+
+        let page = &mut self.pages[region.page];
+        let layer = page.layer_mut(slot);
+        let clamp = |v: u32, max: u32| v.saturating_sub(PADDING).min(max - 1);
+        for py in 0..height + 2 * PADDING {
+            let src_row = clamp(py, height) * width;
+            let dst_row = (y - PADDING + py) * ATLAS_SIZE + x - PADDING;
+            for px in 0..width + 2 * PADDING {
+                layer[(dst_row + px) as usize] = data[(src_row + clamp(px, width)) as usize];
+            }
+        }
+
+        page.mark_dirty(
+            slot,
+            AtlasRect {
+                x: x - PADDING,
+                y: y - PADDING,
+                width: width + 2 * PADDING,
+                height: height + 2 * PADDING,
+            },
+        );
+    }
+
+    /// Returns the view of page `page`, or `None` if it hasn't been uploaded.
+    pub fn view(&self, page: usize) -> Option<&wgpu::TextureView> {
+        self.pages[page].view.as_ref()
+    }
+
+    /// Upload new pages and dirty rectangles to the GPU.
+    pub fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        for page in self.pages.iter_mut() {
+            if page.texture.is_none() {
+                let texture = device.create_texture(&wgpu::TextureDescriptor {
+                    label: Some("lightmap atlas"),
+                    size: wgpu::Extent3d {
+                        width: ATLAS_SIZE,
+                        height: ATLAS_SIZE,
+                        depth_or_array_layers: STYLE_SLOTS as u32,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: LIGHTMAP_TEXTURE_FORMAT,
+                    usage: wgpu::TextureUsage::COPY_DST | wgpu::TextureUsage::SAMPLED,
+                });
+                page.view = Some(texture.create_view(&wgpu::TextureViewDescriptor {
+                    dimension: Some(wgpu::TextureViewDimension::D2Array),
+                    ..Default::default()
+                }));
+                page.texture = Some(texture);
+
+                let whole = AtlasRect {
+                    x: 0,
+                    y: 0,
+                    width: ATLAS_SIZE,
+                    height: ATLAS_SIZE,
+                };
+                page.dirty = [Some(whole); STYLE_SLOTS];
+            }
+
+            for slot in 0..STYLE_SLOTS {
+                let rect = match page.dirty[slot].take() {
+                    Some(r) => r,
+                    None => continue,
+                };
+
+                let len = (ATLAS_SIZE * ATLAS_SIZE) as usize;
+                queue.write_texture(
+                    wgpu::ImageCopyTexture {
+                        texture: page.texture.as_ref().unwrap(),
+                        mip_level: 0,
+                        origin: wgpu::Origin3d {
+                            x: rect.x,
+                            y: rect.y,
+                            z: slot as u32,
+                        },
+                    },
+                    &page.data[slot * len..(slot + 1) * len],
+                    wgpu::ImageDataLayout {
+                        offset: (rect.y * ATLAS_SIZE + rect.x) as u64,
+                        bytes_per_row: NonZeroU32::new(ATLAS_SIZE),
+                        rows_per_image: None,
+                    },
+                    wgpu::Extent3d {
+                        width: rect.width,
+                        height: rect.height,
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shelf_allocator() {
+        let mut alloc = ShelfAllocator::new(16);
+        assert_eq!(alloc.allocate(8, 4), Some([0, 0]));
+        assert_eq!(alloc.allocate(8, 8), Some([0, 4]));
+
+        // fits on the first shelf
+        assert_eq!(alloc.allocate(4, 3), Some([8, 0]));
+
+        // too tall for the first shelf, so it goes on the second
+        assert_eq!(alloc.allocate(8, 6), Some([8, 4]));
+
+        assert_eq!(alloc.allocate(16, 4), Some([0, 12]));
+        assert_eq!(alloc.allocate(1, 1), Some([12, 0]));
+        assert_eq!(alloc.allocate(16, 1), None);
+        assert_eq!(alloc.allocate(17, 1), None);
+    }
+
+    #[test]
+    fn test_atlas_write() {
+        let mut atlas = LightmapAtlas::new();
+        let a = atlas.allocate(2, 2).unwrap();
+        let b = atlas.allocate(3, 1).unwrap();
+        assert_eq!(
+            a.rect,
+            AtlasRect {
+                x: 1,
+                y: 1,
+                width: 2,
+                height: 2
+            }
+        );
+        assert_eq!(
+            b.rect,
+            AtlasRect {
+                x: 5,
+                y: 1,
+                width: 3,
+                height: 1
+            }
+        );
+
+        atlas.write(&a, 1, &[1, 2, 3, 4]);
+        let page = &mut atlas.pages[0];
+        assert_eq!(
+            page.dirty[1],
+            Some(AtlasRect {
+                x: 0,
+                y: 0,
+                width: 4,
+                height: 4
+            })
+        );
+        assert_eq!(page.dirty[0], None);
+
+        // edge texels are copied into the padding
+        let layer = page.layer_mut(1);
+        let row = |y: usize| &layer[y * ATLAS_SIZE as usize..y * ATLAS_SIZE as usize + 4];
+        assert_eq!(row(0), &[1, 1, 2, 2]);
+        assert_eq!(row(1), &[1, 1, 2, 2]);
+        assert_eq!(row(2), &[3, 3, 4, 4]);
+        assert_eq!(row(3), &[3, 3, 4, 4]);
+
+        // rewriting part of the page grows the dirty rectangle
+        atlas.write(&b, 1, &[5, 6, 7]);
+        assert_eq!(
+            atlas.pages[0].dirty[1],
+            Some(AtlasRect {
+                x: 0,
+                y: 0,
+                width: 9,
+                height: 4
+            })
+        );
+
+        assert_eq!(atlas.texcoord(&b, [0.0, 1.0]), [5.0 / 512.0, 2.0 / 512.0]);
+    }
+
+    #[test]
+    fn test_atlas_new_page() {
+        let mut atlas = LightmapAtlas::new();
+        assert!(atlas.allocate(ATLAS_SIZE, 1).is_none());
+
+        let big = ATLAS_SIZE - 2 * PADDING;
+        assert_eq!(atlas.allocate(big, big).unwrap().page, 0);
+        assert_eq!(atlas.allocate(1, 1).unwrap().page, 1);
+        assert_eq!(atlas.page_count(), 2);
+    }
+}
@@ -1,40 +1,50 @@
 pub mod alias;
 pub mod brush;
 pub mod deferred;
+pub mod lightmap;
 pub mod particle;
 pub mod postprocess;
+pub mod sky;
 pub mod sprite;
 
-use std::{cell::RefCell, mem::size_of};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    mem::size_of,
+};
 
 use crate::{
     client::{
         entity::particle::Particle,
         render::{
+            palette,
             pipeline::{Pipeline, PushConstantUpdate},
             uniform::{DynamicUniformBufferBlock, UniformArrayFloat, UniformBool},
             world::{
-                alias::{AliasPipeline, AliasRenderer},
+                alias::{AliasPipeline, AliasRenderer, TranslatedSkin},
                 brush::{BrushPipeline, BrushRenderer, BrushRendererBuilder},
+                sky::Skybox,
                 sprite::{SpritePipeline, SpriteRenderer},
             },
-            GraphicsState, DEPTH_ATTACHMENT_FORMAT, DIFFUSE_ATTACHMENT_FORMAT,
+            GraphicsState, Section, DEPTH_ATTACHMENT_FORMAT, DIFFUSE_ATTACHMENT_FORMAT,
             LIGHT_ATTACHMENT_FORMAT, NORMAL_ATTACHMENT_FORMAT,
         },
+        view::ViewModel,
         ClientEntity,
     },
     common::{
         console::CvarRegistry,
         engine,
-        math::Angles,
+        math::{Angles, Frustum},
         model::{Model, ModelKind},
-        sprite::SpriteKind,
+        net::PlayerColor,
+        stream::AssetStreamer,
         util::any_as_bytes,
     },
 };
 
 use bumpalo::Bump;
-use cgmath::{Euler, InnerSpace, Matrix4, SquareMatrix as _, Vector3, Vector4};
+use cgmath::{Euler, InnerSpace as _, Matrix as _, Matrix4, SquareMatrix as _, Vector3, Vector4};
 use chrono::Duration;
 
 lazy_static! {
@@ -51,6 +61,27 @@ lazy_static! {
                 },
                 count:None,
             },
+            // skybox faces, one layer each
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    multisampled: false,
+                },
+                count: None,
+            },
+            // skybox sampler
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStage::FRAGMENT,
+                ty: wgpu::BindingType::Sampler {
+                    filtering: true,
+                    comparison: false,
+                },
+                count: None,
+            },
         ],
         vec![
             // transform matrix
@@ -180,6 +211,67 @@ impl Pipeline for WorldPipelineBase {
     }
 }
 
+/// Find the frame of a group that is showing at `time`.
+fn group_frame(durations: &[Duration], total_duration: Duration, time: Duration) -> usize {
+    let total_ms = total_duration.num_milliseconds();
+    if total_ms <= 0 {
+        return 0;
+    }
+
+    let mut time_ms = time.num_milliseconds() % total_ms;
+    for (frame_id, frame_duration) in durations.iter().enumerate() {
+        time_ms -= frame_duration.num_milliseconds();
+        if time_ms < 0 {
+            return frame_id;
+        }
+    }
+
+    durations.len() - 1
+}
+
+/// Returns the transform from Quake coordinates to wgpu coordinates.
+#[rustfmt::skip]
+pub fn quake_to_wgpu() -> Matrix4<f32> {
+    Matrix4::new(
+        0.0, 0.0, -1.0, 0.0,
+        -1.0, 0.0, 0.0, 0.0,
+        0.0, 1.0, 0.0, 0.0,
+        0.0, 0.0, 0.0, 1.0,
+    )
+}
+
+/// Create the per-frame bind group, sampling skybox faces from `skybox_view`.
+pub fn create_per_frame_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    frame_uniform_buffer: &wgpu::Buffer,
+    skybox_view: &wgpu::TextureView,
+    skybox_sampler: &wgpu::Sampler,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("per-frame bind group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: frame_uniform_buffer,
+                    offset: 0,
+                    size: None,
+                }),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(skybox_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::Sampler(skybox_sampler),
+            },
+        ],
+    })
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum BindGroupLayoutId {
     PerFrame = 0,
@@ -195,7 +287,7 @@ pub struct Camera {
     view_projection: Matrix4<f32>,
     projection: Matrix4<f32>,
     inverse_projection: Matrix4<f32>,
-    clipping_planes: [Vector4<f32>; 6],
+    frustum: Frustum,
 }
 
 impl Camera {
@@ -209,21 +301,8 @@ impl Camera {
         let view = rotation * translation;
         let view_projection = projection * view;
 
-        // see https://www.gamedevs.org/uploads/fast-extraction-viewing-frustum-planes-from-world-view-projection-matrix.pdf
-        let clipping_planes = [
-            // left
-            view_projection.w + view_projection.x,
-            // right
-            view_projection.w - view_projection.x,
-            // bottom
-            view_projection.w + view_projection.y,
-            // top
-            view_projection.w - view_projection.y,
-            // near
-            view_projection.w + view_projection.z,
-            // far
-            view_projection.w - view_projection.z,
-        ];
+        // cull in Quake coordinates so world and entity bounds can be used as-is
+        let frustum = Frustum::from_matrix(view_projection * quake_to_wgpu());
 
         Camera {
             origin,
@@ -232,7 +311,7 @@ impl Camera {
             view_projection,
             projection,
             inverse_projection: projection.invert().unwrap(),
-            clipping_planes,
+            frustum,
         }
     }
 
@@ -248,6 +327,16 @@ impl Camera {
         self.view
     }
 
+    /// Returns the camera's right and up vectors in Quake coordinates.
+    pub fn right_up(&self) -> (Vector3<f32>, Vector3<f32>) {
+        // the rows of the view rotation are the camera's axes in wgpu coordinates
+        let wgpu_to_quake = |v: Vector4<f32>| Vector3::new(-v.z, -v.x, v.y);
+        (
+            wgpu_to_quake(self.view.row(0)),
+            wgpu_to_quake(self.view.row(1)),
+        )
+    }
+
     pub fn view_projection(&self) -> Matrix4<f32> {
         self.view_projection
     }
@@ -260,16 +349,14 @@ impl Camera {
         self.inverse_projection
     }
 
-    // TODO: this seems to be too lenient
+    /// Returns the viewing frustum in Quake coordinates.
+    pub fn frustum(&self) -> &Frustum {
+        &self.frustum
+    }
+
     /// Determines whether a point falls outside the viewing frustum.
     pub fn cull_point(&self, p: Vector3<f32>) -> bool {
-        for plane in self.clipping_planes.iter() {
-            if (self.view_projection() * p.extend(1.0)).dot(*plane) < 0.0 {
-                return true;
-            }
-        }
-
-        false
+        !self.frustum.contains_point(p)
     }
 }
 
@@ -284,6 +371,7 @@ pub struct FrameUniforms {
 
     // TODO: pack flags into a bit string
     r_lightmap: UniformBool,
+    r_skybox: UniformBool,
 }
 
 #[repr(C, align(256))]
@@ -303,19 +391,85 @@ enum EntityRenderer {
     None,
 }
 
+impl EntityRenderer {
+    fn new(state: &GraphicsState, model: &Model) -> EntityRenderer {
+        match *model.kind() {
+            ModelKind::Alias(ref amodel) => {
+                EntityRenderer::Alias(AliasRenderer::new(state, amodel).unwrap())
+            }
+
+            ModelKind::Brush(ref bmodel) => EntityRenderer::Brush(
+                BrushRendererBuilder::new(bmodel, false)
+                    .build(state)
+                    .unwrap(),
+            ),
+
+            ModelKind::Sprite(ref smodel) => {
+                EntityRenderer::Sprite(SpriteRenderer::new(&state, smodel))
+            }
+
+            _ => {
+                warn!("Non-brush renderers not implemented!");
+                EntityRenderer::None
+            }
+        }
+    }
+}
+
+/// Returns the radius of a sphere around the entity origin that holds all of `model`.
+fn cull_radius(model: &Model) -> f32 {
+    match *model.kind() {
+        ModelKind::Alias(ref amodel) => amodel.radius(),
+        ModelKind::Brush(ref bmodel) => bmodel.min().magnitude().max(bmodel.max().magnitude()),
+        ModelKind::Sprite(ref smodel) => smodel.radius(),
+
+        // never culled
+        _ => f32::INFINITY,
+    }
+}
+
 /// Top-level renderer.
 pub struct WorldRenderer {
     worldmodel_renderer: BrushRenderer,
     entity_renderers: Vec<EntityRenderer>,
 
+    // used for frustum culling, one per entity renderer
+    entity_cull_radii: Vec<f32>,
+
     world_uniform_block: DynamicUniformBufferBlock<EntityUniforms>,
     entity_uniform_blocks: RefCell<Vec<DynamicUniformBufferBlock<EntityUniforms>>>,
+
+    // drawn in place of the scrolling sky if present
+    skybox: Option<Skybox>,
+
+    // the last skybox requested, loaded or not, so failures are only reported once
+    skybox_name: String,
+
+    // reads skybox faces in the background; set while the faces are loading
+    streamer: AssetStreamer,
+    skybox_loading: bool,
+
+    // whether liquids can be drawn translucent without holes (see BspModel::has_water_vis)
+    water_vis: bool,
+    water_vis_warned: Cell<bool>,
+
+    // skins recolored for each player, keyed by colormap
+    player_skins: HashMap<u8, PlayerSkin>,
+}
+
+struct PlayerSkin {
+    model_id: usize,
+    skin_id: usize,
+    colors: PlayerColor,
+    skin: TranslatedSkin,
 }
 
 impl WorldRenderer {
     pub fn new(state: &GraphicsState, models: &[Model], worldmodel_id: usize) -> WorldRenderer {
         let mut worldmodel_renderer = None;
+        let mut water_vis = true;
         let mut entity_renderers = Vec::new();
+        let mut entity_cull_radii = Vec::new();
 
         let world_uniform_block = state.entity_uniform_buffer_mut().allocate(EntityUniforms {
             transform: Matrix4::identity(),
@@ -326,6 +480,7 @@ impl WorldRenderer {
             if i == worldmodel_id {
                 match *model.kind() {
                     ModelKind::Brush(ref bmodel) => {
+                        water_vis = bmodel.has_water_vis();
                         worldmodel_renderer = Some(
                             BrushRendererBuilder::new(bmodel, true)
                                 .build(state)
@@ -335,38 +490,156 @@ impl WorldRenderer {
                     _ => panic!("Invalid worldmodel"),
                 }
             } else {
-                match *model.kind() {
-                    ModelKind::Alias(ref amodel) => entity_renderers.push(EntityRenderer::Alias(
-                        AliasRenderer::new(state, amodel).unwrap(),
-                    )),
-
-                    ModelKind::Brush(ref bmodel) => {
-                        entity_renderers.push(EntityRenderer::Brush(
-                            BrushRendererBuilder::new(bmodel, false)
-                                .build(state)
-                                .unwrap(),
-                        ));
-                    }
-
-                    ModelKind::Sprite(ref smodel) => {
-                        entity_renderers
-                            .push(EntityRenderer::Sprite(SpriteRenderer::new(&state, smodel)));
-                    }
-
-                    _ => {
-                        warn!("Non-brush renderers not implemented!");
-                        entity_renderers.push(EntityRenderer::None);
-                    }
-                }
+                entity_renderers.push(EntityRenderer::new(state, model));
+                entity_cull_radii.push(cull_radius(model));
             }
         }
 
         WorldRenderer {
             worldmodel_renderer: worldmodel_renderer.unwrap(),
             entity_renderers,
+            entity_cull_radii,
             world_uniform_block,
             entity_uniform_blocks: RefCell::new(Vec::new()),
+            skybox: None,
+            skybox_name: String::new(),
+            streamer: AssetStreamer::new(),
+            skybox_loading: false,
+            water_vis,
+            water_vis_warned: Cell::new(false),
+            player_skins: HashMap::new(),
+        }
+    }
+
+    /// Recolors the skins of player entities to match their shirt and pants.
+    ///
+    /// `players` yields the colormap, model id, skin id and colors of each
+    /// entity. Skins are only rebuilt when one of these changes.
+    pub fn update_player_skins<I>(&mut self, state: &GraphicsState, players: I)
+    where
+        I: Iterator<Item = (u8, usize, usize, PlayerColor)>,
+    {
+        for (colormap, model_id, skin_id, colors) in players {
+            if let Some(p) = self.player_skins.get(&colormap) {
+                if p.model_id == model_id && p.skin_id == skin_id && p.colors == colors {
+                    continue;
+                }
+            }
+
+            let alias = match self.entity_renderers.get(model_id.wrapping_sub(1)) {
+                Some(EntityRenderer::Alias(ref alias)) => alias,
+                _ => continue,
+            };
+
+            let translation = palette::player_translation(colors);
+            if let Some(skin) = alias.translate_skin(state, skin_id, &translation) {
+                self.player_skins.insert(
+                    colormap,
+                    PlayerSkin {
+                        model_id,
+                        skin_id,
+                        colors,
+                        skin,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Draws the skybox `name` in place of the scrolling sky.
+    ///
+    /// The faces are loaded in the background, and the scrolling sky is drawn
+    /// until they're ready. This should be called every frame to pick them
+    /// up. `view_angles` decide which faces load first, and `time` is the
+    /// current client time.
+    ///
+    /// An empty name switches back to the scrolling sky, as does a skybox that
+    /// fails to load.
+    pub fn set_skybox(
+        &mut self,
+        state: &GraphicsState,
+        name: &str,
+        view_angles: Angles,
+        time: Duration,
+    ) {
+        if name != self.skybox_name {
+            self.request_skybox(state, name, view_angles, time);
         }
+
+        if !self.skybox_loading {
+            return;
+        }
+
+        self.streamer.update(time);
+        let paths = Skybox::face_paths(&self.skybox_name);
+        if paths.iter().any(|p| self.streamer.is_loading(p)) {
+            return;
+        }
+
+        self.skybox_loading = false;
+        let faces: Result<Vec<Vec<u8>>, _> = paths
+            .iter()
+            .map(|p| self.streamer.take(p).unwrap_or_else(|| Ok(Vec::new())))
+            .collect();
+        let skybox = faces
+            .map_err(Into::into)
+            .and_then(|f| Skybox::new(state, &self.skybox_name, &f));
+        match skybox {
+            Ok(skybox) => self.skybox = Some(skybox),
+            Err(e) => warn!("Couldn't load skybox {}: {}", self.skybox_name, e),
+        }
+    }
+
+    // switch to the skybox `name` and start loading its faces
+    fn request_skybox(
+        &mut self,
+        state: &GraphicsState,
+        name: &str,
+        view_angles: Angles,
+        time: Duration,
+    ) {
+        for path in Skybox::face_paths(&self.skybox_name) {
+            self.streamer.cancel(path);
+        }
+
+        self.skybox_name = name.to_owned();
+        self.skybox = None;
+        self.skybox_loading = false;
+        if name.is_empty() {
+            return;
+        }
+
+        let distances = Skybox::face_distances(view_angles);
+        for (path, distance) in Skybox::face_paths(name).into_iter().zip(distances.iter()) {
+            let vfs = state.vfs();
+            if let Err(e) = self
+                .streamer
+                .request(&path, *distance, time, || vfs.open_owned(&path))
+            {
+                warn!("Couldn't load skybox {}: {}", name, e);
+                for path in Skybox::face_paths(name) {
+                    self.streamer.cancel(path);
+                }
+                return;
+            }
+        }
+
+        self.skybox_loading = true;
+    }
+
+    /// Builds a renderer for a model added to the precache after sign-on.
+    ///
+    /// If a renderer already exists for `model_id`, it is replaced.
+    pub fn set_model(&mut self, state: &GraphicsState, model_id: usize, model: &Model) {
+        // world entity isn't counted (see renderer_for_entity)
+        let index = model_id - 1;
+        while self.entity_renderers.len() <= index {
+            self.entity_renderers.push(EntityRenderer::None);
+            self.entity_cull_radii.push(f32::INFINITY);
+        }
+
+        self.entity_renderers[index] = EntityRenderer::new(state, model);
+        self.entity_cull_radii[index] = cull_radius(model);
     }
 
     pub fn update_uniform_buffers<'a, I>(
@@ -395,6 +668,7 @@ impl WorldRenderer {
                     camera_pos: camera.origin.extend(1.0),
                     time: engine::duration_to_f32(time),
                     r_lightmap: UniformBool::new(cvars.get_value("r_lightmap").unwrap() != 0.0),
+                    r_skybox: UniformBool::new(self.skybox.is_some()),
                 })
             });
 
@@ -437,7 +711,7 @@ impl WorldRenderer {
         entities: E,
         particles: P,
         lightstyle_values: &[f32],
-        viewmodel_id: usize,
+        viewmodel: Option<ViewModel>,
         cvars: &CvarRegistry,
     ) where
         E: Iterator<Item = &'a ClientEntity> + Clone,
@@ -454,13 +728,18 @@ impl WorldRenderer {
             cvars,
         );
 
+        let per_frame_bind_group = match self.skybox {
+            Some(ref skybox) => skybox.bind_group(),
+            None => &state.world_bind_groups()[BindGroupLayoutId::PerFrame as usize],
+        };
         pass.set_bind_group(
             BindGroupLayoutId::PerFrame as u32,
-            &state.world_bind_groups()[BindGroupLayoutId::PerFrame as usize],
+            per_frame_bind_group,
             &[],
         );
 
         // draw world
+        state.stats().begin_section(pass, Section::World);
         info!("Drawing world");
         pass.set_pipeline(state.brush_pipeline().pipeline());
         BrushPipeline::set_push_constants(
@@ -477,12 +756,26 @@ impl WorldRenderer {
             &state.world_bind_groups()[BindGroupLayoutId::PerEntity as usize],
             &[self.world_uniform_block.offset()],
         );
-        self.worldmodel_renderer
-            .record_draw(state, pass, &bump, time, camera, 0);
+        let water_alpha = self.water_alpha(cvars);
+        self.worldmodel_renderer.record_draw(
+            state,
+            pass,
+            &bump,
+            time,
+            camera,
+            0,
+            water_alpha < 1.0,
+        );
 
         // draw entities
         info!("Drawing entities");
+        let lerp_models = cvars.get_value("r_lerpmodels").unwrap_or(1.0) != 0.0;
+        state.stats().begin_section(pass, Section::Entities);
         for (ent_pos, ent) in entities.enumerate() {
+            if self.cull_entity(camera, ent) {
+                continue;
+            }
+
             pass.set_bind_group(
                 BindGroupLayoutId::PerEntity as u32,
                 &state.world_bind_groups()[BindGroupLayoutId::PerEntity as usize],
@@ -501,24 +794,62 @@ impl WorldRenderer {
                         Clear,
                         Clear,
                     );
-                    bmodel.record_draw(state, pass, &bump, time, camera, ent.frame_id);
+                    bmodel.record_draw(state, pass, &bump, time, camera, ent.frame_id, false);
                 }
                 EntityRenderer::Alias(ref alias) => {
+                    let (prev_frame_id, blend) = if lerp_models {
+                        ent.frame_blend(time)
+                    } else {
+                        (ent.frame_id(), 1.0)
+                    };
+
                     pass.set_pipeline(state.alias_pipeline().pipeline());
                     AliasPipeline::set_push_constants(
                         pass,
                         Update(bump.alloc(alias::VertexPushConstants {
                             transform: self.calculate_mvp_transform(camera, ent),
                             model_view: self.calculate_mv_transform(camera, ent),
+                            blend,
                         })),
                         Clear,
                         Clear,
                     );
-                    alias.record_draw(state, pass, time, ent.frame_id(), ent.skin_id());
+                    let translated = ent
+                        .colormap()
+                        .and_then(|c| self.player_skins.get(&c))
+                        .filter(|p| p.model_id == ent.model_id() && p.skin_id == ent.skin_id())
+                        .map(|p| &p.skin);
+
+                    alias.record_draw(
+                        state,
+                        pass,
+                        time,
+                        ent.frame_id(),
+                        prev_frame_id,
+                        ent.skin_id(),
+                        translated,
+                    );
                 }
                 EntityRenderer::Sprite(ref sprite) => {
+                    let model = quake_to_wgpu()
+                        * sprite.model_transform(
+                            camera,
+                            ent.get_origin(),
+                            ent.get_angles(),
+                            ent.frame_id(),
+                            time,
+                        );
+
                     pass.set_pipeline(state.sprite_pipeline().pipeline());
-                    SpritePipeline::set_push_constants(pass, Clear, Clear, Clear);
+                    SpritePipeline::set_push_constants(
+                        pass,
+                        Update(bump.alloc(sprite::VertexPushConstants {
+                            transform: camera.view_projection() * model,
+                            model_view: camera.view() * model,
+                        })),
+                        Clear,
+                        Clear,
+                    );
                     sprite.record_draw(state, pass, ent.frame_id(), time);
                 }
                 _ => warn!("non-brush renderers not implemented!"),
@@ -526,37 +857,109 @@ impl WorldRenderer {
             }
         }
 
-        let viewmodel_orig = camera.origin();
-        let cam_angles = camera.angles();
-        let viewmodel_mat = Matrix4::from_translation(Vector3::new(
-            -viewmodel_orig.y,
-            viewmodel_orig.z,
-            -viewmodel_orig.x,
-        )) * Matrix4::from_angle_y(cam_angles.yaw)
-            * Matrix4::from_angle_x(-cam_angles.pitch)
-            * Matrix4::from_angle_z(cam_angles.roll);
-        match self.entity_renderers[viewmodel_id] {
-            EntityRenderer::Alias(ref alias) => {
-                pass.set_pipeline(state.alias_pipeline().pipeline());
-                AliasPipeline::set_push_constants(
-                    pass,
-                    Update(bump.alloc(alias::VertexPushConstants {
-                        transform: camera.view_projection() * viewmodel_mat,
-                        model_view: camera.view() * viewmodel_mat,
-                    })),
-                    Clear,
-                    Clear,
-                );
-                alias.record_draw(state, pass, time, 0, 0);
-            }
+        // draw translucent liquids over everything behind them
+        if water_alpha < 1.0 {
+            trace!("Drawing translucent liquids");
+            pass.set_blend_color(wgpu::Color {
+                r: water_alpha as f64,
+                g: water_alpha as f64,
+                b: water_alpha as f64,
+                a: water_alpha as f64,
+            });
+            BrushPipeline::set_push_constants(
+                pass,
+                Update(bump.alloc(brush::VertexPushConstants {
+                    transform: camera.view_projection(),
+                    model_view: camera.view(),
+                })),
+                Clear,
+                Clear,
+            );
+            pass.set_bind_group(
+                BindGroupLayoutId::PerEntity as u32,
+                &state.world_bind_groups()[BindGroupLayoutId::PerEntity as usize],
+                &[self.world_uniform_block.offset()],
+            );
+            self.worldmodel_renderer
+                .record_translucent_draw(state, pass, &bump, time, camera);
+        }
 
-            _ => unreachable!("non-alias viewmodel"),
+        // no view model during intermission
+        if let Some(viewmodel) = viewmodel {
+            let viewmodel_orig = viewmodel.origin;
+            let viewmodel_angles = viewmodel.angles;
+            let viewmodel_mat = Matrix4::from_translation(Vector3::new(
+                -viewmodel_orig.y,
+                viewmodel_orig.z,
+                -viewmodel_orig.x,
+            )) * Matrix4::from_angle_y(viewmodel_angles.yaw)
+                * Matrix4::from_angle_x(-viewmodel_angles.pitch)
+                * Matrix4::from_angle_z(viewmodel_angles.roll);
+
+            // world entity isn't counted (see renderer_for_entity)
+            match self.entity_renderers.get(viewmodel.model_id - 1) {
+                Some(EntityRenderer::Alias(ref alias)) => {
+                    pass.set_pipeline(state.alias_pipeline().pipeline());
+                    AliasPipeline::set_push_constants(
+                        pass,
+                        Update(bump.alloc(alias::VertexPushConstants {
+                            transform: camera.view_projection() * viewmodel_mat,
+                            model_view: camera.view() * viewmodel_mat,
+                            blend: 1.0,
+                        })),
+                        Clear,
+                        Clear,
+                    );
+                    alias.record_draw(
+                        state,
+                        pass,
+                        time,
+                        viewmodel.frame_id,
+                        viewmodel.frame_id,
+                        0,
+                        None,
+                    );
+                }
+
+                _ => debug!("View model {} is not an alias model", viewmodel.model_id),
+            }
         }
 
         log::debug!("Drawing particles");
-        state
-            .particle_pipeline()
-            .record_draw(pass, &bump, camera, particles);
+        state.stats().begin_section(pass, Section::Particles);
+        state.particle_pipeline().record_draw(
+            pass,
+            &bump,
+            camera,
+            particles.inspect(|_| state.stats().count_draw(6, 1)),
+        );
+    }
+
+    /// Returns the opacity of liquid surfaces.
+    ///
+    /// Liquids are drawn opaque if the map wasn't vised for translucent water.
+    fn water_alpha(&self, cvars: &CvarRegistry) -> f32 {
+        let alpha = cvars
+            .get_value("r_wateralpha")
+            .unwrap_or(1.0)
+            .max(0.0)
+            .min(1.0);
+
+        if alpha < 1.0 && !self.water_vis {
+            if !self.water_vis_warned.replace(true) {
+                warn!("Map isn't vised for translucent water, ignoring r_wateralpha");
+            }
+
+            return 1.0;
+        }
+
+        alpha
+    }
+
+    /// Returns `true` if `ent` is entirely outside the view frustum.
+    fn cull_entity(&self, camera: &Camera, ent: &ClientEntity) -> bool {
+        let radius = self.entity_cull_radii[ent.model_id() - 1];
+        camera.frustum().cull_sphere(ent.get_origin(), radius)
     }
 
     fn renderer_for_entity(&self, ent: &ClientEntity) -> &EntityRenderer {
@@ -565,40 +968,21 @@ impl WorldRenderer {
     }
 
     fn calculate_mvp_transform(&self, camera: &Camera, entity: &ClientEntity) -> Matrix4<f32> {
-        let model_transform = self.calculate_model_transform(camera, entity);
+        let model_transform = self.calculate_model_transform(entity);
 
         camera.view_projection() * model_transform
     }
 
     fn calculate_mv_transform(&self, camera: &Camera, entity: &ClientEntity) -> Matrix4<f32> {
-        let model_transform = self.calculate_model_transform(camera, entity);
+        let model_transform = self.calculate_model_transform(entity);
 
         camera.view() * model_transform
     }
 
-    fn calculate_model_transform(&self, camera: &Camera, entity: &ClientEntity) -> Matrix4<f32> {
+    fn calculate_model_transform(&self, entity: &ClientEntity) -> Matrix4<f32> {
         let origin = entity.get_origin();
         let angles = entity.get_angles();
-        let rotation = match self.renderer_for_entity(entity) {
-            EntityRenderer::Sprite(ref sprite) => match sprite.kind() {
-                // used for decals
-                SpriteKind::Oriented => Matrix4::from(Euler::new(angles.z, -angles.x, angles.y)),
-
-                _ => {
-                    // keep sprite facing player, but preserve roll
-                    let cam_angles = camera.angles();
-
-                    Angles {
-                        pitch: -cam_angles.pitch,
-                        roll: angles.x,
-                        yaw: -cam_angles.yaw,
-                    }
-                    .mat4_quake()
-                }
-            },
-
-            _ => Matrix4::from(Euler::new(angles.x, angles.y, angles.z)),
-        };
+        let rotation = Matrix4::from(Euler::new(angles.x, angles.y, angles.z));
 
         Matrix4::from_translation(Vector3::new(-origin.y, origin.z, -origin.x)) * rotation
     }
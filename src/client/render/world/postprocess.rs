@@ -5,10 +5,32 @@ use crate::{
     common::util::any_as_bytes,
 };
 
+/// How the 3D view is scaled up to the window when `r_scale` is below 1.
+///
+/// Views rendered above the window size are always box-filtered down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpscaleFilter {
+    Nearest = 0,
+    /// Bilinear filtering followed by contrast-adaptive sharpening.
+    Sharp = 1,
+}
+
+impl UpscaleFilter {
+    pub fn from_cvar(value: f32) -> UpscaleFilter {
+        if value as i32 == 1 {
+            UpscaleFilter::Sharp
+        } else {
+            UpscaleFilter::Nearest
+        }
+    }
+}
+
 #[repr(C, align(256))]
 #[derive(Clone, Copy, Debug)]
 pub struct PostProcessUniforms {
     pub color_shift: [f32; 4],
+    pub upscale_filter: u32,
+    pub sharpness: f32,
 }
 
 pub struct PostProcessPipeline {
@@ -31,6 +53,8 @@ impl PostProcessPipeline {
             contents: unsafe {
                 any_as_bytes(&PostProcessUniforms {
                     color_shift: [0.0; 4],
+                    upscale_filter: UpscaleFilter::Nearest as u32,
+                    sharpness: 0.0,
                 })
             },
             usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
@@ -198,12 +222,22 @@ impl PostProcessRenderer {
         self.bind_group = Self::create_bind_group(state, color_buffer);
     }
 
-    pub fn update_uniform_buffers(&self, state: &GraphicsState, color_shift: [f32; 4]) {
-        // update color shift
+    pub fn update_uniform_buffers(
+        &self,
+        state: &GraphicsState,
+        color_shift: [f32; 4],
+        upscale_filter: UpscaleFilter,
+        sharpness: f32,
+    ) {
+        // update color shift and upscale settings
         state
             .queue()
             .write_buffer(state.postprocess_pipeline().uniform_buffer(), 0, unsafe {
-                any_as_bytes(&PostProcessUniforms { color_shift })
+                any_as_bytes(&PostProcessUniforms {
+                    color_shift,
+                    upscale_filter: upscale_filter as u32,
+                    sharpness: sharpness.max(0.0).min(1.0),
+                })
             });
     }
 
@@ -212,11 +246,14 @@ impl PostProcessRenderer {
         state: &'pass GraphicsState,
         pass: &mut wgpu::RenderPass<'pass>,
         color_shift: [f32; 4],
+        upscale_filter: UpscaleFilter,
+        sharpness: f32,
     ) {
-        self.update_uniform_buffers(state, color_shift);
+        self.update_uniform_buffers(state, color_shift, upscale_filter, sharpness);
         pass.set_pipeline(state.postprocess_pipeline().pipeline());
         pass.set_vertex_buffer(0, state.quad_pipeline().vertex_buffer().slice(..));
         pass.set_bind_group(0, &self.bind_group, &[]);
         pass.draw(0..6, 0..1);
+        state.stats().count_draw(6, 1);
     }
 }
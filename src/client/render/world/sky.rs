@@ -0,0 +1,255 @@
+// Copyright © 2020 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Six-sided skyboxes, drawn in place of the scrolling sky.
+//!
+//! A skybox named `foo` is loaded from `env/foort.tga`, `env/foolf.tga` and so
+//! on, one image per face. The faces are read in the background by the world
+//! renderer's asset streamer and stored as the layers of a single texture
+//! array which the brush shader samples by view direction.
+
+use std::num::NonZeroU32;
+
+use crate::{
+    client::render::{
+        world::{create_per_frame_bind_group, BindGroupLayoutId},
+        GraphicsState, DIFFUSE_TEXTURE_FORMAT,
+    },
+    common::{
+        math::{self, Angles},
+        tga::Tga,
+    },
+};
+
+use cgmath::{InnerSpace as _, Vector3};
+use failure::Error;
+
+/// The filename suffix of each face, in layer order.
+pub const SKYBOX_SUFFIXES: [&str; 6] = ["rt", "lf", "bk", "ft", "up", "dn"];
+
+// the direction from the view to the center of each face, in layer order
+const SKYBOX_NORMALS: [[f32; 3]; 6] = [
+    [1.0, 0.0, 0.0],
+    [-1.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0],
+    [0.0, -1.0, 0.0],
+    [0.0, 0.0, 1.0],
+    [0.0, 0.0, -1.0],
+];
+
+/// The largest skybox face size, in pixels.
+///
+/// wgpu 0.8 doesn't report the device's texture size limit, so this is the
+/// `maxTextureDimension2D` every WebGPU device must support.
+pub const MAX_SKYBOX_SIZE: u32 = 8192;
+
+/// Create a texture array with one layer per skybox face.
+///
+/// `faces` holds the RGBA data of each `size`x`size` face in the order given by
+/// `SKYBOX_SUFFIXES`.
+pub fn create_skybox_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    size: u32,
+    faces: &[&[u8]],
+) -> wgpu::Texture {
+    assert_eq!(faces.len(), SKYBOX_SUFFIXES.len());
+
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("skybox texture"),
+        size: wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: faces.len() as u32,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: DIFFUSE_TEXTURE_FORMAT,
+        usage: wgpu::TextureUsage::COPY_DST | wgpu::TextureUsage::SAMPLED,
+    });
+
+    for (layer, face) in faces.iter().enumerate() {
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: 0,
+                    y: 0,
+                    z: layer as u32,
+                },
+            },
+            face,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(size * 4),
+                rows_per_image: None,
+            },
+            wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    texture
+}
+
+pub fn create_skybox_view(texture: &wgpu::Texture) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::D2Array),
+        ..Default::default()
+    })
+}
+
+pub struct Skybox {
+    name: String,
+
+    // kept alive for the view
+    _texture: wgpu::Texture,
+    _view: wgpu::TextureView,
+
+    // the per-frame bind group, with this skybox in place of the default
+    bind_group: wgpu::BindGroup,
+}
+
+impl Skybox {
+    /// Return the paths of the faces of the skybox `name`, in layer order.
+    pub fn face_paths(name: &str) -> Vec<String> {
+        SKYBOX_SUFFIXES
+            .iter()
+            .map(|suffix| format!("env/{}{}.tga", name, suffix))
+            .collect()
+    }
+
+    /// Return the distance from the point the view is looking at to the center
+    /// of each face, on a sky of unit radius around the view origin, in the
+    /// order given by `face_paths`.
+    pub fn face_distances(view_angles: Angles) -> [f32; 6] {
+        let (forward, _, _) = math::angle_vectors(Vector3::new(
+            view_angles.pitch,
+            view_angles.yaw,
+            view_angles.roll,
+        ));
+
+        let mut distances = [0.0; 6];
+        for (distance, normal) in distances.iter_mut().zip(SKYBOX_NORMALS.iter()) {
+            *distance = (forward - Vector3::from(*normal)).magnitude();
+        }
+
+        distances
+    }
+
+    /// Create the skybox `name` from the contents of its face images, in the
+    /// order given by `face_paths`.
+    ///
+    /// All six faces must be square and the same size, and no larger than
+    /// `MAX_SKYBOX_SIZE`.
+    pub fn new(state: &GraphicsState, name: &str, faces: &[Vec<u8>]) -> Result<Skybox, Error> {
+        ensure!(
+            faces.len() == SKYBOX_SUFFIXES.len(),
+            "Skybox {} has {} faces",
+            name,
+            faces.len()
+        );
+
+        let mut images = Vec::with_capacity(SKYBOX_SUFFIXES.len());
+        for (path, face) in Skybox::face_paths(name).iter().zip(faces.iter()) {
+            let image = Tga::load(face.as_slice())?;
+            if image.width() != image.height() {
+                bail!(
+                    "{} is not square ({}x{})",
+                    path,
+                    image.width(),
+                    image.height()
+                );
+            }
+
+            images.push(image);
+        }
+
+        let size = images[0].width();
+        if images.iter().any(|i| i.width() != size) {
+            bail!("Skybox {} has faces of different sizes", name);
+        }
+
+        if size == 0 || size > MAX_SKYBOX_SIZE {
+            bail!(
+                "Skybox {} has an unsupported face size ({}, maximum {})",
+                name,
+                size,
+                MAX_SKYBOX_SIZE
+            );
+        }
+
+        let faces: Vec<&[u8]> = images.iter().map(|i| i.rgba()).collect();
+        let texture = create_skybox_texture(state.device(), state.queue(), size, &faces);
+        let view = create_skybox_view(&texture);
+        let bind_group = create_per_frame_bind_group(
+            state.device(),
+            &state.world_bind_group_layouts()[BindGroupLayoutId::PerFrame as usize],
+            state.frame_uniform_buffer(),
+            &view,
+            state.skybox_sampler(),
+        );
+
+        Ok(Skybox {
+            name: name.to_owned(),
+            _texture: texture,
+            _view: view,
+            bind_group,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use cgmath::Deg;
+
+    #[test]
+    fn test_face_distances() {
+        let ahead = Skybox::face_distances(Angles::zero());
+        assert!(ahead[0].abs() < 0.001);
+        assert!((ahead[1] - 2.0).abs() < 0.001);
+
+        // looking straight up, the up face is nearest
+        let up = Skybox::face_distances(Angles {
+            pitch: Deg(-90.0),
+            roll: Deg(0.0),
+            yaw: Deg(0.0),
+        });
+        let nearest = (0..6)
+            .min_by(|a, b| up[*a].partial_cmp(&up[*b]).unwrap())
+            .unwrap();
+        assert_eq!(SKYBOX_SUFFIXES[nearest], "up");
+    }
+}
@@ -2,15 +2,17 @@ use std::mem::size_of;
 
 use crate::{
     client::render::{
-        world::{BindGroupLayoutId, WorldPipelineBase},
+        world::{group_frame, BindGroupLayoutId, Camera, WorldPipelineBase},
         GraphicsState, Pipeline, TextureData,
     },
     common::{
+        math,
         sprite::{SpriteFrame, SpriteKind, SpriteModel, SpriteSubframe},
         util::any_slice_as_bytes,
     },
 };
 
+use cgmath::{Angle as _, Deg, InnerSpace as _, Matrix4, Vector3};
 use chrono::Duration;
 
 pub struct SpritePipeline {
@@ -70,6 +72,13 @@ impl SpritePipeline {
     }
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct VertexPushConstants {
+    pub transform: Matrix4<f32>,
+    pub model_view: Matrix4<f32>,
+}
+
 lazy_static! {
     static ref VERTEX_BUFFER_ATTRIBUTES: [wgpu::VertexAttribute; 3] =
         wgpu::vertex_attr_array![
@@ -83,7 +92,7 @@ lazy_static! {
 }
 
 impl Pipeline for SpritePipeline {
-    type VertexPushConstants = ();
+    type VertexPushConstants = VertexPushConstants;
     type SharedPushConstants = ();
     type FragmentPushConstants = ();
 
@@ -191,16 +200,70 @@ pub const VERTICES: [SpriteVertex; 6] = [
     },
 ];
 
+/// The extent of a subframe around the sprite's origin, in world units.
+#[derive(Clone, Copy, Debug)]
+struct SubframeBounds {
+    left: f32,
+    right: f32,
+    down: f32,
+    up: f32,
+}
+
+impl SubframeBounds {
+    fn new(subframe: &SpriteSubframe) -> SubframeBounds {
+        SubframeBounds {
+            left: subframe.left(),
+            right: subframe.right(),
+            down: subframe.down(),
+            up: subframe.up(),
+        }
+    }
+}
+
+struct Subframe {
+    #[allow(dead_code)]
+    diffuse: wgpu::Texture,
+    #[allow(dead_code)]
+    diffuse_view: wgpu::TextureView,
+    bind_group: wgpu::BindGroup,
+    bounds: SubframeBounds,
+}
+
+impl Subframe {
+    fn new(state: &GraphicsState, subframe: &SpriteSubframe) -> Subframe {
+        let (diffuse_data, _fullbright_data) = state.palette.translate(subframe.indexed());
+        let diffuse = state.create_texture(
+            None,
+            subframe.width(),
+            subframe.height(),
+            &TextureData::Diffuse(diffuse_data),
+        );
+        let diffuse_view = diffuse.create_view(&Default::default());
+        let bind_group = state
+            .device()
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &state.sprite_pipeline().bind_group_layouts()
+                    [BindGroupLayoutId::PerTexture as usize - 2],
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&diffuse_view),
+                }],
+            });
+
+        Subframe {
+            diffuse,
+            diffuse_view,
+            bind_group,
+            bounds: SubframeBounds::new(subframe),
+        }
+    }
+}
+
 enum Frame {
-    Static {
-        diffuse: wgpu::Texture,
-        diffuse_view: wgpu::TextureView,
-        bind_group: wgpu::BindGroup,
-    },
+    Static(Subframe),
     Animated {
-        diffuses: Vec<wgpu::Texture>,
-        diffuse_views: Vec<wgpu::TextureView>,
-        bind_groups: Vec<wgpu::BindGroup>,
+        subframes: Vec<Subframe>,
         total_duration: Duration,
         durations: Vec<Duration>,
     },
@@ -208,67 +271,18 @@ enum Frame {
 
 impl Frame {
     fn new(state: &GraphicsState, sframe: &SpriteFrame) -> Frame {
-        fn convert_subframe(
-            state: &GraphicsState,
-            subframe: &SpriteSubframe,
-        ) -> (wgpu::Texture, wgpu::TextureView, wgpu::BindGroup) {
-            let (diffuse_data, _fullbright_data) = state.palette.translate(subframe.indexed());
-            let diffuse = state.create_texture(
-                None,
-                subframe.width(),
-                subframe.height(),
-                &TextureData::Diffuse(diffuse_data),
-            );
-            let diffuse_view = diffuse.create_view(&Default::default());
-            let bind_group = state
-                .device()
-                .create_bind_group(&wgpu::BindGroupDescriptor {
-                    label: None,
-                    layout: &state.sprite_pipeline().bind_group_layouts()
-                        [BindGroupLayoutId::PerTexture as usize - 2],
-                    entries: &[wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::TextureView(&diffuse_view),
-                    }],
-                });
-            (diffuse, diffuse_view, bind_group)
-        }
-
         match sframe {
-            SpriteFrame::Static { frame } => {
-                let (diffuse, diffuse_view, bind_group) = convert_subframe(state, frame);
-
-                Frame::Static {
-                    diffuse,
-                    diffuse_view,
-                    bind_group,
-                }
-            }
+            SpriteFrame::Static { frame } => Frame::Static(Subframe::new(state, frame)),
 
             SpriteFrame::Animated {
                 subframes,
                 durations,
             } => {
-                let mut diffuses = Vec::new();
-                let mut diffuse_views = Vec::new();
-                let mut bind_groups = Vec::new();
-
-                let _ = subframes
-                    .iter()
-                    .map(|subframe| {
-                        let (diffuse, diffuse_view, bind_group) = convert_subframe(state, subframe);
-                        diffuses.push(diffuse);
-                        diffuse_views.push(diffuse_view);
-                        bind_groups.push(bind_group);
-                    })
-                    .count(); // count to consume the iterator
-
+                let subframes = subframes.iter().map(|s| Subframe::new(state, s)).collect();
                 let total_duration = durations.iter().fold(Duration::zero(), |init, d| init + *d);
 
                 Frame::Animated {
-                    diffuses,
-                    diffuse_views,
-                    bind_groups,
+                    subframes,
                     total_duration,
                     durations: durations.clone(),
                 }
@@ -276,25 +290,14 @@ impl Frame {
         }
     }
 
-    fn animate(&self, time: Duration) -> &wgpu::BindGroup {
+    fn animate(&self, time: Duration) -> &Subframe {
         match self {
-            Frame::Static { bind_group, .. } => &bind_group,
+            Frame::Static(subframe) => subframe,
             Frame::Animated {
-                bind_groups,
+                subframes,
                 total_duration,
                 durations,
-                ..
-            } => {
-                let mut time_ms = time.num_milliseconds() % total_duration.num_milliseconds();
-                for (i, d) in durations.iter().enumerate() {
-                    time_ms -= d.num_milliseconds();
-                    if time_ms <= 0 {
-                        return &bind_groups[i];
-                    }
-                }
-
-                unreachable!()
-            }
+            } => &subframes[group_frame(durations, *total_duration, time)],
         }
     }
 }
@@ -318,6 +321,91 @@ impl SpriteRenderer {
         }
     }
 
+    fn frame(&self, frame_id: usize) -> &Frame {
+        match self.frames.get(frame_id) {
+            Some(f) => f,
+            None => {
+                debug!("No such sprite frame: {}", frame_id);
+                &self.frames[0]
+            }
+        }
+    }
+
+    /// Returns the right and up vectors of the sprite's plane, in Quake
+    /// coordinates, according to its orientation mode.
+    fn axes(
+        &self,
+        camera: &Camera,
+        origin: Vector3<f32>,
+        angles: Vector3<Deg<f32>>,
+    ) -> (Vector3<f32>, Vector3<f32>) {
+        let (view_right, view_up) = camera.right_up();
+
+        match self.kind {
+            // face the view plane, but stay upright
+            SpriteKind::ViewPlaneParallelUpright => {
+                let right = Vector3::new(view_right.x, view_right.y, 0.0);
+                if right.magnitude2() < 0.0001 {
+                    return (view_right, view_up);
+                }
+                (right.normalize(), Vector3::unit_z())
+            }
+
+            // stay upright and turn to face the viewer
+            SpriteKind::Upright => {
+                let to_viewer = camera.origin() - origin;
+                let right = Vector3::new(to_viewer.y, -to_viewer.x, 0.0);
+                if right.magnitude2() < 0.0001 {
+                    return (view_right, view_up);
+                }
+                (right.normalize(), Vector3::unit_z())
+            }
+
+            SpriteKind::ViewPlaneParallel => (view_right, view_up),
+
+            SpriteKind::Oriented => {
+                let (_, right, up) = math::angle_vectors(angles);
+                (right, up)
+            }
+
+            // face the view plane, rolled by the entity's roll angle
+            SpriteKind::ViewPlaneParallelOriented => {
+                let (sr, cr) = angles.z.sin_cos();
+                (
+                    view_right * cr + view_up * sr,
+                    view_right * -sr + view_up * cr,
+                )
+            }
+        }
+    }
+
+    /// Returns the transform from the unit quad in the sprite pipeline's vertex
+    /// buffer to the current frame of the sprite, in Quake coordinates.
+    pub fn model_transform(
+        &self,
+        camera: &Camera,
+        origin: Vector3<f32>,
+        angles: Vector3<Deg<f32>>,
+        frame_id: usize,
+        time: Duration,
+    ) -> Matrix4<f32> {
+        let SubframeBounds {
+            left,
+            right,
+            down,
+            up,
+        } = self.frame(frame_id).animate(time).bounds;
+        let (right_axis, up_axis) = self.axes(camera, origin, angles);
+        let corner = origin + right_axis * left + up_axis * down;
+
+        Matrix4::from_cols(
+            (right_axis * (right - left)).extend(0.0),
+            (up_axis * (up - down)).extend(0.0),
+            right_axis.cross(up_axis).extend(0.0),
+            corner.extend(1.0),
+        )
+    }
+
     pub fn record_draw<'a>(
         &'a self,
         state: &'a GraphicsState,
@@ -329,10 +417,11 @@ impl SpriteRenderer {
         pass.set_vertex_buffer(0, state.sprite_pipeline().vertex_buffer().slice(..));
         pass.set_bind_group(
             BindGroupLayoutId::PerTexture as u32,
-            self.frames[frame_id].animate(time),
+            &self.frame(frame_id).animate(time).bind_group,
             &[],
         );
         pass.draw(0..VERTICES.len() as u32, 0..1);
+        state.stats().count_draw(VERTICES.len() as u32, 1);
     }
 
     pub fn kind(&self) -> SpriteKind {
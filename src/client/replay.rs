@@ -0,0 +1,440 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Headless demo replay.
+//!
+//! A [`DemoReplay`] steps through a demo at a fixed frame rate without a
+//! renderer, sound or game data, applying the same message timing and entity
+//! interpolation as the client. The view origin and stats of every frame are
+//! hashed so that a replay can be compared against a stored baseline.
+
+use std::io::Read;
+
+use crate::{
+    client::{
+        demo::{DemoReader, DemoServerError},
+        entity::ClientEntity,
+        state::{apply_player_stats, lerp_factor},
+        MAX_STATS,
+    },
+    common::{
+        engine,
+        net::{self, EntityState, ServerCmd},
+    },
+};
+
+use cgmath::Vector3;
+use chrono::Duration;
+
+/// The frame rate demos are replayed at.
+pub const REPLAY_FPS: i64 = 72;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// The client state at the end of one replayed frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplayFrame {
+    pub time: Duration,
+    pub view_origin: Vector3<f32>,
+    pub stats: [i32; MAX_STATS],
+}
+
+impl ReplayFrame {
+    /// Returns a hash of the frame's time, view origin and stats.
+    ///
+    /// This uses FNV-1a over the exact bit patterns of each value, so it is
+    /// stable across platforms and compiler versions.
+    pub fn hash(&self) -> u64 {
+        let mut hash = FNV_OFFSET_BASIS;
+        let mut write = |bytes: &[u8]| {
+            for b in bytes {
+                hash ^= *b as u64;
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        };
+
+        write(&self.time.num_milliseconds().to_le_bytes());
+        for c in 0..3 {
+            write(&self.view_origin[c].to_bits().to_le_bytes());
+        }
+        for stat in self.stats.iter() {
+            write(&stat.to_le_bytes());
+        }
+
+        hash
+    }
+}
+
+/// Replays a demo one client frame at a time.
+pub struct DemoReplay<R>
+where
+    R: Read,
+{
+    reader: DemoReader<R>,
+    frame_time: Duration,
+
+    time: Duration,
+    msg_times: [Duration; 2],
+    entities: Vec<ClientEntity>,
+    view_entity_id: usize,
+    view_height: f32,
+    stats: [i32; MAX_STATS],
+
+    done: bool,
+}
+
+impl<R> DemoReplay<R>
+where
+    R: Read,
+{
+    /// Constructs a new `DemoReplay`, reading the demo header from `reader`.
+    pub fn new(reader: R) -> Result<DemoReplay<R>, DemoServerError> {
+        Ok(DemoReplay {
+            reader: DemoReader::new(reader)?,
+            frame_time: Duration::nanoseconds(1_000_000_000 / REPLAY_FPS),
+            time: Duration::zero(),
+            msg_times: [Duration::zero(); 2],
+            entities: Vec::new(),
+            view_entity_id: 0,
+            view_height: net::DEFAULT_VIEWHEIGHT,
+            stats: [0; MAX_STATS],
+            done: false,
+        })
+    }
+
+    fn entity_mut(&mut self, id: usize) -> &mut ClientEntity {
+        if id >= self.entities.len() {
+            self.entities
+                .resize_with(id + 1, ClientEntity::uninitialized);
+        }
+
+        &mut self.entities[id]
+    }
+
+    fn apply(&mut self, cmd: ServerCmd) {
+        match cmd {
+            ServerCmd::Time { time } => {
+                self.msg_times[1] = self.msg_times[0];
+                self.msg_times[0] = engine::duration_from_f32(time);
+            }
+
+            ServerCmd::SpawnBaseline {
+                ent_id,
+                model_id,
+                frame_id,
+                colormap,
+                skin_id,
+                origin,
+                angles,
+            } => {
+                *self.entity_mut(ent_id as usize) = ClientEntity::from_baseline(EntityState {
+                    model_id: model_id as usize,
+                    frame_id: frame_id as usize,
+                    colormap,
+                    skin_id: skin_id as usize,
+                    origin,
+                    angles,
+                    effects: net::EntityEffects::empty(),
+                });
+            }
+
+            ServerCmd::FastUpdate(update) => {
+                let id = update.ent_id as usize;
+                if id >= self.entities.len() {
+                    // like the client, spawn unknown entities from their first update
+                    let mut baseline = update.to_entity_state(&EntityState::uninitialized());
+                    baseline.effects = net::EntityEffects::empty();
+                    *self.entity_mut(id) = ClientEntity::from_baseline(baseline);
+                }
+
+                self.entities[id].update(self.msg_times, update);
+            }
+
+            ServerCmd::SetView { ent_id } if ent_id > 0 => {
+                self.view_entity_id = ent_id as usize;
+            }
+
+            ServerCmd::UpdateStat { stat, value } => self.stats[stat as usize] = value,
+
+            ServerCmd::PlayerData(update) => {
+                self.view_height = update.view_height.unwrap_or(net::DEFAULT_VIEWHEIGHT);
+                apply_player_stats(&mut self.stats, &update);
+            }
+
+            ServerCmd::KilledMonster => self.stats[net::ClientStat::KilledMonsters as usize] += 1,
+
+            ServerCmd::FoundSecret => self.stats[net::ClientStat::FoundSecrets as usize] += 1,
+
+            _ => (),
+        }
+    }
+
+    fn next_frame(&mut self) -> Result<Option<ReplayFrame>, DemoServerError> {
+        self.time = self.time + self.frame_time;
+
+        // only read the next message once we've reached the previous one
+        if self.time >= self.msg_times[0] {
+            match self.reader.next() {
+                Some(frame) => {
+                    let (_, _, cmds) = frame?.into_parts();
+                    for cmd in cmds {
+                        self.apply(cmd);
+                    }
+                }
+
+                None => return Ok(None),
+            }
+        }
+
+        let lerp_factor = lerp_factor(&mut self.time, &mut self.msg_times);
+        for ent in self.entities.iter_mut().skip(1) {
+            if ent.model_id == 0 {
+                continue;
+            }
+
+            // entities that weren't in the last message are removed
+            if ent.msg_time != self.msg_times[0] {
+                ent.model_id = 0;
+                continue;
+            }
+
+            ent.lerp(lerp_factor);
+        }
+
+        let view_origin = match self.entities.get(self.view_entity_id) {
+            Some(ent) => ent.origin + Vector3::new(0.0, 0.0, self.view_height),
+            None => Vector3::new(0.0, 0.0, 0.0),
+        };
+
+        Ok(Some(ReplayFrame {
+            time: self.time,
+            view_origin,
+            stats: self.stats,
+        }))
+    }
+}
+
+impl<R> Iterator for DemoReplay<R>
+where
+    R: Read,
+{
+    type Item = Result<ReplayFrame, DemoServerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.next_frame() {
+            Ok(Some(frame)) => Some(Ok(frame)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Formats frame hashes as a baseline, one `time_ms hash` pair per line.
+pub fn write_baseline(frames: &[ReplayFrame]) -> String {
+    let mut baseline = String::new();
+    for frame in frames {
+        baseline.push_str(&format!(
+            "{} {:016x}\n",
+            frame.time.num_milliseconds(),
+            frame.hash()
+        ));
+    }
+    baseline
+}
+
+/// Compares replayed frames against a baseline produced by [`write_baseline`].
+///
+/// Returns a description of the first difference, if any.
+pub fn compare_baseline(frames: &[ReplayFrame], baseline: &str) -> Option<String> {
+    let expected: Vec<&str> = baseline.lines().filter(|l| !l.is_empty()).collect();
+    let actual = write_baseline(frames);
+    let actual: Vec<&str> = actual.lines().collect();
+
+    for (frame_id, (a, e)) in actual.iter().zip(expected.iter()).enumerate() {
+        if a != e {
+            return Some(format!(
+                "frame {}: expected \"{}\", got \"{}\" ({:?})",
+                frame_id, e, a, frames[frame_id]
+            ));
+        }
+    }
+
+    if actual.len() != expected.len() {
+        return Some(format!(
+            "expected {} frames, got {}",
+            expected.len(),
+            actual.len()
+        ));
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{fs, path::Path};
+
+    use crate::common::net::{ClientStat, EntityUpdate};
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use cgmath::Deg;
+
+    fn write_demo_message(demo: &mut Vec<u8>, cmds: &[ServerCmd]) {
+        let mut msg = Vec::new();
+        for cmd in cmds {
+            cmd.serialize(&mut msg).unwrap();
+        }
+
+        demo.write_u32::<LittleEndian>(msg.len() as u32).unwrap();
+        for _ in 0..3 {
+            demo.write_f32::<LittleEndian>(0.0).unwrap();
+        }
+        demo.extend_from_slice(&msg);
+    }
+
+    fn move_to(ent_id: u16, x: f32) -> ServerCmd {
+        let mut update = EntityUpdate::between(
+            ent_id,
+            &EntityState::uninitialized(),
+            &EntityState::uninitialized(),
+        );
+        update.origin_x = Some(x);
+        ServerCmd::FastUpdate(update)
+    }
+
+    fn replay(demo: &[u8]) -> Vec<ReplayFrame> {
+        DemoReplay::new(demo)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_replay_interpolates_view_origin() {
+        let mut demo = b"-1\n".to_vec();
+        write_demo_message(
+            &mut demo,
+            &[
+                ServerCmd::SpawnBaseline {
+                    ent_id: 1,
+                    model_id: 1,
+                    frame_id: 0,
+                    colormap: 0,
+                    skin_id: 0,
+                    origin: Vector3::new(0.0, 0.0, 0.0),
+                    angles: Vector3::new(Deg(0.0), Deg(0.0), Deg(0.0)),
+                },
+                ServerCmd::SetView { ent_id: 1 },
+                ServerCmd::UpdateStat {
+                    stat: ClientStat::Health,
+                    value: 100,
+                },
+            ],
+        );
+        write_demo_message(&mut demo, &[ServerCmd::Time { time: 0.0 }, move_to(1, 0.0)]);
+        write_demo_message(
+            &mut demo,
+            &[ServerCmd::Time { time: 0.1 }, move_to(1, 64.0)],
+        );
+
+        let frames = replay(&demo);
+        assert!(frames
+            .iter()
+            .all(|f| f.stats[ClientStat::Health as usize] == 100));
+
+        // the view origin moves smoothly from the first update to the second
+        let last = frames.last().unwrap();
+        assert_eq!(last.view_origin.z, net::DEFAULT_VIEWHEIGHT);
+        let xs: Vec<f32> = frames.iter().map(|f| f.view_origin.x).collect();
+        assert!(xs.windows(2).all(|w| w[0] <= w[1]));
+        assert!(xs.iter().any(|x| *x > 0.0 && *x < 64.0));
+    }
+
+    #[test]
+    fn test_compare_baseline() {
+        let frame = ReplayFrame {
+            time: Duration::milliseconds(14),
+            view_origin: Vector3::new(1.0, 2.0, 3.0),
+            stats: [0; MAX_STATS],
+        };
+        let frames = vec![frame.clone(), frame.clone()];
+        let baseline = write_baseline(&frames);
+        assert_eq!(compare_baseline(&frames, &baseline), None);
+
+        let mut changed = frames.clone();
+        changed[1].stats[ClientStat::Health as usize] = 1;
+        assert!(compare_baseline(&changed, &baseline)
+            .unwrap()
+            .starts_with("frame 1"));
+
+        assert!(compare_baseline(&frames[..1], &baseline).is_some());
+    }
+
+    /// Replays each demo in `demos/` and compares it against the baseline
+    /// stored next to it.
+    ///
+    /// A missing or different baseline fails the test. When
+    /// `RICHTER_BLESS_DEMOS` is set, all baselines are written instead.
+    #[test]
+    fn test_demo_baselines() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("demos");
+        let bless = std::env::var_os("RICHTER_BLESS_DEMOS").is_some();
+
+        let mut failures = Vec::new();
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map(|e| e != "dem").unwrap_or(true) {
+                continue;
+            }
+
+            let frames = replay(&fs::read(&path).unwrap());
+            let baseline_path = path.with_extension("baseline");
+            if bless {
+                fs::write(&baseline_path, write_baseline(&frames)).unwrap();
+                continue;
+            }
+
+            match fs::read_to_string(&baseline_path) {
+                Ok(baseline) => {
+                    if let Some(diff) = compare_baseline(&frames, &baseline) {
+                        failures.push(format!("{}: {}", path.display(), diff));
+                    }
+                }
+
+                Err(e) => failures.push(format!("{}: {}", baseline_path.display(), e)),
+            }
+        }
+
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+}
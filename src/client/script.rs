@@ -0,0 +1,412 @@
+// Copyright © 2020 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Client-side scripting with [Rhai](https://rhai.rs).
+//!
+//! Scripts are loaded from `scripts/` with the `script_load` command. A script
+//! can call these functions:
+//!
+//! - `print(text)` prints to the console.
+//! - `cmd(text)` runs console commands after the script returns.
+//! - `cvar(name)` and `cvar_string(name)` read a cvar.
+//! - `register_command(name, function)` adds a console command that calls
+//!   `function` with an array of its arguments.
+//! - `hud_text(x, y, text)` draws text on the HUD. It only works inside
+//!   `on_hud`.
+//!
+//! The client calls these functions if a script defines them:
+//!
+//! - `on_connect()` when the client enters a game.
+//! - `on_disconnect()` when it leaves.
+//! - `on_frame(frame_time)` every frame, with the frame time in seconds.
+//! - `on_hud(width, height)` every frame, with the size of the HUD in HUD
+//!   units. Text positions are measured from the top left corner.
+//!
+//! Scripts can't touch the filesystem or the network, and each call is limited
+//! in how much work it can do, so a broken script can't hang the client.
+
+use std::{cell::RefCell, io::Read, rc::Rc};
+
+use crate::{
+    client::render::HudText,
+    common::{
+        console::CvarRegistry,
+        vfs::{Vfs, VfsError},
+    },
+};
+
+use chrono::Duration;
+use rhai::{Array, Dynamic, Engine, FuncArgs, Scope, AST, FLOAT, INT};
+use thiserror::Error;
+
+/// The directory scripts are loaded from.
+pub const SCRIPT_DIR: &str = "scripts";
+
+// limits on a single call into a script
+const MAX_OPERATIONS: u64 = 100_000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_STRING_SIZE: usize = 4096;
+
+#[derive(Error, Debug)]
+pub enum ScriptError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Virtual filesystem error: {0}")]
+    Vfs(#[from] VfsError),
+    #[error("Parse error in {name}: {msg}")]
+    Parse { name: String, msg: String },
+    #[error("Error in {name}: {msg}")]
+    Eval { name: String, msg: String },
+}
+
+/// Something that happened on the client that scripts may want to react to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScriptEvent {
+    Connect,
+    Disconnect,
+    Frame(Duration),
+}
+
+// everything scripts ask the client to do, collected while they run
+#[derive(Default)]
+struct ScriptOutput {
+    commands: String,
+    lines: Vec<String>,
+    registrations: Vec<(String, String)>,
+    hud: Vec<HudText>,
+    in_hud: bool,
+}
+
+struct Script {
+    name: String,
+    ast: AST,
+}
+
+impl Script {
+    fn defines(&self, function: &str, arg_count: usize) -> bool {
+        self.ast
+            .iter_functions()
+            .any(|f| f.name == function && f.params.len() == arg_count)
+    }
+}
+
+/// Runs client scripts and collects their output.
+pub struct ScriptHost {
+    engine: Engine,
+    scripts: Vec<Script>,
+    output: Rc<RefCell<ScriptOutput>>,
+    connected: bool,
+}
+
+impl ScriptHost {
+    pub fn new(cvars: Rc<RefCell<CvarRegistry>>) -> ScriptHost {
+        let output = Rc::new(RefCell::new(ScriptOutput::default()));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_call_levels(MAX_CALL_LEVELS);
+        engine.set_max_string_size(MAX_STRING_SIZE);
+
+        let out = output.clone();
+        engine.on_print(move |s| out.borrow_mut().lines.push(s.to_owned()));
+        engine.on_debug(|s, _, pos| debug!("script {}: {}", pos, s));
+
+        let out = output.clone();
+        engine.register_fn("cmd", move |text: &str| {
+            let mut out = out.borrow_mut();
+            out.commands.push_str(text);
+            out.commands.push('\n');
+        });
+
+        let cv = cvars.clone();
+        engine.register_fn("cvar", move |name: &str| -> FLOAT {
+            cv.borrow().get_value(name).unwrap_or(0.0) as FLOAT
+        });
+
+        let cv = cvars;
+        engine.register_fn("cvar_string", move |name: &str| -> String {
+            cv.borrow().get(name).unwrap_or_default()
+        });
+
+        let out = output.clone();
+        engine.register_fn("register_command", move |name: &str, function: &str| {
+            out.borrow_mut()
+                .registrations
+                .push((name.to_owned(), function.to_owned()));
+        });
+
+        let out = output.clone();
+        engine.register_fn("hud_text", move |x: INT, y: INT, text: &str| {
+            let mut out = out.borrow_mut();
+            if out.in_hud {
+                out.hud.push(HudText {
+                    x: x as i32,
+                    y: y as i32,
+                    text: text.to_owned(),
+                });
+            }
+        });
+
+        ScriptHost {
+            engine,
+            scripts: Vec::new(),
+            output,
+            connected: false,
+        }
+    }
+
+    /// Load `name` from the scripts directory, replacing any script already
+    /// loaded with that name.
+    pub fn load_file(&mut self, vfs: &Vfs, name: &str) -> Result<(), ScriptError> {
+        let mut source = String::new();
+        vfs.open(format!("{}/{}", SCRIPT_DIR, name))?
+            .read_to_string(&mut source)?;
+        self.load(name, &source)
+    }
+
+    /// Compile and run the top level of a script.
+    pub fn load(&mut self, name: &str, source: &str) -> Result<(), ScriptError> {
+        let ast = self
+            .engine
+            .compile(source)
+            .map_err(|e| ScriptError::Parse {
+                name: name.to_owned(),
+                msg: e.to_string(),
+            })?;
+
+        self.engine.run_ast(&ast).map_err(|e| ScriptError::Eval {
+            name: name.to_owned(),
+            msg: e.to_string(),
+        })?;
+
+        let script = Script {
+            name: name.to_owned(),
+            ast,
+        };
+        match self.scripts.iter_mut().find(|s| s.name == name) {
+            Some(s) => *s = script,
+            None => self.scripts.push(script),
+        }
+
+        Ok(())
+    }
+
+    /// Unload all scripts. Commands they registered stay registered but do
+    /// nothing.
+    pub fn unload_all(&mut self) {
+        self.scripts.clear();
+    }
+
+    pub fn script_names(&self) -> impl Iterator<Item = &str> {
+        self.scripts.iter().map(|s| s.name.as_str())
+    }
+
+    /// Tell scripts whether the client is in a game, sending `Connect` or
+    /// `Disconnect` if that changed.
+    pub fn set_connected(&mut self, connected: bool) {
+        if connected != self.connected {
+            self.connected = connected;
+            self.dispatch(match connected {
+                true => ScriptEvent::Connect,
+                false => ScriptEvent::Disconnect,
+            });
+        }
+    }
+
+    pub fn dispatch(&mut self, event: ScriptEvent) {
+        match event {
+            ScriptEvent::Connect => self.call_all("on_connect", ()),
+            ScriptEvent::Disconnect => self.call_all("on_disconnect", ()),
+            ScriptEvent::Frame(frame_time) => {
+                let secs = frame_time.num_microseconds().unwrap_or(0) as FLOAT / 1_000_000.0;
+                self.call_all("on_frame", (secs,));
+            }
+        }
+    }
+
+    /// Collect the text scripts draw on a HUD of the given size.
+    pub fn hud(&mut self, width: i32, height: i32) -> Vec<HudText> {
+        self.output.borrow_mut().in_hud = true;
+        self.call_all("on_hud", (width as INT, height as INT));
+
+        let mut out = self.output.borrow_mut();
+        out.in_hud = false;
+        std::mem::take(&mut out.hud)
+    }
+
+    /// Run the script function registered for a console command.
+    pub fn call_command(&mut self, function: &str, args: &[&str]) {
+        let args: Array = args.iter().map(|a| Dynamic::from(a.to_string())).collect();
+        self.call_all(function, (args,));
+    }
+
+    /// Console commands queued by `cmd()`.
+    pub fn take_commands(&mut self) -> String {
+        std::mem::take(&mut self.output.borrow_mut().commands)
+    }
+
+    /// Lines printed by scripts.
+    pub fn take_lines(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.output.borrow_mut().lines)
+    }
+
+    /// Console commands added by `register_command()`, as pairs of command
+    /// name and script function.
+    pub fn take_registrations(&mut self) -> Vec<(String, String)> {
+        std::mem::take(&mut self.output.borrow_mut().registrations)
+    }
+
+    fn call_all<A>(&mut self, function: &str, args: A)
+    where
+        A: FuncArgs + Clone,
+    {
+        let arg_count = {
+            let mut v = Vec::new();
+            args.clone().parse(&mut v);
+            v.len()
+        };
+
+        for script in self.scripts.iter() {
+            if !script.defines(function, arg_count) {
+                continue;
+            }
+
+            if let Err(e) = self.engine.call_fn::<Dynamic>(
+                &mut Scope::new(),
+                &script.ast,
+                function,
+                args.clone(),
+            ) {
+                self.output
+                    .borrow_mut()
+                    .lines
+                    .push(format!("{}: {}: {}", script.name, function, e));
+            }
+        }
+    }
+}
+
+pub fn cmd_script_load(
+    scripts: Rc<RefCell<ScriptHost>>,
+    vfs: Rc<Vfs>,
+) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| {
+        if args.len() != 1 {
+            return "usage: script_load <file>".to_owned();
+        }
+
+        match scripts.try_borrow_mut() {
+            Ok(mut s) => match s.load_file(&vfs, args[0]) {
+                Ok(()) => String::new(),
+                Err(e) => format!("{}", e),
+            },
+            Err(_) => "Scripts can't load other scripts".to_owned(),
+        }
+    })
+}
+
+pub fn cmd_script_unload(scripts: Rc<RefCell<ScriptHost>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| match scripts.try_borrow_mut() {
+        Ok(mut s) => {
+            s.unload_all();
+            String::new()
+        }
+        Err(_) => "Scripts can't unload scripts".to_owned(),
+    })
+}
+
+pub fn cmd_script_list(scripts: Rc<RefCell<ScriptHost>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| {
+        let mut out = String::new();
+        for name in scripts.borrow().script_names() {
+            out.push_str(name);
+            out.push('\n');
+        }
+        out
+    })
+}
+
+/// A console command registered by a script with `register_command()`.
+pub fn cmd_script_function(
+    scripts: Rc<RefCell<ScriptHost>>,
+    function: String,
+) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| match scripts.try_borrow_mut() {
+        Ok(mut s) => {
+            s.call_command(&function, args);
+            String::new()
+        }
+        Err(_) => format!("{}: script is already running", function),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn host() -> ScriptHost {
+        let cvars = CvarRegistry::new(Rc::new(RefCell::new(Vec::new())));
+        cvars.register("fov", "90").unwrap();
+        ScriptHost::new(Rc::new(RefCell::new(cvars)))
+    }
+
+    #[test]
+    fn test_script_events() {
+        let mut host = host();
+        host.load(
+            "test.rhai",
+            r#"
+            register_command("hello", "hello");
+            fn hello(args) { print("hello " + args[0]); }
+            fn on_connect() { cmd("say hi"); }
+            fn on_hud(w, h) { hud_text(8, 16, "fov " + cvar("fov")); }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            host.take_registrations(),
+            vec![("hello".to_owned(), "hello".to_owned())]
+        );
+
+        host.call_command("hello", &["world"]);
+        assert_eq!(host.take_lines(), vec!["hello world".to_owned()]);
+
+        // only a change in connection state is an event
+        host.set_connected(true);
+        host.set_connected(true);
+        assert_eq!(host.take_commands(), "say hi\n");
+
+        let hud = host.hud(320, 200);
+        assert_eq!(hud.len(), 1);
+        assert_eq!((hud[0].x, hud[0].y), (8, 16));
+        assert_eq!(hud[0].text, "fov 90.0");
+    }
+
+    #[test]
+    fn test_script_limits() {
+        let mut host = host();
+        assert!(host.load("bad.rhai", "fn on_frame(t) {").is_err());
+
+        host.load("loop.rhai", "fn on_frame(t) { loop {} }")
+            .unwrap();
+        host.dispatch(ScriptEvent::Frame(Duration::milliseconds(16)));
+        assert_eq!(host.take_lines().len(), 1);
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Text captions for sound events.
+//!
+//! Captions are defined in a `captions.txt` file in the mod directory, one per
+//! line:
+//!
+//! ```text
+//! // sound name            caption text               [seconds]
+//! weapons/rocket1i.wav     "Rocket launcher fires"
+//! player/plyrjmp8.wav      "Jump"                     1
+//! ```
+//!
+//! When a sound with a caption starts close enough to the listener, its text
+//! is shown on screen for the given duration (or `DEFAULT_DURATION_SECS`).
+
+use std::{collections::HashMap, io::Read};
+
+use crate::common::{
+    parse,
+    vfs::{Vfs, VfsError},
+};
+
+use chrono::Duration;
+
+use super::SoundError;
+
+pub const CAPTIONS_FILE: &str = "captions.txt";
+
+const DEFAULT_DURATION_SECS: f32 = 2.0;
+
+// most captions that can be displayed at once
+const MAX_ACTIVE_CAPTIONS: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct CaptionVars {
+    pub cl_captions: f32,
+    pub cl_captions_distance: f32,
+}
+
+impl Default for CaptionVars {
+    fn default() -> CaptionVars {
+        CaptionVars {
+            cl_captions: 0.0,
+            cl_captions_distance: 1000.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct CaptionDef {
+    text: String,
+    duration: Duration,
+}
+
+#[derive(Debug)]
+struct ActiveCaption {
+    text: String,
+    expire_time: Duration,
+}
+
+/// Tracks caption definitions and the captions currently on screen.
+#[derive(Debug)]
+pub struct Captions {
+    defs: HashMap<String, CaptionDef>,
+    active: Vec<ActiveCaption>,
+    vars: CaptionVars,
+}
+
+impl Captions {
+    pub fn new() -> Captions {
+        Captions {
+            defs: HashMap::new(),
+            active: Vec::new(),
+            vars: CaptionVars::default(),
+        }
+    }
+
+    /// Load caption definitions from the virtual filesystem.
+    ///
+    /// A missing captions file is not an error; most mods won't have one.
+    pub fn load(vfs: &Vfs) -> Result<Captions, SoundError> {
+        let mut file = match vfs.open(CAPTIONS_FILE) {
+            Ok(f) => f,
+            Err(VfsError::NoSuchFile(_)) => return Ok(Captions::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut src = String::new();
+        file.read_to_string(&mut src)?;
+
+        Ok(Captions::parse(&src))
+    }
+
+    /// Parse caption definitions from a string.
+    ///
+    /// Malformed lines are skipped with a warning.
+    pub fn parse<S>(src: S) -> Captions
+    where
+        S: AsRef<str>,
+    {
+        let mut captions = Captions::new();
+
+        // make sure the last line is terminated
+        let mut src = src.as_ref().to_owned();
+        src.push('\n');
+
+        let lines = match parse::console::commands(&src) {
+            Ok((_, lines)) => lines,
+            Err(e) => {
+                warn!("Failed to parse {}: {}", CAPTIONS_FILE, e);
+                return captions;
+            }
+        };
+
+        for line in lines {
+            if line.len() < 2 || line.len() > 3 {
+                warn!("Invalid caption definition: {}", line.join(" "));
+                continue;
+            }
+
+            let duration = match line.get(2) {
+                Some(d) => match d.parse::<f32>() {
+                    Ok(secs) => secs,
+                    Err(_) => {
+                        warn!("Invalid caption duration for {}: {}", line[0], d);
+                        continue;
+                    }
+                },
+                None => DEFAULT_DURATION_SECS,
+            };
+
+            captions.defs.insert(
+                line[0].to_owned(),
+                CaptionDef {
+                    text: line[1].to_owned(),
+                    duration: Duration::milliseconds((duration * 1000.0) as i64),
+                },
+            );
+        }
+
+        captions
+    }
+
+    pub fn set_vars(&mut self, vars: CaptionVars) {
+        self.vars = vars;
+    }
+
+    /// Show the caption for a sound, if it has one.
+    ///
+    /// `distance` is the distance from the listener to the sound. Captions are
+    /// only shown if `cl_captions` is enabled and the sound is within
+    /// `cl_captions_distance` units.
+    pub fn sound_started(&mut self, sound_name: &str, distance: f32, time: Duration) {
+        if self.vars.cl_captions == 0.0 || distance > self.vars.cl_captions_distance {
+            return;
+        }
+
+        let def = match self.defs.get(sound_name) {
+            Some(d) => d,
+            None => return,
+        };
+
+        // if the caption is already up, just extend it
+        if let Some(active) = self.active.iter_mut().find(|a| a.text == def.text) {
+            active.expire_time = time + def.duration;
+            return;
+        }
+
+        if self.active.len() >= MAX_ACTIVE_CAPTIONS {
+            self.active.remove(0);
+        }
+
+        self.active.push(ActiveCaption {
+            text: def.text.clone(),
+            expire_time: time + def.duration,
+        });
+    }
+
+    /// Remove captions that have expired as of `time`.
+    pub fn update(&mut self, time: Duration) {
+        self.active.retain(|a| a.expire_time > time);
+    }
+
+    /// Remove all captions from the screen.
+    pub fn clear(&mut self) {
+        self.active.clear();
+    }
+
+    /// Return an iterator over the captions on screen, oldest first.
+    pub fn active(&self) -> impl DoubleEndedIterator<Item = &str> {
+        self.active.iter().map(|a| a.text.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn enabled(src: &str) -> Captions {
+        let mut captions = Captions::parse(src);
+        captions.set_vars(CaptionVars {
+            cl_captions: 1.0,
+            ..Default::default()
+        });
+        captions
+    }
+
+    #[test]
+    fn test_parse() {
+        let captions = Captions::parse(
+            "// comment\n\
+             weapons/rocket1i.wav \"Rocket fires\"\n\
+             \n\
+             player/plyrjmp8.wav \"Jump\" 0.5\n\
+             bad/duration.wav \"Oops\" soon\n\
+             bad/line.wav",
+        );
+
+        assert_eq!(captions.defs.len(), 2);
+        assert_eq!(
+            captions.defs.get("weapons/rocket1i.wav"),
+            Some(&CaptionDef {
+                text: "Rocket fires".to_owned(),
+                duration: Duration::seconds(2),
+            })
+        );
+        assert_eq!(
+            captions.defs.get("player/plyrjmp8.wav"),
+            Some(&CaptionDef {
+                text: "Jump".to_owned(),
+                duration: Duration::milliseconds(500),
+            })
+        );
+    }
+
+    #[test]
+    fn test_sound_started() {
+        let mut captions = enabled("a.wav \"A\" 1\nb.wav \"B\" 3\n");
+
+        captions.sound_started("a.wav", 0.0, Duration::zero());
+        captions.sound_started("b.wav", 0.0, Duration::zero());
+        captions.sound_started("c.wav", 0.0, Duration::zero());
+        assert_eq!(captions.active().collect::<Vec<_>>(), vec!["A", "B"]);
+
+        captions.update(Duration::seconds(2));
+        assert_eq!(captions.active().collect::<Vec<_>>(), vec!["B"]);
+
+        // retriggering extends the caption instead of duplicating it
+        captions.sound_started("b.wav", 0.0, Duration::seconds(2));
+        captions.update(Duration::seconds(4));
+        assert_eq!(captions.active().collect::<Vec<_>>(), vec!["B"]);
+    }
+
+    #[test]
+    fn test_sound_started_disabled_or_distant() {
+        let mut captions = Captions::parse("a.wav \"A\"\n");
+        captions.sound_started("a.wav", 0.0, Duration::zero());
+        assert_eq!(captions.active().count(), 0);
+
+        let mut captions = enabled("a.wav \"A\"\n");
+        captions.sound_started("a.wav", 5000.0, Duration::zero());
+        assert_eq!(captions.active().count(), 0);
+    }
+}
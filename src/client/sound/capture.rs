@@ -0,0 +1,569 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Capture of the game's audio output to a WAV file.
+//!
+//! Rodio mixes sinks internally, so the final mix can't be read back from the
+//! output stream. Instead, every source started while a capture is active is
+//! wrapped in a [`Tap`], which converts it to the capture format and adds its
+//! samples into a shared mix buffer at the position the sound started.
+//!
+//! Positions are measured in game frames rather than wall-clock time: the
+//! capture advances by exactly `sample_rate / fps` samples each time
+//! [`AudioCapture::end_frame`] is called, and taps won't consume their sources
+//! past the end of the current frame. This keeps the WAV in sync with a frame
+//! dump no matter how long each frame takes to render.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::client::sound::SoundError;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use rodio::{source::UniformSourceIterator, Sample, Source};
+
+// size of the RIFF header and the fmt and data chunk headers
+const WAV_HEADER_SIZE: u32 = 44;
+
+// number of samples a tap buffers before mixing them into the capture
+const TAP_BATCH_SIZE: usize = 1024;
+
+// how far behind the current frame the capture writes to disk, in seconds.
+// this gives the audio thread time to pull samples for the latest frames.
+const FLUSH_LAG: f32 = 0.5;
+
+trait WriteSeek: Write + Seek + Send {}
+impl<T> WriteSeek for T where T: Write + Seek + Send {}
+
+/// Writes 16-bit PCM audio to a WAV file.
+///
+/// The RIFF and data chunk sizes aren't known until the file is complete, so
+/// they are written as zero and patched by `finish()`.
+pub struct WavWriter<W>
+where
+    W: Write + Seek,
+{
+    writer: W,
+    sample_count: u32,
+}
+
+impl<W> WavWriter<W>
+where
+    W: Write + Seek,
+{
+    pub fn new(mut writer: W, channels: u16, sample_rate: u32) -> io::Result<WavWriter<W>> {
+        let block_align = channels * 2;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_u32::<LittleEndian>(0)?;
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"fmt ")?;
+        writer.write_u32::<LittleEndian>(16)?;
+        // PCM
+        writer.write_u16::<LittleEndian>(1)?;
+        writer.write_u16::<LittleEndian>(channels)?;
+        writer.write_u32::<LittleEndian>(sample_rate)?;
+        writer.write_u32::<LittleEndian>(sample_rate * block_align as u32)?;
+        writer.write_u16::<LittleEndian>(block_align)?;
+        writer.write_u16::<LittleEndian>(16)?;
+
+        writer.write_all(b"data")?;
+        writer.write_u32::<LittleEndian>(0)?;
+
+        Ok(WavWriter {
+            writer,
+            sample_count: 0,
+        })
+    }
+
+    /// Write a single sample, clamping it to the range [-1, 1].
+    pub fn write_sample(&mut self, sample: f32) -> io::Result<()> {
+        let s = (sample.max(-1.0).min(1.0) * i16::MAX as f32) as i16;
+        self.writer.write_i16::<LittleEndian>(s)?;
+        self.sample_count += 1;
+        Ok(())
+    }
+
+    /// Patch the chunk sizes and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_size = self.sample_count * 2;
+
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_u32::<LittleEndian>(WAV_HEADER_SIZE - 8 + data_size)?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_u32::<LittleEndian>(data_size)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+struct CaptureState {
+    // None once the capture has been finished
+    writer: Option<WavWriter<Box<dyn WriteSeek>>>,
+
+    // number of sample frames written to disk
+    written: u64,
+
+    // mixed samples not yet written, starting at `written`
+    pending: VecDeque<f32>,
+}
+
+impl CaptureState {
+    fn mix(&mut self, channels: u16, start: u64, samples: &[f32]) {
+        if self.writer.is_none() {
+            return;
+        }
+
+        // drop anything that arrived too late to be written
+        let skip = (self.written.saturating_sub(start) * channels as u64) as usize;
+        if skip >= samples.len() {
+            return;
+        }
+        let samples = &samples[skip..];
+        let offset = (start.max(self.written) - self.written) as usize * channels as usize;
+
+        if self.pending.len() < offset + samples.len() {
+            self.pending.resize(offset + samples.len(), 0.0);
+        }
+
+        for (i, s) in samples.iter().enumerate() {
+            self.pending[offset + i] += s;
+        }
+    }
+
+    fn flush(&mut self, channels: u16, until: u64) -> io::Result<()> {
+        let writer = match self.writer {
+            Some(ref mut w) => w,
+            None => return Ok(()),
+        };
+
+        while self.written < until {
+            for _ in 0..channels {
+                writer.write_sample(self.pending.pop_front().unwrap_or(0.0))?;
+            }
+            self.written += 1;
+        }
+
+        Ok(())
+    }
+}
+
+struct CaptureShared {
+    channels: u16,
+    sample_rate: u32,
+    fps: u32,
+
+    // the first sample frame of the game frame being rendered
+    cursor: AtomicU64,
+
+    // the first sample frame of the next game frame. taps stall here.
+    limit: AtomicU64,
+
+    state: Mutex<CaptureState>,
+}
+
+impl CaptureShared {
+    fn frame_start(&self, frame: u64) -> u64 {
+        frame * self.sample_rate as u64 / self.fps as u64
+    }
+}
+
+/// An in-progress capture of the game's audio output.
+///
+/// This is cheap to clone; all clones refer to the same capture.
+#[derive(Clone)]
+pub struct AudioCapture {
+    shared: Arc<CaptureShared>,
+    frame: Arc<AtomicU64>,
+}
+
+impl fmt::Debug for AudioCapture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AudioCapture")
+            .field("channels", &self.shared.channels)
+            .field("sample_rate", &self.shared.sample_rate)
+            .field("fps", &self.shared.fps)
+            .field("frame", &self.frame_count())
+            .finish()
+    }
+}
+
+impl AudioCapture {
+    /// Begin capturing audio to a WAV file at `path`.
+    ///
+    /// `channels` and `sample_rate` should match the output device so that
+    /// tapped sources don't need a second conversion. `fps` is the rate at
+    /// which `end_frame()` will be called.
+    pub fn create<P>(
+        path: P,
+        channels: u16,
+        sample_rate: u32,
+        fps: u32,
+    ) -> Result<AudioCapture, SoundError>
+    where
+        P: AsRef<Path>,
+    {
+        let file = BufWriter::new(File::create(path)?);
+        AudioCapture::with_writer(Box::new(file), channels, sample_rate, fps)
+            .map_err(SoundError::Io)
+    }
+
+    fn with_writer(
+        writer: Box<dyn WriteSeek>,
+        channels: u16,
+        sample_rate: u32,
+        fps: u32,
+    ) -> io::Result<AudioCapture> {
+        assert!(channels > 0 && sample_rate > 0 && fps > 0);
+
+        let shared = CaptureShared {
+            channels,
+            sample_rate,
+            fps,
+            cursor: AtomicU64::new(0),
+            limit: AtomicU64::new(0),
+            state: Mutex::new(CaptureState {
+                writer: Some(WavWriter::new(writer, channels, sample_rate)?),
+                written: 0,
+                pending: VecDeque::new(),
+            }),
+        };
+        shared.limit.store(shared.frame_start(1), Ordering::SeqCst);
+
+        Ok(AudioCapture {
+            shared: Arc::new(shared),
+            frame: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.shared.channels
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.shared.sample_rate
+    }
+
+    /// Return the number of frames captured so far.
+    pub fn frame_count(&self) -> u64 {
+        self.frame.load(Ordering::SeqCst)
+    }
+
+    /// Returns `true` if `other` refers to the same capture as `self`.
+    pub fn ptr_eq(&self, other: &AudioCapture) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+
+    /// Wrap a source so that its output is included in the capture.
+    ///
+    /// The source is taken to start at the beginning of the current frame.
+    pub fn tap<S>(&self, source: S) -> Tap<S>
+    where
+        S: Source,
+        S::Item: Sample,
+    {
+        let start = self.shared.cursor.load(Ordering::SeqCst);
+        Tap {
+            src: UniformSourceIterator::new(source, self.shared.channels, self.shared.sample_rate),
+            shared: self.shared.clone(),
+            pos: start,
+            channel: 0,
+            silence: 0,
+            batch: Vec::with_capacity(TAP_BATCH_SIZE),
+        }
+    }
+
+    /// Mark the end of a frame, advancing the capture by one frame's worth of
+    /// samples and writing out anything old enough to be complete.
+    pub fn end_frame(&self) -> Result<(), SoundError> {
+        let frame = self.frame.fetch_add(1, Ordering::SeqCst) + 1;
+        let cursor = self.shared.frame_start(frame);
+        self.shared.cursor.store(cursor, Ordering::SeqCst);
+        self.shared
+            .limit
+            .store(self.shared.frame_start(frame + 1), Ordering::SeqCst);
+
+        let lag = (FLUSH_LAG * self.shared.sample_rate as f32) as u64;
+        let mut state = self.shared.state.lock().unwrap();
+        state.flush(self.shared.channels, cursor.saturating_sub(lag))?;
+
+        Ok(())
+    }
+
+    /// Write out all captured frames and close the file.
+    ///
+    /// The WAV file contains exactly `frame_count()` frames of audio. Taps
+    /// created from this capture go on playing but are no longer recorded.
+    pub fn finish(&self) -> Result<(), SoundError> {
+        let cursor = self.shared.cursor.load(Ordering::SeqCst);
+        let mut state = self.shared.state.lock().unwrap();
+        state.flush(self.shared.channels, cursor)?;
+        state.pending.clear();
+
+        if let Some(w) = state.writer.take() {
+            w.finish()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A source whose samples are recorded by an [`AudioCapture`].
+///
+/// The wrapped source is converted to the capture's channel count and sample
+/// rate. If the source gets ahead of the capture, the tap outputs silence
+/// until the capture catches up.
+pub struct Tap<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    src: UniformSourceIterator<S, f32>,
+    shared: Arc<CaptureShared>,
+
+    // the sample frame the next sample belongs to
+    pos: u64,
+
+    // the channel the next sample belongs to
+    channel: u16,
+
+    // number of silent samples left to output
+    silence: u16,
+
+    batch: Vec<f32>,
+}
+
+impl<S> Tap<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn flush(&mut self) {
+        // only whole frames are mixed
+        let channels = self.shared.channels as usize;
+        let frames = self.batch.len() / channels;
+        if frames == 0 {
+            return;
+        }
+
+        let start = self.pos - frames as u64;
+        self.shared.state.lock().unwrap().mix(
+            self.shared.channels,
+            start,
+            &self.batch[..frames * channels],
+        );
+        self.batch.clear();
+    }
+}
+
+impl<S> Iterator for Tap<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.silence > 0 {
+            self.silence -= 1;
+            return Some(0.0);
+        }
+
+        // stall at frame boundaries if the source is ahead of the capture
+        if self.channel == 0 && self.pos >= self.shared.limit.load(Ordering::Relaxed) {
+            self.flush();
+            self.silence = self.shared.channels - 1;
+            return Some(0.0);
+        }
+
+        let sample = match self.src.next() {
+            Some(s) => s,
+            None => {
+                self.flush();
+                return None;
+            }
+        };
+
+        self.batch.push(sample);
+        self.channel += 1;
+        if self.channel == self.shared.channels {
+            self.channel = 0;
+            self.pos += 1;
+
+            if self.batch.len() >= TAP_BATCH_SIZE {
+                self.flush();
+            }
+        }
+
+        Some(sample)
+    }
+}
+
+impl<S> Source for Tap<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.shared.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.shared.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl<S> Drop for Tap<S>
+where
+    S: Source,
+    S::Item: Sample,
+{
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    use byteorder::{LittleEndian, ReadBytesExt};
+    use rodio::buffer::SamplesBuffer;
+
+    #[derive(Clone)]
+    struct SharedBuf(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for SharedBuf {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.0.lock().unwrap().seek(pos)
+        }
+    }
+
+    fn read_samples(buf: &SharedBuf) -> Vec<i16> {
+        let data = buf.0.lock().unwrap().get_ref().clone();
+        let mut reader = Cursor::new(&data[WAV_HEADER_SIZE as usize..]);
+        let mut samples = Vec::new();
+        while let Ok(s) = reader.read_i16::<LittleEndian>() {
+            samples.push(s);
+        }
+        samples
+    }
+
+    #[test]
+    fn test_wav_writer_header() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 2, 44100).unwrap();
+        for _ in 0..8 {
+            wav.write_sample(0.5).unwrap();
+        }
+        let data = wav.finish().unwrap().into_inner();
+
+        assert_eq!(data.len(), WAV_HEADER_SIZE as usize + 16);
+        let mut reader = Cursor::new(&data);
+        reader.seek(SeekFrom::Start(4)).unwrap();
+        assert_eq!(reader.read_u32::<LittleEndian>().unwrap(), 36 + 16);
+        reader.seek(SeekFrom::Start(22)).unwrap();
+        assert_eq!(reader.read_u16::<LittleEndian>().unwrap(), 2);
+        assert_eq!(reader.read_u32::<LittleEndian>().unwrap(), 44100);
+        reader.seek(SeekFrom::Start(40)).unwrap();
+        assert_eq!(reader.read_u32::<LittleEndian>().unwrap(), 16);
+    }
+
+    #[test]
+    fn test_capture_length_matches_frame_count() {
+        let buf = SharedBuf(Arc::new(Mutex::new(Cursor::new(Vec::new()))));
+        let capture = AudioCapture::with_writer(Box::new(buf.clone()), 1, 100, 10).unwrap();
+        for _ in 0..7 {
+            capture.end_frame().unwrap();
+        }
+        capture.finish().unwrap();
+
+        assert_eq!(read_samples(&buf).len(), 70);
+    }
+
+    #[test]
+    fn test_tap_mixes_at_start_frame() {
+        let buf = SharedBuf(Arc::new(Mutex::new(Cursor::new(Vec::new()))));
+        let capture = AudioCapture::with_writer(Box::new(buf.clone()), 1, 100, 10).unwrap();
+
+        // start a 5-sample sound in the second frame
+        capture.end_frame().unwrap();
+        let mut tap = capture.tap(SamplesBuffer::new(1, 100, vec![0.5f32; 5]));
+        while tap.next().is_some() {}
+        drop(tap);
+
+        capture.end_frame().unwrap();
+        capture.end_frame().unwrap();
+        capture.finish().unwrap();
+
+        let samples = read_samples(&buf);
+        assert_eq!(samples.len(), 30);
+        assert!(samples[..10].iter().all(|s| *s == 0));
+        assert!(samples[10..15].iter().all(|s| *s > 0));
+        assert!(samples[15..].iter().all(|s| *s == 0));
+    }
+
+    #[test]
+    fn test_tap_stalls_at_frame_boundary() {
+        let buf = SharedBuf(Arc::new(Mutex::new(Cursor::new(Vec::new()))));
+        let capture = AudioCapture::with_writer(Box::new(buf.clone()), 1, 100, 10).unwrap();
+
+        // a source longer than one frame can only be consumed up to the limit
+        let mut tap = capture.tap(SamplesBuffer::new(1, 100, vec![0.5f32; 25]));
+        let first: Vec<f32> = (0..15).map(|_| tap.next().unwrap()).collect();
+        assert!(first[..10].iter().all(|s| *s == 0.5));
+        assert!(first[10..].iter().all(|s| *s == 0.0));
+
+        capture.end_frame().unwrap();
+        assert_eq!(tap.next(), Some(0.5));
+    }
+}
@@ -0,0 +1,105 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    client::sound::{AudioSource, Channel, Listener, SoundError},
+    common::vfs::Vfs,
+};
+
+use cgmath::Vector3;
+use rodio::OutputStreamHandle;
+
+// maximum number of local sounds that can play at once
+const MAX_LOCAL_CHANNELS: usize = 8;
+
+/// Plays sounds that aren't part of the game world.
+///
+/// Local sounds play at full volume with no spatialization, as if emitted at
+/// the listener's position. This is used by the `play` and `playvol` commands
+/// to audition sound files.
+pub struct LocalSoundPlayer {
+    vfs: Rc<Vfs>,
+    stream: OutputStreamHandle,
+    listener: Listener,
+    channels: Vec<Channel>,
+
+    // sounds that have been played, by name
+    cache: HashMap<String, AudioSource>,
+}
+
+impl LocalSoundPlayer {
+    pub fn new(vfs: Rc<Vfs>, stream: OutputStreamHandle) -> LocalSoundPlayer {
+        LocalSoundPlayer {
+            vfs,
+            stream,
+            listener: Listener::new(),
+            channels: Vec::new(),
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Play the sound with the given name at the given volume.
+    ///
+    /// `name` is relative to the `sound/` directory of the virtual filesystem.
+    /// If it has no extension, `.wav` is assumed.
+    pub fn play<S>(&mut self, name: S, volume: f32) -> Result<(), SoundError>
+    where
+        S: AsRef<str>,
+    {
+        let mut name = name.as_ref().to_owned();
+        if !name.contains('.') {
+            name.push_str(".wav");
+        }
+
+        let src = match self.cache.get(&name) {
+            Some(s) => s.clone(),
+            None => {
+                let s = AudioSource::load(&self.vfs, &name)?;
+                self.cache.insert(name, s.clone());
+                s
+            }
+        };
+
+        // reuse a free channel if there is one, otherwise cut off the oldest
+        let chan_id = match self.channels.iter().position(|c| !c.in_use()) {
+            Some(i) => i,
+            None if self.channels.len() < MAX_LOCAL_CHANNELS => {
+                self.channels.push(Channel::new(self.stream.clone()));
+                self.channels.len() - 1
+            }
+            None => {
+                self.channels.rotate_left(1);
+                self.channels.len() - 1
+            }
+        };
+
+        self.channels[chan_id].play(
+            src,
+            Vector3::new(0.0, 0.0, 0.0),
+            &self.listener,
+            volume,
+            0.0,
+        );
+
+        Ok(())
+    }
+
+    /// Return an iterator over the sounds that have been played.
+    pub fn sounds(&self) -> impl Iterator<Item = &AudioSource> {
+        self.cache.values()
+    }
+
+    /// Move playback to a different output stream.
+    ///
+    /// Any sounds currently playing are stopped.
+    pub fn set_stream(&mut self, stream: OutputStreamHandle) {
+        self.stream = stream;
+        self.channels.clear();
+    }
+
+    /// Stop all local sounds.
+    pub fn stop(&mut self) {
+        for chan in self.channels.iter() {
+            chan.stop();
+        }
+    }
+}
@@ -18,12 +18,24 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+mod captions;
+mod capture;
+mod local;
 mod music;
+mod output;
+mod spatial;
+
+pub use captions::{CaptionVars, Captions};
+pub use capture::AudioCapture;
+pub use local::LocalSoundPlayer;
 pub use music::MusicPlayer;
+pub use output::{AudioOutput, DeviceWatcher};
+pub use spatial::{OutputMode, Rolloff, SpatialVars, Spatialization};
 
 use std::{
     cell::{Cell, RefCell},
     io::{self, BufReader, Cursor, Read},
+    sync::Arc,
 };
 
 use crate::common::vfs::{Vfs, VfsError};
@@ -33,6 +45,7 @@ use rodio::{
     source::{Buffered, SamplesConverter},
     Decoder, OutputStreamHandle, Sink, Source,
 };
+use spatial::{SharedSpatialization, Spatialize};
 use thiserror::Error;
 use chrono::Duration;
 
@@ -49,6 +62,12 @@ pub enum SoundError {
     Vfs(#[from] VfsError),
     #[error("WAV decoder error: {0}")]
     Decoder(#[from] rodio::decoder::DecoderError),
+    #[error("No such audio device: {0}")]
+    NoSuchDevice(String),
+    #[error("Failed to enumerate audio devices: {0}")]
+    Devices(#[from] rodio::DevicesError),
+    #[error("Failed to open audio stream: {0}")]
+    Stream(#[from] rodio::StreamError),
 }
 
 /// Data needed for sound spatialization.
@@ -59,6 +78,11 @@ pub struct Listener {
     origin: Cell<Vector3<f32>>,
     left_ear: Cell<Vector3<f32>>,
     right_ear: Cell<Vector3<f32>>,
+    forward: Cell<Vector3<f32>>,
+    vars: Cell<SpatialVars>,
+    output_mode: Cell<OutputMode>,
+    capture: RefCell<Option<AudioCapture>>,
+    volume: Cell<f32>,
 }
 
 impl Listener {
@@ -67,6 +91,11 @@ impl Listener {
             origin: Cell::new(Vector3::new(0.0, 0.0, 0.0)),
             left_ear: Cell::new(Vector3::new(0.0, 0.0, 0.0)),
             right_ear: Cell::new(Vector3::new(0.0, 0.0, 0.0)),
+            forward: Cell::new(Vector3::new(0.0, 0.0, 0.0)),
+            vars: Cell::new(SpatialVars::default()),
+            output_mode: Cell::new(OutputMode::Stereo),
+            capture: RefCell::new(None),
+            volume: Cell::new(1.0),
         }
     }
 
@@ -94,22 +123,111 @@ impl Listener {
         self.right_ear.set(new_origin);
     }
 
-    pub fn attenuate(
+    /// Set the unit vector in the direction the listener is facing.
+    pub fn set_forward(&self, forward: Vector3<f32>) {
+        self.forward.set(forward);
+    }
+
+    pub fn output_mode(&self) -> OutputMode {
+        self.output_mode.get()
+    }
+
+    /// Set the speaker layout used for new sounds.
+    ///
+    /// Sounds that are already playing keep their layout until restarted.
+    pub fn set_output_mode(&self, mode: OutputMode) {
+        self.output_mode.set(mode);
+    }
+
+    pub fn capture(&self) -> Option<AudioCapture> {
+        self.capture.borrow().clone()
+    }
+
+    /// Returns `true` if new sounds are recorded by `capture`, or are not
+    /// recorded at all if `capture` is `None`.
+    pub fn is_capturing(&self, capture: Option<&AudioCapture>) -> bool {
+        match (self.capture.borrow().as_ref(), capture) {
+            (Some(a), Some(b)) => a.ptr_eq(b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    /// Set the audio capture that new sounds are recorded by.
+    ///
+    /// Sounds that are already playing are not recorded until restarted.
+    pub fn set_capture(&self, capture: Option<AudioCapture>) {
+        self.capture.replace(capture);
+    }
+
+    /// Set the overall volume scale applied to every sound.
+    pub fn set_volume(&self, volume: f32) {
+        self.volume.set(volume);
+    }
+
+    /// Set the distance model and stereo separation used to spatialize sounds.
+    pub fn set_vars(&self, vars: SpatialVars) {
+        self.vars.set(vars);
+    }
+
+    /// Calculate the output channel parameters for a sound emitted at
+    /// `emitter_origin`.
+    pub fn spatialize(
         &self,
         emitter_origin: Vector3<f32>,
         base_volume: f32,
         attenuation: f32,
-    ) -> f32 {
-        let decay = (emitter_origin - self.origin.get()).magnitude()
-            * attenuation
-            * DISTANCE_ATTENUATION_FACTOR;
-        let volume = ((1.0 - decay) * base_volume).max(0.0);
-        volume
+    ) -> Spatialization {
+        let vars = self.vars.get();
+        let to_emitter = emitter_origin - self.origin.get();
+
+        let dist =
+            to_emitter.magnitude() * attenuation * vars.snd_distance * DISTANCE_ATTENUATION_FACTOR;
+        let volume =
+            Rolloff::from_value(vars.snd_rolloff).falloff(dist) * base_volume * self.volume.get();
+
+        // sounds at the listener's position or without attenuation have no
+        // direction
+        let right = self.right_ear.get() - self.left_ear.get();
+        let centered =
+            attenuation == 0.0 || to_emitter.magnitude2() == 0.0 || right.magnitude2() == 0.0;
+        let direction = match centered {
+            true => None,
+            false => {
+                // transform into the listener's frame of reference
+                let dir = to_emitter.normalize();
+                let forward = self.forward.get();
+                let left = -right.normalize();
+                let up = forward.cross(left);
+                Some(Vector3::new(dir.dot(forward), dir.dot(left), dir.dot(up)))
+            }
+        };
+
+        Spatialization::new(
+            self.output_mode.get(),
+            volume,
+            direction,
+            vars.snd_separation,
+        )
+    }
+}
+
+/// Append `source` to `sink`, recording it if `capture` is `Some`.
+fn append_source<S>(sink: &Sink, source: S, capture: Option<AudioCapture>)
+where
+    S: Source<Item = f32> + Send + 'static,
+{
+    match capture {
+        Some(c) => sink.append(c.tap(source)),
+        None => sink.append(source),
     }
 }
 
 #[derive(Clone)]
-pub struct AudioSource(Buffered<SamplesConverter<Decoder<Cursor<Vec<u8>>>, f32>>);
+pub struct AudioSource {
+    name: String,
+    data: Buffered<SamplesConverter<Decoder<Cursor<Vec<u8>>>, f32>>,
+}
 
 impl AudioSource {
     pub fn load<S>(vfs: &Vfs, name: S) -> Result<AudioSource, SoundError>
@@ -126,13 +244,36 @@ impl AudioSource {
             .convert_samples()
             .buffered();
 
-        Ok(AudioSource(src))
+        Ok(AudioSource {
+            name: name.to_owned(),
+            data: src,
+        })
+    }
+
+    /// Return the name this sound was loaded with, relative to `sound/`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.data.channels()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.data.sample_rate()
+    }
+
+    /// Return the length of this sound, if known.
+    pub fn duration(&self) -> Option<std::time::Duration> {
+        self.data.total_duration()
     }
 }
 
 pub struct StaticSound {
     origin: Vector3<f32>,
+    src: AudioSource,
     sink: RefCell<Sink>,
+    spatial: Arc<SharedSpatialization>,
     volume: f32,
     attenuation: f32,
 }
@@ -146,24 +287,50 @@ impl StaticSound {
         attenuation: f32,
         listener: &Listener,
     ) -> StaticSound {
+        let spatial = Arc::new(SharedSpatialization::new(listener.spatialize(
+            origin,
+            volume,
+            attenuation,
+        )));
+
         // TODO: handle PlayError once PR accepted
         let sink = Sink::try_new(&stream).unwrap();
-        let infinite = src.0.clone().repeat_infinite();
-        sink.append(infinite);
-        sink.set_volume(listener.attenuate(origin, volume, attenuation));
+        let infinite = src.data.clone().repeat_infinite();
+        append_source(
+            &sink,
+            Spatialize::new(infinite, listener.output_mode(), spatial.clone()),
+            listener.capture(),
+        );
 
         StaticSound {
             origin,
+            src,
             sink: RefCell::new(sink),
+            spatial,
             volume,
             attenuation,
         }
     }
 
-    pub fn update(&self, listener: &Listener) {
-        let sink = self.sink.borrow_mut();
+    /// Restart this sound on a different output stream.
+    pub fn set_stream(&self, stream: &OutputStreamHandle, listener: &Listener) {
+        self.update(listener);
+        let sink = Sink::try_new(&stream).unwrap();
+        append_source(
+            &sink,
+            Spatialize::new(
+                self.src.data.clone().repeat_infinite(),
+                listener.output_mode(),
+                self.spatial.clone(),
+            ),
+            listener.capture(),
+        );
+        self.sink.replace(sink);
+    }
 
-        sink.set_volume(listener.attenuate(self.origin, self.volume, self.attenuation));
+    pub fn update(&self, listener: &Listener) {
+        self.spatial
+            .set(listener.spatialize(self.origin, self.volume, self.attenuation));
     }
 }
 
@@ -171,6 +338,7 @@ impl StaticSound {
 pub struct Channel {
     stream: OutputStreamHandle,
     sink: RefCell<Option<Sink>>,
+    spatial: Arc<SharedSpatialization>,
     master_vol: Cell<f32>,
     attenuation: Cell<f32>,
 }
@@ -181,6 +349,7 @@ impl Channel {
         Channel {
             stream,
             sink: RefCell::new(None),
+            spatial: Arc::new(SharedSpatialization::new(Spatialization::silent())),
             master_vol: Cell::new(0.0),
             attenuation: Cell::new(0.0),
         }
@@ -202,26 +371,24 @@ impl Channel {
         self.sink.replace(None);
 
         // start the new sound
+        self.update(ent_pos, listener);
         let new_sink = Sink::try_new(&self.stream).unwrap();
-        new_sink.append(src.0);
-        new_sink.set_volume(listener.attenuate(
-            ent_pos,
-            self.master_vol.get(),
-            self.attenuation.get(),
-        ));
+        append_source(
+            &new_sink,
+            Spatialize::new(src.data, listener.output_mode(), self.spatial.clone()),
+            listener.capture(),
+        );
 
         self.sink.replace(Some(new_sink));
     }
 
     pub fn update(&self, ent_pos: Vector3<f32>, listener: &Listener) {
-        if let Some(ref sink) = *self.sink.borrow_mut() {
-            // attenuate using quake coordinates since distance is the same either way
-            sink.set_volume(listener.attenuate(
-                ent_pos,
-                self.master_vol.get(),
-                self.attenuation.get(),
-            ));
-        };
+        // attenuate using quake coordinates since distance is the same either way
+        self.spatial.set(listener.spatialize(
+            ent_pos,
+            self.master_vol.get(),
+            self.attenuation.get(),
+        ));
     }
 
     /// Stop the sound currently playing on this channel, if there is one.
@@ -1,10 +1,11 @@
-use std::{
-    io::{Cursor, Read},
-    rc::Rc,
-};
+use std::{io::Cursor, rc::Rc};
 
-use crate::{client::sound::SoundError, common::vfs::Vfs};
+use crate::{
+    client::sound::{append_source, AudioCapture, SoundError},
+    common::{stream::AssetStreamer, vfs::Vfs},
+};
 
+use chrono::{DateTime, Duration, Utc};
 use rodio::{Decoder, OutputStreamHandle, Sink, Source};
 
 /// Plays music tracks.
@@ -13,6 +14,18 @@ pub struct MusicPlayer {
     stream: OutputStreamHandle,
     playing: Option<String>,
     sink: Option<Sink>,
+    capture: Option<AudioCapture>,
+    volume: f32,
+
+    // tracks are loaded in the background; this is the one on its way
+    streamer: AssetStreamer,
+    loading: Option<String>,
+
+    // the streamer's clock, which runs across levels
+    start_time: DateTime<Utc>,
+
+    // contents of the current track, kept to restart it on a new stream
+    data: Option<Vec<u8>>,
 }
 
 impl MusicPlayer {
@@ -22,9 +35,19 @@ impl MusicPlayer {
             stream,
             playing: None,
             sink: None,
+            capture: None,
+            volume: 1.0,
+            streamer: AssetStreamer::new(),
+            loading: None,
+            start_time: Utc::now(),
+            data: None,
         }
     }
 
+    fn time(&self) -> Duration {
+        Utc::now().signed_duration_since(self.start_time)
+    }
+
     /// Start playing the track with the given name.
     ///
     /// Music tracks are expected to be in the "music/" directory of the virtual
@@ -32,6 +55,10 @@ impl MusicPlayer {
     /// `"id1/music/"` or packaged in a PAK archive with a path beginning with
     /// `"music/"`.
     ///
+    /// The track is loaded in the background and `MusicPlayer::update`
+    /// starts it once it has been read in full. The previous track stops
+    /// immediately.
+    ///
     /// If the specified track is already playing, this has no effect.
     pub fn play_named<S>(&mut self, name: S) -> Result<(), SoundError>
     where
@@ -40,27 +67,69 @@ impl MusicPlayer {
         let name = name.as_ref();
 
         // don't replay the same track
-        if let Some(ref playing) = self.playing {
-            if playing == name {
-                return Ok(());
+        if self.playing.as_deref() == Some(name) || self.loading.as_deref() == Some(name) {
+            return Ok(());
+        }
+
+        let vfs = self.vfs.clone();
+        let open = || {
+            // TODO: there's probably a better way to do this extension check
+            if !name.contains('.') {
+                // try all supported formats
+                vfs.open_owned(format!("music/{}.flac", name))
+                    .or_else(|_| vfs.open_owned(format!("music/{}.wav", name)))
+                    .or_else(|_| vfs.open_owned(format!("music/{}.mp3", name)))
+                    .or_else(|_| vfs.open_owned(format!("music/{}.ogg", name)))
+                    .or(Err(SoundError::NoSuchTrack(name.to_owned())))
+            } else {
+                Ok(vfs.open_owned(name)?)
             }
+        };
+
+        if let Some(loading) = self.loading.take() {
+            self.streamer.cancel(loading);
         }
 
-        // TODO: there's probably a better way to do this extension check
-        let mut file = if !name.contains('.') {
-            // try all supported formats
-            self.vfs
-                .open(format!("music/{}.flac", name))
-                .or_else(|_| self.vfs.open(format!("music/{}.wav", name)))
-                .or_else(|_| self.vfs.open(format!("music/{}.mp3", name)))
-                .or_else(|_| self.vfs.open(format!("music/{}.ogg", name)))
-                .or(Err(SoundError::NoSuchTrack(name.to_owned())))?
-        } else {
-            self.vfs.open(name)?
+        // tracks aren't placed in the world, so they have no distance
+        let time = self.time();
+        self.streamer.request(name, 0.0, time, open)?;
+        self.loading = Some(name.to_owned());
+
+        // stop the old track now rather than letting it play over a level
+        // change
+        self.sink = None;
+        self.playing = None;
+        self.data = None;
+
+        Ok(())
+    }
+
+    /// Start the next track if it has finished loading.
+    pub fn update(&mut self) -> Result<(), SoundError> {
+        let name = match self.loading {
+            Some(ref n) => n.to_owned(),
+            None => return Ok(()),
+        };
+
+        let time = self.time();
+        self.streamer.update(time);
+        if let Some(result) = self.streamer.take(&name) {
+            self.loading = None;
+            self.data = Some(result?);
+            self.playing = Some(name);
+            self.start()?;
+        }
+
+        Ok(())
+    }
+
+    // (Re)start playback of the loaded track from the beginning.
+    fn start(&mut self) -> Result<(), SoundError> {
+        let data = match self.data {
+            Some(ref d) => d.clone(),
+            None => return Ok(()),
         };
 
-        let mut data = Vec::new();
-        file.read_to_end(&mut data)?;
         let source = Decoder::new(Cursor::new(data))?
             .convert_samples::<f32>()
             .buffered()
@@ -70,7 +139,8 @@ impl MusicPlayer {
         self.sink = None;
         // TODO handle PlayError
         let new_sink = Sink::try_new(&self.stream).unwrap();
-        new_sink.append(source);
+        new_sink.set_volume(self.volume);
+        append_source(&new_sink, source, self.capture.clone());
         self.sink = Some(new_sink);
 
         Ok(())
@@ -84,6 +154,32 @@ impl MusicPlayer {
         self.play_named(format!("track{:02}", track_id))
     }
 
+    /// Move playback to a different output stream.
+    ///
+    /// If a track is playing, it is restarted on the new stream.
+    pub fn set_stream(&mut self, stream: OutputStreamHandle) -> Result<(), SoundError> {
+        self.stream = stream;
+        self.sink = None;
+        self.start()
+    }
+
+    /// Set the audio capture that music is recorded by.
+    ///
+    /// If a track is playing, it is restarted so that it is recorded.
+    pub fn set_capture(&mut self, capture: Option<AudioCapture>) -> Result<(), SoundError> {
+        self.capture = capture;
+        self.sink = None;
+        self.start()
+    }
+
+    /// Set the playback volume, where 1.0 is the track's original volume.
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+        if let Some(ref sink) = self.sink {
+            sink.set_volume(volume);
+        }
+    }
+
     /// Stop the current music track.
     ///
     /// This ceases playback entirely. To pause the track, allowing it to be
@@ -91,8 +187,13 @@ impl MusicPlayer {
     ///
     /// If no music track is currently playing, this has no effect.
     pub fn stop(&mut self) {
+        if let Some(loading) = self.loading.take() {
+            self.streamer.cancel(loading);
+        }
+
         self.sink = None;
         self.playing = None;
+        self.data = None;
     }
 
     /// Pause the current music track.
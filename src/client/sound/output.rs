@@ -0,0 +1,186 @@
+use std::{
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+
+use crate::client::sound::SoundError;
+
+use rodio::{
+    cpal::{
+        self,
+        traits::{DeviceTrait as _, HostTrait as _},
+    },
+    OutputStream, OutputStreamHandle,
+};
+
+/// An open audio output device.
+///
+/// Dropping this closes the device, after which any sinks created from its
+/// handle go silent.
+pub struct AudioOutput {
+    _stream: OutputStream,
+    handle: OutputStreamHandle,
+
+    // the device name that was asked for, empty for the default device
+    requested: String,
+
+    // the name of the device actually in use
+    device_name: String,
+
+    // the number of output channels
+    channels: u16,
+
+    // the output sample rate in Hz
+    sample_rate: u32,
+}
+
+impl AudioOutput {
+    /// Return the names of all available output devices.
+    pub fn device_names() -> Vec<String> {
+        match cpal::default_host().output_devices() {
+            Ok(devices) => devices.filter_map(|d| d.name().ok()).collect(),
+            Err(e) => {
+                warn!("Failed to enumerate audio devices: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Open the output device with the given name.
+    ///
+    /// If `name` is empty, the system default device is opened.
+    pub fn open<S>(name: S) -> Result<AudioOutput, SoundError>
+    where
+        S: AsRef<str>,
+    {
+        let name = name.as_ref();
+        let host = cpal::default_host();
+
+        let device = if name.is_empty() {
+            host.default_output_device()
+                .ok_or_else(|| SoundError::NoSuchDevice(String::from("default")))?
+        } else {
+            host.output_devices()?
+                .find(|d| d.name().map_or(false, |n| n == name))
+                .ok_or_else(|| SoundError::NoSuchDevice(name.to_owned()))?
+        };
+
+        let device_name = device.name().unwrap_or_else(|_| String::from("unknown"));
+
+        // the stream is opened with the device's default configuration
+        let (channels, sample_rate) = device
+            .default_output_config()
+            .map(|c| (c.channels(), c.sample_rate().0))
+            .unwrap_or((2, 44100));
+
+        let (stream, handle) = OutputStream::try_from_device(&device)?;
+        debug!(
+            "Opened audio device {} ({} channels)",
+            device_name, channels
+        );
+
+        Ok(AudioOutput {
+            _stream: stream,
+            handle,
+            requested: name.to_owned(),
+            device_name,
+            channels,
+            sample_rate,
+        })
+    }
+
+    /// Open the output device with the given name, falling back to the
+    /// default device if it can't be opened.
+    pub fn open_or_default<S>(name: S) -> Result<AudioOutput, SoundError>
+    where
+        S: AsRef<str>,
+    {
+        let name = name.as_ref();
+        match AudioOutput::open(name) {
+            Ok(o) => Ok(o),
+            Err(e) if !name.is_empty() => {
+                warn!("Couldn't open audio device {} ({}), using default", name, e);
+                let mut output = AudioOutput::open("")?;
+                output.requested = name.to_owned();
+                Ok(output)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn handle(&self) -> OutputStreamHandle {
+        self.handle.clone()
+    }
+
+    /// Return the device name this output was opened with.
+    pub fn requested(&self) -> &str {
+        &self.requested
+    }
+
+    /// Return the name of the device in use.
+    pub fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    /// Return the number of output channels.
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// Return the output sample rate in Hz.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Returns `true` if the output should be reopened: either the device in
+    /// use has disappeared, or the requested device has (re)appeared after we
+    /// fell back to the default.
+    pub fn needs_reopen(&self, names: &[String]) -> bool {
+        if !names.iter().any(|n| *n == self.device_name) {
+            return true;
+        }
+
+        !self.requested.is_empty()
+            && self.requested != self.device_name
+            && names.iter().any(|n| *n == self.requested)
+    }
+}
+
+/// Watches the available output devices from a worker thread.
+///
+/// Enumerating devices can stall for a noticeable time on some hosts, so it
+/// is kept off the main thread. A new list is only sent when it changes.
+pub struct DeviceWatcher {
+    names: Receiver<Vec<String>>,
+}
+
+impl DeviceWatcher {
+    /// Start enumerating devices every `interval`.
+    pub fn spawn(interval: Duration) -> DeviceWatcher {
+        let (tx, names) = mpsc::channel();
+        thread::spawn(move || {
+            let mut last = None;
+            loop {
+                let current = AudioOutput::device_names();
+                if last.as_ref() != Some(&current) {
+                    // the watcher was dropped
+                    if tx.send(current.clone()).is_err() {
+                        return;
+                    }
+
+                    last = Some(current);
+                }
+
+                thread::sleep(interval);
+            }
+        });
+
+        DeviceWatcher { names }
+    }
+
+    /// Return the latest device list if it changed since the last call.
+    pub fn poll(&self) -> Option<Vec<String>> {
+        self.names.try_iter().last()
+    }
+}
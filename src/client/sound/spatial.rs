@@ -0,0 +1,509 @@
+// Copyright © 2020 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use cgmath::Vector3;
+use rodio::Source;
+
+/// The curve used to attenuate sounds over distance.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Rolloff {
+    /// Volume falls off linearly, reaching silence at the clip distance. This
+    /// is the original behavior.
+    Linear,
+
+    /// Volume falls off with the inverse of distance and never quite reaches
+    /// silence.
+    Inverse,
+}
+
+impl Rolloff {
+    /// Returns the rolloff model selected by the value of `snd_rolloff`.
+    pub fn from_value(value: f32) -> Rolloff {
+        match value as i32 {
+            1 => Rolloff::Inverse,
+            _ => Rolloff::Linear,
+        }
+    }
+
+    /// Returns the volume scale for a sound at the given scaled distance.
+    pub fn falloff(&self, dist: f32) -> f32 {
+        match *self {
+            Rolloff::Linear => (1.0 - dist).max(0.0),
+            Rolloff::Inverse => 1.0 / (1.0 + dist),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SpatialVars {
+    pub snd_distance: f32,
+    pub snd_rolloff: f32,
+    pub snd_separation: f32,
+}
+
+impl Default for SpatialVars {
+    fn default() -> SpatialVars {
+        SpatialVars {
+            snd_distance: 1.0,
+            snd_rolloff: 0.0,
+            snd_separation: 1.0,
+        }
+    }
+}
+
+/// The most output channels supported by any `OutputMode`.
+pub const MAX_OUTPUT_CHANNELS: usize = 8;
+
+// spherical head model used for binaural output
+const HEAD_RADIUS: f32 = 0.0875;
+const SPEED_OF_SOUND: f32 = 343.0;
+
+// how much the far ear's lowpass filter is strengthened by head shadowing
+const HEAD_SHADOW_STRENGTH: f32 = 0.85;
+
+// the longest interaural delay the model can produce, in samples, at 48kHz
+const MAX_DELAY_SAMPLES: usize = 64;
+
+// how often playing sounds pick up new spatialization parameters, in frames
+const PARAMS_REFRESH_FRAMES: usize = 128;
+
+/// The speaker layout that sounds are spatialized for.
+///
+/// All modes are built on the same mono source positions; they differ only in
+/// how a source's direction is mapped to output channels.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OutputMode {
+    /// Stereo panning, as in the original.
+    Stereo,
+
+    /// Binaural output for headphones, which adds interaural delay and head
+    /// shadowing to stereo panning.
+    Binaural,
+
+    /// 5.1 surround output.
+    Surround51,
+
+    /// 7.1 surround output.
+    Surround71,
+}
+
+impl OutputMode {
+    /// Returns the output mode selected by the value of `snd_output`.
+    pub fn from_value(value: f32) -> OutputMode {
+        match value as i32 {
+            1 => OutputMode::Binaural,
+            2 => OutputMode::Surround51,
+            3 => OutputMode::Surround71,
+            _ => OutputMode::Stereo,
+        }
+    }
+
+    /// Returns the number of channels this mode outputs.
+    pub fn channels(&self) -> u16 {
+        match *self {
+            OutputMode::Stereo | OutputMode::Binaural => 2,
+            OutputMode::Surround51 => 6,
+            OutputMode::Surround71 => 8,
+        }
+    }
+
+    // azimuth of each output channel's speaker in degrees, clockwise from the
+    // front. the LFE channel has no position.
+    fn speaker_azimuths(&self) -> &'static [Option<f32>] {
+        match *self {
+            OutputMode::Stereo | OutputMode::Binaural => &[Some(-90.0), Some(90.0)],
+            OutputMode::Surround51 => &[
+                Some(-30.0),
+                Some(30.0),
+                Some(0.0),
+                None,
+                Some(-110.0),
+                Some(110.0),
+            ],
+            OutputMode::Surround71 => &[
+                Some(-30.0),
+                Some(30.0),
+                Some(0.0),
+                None,
+                Some(-150.0),
+                Some(150.0),
+                Some(-90.0),
+                Some(90.0),
+            ],
+        }
+    }
+}
+
+/// Per-channel parameters for playing a sound at a particular position.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Spatialization {
+    /// The gain of each output channel.
+    pub gains: [f32; MAX_OUTPUT_CHANNELS],
+
+    /// For binaural output, the delay in seconds before the sound reaches
+    /// each ear.
+    pub delays: [f32; 2],
+
+    /// For binaural output, how much each ear is shadowed by the head, from 0
+    /// to 1.
+    pub shadows: [f32; 2],
+}
+
+impl Spatialization {
+    pub fn silent() -> Spatialization {
+        Spatialization {
+            gains: [0.0; MAX_OUTPUT_CHANNELS],
+            delays: [0.0; 2],
+            shadows: [0.0; 2],
+        }
+    }
+
+    /// Spatialize a sound for the given output mode.
+    ///
+    /// `volume` is the attenuated volume of the sound. `direction` is the unit
+    /// vector from the listener to the sound in the listener's frame of
+    /// reference (+x forward, +y left, +z up), or `None` if the sound has no
+    /// direction. `separation` scales how strongly the sound is localized,
+    /// from 0 (not at all) to 1 (fully).
+    pub fn new(
+        mode: OutputMode,
+        volume: f32,
+        direction: Option<Vector3<f32>>,
+        separation: f32,
+    ) -> Spatialization {
+        let mut spatial = Spatialization::silent();
+
+        let dir = match direction {
+            Some(d) => d,
+            None => {
+                // play nondirectional sounds at full volume through the front
+                // speakers
+                spatial.gains[0] = volume;
+                spatial.gains[1] = volume;
+                return spatial;
+            }
+        };
+
+        match mode {
+            OutputMode::Stereo | OutputMode::Binaural => {
+                let dot = -dir.y * separation;
+                spatial.gains[0] = ((1.0 - dot) * volume).max(0.0).min(volume);
+                spatial.gains[1] = ((1.0 + dot) * volume).max(0.0).min(volume);
+
+                if mode == OutputMode::Binaural {
+                    // Woodworth's formula for interaural time difference
+                    let lateral = dot.max(-1.0).min(1.0).asin();
+                    let itd = HEAD_RADIUS / SPEED_OF_SOUND * (lateral.abs() + lateral.abs().sin());
+
+                    let far_ear = if lateral < 0.0 { 1 } else { 0 };
+                    spatial.delays[far_ear] = itd;
+                    spatial.shadows[far_ear] = dot.abs();
+                }
+            }
+
+            OutputMode::Surround51 | OutputMode::Surround71 => {
+                let speakers = mode.speaker_azimuths();
+                let positioned = speakers.iter().filter(|s| s.is_some()).count();
+                let omni = volume * (1.0 - separation) / (positioned as f32).sqrt();
+
+                let azimuth = (-dir.y).atan2(dir.x).to_degrees();
+                let (a, b, t) = speaker_pair(speakers, azimuth);
+                let t = t * std::f32::consts::FRAC_PI_2;
+                for (c, speaker) in speakers.iter().enumerate() {
+                    if speaker.is_none() {
+                        continue;
+                    }
+
+                    let mut pair_gain = 0.0;
+                    if c == a {
+                        pair_gain += t.cos();
+                    }
+                    if c == b {
+                        pair_gain += t.sin();
+                    }
+
+                    spatial.gains[c] = omni + volume * separation * pair_gain;
+                }
+            }
+        }
+
+        spatial
+    }
+}
+
+// finds the adjacent pair of speakers surrounding the given azimuth, returning
+// their channel indices and how far the azimuth is from the first toward the
+// second, from 0 to 1.
+fn speaker_pair(speakers: &[Option<f32>], azimuth: f32) -> (usize, usize, f32) {
+    let mut sorted: Vec<(usize, f32)> = speakers
+        .iter()
+        .enumerate()
+        .filter_map(|(c, s)| s.map(|a| (c, a)))
+        .collect();
+    sorted.sort_by(|x, y| x.1.partial_cmp(&y.1).unwrap());
+
+    // normalize to the range covered by the speakers, wrapping from the last
+    // speaker around to the first
+    let first = sorted[0].1;
+    let mut az = azimuth;
+    while az < first {
+        az += 360.0;
+    }
+    while az >= first + 360.0 {
+        az -= 360.0;
+    }
+
+    for i in 0..sorted.len() {
+        let (c0, a0) = sorted[i];
+        let (c1, a1) = match sorted.get(i + 1) {
+            Some(&next) => next,
+            None => (sorted[0].0, sorted[0].1 + 360.0),
+        };
+
+        if az >= a0 && az < a1 {
+            return (c0, c1, (az - a0) / (a1 - a0));
+        }
+    }
+
+    unreachable!()
+}
+
+/// Spatialization parameters shared between the mixer and a playing sound.
+#[derive(Debug)]
+pub struct SharedSpatialization(Mutex<Spatialization>);
+
+impl SharedSpatialization {
+    pub fn new(spatial: Spatialization) -> SharedSpatialization {
+        SharedSpatialization(Mutex::new(spatial))
+    }
+
+    pub fn get(&self) -> Spatialization {
+        *self.0.lock().unwrap()
+    }
+
+    pub fn set(&self, spatial: Spatialization) {
+        *self.0.lock().unwrap() = spatial;
+    }
+}
+
+/// A source which mixes its input down to mono and plays it back through the
+/// channels of an `OutputMode`.
+///
+/// The spatialization parameters may be changed while the sound is playing.
+pub struct Spatialize<S>
+where
+    S: Source<Item = f32>,
+{
+    input: S,
+    mode: OutputMode,
+    shared: Arc<SharedSpatialization>,
+    params: Spatialization,
+    refresh: usize,
+
+    // the current output frame
+    frame: [f32; MAX_OUTPUT_CHANNELS],
+    frame_pos: usize,
+    frame_len: usize,
+
+    // recent mono samples, for interaural delay
+    history: [f32; MAX_DELAY_SAMPLES],
+    history_pos: usize,
+
+    // lowpass filter state for each ear
+    lowpass: [f32; 2],
+}
+
+impl<S> Spatialize<S>
+where
+    S: Source<Item = f32>,
+{
+    pub fn new(input: S, mode: OutputMode, shared: Arc<SharedSpatialization>) -> Spatialize<S> {
+        Spatialize {
+            input,
+            mode,
+            shared,
+            params: Spatialization::silent(),
+            refresh: 0,
+            frame: [0.0; MAX_OUTPUT_CHANNELS],
+            frame_pos: 0,
+            frame_len: 0,
+            history: [0.0; MAX_DELAY_SAMPLES],
+            history_pos: 0,
+            lowpass: [0.0; 2],
+        }
+    }
+
+    fn next_frame(&mut self) -> Option<()> {
+        if self.refresh == 0 {
+            self.params = self.shared.get();
+            self.refresh = PARAMS_REFRESH_FRAMES;
+        }
+        self.refresh -= 1;
+
+        let in_channels = self.input.channels().max(1);
+        let mut mono = 0.0;
+        for _ in 0..in_channels {
+            mono += self.input.next()?;
+        }
+        mono /= in_channels as f32;
+
+        let out_channels = self.mode.channels() as usize;
+        match self.mode {
+            OutputMode::Binaural => {
+                self.history[self.history_pos] = mono;
+
+                for ear in 0..2 {
+                    let delay =
+                        (self.params.delays[ear] * self.input.sample_rate() as f32) as usize;
+                    let delay = delay.min(MAX_DELAY_SAMPLES - 1);
+                    let idx = (self.history_pos + MAX_DELAY_SAMPLES - delay) % MAX_DELAY_SAMPLES;
+
+                    // one-pole lowpass, stronger for the shadowed ear
+                    let alpha = 1.0 - self.params.shadows[ear] * HEAD_SHADOW_STRENGTH;
+                    self.lowpass[ear] += alpha * (self.history[idx] - self.lowpass[ear]);
+                    self.frame[ear] = self.lowpass[ear] * self.params.gains[ear];
+                }
+
+                self.history_pos = (self.history_pos + 1) % MAX_DELAY_SAMPLES;
+            }
+
+            _ => {
+                for c in 0..out_channels {
+                    self.frame[c] = mono * self.params.gains[c];
+                }
+            }
+        }
+
+        self.frame_pos = 0;
+        self.frame_len = out_channels;
+        Some(())
+    }
+}
+
+impl<S> Iterator for Spatialize<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.frame_pos == self.frame_len {
+            self.next_frame()?;
+        }
+
+        let sample = self.frame[self.frame_pos];
+        self.frame_pos += 1;
+        Some(sample)
+    }
+}
+
+impl<S> Source for Spatialize<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        let in_channels = self.input.channels().max(1) as usize;
+        let out_channels = self.mode.channels() as usize;
+        let buffered = self.frame_len - self.frame_pos;
+        self.input
+            .current_frame_len()
+            .map(|l| l / in_channels * out_channels + buffered)
+    }
+
+    fn channels(&self) -> u16 {
+        self.mode.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rolloff_falloff() {
+        assert_eq!(Rolloff::Linear.falloff(0.0), 1.0);
+        assert_eq!(Rolloff::Linear.falloff(2.0), 0.0);
+        assert_eq!(Rolloff::Inverse.falloff(1.0), 0.5);
+        assert_eq!(Rolloff::from_value(1.0), Rolloff::Inverse);
+        assert_eq!(Rolloff::from_value(0.0), Rolloff::Linear);
+    }
+
+    #[test]
+    fn test_spatialize_mono_to_stereo() {
+        let input = rodio::buffer::SamplesBuffer::new(1, 11025, vec![1.0f32, 0.5]);
+        let mut spatial = Spatialization::silent();
+        spatial.gains[0] = 0.25;
+        spatial.gains[1] = 1.0;
+        let shared = Arc::new(SharedSpatialization::new(spatial));
+        let src = Spatialize::new(input, OutputMode::Stereo, shared);
+
+        assert_eq!(src.channels(), 2);
+        assert_eq!(src.collect::<Vec<_>>(), vec![0.25, 1.0, 0.125, 0.5]);
+    }
+
+    #[test]
+    fn test_surround_front_uses_center() {
+        let ahead = Some(Vector3::new(1.0, 0.0, 0.0));
+        let spatial = Spatialization::new(OutputMode::Surround51, 1.0, ahead, 1.0);
+
+        assert_eq!(spatial.gains[2], 1.0);
+        for c in [0, 1, 3, 4, 5].iter() {
+            assert!(spatial.gains[*c].abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_surround_behind_splits_rear() {
+        let behind = Some(Vector3::new(-1.0, 0.0, 0.0));
+        let spatial = Spatialization::new(OutputMode::Surround71, 1.0, behind, 1.0);
+
+        // rear left and rear right share the sound equally
+        assert!((spatial.gains[4] - spatial.gains[5]).abs() < 1e-6);
+        assert!(spatial.gains[4] > 0.7);
+        assert_eq!(spatial.gains[3], 0.0);
+    }
+
+    #[test]
+    fn test_binaural_delays_far_ear() {
+        let right = Some(Vector3::new(0.0, -1.0, 0.0));
+        let spatial = Spatialization::new(OutputMode::Binaural, 1.0, right, 1.0);
+
+        assert!(spatial.delays[0] > 0.0);
+        assert_eq!(spatial.delays[1], 0.0);
+        assert!(spatial.shadows[0] > 0.0);
+        assert!(spatial.gains[1] > spatial.gains[0]);
+    }
+}
@@ -3,14 +3,21 @@ use std::{cell::RefCell, collections::HashMap, rc::Rc};
 use super::view::BobVars;
 use crate::{
     client::{
+        centerprint::CenterPrint,
+        chat::ChatLog,
+        coop::{self, Marker, Teammate},
         entity::{
             particle::{Particle, Particles, TrailKind, MAX_PARTICLES},
+            pool::{EffectPool, EffectPriority},
             Beam, ClientEntity, Light, LightDesc, Lights, MAX_BEAMS, MAX_LIGHTS, MAX_TEMP_ENTITIES,
         },
-        input::game::{Action, GameInput},
+        input::{
+            game::{Action, GameInput},
+            haptics::HapticEvent,
+        },
         render::Camera,
-        sound::{AudioSource, EntityMixer, Listener, StaticSound},
-        view::{IdleVars, KickVars, MouseVars, RollVars, View},
+        sound::{AudioSource, Captions, EntityMixer, Listener, StaticSound},
+        view::{IdleVars, KickVars, MouseVars, RollVars, View, ViewModel},
         ClientError, ColorShiftCode, IntermissionKind, MoveVars, MAX_STATS,
     },
     common::{
@@ -18,8 +25,8 @@ use crate::{
         math::{self, Angles},
         model::{Model, ModelFlags, ModelKind, SyncType},
         net::{
-            self, BeamEntityKind, ButtonFlags, ColorShift, EntityEffects, ItemFlags, PlayerData,
-            PointEntityKind, TempEntity,
+            self, BeamEntityKind, ButtonFlags, ColorShift, EntityEffects, GameType, ItemFlags,
+            PlayerData, PointEntityKind, TempEntity,
         },
         vfs::Vfs,
     },
@@ -35,6 +42,10 @@ use rand::{
 };
 use rodio::OutputStreamHandle;
 
+// how long an entity is kept without updates while the server's datagrams
+// are full
+const MAX_DEFER_MS: i64 = 500;
+
 const CACHED_SOUND_NAMES: &[&'static str] = &[
     "hknight/hit.wav",
     "weapons/r_exp3.wav",
@@ -49,7 +60,95 @@ pub struct PlayerInfo {
     pub name: String,
     pub frags: i32,
     pub colors: PlayerColor,
-    // translations: [u8; VID_GRADES],
+    pub join_time: Duration,
+}
+
+/// A row of the scoreboard.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScoreboardEntry {
+    pub player_id: usize,
+    pub name: String,
+    pub frags: i32,
+    pub colors: PlayerColor,
+    pub join_time: Duration,
+}
+
+/// Returns the color shift for a view inside a leaf with `contents`.
+pub fn contents_color_shift(contents: bsp::BspLeafContents) -> ColorShift {
+    match contents {
+        // a noclipping player inside a wall sees no tint
+        bsp::BspLeafContents::Empty | bsp::BspLeafContents::Solid => ColorShift {
+            dest_color: [0, 0, 0],
+            percent: 0,
+        },
+        bsp::BspLeafContents::Lava => ColorShift {
+            dest_color: [255, 80, 0],
+            percent: 150,
+        },
+        bsp::BspLeafContents::Slime => ColorShift {
+            dest_color: [0, 25, 5],
+            percent: 150,
+        },
+        _ => ColorShift {
+            dest_color: [130, 80, 50],
+            percent: 128,
+        },
+    }
+}
+
+/// Blends `shifts` in order into a single RGBA tint.
+///
+/// Each shift covers what's beneath it in proportion to its percent, scaled by
+/// `scale_percent`.
+pub fn blend_color_shifts<I>(shifts: I, scale_percent: f32) -> [f32; 4]
+where
+    I: Iterator<Item = ColorShift>,
+{
+    let scale = scale_percent.max(0.0) / 100.0;
+    shifts.fold([0.0; 4], |accum, elem| {
+        let elem_a = (elem.percent as f32 / 255.0 / 2.0 * scale).min(1.0);
+        if elem_a <= 0.0 {
+            return accum;
+        }
+        let in_a = accum[3];
+        let out_a = in_a + elem_a * (1.0 - in_a);
+        let color_factor = elem_a / out_a;
+
+        let mut out = [0.0; 4];
+        for i in 0..3 {
+            out[i] =
+                accum[i] * (1.0 - color_factor) + elem.dest_color[i] as f32 / 255.0 * color_factor;
+        }
+        out[3] = out_a.min(1.0).max(0.0);
+        out
+    })
+}
+
+/// Lists the connected players in `player_info`, highest frags first.
+///
+/// Players with equal frags are listed in the order they joined.
+pub fn scoreboard_entries(player_info: &[Option<PlayerInfo>]) -> Vec<ScoreboardEntry> {
+    let mut entries: Vec<ScoreboardEntry> = player_info
+        .iter()
+        .enumerate()
+        .filter_map(|(player_id, info)| {
+            info.as_ref().map(|info| ScoreboardEntry {
+                player_id,
+                name: info.name.clone(),
+                frags: info.frags,
+                colors: info.colors,
+                join_time: info.join_time,
+            })
+        })
+        .collect();
+
+    entries.sort_by(|a, b| {
+        b.frags
+            .cmp(&a.frags)
+            .then(a.join_time.cmp(&b.join_time))
+            .then(a.player_id.cmp(&b.player_id))
+    });
+    entries
 }
 
 // client information regarding the current level
@@ -78,7 +177,7 @@ pub struct ClientState {
     // dynamic point lights
     pub lights: Lights,
     // lightning bolts and grappling hook cable
-    pub beams: [Option<Beam>; MAX_BEAMS],
+    pub beams: EffectPool<Beam>,
     // particle effects
     pub particles: Particles,
 
@@ -87,14 +186,22 @@ pub struct ClientState {
 
     pub light_styles: HashMap<u8, String>,
 
+    // skybox named by the worldspawn entity or the server, empty for the scrolling sky
+    pub sky_name: String,
+
     // various values relevant to the player and level (see common::net::ClientStat)
     pub stats: [i32; MAX_STATS],
 
     pub max_players: usize,
+    pub game_type: GameType,
     pub player_info: [Option<PlayerInfo>; net::MAX_CLIENTS],
 
     // the last two timestamps sent by the server (for lerping)
     pub msg_times: [Duration; 2],
+
+    // whether the last datagram was too full to be sure it had every entity.
+    // the server leaves out updates that don't fit and sends them later
+    pub msg_full: bool,
     pub time: Duration,
     pub lerp_factor: f32,
 
@@ -107,7 +214,9 @@ pub struct ClientState {
     pub msg_velocity: [Vector3<f32>; 2],
     pub velocity: Vector3<f32>,
 
-    // paused: bool,
+    pub paused: bool,
+    // set if the client paused the game because the window lost focus
+    pub background_paused: bool,
     pub on_ground: bool,
     pub in_water: bool,
     pub intermission: Option<IntermissionKind>,
@@ -116,6 +225,12 @@ pub struct ClientState {
 
     pub mixer: EntityMixer,
     pub listener: Listener,
+    pub captions: Captions,
+    pub chat: ChatLog,
+    pub center_print: CenterPrint,
+
+    // events for controller rumble, drained by the client each frame
+    pub haptic_events: Vec<HapticEvent>,
 }
 
 impl ClientState {
@@ -132,14 +247,17 @@ impl ClientState {
             static_entities: Vec::new(),
             temp_entities: Vec::new(),
             lights: Lights::with_capacity(MAX_LIGHTS),
-            beams: [None; MAX_BEAMS],
+            beams: EffectPool::with_capacity(MAX_BEAMS),
             particles: Particles::with_capacity(MAX_PARTICLES),
             visible_entity_ids: Vec::new(),
             light_styles: HashMap::new(),
+            sky_name: String::new(),
             stats: [0; MAX_STATS],
             max_players: 0,
+            game_type: GameType::CoOp,
             player_info: Default::default(),
             msg_times: [Duration::zero(), Duration::zero()],
+            msg_full: false,
             time: Duration::zero(),
             lerp_factor: 0.0,
             items: ItemFlags::empty(),
@@ -166,6 +284,8 @@ impl ClientState {
             face_anim_time: Duration::zero(),
             msg_velocity: [Vector3::zero(), Vector3::zero()],
             velocity: Vector3::zero(),
+            paused: false,
+            background_paused: false,
             on_ground: false,
             in_water: false,
             intermission: None,
@@ -173,64 +293,138 @@ impl ClientState {
             completion_time: None,
             mixer: EntityMixer::new(stream),
             listener: Listener::new(),
+            captions: Captions::new(),
+            chat: ChatLog::new(),
+            center_print: CenterPrint::new(),
+            haptic_events: Vec::new(),
         }
     }
 
+    /// Create the state for a new level.
+    ///
+    /// The level's precached models and sounds are loaded afterward by a
+    /// [`PrecacheLoader`](crate::client::loading::PrecacheLoader).
     pub fn from_server_info(
         vfs: &Vfs,
         stream: OutputStreamHandle,
         max_clients: u8,
-        model_precache: Vec<String>,
-        sound_precache: Vec<String>,
+        game_type: GameType,
     ) -> Result<ClientState, ClientError> {
-        // TODO: validate submodel names
-        let mut models = Vec::with_capacity(model_precache.len());
-        models.push(Model::none());
-        let mut model_names = HashMap::new();
-        for mod_name in model_precache {
-            // BSPs can have more than one model
-            if mod_name.ends_with(".bsp") {
-                let bsp_data = vfs.open(&mod_name)?;
-                let (mut brush_models, _) = bsp::load(bsp_data).unwrap();
-                for bmodel in brush_models.drain(..) {
-                    let id = models.len();
-                    let name = bmodel.name().to_owned();
-                    models.push(bmodel);
-                    model_names.insert(name, id);
-                }
-            } else if !mod_name.starts_with("*") {
-                // model names starting with * are loaded from the world BSP
-                debug!("Loading model {}", mod_name);
-                let id = models.len();
-                models.push(Model::load(vfs, &mod_name)?);
-                model_names.insert(mod_name, id);
-            }
-
-            // TODO: send keepalive message?
-        }
-
-        let mut sounds = vec![AudioSource::load(&vfs, "misc/null.wav")?];
-        for ref snd_name in sound_precache {
-            debug!("Loading sound {}: {}", sounds.len(), snd_name);
-            sounds.push(AudioSource::load(vfs, snd_name)?);
-            // TODO: send keepalive message?
-        }
+        let models = vec![Model::none()];
+        let model_names = HashMap::new();
+        let sounds = vec![AudioSource::load(&vfs, "misc/null.wav")?];
 
         let mut cached_sounds = HashMap::new();
         for name in CACHED_SOUND_NAMES {
             cached_sounds.insert(name.to_string(), AudioSource::load(vfs, name)?);
         }
 
+        // captions are optional, so a bad file shouldn't keep us off the server
+        let captions = Captions::load(vfs).unwrap_or_else(|e| {
+            warn!("Failed to load captions: {}", e);
+            Captions::new()
+        });
+
         Ok(ClientState {
             models,
             model_names,
             sounds,
             cached_sounds,
+            captions,
             max_players: max_clients as usize,
+            game_type,
             ..ClientState::new(stream)
         })
     }
 
+    /// Add a model to the precache after sign-on.
+    ///
+    /// The model list is grown as needed to fit `model_id`. If a model is
+    /// already loaded at `model_id`, it is replaced.
+    pub fn precache_model(
+        &mut self,
+        vfs: &Vfs,
+        model_id: usize,
+        name: &str,
+    ) -> Result<(), ClientError> {
+        // model 0 is the null model and model 1 is the world
+        if model_id < 2 {
+            Err(ClientError::InvalidPrecacheId(model_id))?;
+        }
+
+        // submodels are only ever loaded with the world
+        if name.ends_with(".bsp") || name.starts_with("*") {
+            warn!("Can't add brush model {} after sign-on", name);
+            return Ok(());
+        }
+
+        debug!("Loading model {}: {}", model_id, name);
+        let model = Model::load(vfs, name)?;
+
+        while self.models.len() <= model_id {
+            self.models.push(Model::none());
+        }
+
+        if let Some(old_name) = self
+            .model_names
+            .iter()
+            .find(|(_, id)| **id == model_id)
+            .map(|(n, _)| n.clone())
+        {
+            warn!("Replacing model {} ({}) with {}", model_id, old_name, name);
+            self.model_names.remove(&old_name);
+        }
+
+        self.models[model_id] = model;
+        self.model_names.insert(name.to_owned(), model_id);
+
+        Ok(())
+    }
+
+    /// Add a sound to the precache after sign-on.
+    ///
+    /// The sound list is grown as needed to fit `sound_id`. If a sound is
+    /// already loaded at `sound_id`, it is replaced.
+    pub fn precache_sound(
+        &mut self,
+        vfs: &Vfs,
+        sound_id: usize,
+        name: &str,
+    ) -> Result<(), ClientError> {
+        // sound 0 is reserved for misc/null.wav
+        if sound_id == 0 || self.sounds.is_empty() {
+            Err(ClientError::InvalidPrecacheId(sound_id))?;
+        }
+
+        debug!("Loading sound {}: {}", sound_id, name);
+        let sound = AudioSource::load(vfs, name)?;
+
+        if sound_id < self.sounds.len() {
+            warn!("Replacing sound {} with {}", sound_id, name);
+        }
+
+        while self.sounds.len() <= sound_id {
+            let null = self.sounds[0].clone();
+            self.sounds.push(null);
+        }
+
+        self.sounds[sound_id] = sound;
+
+        Ok(())
+    }
+
+    /// Move all sound output to a different output stream.
+    ///
+    /// Sounds playing on entity channels are stopped; static sounds are
+    /// restarted on the new stream.
+    pub fn set_stream(&mut self, stream: OutputStreamHandle) {
+        for ss in self.static_sounds.iter() {
+            ss.set_stream(&stream, &self.listener);
+        }
+
+        self.mixer = EntityMixer::new(stream);
+    }
+
     /// Advance the simulation time by the specified amount.
     ///
     /// This method does not change the state of the world to match the new time value.
@@ -238,6 +432,11 @@ impl ClientState {
         self.time = self.time + frame_time;
     }
 
+    /// Returns the time elapsed between the last two messages from the server.
+    pub fn msg_interval(&self) -> Duration {
+        self.msg_times[0] - self.msg_times[1]
+    }
+
     /// Update the client state interpolation ratio.
     ///
     /// This calculates the ratio used to interpolate entities between the last
@@ -249,51 +448,7 @@ impl ClientState {
             return;
         }
 
-        let server_delta = engine::duration_to_f32(match self.msg_times[0] - self.msg_times[1] {
-            // if no time has passed between updates, don't lerp anything
-            d if d == Duration::zero() => {
-                self.time = self.msg_times[0];
-                self.lerp_factor = 1.0;
-                return;
-            }
-
-            d if d > Duration::milliseconds(100) => {
-                self.msg_times[1] = self.msg_times[0] - Duration::milliseconds(100);
-                Duration::milliseconds(100)
-            }
-
-            d if d < Duration::zero() => {
-                warn!(
-                    "Negative time delta from server!: ({})s",
-                    engine::duration_to_f32(d)
-                );
-                d
-            }
-
-            d => d,
-        });
-
-        let frame_delta = engine::duration_to_f32(self.time - self.msg_times[1]);
-
-        self.lerp_factor = match frame_delta / server_delta {
-            f if f < 0.0 => {
-                if f < -0.01 {
-                    self.time = self.msg_times[1];
-                }
-
-                0.0
-            }
-
-            f if f > 1.0 => {
-                if f > 1.01 {
-                    self.time = self.msg_times[0];
-                }
-
-                1.0
-            }
-
-            f => f,
-        }
+        self.lerp_factor = lerp_factor(&mut self.time, &mut self.msg_times);
     }
 
     /// Update all entities in the game world.
@@ -335,46 +490,19 @@ impl ClientState {
                 continue;
             }
 
-            // if we didn't get an update this frame, remove the entity
-            if ent.msg_time != self.msg_times[0] {
+            // if we didn't get an update this frame, remove the entity,
+            // unless the server may have run out of room for it
+            if ent.msg_time != self.msg_times[0]
+                && (!self.msg_full
+                    || self.msg_times[0] - ent.msg_time > Duration::milliseconds(MAX_DEFER_MS))
+            {
                 ent.model_id = 0;
                 continue;
             }
 
             let prev_origin = ent.origin;
 
-            if ent.force_link {
-                trace!("force link on entity {}", ent_id);
-                ent.origin = ent.msg_origins[0];
-                ent.angles = ent.msg_angles[0];
-            } else {
-                let origin_delta = ent.msg_origins[0] - ent.msg_origins[1];
-                let ent_lerp_factor = if origin_delta.magnitude2() > 10_000.0 {
-                    // if the entity moved more than 100 units in one frame,
-                    // assume it was teleported and don't lerp anything
-                    1.0
-                } else {
-                    lerp_factor
-                };
-
-                ent.origin = ent.msg_origins[1] + ent_lerp_factor * origin_delta;
-
-                // assume that entities will not whip around 180+ degrees in one
-                // frame and adjust the delta accordingly. this avoids a bug
-                // where small turns between 0 <-> 359 cause the demo camera to
-                // face backwards for one frame.
-                for i in 0..3 {
-                    let mut angle_delta = ent.msg_angles[0][i] - ent.msg_angles[1][i];
-                    if angle_delta > Deg(180.0) {
-                        angle_delta = Deg(360.0) - angle_delta;
-                    } else if angle_delta < Deg(-180.0) {
-                        angle_delta = Deg(360.0) + angle_delta;
-                    }
-
-                    ent.angles[i] =
-                        (ent.msg_angles[1][i] + angle_delta * ent_lerp_factor).normalize();
-                }
-            }
+            ent.lerp(lerp_factor);
 
             let model = &self.models[ent.model_id];
             if model.has_flag(ModelFlags::ROTATE) {
@@ -387,22 +515,25 @@ impl ClientState {
 
             // TODO: factor out EntityEffects->LightDesc mapping
             if ent.effects.contains(EntityEffects::MUZZLE_FLASH) {
-                // TODO: angle and move origin to muzzle
-                ent.light_id = Some(self.lights.insert(
+                // move the light out to the end of the weapon
+                let yaw = ent.angles[1];
+                let forward = Vector3::new(yaw.cos(), yaw.sin(), 0.0);
+                ent.light_id = self.lights.insert(
                     self.time,
                     LightDesc {
-                        origin: ent.origin + Vector3::new(0.0, 0.0, 16.0),
+                        origin: ent.origin + 18.0 * forward + Vector3::new(0.0, 0.0, 16.0),
                         init_radius: MFLASH_DIMLIGHT_DISTRIBUTION.sample(&mut self.rng),
                         decay_rate: 0.0,
                         min_radius: Some(32.0),
                         ttl: Duration::milliseconds(100),
                     },
+                    EffectPriority::Normal,
                     ent.light_id,
-                ));
+                );
             }
 
             if ent.effects.contains(EntityEffects::BRIGHT_LIGHT) {
-                ent.light_id = Some(self.lights.insert(
+                ent.light_id = self.lights.insert(
                     self.time,
                     LightDesc {
                         origin: ent.origin,
@@ -411,12 +542,13 @@ impl ClientState {
                         min_radius: None,
                         ttl: Duration::milliseconds(1),
                     },
+                    EffectPriority::Low,
                     ent.light_id,
-                ));
+                );
             }
 
             if ent.effects.contains(EntityEffects::DIM_LIGHT) {
-                ent.light_id = Some(self.lights.insert(
+                ent.light_id = self.lights.insert(
                     self.time,
                     LightDesc {
                         origin: ent.origin,
@@ -425,8 +557,9 @@ impl ClientState {
                         min_radius: None,
                         ttl: Duration::milliseconds(1),
                     },
+                    EffectPriority::Low,
                     ent.light_id,
-                ));
+                );
             }
 
             // check if this entity leaves a trail
@@ -439,7 +572,7 @@ impl ClientState {
             } else if model.has_flag(ModelFlags::TRACER2) {
                 Some(TrailKind::TracerRed)
             } else if model.has_flag(ModelFlags::ROCKET) {
-                ent.light_id = Some(self.lights.insert(
+                ent.light_id = self.lights.insert(
                     self.time,
                     LightDesc {
                         origin: ent.origin,
@@ -448,8 +581,9 @@ impl ClientState {
                         min_radius: None,
                         ttl: Duration::milliseconds(10),
                     },
+                    EffectPriority::Normal,
                     ent.light_id,
-                ));
+                );
                 Some(TrailKind::Rocket)
             } else if model.has_flag(ModelFlags::GRENADE) {
                 Some(TrailKind::Smoke)
@@ -479,7 +613,7 @@ impl ClientState {
         for ent in self.static_entities.iter_mut() {
             if ent.effects.contains(EntityEffects::BRIGHT_LIGHT) {
                 debug!("spawn bright light on static entity");
-                ent.light_id = Some(self.lights.insert(
+                ent.light_id = self.lights.insert(
                     self.time,
                     LightDesc {
                         origin: ent.origin,
@@ -488,13 +622,14 @@ impl ClientState {
                         min_radius: None,
                         ttl: Duration::milliseconds(1),
                     },
+                    EffectPriority::Low,
                     ent.light_id,
-                ));
+                );
             }
 
             if ent.effects.contains(EntityEffects::DIM_LIGHT) {
                 debug!("spawn dim light on static entity");
-                ent.light_id = Some(self.lights.insert(
+                ent.light_id = self.lights.insert(
                     self.time,
                     LightDesc {
                         origin: ent.origin,
@@ -503,8 +638,9 @@ impl ClientState {
                         min_radius: None,
                         ttl: Duration::milliseconds(1),
                     },
+                    EffectPriority::Low,
                     ent.light_id,
-                ));
+                );
             }
         }
 
@@ -517,38 +653,34 @@ impl ClientState {
         }
 
         self.temp_entities.clear();
-        for id in 0..self.beams.len() {
-            // remove beam if expired
-            if self.beams[id].map_or(false, |b| b.expire < self.time) {
-                self.beams[id] = None;
-                continue;
+
+        // remove expired beams and keep lightning gun bolts fixed to player
+        let time = self.time;
+        let view_ent = self.view_entity_id();
+        let view_origin = self.entities[view_ent].origin;
+        self.beams.retain(|beam| {
+            if beam.entity_id == view_ent {
+                beam.start = view_origin;
             }
 
-            let view_ent = self.view_entity_id();
-            if let Some(ref mut beam) = self.beams[id] {
-                // keep lightning gun bolts fixed to player
-                if beam.entity_id == view_ent {
-                    beam.start = self.entities[view_ent].origin;
-                }
+            beam.expire >= time
+        });
 
-                let vec = beam.end - beam.start;
-                let yaw = Deg::from(cgmath::Rad(vec.y.atan2(vec.x))).normalize();
-                let forward = (vec.x.powf(2.0) + vec.y.powf(2.0)).sqrt();
-                let pitch = Deg::from(cgmath::Rad(vec.z.atan2(forward))).normalize();
-
-                let len = vec.magnitude();
-                let direction = vec.normalize();
-                for interval in 0..(len / 30.0) as i32 {
-                    let mut ent = ClientEntity::uninitialized();
-                    ent.origin = beam.start + 30.0 * interval as f32 * direction;
-                    ent.angles =
-                        Vector3::new(pitch, yaw, Deg(ANGLE_DISTRIBUTION.sample(&mut self.rng)));
-
-                    if self.temp_entities.len() < MAX_TEMP_ENTITIES {
-                        self.temp_entities.push(ent);
-                    } else {
-                        warn!("too many temp entities!");
-                    }
+        for beam in self.beams.iter() {
+            let (pitch, yaw) = beam.angles();
+
+            // each segment gets a random roll so the bolt flickers
+            for origin in beam.segment_origins() {
+                let mut ent = ClientEntity::uninitialized();
+                ent.model_id = beam.model_id;
+                ent.origin = origin;
+                ent.angles =
+                    Vector3::new(pitch, yaw, Deg(ANGLE_DISTRIBUTION.sample(&mut self.rng)));
+
+                if self.temp_entities.len() < MAX_TEMP_ENTITIES {
+                    self.temp_entities.push(ent);
+                } else {
+                    warn!("too many temp entities!");
                 }
             }
         }
@@ -589,19 +721,12 @@ impl ClientState {
         self.on_ground = update.on_ground;
         self.in_water = update.in_water;
 
-        self.stats[ClientStat::WeaponFrame as usize] = update.weapon_frame.unwrap_or(0) as i32;
-        self.stats[ClientStat::Armor as usize] = update.armor.unwrap_or(0) as i32;
-        self.stats[ClientStat::Weapon as usize] = update.weapon.unwrap_or(0) as i32;
-        self.stats[ClientStat::Health as usize] = update.health as i32;
-        self.stats[ClientStat::Ammo as usize] = update.ammo as i32;
-        self.stats[ClientStat::Shells as usize] = update.ammo_shells as i32;
-        self.stats[ClientStat::Nails as usize] = update.ammo_nails as i32;
-        self.stats[ClientStat::Rockets as usize] = update.ammo_rockets as i32;
-        self.stats[ClientStat::Cells as usize] = update.ammo_cells as i32;
-
-        // TODO: this behavior assumes the `standard_quake` behavior and will likely
-        // break with the mission packs
-        self.stats[ClientStat::ActiveWeapon as usize] = update.active_weapon as i32;
+        // the weapon frame leaves 0 when the fire animation starts
+        let weapon_frame = update.weapon_frame.unwrap_or(0) as i32;
+        if weapon_frame != 0 && self.stats[ClientStat::WeaponFrame as usize] == 0 {
+            self.haptic_events.push(HapticEvent::WeaponFire);
+        }
+        apply_player_stats(&mut self.stats, &update);
     }
 
     pub fn handle_input(
@@ -651,6 +776,14 @@ impl ClientState {
             forwardmove *= move_vars.cl_movespeedkey;
         }
 
+        // the player can't move during intermission, but attack and jump still
+        // go to the server, which uses them to move on to the next level
+        if self.intermission.is_some() {
+            forwardmove = 0.0;
+            sidemove = 0.0;
+            upmove = 0.0;
+        }
+
         let mut button_flags = ButtonFlags::empty();
 
         if game_input.action_state(Attack) {
@@ -688,10 +821,12 @@ impl ClientState {
         kick_vars: KickVars,
     ) {
         self.face_anim_time = self.time + Duration::milliseconds(200);
+        self.haptic_events.push(HapticEvent::Damage { armor, health });
 
-        let dmg_factor = (armor + health).min(20) as f32 / 2.0;
+        // even a scratch flashes the screen
+        let dmg_factor = ((armor as u32 + health as u32) as f32 / 2.0).max(10.0);
         let mut cshift = self.color_shifts[ColorShiftCode::Damage as usize].borrow_mut();
-        cshift.percent += 3 * dmg_factor as i32;
+        cshift.percent += (3.0 * dmg_factor) as i32;
         cshift.percent = cshift.percent.clamp(0, 150);
 
         if armor > health {
@@ -738,6 +873,7 @@ impl ClientState {
         );
         self.view.calc_final_origin(
             self.time,
+            self.intermission.as_ref(),
             self.entities[self.view.entity_id()].origin,
             self.velocity,
             bob_vars,
@@ -750,9 +886,20 @@ impl ClientState {
     // the server. it may make more sense to use a HashMap to store entities by
     // ID since the lookup table is relatively sparse.
     pub fn spawn_entities(&mut self, id: usize, baseline: EntityState) -> Result<(), ClientError> {
-        // don't clobber existing entities
         if id < self.entities.len() {
-            Err(ClientError::EntityExists(id))?;
+            // slots below the highest entity ID are filled with placeholders,
+            // which may receive a baseline later
+            if self.entities[id].baseline != EntityState::uninitialized() {
+                // don't clobber existing entities
+                Err(ClientError::EntityExists(id))?;
+            }
+
+            debug!(
+                "Spawning entity with id {} from baseline {:?}",
+                id, baseline
+            );
+            self.entities[id] = ClientEntity::from_baseline(baseline);
+            return Ok(());
         }
 
         // spawn intermediate entities (uninitialized)
@@ -815,7 +962,6 @@ impl ClientState {
                     id
                 );
             }
-            // TODO: set player custom colormaps
         }
 
         Ok(())
@@ -840,16 +986,10 @@ impl ClientState {
                     // projectile impacts
                     WizSpike | KnightSpike | Spike | SuperSpike | Gunshot => {
                         let (color, count, sound) = match kind {
-                            // TODO: start wizard/hit.wav
                             WizSpike => (20, 30, Some("wizard/hit.wav")),
 
                             KnightSpike => (226, 20, Some("hknight/hit.wav")),
 
-                            // TODO: for Spike and SuperSpike, start one of:
-                            // - 26.67%: weapons/tink1.wav
-                            // - 20.0%: weapons/ric1.wav
-                            // - 20.0%: weapons/ric2.wav
-                            // - 20.0%: weapons/ric3.wav
                             Spike => (0, 10, Some(spike_sound())),
                             SuperSpike => (0, 20, Some(spike_sound())),
 
@@ -867,16 +1007,7 @@ impl ClientState {
                         );
 
                         if let Some(snd) = sound {
-                            self.mixer.start_sound(
-                                self.cached_sounds.get(snd).unwrap().clone(),
-                                self.time,
-                                None,
-                                0,
-                                1.0,
-                                1.0,
-                                *origin,
-                                &self.listener,
-                            );
+                            self.start_temp_entity_sound(snd, *origin);
                         }
                     }
 
@@ -891,32 +1022,23 @@ impl ClientState {
                                 min_radius: None,
                                 ttl: Duration::milliseconds(500),
                             },
+                            EffectPriority::High,
                             None,
                         );
 
-                        self.mixer.start_sound(
-                            self.cached_sounds
-                                .get("weapons/r_exp3.wav")
-                                .unwrap()
-                                .clone(),
-                            self.time,
-                            None,
-                            0,
-                            1.0,
-                            1.0,
-                            *origin,
-                            &self.listener,
-                        );
+                        self.start_temp_entity_sound("weapons/r_exp3.wav", *origin);
                     }
 
                     ColorExplosion {
                         color_start,
                         color_len,
                     } => {
+                        // a zero-length range uses only the start color
+                        let color_end = color_start.saturating_add(color_len.saturating_sub(1));
                         self.particles.create_color_explosion(
                             self.time,
                             *origin,
-                            (*color_start)..=(*color_start + *color_len - 1),
+                            *color_start..=color_end,
                         );
                         self.lights.insert(
                             self.time,
@@ -927,40 +1049,17 @@ impl ClientState {
                                 min_radius: None,
                                 ttl: Duration::milliseconds(500),
                             },
+                            EffectPriority::High,
                             None,
                         );
 
-                        self.mixer.start_sound(
-                            self.cached_sounds
-                                .get("weapons/r_exp3.wav")
-                                .unwrap()
-                                .clone(),
-                            self.time,
-                            None,
-                            0,
-                            1.0,
-                            1.0,
-                            *origin,
-                            &self.listener,
-                        );
+                        self.start_temp_entity_sound("weapons/r_exp3.wav", *origin);
                     }
 
                     TarExplosion => {
                         self.particles.create_spawn_explosion(self.time, *origin);
 
-                        self.mixer.start_sound(
-                            self.cached_sounds
-                                .get("weapons/r_exp3.wav")
-                                .unwrap()
-                                .clone(),
-                            self.time,
-                            None,
-                            0,
-                            1.0,
-                            1.0,
-                            *origin,
-                            &self.listener,
-                        );
+                        self.start_temp_entity_sound("weapons/r_exp3.wav", *origin);
                     }
 
                     LavaSplash => self.particles.create_lava_splash(self.time, *origin),
@@ -982,23 +1081,39 @@ impl ClientState {
                             1 => "",
                             2 => "2",
                             3 => "3",
-                            x => panic!("invalid lightning model id: {}", x),
+                            x => unreachable!("invalid lightning model id: {}", x),
                         }
                     ),
                     Grapple => "progs/beam.mdl".to_string(),
                 };
 
-                self.spawn_beam(
-                    self.time,
-                    *entity_id as usize,
-                    *self.model_names.get(&model_name).unwrap(),
-                    *start,
-                    *end,
-                );
+                let model_id = match self.model_names.get(&model_name) {
+                    Some(id) => *id,
+                    None => {
+                        warn!("Beam model {} isn't precached", model_name);
+                        return;
+                    }
+                };
+
+                self.spawn_beam(self.time, *entity_id as usize, model_id, *start, *end);
             }
         }
     }
 
+    // start a temp entity's sound at its origin
+    fn start_temp_entity_sound(&mut self, name: &str, origin: Vector3<f32>) {
+        let src = match self.cached_sounds.get(name) {
+            Some(src) => src.clone(),
+            None => {
+                warn!("Temp entity sound {} isn't cached", name);
+                return;
+            }
+        };
+
+        self.mixer
+            .start_sound(src, self.time, None, 0, 1.0, 1.0, origin, &self.listener);
+    }
+
     pub fn spawn_beam(
         &mut self,
         time: Duration,
@@ -1007,31 +1122,33 @@ impl ClientState {
         start: Vector3<f32>,
         end: Vector3<f32>,
     ) {
+        let beam = Beam {
+            entity_id,
+            model_id,
+            expire: time + Duration::milliseconds(200),
+            start,
+            end,
+        };
+
+        // the player's own beam is always the most important
+        let priority = if entity_id == self.view_entity_id() {
+            EffectPriority::High
+        } else {
+            EffectPriority::Normal
+        };
+
         // always override beam with same entity_id if it exists
-        // otherwise use the first free slot
-        let mut free = None;
-        for i in 0..self.beams.len() {
-            if let Some(ref mut beam) = self.beams[i] {
-                if beam.entity_id == entity_id {
-                    beam.model_id = model_id;
-                    beam.expire = time + Duration::milliseconds(200);
-                    beam.start = start;
-                    beam.end = end;
-                }
-            } else if free.is_none() {
-                free = Some(i);
+        let mut found = false;
+        self.beams.retain(|b| {
+            if b.entity_id == entity_id {
+                found = true;
+                *b = beam;
             }
-        }
 
-        if let Some(i) = free {
-            self.beams[i] = Some(Beam {
-                entity_id,
-                model_id,
-                expire: time + Duration::milliseconds(200),
-                start,
-                end,
-            });
-        } else {
+            true
+        });
+
+        if !found && self.beams.insert(time, priority, beam).is_none() {
             warn!("No free beam slots!");
         }
     }
@@ -1048,10 +1165,12 @@ impl ClientState {
 
         let left = (world_translate * rotate * left_base.extend(1.0)).truncate();
         let right = (world_translate * rotate * right_base.extend(1.0)).truncate();
+        let forward = (rotate * Vector3::unit_x().extend(0.0)).truncate();
 
         self.listener.set_origin(view_origin);
         self.listener.set_left_ear(left);
         self.listener.set_right_ear(right);
+        self.listener.set_forward(forward);
     }
 
     pub fn update_sound_spatialization(&self) {
@@ -1078,7 +1197,8 @@ impl ClientState {
         match self.models[1].kind() {
             ModelKind::Brush(ref bmodel) => {
                 let bsp_data = bmodel.bsp_data();
-                let leaf_id = bsp_data.find_leaf(self.entities[self.view.entity_id()].origin);
+                // tint by what's in front of the eyes, not around the feet
+                let leaf_id = bsp_data.find_leaf(self.view.final_origin());
                 let leaf = &bsp_data.leaves()[leaf_id];
                 Ok(leaf.contents)
             }
@@ -1090,26 +1210,8 @@ impl ClientState {
         let float_time = engine::duration_to_f32(frame_time);
 
         // set color for leaf contents
-        self.color_shifts[ColorShiftCode::Contents as usize].replace(
-            match self.view_leaf_contents()? {
-                bsp::BspLeafContents::Empty => ColorShift {
-                    dest_color: [0, 0, 0],
-                    percent: 0,
-                },
-                bsp::BspLeafContents::Lava => ColorShift {
-                    dest_color: [255, 80, 0],
-                    percent: 150,
-                },
-                bsp::BspLeafContents::Slime => ColorShift {
-                    dest_color: [0, 25, 5],
-                    percent: 150,
-                },
-                _ => ColorShift {
-                    dest_color: [130, 80, 50],
-                    percent: 128,
-                },
-            },
-        );
+        self.color_shifts[ColorShiftCode::Contents as usize]
+            .replace(contents_color_shift(self.view_leaf_contents()?));
 
         // decay damage and item pickup shifts
         // always decay at least 1 "percent" (actually 1/255)
@@ -1200,11 +1302,25 @@ impl ClientState {
         &self.models
     }
 
-    pub fn viewmodel_id(&self) -> usize {
-        match self.stats[ClientStat::Weapon as usize] as usize {
-            0 => 0,
-            x => x - 1,
+    /// Returns the first-person weapon model, or `None` if no view model
+    /// should be drawn.
+    pub fn viewmodel(&self) -> Option<ViewModel> {
+        if self.intermission.is_some() {
+            return None;
+        }
+
+        // 0 is no weapon and 1 is the world
+        let model_id = self.stats[ClientStat::Weapon as usize] as usize;
+        if model_id < 2 || model_id >= self.models.len() {
+            return None;
         }
+
+        Some(ViewModel {
+            model_id,
+            frame_id: self.stats[ClientStat::WeaponFrame as usize].max(0) as usize,
+            origin: self.view.viewmodel_origin(),
+            angles: self.view.viewmodel_angles(),
+        })
     }
 
     pub fn iter_visible_entities(&self) -> impl Iterator<Item = &ClientEntity> + Clone {
@@ -1215,6 +1331,21 @@ impl ClientState {
             .chain(self.static_entities.iter())
     }
 
+    /// Lists the colormap, model, skin and colors of each visible entity
+    /// drawn in a player's colors.
+    pub fn player_skins(&self) -> impl Iterator<Item = (u8, usize, usize, PlayerColor)> + '_ {
+        self.visible_entity_ids.iter().filter_map(move |i| {
+            let ent = &self.entities[*i];
+            let colormap = ent.colormap()?;
+            if colormap as usize > self.max_players {
+                return None;
+            }
+
+            let info = self.player_info.get(colormap as usize - 1)?.as_ref()?;
+            Some((colormap, ent.model_id(), ent.skin_id(), info.colors))
+        })
+    }
+
     pub fn iter_particles(&self) -> impl Iterator<Item = &Particle> {
         self.particles.iter()
     }
@@ -1281,6 +1412,22 @@ impl ClientState {
         Ok(values)
     }
 
+    /// Freeze the level at the current time and switch to an intermission.
+    ///
+    /// Finale and cutscene text is revealed in the center of the screen a few
+    /// characters at a time over the intermission camera.
+    pub fn start_intermission(&mut self, kind: IntermissionKind) {
+        match kind {
+            IntermissionKind::Intermission => (),
+            IntermissionKind::Finale { ref text } | IntermissionKind::Cutscene { ref text } => {
+                self.center_print.print(text, self.time, true)
+            }
+        }
+
+        self.intermission = Some(kind);
+        self.completion_time = Some(self.time);
+    }
+
     pub fn intermission(&self) -> Option<&IntermissionKind> {
         self.intermission.as_ref()
     }
@@ -1293,6 +1440,55 @@ impl ClientState {
         self.completion_time
     }
 
+    /// Returns the scoreboard, highest frags first.
+    pub fn scoreboard(&self) -> Vec<ScoreboardEntry> {
+        scoreboard_entries(&self.player_info)
+    }
+
+    /// Lists the other players in a coop game, with markers for those whose
+    /// entities were sent this frame.
+    ///
+    /// Returns an empty list outside of coop games.
+    pub fn teammates(&self, camera: &Camera) -> Vec<Teammate> {
+        if self.game_type != GameType::CoOp || self.max_players <= 1 {
+            return Vec::new();
+        }
+
+        let view_projection = camera.view_projection();
+        self.player_info
+            .iter()
+            .enumerate()
+            .take(self.max_players)
+            .filter_map(|(player_id, info)| {
+                // player entities come right after the world
+                let ent_id = player_id + 1;
+                if ent_id == self.view.entity_id() {
+                    return None;
+                }
+
+                let info = info.as_ref()?;
+                let marker = self
+                    .entities
+                    .get(ent_id)
+                    .filter(|ent| ent.model_id != 0)
+                    .map(|ent| {
+                        let origin = ent.origin + Vector3::unit_z() * coop::NAMEPLATE_HEIGHT;
+                        Marker {
+                            position: coop::project_marker(view_projection, origin),
+                            distance: (ent.origin - camera.origin()).magnitude(),
+                        }
+                    });
+
+                Some(Teammate {
+                    player_id,
+                    name: info.name.clone(),
+                    colors: info.colors,
+                    marker,
+                })
+            })
+            .collect()
+    }
+
     pub fn stats(&self) -> &[i32] {
         &self.stats
     }
@@ -1309,24 +1505,11 @@ impl ClientState {
         self.face_anim_time
     }
 
-    pub fn color_shift(&self) -> [f32; 4] {
-        self.color_shifts.iter().fold([0.0; 4], |accum, elem| {
-            let elem_a = elem.borrow().percent as f32 / 255.0 / 2.0;
-            if elem_a == 0.0 {
-                return accum;
-            }
-            let in_a = accum[3];
-            let out_a = in_a + elem_a * (1.0 - in_a);
-            let color_factor = elem_a / out_a;
-
-            let mut out = [0.0; 4];
-            for i in 0..3 {
-                out[i] = accum[i] * (1.0 - color_factor)
-                    + elem.borrow().dest_color[i] as f32 / 255.0 * color_factor;
-            }
-            out[3] = out_a.min(1.0).max(0.0);
-            out
-        })
+    /// Returns the tint to draw over the screen as RGBA.
+    ///
+    /// `percent` scales the strength of every color shift, so 0 disables them.
+    pub fn color_shift(&self, percent: f32) -> [f32; 4] {
+        blend_color_shifts(self.color_shifts.iter().map(|c| *c.borrow()), percent)
     }
 
     pub fn check_entity_id(&self, id: usize) -> Result<(), ClientError> {
@@ -1347,3 +1530,130 @@ impl ClientState {
         }
     }
 }
+
+/// Calculate the ratio used to interpolate entities between the last two
+/// messages from the server.
+///
+/// `time` is pulled back within the interval if it has strayed too far outside
+/// it, and the older message time is moved up if the interval is longer than
+/// 100ms.
+pub fn lerp_factor(time: &mut Duration, msg_times: &mut [Duration; 2]) -> f32 {
+    let server_delta = engine::duration_to_f32(match msg_times[0] - msg_times[1] {
+        // if no time has passed between updates, don't lerp anything
+        d if d == Duration::zero() => {
+            *time = msg_times[0];
+            return 1.0;
+        }
+
+        d if d > Duration::milliseconds(100) => {
+            msg_times[1] = msg_times[0] - Duration::milliseconds(100);
+            Duration::milliseconds(100)
+        }
+
+        d if d < Duration::zero() => {
+            warn!(
+                "Negative time delta from server!: ({})s",
+                engine::duration_to_f32(d)
+            );
+            d
+        }
+
+        d => d,
+    });
+
+    let frame_delta = engine::duration_to_f32(*time - msg_times[1]);
+
+    match frame_delta / server_delta {
+        f if f < 0.0 => {
+            if f < -0.01 {
+                *time = msg_times[1];
+            }
+
+            0.0
+        }
+
+        f if f > 1.0 => {
+            if f > 1.01 {
+                *time = msg_times[0];
+            }
+
+            1.0
+        }
+
+        f => f,
+    }
+}
+
+/// Copy the player stats carried by a `PlayerData` message into `stats`.
+pub fn apply_player_stats(stats: &mut [i32], update: &PlayerData) {
+    stats[ClientStat::WeaponFrame as usize] = update.weapon_frame.unwrap_or(0) as i32;
+    stats[ClientStat::Armor as usize] = update.armor.unwrap_or(0) as i32;
+    stats[ClientStat::Weapon as usize] = update.weapon.unwrap_or(0) as i32;
+    stats[ClientStat::Health as usize] = update.health as i32;
+    stats[ClientStat::Ammo as usize] = update.ammo as i32;
+    stats[ClientStat::Shells as usize] = update.ammo_shells as i32;
+    stats[ClientStat::Nails as usize] = update.ammo_nails as i32;
+    stats[ClientStat::Rockets as usize] = update.ammo_rockets as i32;
+    stats[ClientStat::Cells as usize] = update.ammo_cells as i32;
+
+    // TODO: this behavior assumes the `standard_quake` behavior and will likely
+    // break with the mission packs
+    stats[ClientStat::ActiveWeapon as usize] = update.active_weapon as i32;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_blend_color_shifts() {
+        let lava = contents_color_shift(bsp::BspLeafContents::Lava);
+        let none = contents_color_shift(bsp::BspLeafContents::Solid);
+        assert_eq!(none.percent, 0);
+
+        // empty shifts don't darken the screen
+        let blend = blend_color_shifts(vec![none, lava].into_iter(), 100.0);
+        assert_eq!(blend[0], 1.0);
+        assert!((blend[3] - 150.0 / 510.0).abs() < 1e-6);
+
+        // the shift on top covers the one beneath in proportion to its alpha
+        let red = ColorShift {
+            dest_color: [255, 0, 0],
+            percent: 255,
+        };
+        let blue = ColorShift {
+            dest_color: [0, 0, 255],
+            percent: 255,
+        };
+        let blend = blend_color_shifts(vec![red, blue].into_iter(), 100.0);
+        assert!((blend[0] - 1.0 / 3.0).abs() < 1e-6);
+        assert!((blend[2] - 2.0 / 3.0).abs() < 1e-6);
+        assert!((blend[3] - 0.75).abs() < 1e-6);
+
+        assert_eq!(blend_color_shifts(vec![red].into_iter(), 0.0), [0.0; 4]);
+    }
+
+    #[test]
+    fn test_scoreboard_entries() {
+        let player = |name: &str, frags, join_secs| {
+            Some(PlayerInfo {
+                name: name.to_owned(),
+                frags,
+                colors: PlayerColor::new(4, 13),
+                join_time: Duration::seconds(join_secs),
+            })
+        };
+
+        let player_info = [
+            player("late", 5, 30),
+            None,
+            player("leader", 10, 20),
+            player("early", 5, 10),
+        ];
+
+        let entries = scoreboard_entries(&player_info);
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["leader", "early", "late"]);
+        assert_eq!(entries[0].player_id, 2);
+    }
+}
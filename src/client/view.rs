@@ -12,6 +12,9 @@ use super::IntermissionKind;
 use cgmath::{Angle as _, Deg, InnerSpace as _, Vector3, Zero as _};
 use chrono::Duration;
 
+// how far the view model is raised above the camera
+const VIEWMODEL_HEIGHT: f32 = 2.0;
+
 pub struct View {
     // entity "holding" the camera
     entity_id: usize,
@@ -42,6 +45,10 @@ pub struct View {
 
     // final origin accounting for view bob
     final_origin: Vector3<f32>,
+
+    // the view model ignores idle sway and server punch
+    viewmodel_angles: Angles,
+    viewmodel_origin: Vector3<f32>,
 }
 
 impl View {
@@ -57,6 +64,8 @@ impl View {
             punch_angles: Angles::zero(),
             final_angles: Angles::zero(),
             final_origin: Vector3::zero(),
+            viewmodel_angles: Angles::zero(),
+            viewmodel_origin: Vector3::zero(),
         }
     }
 
@@ -163,16 +172,25 @@ impl View {
     ) {
         self.damage_time = time + duration_from_f32(vars.v_kicktime);
 
-        // dmg_factor is at most 10.0
-        let dmg_factor = (armor_dmg + health_dmg).min(20.0) / 2.0;
-        let dmg_vector = (view_ent_origin - src_origin).normalize();
-        let rot = view_ent_angles.mat3_quake();
+        // dmg_factor is at least 10.0
+        let dmg_factor = ((armor_dmg + health_dmg) / 2.0).max(10.0);
 
-        let roll_factor = dmg_vector.dot(-rot.x);
-        self.damage_angles.roll = Deg(dmg_factor * roll_factor * vars.v_kickroll);
-
-        let pitch_factor = dmg_vector.dot(rot.y);
-        self.damage_angles.pitch = Deg(dmg_factor * pitch_factor * vars.v_kickpitch);
+        // damage from inside the player (e.g. drowning) has no direction
+        let from = src_origin - view_ent_origin;
+        if from.magnitude2() == 0.0 {
+            self.damage_angles = Angles::zero();
+            return;
+        }
+        let from = from.normalize();
+        let (forward, right, _) = math::angle_vectors(Vector3::new(
+            view_ent_angles.pitch,
+            view_ent_angles.yaw,
+            view_ent_angles.roll,
+        ));
+
+        // hits from the side roll the view, hits from the front pitch it
+        self.damage_angles.roll = Deg(dmg_factor * from.dot(right) * vars.v_kickroll);
+        self.damage_angles.pitch = Deg(dmg_factor * from.dot(forward) * vars.v_kickpitch);
     }
 
     pub fn calc_final_angles(
@@ -193,14 +211,17 @@ impl View {
         let kick_factor = duration_to_f32(self.damage_time - time).max(0.0) / kick_vars.v_kicktime;
         let damage_angles = self.damage_angles * kick_factor;
 
-        // always idle during intermission
+        // during intermission the view is fixed at the angles set by the
+        // server, and always idles
         if intermission.is_some() {
             idle_vars.v_idlescale = 1.0;
+            self.final_angles = self.input_angles + idle(time, idle_vars);
+            return;
         }
         let idle_angles = idle(time, idle_vars);
 
-        self.final_angles =
-            self.input_angles + move_angles + damage_angles + self.punch_angles + idle_angles;
+        self.viewmodel_angles = self.input_angles + move_angles + damage_angles;
+        self.final_angles = self.viewmodel_angles + self.punch_angles + idle_angles;
     }
 
     pub fn final_angles(&self) -> Angles {
@@ -210,27 +231,61 @@ impl View {
     pub fn calc_final_origin(
         &mut self,
         time: Duration,
+        intermission: Option<&IntermissionKind>,
         origin: Vector3<f32>,
         velocity: Vector3<f32>,
         bob_vars: BobVars,
     ) {
+        // the server moves the view entity to the intermission spot, and the
+        // camera sits exactly there
+        if intermission.is_some() {
+            self.final_origin = origin;
+            return;
+        }
+
         // offset the view by 1/32 unit to keep it from intersecting liquid planes
         let plane_offset = Vector3::new(1.0 / 32.0, 1.0 / 32.0, 1.0 / 32.0);
         let height_offset = Vector3::new(0.0, 0.0, self.view_height);
-        let bob_offset = Vector3::new(0.0, 0.0, bob(time, velocity, bob_vars));
+        let bob = bob(time, velocity, bob_vars);
+        let bob_offset = Vector3::new(0.0, 0.0, bob);
         self.final_origin = origin + plane_offset + height_offset + bob_offset;
+
+        // the weapon also bobs forward, and sits a little higher so its
+        // bottom edge stays offscreen
+        let (forward, _, _) = math::angle_vectors(Vector3::new(
+            self.input_angles.pitch,
+            self.input_angles.yaw,
+            self.input_angles.roll,
+        ));
+        self.viewmodel_origin = origin
+            + height_offset
+            + bob_offset
+            + forward * bob * 0.4
+            + Vector3::new(0.0, 0.0, VIEWMODEL_HEIGHT);
     }
 
     pub fn final_origin(&self) -> Vector3<f32> {
         self.final_origin
     }
 
-    pub fn viewmodel_angle(&self) -> Angles {
-        // TODO
-        self.final_angles()
+    pub fn viewmodel_angles(&self) -> Angles {
+        self.viewmodel_angles
+    }
+
+    pub fn viewmodel_origin(&self) -> Vector3<f32> {
+        self.viewmodel_origin
     }
 }
 
+/// The first-person weapon model.
+#[derive(Clone, Copy, Debug)]
+pub struct ViewModel {
+    pub model_id: usize,
+    pub frame_id: usize,
+    pub origin: Vector3<f32>,
+    pub angles: Angles,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct MouseVars {
     pub m_pitch: f32,
@@ -253,6 +308,10 @@ pub struct BobVars {
 }
 
 pub fn bob(time: Duration, velocity: Vector3<f32>, vars: BobVars) -> f32 {
+    if vars.cl_bobcycle <= 0.0 {
+        return 0.0;
+    }
+
     let time = duration_to_f32(time);
     let ratio = (time % vars.cl_bobcycle) / vars.cl_bobcycle;
     let cycle = if ratio < vars.cl_bobup {
@@ -275,12 +334,12 @@ pub struct RollVars {
 }
 
 pub fn roll(angles: Angles, velocity: Vector3<f32>, vars: RollVars) -> Deg<f32> {
-    let rot = angles.mat3_quake();
-    let side = velocity.dot(rot.y);
+    let (_, right, _) = math::angle_vectors(Vector3::new(angles.pitch, angles.yaw, angles.roll));
+    let side = velocity.dot(right);
     let sign = side.signum();
     let side_abs = side.abs();
 
-    let roll_abs = if side < vars.cl_rollspeed {
+    let roll_abs = if side_abs < vars.cl_rollspeed {
         side_abs * vars.cl_rollangle / vars.cl_rollspeed
     } else {
         vars.cl_rollangle
@@ -308,3 +367,34 @@ pub fn idle(time: Duration, vars: IdleVars) -> Angles {
 
     Angles { pitch, roll, yaw }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roll_clamped_both_directions() {
+        let vars = RollVars {
+            cl_rollangle: 2.0,
+            cl_rollspeed: 200.0,
+        };
+
+        // facing +x, right is -y
+        let angles = Angles::zero();
+        assert_eq!(roll(angles, Vector3::new(0.0, -100.0, 0.0), vars), Deg(1.0));
+        assert_eq!(roll(angles, Vector3::new(0.0, -400.0, 0.0), vars), Deg(2.0));
+        assert_eq!(roll(angles, Vector3::new(0.0, 400.0, 0.0), vars), Deg(-2.0));
+    }
+
+    #[test]
+    fn test_bob_zero_cycle() {
+        let vars = BobVars {
+            cl_bob: 0.02,
+            cl_bobcycle: 0.0,
+            cl_bobup: 0.5,
+        };
+
+        let b = bob(Duration::seconds(1), Vector3::new(320.0, 0.0, 0.0), vars);
+        assert_eq!(b, 0.0);
+    }
+}
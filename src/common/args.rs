@@ -0,0 +1,216 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Command-line argument handling.
+//!
+//! Besides normal `--long` options, the original engine's conventions are
+//! accepted: single-dash options like `-game hipnotic`, and trailing console
+//! commands like `+map e1m1 +skill 2`. The console commands run when
+//! `stuffcmds` is executed, normally at the end of `quake.rc`.
+//!
+//! Any other single-dash option sets the cvar of the same name, so
+//! `-maxplayers 8` is the same as `+maxplayers 8`.
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::common::console::Console;
+
+// single-dash options from the original engine and their replacements
+const CLASSIC_OPTIONS: &[(&str, &str)] = &[
+    ("-basedir", "--base-dir"),
+    ("-dedicated", "--dedicated"),
+    ("-game", "--game"),
+];
+
+// single-dash options from the original engine that set a differently named
+// cvar
+const CLASSIC_CVARS: &[(&str, &str)] = &[("-port", "hostport")];
+
+/// Command-line arguments, split into options and console commands.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CommandLine {
+    /// Program name and options, with classic options translated to their
+    /// `--long` form.
+    pub options: Vec<String>,
+
+    /// Console commands given with `+`, without the `+`.
+    pub commands: Vec<String>,
+}
+
+impl CommandLine {
+    pub fn parse<I, S>(args: I) -> CommandLine
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut cmdline = CommandLine::default();
+        let mut command: Option<Vec<String>> = None;
+
+        for arg in args.into_iter().map(Into::into) {
+            if arg.starts_with('+') && arg.len() > 1 {
+                cmdline.finish_command(command.take());
+                command = Some(vec![arg[1..].to_owned()]);
+                continue;
+            }
+
+            // a command's arguments run until the next option, though
+            // negative numbers are still arguments
+            let is_option = arg.starts_with('-') && arg.parse::<f32>().is_err();
+            if let Some(ref mut c) = command {
+                if !is_option {
+                    c.push(arg);
+                    continue;
+                }
+            }
+            cmdline.finish_command(command.take());
+
+            match CLASSIC_OPTIONS.iter().find(|(classic, _)| *classic == arg) {
+                Some((_, long)) => cmdline.options.push((*long).to_owned()),
+
+                // one-letter flags like -h are left for the option parser
+                None if is_option && !arg.starts_with("--") && arg.len() > 2 => {
+                    let cvar = match CLASSIC_CVARS.iter().find(|(classic, _)| *classic == arg) {
+                        Some((_, cvar)) => (*cvar).to_owned(),
+                        None => arg[1..].to_owned(),
+                    };
+                    command = Some(vec![cvar]);
+                }
+
+                None => cmdline.options.push(arg),
+            }
+        }
+
+        cmdline.finish_command(command);
+        cmdline
+    }
+
+    fn finish_command(&mut self, command: Option<Vec<String>>) {
+        let command = match command {
+            Some(c) => c,
+            None => return,
+        };
+
+        let mut text = command[0].clone();
+        for arg in &command[1..] {
+            text.push(' ');
+            if arg.is_empty() || arg.contains(char::is_whitespace) || arg.contains(';') {
+                text.push('"');
+                text.push_str(arg);
+                text.push('"');
+            } else {
+                text.push_str(arg);
+            }
+        }
+
+        self.commands.push(text);
+    }
+}
+
+/// Implements the `stuffcmds` command.
+///
+/// Adds the console commands from the command line to the command buffer.
+pub fn cmd_stuffcmds(
+    console: Rc<RefCell<Console>>,
+    commands: Vec<String>,
+) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| {
+        let console = console.borrow();
+        for command in commands.iter().rev() {
+            console.insert_text(command);
+        }
+
+        String::new()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_options() {
+        let cmdline = CommandLine::parse(vec![
+            "quake-client",
+            "-basedir",
+            "/games/quake",
+            "-game",
+            "hipnotic",
+            "--trace",
+        ]);
+        assert_eq!(
+            cmdline.options,
+            vec![
+                "quake-client",
+                "--base-dir",
+                "/games/quake",
+                "--game",
+                "hipnotic",
+                "--trace"
+            ]
+        );
+        assert!(cmdline.commands.is_empty());
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        let cmdline = CommandLine::parse(vec![
+            "quake-client",
+            "+map",
+            "e1m1",
+            "+cl_forwardspeed",
+            "-200",
+            "-game",
+            "rogue",
+            "+name",
+            "Big Bob",
+            "+connect",
+        ]);
+        assert_eq!(cmdline.options, vec!["quake-client", "--game", "rogue"]);
+        assert_eq!(
+            cmdline.commands,
+            vec![
+                "map e1m1",
+                "cl_forwardspeed -200",
+                "name \"Big Bob\"",
+                "connect"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_cvar_options() {
+        let cmdline = CommandLine::parse(vec![
+            "quake-client",
+            "-dedicated",
+            "-port",
+            "26001",
+            "-maxplayers",
+            "8",
+            "-h",
+            "+map",
+            "e1m1",
+        ]);
+        assert_eq!(cmdline.options, vec!["quake-client", "--dedicated", "-h"]);
+        assert_eq!(
+            cmdline.commands,
+            vec!["hostport 26001", "maxplayers 8", "map e1m1"]
+        );
+    }
+}
@@ -42,7 +42,7 @@ use failure::ResultExt as _;
 use num::FromPrimitive;
 use thiserror::Error;
 
-const VERSION: i32 = 29;
+pub(super) const VERSION: i32 = 29;
 
 pub const MAX_MODELS: usize = 256;
 const MAX_LEAVES: usize = 32767;
@@ -96,6 +96,10 @@ pub enum BspFileError {
     InvalidTextureFrameSpecifier(String),
     #[error("texture has primary animation with 0 frames: {0}")]
     EmptyPrimaryAnimation(String),
+    #[error("texture animation {name} is missing frame {frame}")]
+    MissingTextureFrame { name: String, frame: char },
+    #[error("texture animation {name} has more than one frame {frame}")]
+    DuplicateTextureFrame { name: String, frame: char },
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -303,6 +307,33 @@ where
     })
 }
 
+// check that the frames of an animation, sorted by name, are numbered consecutively from `first`
+fn check_frame_sequence(
+    stem: &str,
+    frames: &[(usize, BspFileTexture)],
+    first: char,
+) -> Result<(), BspFileError> {
+    for (i, (_, texture)) in frames.iter().enumerate() {
+        let expected = (first as u8 + i as u8) as char;
+
+        // names have already been matched as +[frame][stem]
+        let frame = texture.name.chars().nth(1).unwrap();
+        if frame < expected {
+            Err(BspFileError::DuplicateTextureFrame {
+                name: stem.to_owned(),
+                frame,
+            })?;
+        } else if frame > expected {
+            Err(BspFileError::MissingTextureFrame {
+                name: stem.to_owned(),
+                frame: expected,
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
 fn load_render_node<R>(reader: &mut R) -> Result<BspRenderNode, failure::Error>
 where
     R: ReadBytesExt,
@@ -607,9 +638,9 @@ where
             Err(BspFileError::EmptyPrimaryAnimation(name.to_owned()))?;
         }
 
-        // TODO: ensure one-to-one frame specifiers
         // sort names in ascending order to get the frames ordered correctly
         pri.sort_unstable_by(|(_, tex), (_, other)| tex.name.cmp(&other.name));
+        check_frame_sequence(&name, &pri, '0')?;
 
         // TODO: verify width and height?
         let width = pri[0].1.width;
@@ -634,6 +665,7 @@ where
             0 => None,
             _ => {
                 alt.sort_unstable_by(|(_, tex), (_, other)| tex.name.cmp(&other.name));
+                check_frame_sequence(&name, &alt, 'a')?;
                 let mut alternate = Vec::new();
                 for (file_id, file_texture) in alt {
                     alt_corresp_file_ids.push(file_id);
@@ -1117,3 +1149,42 @@ where
     reader.read_i16_into::<LittleEndian>(&mut ar)?;
     Ok(ar)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frames(names: &[&str]) -> Vec<(usize, BspFileTexture)> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                (
+                    i,
+                    BspFileTexture {
+                        name: name.to_string(),
+                        width: 0,
+                        height: 0,
+                        mipmaps: [Vec::new(), Vec::new(), Vec::new(), Vec::new()],
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_check_frame_sequence() {
+        check_frame_sequence("slip", &frames(&["+0slip", "+1slip", "+2slip"]), '0').unwrap();
+        check_frame_sequence("slip", &frames(&["+aslip", "+bslip"]), 'a').unwrap();
+
+        match check_frame_sequence("slip", &frames(&["+0slip", "+2slip"]), '0') {
+            Err(BspFileError::MissingTextureFrame { frame: '1', .. }) => (),
+            r => panic!("expected missing frame 1, got {:?}", r),
+        }
+
+        match check_frame_sequence("slip", &frames(&["+aslip", "+aslip"]), 'a') {
+            Err(BspFileError::DuplicateTextureFrame { frame: 'a', .. }) => (),
+            r => panic!("expected duplicate frame a, got {:?}", r),
+        }
+    }
+}
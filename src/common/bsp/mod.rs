@@ -117,15 +117,16 @@
 //! The edges are stored as a pair of 16-bit integer vertex IDs.
 
 mod load;
+pub mod stress;
 
 use std::{collections::HashSet, error::Error, fmt, iter::Iterator, rc::Rc};
 
-use crate::common::math::{Hyperplane, HyperplaneSide, LinePlaneIntersect};
+use crate::common::math::{Frustum, Hyperplane, HyperplaneSide, LinePlaneIntersect};
 
 // TODO: Either Trace should be moved into common or the functions requiring it should be moved into server
 use crate::server::world::{Trace, TraceEnd, TraceStart};
 
-use cgmath::Vector3;
+use cgmath::{InnerSpace as _, Vector3};
 use chrono::Duration;
 
 pub use self::load::{load, BspFileError};
@@ -143,6 +144,18 @@ pub fn frame_duration() -> Duration {
     Duration::milliseconds(200)
 }
 
+/// Returns the index of the frame to show at `time` for an animation of `frame_count` frames.
+///
+/// Animations advance one frame every `frame_duration()` and loop forever.
+pub fn animation_frame(time: Duration, frame_count: usize) -> usize {
+    if frame_count == 0 {
+        return 0;
+    }
+
+    let frame = time.num_milliseconds() / frame_duration().num_milliseconds();
+    frame.rem_euclid(frame_count as i64) as usize
+}
+
 #[derive(Debug)]
 pub enum BspError {
     Io(::std::io::Error),
@@ -343,6 +356,13 @@ pub enum BspLeafContents {
     CurrentDown = 14,
 }
 
+impl BspLeafContents {
+    /// Returns `true` if the leaf is filled with water, slime or lava.
+    pub fn is_liquid(&self) -> bool {
+        !matches!(self, BspLeafContents::Empty | BspLeafContents::Solid)
+    }
+}
+
 #[derive(Debug)]
 pub enum BspCollisionNodeChild {
     Node(usize),
@@ -828,41 +848,153 @@ impl BspData {
         }
     }
 
+    /// Decompresses the potentially visible set of the leaf `leaf_id`.
+    ///
+    /// The result holds one bit per leaf, starting with leaf 1 in the lowest bit of the first
+    /// byte. `leaf_count` is the number of leaves in the world model including leaf 0. Leaf 0 is
+    /// outside the map, so it sees everything, as does every leaf of a map without visibility
+    /// data.
+    pub fn decompress_vis(&self, leaf_id: usize, leaf_count: usize) -> Vec<u8> {
+        let visleafs = leaf_count.saturating_sub(1);
+        let row_len = (visleafs + 7) / 8;
+
+        match self.leaves.get(leaf_id).and_then(|leaf| leaf.vis_offset) {
+            Some(o) if leaf_id != 0 => {
+                decompress_vis_row(self.visibility.get(o..).unwrap_or(&[]), row_len)
+            }
+
+            _ => {
+                let mut row = vec![0xFF; row_len];
+                if visleafs % 8 != 0 {
+                    row[row_len - 1] = (1 << (visleafs % 8)) - 1;
+                }
+                row
+            }
+        }
+    }
+
+    /// Returns the IDs of the leaves in the potentially visible set of `leaf_id`.
+    ///
+    /// See `decompress_vis` for the meaning of `leaf_count`.
     pub fn get_pvs(&self, leaf_id: usize, leaf_count: usize) -> Vec<usize> {
-        // leaf 0 is outside the map, everything is visible
-        if leaf_id == 0 {
-            return Vec::new();
+        let row = self.decompress_vis(leaf_id, leaf_count);
+        (1..leaf_count)
+            .filter(|l| row[(l - 1) / 8] & 1 << ((l - 1) % 8) != 0)
+            .collect()
+    }
+
+    /// Returns the leaves of the world model that are both in the PVS row `vis` and at least
+    /// partly inside `frustum`.
+    ///
+    /// `vis` is a row as returned by `decompress_vis`. Nodes entirely outside the frustum are
+    /// skipped along with everything under them.
+    pub fn visible_leaves(&self, vis: &[u8], frustum: &Frustum) -> Vec<usize> {
+        let mut leaf_ids = Vec::new();
+        self.mark_visible_leaves(0, vis, frustum, &mut leaf_ids);
+        leaf_ids
+    }
+
+    fn mark_visible_leaves(
+        &self,
+        node_id: usize,
+        vis: &[u8],
+        frustum: &Frustum,
+        leaf_ids: &mut Vec<usize>,
+    ) {
+        let node = &self.render_nodes[node_id];
+        if frustum.cull_box(bounds_to_vec(node.min), bounds_to_vec(node.max)) {
+            return;
         }
 
-        match self.leaves[leaf_id].vis_offset {
-            Some(o) => {
-                let mut visleaf = 1;
-                let mut visleaf_list = Vec::new();
-                let mut it = (&self.visibility[o..]).iter();
-
-                while visleaf < leaf_count {
-                    let byte = it.next().unwrap();
-                    match *byte {
-                        // a zero byte signals the start of an RLE sequence
-                        0 => visleaf += 8 * *it.next().unwrap() as usize,
-
-                        bits => {
-                            for shift in 0..8 {
-                                if bits & 1 << shift != 0 {
-                                    visleaf_list.push(visleaf);
-                                }
-
-                                visleaf += 1;
-                            }
-                        }
+        for child in node.children.iter() {
+            match *child {
+                BspRenderNodeChild::Node(child_id) => {
+                    self.mark_visible_leaves(child_id, vis, frustum, leaf_ids)
+                }
+
+                // leaf 0 is solid and never drawn
+                BspRenderNodeChild::Leaf(0) => (),
+
+                BspRenderNodeChild::Leaf(leaf_id) => {
+                    let leaf = &self.leaves[leaf_id];
+                    let in_pvs = vis
+                        .get((leaf_id - 1) / 8)
+                        .map(|bits| bits & 1 << ((leaf_id - 1) % 8) != 0)
+                        .unwrap_or(false);
+
+                    if in_pvs && !frustum.cull_box(bounds_to_vec(leaf.min), bounds_to_vec(leaf.max))
+                    {
+                        leaf_ids.push(leaf_id);
                     }
                 }
+            }
+        }
+    }
 
-                visleaf_list
+    /// Returns the faces under `node_id` that a light at `origin` with the given `radius` can
+    /// reach.
+    ///
+    /// Faces further than `radius` from `origin`, either from their plane or from their texture
+    /// extents, are left out.
+    pub fn light_faces(&self, node_id: usize, origin: Vector3<f32>, radius: f32) -> Vec<usize> {
+        let mut face_ids = Vec::new();
+        self.mark_light_faces(node_id, origin, radius, &mut face_ids);
+        face_ids
+    }
+
+    fn mark_light_faces(
+        &self,
+        node_id: usize,
+        origin: Vector3<f32>,
+        radius: f32,
+        face_ids: &mut Vec<usize>,
+    ) {
+        let node = &self.render_nodes[node_id];
+        let dist = self.planes[node.plane_id].point_dist(origin);
+
+        // the light only reaches one side of the plane
+        let sides: &[usize] = if dist > radius {
+            &[0]
+        } else if dist < -radius {
+            &[1]
+        } else {
+            for face_id in node.face_id..node.face_id + node.face_count {
+                if self.face_in_light(face_id, origin, radius) {
+                    face_ids.push(face_id);
+                }
             }
 
-            None => Vec::new(),
+            &[0, 1]
+        };
+
+        for side in sides {
+            if let BspRenderNodeChild::Node(child_id) = node.children[*side] {
+                self.mark_light_faces(child_id, origin, radius, face_ids);
+            }
+        }
+    }
+
+    fn face_in_light(&self, face_id: usize, origin: Vector3<f32>, radius: f32) -> bool {
+        let face = &self.faces[face_id];
+        let plane = &self.planes[face.plane_id];
+        let dist = plane.point_dist(origin);
+        if dist.abs() > radius {
+            return false;
         }
+
+        // project the light onto the face and find the nearest point within its extents
+        let impact = origin - plane.normal() * dist;
+        let texinfo = &self.texinfo[face.texinfo_id];
+        let local = [
+            impact.dot(texinfo.s_vector) + texinfo.s_offset - face.texture_mins[0] as f32,
+            impact.dot(texinfo.t_vector) + texinfo.t_offset - face.texture_mins[1] as f32,
+        ];
+        let outside = [
+            local[0] - local[0].max(0.0).min(face.extents[0] as f32),
+            local[1] - local[1].max(0.0).min(face.extents[1] as f32),
+        ];
+
+        outside[0] * outside[0] + outside[1] * outside[1] <= radius * radius - dist * dist
     }
 
     pub fn gen_dot_graph(&self) -> String {
@@ -979,6 +1111,35 @@ impl BspModel {
         self.bsp_data.leaves[self.leaf_id..self.leaf_id + self.leaf_count + 1].iter()
     }
 
+    /// Returns `true` if the map was vised for translucent liquids.
+    ///
+    /// Only meaningful for the world model. Without watervis, no leaf inside a liquid can see an
+    /// empty leaf, so anything on the far side of a liquid surface is culled and translucent
+    /// liquids would show holes. Maps without liquids or without visibility data count as vised.
+    pub fn has_water_vis(&self) -> bool {
+        let leaf_count = self.leaf_count + 1;
+        let leaves = &self.bsp_data.leaves[self.leaf_id..self.leaf_id + leaf_count];
+
+        let mut has_liquid = false;
+        for (leaf_id, leaf) in leaves.iter().enumerate().skip(1) {
+            if !leaf.contents.is_liquid() {
+                continue;
+            }
+
+            has_liquid = true;
+            if self
+                .bsp_data
+                .get_pvs(leaf_id, leaf_count)
+                .into_iter()
+                .any(|l| leaves[l].contents == BspLeafContents::Empty)
+            {
+                return true;
+            }
+        }
+
+        !has_liquid
+    }
+
     pub fn iter_faces(&self) -> impl Iterator<Item = &BspFace> {
         self.bsp_data.facelist[self.face_id..self.face_id + self.face_count]
             .iter()
@@ -989,6 +1150,37 @@ impl BspModel {
         &self.bsp_data.facelist[self.face_id..self.face_id + self.face_count]
     }
 
+    /// Returns the faces of this model that a light at `origin` can reach.
+    ///
+    /// `origin` is relative to the model.
+    pub fn light_faces(&self, origin: Vector3<f32>, radius: f32) -> Vec<usize> {
+        // the head node of hull 0 is the root of the model's render tree
+        self.bsp_data
+            .light_faces(self.collision_node_ids[0], origin, radius)
+    }
+
+    /// Returns the faces of this model that may be visible from `origin`, in order.
+    ///
+    /// `origin` is relative to the model. Only the world model has visibility data, so all the
+    /// faces of any other model are returned.
+    pub fn visible_faces(&self, origin: Vector3<f32>) -> Vec<usize> {
+        let faces = self.face_id..self.face_id + self.face_count;
+        if self.leaf_count == 0 {
+            return faces.collect();
+        }
+
+        let leaf_id = self.bsp_data.find_leaf(origin);
+        let mut marked = vec![false; self.bsp_data.faces.len()];
+        for visleaf in self.bsp_data.get_pvs(leaf_id, self.leaf_count + 1) {
+            let leaf = &self.bsp_data.leaves[visleaf];
+            for facelist_id in leaf.facelist_id..leaf.facelist_id + leaf.facelist_count {
+                marked[self.bsp_data.facelist[facelist_id]] = true;
+            }
+        }
+
+        faces.filter(|f| marked[*f]).collect()
+    }
+
     pub fn hull(&self, index: usize) -> Result<BspCollisionHull, BspError> {
         if index > MAX_HULLS {
             return Err(BspError::with_msg(format!(
@@ -1011,11 +1203,62 @@ impl BspModel {
 
 impl BspData {}
 
+fn bounds_to_vec(b: [i16; 3]) -> Vector3<f32> {
+    Vector3::new(b[0] as f32, b[1] as f32, b[2] as f32)
+}
+
+/// Run-length decodes one row of the visibility lump.
+///
+/// Runs of zero bytes are stored as a zero followed by the length of the run. Data that ends
+/// early leaves the rest of the row zeroed.
+fn decompress_vis_row(data: &[u8], row_len: usize) -> Vec<u8> {
+    let mut row = Vec::with_capacity(row_len);
+    let mut it = data.iter();
+
+    while row.len() < row_len {
+        match it.next() {
+            Some(0) => {
+                let run = it.next().copied().unwrap_or(0) as usize;
+                row.resize((row.len() + run).min(row_len), 0);
+            }
+
+            Some(bits) => row.push(*bits),
+
+            None => break,
+        }
+    }
+
+    row.resize(row_len, 0);
+    row
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use cgmath::Zero;
 
+    #[test]
+    fn test_animation_frame() {
+        assert_eq!(animation_frame(Duration::zero(), 4), 0);
+        assert_eq!(animation_frame(Duration::milliseconds(199), 4), 0);
+        assert_eq!(animation_frame(Duration::milliseconds(200), 4), 1);
+        assert_eq!(animation_frame(Duration::milliseconds(1000), 4), 1);
+        assert_eq!(animation_frame(Duration::milliseconds(1000), 1), 0);
+        assert_eq!(animation_frame(Duration::milliseconds(1000), 0), 0);
+    }
+
+    #[test]
+    fn test_decompress_vis_row() {
+        assert_eq!(
+            decompress_vis_row(&[0x81, 0, 3, 0x0F], 5),
+            vec![0x81, 0, 0, 0, 0x0F]
+        );
+
+        // runs are clipped to the row, and missing data is invisible
+        assert_eq!(decompress_vis_row(&[0x01, 0, 200], 3), vec![0x01, 0, 0]);
+        assert_eq!(decompress_vis_row(&[0xFF], 3), vec![0xFF, 0, 0]);
+    }
+
     #[test]
     fn test_hull_for_bounds() {
         let hull =
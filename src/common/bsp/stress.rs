@@ -0,0 +1,890 @@
+// Copyright © 2020 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Procedurally generated BSP files for limits and performance testing.
+//!
+//! The generated map is a flat floor at z = 0, divided into a grid of square faces. The space
+//! above the floor is split into leaves by a balanced tree of axial planes, every leaf can see
+//! every other leaf, and everything below the floor is solid. Faces cycle through a set of
+//! checkered textures and carry lightmaps for up to four light styles. The entity lump holds a
+//! `worldspawn`, an `info_player_start` in the middle of the floor and any number of extra
+//! point entities spread over the grid.
+//!
+//! Nothing here checks the loader's limits, so maps that exceed them can be generated on purpose.
+//! The generator only refuses specs that can't be represented in the file format.
+
+use std::{collections::HashMap, io::Write as _};
+
+use crate::common::bsp::{load::VERSION, MAX_LIGHTSTYLES, MIPLEVELS};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use failure::Error;
+
+const SECTION_COUNT: usize = 15;
+const HEADER_SIZE: usize = 4 + SECTION_COUNT * 8;
+const TEX_NAME_MAX: usize = 16;
+
+// leaf and model bounds extend this far above the floor
+const CEILING: i32 = 512;
+
+// the collision hulls are offset by the player's bounding box, which extends 24 units below
+// its origin
+const HULL_FLOOR: f32 = 24.0;
+
+const CONTENTS_EMPTY: i32 = -1;
+const CONTENTS_SOLID: i32 = -2;
+
+const AXIS_X: i32 = 0;
+const AXIS_Y: i32 = 1;
+const AXIS_Z: i32 = 2;
+
+/// Describes the size of a generated map.
+#[derive(Clone, Debug)]
+pub struct StressMapSpec {
+    /// The number of faces along the x-axis.
+    pub grid_width: usize,
+
+    /// The number of faces along the y-axis.
+    pub grid_height: usize,
+
+    /// The edge length of each face in world units. Must be a multiple of 16 no greater than 256.
+    pub face_size: usize,
+
+    /// The number of faces along each edge of a leaf.
+    pub leaf_size: usize,
+
+    /// The number of distinct textures, assigned to faces in turn.
+    pub texture_count: usize,
+
+    /// The width and height of each texture. Must be a nonzero multiple of 8.
+    pub texture_size: u32,
+
+    /// The number of light styles on each face, between 0 and 4. Each style adds a full lightmap
+    /// per face.
+    pub light_styles: usize,
+
+    /// The number of point entities in addition to `worldspawn` and `info_player_start`.
+    pub entity_count: usize,
+
+    /// The class name of the extra entities.
+    pub entity_classname: String,
+}
+
+impl Default for StressMapSpec {
+    fn default() -> Self {
+        StressMapSpec {
+            grid_width: 16,
+            grid_height: 16,
+            face_size: 128,
+            leaf_size: 1,
+            texture_count: 8,
+            texture_size: 64,
+            light_styles: 1,
+            entity_count: 64,
+            entity_classname: "info_notnull".to_owned(),
+        }
+    }
+}
+
+/// Counts of the elements in a generated map.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StressMapStats {
+    pub faces: usize,
+    pub leaves: usize,
+    pub nodes: usize,
+    pub planes: usize,
+    pub vertices: usize,
+    pub edges: usize,
+    pub textures: usize,
+    pub lightmap_bytes: usize,
+    pub entities: usize,
+    pub entity_bytes: usize,
+}
+
+// an on-disk render node, before its plane and children are known
+#[derive(Clone, Default)]
+struct NodeRecord {
+    plane_id: i32,
+    children: [i16; 2],
+    min: [i16; 3],
+    max: [i16; 3],
+    face_id: u16,
+    face_count: u16,
+}
+
+struct LeafRecord {
+    contents: i32,
+    vis_offset: i32,
+    min: [i16; 3],
+    max: [i16; 3],
+    facelist_id: u16,
+    facelist_count: u16,
+}
+
+// a rectangle of faces, in grid coordinates
+#[derive(Copy, Clone)]
+struct Block {
+    x0: usize,
+    x1: usize,
+    y0: usize,
+    y1: usize,
+}
+
+struct TreeBuilder<'a> {
+    spec: &'a StressMapSpec,
+    origin: [i32; 2],
+    planes: Vec<(i32, f32)>,
+    plane_ids: HashMap<(i32, i32), usize>,
+    nodes: Vec<NodeRecord>,
+    leaves: Vec<LeafRecord>,
+    facelist: Vec<u16>,
+}
+
+impl<'a> TreeBuilder<'a> {
+    fn plane(&mut self, axis: i32, dist: i32) -> i32 {
+        let planes = &mut self.planes;
+        *self.plane_ids.entry((axis, dist)).or_insert_with(|| {
+            planes.push((axis, dist as f32));
+            planes.len() - 1
+        }) as i32
+    }
+
+    fn bounds(&self, block: Block) -> ([i16; 3], [i16; 3]) {
+        let size = self.spec.face_size as i32;
+        (
+            [
+                (self.origin[0] + block.x0 as i32 * size) as i16,
+                (self.origin[1] + block.y0 as i32 * size) as i16,
+                0,
+            ],
+            [
+                (self.origin[0] + block.x1 as i32 * size) as i16,
+                (self.origin[1] + block.y1 as i32 * size) as i16,
+                CEILING as i16,
+            ],
+        )
+    }
+
+    // returns the on-disk child index of the subtree covering `block`
+    fn build(&mut self, block: Block) -> i16 {
+        let leaf_size = self.spec.leaf_size;
+        let blocks_x = (block.x1 - block.x0 + leaf_size - 1) / leaf_size;
+        let blocks_y = (block.y1 - block.y0 + leaf_size - 1) / leaf_size;
+        let (min, max) = self.bounds(block);
+
+        if blocks_x <= 1 && blocks_y <= 1 {
+            let facelist_id = self.facelist.len();
+            for y in block.y0..block.y1 {
+                for x in block.x0..block.x1 {
+                    self.facelist.push((y * self.spec.grid_width + x) as u16);
+                }
+            }
+
+            self.leaves.push(LeafRecord {
+                contents: CONTENTS_EMPTY,
+                vis_offset: 0,
+                min,
+                max,
+                facelist_id: facelist_id as u16,
+                facelist_count: (self.facelist.len() - facelist_id) as u16,
+            });
+
+            return !(self.leaves.len() as i16 - 1);
+        }
+
+        let node_id = self.nodes.len();
+        self.nodes.push(NodeRecord::default());
+
+        // split along the longer axis, keeping whole leaves on either side
+        let size = self.spec.face_size as i32;
+        let (plane_id, front, back) = if blocks_x >= blocks_y {
+            let mid = block.x0 + blocks_x / 2 * leaf_size;
+            let dist = self.origin[0] + mid as i32 * size;
+            (
+                self.plane(AXIS_X, dist),
+                Block { x0: mid, ..block },
+                Block { x1: mid, ..block },
+            )
+        } else {
+            let mid = block.y0 + blocks_y / 2 * leaf_size;
+            let dist = self.origin[1] + mid as i32 * size;
+            (
+                self.plane(AXIS_Y, dist),
+                Block { y0: mid, ..block },
+                Block { y1: mid, ..block },
+            )
+        };
+
+        let front = self.build(front);
+        let back = self.build(back);
+        self.nodes[node_id] = NodeRecord {
+            plane_id,
+            children: [front, back],
+            min,
+            max,
+            face_id: 0,
+            face_count: 0,
+        };
+
+        node_id as i16
+    }
+}
+
+impl StressMapSpec {
+    fn validate(&self) -> Result<(), Error> {
+        ensure!(
+            self.grid_width > 0 && self.grid_height > 0,
+            "grid must have at least one face"
+        );
+        ensure!(
+            self.face_size > 0 && self.face_size % 16 == 0 && self.face_size <= 256,
+            "face size must be a multiple of 16 no greater than 256"
+        );
+        ensure!(self.leaf_size > 0, "leaf size must be nonzero");
+        ensure!(
+            (self.grid_width + 1) * (self.grid_height + 1) <= u16::MAX as usize + 1,
+            "too many vertices for 16-bit vertex indices"
+        );
+        ensure!(
+            self.face_count() <= u16::MAX as usize,
+            "too many faces for 16-bit face lists"
+        );
+        ensure!(
+            self.leaf_count() <= i16::MAX as usize,
+            "too many leaves for 16-bit node children"
+        );
+        ensure!(
+            (self.grid_width.max(self.grid_height) + 1) / 2 * self.face_size <= i16::MAX as usize,
+            "grid is too large for 16-bit bounding boxes"
+        );
+        ensure!(
+            self.texture_count > 0 && self.texture_count <= i16::MAX as usize,
+            "texture count must be between 1 and {}",
+            i16::MAX
+        );
+        ensure!(
+            self.texture_size > 0 && self.texture_size % 8 == 0,
+            "texture size must be a nonzero multiple of 8"
+        );
+        ensure!(
+            self.light_styles <= MAX_LIGHTSTYLES,
+            "at most {} light styles are supported",
+            MAX_LIGHTSTYLES
+        );
+
+        Ok(())
+    }
+
+    pub fn face_count(&self) -> usize {
+        self.grid_width * self.grid_height
+    }
+
+    /// The number of leaves, including the solid leaf 0.
+    pub fn leaf_count(&self) -> usize {
+        let leaves_x = (self.grid_width + self.leaf_size - 1) / self.leaf_size;
+        let leaves_y = (self.grid_height + self.leaf_size - 1) / self.leaf_size;
+        leaves_x * leaves_y + 1
+    }
+
+    /// The size in bytes of the lightmap lump.
+    pub fn lightmap_bytes(&self) -> usize {
+        let samples = self.face_size / 16 + 1;
+        self.face_count() * samples * samples * self.light_styles
+    }
+
+    // the world-space position of the grid's minimum corner, kept on a face boundary so
+    // texture extents line up with the lightmap grid
+    fn origin(&self) -> [i32; 2] {
+        let size = self.face_size as i32;
+        [
+            -(self.grid_width as i32 / 2) * size,
+            -(self.grid_height as i32 / 2) * size,
+        ]
+    }
+
+    /// Generate the entity lump.
+    pub fn entity_string(&self) -> String {
+        let size = self.face_size as i32;
+        let origin = self.origin();
+
+        let mut ents = String::new();
+        ents += "{\n\"classname\" \"worldspawn\"\n\"message\" \"Stress test\"\n}\n";
+        ents += &format!(
+            "{{\n\"classname\" \"info_player_start\"\n\"origin\" \"{} {} 24\"\n}}\n",
+            origin[0] + self.grid_width as i32 * size / 2,
+            origin[1] + self.grid_height as i32 * size / 2,
+        );
+
+        // one entity over the center of each face, stacking up once the grid is full
+        let cells = self.face_count();
+        for i in 0..self.entity_count {
+            let cell = i % cells;
+            let x = origin[0] + (cell % self.grid_width) as i32 * size + size / 2;
+            let y = origin[1] + (cell / self.grid_width) as i32 * size + size / 2;
+            let z = 32 + 64 * (i / cells) as i32;
+            ents += &format!(
+                "{{\n\"classname\" \"{}\"\n\"origin\" \"{} {} {}\"\n}}\n",
+                self.entity_classname, x, y, z
+            );
+        }
+
+        ents
+    }
+
+    /// Generate a BSP file matching this spec.
+    pub fn generate(&self) -> Result<(Vec<u8>, StressMapStats), Error> {
+        self.validate()?;
+
+        let w = self.grid_width;
+        let h = self.grid_height;
+        let size = self.face_size as i32;
+        let origin = self.origin();
+
+        let mut builder = TreeBuilder {
+            spec: self,
+            origin,
+            planes: Vec::new(),
+            plane_ids: HashMap::new(),
+            nodes: Vec::new(),
+            leaves: Vec::new(),
+            facelist: Vec::new(),
+        };
+
+        // plane 0 is the floor, plane 1 the floor of the player-sized collision hulls
+        let floor_plane = builder.plane(AXIS_Z, 0);
+        builder.planes.push((AXIS_Z, HULL_FLOOR));
+
+        // leaf 0 is the solid space below the floor
+        let (mut min, mut max) = builder.bounds(Block {
+            x0: 0,
+            x1: w,
+            y0: 0,
+            y1: h,
+        });
+        builder.leaves.push(LeafRecord {
+            contents: CONTENTS_SOLID,
+            vis_offset: -1,
+            min: [min[0], min[1], -(CEILING as i16)],
+            max: [max[0], max[1], 0],
+            facelist_id: 0,
+            facelist_count: 0,
+        });
+
+        // the root node holds every face on the floor plane
+        builder.nodes.push(NodeRecord::default());
+        let above = builder.build(Block {
+            x0: 0,
+            x1: w,
+            y0: 0,
+            y1: h,
+        });
+        min[2] = -(CEILING as i16);
+        max[2] = CEILING as i16;
+        builder.nodes[0] = NodeRecord {
+            plane_id: floor_plane,
+            children: [above, !0],
+            min,
+            max,
+            face_id: 0,
+            face_count: self.face_count() as u16,
+        };
+
+        let TreeBuilder {
+            planes,
+            nodes,
+            leaves,
+            facelist,
+            ..
+        } = builder;
+
+        // every leaf sees every other leaf, so they can all share one row
+        let visleafs = leaves.len() - 1;
+        let mut visibility = vec![0xFF; (visleafs + 7) / 8];
+        if visleafs % 8 != 0 {
+            *visibility.last_mut().unwrap() = (1 << (visleafs % 8)) - 1;
+        }
+
+        let vertex_id = |x: usize, y: usize| (y * (w + 1) + x) as u16;
+        let mut vertices = Vec::with_capacity((w + 1) * (h + 1));
+        for y in 0..=h {
+            for x in 0..=w {
+                vertices.push([
+                    (origin[0] + x as i32 * size) as f32,
+                    (origin[1] + y as i32 * size) as f32,
+                    0.0,
+                ]);
+            }
+        }
+
+        // edge 0 can't be referenced backwards, so it goes unused. horizontal edges come first,
+        // then vertical edges.
+        let mut edges = vec![[0u16; 2]];
+        for y in 0..=h {
+            for x in 0..w {
+                edges.push([vertex_id(x, y), vertex_id(x + 1, y)]);
+            }
+        }
+        for x in 0..=w {
+            for y in 0..h {
+                edges.push([vertex_id(x, y), vertex_id(x, y + 1)]);
+            }
+        }
+        let h_edge = |x: usize, y: usize| (1 + y * w + x) as i32;
+        let v_edge = |x: usize, y: usize| (1 + (h + 1) * w + x * h + y) as i32;
+
+        // faces wind clockwise seen from above
+        let mut edgelist = Vec::with_capacity(self.face_count() * 4);
+        for y in 0..h {
+            for x in 0..w {
+                edgelist.extend_from_slice(&[
+                    v_edge(x, y),
+                    h_edge(x, y + 1),
+                    -v_edge(x + 1, y),
+                    -h_edge(x, y),
+                ]);
+            }
+        }
+
+        let samples = self.face_size / 16 + 1;
+        let mut lightmaps = Vec::with_capacity(self.lightmap_bytes());
+        let mut face_lightmaps = Vec::with_capacity(self.face_count());
+        for face_id in 0..self.face_count() {
+            face_lightmaps.push(match self.light_styles {
+                0 => -1,
+                _ => lightmaps.len() as i32,
+            });
+
+            for style in 0..self.light_styles {
+                for t in 0..samples {
+                    for s in 0..samples {
+                        let pattern = (s ^ t ^ face_id) & 0x1F;
+                        lightmaps.push(((160 >> style) + pattern) as u8);
+                    }
+                }
+            }
+        }
+
+        let mut out = vec![0; HEADER_SIZE];
+        let mut table = Vec::with_capacity(SECTION_COUNT);
+
+        // entities
+        let ent_string = self.entity_string();
+        let mut section = ent_string.clone().into_bytes();
+        section.push(0);
+        table.push(append_section(&mut out, &section));
+
+        // planes
+        let mut section = Vec::new();
+        for (axis, dist) in planes.iter() {
+            let mut normal = [0.0f32; 3];
+            normal[*axis as usize] = 1.0;
+            for n in normal.iter() {
+                section.write_f32::<LittleEndian>(*n)?;
+            }
+            section.write_f32::<LittleEndian>(*dist)?;
+            section.write_i32::<LittleEndian>(*axis)?;
+        }
+        table.push(append_section(&mut out, &section));
+
+        // textures
+        table.push(append_section(&mut out, &self.texture_section()?));
+
+        // vertices
+        let mut section = Vec::new();
+        for vertex in vertices.iter() {
+            for c in vertex.iter() {
+                section.write_f32::<LittleEndian>(*c)?;
+            }
+        }
+        table.push(append_section(&mut out, &section));
+
+        // visibility
+        table.push(append_section(&mut out, &visibility));
+
+        // render nodes
+        let mut section = Vec::new();
+        for node in nodes.iter() {
+            section.write_i32::<LittleEndian>(node.plane_id)?;
+            for child in node.children.iter() {
+                section.write_i16::<LittleEndian>(*child)?;
+            }
+            for c in node.min.iter().chain(node.max.iter()) {
+                section.write_i16::<LittleEndian>(*c)?;
+            }
+            section.write_u16::<LittleEndian>(node.face_id)?;
+            section.write_u16::<LittleEndian>(node.face_count)?;
+        }
+        table.push(append_section(&mut out, &section));
+
+        // texinfo, one per texture
+        let mut section = Vec::new();
+        for tex_id in 0..self.texture_count {
+            for c in [1.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, 0.0].iter() {
+                section.write_f32::<LittleEndian>(*c)?;
+            }
+            section.write_i32::<LittleEndian>(tex_id as i32)?;
+            section.write_i32::<LittleEndian>(0)?;
+        }
+        table.push(append_section(&mut out, &section));
+
+        // faces
+        let mut section = Vec::new();
+        for face_id in 0..self.face_count() {
+            section.write_i16::<LittleEndian>(floor_plane as i16)?;
+            section.write_i16::<LittleEndian>(0)?;
+            section.write_i32::<LittleEndian>(face_id as i32 * 4)?;
+            section.write_i16::<LittleEndian>(4)?;
+            section.write_i16::<LittleEndian>((face_id % self.texture_count) as i16)?;
+            for style in 0..MAX_LIGHTSTYLES {
+                section.write_u8(if style < self.light_styles {
+                    style as u8
+                } else {
+                    255
+                })?;
+            }
+            section.write_i32::<LittleEndian>(face_lightmaps[face_id])?;
+        }
+        table.push(append_section(&mut out, &section));
+
+        // lightmaps
+        table.push(append_section(&mut out, &lightmaps));
+
+        // collision nodes, shared by hulls 1 and 2
+        let mut section = Vec::new();
+        section.write_i32::<LittleEndian>(1)?;
+        section.write_i16::<LittleEndian>(CONTENTS_EMPTY as i16)?;
+        section.write_i16::<LittleEndian>(CONTENTS_SOLID as i16)?;
+        table.push(append_section(&mut out, &section));
+
+        // leaves
+        let mut section = Vec::new();
+        for leaf in leaves.iter() {
+            section.write_i32::<LittleEndian>(leaf.contents)?;
+            section.write_i32::<LittleEndian>(leaf.vis_offset)?;
+            for c in leaf.min.iter().chain(leaf.max.iter()) {
+                section.write_i16::<LittleEndian>(*c)?;
+            }
+            section.write_u16::<LittleEndian>(leaf.facelist_id)?;
+            section.write_u16::<LittleEndian>(leaf.facelist_count)?;
+            section.write_all(&[0; 4])?;
+        }
+        table.push(append_section(&mut out, &section));
+
+        // face list
+        let mut section = Vec::new();
+        for face_id in facelist.iter() {
+            section.write_u16::<LittleEndian>(*face_id)?;
+        }
+        table.push(append_section(&mut out, &section));
+
+        // edges
+        let mut section = Vec::new();
+        for edge in edges.iter() {
+            section.write_u16::<LittleEndian>(edge[0])?;
+            section.write_u16::<LittleEndian>(edge[1])?;
+        }
+        table.push(append_section(&mut out, &section));
+
+        // edge list
+        let mut section = Vec::new();
+        for edge in edgelist.iter() {
+            section.write_i32::<LittleEndian>(*edge)?;
+        }
+        table.push(append_section(&mut out, &section));
+
+        // models: only the world
+        let mut section = Vec::new();
+        for c in min.iter().chain(max.iter()) {
+            section.write_f32::<LittleEndian>(*c as f32)?;
+        }
+        section.write_all(&[0; 12])?;
+        for _ in 0..4 {
+            section.write_i32::<LittleEndian>(0)?;
+        }
+        section.write_i32::<LittleEndian>(visleafs as i32)?;
+        section.write_i32::<LittleEndian>(0)?;
+        section.write_i32::<LittleEndian>(self.face_count() as i32)?;
+        table.push(append_section(&mut out, &section));
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.write_i32::<LittleEndian>(VERSION)?;
+        for (ofs, len) in table.iter() {
+            header.write_i32::<LittleEndian>(*ofs as i32)?;
+            header.write_i32::<LittleEndian>(*len as i32)?;
+        }
+        out[..HEADER_SIZE].copy_from_slice(&header);
+
+        let stats = StressMapStats {
+            faces: self.face_count(),
+            leaves: leaves.len(),
+            nodes: nodes.len(),
+            planes: planes.len(),
+            vertices: vertices.len(),
+            edges: edges.len(),
+            textures: self.texture_count,
+            lightmap_bytes: lightmaps.len(),
+            entities: self.entity_count + 2,
+            entity_bytes: ent_string.len(),
+        };
+
+        Ok((out, stats))
+    }
+
+    // a checkerboard in a different color for each texture
+    fn texture_section(&self) -> Result<Vec<u8>, Error> {
+        let header_size = TEX_NAME_MAX + 4 * (2 + MIPLEVELS);
+        let pixels: usize = (0..MIPLEVELS)
+            .map(|m| (self.texture_size as usize >> m).pow(2))
+            .sum();
+
+        let mut section = Vec::new();
+        section.write_i32::<LittleEndian>(self.texture_count as i32)?;
+        let first = 4 + 4 * self.texture_count;
+        for tex_id in 0..self.texture_count {
+            section.write_i32::<LittleEndian>((first + tex_id * (header_size + pixels)) as i32)?;
+        }
+
+        for tex_id in 0..self.texture_count {
+            let mut name = [0u8; TEX_NAME_MAX];
+            let formatted = format!("stress{}", tex_id);
+            name[..formatted.len()].copy_from_slice(formatted.as_bytes());
+            section.write_all(&name)?;
+            section.write_u32::<LittleEndian>(self.texture_size)?;
+            section.write_u32::<LittleEndian>(self.texture_size)?;
+
+            let mut mip_ofs = header_size;
+            for m in 0..MIPLEVELS {
+                section.write_u32::<LittleEndian>(mip_ofs as u32)?;
+                mip_ofs += (self.texture_size as usize >> m).pow(2);
+            }
+
+            // skip the brightest and darkest palette rows
+            let base = (tex_id % 13 + 1) * 16;
+            for m in 0..MIPLEVELS {
+                let mip_size = self.texture_size as usize >> m;
+                for y in 0..mip_size {
+                    for x in 0..mip_size {
+                        let check = ((x << m) / 16 ^ (y << m) / 16) & 1;
+                        section.write_u8((base + 4 + check * 6) as u8)?;
+                    }
+                }
+            }
+        }
+
+        Ok(section)
+    }
+}
+
+// append a section to the file, aligned to 4 bytes, and return its offset and length
+fn append_section(out: &mut Vec<u8>, section: &[u8]) -> (usize, usize) {
+    while out.len() % 4 != 0 {
+        out.push(0);
+    }
+
+    let ofs = out.len();
+    out.extend_from_slice(section);
+    (ofs, section.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use crate::common::{
+        bsp::{self, BspLeafContents},
+        math::Frustum,
+        model::ModelKind,
+    };
+
+    use cgmath::{Deg, Matrix4, Point3, Vector3};
+
+    #[test]
+    fn test_generated_map_loads() {
+        let spec = StressMapSpec {
+            grid_width: 5,
+            grid_height: 3,
+            leaf_size: 2,
+            texture_count: 4,
+            light_styles: 2,
+            entity_count: 20,
+            ..Default::default()
+        };
+        let (data, stats) = spec.generate().unwrap();
+        assert_eq!(stats.faces, 15);
+        assert_eq!(stats.leaves, spec.leaf_count());
+        assert_eq!(stats.leaves, 3 * 2 + 1);
+        assert_eq!(stats.lightmap_bytes, spec.lightmap_bytes());
+
+        let (models, ents) = bsp::load(Cursor::new(data)).unwrap();
+        assert_eq!(models.len(), 1);
+        assert_eq!(ents.trim_end_matches('\0'), spec.entity_string());
+        assert_eq!(ents.matches("\"classname\"").count(), stats.entities);
+
+        let world = match models[0].kind() {
+            ModelKind::Brush(ref b) => b,
+            _ => panic!("world model isn't a brush model"),
+        };
+        let bsp_data = world.bsp_data();
+        assert_eq!(bsp_data.faces().len(), stats.faces);
+        assert_eq!(bsp_data.textures().len(), 4);
+        assert_eq!(bsp_data.lightmaps().len(), stats.lightmap_bytes);
+        assert_eq!(bsp_data.face_lightmaps(7).len(), 2);
+
+        // every face is a square on the floor
+        for face_id in 0..stats.faces {
+            let verts: Vec<_> = bsp_data.face_iter_vertices(face_id).collect();
+            assert_eq!(verts.len(), 4);
+            assert!(verts.iter().all(|v| v.z == 0.0));
+            assert_eq!(bsp_data.faces()[face_id].extents, [128, 128]);
+        }
+
+        // each leaf sees all the others, and together they hold every face once
+        let leaf_id = bsp_data.find_leaf(Vector3::new(10.0, 10.0, 64.0));
+        assert_ne!(leaf_id, 0);
+        let pvs = bsp_data.get_pvs(leaf_id, stats.leaves);
+        assert_eq!(pvs, (1..stats.leaves).collect::<Vec<_>>());
+        let mut faces: Vec<_> = pvs
+            .iter()
+            .flat_map(|l| {
+                let leaf = &bsp_data.leaves()[*l];
+                bsp_data.facelist()[leaf.facelist_id..leaf.facelist_id + leaf.facelist_count]
+                    .to_vec()
+            })
+            .collect();
+        faces.sort();
+        assert_eq!(faces, (0..stats.faces).collect::<Vec<_>>());
+        assert_eq!(
+            world.visible_faces(Vector3::new(10.0, 10.0, 64.0)),
+            (0..stats.faces).collect::<Vec<_>>()
+        );
+
+        // outside the map everything is visible
+        assert_eq!(bsp_data.get_pvs(0, stats.leaves), pvs);
+
+        let above = Vector3::new(0.0, 0.0, 64.0);
+        let below = Vector3::new(0.0, 0.0, -8.0);
+        for hull_id in 0..3 {
+            let hull = world.hull(hull_id).unwrap();
+            assert_eq!(
+                hull.contents_at_point(above).unwrap(),
+                BspLeafContents::Empty
+            );
+            assert_eq!(
+                hull.contents_at_point(below).unwrap(),
+                BspLeafContents::Solid
+            );
+        }
+    }
+
+    #[test]
+    fn test_light_faces() {
+        let spec = StressMapSpec {
+            grid_width: 4,
+            grid_height: 4,
+            ..Default::default()
+        };
+        let (data, _) = spec.generate().unwrap();
+        let (models, _) = bsp::load(Cursor::new(data)).unwrap();
+        let world = match models[0].kind() {
+            ModelKind::Brush(ref b) => b,
+            _ => panic!("world model isn't a brush model"),
+        };
+        let bsp_data = world.bsp_data();
+
+        // a small light just above the middle of a face only reaches that face
+        let verts: Vec<_> = bsp_data.face_iter_vertices(5).collect();
+        let center = verts.iter().fold(Vector3::new(0.0, 0.0, 0.0), |a, v| a + v) / 4.0;
+        let above = center + Vector3::new(0.0, 0.0, 16.0);
+        assert_eq!(world.light_faces(above, 32.0), vec![5]);
+
+        // a light that can't reach the floor lights nothing
+        assert!(world.light_faces(above, 15.0).is_empty());
+
+        // a big one reaches its neighbours too
+        let mut lit = world.light_faces(above, 80.0);
+        lit.sort();
+        assert_eq!(lit, vec![1, 4, 5, 6, 9]);
+    }
+
+    #[test]
+    fn test_visible_leaves() {
+        let spec = StressMapSpec {
+            grid_width: 8,
+            grid_height: 8,
+            ..Default::default()
+        };
+        let (data, stats) = spec.generate().unwrap();
+        let (models, _) = bsp::load(Cursor::new(data)).unwrap();
+        let world = match models[0].kind() {
+            ModelKind::Brush(ref b) => b,
+            _ => panic!("world model isn't a brush model"),
+        };
+        let bsp_data = world.bsp_data();
+
+        let verts: Vec<_> = bsp_data.face_iter_vertices(9).collect();
+        let center = verts.iter().fold(Vector3::new(0.0, 0.0, 0.0), |a, v| a + v) / 4.0;
+        let leaf_id = bsp_data.find_leaf(center + Vector3::new(0.0, 0.0, 64.0));
+        let vis = bsp_data.decompress_vis(leaf_id, stats.leaves);
+
+        // looking straight down at the middle of a face from high above
+        let frustum = |fov| {
+            let view = Matrix4::look_at_rh(
+                Point3::new(center.x, center.y, 1024.0),
+                Point3::new(center.x, center.y, 0.0),
+                Vector3::unit_y(),
+            );
+            Frustum::from_matrix(cgmath::perspective(Deg(fov), 1.0, 4.0, 4096.0) * view)
+        };
+
+        // a narrow view only takes in that face's leaf
+        assert_eq!(bsp_data.visible_leaves(&vis, &frustum(2.0)), vec![leaf_id]);
+
+        // a wide one takes in everything
+        let mut visible = bsp_data.visible_leaves(&vis, &frustum(120.0));
+        visible.sort();
+        assert_eq!(visible, (1..stats.leaves).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_loader_rejects_oversized_entity_lump() {
+        let spec = StressMapSpec {
+            entity_count: 2000,
+            ..Default::default()
+        };
+        let (data, stats) = spec.generate().unwrap();
+        assert!(stats.entity_bytes > 65536);
+        assert!(bsp::load(Cursor::new(data)).is_err());
+    }
+
+    #[test]
+    fn test_invalid_spec() {
+        let spec = StressMapSpec {
+            face_size: 100,
+            ..Default::default()
+        };
+        assert!(spec.generate().is_err());
+
+        let spec = StressMapSpec {
+            grid_width: 300,
+            grid_height: 300,
+            ..Default::default()
+        };
+        assert!(spec.generate().is_err());
+    }
+}
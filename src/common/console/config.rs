@@ -0,0 +1,365 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Saving and upgrading `config.cfg`.
+//!
+//! Saved configs begin with a version comment. When a config written by an
+//! older version of the engine is executed, it is first rewritten by each
+//! `Migration` added since that version, so renamed cvars keep their values
+//! and stale defaults don't override new ones.
+
+use std::fmt::Write;
+
+use crate::common::{console::CvarRegistry, parse};
+
+pub const CONFIG_FILE: &str = "config.cfg";
+
+/// Named config profiles are stored in this subdirectory of the game directory.
+pub const PROFILE_DIR: &str = "profiles";
+
+/// The version written to new configs.
+///
+/// Bump this when adding a `Migration`.
+pub const CONFIG_VERSION: u32 = 1;
+
+const VERSION_PREFIX: &str = "// config version ";
+
+/// A change to the config format.
+#[derive(Clone, Copy, Debug)]
+pub enum Migration {
+    /// A cvar was renamed from `from` to `to`.
+    RenameCvar {
+        version: u32,
+        from: &'static str,
+        to: &'static str,
+    },
+
+    /// The default value of a cvar changed from `old` to `new`.
+    ///
+    /// Configs still holding the old default are updated to the new one, since
+    /// the user most likely never changed it.
+    ChangeDefault {
+        version: u32,
+        name: &'static str,
+        old: &'static str,
+        new: &'static str,
+    },
+}
+
+impl Migration {
+    /// The config version that introduced this change.
+    pub fn version(&self) -> u32 {
+        match *self {
+            Migration::RenameCvar { version, .. } => version,
+            Migration::ChangeDefault { version, .. } => version,
+        }
+    }
+}
+
+/// Migrations applied to configs older than `CONFIG_VERSION`, oldest first.
+pub const MIGRATIONS: &[Migration] = &[];
+
+/// Return the version of a saved config.
+///
+/// Configs without a version comment predate versioning and are version 0.
+pub fn config_version(src: &str) -> u32 {
+    src.lines()
+        .find_map(|l| l.trim().strip_prefix(VERSION_PREFIX))
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Return the path of a named profile relative to the game directory, or
+/// `None` if the name isn't allowed.
+///
+/// Names are limited to ASCII letters, digits, `_` and `-`, so a profile can't
+/// be read or written outside the profile directory.
+pub fn profile_path(name: &str) -> Option<String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    match valid {
+        true => Some(format!("{}/{}.cfg", PROFILE_DIR, name)),
+        false => None,
+    }
+}
+
+// quote a config argument. Command lines have no escape sequences, so
+// embedded double quotes are written as single quotes.
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('"', "'"))
+}
+
+/// Write the contents of a config file.
+///
+/// `binds` is a list of (key, command) pairs. Every archived cvar is saved.
+pub fn write_config(cvars: &CvarRegistry, binds: &[(String, String)]) -> String {
+    let mut out = String::new();
+    writeln!(&mut out, "{}{}", VERSION_PREFIX, CONFIG_VERSION).unwrap();
+    writeln!(&mut out, "unbindall").unwrap();
+
+    for (key, target) in binds {
+        writeln!(&mut out, "bind {} {}", quote(key), quote(target)).unwrap();
+    }
+
+    for (name, value) in cvars.archived() {
+        writeln!(&mut out, "{} {}", name, quote(&value)).unwrap();
+    }
+
+    out
+}
+
+/// Upgrade a saved config to `CONFIG_VERSION`.
+///
+/// Returns the upgraded config along with a notice for each change made.
+pub fn migrate(src: &str, migrations: &[Migration]) -> (String, Vec<String>) {
+    let version = config_version(src);
+    let pending: Vec<&Migration> = migrations
+        .iter()
+        .filter(|m| m.version() > version)
+        .collect();
+
+    let mut notices = Vec::new();
+    if pending.is_empty() {
+        return (src.to_owned(), notices);
+    }
+
+    let mut out = String::new();
+    for line in src.lines() {
+        if line.trim().starts_with(VERSION_PREFIX) {
+            continue;
+        }
+
+        let mut src_line = line.to_owned();
+        src_line.push('\n');
+        let commands = match parse::commands(&src_line) {
+            Ok(("", c)) if !c.is_empty() => c,
+
+            // leave comments, blank lines and anything we can't parse alone
+            _ => {
+                out.push_str(&src_line);
+                continue;
+            }
+        };
+
+        let mut changed = false;
+        let mut kept = Vec::new();
+        for command in commands {
+            let mut args: Vec<String> = command.iter().map(|a| (*a).to_owned()).collect();
+            match migrate_command(&mut args, &pending, &mut notices) {
+                Some(true) => {
+                    changed = true;
+                    kept.push(args);
+                }
+                Some(false) => kept.push(args),
+                None => changed = true,
+            }
+        }
+
+        if !changed {
+            out.push_str(&src_line);
+            continue;
+        }
+
+        for args in kept {
+            let quoted: Vec<String> = args
+                .iter()
+                .enumerate()
+                .map(|(i, a)| match i {
+                    0 => a.clone(),
+                    _ => format!("\"{}\"", a),
+                })
+                .collect();
+            writeln!(&mut out, "{}", quoted.join(" ")).unwrap();
+        }
+    }
+
+    (out, notices)
+}
+
+// Apply migrations to a single command.
+//
+// Returns `Some(true)` if the command was changed, `Some(false)` if not, or
+// `None` if it should be removed.
+fn migrate_command(
+    args: &mut Vec<String>,
+    migrations: &[&Migration],
+    notices: &mut Vec<String>,
+) -> Option<bool> {
+    let mut changed = false;
+
+    for migration in migrations {
+        match **migration {
+            Migration::RenameCvar { from, to, .. } => {
+                if args[0] == from {
+                    args[0] = to.to_owned();
+                    notices.push(format!("Renamed cvar \"{}\" to \"{}\"", from, to));
+                    changed = true;
+                }
+
+                // a binding can set the cvar too, e.g. bind f "fov 110"
+                if args[0] == "bind" && args.len() == 3 {
+                    let target_cmd = args[2].split_whitespace().next();
+                    if target_cmd == Some(from) {
+                        args[2] = format!("{}{}", to, &args[2][from.len()..]);
+                        notices.push(format!(
+                            "Updated binding for \"{}\": \"{}\" is now \"{}\"",
+                            args[1], from, to
+                        ));
+                        changed = true;
+                    }
+                }
+            }
+
+            Migration::ChangeDefault { name, old, new, .. } => {
+                if args[0] == name && args.len() == 2 && args[1] == old {
+                    notices.push(format!(
+                        "Default for \"{}\" changed from \"{}\" to \"{}\"",
+                        name, old, new
+                    ));
+                    return None;
+                }
+            }
+        }
+    }
+
+    Some(changed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{cell::RefCell, rc::Rc};
+
+    const TEST_MIGRATIONS: &[Migration] = &[
+        Migration::RenameCvar {
+            version: 1,
+            from: "sensitivity",
+            to: "m_sensitivity",
+        },
+        Migration::ChangeDefault {
+            version: 2,
+            name: "host_maxfps",
+            old: "72",
+            new: "250",
+        },
+    ];
+
+    #[test]
+    fn test_config_version() {
+        assert_eq!(config_version("// config version 3\nfov 90\n"), 3);
+        assert_eq!(config_version("fov 90\n"), 0);
+    }
+
+    #[test]
+    fn test_profile_path() {
+        assert_eq!(profile_path("ctf"), Some("profiles/ctf.cfg".to_owned()));
+        assert_eq!(
+            profile_path("Left-Handed_2"),
+            Some("profiles/Left-Handed_2.cfg".to_owned())
+        );
+
+        for name in &[
+            "",
+            "..",
+            "../config",
+            "a/b",
+            "a\\b",
+            "x.cfg",
+            "two words",
+            "caf\u{e9}",
+        ] {
+            assert_eq!(profile_path(name), None, "{:?} should be rejected", name);
+        }
+    }
+
+    #[test]
+    fn test_write_config() {
+        let cvars = CvarRegistry::new(Rc::new(RefCell::new(Vec::new())));
+        cvars.register_archive("fov", "90").unwrap();
+        cvars.register_archive("_cl_name", "player").unwrap();
+        cvars.register("cl_shownet", "0").unwrap();
+        cvars.set("fov", "110").unwrap();
+
+        let binds = vec![("w".to_owned(), "+forward".to_owned())];
+        assert_eq!(
+            write_config(&cvars, &binds),
+            format!(
+                "// config version {}\n\
+                 unbindall\n\
+                 bind \"w\" \"+forward\"\n\
+                 _cl_name \"player\"\n\
+                 fov \"110\"\n",
+                CONFIG_VERSION
+            )
+        );
+    }
+
+    #[test]
+    fn test_write_config_quotes() {
+        let cvars = CvarRegistry::new(Rc::new(RefCell::new(Vec::new())));
+        cvars.register_archive("hostname", "").unwrap();
+        cvars.set("hostname", "the \"best\" server").unwrap();
+
+        let binds = vec![("t".to_owned(), "say \"hello\"; wait".to_owned())];
+        let config = write_config(&cvars, &binds);
+        let (rest, commands) = parse::commands(&config).unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(
+            commands[1..],
+            [
+                vec!["bind", "t", "say 'hello'; wait"],
+                vec!["hostname", "the 'best' server"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_migrate() {
+        let src = "// my settings\n\
+                   bind \"m\" \"sensitivity 10\"\n\
+                   sensitivity \"5\"; fov \"100\"\n\
+                   host_maxfps \"72\"\n\
+                   volume \"0.5\"\n";
+
+        let (out, notices) = migrate(src, TEST_MIGRATIONS);
+        assert_eq!(
+            out,
+            "// my settings\n\
+             bind \"m\" \"m_sensitivity 10\"\n\
+             m_sensitivity \"5\"\n\
+             fov \"100\"\n\
+             volume \"0.5\"\n"
+        );
+        assert_eq!(notices.len(), 3);
+
+        // only newer migrations apply
+        let (out, notices) = migrate("// config version 1\nhost_maxfps \"72\"\n", TEST_MIGRATIONS);
+        assert_eq!(out, "");
+        assert_eq!(notices.len(), 1);
+
+        // up-to-date configs are left alone
+        let src = "// config version 2\nsensitivity \"5\"\n";
+        assert_eq!(migrate(src, TEST_MIGRATIONS), (src.to_owned(), Vec::new()));
+    }
+}
@@ -18,10 +18,12 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+pub mod config;
+
 use std::{
-    cell::{Ref, RefCell},
+    cell::{Cell, Ref, RefCell},
     collections::{HashMap, VecDeque},
-    fmt::Write,
+    fmt::{self, Write},
     iter::FromIterator,
     rc::Rc,
 };
@@ -31,6 +33,19 @@ use crate::common::parse;
 use chrono::{Duration, Utc};
 use thiserror::Error;
 
+// default number of lines kept in the scrollback; oldest lines are discarded
+// past this point
+const MAX_OUTPUT_LINES: usize = 1024;
+
+// number of lines moved by `Console::page_up` and `Console::page_down`
+const PAGE_LINES: usize = 8;
+
+// most aliases that can be expanded in one call to `Console::execute`
+const MAX_ALIAS_EXPANSIONS: usize = 1024;
+
+// oldest entered lines are forgotten past this point
+const MAX_HISTORY_LINES: usize = 64;
+
 #[derive(Error, Debug)]
 pub enum ConsoleError {
     #[error("{0}")]
@@ -49,6 +64,17 @@ pub enum ConsoleError {
 
 type Cmd = Box<dyn Fn(&[&str]) -> String>;
 
+/// Returns `true` if `arg` can be passed as a single quoted command argument.
+///
+/// Command lines have no escape sequences, so a quote would end the argument
+/// early and a semicolon or newline would start a new command.
+pub fn is_quotable(arg: &str) -> bool {
+    !arg.contains(|c| c == '"' || c == ';' || c == '\n')
+}
+
+/// A function called with a cvar's name and new value when it changes.
+pub type CvarHook = Box<dyn Fn(&str, &str)>;
+
 fn insert_name<S>(names: &mut Vec<String>, name: S) -> Result<usize, usize>
 where
     S: AsRef<str>,
@@ -67,6 +93,9 @@ where
 pub struct CmdRegistry {
     cmds: HashMap<String, Cmd>,
     names: Rc<RefCell<Vec<String>>>,
+
+    // shared so that `help` and `find` can read it while a command is running
+    descriptions: Rc<RefCell<HashMap<String, String>>>,
 }
 
 impl CmdRegistry {
@@ -74,6 +103,7 @@ impl CmdRegistry {
         CmdRegistry {
             cmds: HashMap::new(),
             names,
+            descriptions: Rc::new(RefCell::new(HashMap::new())),
         }
     }
 
@@ -129,6 +159,7 @@ impl CmdRegistry {
         if self.cmds.remove(name.as_ref()).is_none() {
             return Err(ConsoleError::NoSuchCommand(name.as_ref().to_string()))?;
         }
+        self.descriptions.borrow_mut().remove(name.as_ref());
 
         let mut names = self.names.borrow_mut();
         match names.binary_search_by(|item| item.as_str().cmp(name.as_ref())) {
@@ -164,6 +195,29 @@ impl CmdRegistry {
     pub fn names(&self) -> Rc<RefCell<Vec<String>>> {
         self.names.clone()
     }
+
+    /// Set the description shown by `help` and searched by `find`.
+    ///
+    /// Returns an error if no command with the specified name exists.
+    pub fn describe<S>(&mut self, name: S, description: S) -> Result<(), ConsoleError>
+    where
+        S: AsRef<str>,
+    {
+        let name = name.as_ref();
+        if !self.cmds.contains_key(name) {
+            Err(ConsoleError::NoSuchCommand(name.to_owned()))?;
+        }
+
+        self.descriptions
+            .borrow_mut()
+            .insert(name.to_owned(), description.as_ref().to_owned());
+
+        Ok(())
+    }
+
+    pub fn descriptions(&self) -> Rc<RefCell<HashMap<String, String>>> {
+        self.descriptions.clone()
+    }
 }
 
 /// A configuration variable.
@@ -184,12 +238,28 @@ struct Cvar {
 
     // The default value of this variable
     default: String,
+
+    // Shown by `help` and searched by `find`
+    description: Option<String>,
 }
 
-#[derive(Debug)]
 pub struct CvarRegistry {
     cvars: RefCell<HashMap<String, Cvar>>,
     names: Rc<RefCell<Vec<String>>>,
+    hooks: RefCell<HashMap<String, Vec<Rc<CvarHook>>>>,
+
+    // changes to notify cvars that haven't been announced yet
+    notify_changes: RefCell<Vec<(String, String)>>,
+}
+
+impl fmt::Debug for CvarRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CvarRegistry")
+            .field("cvars", &self.cvars)
+            .field("names", &self.names)
+            .field("notify_changes", &self.notify_changes)
+            .finish()
+    }
 }
 
 impl CvarRegistry {
@@ -198,6 +268,8 @@ impl CvarRegistry {
         CvarRegistry {
             cvars: RefCell::new(HashMap::new()),
             names,
+            hooks: RefCell::new(HashMap::new()),
+            notify_changes: RefCell::new(Vec::new()),
         }
     }
 
@@ -229,6 +301,7 @@ impl CvarRegistry {
                         archive,
                         notify,
                         default: default.to_owned(),
+                        description: None,
                     },
                 );
             }
@@ -276,7 +349,7 @@ impl CvarRegistry {
     /// Additionally, when this `Cvar` is set:
     /// - If the host is a server, broadcast that the variable has been changed to all clients.
     /// - If the host is a client, update the clientinfo string.
-    pub fn register_archive_notify<S>(&mut self, name: S, default: S) -> Result<(), ConsoleError>
+    pub fn register_archive_notify<S>(&self, name: S, default: S) -> Result<(), ConsoleError>
     where
         S: AsRef<str>,
     {
@@ -324,30 +397,247 @@ impl CvarRegistry {
         Ok(val)
     }
 
+    /// Set the value of a cvar and run its change hooks.
+    ///
+    /// Hooks are only run if the value actually changes. Since hooks may
+    /// access the registry themselves, don't call this through a mutable
+    /// borrow of a shared registry.
     pub fn set<S>(&self, name: S, value: S) -> Result<(), ConsoleError>
     where
         S: AsRef<str>,
     {
-        trace!("cvar assignment: {} {}", name.as_ref(), value.as_ref());
-        let mut cvars = self.cvars.borrow_mut();
-        let mut cvar = cvars
-            .get_mut(name.as_ref())
-            .ok_or(ConsoleError::NoSuchCvar(name.as_ref().to_owned()))?;
-        cvar.val = value.as_ref().to_owned();
-        if cvar.notify {
-            // TODO: update userinfo/serverinfo
-            unimplemented!();
+        let name = name.as_ref();
+        let value = value.as_ref();
+        trace!("cvar assignment: {} {}", name, value);
+
+        {
+            let mut cvars = self.cvars.borrow_mut();
+            let cvar = cvars
+                .get_mut(name)
+                .ok_or(ConsoleError::NoSuchCvar(name.to_owned()))?;
+            if cvar.val == value {
+                return Ok(());
+            }
+
+            cvar.val = value.to_owned();
+            // only the latest change to each cvar is kept, so nothing piles
+            // up if the changes are never taken
+            if cvar.notify {
+                let mut changes = self.notify_changes.borrow_mut();
+                changes.retain(|(n, _)| n != name);
+                changes.push((name.to_owned(), value.to_owned()));
+            }
+        }
+
+        // hooks may read or set other cvars, so nothing can be borrowed here
+        let hooks = self.hooks.borrow().get(name).cloned().unwrap_or_default();
+        for hook in hooks {
+            hook(name, value);
         }
 
         Ok(())
     }
 
+    /// Call `hook` whenever the value of the named cvar changes.
+    pub fn add_hook<S>(&self, name: S, hook: CvarHook) -> Result<(), ConsoleError>
+    where
+        S: AsRef<str>,
+    {
+        let name = name.as_ref();
+        if !self.contains(name) {
+            Err(ConsoleError::NoSuchCvar(name.to_owned()))?;
+        }
+
+        self.hooks
+            .borrow_mut()
+            .entry(name.to_owned())
+            .or_insert_with(Vec::new)
+            .push(Rc::new(hook));
+
+        Ok(())
+    }
+
+    /// Return the changes made to notify cvars since the last call, oldest
+    /// first. A cvar changed more than once only appears with its last value.
+    ///
+    /// Servers announce these to clients; clients send them as user info.
+    pub fn take_notify_changes(&self) -> Vec<(String, String)> {
+        self.notify_changes.replace(Vec::new())
+    }
+
+    /// Return the name and value of every notify cvar, sorted by name.
+    pub fn notify_values(&self) -> Vec<(String, String)> {
+        let mut values: Vec<_> = self
+            .cvars
+            .borrow()
+            .iter()
+            .filter(|(_, c)| c.notify)
+            .map(|(n, c)| (n.clone(), c.val.clone()))
+            .collect();
+        values.sort();
+        values
+    }
+
     pub fn contains<S>(&self, name: S) -> bool
     where
         S: AsRef<str>,
     {
         self.cvars.borrow().contains_key(name.as_ref())
     }
+
+    /// Return the name and value of every archived cvar, sorted by name.
+    pub fn archived(&self) -> Vec<(String, String)> {
+        let mut values: Vec<_> = self
+            .cvars
+            .borrow()
+            .iter()
+            .filter(|(_, c)| c.archive)
+            .map(|(n, c)| (n.clone(), c.val.clone()))
+            .collect();
+        values.sort();
+        values
+    }
+
+    /// Set the description shown by `help` and searched by `find`.
+    pub fn describe<S>(&self, name: S, description: S) -> Result<(), ConsoleError>
+    where
+        S: AsRef<str>,
+    {
+        let mut cvars = self.cvars.borrow_mut();
+        let cvar = cvars
+            .get_mut(name.as_ref())
+            .ok_or(ConsoleError::NoSuchCvar(name.as_ref().to_owned()))?;
+        cvar.description = Some(description.as_ref().to_owned());
+
+        Ok(())
+    }
+
+    pub fn description<S>(&self, name: S) -> Option<String>
+    where
+        S: AsRef<str>,
+    {
+        self.cvars
+            .borrow()
+            .get(name.as_ref())
+            .and_then(|c| c.description.clone())
+    }
+
+    pub fn default_value<S>(&self, name: S) -> Result<String, ConsoleError>
+    where
+        S: AsRef<str>,
+    {
+        Ok(self
+            .cvars
+            .borrow()
+            .get(name.as_ref())
+            .ok_or(ConsoleError::NoSuchCvar(name.as_ref().to_owned()))?
+            .default
+            .clone())
+    }
+}
+
+/// Generate a `help` command.
+fn cmd_help(
+    cvars: Rc<RefCell<CvarRegistry>>,
+    cmd_descriptions: Rc<RefCell<HashMap<String, String>>>,
+) -> Cmd {
+    Box::new(move |args| {
+        if args.len() != 1 {
+            return "usage: help <cvar or command>".into();
+        }
+
+        let name = args[0];
+        let cvars = cvars.borrow();
+        if cvars.contains(name) {
+            let mut output = format!(
+                "\"{}\" is \"{}\" (default \"{}\")",
+                name,
+                cvars.get(name).unwrap(),
+                cvars.default_value(name).unwrap()
+            );
+            if let Some(desc) = cvars.description(name) {
+                write!(&mut output, "\n{}", desc).unwrap();
+            }
+            return output;
+        }
+
+        match cmd_descriptions.borrow().get(name) {
+            Some(desc) => format!("{}: {}", name, desc),
+            None => format!("No help available for \"{}\"", name),
+        }
+    })
+}
+
+/// Generate a `find` command.
+///
+/// Lists every cvar and command whose name or description contains the
+/// search string, ignoring case.
+fn cmd_find(
+    names: Rc<RefCell<Vec<String>>>,
+    cvars: Rc<RefCell<CvarRegistry>>,
+    cmd_descriptions: Rc<RefCell<HashMap<String, String>>>,
+) -> Cmd {
+    Box::new(move |args| {
+        if args.len() != 1 {
+            return "usage: find <substring>".into();
+        }
+
+        let pattern = args[0].to_lowercase();
+        let cvars = cvars.borrow();
+        let cmd_descriptions = cmd_descriptions.borrow();
+
+        let mut output = String::new();
+        for name in names.borrow().iter() {
+            let desc = match cvars.description(name) {
+                Some(d) => Some(d),
+                None => cmd_descriptions.get(name).cloned(),
+            };
+
+            let matched = name.to_lowercase().contains(&pattern)
+                || desc
+                    .as_ref()
+                    .map(|d| d.to_lowercase().contains(&pattern))
+                    .unwrap_or(false);
+            if !matched {
+                continue;
+            }
+
+            match desc {
+                Some(d) => writeln!(&mut output, "{} - {}", name, d).unwrap(),
+                None => writeln!(&mut output, "{}", name).unwrap(),
+            }
+        }
+
+        // the console adds its own line break
+        output.pop();
+        output
+    })
+}
+
+/// Implements the `con_search` command.
+///
+/// Each search starts just above the current scroll position, so repeating a
+/// search steps back through older matches.
+fn cmd_con_search(output: Rc<RefCell<ConsoleOutput>>) -> Cmd {
+    Box::new(move |args| {
+        if args.is_empty() {
+            return "usage: con_search <text>".into();
+        }
+
+        let pattern = args.join(" ");
+        let mut output = output.borrow_mut();
+
+        // this skips the echoed command line, which is the newest line
+        let found = output.find(&pattern, output.scroll());
+        match found {
+            Some(line) => {
+                output.scroll_to_bottom();
+                output.scroll_up(line);
+                String::new()
+            }
+            None => format!("No earlier output contains \"{}\"", pattern),
+        }
+    })
 }
 
 /// The line of text currently being edited in the console.
@@ -432,6 +722,14 @@ impl ConsoleInput {
         self.text.clear();
         self.curs = 0;
     }
+
+    /// Replaces the characters in `start..end` with `with`.
+    ///
+    /// The cursor is moved to the end of the inserted text.
+    pub fn replace(&mut self, start: usize, end: usize, with: &[char]) {
+        self.text.splice(start..end, with.iter().copied());
+        self.curs = start + with.len();
+    }
 }
 
 pub struct History {
@@ -447,9 +745,23 @@ impl History {
         }
     }
 
+    /// Adds a line to the history.
+    ///
+    /// Empty lines and repeats of the most recent line are not recorded.
     pub fn add_line(&mut self, line: Vec<char>) {
-        self.lines.push_front(line);
         self.curs = 0;
+
+        if line.iter().all(|c| c.is_whitespace()) || self.lines.front() == Some(&line) {
+            return;
+        }
+
+        self.lines.push_front(line);
+        self.lines.truncate(MAX_HISTORY_LINES);
+    }
+
+    /// Returns the recorded lines, oldest first.
+    pub fn lines(&self) -> impl Iterator<Item = &[char]> {
+        self.lines.iter().rev().map(Vec::as_slice)
     }
 
     // TODO: handle case where history is empty
@@ -483,12 +795,18 @@ pub struct ConsoleOutput {
     // The timestamp is specified in seconds since the Unix epoch (so it is
     // decoupled from client/server time).
     lines: VecDeque<(Vec<char>, Option<i64>)>,
+    max_lines: usize,
+
+    // number of lines the view is scrolled back from the newest line
+    scroll: usize,
 }
 
 impl ConsoleOutput {
     pub fn new() -> ConsoleOutput {
         ConsoleOutput {
             lines: VecDeque::new(),
+            max_lines: MAX_OUTPUT_LINES,
+            scroll: 0,
         }
     }
 
@@ -497,14 +815,78 @@ impl ConsoleOutput {
         C: IntoIterator<Item = char>,
     {
         self.lines
-            .push_front((chars.into_iter().collect(), timestamp))
-        // TODO: set maximum capacity and pop_back when we reach it
+            .push_front((chars.into_iter().collect(), timestamp));
+        self.lines.truncate(self.max_lines);
+
+        // keep the view on the same text while scrolled back
+        if self.scroll > 0 {
+            self.scroll_up(1);
+        }
+    }
+
+    /// Remove all lines of output.
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.scroll = 0;
+    }
+
+    /// Set the number of lines kept in the scrollback.
+    pub fn set_max_lines(&mut self, max_lines: usize) {
+        self.max_lines = max_lines.max(1);
+        self.lines.truncate(self.max_lines);
+        self.scroll = self.scroll.min(self.lines.len().saturating_sub(1));
+    }
+
+    /// Returns the number of lines the view is scrolled back.
+    pub fn scroll(&self) -> usize {
+        self.scroll
     }
 
+    /// Scroll the view back by `lines`, stopping at the oldest line.
+    pub fn scroll_up(&mut self, lines: usize) {
+        self.scroll = (self.scroll + lines).min(self.lines.len().saturating_sub(1));
+    }
+
+    /// Scroll the view forward by `lines`, stopping at the newest line.
+    pub fn scroll_down(&mut self, lines: usize) {
+        self.scroll = self.scroll.saturating_sub(lines);
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.scroll = 0;
+    }
+
+    /// Returns the index of the newest line older than line `start` that
+    /// contains `pattern`, ignoring case. Line 0 is the newest line.
+    pub fn find<S>(&self, pattern: S, start: usize) -> Option<usize>
+    where
+        S: AsRef<str>,
+    {
+        let pattern = pattern.as_ref().to_lowercase();
+        self.lines
+            .iter()
+            .enumerate()
+            .skip(start + 1)
+            .find(|(_, (line, _))| {
+                line.iter()
+                    .collect::<String>()
+                    .to_lowercase()
+                    .contains(&pattern)
+            })
+            .map(|(i, _)| i)
+    }
+
+    /// Returns an iterator over all lines, newest first.
     pub fn lines(&self) -> impl Iterator<Item = &[char]> {
         self.lines.iter().map(|(v, _)| v.as_slice())
     }
 
+    /// Returns an iterator over the lines visible at the current scroll
+    /// position, newest first.
+    pub fn visible_lines(&self) -> impl Iterator<Item = &[char]> {
+        self.lines().skip(self.scroll)
+    }
+
     /// Return an iterator over lines that have been printed in the last
     /// `interval` of time.
     ///
@@ -539,17 +921,23 @@ pub struct Console {
     cvars: Rc<RefCell<CvarRegistry>>,
     aliases: Rc<RefCell<HashMap<String, String>>>,
 
+    // set by the `wait` command to stop execution until the next frame
+    wait: Rc<Cell<bool>>,
+
     input: ConsoleInput,
     hist: History,
     buffer: RefCell<String>,
 
     out_buffer: RefCell<Vec<char>>,
-    output: RefCell<ConsoleOutput>,
+    output: Rc<RefCell<ConsoleOutput>>,
+
+    // if set, printed text is also collected here (e.g. to send to rcon clients)
+    redirect: RefCell<Option<String>>,
 }
 
 impl Console {
     pub fn new(cmds: Rc<RefCell<CmdRegistry>>, cvars: Rc<RefCell<CvarRegistry>>) -> Console {
-        let output = RefCell::new(ConsoleOutput::new());
+        let output = Rc::new(RefCell::new(ConsoleOutput::new()));
         cmds.borrow_mut()
             .insert(
                 "echo",
@@ -569,79 +957,115 @@ impl Console {
         cmds.borrow_mut()
             .insert(
                 "alias",
-                Box::new(move |args| {
-                    match args.len() {
-                        0 => {
-                            for (name, script) in cmd_aliases.borrow().iter() {
-                                println!("    {}: {}", name, script);
-                            }
-                            println!("{} alias command(s)", cmd_aliases.borrow().len());
-                        }
-
-                        2 => {
-                            let name = args[0].to_string();
-                            let script = args[1].to_string();
-                            let _ = cmd_aliases.borrow_mut().insert(name, script);
+                Box::new(move |args| match args.len() {
+                    0 => {
+                        let mut output = String::new();
+                        for (name, script) in cmd_aliases.borrow().iter() {
+                            write!(&mut output, "    {}: {}\n", name, script).unwrap();
                         }
+                        write!(
+                            &mut output,
+                            "{} alias command(s)",
+                            cmd_aliases.borrow().len()
+                        )
+                        .unwrap();
+                        output
+                    }
 
-                        _ => (),
+                    2 => {
+                        let name = args[0].to_string();
+                        let script = args[1].to_string();
+                        let _ = cmd_aliases.borrow_mut().insert(name, script);
+                        String::new()
                     }
-                    String::new()
+
+                    _ => "usage: alias [<name> <script>]".into(),
                 }),
             )
             .unwrap();
 
-        let find_names = cmds.borrow().names();
+        let clear_output = output.clone();
         cmds.borrow_mut()
             .insert(
-                "find",
-                Box::new(move |args| match args.len() {
-                    1 => {
-                        let names = find_names.borrow_mut();
-
-                        // Find the index of the first item >= the target.
-                        let start = match names.binary_search_by(|item| item.as_str().cmp(&args[0]))
-                        {
-                            Ok(i) => i,
-                            Err(i) => i,
-                        };
-
-                        // Take every item starting with the target.
-                        let it = (&names[start..])
-                            .iter()
-                            .take_while(move |item| item.starts_with(&args[0]))
-                            .map(|s| s.as_str());
+                "clear",
+                Box::new(move |_| {
+                    clear_output.borrow_mut().clear();
+                    String::new()
+                }),
+            )
+            .unwrap();
 
-                        let mut output = String::new();
-                        for name in it {
-                            write!(&mut output, "{}\n", name).unwrap();
-                        }
+        cmds.borrow_mut()
+            .insert("con_search", cmd_con_search(output.clone()))
+            .unwrap();
 
-                        output
-                    }
+        let names = cmds.borrow().names();
+        let cmd_descriptions = cmds.borrow().descriptions();
+        cmds.borrow_mut()
+            .insert("help", cmd_help(cvars.clone(), cmd_descriptions.clone()))
+            .unwrap();
+        cmds.borrow_mut()
+            .insert("find", cmd_find(names, cvars.clone(), cmd_descriptions))
+            .unwrap();
 
-                    _ => "usage: find <cvar or command>".into(),
+        let wait = Rc::new(Cell::new(false));
+        let cmd_wait = wait.clone();
+        cmds.borrow_mut()
+            .insert(
+                "wait",
+                Box::new(move |_| {
+                    cmd_wait.set(true);
+                    String::new()
                 }),
             )
             .unwrap();
 
+        for (name, desc) in &[
+            (
+                "alias",
+                "Define a command that runs a script, or list aliases",
+            ),
+            ("clear", "Clear the console"),
+            (
+                "con_search",
+                "Scroll the console back to the next earlier line containing some text",
+            ),
+            ("echo", "Print text to the console"),
+            ("find", "Search cvar and command names and descriptions"),
+            ("help", "Show the description of a cvar or command"),
+            ("wait", "Run the rest of the command buffer next frame"),
+        ] {
+            cmds.borrow_mut().describe(*name, *desc).unwrap();
+        }
+
         Console {
             cmds,
             cvars,
             aliases: aliases.clone(),
+            wait,
             input: ConsoleInput::new(),
             hist: History::new(),
             buffer: RefCell::new(String::new()),
             out_buffer: RefCell::new(Vec::new()),
             output,
+            redirect: RefCell::new(None),
         }
     }
 
+    /// Returns the aliases defined with the `alias` command, by name.
+    pub fn aliases(&self) -> Rc<RefCell<HashMap<String, String>>> {
+        self.aliases.clone()
+    }
+
     // The timestamp is applied to any line flushed during this call.
     fn print_impl<S>(&self, s: S, timestamp: Option<i64>)
     where
         S: AsRef<str>,
     {
+        if let Some(ref mut r) = *self.redirect.borrow_mut() {
+            r.push_str(s.as_ref());
+        }
+
         let mut buf = self.out_buffer.borrow_mut();
         let mut it = s.as_ref().chars();
 
@@ -689,6 +1113,17 @@ impl Console {
         self.print_impl("\n", ts);
     }
 
+    /// Start collecting printed text in addition to displaying it.
+    pub fn begin_redirect(&self) {
+        self.redirect.replace(Some(String::new()));
+    }
+
+    /// Stop collecting printed text and return everything printed since the
+    /// call to `begin_redirect`.
+    pub fn end_redirect(&self) -> String {
+        self.redirect.replace(None).unwrap_or_default()
+    }
+
     pub fn send_char(&mut self, c: char) {
         match c {
             // ignore grave and escape keys
@@ -715,7 +1150,7 @@ impl Console {
             '\x08' => self.input.backspace(),
             '\x7f' => self.input.delete(),
 
-            '\t' => warn!("Tab completion not implemented"), // TODO: tab completion
+            '\t' => self.complete(),
 
             // TODO: we should probably restrict what characters are allowed
             c => self.input.insert(c),
@@ -734,6 +1169,21 @@ impl Console {
         self.input.cursor_left()
     }
 
+    /// Scroll the console output back by a page.
+    pub fn page_up(&self) {
+        self.output.borrow_mut().scroll_up(PAGE_LINES);
+    }
+
+    /// Scroll the console output forward by a page.
+    pub fn page_down(&self) {
+        self.output.borrow_mut().scroll_down(PAGE_LINES);
+    }
+
+    /// Set the number of lines kept in the console scrollback.
+    pub fn set_scrollback(&self, lines: usize) {
+        self.output.borrow_mut().set_max_lines(lines);
+    }
+
     pub fn history_up(&mut self) {
         if let Some(line) = self.hist.line_up() {
             self.input.set_text(&line);
@@ -746,58 +1196,206 @@ impl Console {
         }
     }
 
-    /// Interprets the contents of the execution buffer.
-    pub fn execute(&self) {
-        let text = self.buffer.replace(String::new());
+    /// Returns the input history, oldest line first.
+    pub fn history(&self) -> Vec<String> {
+        self.hist.lines().map(|l| l.iter().collect()).collect()
+    }
 
-        let (_remaining, commands) = parse::commands(text.as_str()).unwrap();
+    /// Adds previously entered lines to the input history, oldest line first.
+    pub fn load_history<I, S>(&mut self, lines: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for line in lines {
+            self.hist.add_line(line.as_ref().chars().collect());
+        }
+    }
 
-        for command in commands.iter() {
-            debug!("{:?}", command);
+    /// Returns the names of all commands, cvars and aliases starting with `partial`, in
+    /// alphabetical order.
+    pub fn completions<S>(&self, partial: S) -> Vec<String>
+    where
+        S: AsRef<str>,
+    {
+        let partial = partial.as_ref().to_lowercase();
+        let names = self.cmds.borrow().names();
+        let names = names.borrow();
+        let aliases = self.aliases.borrow();
+
+        let mut matches: Vec<String> = names
+            .iter()
+            .chain(aliases.keys())
+            .filter(|name| name.to_lowercase().starts_with(&partial))
+            .cloned()
+            .collect();
+        matches.sort();
+        matches.dedup();
+        matches
+    }
+
+    /// Completes the command name under the cursor.
+    ///
+    /// A unique match is filled in along with a trailing space. If there are
+    /// several, the input is extended to their longest common prefix and the
+    /// candidates are listed in the console.
+    pub fn complete(&mut self) {
+        let curs = self.input.curs;
+        let text = &self.input.text;
+
+        // only the first word of the command being typed is completed
+        let mut start = text[..curs]
+            .iter()
+            .rposition(|c| *c == ';')
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        while start < curs && text[start].is_whitespace() {
+            start += 1;
         }
 
-        for args in commands {
-            if let Some(arg_0) = args.get(0) {
-                let maybe_alias = self.aliases.borrow().get(*arg_0).map(|a| a.to_owned());
-                match maybe_alias {
-                    Some(a) => {
-                        self.stuff_text(a);
-                        self.execute();
+        let partial: String = text[start..curs].iter().collect();
+        if partial.is_empty() || partial.contains(char::is_whitespace) {
+            return;
+        }
+
+        let matches = self.completions(&partial);
+        match matches.len() {
+            0 => (),
+
+            1 => {
+                let mut completed: Vec<char> = matches[0].chars().collect();
+                completed.push(' ');
+                self.input.replace(start, curs, &completed);
+            }
+
+            _ => {
+                self.println(format!("]{}", self.get_string()));
+                for name in matches.iter() {
+                    self.println(format!("    {}", name));
+                }
+
+                let mut prefix: Vec<char> = matches[0].chars().collect();
+                for name in matches.iter().skip(1) {
+                    let common = prefix
+                        .iter()
+                        .zip(name.chars())
+                        .take_while(|(a, b)| **a == *b)
+                        .count();
+                    prefix.truncate(common);
+                }
+
+                if prefix.len() > partial.chars().count() {
+                    self.input.replace(start, curs, &prefix);
+                }
+            }
+        }
+    }
+
+    /// Interprets the contents of the execution buffer.
+    ///
+    /// Commands are run one at a time from the front of the buffer, so text
+    /// inserted by a command (e.g. by `exec` or an alias) runs before the rest
+    /// of the buffer. A `wait` command stops execution until the next call.
+    pub fn execute(&self) {
+        let mut alias_count = 0;
+
+        while let Some(args) = self.next_command() {
+            debug!("{:?}", args);
+
+            let arg_0 = args[0].as_str();
+            let maybe_alias = self.aliases.borrow().get(arg_0).map(|a| a.to_owned());
+            match maybe_alias {
+                Some(a) => {
+                    // an alias that invokes itself would never finish
+                    alias_count += 1;
+                    if alias_count > MAX_ALIAS_EXPANSIONS {
+                        self.println(format!(
+                            "Too many alias expansions running \"{}\", discarding commands",
+                            arg_0
+                        ));
+                        self.buffer.replace(String::new());
+                        break;
                     }
 
-                    None => {
-                        let tail_args: Vec<&str> =
-                            args.iter().map(|s| s.as_ref()).skip(1).collect();
+                    self.insert_text(a);
+                }
+
+                None => {
+                    let tail_args: Vec<&str> = args.iter().map(|s| s.as_ref()).skip(1).collect();
 
-                        if self.cmds.borrow().contains(arg_0) {
-                            match self.cmds.borrow_mut().exec(arg_0, &tail_args) {
-                                Ok(o) => {
-                                    if !o.is_empty() {
-                                        self.println(o)
-                                    }
+                    if self.cmds.borrow().contains(arg_0) {
+                        match self.cmds.borrow_mut().exec(arg_0, &tail_args) {
+                            Ok(o) => {
+                                if !o.is_empty() {
+                                    self.println(o)
                                 }
-                                Err(e) => self.println(format!("{}", e)),
                             }
-                        } else if self.cvars.borrow().contains(arg_0) {
-                            // TODO error handling on cvar set
-                            match args.get(1) {
-                                Some(arg_1) => self.cvars.borrow_mut().set(arg_0, arg_1).unwrap(),
-                                None => {
-                                    let msg = format!(
-                                        "\"{}\" is \"{}\"",
-                                        arg_0,
-                                        self.cvars.borrow().get(arg_0).unwrap()
-                                    );
-                                    self.println(msg);
-                                }
+                            Err(e) => self.println(format!("{}", e)),
+                        }
+                    } else if self.cvars.borrow().contains(arg_0) {
+                        // TODO error handling on cvar set
+                        match args.get(1) {
+                            Some(arg_1) => self.cvars.borrow().set(arg_0, arg_1).unwrap(),
+                            None => {
+                                let msg = format!(
+                                    "\"{}\" is \"{}\"",
+                                    arg_0,
+                                    self.cvars.borrow().get(arg_0).unwrap()
+                                );
+                                self.println(msg);
                             }
-                        } else {
-                            // TODO: try sending to server first
-                            self.println(format!("Unrecognized command \"{}\"", arg_0));
                         }
+                    } else {
+                        // TODO: try sending to server first
+                        self.println(format!("Unrecognized command \"{}\"", arg_0));
                     }
                 }
             }
+
+            if self.wait.replace(false) {
+                break;
+            }
+        }
+    }
+
+    // Remove the next command from the front of the execution buffer.
+    //
+    // Lines that can't be parsed are discarded with a warning.
+    fn next_command(&self) -> Option<Vec<String>> {
+        let mut buffer = self.buffer.borrow_mut();
+
+        loop {
+            // skip blank lines and comments
+            let start = match parse::console::empty_lines(&buffer) {
+                Ok((rest, _)) => buffer.len() - rest.len(),
+                Err(_) => 0,
+            };
+            buffer.drain(..start);
+
+            if buffer.is_empty() {
+                return None;
+            }
+
+            let parsed = match parse::console::command(&buffer) {
+                Ok((rest, args)) => Some((
+                    buffer.len() - rest.len(),
+                    args.into_iter().map(|a| a.to_owned()).collect(),
+                )),
+                Err(_) => None,
+            };
+
+            match parsed {
+                Some((end, args)) => {
+                    buffer.drain(..end);
+                    return Some(args);
+                }
+
+                None => {
+                    let end = buffer.find('\n').map(|i| i + 1).unwrap_or(buffer.len());
+                    let line: String = buffer.drain(..end).collect();
+                    warn!("Couldn't parse command: {}", line.trim_end());
+                }
+            }
         }
     }
 
@@ -805,6 +1403,7 @@ impl Console {
         String::from_iter(self.input.text.clone().into_iter())
     }
 
+    /// Add text to the end of the execution buffer.
     pub fn stuff_text<S>(&self, text: S)
     where
         S: AsRef<str>,
@@ -816,7 +1415,364 @@ impl Console {
         self.buffer.borrow_mut().push_str("\n");
     }
 
+    /// Add text to the front of the execution buffer.
+    ///
+    /// The text runs after the current command, before anything else in the
+    /// buffer.
+    pub fn insert_text<S>(&self, text: S)
+    where
+        S: AsRef<str>,
+    {
+        debug!("insert_text:\n{:?}", text.as_ref());
+        let mut buffer = self.buffer.borrow_mut();
+        buffer.insert(0, '\n');
+        buffer.insert_str(0, text.as_ref());
+    }
+
     pub fn output(&self) -> Ref<ConsoleOutput> {
         self.output.borrow()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_console() -> Console {
+        let names = Rc::new(RefCell::new(Vec::new()));
+        let cmds = Rc::new(RefCell::new(CmdRegistry::new(names.clone())));
+        let cvars = Rc::new(RefCell::new(CvarRegistry::new(names)));
+        Console::new(cmds, cvars)
+    }
+
+    fn output_lines(console: &Console) -> Vec<String> {
+        console
+            .output()
+            .lines()
+            .map(|l| l.iter().collect())
+            .collect()
+    }
+
+    fn type_text(console: &mut Console, text: &str) {
+        for c in text.chars() {
+            console.send_char(c);
+        }
+    }
+
+    #[test]
+    fn test_is_quotable() {
+        assert!(is_quotable("player one"));
+        assert!(!is_quotable("a\"b"));
+        assert!(!is_quotable("a;quit"));
+        assert!(!is_quotable("a\nquit"));
+    }
+
+    #[test]
+    fn test_console_complete_unique() {
+        let mut console = new_console();
+        console.cvars.borrow().register("sensitivity", "3").unwrap();
+        type_text(&mut console, "sens\t");
+        assert_eq!(console.get_string(), "sensitivity ");
+
+        console.input.clear();
+        type_text(&mut console, "echo hi; he\t");
+        assert_eq!(console.get_string(), "echo hi; help ");
+    }
+
+    #[test]
+    fn test_console_complete_ambiguous() {
+        let mut console = new_console();
+        console.cvars.borrow().register("cl_bob", "0.02").unwrap();
+        console
+            .cvars
+            .borrow()
+            .register("cl_bobcycle", "0.6")
+            .unwrap();
+        console.stuff_text("alias cl_bobup \"cl_bob 0.04\"");
+        console.execute();
+
+        type_text(&mut console, "cl_b\t");
+        assert_eq!(console.get_string(), "cl_bob");
+
+        // output is newest first
+        assert_eq!(
+            output_lines(&console),
+            vec!["    cl_bobup", "    cl_bobcycle", "    cl_bob", "]cl_b"]
+        );
+    }
+
+    #[test]
+    fn test_console_history() {
+        let mut console = new_console();
+        console.load_history(vec!["echo one", "echo two"]);
+        type_text(&mut console, "echo two\r");
+        type_text(&mut console, "\r");
+        type_text(&mut console, "echo three\r");
+        assert_eq!(
+            console.history(),
+            vec!["echo one", "echo two", "echo three"]
+        );
+
+        console.history_up();
+        console.history_up();
+        assert_eq!(console.get_string(), "echo two");
+        console.history_down();
+        assert_eq!(console.get_string(), "echo three");
+    }
+
+    #[test]
+    fn test_console_history_max_lines() {
+        let mut console = new_console();
+        console.load_history((0..MAX_HISTORY_LINES + 10).map(|i| format!("echo {}", i)));
+        let history = console.history();
+        assert_eq!(history.len(), MAX_HISTORY_LINES);
+        assert_eq!(history[0], "echo 10");
+    }
+
+    #[test]
+    fn test_console_output() {
+        let console = new_console();
+        console.print("partial ");
+        assert!(output_lines(&console).is_empty());
+
+        console.println("line");
+        console.stuff_text("echo second");
+        console.execute();
+
+        // newest lines first
+        assert_eq!(output_lines(&console), vec!["second", "partial line"]);
+
+        console.stuff_text("clear");
+        console.execute();
+        assert!(output_lines(&console).is_empty());
+    }
+
+    #[test]
+    fn test_console_output_max_lines() {
+        let console = new_console();
+        for i in 0..MAX_OUTPUT_LINES + 10 {
+            console.println(format!("{}", i));
+        }
+
+        let lines = output_lines(&console);
+        assert_eq!(lines.len(), MAX_OUTPUT_LINES);
+        assert_eq!(lines[0], format!("{}", MAX_OUTPUT_LINES + 9));
+        assert_eq!(lines[MAX_OUTPUT_LINES - 1], "10");
+    }
+
+    #[test]
+    fn test_console_scrollback() {
+        let console = new_console();
+        console.set_scrollback(20);
+        for i in 0..30 {
+            console.println(format!("{}", i));
+        }
+        assert_eq!(output_lines(&console).len(), 20);
+
+        console.page_up();
+        let visible: Vec<String> = console
+            .output()
+            .visible_lines()
+            .map(|l| l.iter().collect())
+            .collect();
+        assert_eq!(visible[0], format!("{}", 29 - PAGE_LINES));
+
+        // new output doesn't move the view
+        console.println("30");
+        assert_eq!(console.output().scroll(), PAGE_LINES + 1);
+
+        // scrolling stops at the oldest line
+        console.page_up();
+        console.page_up();
+        assert_eq!(console.output().scroll(), 19);
+
+        console.page_down();
+        assert_eq!(console.output().scroll(), 19 - PAGE_LINES);
+    }
+
+    #[test]
+    fn test_console_search() {
+        let mut console = new_console();
+        console.println("Player joined");
+        console.println("something else");
+        console.println("player left");
+        console.println("more output");
+
+        type_text(&mut console, "con_search PLAYER\r");
+        console.execute();
+        assert_eq!(console.output().scroll(), 2);
+
+        // repeating the search finds the next older match
+        type_text(&mut console, "con_search player\r");
+        console.execute();
+        let visible: Vec<String> = console
+            .output()
+            .visible_lines()
+            .map(|l| l.iter().collect())
+            .collect();
+        assert_eq!(visible[0], "Player joined");
+
+        type_text(&mut console, "con_search player\r");
+        console.execute();
+        assert_eq!(
+            output_lines(&console)[0],
+            "No earlier output contains \"player\""
+        );
+    }
+
+    #[test]
+    fn test_console_help_find() {
+        let console = new_console();
+        {
+            let cvars = console.cvars.borrow();
+            cvars.register("sensitivity", "3").unwrap();
+            cvars
+                .describe("sensitivity", "Mouse speed multiplier")
+                .unwrap();
+            cvars.set("sensitivity", "5").unwrap();
+        }
+
+        console.stuff_text("help sensitivity");
+        console.execute();
+        assert_eq!(
+            output_lines(&console),
+            vec![
+                "Mouse speed multiplier",
+                "\"sensitivity\" is \"5\" (default \"3\")",
+            ]
+        );
+
+        console.stuff_text("clear\nhelp echo");
+        console.execute();
+        assert_eq!(
+            output_lines(&console),
+            vec!["echo: Print text to the console"]
+        );
+
+        // matches names and descriptions
+        console.stuff_text("clear\nfind MOUSE");
+        console.execute();
+        assert_eq!(
+            output_lines(&console),
+            vec!["sensitivity - Mouse speed multiplier"]
+        );
+
+        console.stuff_text("clear\nfind ec");
+        console.execute();
+        assert_eq!(
+            output_lines(&console),
+            vec!["echo - Print text to the console"]
+        );
+    }
+
+    #[test]
+    fn test_cvar_hooks() {
+        let names = Rc::new(RefCell::new(Vec::new()));
+        let cvars = Rc::new(CvarRegistry::new(names));
+        cvars.register("volume", "0.7").unwrap();
+        cvars.register("bgmvolume", "1").unwrap();
+
+        // hooks can modify other cvars
+        let hook_cvars = cvars.clone();
+        cvars
+            .add_hook(
+                "volume",
+                Box::new(move |_, value| hook_cvars.set("bgmvolume", value).unwrap()),
+            )
+            .unwrap();
+
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let hook_changes = changes.clone();
+        cvars
+            .add_hook(
+                "bgmvolume",
+                Box::new(move |name, value| {
+                    hook_changes
+                        .borrow_mut()
+                        .push(format!("{} {}", name, value))
+                }),
+            )
+            .unwrap();
+
+        cvars.set("volume", "0.5").unwrap();
+        assert_eq!(cvars.get("bgmvolume").unwrap(), "0.5");
+
+        // setting the same value again doesn't trigger hooks
+        cvars.set("bgmvolume", "0.5").unwrap();
+        assert_eq!(*changes.borrow(), vec!["bgmvolume 0.5"]);
+
+        assert!(cvars.add_hook("nonexistent", Box::new(|_, _| ())).is_err());
+    }
+
+    #[test]
+    fn test_cvar_notify() {
+        let names = Rc::new(RefCell::new(Vec::new()));
+        let cvars = CvarRegistry::new(names);
+        cvars.register_notify("sv_gravity", "800").unwrap();
+        cvars.register_archive_notify("timelimit", "0").unwrap();
+        cvars.register("sv_friction", "4").unwrap();
+
+        cvars.set("sv_gravity", "200").unwrap();
+        cvars.set("sv_gravity", "100").unwrap();
+        cvars.set("sv_friction", "2").unwrap();
+        cvars.set("timelimit", "10").unwrap();
+        assert_eq!(
+            cvars.take_notify_changes(),
+            vec![
+                ("sv_gravity".to_owned(), "100".to_owned()),
+                ("timelimit".to_owned(), "10".to_owned()),
+            ]
+        );
+        assert!(cvars.take_notify_changes().is_empty());
+
+        assert_eq!(
+            cvars.notify_values(),
+            vec![
+                ("sv_gravity".to_owned(), "100".to_owned()),
+                ("timelimit".to_owned(), "10".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_console_alias() {
+        let console = new_console();
+        console.stuff_text("alias greet \"echo hello; echo there\"\ngreet; echo done");
+        console.execute();
+        assert_eq!(output_lines(&console), vec!["done", "there", "hello"]);
+
+        // recursive aliases are cut off
+        console.stuff_text("clear\nalias loop loop\nloop\necho unreachable");
+        console.execute();
+        assert_eq!(output_lines(&console).len(), 1);
+        assert!(output_lines(&console)[0].starts_with("Too many alias expansions"));
+    }
+
+    #[test]
+    fn test_console_insert_text() {
+        let console = new_console();
+        console.stuff_text("echo first\necho third");
+        console.insert_text("echo zeroth");
+        console.execute();
+        assert_eq!(output_lines(&console), vec!["third", "first", "zeroth"]);
+    }
+
+    #[test]
+    fn test_console_wait() {
+        let console = new_console();
+        console.stuff_text("echo one; wait; echo two");
+        console.execute();
+        assert_eq!(output_lines(&console), vec!["one"]);
+
+        console.execute();
+        assert_eq!(output_lines(&console), vec!["two", "one"]);
+    }
+
+    #[test]
+    fn test_console_bad_line() {
+        let console = new_console();
+        console.stuff_text("// comment\necho \"unterminated\necho ok");
+        console.execute();
+        assert_eq!(output_lines(&console), vec!["ok"]);
+    }
+}
@@ -18,9 +18,16 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use std::cell::{Ref, RefMut};
+use std::{
+    cell::{Ref, RefCell, RefMut},
+    rc::Rc,
+};
 
-use crate::common::{console::CvarRegistry, engine};
+use crate::common::{
+    console::{CmdRegistry, CvarRegistry},
+    engine,
+    plugin::{ClientPlugin, Plugins, ServerPlugin},
+};
 
 use chrono::{DateTime, Duration, Utc};
 use winit::{
@@ -38,8 +45,15 @@ pub trait Program: Sized {
 
     fn frame(&mut self, frame_duration: Duration);
     fn shutdown(&mut self);
+
+    /// Returns true if the program has asked to exit, e.g. with `quit`.
+    fn quit_requested(&self) -> bool {
+        false
+    }
+
     fn cvars(&self) -> Ref<CvarRegistry>;
     fn cvars_mut(&self) -> RefMut<CvarRegistry>;
+    fn cmds_mut(&self) -> RefMut<CmdRegistry>;
 }
 
 pub struct Host<P>
@@ -51,27 +65,64 @@ where
     init_time: DateTime<Utc>,
     prev_frame_time: DateTime<Utc>,
     prev_frame_duration: Duration,
+
+    // whether the window has focus, for host_bgmaxfps
+    focused: bool,
+
+    plugins: Rc<RefCell<Plugins>>,
 }
 
 impl<P> Host<P>
 where
     P: Program,
 {
-    pub fn new(program: P) -> Host<P> {
+    /// Create a host for `program`.
+    ///
+    /// `plugins` must be the same plugins the program was built with, so that
+    /// plugins added through the host reach the parts of the program that call
+    /// their hooks.
+    pub fn new(program: P, plugins: Rc<RefCell<Plugins>>) -> Host<P> {
         let init_time = Utc::now();
         program
             .cvars_mut()
             .register_archive("host_maxfps", "72")
             .unwrap();
+        program
+            .cvars_mut()
+            .register_archive("host_bgmaxfps", "20")
+            .unwrap();
+
+        program
+            .cmds_mut()
+            .insert_or_replace("plugins", cmd_plugins(plugins.clone()))
+            .unwrap();
 
         Host {
             program,
             init_time,
             prev_frame_time: init_time,
             prev_frame_duration: Duration::zero(),
+            focused: true,
+            plugins,
         }
     }
 
+    pub fn add_client_plugin(&mut self, plugin: Box<dyn ClientPlugin>) {
+        self.plugins.borrow_mut().add_client(
+            plugin,
+            &mut self.program.cmds_mut(),
+            &self.program.cvars(),
+        );
+    }
+
+    pub fn add_server_plugin(&mut self, plugin: Box<dyn ServerPlugin>) {
+        self.plugins.borrow_mut().add_server(
+            plugin,
+            &mut self.program.cmds_mut(),
+            &self.program.cvars(),
+        );
+    }
+
     pub fn handle_event<T>(
         &mut self,
         event: Event<T>,
@@ -87,7 +138,22 @@ where
                 *control_flow = ControlFlow::Exit;
             }
 
-            Event::MainEventsCleared => self.frame(),
+            Event::WindowEvent {
+                event: WindowEvent::Focused(focused),
+                ..
+            } => {
+                self.focused = focused;
+                self.program.handle_event(event, _target, control_flow);
+            }
+
+            Event::MainEventsCleared => {
+                self.frame();
+                if self.program.quit_requested() {
+                    self.program.shutdown();
+                    *control_flow = ControlFlow::Exit;
+                }
+            }
+
             Event::Suspended | Event::Resumed => unimplemented!(),
             Event::LoopDestroyed => {
                 // TODO:
@@ -116,15 +182,22 @@ where
         self.prev_frame_time = new_frame_time;
 
         self.program.frame(self.prev_frame_duration);
+        self.plugins.borrow_mut().frame(self.prev_frame_duration);
     }
 
     // Returns whether enough time has elapsed to run the next frame.
     fn check_frame_duration(&mut self, frame_duration: Duration) -> bool {
-        let host_maxfps = self
-            .program
-            .cvars()
-            .get_value("host_maxfps")
-            .unwrap_or(72.0);
+        let cvars = self.program.cvars();
+        let mut host_maxfps = cvars.get_value("host_maxfps").unwrap_or(72.0);
+
+        // run slower in the background, unless host_bgmaxfps is 0
+        if !self.focused {
+            let host_bgmaxfps = cvars.get_value("host_bgmaxfps").unwrap_or(0.0);
+            if host_bgmaxfps > 0.0 {
+                host_maxfps = host_maxfps.min(host_bgmaxfps);
+            }
+        }
+
         let min_frame_duration = engine::duration_from_f32(1.0 / host_maxfps);
         frame_duration >= min_frame_duration
     }
@@ -133,3 +206,33 @@ where
         self.prev_frame_time.signed_duration_since(self.init_time)
     }
 }
+
+/// The `framestep` command, which lets `host_framestep` mode advance the
+/// given number of frames, or one if no count is given.
+pub fn cmd_framestep(framestep: Rc<RefCell<usize>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| {
+        let count = match args.len() {
+            0 => 1,
+            1 => match args[0].parse::<usize>() {
+                Ok(n) => n,
+                Err(_) => return "usage: framestep [FRAMES]".to_owned(),
+            },
+            _ => return "usage: framestep [FRAMES]".to_owned(),
+        };
+
+        *framestep.borrow_mut() += count;
+        String::new()
+    })
+}
+
+/// The `plugins` command, which lists the loaded plugins by name.
+pub fn cmd_plugins(plugins: Rc<RefCell<Plugins>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| {
+        let mut out = String::new();
+        for name in plugins.borrow().names() {
+            out.push_str(name);
+            out.push('\n');
+        }
+        out
+    })
+}
@@ -20,7 +20,7 @@
 
 use std::{cmp::Ordering, convert::Into, ops::Neg};
 
-use cgmath::{Angle, Deg, InnerSpace, Matrix3, Matrix4, Vector2, Vector3, Zero};
+use cgmath::{Angle, Deg, InnerSpace, Matrix, Matrix3, Matrix4, Vector2, Vector3, Vector4, Zero};
 
 trait CoordSys {}
 
@@ -264,6 +264,20 @@ impl std::ops::Mul<f32> for Angles {
     }
 }
 
+/// Returns the forward, right and up vectors of a set of pitch, yaw and roll
+/// angles, as `AngleVectors` does in the original engine.
+pub fn angle_vectors(angles: Vector3<Deg<f32>>) -> (Vector3<f32>, Vector3<f32>, Vector3<f32>) {
+    let (sp, cp) = angles.x.sin_cos();
+    let (sy, cy) = angles.y.sin_cos();
+    let (sr, cr) = angles.z.sin_cos();
+
+    let forward = Vector3::new(cp * cy, cp * sy, -sp);
+    let right = Vector3::new(-sr * sp * cy + cr * sy, -sr * sp * sy - cr * cy, -sr * cp);
+    let up = Vector3::new(cr * sp * cy + sr * sy, cr * sp * sy - sr * cy, cr * cp);
+
+    (forward, right, up)
+}
+
 pub fn clamp_deg(val: Deg<f32>, min: Deg<f32>, max: Deg<f32>) -> Deg<f32> {
     assert!(min <= max);
 
@@ -448,6 +462,11 @@ impl Hyperplane {
         }
     }
 
+    /// Returns the distance of this plane from the origin along its normal.
+    pub fn dist(&self) -> f32 {
+        self.dist
+    }
+
     /// Calculates the shortest distance between this hyperplane and the given point.
     pub fn point_dist(&self, point: Vector3<f32>) -> f32 {
         match self.alignment {
@@ -511,6 +530,84 @@ impl Hyperplane {
     }
 }
 
+/// The six planes bounding a view volume.
+///
+/// The plane normals face into the volume.
+#[derive(Clone, Debug)]
+pub struct Frustum {
+    planes: [Hyperplane; 6],
+}
+
+impl Frustum {
+    /// Extracts the frustum planes from a combined projection-view matrix.
+    ///
+    /// The planes are in the coordinate space the matrix transforms from. Depth is expected to
+    /// map to `[-1, 1]`, as it does with `cgmath::perspective`. See Gribb and Hartmann, "Fast
+    /// Extraction of Viewing Frustum Planes from the World-View-Projection Matrix".
+    pub fn from_matrix(m: Matrix4<f32>) -> Frustum {
+        let plane = |v: Vector4<f32>| {
+            let normal = v.truncate();
+            let len = normal.magnitude();
+            if len == 0.0 {
+                // a plane at infinity never culls anything
+                return Hyperplane::from_normal(Vector3::unit_z(), f32::NEG_INFINITY);
+            }
+
+            Hyperplane::from_normal(normal / len, -v.w / len)
+        };
+
+        let (x, y, z, w) = (m.row(0), m.row(1), m.row(2), m.row(3));
+        Frustum {
+            planes: [
+                // left
+                plane(w + x),
+                // right
+                plane(w - x),
+                // bottom
+                plane(w + y),
+                // top
+                plane(w - y),
+                // near
+                plane(w + z),
+                // far
+                plane(w - z),
+            ],
+        }
+    }
+
+    pub fn planes(&self) -> &[Hyperplane; 6] {
+        &self.planes
+    }
+
+    /// Returns `true` if `point` is inside the frustum.
+    pub fn contains_point(&self, point: Vector3<f32>) -> bool {
+        self.planes.iter().all(|p| p.point_dist(point) >= 0.0)
+    }
+
+    /// Returns `true` if the box from `min` to `max` is entirely outside the frustum.
+    ///
+    /// This is conservative: a box near a corner of the frustum may be kept even though it's
+    /// outside.
+    pub fn cull_box(&self, min: Vector3<f32>, max: Vector3<f32>) -> bool {
+        self.planes.iter().any(|p| {
+            // the corner furthest along the plane normal
+            let n = p.normal();
+            let corner = Vector3::new(
+                if n.x >= 0.0 { max.x } else { min.x },
+                if n.y >= 0.0 { max.y } else { min.y },
+                if n.z >= 0.0 { max.z } else { min.z },
+            );
+
+            p.point_dist(corner) < 0.0
+        })
+    }
+
+    /// Returns `true` if the sphere at `center` is entirely outside the frustum.
+    pub fn cull_sphere(&self, center: Vector3<f32>, radius: f32) -> bool {
+        self.planes.iter().any(|p| p.point_dist(center) < -radius)
+    }
+}
+
 pub fn fov_x_to_fov_y(fov_x: Deg<f32>, aspect: f32) -> Option<Deg<f32>> {
     // aspect = tan(fov_x / 2) / tan(fov_y / 2)
     // tan(fov_y / 2) = tan(fov_x / 2) / aspect
@@ -631,6 +728,22 @@ pub fn vec3_truncate_n(v: Vector3<f32>, n: usize) -> Vector2<f32> {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_angle_vectors() {
+        let close = |a: Vector3<f32>, b: Vector3<f32>| (a - b).magnitude() < 1e-5;
+
+        let (forward, right, up) = angle_vectors(Vector3::new(Deg(0.0), Deg(0.0), Deg(0.0)));
+        assert!(close(forward, Vector3::unit_x()));
+        assert!(close(right, -Vector3::unit_y()));
+        assert!(close(up, Vector3::unit_z()));
+
+        // facing +y, looking 90 degrees down
+        let (forward, right, up) = angle_vectors(Vector3::new(Deg(90.0), Deg(90.0), Deg(0.0)));
+        assert!(close(forward, -Vector3::unit_z()));
+        assert!(close(right, Vector3::unit_x()));
+        assert!(close(up, Vector3::unit_y()));
+    }
+
     #[test]
     fn test_hyperplane_side_x() {
         let plane = Hyperplane::axis_x(1.0);
@@ -868,4 +981,28 @@ mod test {
             assert_eq!(remove_collinear(input), output);
         }
     }
+
+    #[test]
+    fn test_frustum() {
+        // looking down -z from the origin with a 90 degree field of view
+        let frustum = Frustum::from_matrix(cgmath::perspective(Deg(90.0), 1.0, 1.0, 100.0));
+
+        assert!(frustum.contains_point(Vector3::new(0.0, 0.0, -10.0)));
+        assert!(frustum.contains_point(Vector3::new(9.0, -9.0, -10.0)));
+        assert!(!frustum.contains_point(Vector3::new(11.0, 0.0, -10.0)));
+        assert!(!frustum.contains_point(Vector3::new(0.0, 0.0, 10.0)));
+        assert!(!frustum.contains_point(Vector3::new(0.0, 0.0, -101.0)));
+
+        // boxes and spheres straddling a plane are kept
+        assert!(!frustum.cull_box(
+            Vector3::new(5.0, -1.0, -11.0),
+            Vector3::new(15.0, 1.0, -9.0)
+        ));
+        assert!(frustum.cull_box(
+            Vector3::new(12.0, -1.0, -11.0),
+            Vector3::new(15.0, 1.0, -9.0)
+        ));
+        assert!(!frustum.cull_sphere(Vector3::new(0.0, 0.0, 2.0), 4.0));
+        assert!(frustum.cull_sphere(Vector3::new(0.0, 0.0, 5.0), 4.0));
+    }
 }
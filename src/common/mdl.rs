@@ -399,12 +399,11 @@ where
                 // TODO: sanity check this value
                 let texture_frame_count = reader.read_i32::<LittleEndian>()? as usize;
 
-                let mut durations = Vec::with_capacity(texture_frame_count);
+                let mut intervals = Vec::with_capacity(texture_frame_count);
                 for _ in 0..texture_frame_count {
-                    durations.push(engine::duration_from_f32(
-                        reader.read_f32::<LittleEndian>()?,
-                    ));
+                    intervals.push(reader.read_f32::<LittleEndian>()?);
                 }
+                let durations = durations_from_intervals(&intervals);
 
                 let mut frames = Vec::with_capacity(texture_frame_count);
                 for frame_id in 0..texture_frame_count {
@@ -516,12 +515,11 @@ where
                 let abs_max = read_vertex(&mut reader, scale, origin)?;
                 reader.read_u8()?; // discard vertex normal
 
-                let mut durations = Vec::new();
+                let mut intervals = Vec::new();
                 for _ in 0..subframe_count {
-                    durations.push(engine::duration_from_f32(
-                        reader.read_f32::<LittleEndian>()?,
-                    ));
+                    intervals.push(reader.read_f32::<LittleEndian>()?);
                 }
+                let durations = durations_from_intervals(&intervals);
 
                 let mut subframes = Vec::new();
                 for subframe_id in 0..subframe_count {
@@ -585,6 +583,21 @@ where
     })
 }
 
+/// Convert the intervals of a frame group into the duration of each frame.
+///
+/// Groups store the time at which each frame ends, counting from the start of the group.
+pub fn durations_from_intervals(intervals: &[f32]) -> Vec<Duration> {
+    let mut start = 0.0;
+    intervals
+        .iter()
+        .map(|end| {
+            let duration = engine::duration_from_f32((end - start).max(0.0));
+            start = *end;
+            duration
+        })
+        .collect()
+}
+
 fn read_vertex<R>(
     reader: &mut R,
     scale: Vector3<f32>,
@@ -601,3 +614,20 @@ where
     .mul_element_wise(scale)
         + translate)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_durations_from_intervals() {
+        assert_eq!(
+            durations_from_intervals(&[0.125, 0.375, 0.5]),
+            vec![
+                Duration::milliseconds(125),
+                Duration::milliseconds(250),
+                Duration::milliseconds(125),
+            ]
+        );
+    }
+}
@@ -19,6 +19,7 @@
 // SOFTWARE.
 
 pub mod alloc;
+pub mod args;
 pub mod bitset;
 pub mod bsp;
 pub mod console;
@@ -30,7 +31,10 @@ pub mod model;
 pub mod net;
 pub mod pak;
 pub mod parse;
+pub mod plugin;
 pub mod sprite;
+pub mod stream;
+pub mod tga;
 pub mod util;
 pub mod vfs;
 pub mod wad;
@@ -0,0 +1,388 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Async versions of the socket types, built on tokio.
+//!
+//! These share their packet framing with the blocking sockets in
+//! [`net`](super) and [`connect`](super::connect), so the two can be used
+//! interchangeably on either end of a connection. Each socket only needs a
+//! task rather than a thread, which lets a server multiplex many clients on a
+//! single thread.
+//!
+//! All of these types must be created from within a tokio runtime.
+
+use std::net::SocketAddr;
+
+use crate::common::net::{
+    self,
+    connect::{ConnectPacket as _, Request, Response},
+    stats::{LatencyGraph, NetStats},
+    Framer, NetError, Received, MAX_MESSAGE,
+};
+
+use chrono::Duration;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+
+/// An async [`QSocket`](super::QSocket).
+pub struct AsyncQSocket {
+    socket: UdpSocket,
+    remote: SocketAddr,
+    framer: Framer,
+    recv_buf: Box<[u8]>,
+}
+
+impl AsyncQSocket {
+    pub fn new(socket: UdpSocket, remote: SocketAddr) -> AsyncQSocket {
+        AsyncQSocket {
+            socket,
+            remote,
+            framer: Framer::new(),
+            recv_buf: vec![0; MAX_MESSAGE].into_boxed_slice(),
+        }
+    }
+
+    /// Convert a blocking socket into an async one.
+    pub fn from_std(
+        socket: std::net::UdpSocket,
+        remote: SocketAddr,
+    ) -> Result<AsyncQSocket, NetError> {
+        socket.set_nonblocking(true)?;
+        Ok(AsyncQSocket::new(UdpSocket::from_std(socket)?, remote))
+    }
+
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    pub fn can_send(&self) -> bool {
+        self.framer.can_send()
+    }
+
+    /// Return the traffic counters for this connection.
+    pub fn stats(&self) -> NetStats {
+        self.framer.stats
+    }
+
+    /// Return the recent round-trip times for this connection.
+    pub fn latency_graph(&self) -> &LatencyGraph {
+        &self.framer.latency_graph
+    }
+
+    /// Return the time since a packet was last sent.
+    pub fn time_since_send(&self) -> Duration {
+        self.framer.time_since_send()
+    }
+
+    /// Return the time since a packet was last received from the remote.
+    pub fn time_since_recv(&self) -> Duration {
+        self.framer.time_since_recv()
+    }
+
+    /// Begin sending a reliable message over this socket.
+    pub async fn begin_send_msg(&mut self, msg: &[u8]) -> Result<(), NetError> {
+        let packet = self.framer.begin_send_msg(msg)?;
+        self.socket.send_to(packet, self.remote).await?;
+        Ok(())
+    }
+
+    /// Resend the last reliable message packet.
+    pub async fn resend_msg(&mut self) -> Result<(), NetError> {
+        let packet = self.framer.resend_packet()?;
+        self.socket.send_to(packet, self.remote).await?;
+        Ok(())
+    }
+
+    /// Send the next segment of a reliable message.
+    pub async fn send_msg_next(&mut self) -> Result<(), NetError> {
+        let packet = self.framer.next_packet()?;
+        self.socket.send_to(packet, self.remote).await?;
+        Ok(())
+    }
+
+    pub async fn send_msg_unreliable(&mut self, content: &[u8]) -> Result<(), NetError> {
+        let packet = self.framer.unreliable_packet(content)?;
+        self.socket.send_to(&packet, self.remote).await?;
+        Ok(())
+    }
+
+    /// Receive a message on this socket.
+    ///
+    /// This waits until a complete message arrives. If the future is dropped
+    /// partway through a multi-packet reliable message, the packets received
+    /// so far are kept until the rest of the message arrives.
+    pub async fn recv_msg(&mut self) -> Result<Vec<u8>, NetError> {
+        let mut msg = Vec::new();
+
+        loop {
+            let (packet_len, src_addr) = self.socket.recv_from(&mut self.recv_buf).await?;
+
+            if src_addr != self.remote {
+                // this packet didn't come from remote, drop it
+                debug!(
+                    "forged packet (src_addr was {}, should be {})",
+                    src_addr, self.remote
+                );
+                continue;
+            }
+
+            match self
+                .framer
+                .handle_packet(&self.recv_buf[..packet_len], &mut msg)?
+            {
+                Received::Continue => (),
+                Received::Stale => break,
+                Received::Unreliable => return Ok(msg),
+                Received::Reliable { ack, complete } => {
+                    self.socket.send_to(&ack, self.remote).await?;
+
+                    if complete {
+                        break;
+                    }
+                }
+            }
+        }
+
+        if let Some(packet) = self.framer.take_next_packet()? {
+            self.socket.send_to(packet, self.remote).await?;
+        }
+
+        Ok(msg)
+    }
+
+    /// Receive a message on this socket, giving up after `timeout`.
+    ///
+    /// Returns `None` if the timeout elapsed first.
+    pub async fn recv_msg_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, NetError> {
+        match tokio::time::timeout(net::read_timeout(timeout), self.recv_msg()).await {
+            Ok(r) => r.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// An async [`ConnectListener`](super::connect::ConnectListener).
+pub struct AsyncConnectListener {
+    socket: UdpSocket,
+}
+
+impl AsyncConnectListener {
+    pub async fn bind<A>(addr: A) -> Result<AsyncConnectListener, NetError>
+    where
+        A: ToSocketAddrs,
+    {
+        let socket = UdpSocket::bind(addr).await?;
+
+        Ok(AsyncConnectListener { socket })
+    }
+
+    /// Returns the local address this listener is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, NetError> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Receives a request and returns it along with its remote address.
+    pub async fn recv_request(&self) -> Result<(Request, SocketAddr), NetError> {
+        let mut recv_buf = [0u8; MAX_MESSAGE];
+        let (len, remote) = self.socket.recv_from(&mut recv_buf).await?;
+        let request = Request::from_bytes(&recv_buf[..len])?;
+
+        Ok((request, remote))
+    }
+
+    pub async fn send_response(
+        &self,
+        response: Response,
+        remote: SocketAddr,
+    ) -> Result<(), NetError> {
+        self.socket.send_to(&response.to_bytes()?, remote).await?;
+        Ok(())
+    }
+}
+
+/// An async [`ConnectSocket`](super::connect::ConnectSocket).
+pub struct AsyncConnectSocket {
+    socket: UdpSocket,
+}
+
+impl AsyncConnectSocket {
+    pub async fn bind<A>(local: A) -> Result<AsyncConnectSocket, NetError>
+    where
+        A: ToSocketAddrs,
+    {
+        let socket = UdpSocket::bind(local).await?;
+
+        Ok(AsyncConnectSocket { socket })
+    }
+
+    /// Binds to an ephemeral port on the wildcard address of the same family
+    /// as `remote`.
+    pub async fn bind_for(remote: &SocketAddr) -> Result<AsyncConnectSocket, NetError> {
+        AsyncConnectSocket::bind(net::unspecified_addr(remote)).await
+    }
+
+    pub fn into_qsocket(self, remote: SocketAddr) -> AsyncQSocket {
+        AsyncQSocket::new(self.socket, remote)
+    }
+
+    /// Send a `Request` to the server at the specified address.
+    pub async fn send_request(
+        &mut self,
+        request: Request,
+        remote: SocketAddr,
+    ) -> Result<(), NetError> {
+        self.socket.send_to(&request.to_bytes()?, remote).await?;
+        Ok(())
+    }
+
+    /// Receive a `Response` from the server.
+    ///
+    /// If `timeout` is not `None`, the operation times out after the specified
+    /// duration and the function returns `None`.
+    pub async fn recv_response(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<(Response, SocketAddr)>, NetError> {
+        let mut recv_buf = [0u8; MAX_MESSAGE];
+
+        let recv = self.socket.recv_from(&mut recv_buf);
+        let (len, remote) = match timeout {
+            Some(d) => match tokio::time::timeout(net::read_timeout(d), recv).await {
+                Ok(r) => r?,
+                Err(_) => return Ok(None),
+            },
+            None => recv.await?,
+        };
+
+        let response = Response::from_bytes(&recv_buf[..len])?;
+
+        Ok(Some((response, remote)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::common::net::{connect::ResponseReject, BlockingMode, QSocket};
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_async_qsocket_send_msg_sync_recv() {
+        runtime().block_on(async {
+            let src_udp = UdpSocket::bind("localhost:0").await.unwrap();
+            let dst_udp = std::net::UdpSocket::bind("localhost:0").unwrap();
+            let src_addr = src_udp.local_addr().unwrap();
+            let dst_addr = dst_udp.local_addr().unwrap();
+
+            let mut src = AsyncQSocket::new(src_udp, dst_addr);
+            let mut dst = QSocket::new(dst_udp, src_addr);
+
+            let message = String::from("test message").into_bytes();
+            src.send_msg_unreliable(&message).await.unwrap();
+            let received = dst.recv_msg(BlockingMode::Blocking).unwrap();
+            assert_eq!(message, received);
+        });
+    }
+
+    #[test]
+    fn test_async_qsocket_send_msg_short() {
+        runtime().block_on(async {
+            let src_udp = UdpSocket::bind("localhost:0").await.unwrap();
+            let dst_udp = UdpSocket::bind("localhost:0").await.unwrap();
+            let src_addr = src_udp.local_addr().unwrap();
+            let dst_addr = dst_udp.local_addr().unwrap();
+
+            let mut src = AsyncQSocket::new(src_udp, dst_addr);
+            let mut dst = AsyncQSocket::new(dst_udp, src_addr);
+
+            let message = String::from("test message").into_bytes();
+            src.begin_send_msg(&message).await.unwrap();
+            let received = dst.recv_msg().await.unwrap();
+            assert_eq!(message, received);
+        });
+    }
+
+    #[test]
+    fn test_async_recv_msg_timeout() {
+        runtime().block_on(async {
+            let udp = UdpSocket::bind("localhost:0").await.unwrap();
+            let addr = udp.local_addr().unwrap();
+            let mut sock = AsyncQSocket::new(udp, addr);
+
+            let received = sock
+                .recv_msg_timeout(Duration::milliseconds(10))
+                .await
+                .unwrap();
+            assert!(received.is_none());
+
+            // a deadline that has already passed times out immediately
+            let received = sock
+                .recv_msg_timeout(Duration::milliseconds(-5))
+                .await
+                .unwrap();
+            assert!(received.is_none());
+        });
+    }
+
+    #[test]
+    fn test_async_connect_request_response() {
+        runtime().block_on(async {
+            let listener = AsyncConnectListener::bind("localhost:0").await.unwrap();
+            let server_addr = listener.local_addr().unwrap();
+            let mut client = AsyncConnectSocket::bind("localhost:0").await.unwrap();
+
+            client
+                .send_request(Request::server_info("QUAKE"), server_addr)
+                .await
+                .unwrap();
+            let (request, client_addr) = listener.recv_request().await.unwrap();
+            match request {
+                Request::ServerInfo(info) => assert_eq!(info.game_name, "QUAKE"),
+                r => panic!("expected server info request, got {:?}", r),
+            }
+
+            let response = Response::Reject(ResponseReject {
+                message: String::from("server is full"),
+            });
+            listener.send_response(response, client_addr).await.unwrap();
+            let (response, remote) = client
+                .recv_response(Some(Duration::seconds(1)))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(remote, server_addr);
+            assert_eq!(
+                response,
+                Response::Reject(ResponseReject {
+                    message: String::from("server is full"),
+                })
+            );
+        });
+    }
+}
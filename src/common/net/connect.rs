@@ -19,19 +19,21 @@
 // SOFTWARE.
 
 use std::{
+    fmt,
     io::{BufReader, Cursor, ErrorKind},
     mem::size_of,
-    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    net::{Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
 };
 
 use crate::common::{
-    net::{NetError, QSocket, MAX_MESSAGE},
+    net::{self, NetError, QSocket, MAX_MESSAGE},
     util,
 };
 
 use byteorder::{LittleEndian, NetworkEndian, ReadBytesExt, WriteBytesExt};
-use chrono::Duration;
+use chrono::{Duration, Utc};
 use num::FromPrimitive;
+use socket2::{Domain, Protocol, Socket, Type};
 
 pub const CONNECT_PROTOCOL_VERSION: u8 = 3;
 const CONNECT_CONTROL: i32 = 1 << 31;
@@ -86,6 +88,7 @@ pub enum RequestCode {
     ServerInfo = 2,
     PlayerInfo = 3,
     RuleInfo = 4,
+    Rcon = 5,
 }
 
 #[derive(Debug)]
@@ -115,7 +118,7 @@ impl ConnectPacket for RequestConnect {
     where
         W: WriteBytesExt,
     {
-        writer.write(self.game_name.as_bytes())?;
+        writer.write(&util::quake_string_to_bytes(&self.game_name))?;
         writer.write_u8(0)?;
         writer.write_u8(self.proto_ver)?;
         Ok(())
@@ -141,7 +144,7 @@ impl ConnectPacket for RequestServerInfo {
     where
         W: WriteBytesExt,
     {
-        writer.write(self.game_name.as_bytes())?;
+        writer.write(&util::quake_string_to_bytes(&self.game_name))?;
         writer.write_u8(0)?;
         Ok(())
     }
@@ -190,7 +193,72 @@ impl ConnectPacket for RequestRuleInfo {
     where
         W: WriteBytesExt,
     {
-        writer.write(self.prev_cvar.as_bytes())?;
+        writer.write(&util::quake_string_to_bytes(&self.prev_cvar))?;
+        writer.write_u8(0)?;
+        Ok(())
+    }
+}
+
+/// A remote console command.
+///
+/// The password is sent in the clear, as in ProQuake; rcon should only be
+/// enabled on servers whose admins accept that.
+pub struct RequestRcon {
+    pub password: String,
+    pub command: String,
+}
+
+impl RequestRcon {
+    /// Returns `true` if this request's password matches `password`.
+    ///
+    /// An empty `password` means rcon is disabled, so nothing matches it.
+    pub fn authenticate(&self, password: &str) -> bool {
+        if password.is_empty() || self.password.len() != password.len() {
+            return false;
+        }
+
+        // compare every byte so the time taken doesn't leak the password
+        self.password
+            .bytes()
+            .zip(password.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
+
+// don't print passwords in logs
+impl fmt::Debug for RequestRcon {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RequestRcon")
+            .field("command", &self.command)
+            .finish()
+    }
+}
+
+impl ConnectPacket for RequestRcon {
+    fn code(&self) -> u8 {
+        RequestCode::Rcon as u8
+    }
+
+    fn content_len(&self) -> usize {
+        let mut len = 0;
+
+        // password and terminating zero byte
+        len += self.password.len() + size_of::<u8>();
+
+        // command and terminating zero byte
+        len += self.command.len() + size_of::<u8>();
+
+        len
+    }
+
+    fn write_content<W>(&self, writer: &mut W) -> Result<(), NetError>
+    where
+        W: WriteBytesExt,
+    {
+        writer.write(&util::quake_string_to_bytes(&self.password))?;
+        writer.write_u8(0)?;
+        writer.write(&util::quake_string_to_bytes(&self.command))?;
         writer.write_u8(0)?;
         Ok(())
     }
@@ -203,9 +271,86 @@ pub enum Request {
     ServerInfo(RequestServerInfo),
     PlayerInfo(RequestPlayerInfo),
     RuleInfo(RequestRuleInfo),
+    Rcon(RequestRcon),
 }
 
 impl Request {
+    /// Parse a request from a received packet.
+    pub fn from_bytes(packet: &[u8]) -> Result<Request, NetError> {
+        let len = packet.len();
+        let mut reader = BufReader::new(packet);
+
+        let control = reader.read_i32::<NetworkEndian>()?;
+
+        // TODO: figure out what a control value of -1 means
+        if control == -1 {
+            return Err(NetError::with_msg("Control value is -1"));
+        }
+
+        // high 4 bits must be 0x8000 (CONNECT_CONTROL)
+        if control & !CONNECT_LENGTH_MASK != CONNECT_CONTROL {
+            return Err(NetError::InvalidData(format!(
+                "control value {:X}",
+                control & !CONNECT_LENGTH_MASK
+            )));
+        }
+
+        // low 4 bits must be total length of packet
+        let control_len = (control & CONNECT_LENGTH_MASK) as usize;
+        if control_len != len {
+            return Err(NetError::InvalidData(format!(
+                "Actual packet length ({}) differs from header value ({})",
+                len, control_len,
+            )));
+        }
+
+        // validate request code
+        let request_byte = reader.read_u8()?;
+        let request_code = match RequestCode::from_u8(request_byte) {
+            Some(r) => r,
+            None => {
+                return Err(NetError::InvalidData(format!(
+                    "request code {}",
+                    request_byte
+                )))
+            }
+        };
+
+        let request = match request_code {
+            RequestCode::Connect => {
+                let game_name = util::read_cstring(&mut reader).unwrap();
+                let proto_ver = reader.read_u8()?;
+                Request::Connect(RequestConnect {
+                    game_name,
+                    proto_ver,
+                })
+            }
+
+            RequestCode::ServerInfo => {
+                let game_name = util::read_cstring(&mut reader).unwrap();
+                Request::ServerInfo(RequestServerInfo { game_name })
+            }
+
+            RequestCode::PlayerInfo => {
+                let player_id = reader.read_u8()?;
+                Request::PlayerInfo(RequestPlayerInfo { player_id })
+            }
+
+            RequestCode::RuleInfo => {
+                let prev_cvar = util::read_cstring(&mut reader).unwrap();
+                Request::RuleInfo(RequestRuleInfo { prev_cvar })
+            }
+
+            RequestCode::Rcon => {
+                let password = util::read_cstring(&mut reader).unwrap();
+                let command = util::read_cstring(&mut reader).unwrap();
+                Request::Rcon(RequestRcon { password, command })
+            }
+        };
+
+        Ok(request)
+    }
+
     pub fn connect<S>(game_name: S, proto_ver: u8) -> Request
     where
         S: AsRef<str>,
@@ -237,6 +382,17 @@ impl Request {
             prev_cvar: prev_cvar.as_ref().to_string(),
         })
     }
+
+    pub fn rcon<S, T>(password: S, command: T) -> Request
+    where
+        S: AsRef<str>,
+        T: AsRef<str>,
+    {
+        Request::Rcon(RequestRcon {
+            password: password.as_ref().to_owned(),
+            command: command.as_ref().to_owned(),
+        })
+    }
 }
 
 impl ConnectPacket for Request {
@@ -247,6 +403,7 @@ impl ConnectPacket for Request {
             ServerInfo(ref s) => s.code(),
             PlayerInfo(ref p) => p.code(),
             RuleInfo(ref r) => r.code(),
+            Rcon(ref r) => r.code(),
         }
     }
 
@@ -257,6 +414,7 @@ impl ConnectPacket for Request {
             ServerInfo(ref s) => s.content_len(),
             PlayerInfo(ref p) => p.content_len(),
             RuleInfo(ref r) => r.content_len(),
+            Rcon(ref r) => r.content_len(),
         }
     }
 
@@ -270,6 +428,7 @@ impl ConnectPacket for Request {
             ServerInfo(ref s) => s.write_content(writer),
             PlayerInfo(ref p) => p.write_content(writer),
             RuleInfo(ref r) => r.write_content(writer),
+            Rcon(ref r) => r.write_content(writer),
         }
     }
 }
@@ -281,9 +440,10 @@ pub enum ResponseCode {
     ServerInfo = 0x83,
     PlayerInfo = 0x84,
     RuleInfo = 0x85,
+    Rcon = 0x86,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct ResponseAccept {
     pub port: i32,
 }
@@ -307,7 +467,7 @@ impl ConnectPacket for ResponseAccept {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct ResponseReject {
     pub message: String,
 }
@@ -326,13 +486,13 @@ impl ConnectPacket for ResponseReject {
     where
         W: WriteBytesExt,
     {
-        writer.write(self.message.as_bytes())?;
+        writer.write(&util::quake_string_to_bytes(&self.message))?;
         writer.write_u8(0)?;
         Ok(())
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct ResponseServerInfo {
     pub address: String,
     pub hostname: String,
@@ -375,11 +535,11 @@ impl ConnectPacket for ResponseServerInfo {
     where
         W: WriteBytesExt,
     {
-        writer.write(self.address.as_bytes())?;
+        writer.write(&util::quake_string_to_bytes(&self.address))?;
         writer.write_u8(0)?;
-        writer.write(self.hostname.as_bytes())?;
+        writer.write(&util::quake_string_to_bytes(&self.hostname))?;
         writer.write_u8(0)?;
-        writer.write(self.levelname.as_bytes())?;
+        writer.write(&util::quake_string_to_bytes(&self.levelname))?;
         writer.write_u8(0)?;
         writer.write_u8(self.client_count)?;
         writer.write_u8(self.client_max)?;
@@ -388,7 +548,7 @@ impl ConnectPacket for ResponseServerInfo {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct ResponsePlayerInfo {
     pub player_id: u8,
     pub player_name: String,
@@ -432,29 +592,44 @@ impl ConnectPacket for ResponsePlayerInfo {
         W: WriteBytesExt,
     {
         writer.write_u8(self.player_id)?;
-        writer.write(self.player_name.as_bytes())?;
+        writer.write(&util::quake_string_to_bytes(&self.player_name))?;
         writer.write_u8(0)?; // NUL-terminate
         writer.write_i32::<LittleEndian>(self.colors)?;
         writer.write_i32::<LittleEndian>(self.frags)?;
         writer.write_i32::<LittleEndian>(self.connect_duration)?;
-        writer.write(self.address.as_bytes())?;
+        writer.write(&util::quake_string_to_bytes(&self.address))?;
         writer.write_u8(0)?;
         Ok(())
     }
 }
 
-#[derive(Debug)]
+/// A single server cvar.
+///
+/// A `ResponseRuleInfo` with an empty `cvar_name` marks the end of the list and
+/// is sent with no content.
+#[derive(Debug, PartialEq)]
 pub struct ResponseRuleInfo {
     pub cvar_name: String,
     pub cvar_val: String,
 }
 
+impl ResponseRuleInfo {
+    /// Returns `true` if this marks the end of the rule list.
+    pub fn is_end(&self) -> bool {
+        self.cvar_name.is_empty()
+    }
+}
+
 impl ConnectPacket for ResponseRuleInfo {
     fn code(&self) -> u8 {
         ResponseCode::RuleInfo as u8
     }
 
     fn content_len(&self) -> usize {
+        if self.is_end() {
+            return 0;
+        }
+
         let mut len = 0;
 
         // cvar name and terminating zero byte
@@ -470,21 +645,167 @@ impl ConnectPacket for ResponseRuleInfo {
     where
         W: WriteBytesExt,
     {
-        writer.write(self.cvar_name.as_bytes())?;
+        if self.is_end() {
+            return Ok(());
+        }
+
+        writer.write(&util::quake_string_to_bytes(&self.cvar_name))?;
         writer.write_u8(0)?;
-        writer.write(self.cvar_val.as_bytes())?;
+        writer.write(&util::quake_string_to_bytes(&self.cvar_val))?;
         writer.write_u8(0)?;
         Ok(())
     }
 }
 
-#[derive(Debug)]
+/// The console output of a remote console command.
+#[derive(Debug, PartialEq)]
+pub struct ResponseRcon {
+    pub output: String,
+}
+
+impl ConnectPacket for ResponseRcon {
+    fn code(&self) -> u8 {
+        ResponseCode::Rcon as u8
+    }
+
+    fn content_len(&self) -> usize {
+        // output and terminating zero byte
+        self.output.len() + size_of::<u8>()
+    }
+
+    fn write_content<W>(&self, writer: &mut W) -> Result<(), NetError>
+    where
+        W: WriteBytesExt,
+    {
+        writer.write(&util::quake_string_to_bytes(&self.output))?;
+        writer.write_u8(0)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub enum Response {
     Accept(ResponseAccept),
     Reject(ResponseReject),
     ServerInfo(ResponseServerInfo),
     PlayerInfo(ResponsePlayerInfo),
     RuleInfo(ResponseRuleInfo),
+    Rcon(ResponseRcon),
+}
+
+impl Response {
+    /// Parse a response from a received packet.
+    pub fn from_bytes(packet: &[u8]) -> Result<Response, NetError> {
+        let len = packet.len();
+        let mut reader = BufReader::new(packet);
+
+        let control = reader.read_i32::<NetworkEndian>()?;
+
+        // TODO: figure out what a control value of -1 means
+        if control == -1 {
+            return Err(NetError::with_msg("Control value is -1"));
+        }
+
+        // high 4 bits must be 0x8000 (CONNECT_CONTROL)
+        if control & !CONNECT_LENGTH_MASK != CONNECT_CONTROL {
+            return Err(NetError::InvalidData(format!(
+                "control value {:X}",
+                control & !CONNECT_LENGTH_MASK
+            )));
+        }
+
+        // low 4 bits must be total length of packet
+        let control_len = (control & CONNECT_LENGTH_MASK) as usize;
+        if control_len != len {
+            return Err(NetError::with_msg(format!(
+                "Actual packet length ({}) differs from header value ({})",
+                len, control_len,
+            )));
+        }
+
+        let response_byte = reader.read_u8()?;
+        let response_code = match ResponseCode::from_u8(response_byte) {
+            Some(r) => r,
+            None => {
+                return Err(NetError::InvalidData(format!(
+                    "response code {}",
+                    response_byte
+                )))
+            }
+        };
+
+        let response = match response_code {
+            ResponseCode::Accept => {
+                let port = reader.read_i32::<LittleEndian>()?;
+                Response::Accept(ResponseAccept { port })
+            }
+
+            ResponseCode::Reject => {
+                let message = util::read_cstring(&mut reader).unwrap();
+                Response::Reject(ResponseReject { message })
+            }
+
+            ResponseCode::ServerInfo => {
+                let address = util::read_cstring(&mut reader).unwrap();
+                let hostname = util::read_cstring(&mut reader).unwrap();
+                let levelname = util::read_cstring(&mut reader).unwrap();
+                let client_count = reader.read_u8()?;
+                let client_max = reader.read_u8()?;
+                let protocol_version = reader.read_u8()?;
+
+                Response::ServerInfo(ResponseServerInfo {
+                    address,
+                    hostname,
+                    levelname,
+                    client_count,
+                    client_max,
+                    protocol_version,
+                })
+            }
+
+            ResponseCode::PlayerInfo => {
+                let player_id = reader.read_u8()?;
+                let player_name = util::read_cstring(&mut reader).unwrap();
+                let colors = reader.read_i32::<LittleEndian>()?;
+                let frags = reader.read_i32::<LittleEndian>()?;
+                let connect_duration = reader.read_i32::<LittleEndian>()?;
+                let address = util::read_cstring(&mut reader).unwrap();
+
+                Response::PlayerInfo(ResponsePlayerInfo {
+                    player_id,
+                    player_name,
+                    colors,
+                    frags,
+                    connect_duration,
+                    address,
+                })
+            }
+
+            ResponseCode::RuleInfo => {
+                // the end of the rule list is marked by an empty response
+                if len == size_of::<i32>() + size_of::<u8>() {
+                    Response::RuleInfo(ResponseRuleInfo {
+                        cvar_name: String::new(),
+                        cvar_val: String::new(),
+                    })
+                } else {
+                    let cvar_name = util::read_cstring(&mut reader).unwrap();
+                    let cvar_val = util::read_cstring(&mut reader).unwrap();
+                    Response::RuleInfo(ResponseRuleInfo {
+                        cvar_name,
+                        cvar_val,
+                    })
+                }
+            }
+
+            ResponseCode::Rcon => {
+                let output = util::read_cstring(&mut reader).unwrap();
+                Response::Rcon(ResponseRcon { output })
+            }
+        };
+
+        Ok(response)
+    }
 }
 
 impl ConnectPacket for Response {
@@ -496,6 +817,7 @@ impl ConnectPacket for Response {
             ServerInfo(ref s) => s.code(),
             PlayerInfo(ref p) => p.code(),
             RuleInfo(ref r) => r.code(),
+            Rcon(ref r) => r.code(),
         }
     }
 
@@ -507,6 +829,7 @@ impl ConnectPacket for Response {
             ServerInfo(ref s) => s.content_len(),
             PlayerInfo(ref p) => p.content_len(),
             RuleInfo(ref r) => r.content_len(),
+            Rcon(ref r) => r.content_len(),
         }
     }
 
@@ -521,6 +844,7 @@ impl ConnectPacket for Response {
             ServerInfo(ref s) => s.write_content(writer),
             PlayerInfo(ref p) => p.write_content(writer),
             RuleInfo(ref r) => r.write_content(writer),
+            Rcon(ref r) => r.write_content(writer),
         }
     }
 }
@@ -541,75 +865,64 @@ impl ConnectListener {
         Ok(ConnectListener { socket })
     }
 
-    /// Receives a request and returns it along with its remote address.
-    pub fn recv_request(&self) -> Result<(Request, SocketAddr), NetError> {
-        // Original engine receives connection requests in `net_message`,
-        // allocated at https://github.com/id-Software/Quake/blob/master/WinQuake/net_main.c#L851
-        let mut recv_buf = [0u8; MAX_MESSAGE];
-        let (len, remote) = self.socket.recv_from(&mut recv_buf)?;
-        let mut reader = BufReader::new(&recv_buf[..len]);
-
-        let control = reader.read_i32::<NetworkEndian>()?;
+    /// Creates a `ConnectListener` which accepts both IPv4 and IPv6 clients
+    /// on the given port.
+    pub fn bind_dual_stack(port: u16) -> Result<ConnectListener, NetError> {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
 
-        // TODO: figure out what a control value of -1 means
-        if control == -1 {
-            return Err(NetError::with_msg("Control value is -1"));
-        }
+        // IPv4 clients appear as IPv4-mapped IPv6 addresses
+        socket.set_only_v6(false)?;
+        socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port).into())?;
 
-        // high 4 bits must be 0x8000 (CONNECT_CONTROL)
-        if control & !CONNECT_LENGTH_MASK != CONNECT_CONTROL {
-            return Err(NetError::InvalidData(format!(
-                "control value {:X}",
-                control & !CONNECT_LENGTH_MASK
-            )));
-        }
+        Ok(ConnectListener {
+            socket: socket.into(),
+        })
+    }
 
-        // low 4 bits must be total length of packet
-        let control_len = (control & CONNECT_LENGTH_MASK) as usize;
-        if control_len != len {
-            return Err(NetError::InvalidData(format!(
-                "Actual packet length ({}) differs from header value ({})",
-                len, control_len,
-            )));
-        }
+    /// Returns the local address this listener is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, NetError> {
+        Ok(self.socket.local_addr()?)
+    }
 
-        // validate request code
-        let request_byte = reader.read_u8()?;
-        let request_code = match RequestCode::from_u8(request_byte) {
-            Some(r) => r,
-            None => {
-                return Err(NetError::InvalidData(format!(
-                    "request code {}",
-                    request_byte
-                )))
-            }
-        };
+    /// Sets whether `recv_request` returns immediately when no request is
+    /// waiting.
+    ///
+    /// In nonblocking mode, an empty socket is reported as an I/O error of
+    /// kind `WouldBlock`.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), NetError> {
+        Ok(self.socket.set_nonblocking(nonblocking)?)
+    }
 
-        let request = match request_code {
-            RequestCode::Connect => {
-                let game_name = util::read_cstring(&mut reader).unwrap();
-                let proto_ver = reader.read_u8()?;
-                Request::Connect(RequestConnect {
-                    game_name,
-                    proto_ver,
-                })
-            }
+    /// Uses this listener's socket for a connection to `remote`.
+    ///
+    /// The server binds a new listener on an ephemeral port for each client it
+    /// accepts and tells the client to continue on that port.
+    pub fn into_qsocket(self, remote: SocketAddr) -> QSocket {
+        QSocket::new(self.socket, remote)
+    }
 
-            RequestCode::ServerInfo => {
-                let game_name = util::read_cstring(&mut reader).unwrap();
-                Request::ServerInfo(RequestServerInfo { game_name })
-            }
+    /// Returns the listener's socket, which other out-of-band messages (such
+    /// as master server heartbeats) share.
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
 
-            RequestCode::PlayerInfo => {
-                let player_id = reader.read_u8()?;
-                Request::PlayerInfo(RequestPlayerInfo { player_id })
-            }
+    /// Receives a packet without parsing it, returning its length and remote
+    /// address.
+    ///
+    /// Use `Request::from_bytes` to parse packets that aren't out-of-band
+    /// messages.
+    pub fn recv_packet(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), NetError> {
+        Ok(self.socket.recv_from(buf)?)
+    }
 
-            RequestCode::RuleInfo => {
-                let prev_cvar = util::read_cstring(&mut reader).unwrap();
-                Request::RuleInfo(RequestRuleInfo { prev_cvar })
-            }
-        };
+    /// Receives a request and returns it along with its remote address.
+    pub fn recv_request(&self) -> Result<(Request, SocketAddr), NetError> {
+        // Original engine receives connection requests in `net_message`,
+        // allocated at https://github.com/id-Software/Quake/blob/master/WinQuake/net_main.c#L851
+        let mut recv_buf = [0u8; MAX_MESSAGE];
+        let (len, remote) = self.socket.recv_from(&mut recv_buf)?;
+        let request = Request::from_bytes(&recv_buf[..len])?;
 
         Ok((request, remote))
     }
@@ -634,6 +947,27 @@ impl ConnectSocket {
         Ok(ConnectSocket { socket })
     }
 
+    /// Binds to an ephemeral port on the wildcard address of the same family
+    /// as `remote`.
+    pub fn bind_for(remote: &SocketAddr) -> Result<ConnectSocket, NetError> {
+        ConnectSocket::bind_for_port(remote, 0)
+    }
+
+    /// Binds to `port` on the wildcard address of the same family as
+    /// `remote`.
+    ///
+    /// A `port` of 0 selects an ephemeral port.
+    pub fn bind_for_port(remote: &SocketAddr, port: u16) -> Result<ConnectSocket, NetError> {
+        let mut local = net::unspecified_addr(remote);
+        local.set_port(port);
+        ConnectSocket::bind(local)
+    }
+
+    /// Returns the local address this socket is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, NetError> {
+        Ok(self.socket.local_addr()?)
+    }
+
     pub fn into_qsocket(self, remote: SocketAddr) -> QSocket {
         QSocket::new(self.socket, remote)
     }
@@ -644,6 +978,139 @@ impl ConnectSocket {
         Ok(())
     }
 
+    /// Send a `Request` to `remote` and wait for its reply.
+    ///
+    /// Responses from other addresses are ignored. Returns `None` if no reply
+    /// arrives within `timeout`.
+    fn query(
+        &mut self,
+        request: Request,
+        remote: SocketAddr,
+        timeout: Duration,
+    ) -> Result<Option<Response>, NetError> {
+        self.send_request(request, remote)?;
+
+        let deadline = Utc::now() + timeout;
+        loop {
+            let remaining = deadline - Utc::now();
+            if remaining <= Duration::zero() {
+                return Ok(None);
+            }
+
+            match self.recv_response(Some(remaining))? {
+                Some((response, addr)) if addr == remote => return Ok(Some(response)),
+                Some((_, addr)) => debug!("Ignoring response from {}", addr),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Ask the server at `remote` for its name, current map and player count
+    /// without connecting.
+    ///
+    /// Returns `None` if the server doesn't reply within `timeout`.
+    pub fn query_server_info(
+        &mut self,
+        remote: SocketAddr,
+        timeout: Duration,
+    ) -> Result<Option<ResponseServerInfo>, NetError> {
+        match self.query(Request::server_info(net::GAME_NAME), remote, timeout)? {
+            Some(Response::ServerInfo(info)) => Ok(Some(info)),
+            Some(r) => Err(NetError::InvalidData(format!(
+                "Expected server info, got {:?}",
+                r
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    /// Ask the server at `remote` for details of the player in slot
+    /// `player_id`.
+    ///
+    /// Servers don't reply for empty slots, so this returns `None` if there is
+    /// no such player or if the server doesn't reply within `timeout`.
+    pub fn query_player_info(
+        &mut self,
+        remote: SocketAddr,
+        player_id: u8,
+        timeout: Duration,
+    ) -> Result<Option<ResponsePlayerInfo>, NetError> {
+        match self.query(Request::player_info(player_id), remote, timeout)? {
+            Some(Response::PlayerInfo(info)) => Ok(Some(info)),
+            Some(r) => Err(NetError::InvalidData(format!(
+                "Expected player info, got {:?}",
+                r
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    /// Ask the server at `remote` for the names and values of its server
+    /// cvars.
+    ///
+    /// Rules are requested one at a time, each request naming the previous
+    /// cvar. If the server stops replying partway through, the rules received
+    /// so far are returned.
+    pub fn query_rules(
+        &mut self,
+        remote: SocketAddr,
+        timeout: Duration,
+    ) -> Result<Vec<ResponseRuleInfo>, NetError> {
+        let mut rules = Vec::new();
+        let mut prev_cvar = String::new();
+
+        loop {
+            match self.query(Request::rule_info(&prev_cvar), remote, timeout)? {
+                Some(Response::RuleInfo(rule)) => {
+                    if rule.is_end() {
+                        break;
+                    }
+
+                    prev_cvar = rule.cvar_name.clone();
+                    rules.push(rule);
+                }
+
+                Some(r) => {
+                    return Err(NetError::InvalidData(format!(
+                        "Expected rule info, got {:?}",
+                        r
+                    )))
+                }
+
+                None => break,
+            }
+        }
+
+        Ok(rules)
+    }
+
+    /// Execute `command` on the server at `remote` through its remote console
+    /// and return the console output.
+    ///
+    /// Servers ignore requests with the wrong password, so this returns `None`
+    /// if the password is wrong or if the server doesn't reply within
+    /// `timeout`.
+    pub fn send_rcon<S, T>(
+        &mut self,
+        remote: SocketAddr,
+        password: S,
+        command: T,
+        timeout: Duration,
+    ) -> Result<Option<String>, NetError>
+    where
+        S: AsRef<str>,
+        T: AsRef<str>,
+    {
+        match self.query(Request::rcon(password, command), remote, timeout)? {
+            Some(Response::Rcon(rcon)) => Ok(Some(rcon.output)),
+            Some(r) => Err(NetError::InvalidData(format!(
+                "Expected rcon output, got {:?}",
+                r
+            ))),
+            None => Ok(None),
+        }
+    }
+
     /// Receive a `Response` from the server.
     ///
     /// If `timeout` is not `None`, the operation times out after the specified duration and the
@@ -656,7 +1123,7 @@ impl ConnectSocket {
 
         // if a timeout was specified, apply it for this recv
         self.socket
-            .set_read_timeout(timeout.map(|d| d.to_std().unwrap()))?;
+            .set_read_timeout(timeout.map(net::read_timeout))?;
         let (len, remote) = match self.socket.recv_from(&mut recv_buf) {
             Err(e) => match e.kind() {
                 ErrorKind::WouldBlock | ErrorKind::TimedOut => return Ok(None),
@@ -666,75 +1133,7 @@ impl ConnectSocket {
         };
         self.socket.set_read_timeout(None)?;
 
-        let mut reader = BufReader::new(&recv_buf[..len]);
-
-        let control = reader.read_i32::<NetworkEndian>()?;
-
-        // TODO: figure out what a control value of -1 means
-        if control == -1 {
-            return Err(NetError::with_msg("Control value is -1"));
-        }
-
-        // high 4 bits must be 0x8000 (CONNECT_CONTROL)
-        if control & !CONNECT_LENGTH_MASK != CONNECT_CONTROL {
-            return Err(NetError::InvalidData(format!(
-                "control value {:X}",
-                control & !CONNECT_LENGTH_MASK
-            )));
-        }
-
-        // low 4 bits must be total length of packet
-        let control_len = (control & CONNECT_LENGTH_MASK) as usize;
-        if control_len != len {
-            return Err(NetError::with_msg(format!(
-                "Actual packet length ({}) differs from header value ({})",
-                len, control_len,
-            )));
-        }
-
-        let response_byte = reader.read_u8()?;
-        let response_code = match ResponseCode::from_u8(response_byte) {
-            Some(r) => r,
-            None => {
-                return Err(NetError::InvalidData(format!(
-                    "response code {}",
-                    response_byte
-                )))
-            }
-        };
-
-        let response = match response_code {
-            ResponseCode::Accept => {
-                let port = reader.read_i32::<LittleEndian>()?;
-                Response::Accept(ResponseAccept { port })
-            }
-
-            ResponseCode::Reject => {
-                let message = util::read_cstring(&mut reader).unwrap();
-                Response::Reject(ResponseReject { message })
-            }
-
-            ResponseCode::ServerInfo => {
-                let address = util::read_cstring(&mut reader).unwrap();
-                let hostname = util::read_cstring(&mut reader).unwrap();
-                let levelname = util::read_cstring(&mut reader).unwrap();
-                let client_count = reader.read_u8()?;
-                let client_max = reader.read_u8()?;
-                let protocol_version = reader.read_u8()?;
-
-                Response::ServerInfo(ResponseServerInfo {
-                    address,
-                    hostname,
-                    levelname,
-                    client_count,
-                    client_max,
-                    protocol_version,
-                })
-            }
-
-            ResponseCode::PlayerInfo => unimplemented!(),
-            ResponseCode::RuleInfo => unimplemented!(),
-        };
+        let response = Response::from_bytes(&recv_buf[..len])?;
 
         Ok(Some((response, remote)))
     }
@@ -836,6 +1235,146 @@ mod test {
         assert_eq!(packet_len, packet.len());
     }
 
+    #[test]
+    fn test_response_player_info_read_write_eq() {
+        let src = Response::PlayerInfo(ResponsePlayerInfo {
+            player_id: 3,
+            player_name: String::from("player"),
+            colors: 0x4d,
+            frags: -2,
+            connect_duration: 120,
+            address: String::from("127.0.0.1:26001"),
+        });
+        let packet = src.to_bytes().unwrap();
+        assert_eq!(src, Response::from_bytes(&packet).unwrap());
+    }
+
+    #[test]
+    fn test_response_rule_info_read_write_eq() {
+        let src = Response::RuleInfo(ResponseRuleInfo {
+            cvar_name: String::from("sv_gravity"),
+            cvar_val: String::from("800"),
+        });
+        let packet = src.to_bytes().unwrap();
+        assert_eq!(src, Response::from_bytes(&packet).unwrap());
+
+        let end = Response::RuleInfo(ResponseRuleInfo {
+            cvar_name: String::new(),
+            cvar_val: String::new(),
+        });
+        let packet = end.to_bytes().unwrap();
+        assert_eq!(packet.len(), end.packet_len() as usize);
+        assert_eq!(end, Response::from_bytes(&packet).unwrap());
+    }
+
+    #[test]
+    fn test_query_rules() {
+        let listener = ConnectListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let rules = [("fraglimit", "20"), ("sv_gravity", "800")];
+            loop {
+                let (request, remote) = listener.recv_request().unwrap();
+                let prev = match request {
+                    Request::RuleInfo(r) => r.prev_cvar,
+                    r => panic!("unexpected request {:?}", r),
+                };
+
+                let next = match prev.as_str() {
+                    "" => Some(0),
+                    p => rules.iter().position(|(n, _)| *n == p).map(|i| i + 1),
+                };
+                let (cvar_name, cvar_val) = next
+                    .and_then(|i| rules.get(i))
+                    .map(|(n, v)| (n.to_string(), v.to_string()))
+                    .unwrap_or_default();
+                let done = cvar_name.is_empty();
+
+                listener
+                    .send_response(
+                        Response::RuleInfo(ResponseRuleInfo {
+                            cvar_name,
+                            cvar_val,
+                        }),
+                        remote,
+                    )
+                    .unwrap();
+
+                if done {
+                    break;
+                }
+            }
+        });
+
+        let mut sock = ConnectSocket::bind("127.0.0.1:0").unwrap();
+        let rules = sock.query_rules(server_addr, Duration::seconds(5)).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].cvar_name, "fraglimit");
+        assert_eq!(rules[1].cvar_val, "800");
+    }
+
+    #[test]
+    fn test_request_rcon_authenticate() {
+        let request = RequestRcon {
+            password: String::from("hunter2"),
+            command: String::from("status"),
+        };
+
+        assert!(request.authenticate("hunter2"));
+        assert!(!request.authenticate("hunter3"));
+        assert!(!request.authenticate("hunter"));
+
+        // an empty password disables rcon
+        let request = RequestRcon {
+            password: String::new(),
+            command: String::from("status"),
+        };
+        assert!(!request.authenticate(""));
+    }
+
+    #[test]
+    fn test_send_rcon() {
+        let listener = ConnectListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let (request, remote) = listener.recv_request().unwrap();
+            let output = match request {
+                Request::Rcon(r) if r.authenticate("secret") => format!("ran {}", r.command),
+                r => panic!("unexpected request {:?}", r),
+            };
+
+            listener
+                .send_response(Response::Rcon(ResponseRcon { output }), remote)
+                .unwrap();
+        });
+
+        let mut sock = ConnectSocket::bind("127.0.0.1:0").unwrap();
+        let output = sock
+            .send_rcon(server_addr, "secret", "map e1m1", Duration::seconds(5))
+            .unwrap();
+        server.join().unwrap();
+
+        assert_eq!(output.as_deref(), Some("ran map e1m1"));
+    }
+
+    #[test]
+    fn test_connect_socket_bind_for_port() {
+        let remote = "127.0.0.1:26000".parse().unwrap();
+
+        // find a free port, then bind to it explicitly
+        let port = ConnectSocket::bind_for(&remote)
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let sock = ConnectSocket::bind_for_port(&remote, port).unwrap();
+        assert_eq!(sock.local_addr().unwrap().port(), port);
+    }
+
     #[test]
     fn test_connect_listener_bind() {
         let _listener = ConnectListener::bind("127.0.0.1:26000").unwrap();
@@ -0,0 +1,555 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Master server support.
+//!
+//! Servers announce themselves to a master server with periodic heartbeats,
+//! and clients ask the master for the list of servers it knows about. The
+//! packet formats are defined by a [`MasterProtocol`]; [`DpMaster`] implements
+//! the protocol used by dpmaster, which most NetQuake master servers run.
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+};
+
+use crate::common::net::{self, NetError, MAX_MESSAGE};
+
+use chrono::{Duration, Utc};
+use rand::{rngs::SmallRng, Rng as _, SeedableRng};
+
+pub const DEFAULT_MASTER_PORT: u16 = 27950;
+
+/// How often servers send heartbeats, in seconds.
+pub const HEARTBEAT_INTERVAL: i64 = 300;
+
+// prefix of connectionless packets in the dpmaster protocol
+const OOB_PREFIX: &[u8] = b"\xFF\xFF\xFF\xFF";
+
+// how long to wait on each socket when polling for status replies
+const POLL_INTERVAL_MS: u64 = 10;
+
+/// Server details reported to the master server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerStatus {
+    pub hostname: String,
+    pub map: String,
+    pub clients: u8,
+    pub max_clients: u8,
+}
+
+/// Part of a server list received from the master server.
+#[derive(Debug, PartialEq)]
+pub struct ServerListPart {
+    pub servers: Vec<SocketAddr>,
+
+    /// `true` if this was the last part of the list.
+    pub complete: bool,
+}
+
+/// The packet formats of a master server protocol.
+pub trait MasterProtocol {
+    /// Return the packet a server sends to announce itself.
+    fn heartbeat(&self) -> Vec<u8>;
+
+    /// If `packet` is a status query from the master server, return the reply.
+    fn status_reply(&self, packet: &[u8], status: &ServerStatus) -> Option<Vec<u8>>;
+
+    /// Return the packet a client sends to request the server list.
+    fn list_request(&self) -> Vec<u8>;
+
+    /// Parse a packet received in response to `list_request()`.
+    ///
+    /// Returns `None` if the packet isn't part of a server list.
+    fn parse_list(&self, packet: &[u8]) -> Result<Option<ServerListPart>, NetError>;
+
+    /// Return the packet a client sends to ask a server for its status.
+    fn status_request(&self, challenge: &str) -> Vec<u8>;
+
+    /// Parse a server's reply to `status_request(challenge)`.
+    ///
+    /// Returns `None` if the packet isn't a status reply or doesn't echo
+    /// `challenge`.
+    fn parse_status(&self, packet: &[u8], challenge: &str) -> Option<ServerStatus>;
+}
+
+/// The dpmaster protocol.
+pub struct DpMaster {
+    game_name: String,
+    protocol: u32,
+}
+
+impl DpMaster {
+    pub fn new<S>(game_name: S, protocol: u32) -> DpMaster
+    where
+        S: AsRef<str>,
+    {
+        DpMaster {
+            game_name: game_name.as_ref().to_owned(),
+            protocol,
+        }
+    }
+}
+
+impl Default for DpMaster {
+    /// Returns the settings used by NetQuake servers.
+    fn default() -> DpMaster {
+        DpMaster::new("DarkPlaces-Quake", 3)
+    }
+}
+
+// strip the connectionless prefix and the given command from a packet
+fn strip_command<'a>(packet: &'a [u8], command: &[u8]) -> Option<&'a [u8]> {
+    if packet.starts_with(OOB_PREFIX) && packet[OOB_PREFIX.len()..].starts_with(command) {
+        Some(&packet[OOB_PREFIX.len() + command.len()..])
+    } else {
+        None
+    }
+}
+
+impl MasterProtocol for DpMaster {
+    fn heartbeat(&self) -> Vec<u8> {
+        let mut packet = OOB_PREFIX.to_vec();
+        packet.extend_from_slice(b"heartbeat DarkPlaces\n");
+        packet
+    }
+
+    fn status_reply(&self, packet: &[u8], status: &ServerStatus) -> Option<Vec<u8>> {
+        let challenge = strip_command(packet, b"getinfo")?;
+        let challenge = String::from_utf8_lossy(challenge).trim().to_owned();
+
+        // backslashes delimit keys and values, so they can't appear in either
+        let clean = |s: &str| s.replace('\\', "");
+
+        let mut reply = OOB_PREFIX.to_vec();
+        reply.extend_from_slice(b"infoResponse\n");
+        let info = format!(
+            "\\gamename\\{}\\protocol\\{}\\clients\\{}\\sv_maxclients\\{}\\hostname\\{}\\mapname\\{}\\challenge\\{}",
+            self.game_name,
+            self.protocol,
+            status.clients,
+            status.max_clients,
+            clean(&status.hostname),
+            clean(&status.map),
+            clean(&challenge),
+        );
+        reply.extend_from_slice(info.as_bytes());
+
+        Some(reply)
+    }
+
+    fn list_request(&self) -> Vec<u8> {
+        let mut packet = OOB_PREFIX.to_vec();
+        packet.extend_from_slice(
+            format!("getservers {} {} empty full", self.game_name, self.protocol).as_bytes(),
+        );
+        packet
+    }
+
+    fn parse_list(&self, packet: &[u8]) -> Result<Option<ServerListPart>, NetError> {
+        let mut entries = match strip_command(packet, b"getserversResponse")
+            .or_else(|| strip_command(packet, b"getserversExtResponse"))
+        {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+
+        let mut servers = Vec::new();
+        let mut complete = false;
+        while !entries.is_empty() {
+            if entries.starts_with(b"\\EOT") {
+                complete = true;
+                break;
+            }
+
+            let (ip, len): (IpAddr, usize) = match entries[0] {
+                b'\\' if entries.len() >= 7 => {
+                    let mut octets = [0; 4];
+                    octets.copy_from_slice(&entries[1..5]);
+                    (Ipv4Addr::from(octets).into(), 4)
+                }
+
+                b'/' if entries.len() >= 19 => {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(&entries[1..17]);
+                    (Ipv6Addr::from(octets).into(), 16)
+                }
+
+                _ => {
+                    return Err(NetError::InvalidData(format!(
+                        "Malformed server list entry ({} bytes left)",
+                        entries.len()
+                    )))
+                }
+            };
+
+            let port = u16::from_be_bytes([entries[1 + len], entries[2 + len]]);
+            entries = &entries[3 + len..];
+
+            // dpmaster pads lists with zero entries
+            if port != 0 && !ip.is_unspecified() {
+                servers.push(SocketAddr::new(ip, port));
+            }
+        }
+
+        Ok(Some(ServerListPart { servers, complete }))
+    }
+
+    fn status_request(&self, challenge: &str) -> Vec<u8> {
+        let mut packet = OOB_PREFIX.to_vec();
+        packet.extend_from_slice(format!("getinfo {}", challenge).as_bytes());
+        packet
+    }
+
+    fn parse_status(&self, packet: &[u8], challenge: &str) -> Option<ServerStatus> {
+        let info = strip_command(packet, b"infoResponse\n")?;
+        let info = String::from_utf8_lossy(info);
+
+        // the info string is a sequence of \key\value pairs
+        let mut fields = info.split('\\').skip(1);
+        let mut status = ServerStatus {
+            hostname: String::new(),
+            map: String::new(),
+            clients: 0,
+            max_clients: 0,
+        };
+        let mut echoed = false;
+        while let (Some(key), Some(value)) = (fields.next(), fields.next()) {
+            match key {
+                "challenge" => echoed = value == challenge,
+                "hostname" => status.hostname = value.to_owned(),
+                "mapname" => status.map = value.to_owned(),
+                "clients" => status.clients = value.parse().unwrap_or(0),
+                "sv_maxclients" => status.max_clients = value.parse().unwrap_or(0),
+                _ => (),
+            }
+        }
+
+        // anyone can send an infoResponse, so only trust replies to our query
+        match echoed {
+            true => Some(status),
+            false => None,
+        }
+    }
+}
+
+/// Sends heartbeats from a server to a master server.
+pub struct Heartbeat<P>
+where
+    P: MasterProtocol,
+{
+    protocol: P,
+    master: SocketAddr,
+    interval: Duration,
+
+    // time until the next heartbeat is due
+    remaining: Duration,
+}
+
+impl<P> Heartbeat<P>
+where
+    P: MasterProtocol,
+{
+    /// Create a heartbeat for the master server at `master`.
+    ///
+    /// If `master` has no port, the default master port is used. The first
+    /// heartbeat is sent on the next call to `frame()`.
+    pub fn new<S>(protocol: P, master: S) -> Result<Heartbeat<P>, NetError>
+    where
+        S: AsRef<str>,
+    {
+        Ok(Heartbeat {
+            protocol,
+            master: net::resolve_addr(master, DEFAULT_MASTER_PORT)?,
+            interval: Duration::seconds(HEARTBEAT_INTERVAL),
+            remaining: Duration::zero(),
+        })
+    }
+
+    pub fn master(&self) -> SocketAddr {
+        self.master
+    }
+
+    /// Send a heartbeat on `socket` if one is due.
+    pub fn frame(&mut self, socket: &UdpSocket, frame_time: Duration) -> Result<(), NetError> {
+        self.remaining = self.remaining - frame_time;
+        if self.remaining > Duration::zero() {
+            return Ok(());
+        }
+
+        debug!("Sending heartbeat to {}", self.master);
+        let master = net::addr_for_socket(socket, self.master);
+        socket.send_to(&self.protocol.heartbeat(), master)?;
+        self.remaining = self.interval;
+
+        Ok(())
+    }
+
+    /// Answer `packet` if it is a status query from the master server.
+    ///
+    /// Returns `true` if the packet was handled.
+    pub fn handle_packet(
+        &self,
+        socket: &UdpSocket,
+        packet: &[u8],
+        remote: SocketAddr,
+        status: &ServerStatus,
+    ) -> Result<bool, NetError> {
+        if net::unmap_addr(remote) != net::unmap_addr(self.master) {
+            return Ok(false);
+        }
+
+        match self.protocol.status_reply(packet, status) {
+            Some(reply) => {
+                socket.send_to(&reply, remote)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Fetch the list of servers known to the master server at `master`.
+///
+/// Collects server addresses until the master marks the end of the list or
+/// `timeout` elapses.
+pub fn fetch_server_list<P>(
+    protocol: &P,
+    master: SocketAddr,
+    timeout: Duration,
+) -> Result<Vec<SocketAddr>, NetError>
+where
+    P: MasterProtocol,
+{
+    let socket = UdpSocket::bind(net::unspecified_addr(&master))?;
+    socket.send_to(&protocol.list_request(), master)?;
+
+    let deadline = Utc::now() + timeout;
+    let mut servers = Vec::new();
+    let mut recv_buf = [0u8; MAX_MESSAGE];
+    loop {
+        let remaining = deadline - Utc::now();
+        if remaining <= Duration::zero() {
+            break;
+        }
+
+        socket.set_read_timeout(Some(net::read_timeout(remaining)))?;
+        let (len, remote) = match socket.recv_from(&mut recv_buf) {
+            Ok(r) => r,
+            Err(e) => match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => break,
+                _ => return Err(NetError::from(e)),
+            },
+        };
+
+        if remote != master {
+            continue;
+        }
+
+        if let Some(part) = protocol.parse_list(&recv_buf[..len])? {
+            for server in part.servers {
+                if !servers.contains(&server) {
+                    servers.push(server);
+                }
+            }
+
+            if part.complete {
+                break;
+            }
+        }
+    }
+
+    Ok(servers)
+}
+
+/// Ask each of `servers` for its status.
+///
+/// Returns the status and round-trip time of every server that replied
+/// before `timeout` elapsed, in the order the replies arrived.
+pub fn query_status<P>(
+    protocol: &P,
+    servers: &[SocketAddr],
+    timeout: Duration,
+) -> Result<Vec<(SocketAddr, ServerStatus, Duration)>, NetError>
+where
+    P: MasterProtocol,
+{
+    // a fresh challenge for each refresh, so stale or forged replies don't match
+    let challenge = format!("{:08x}", SmallRng::from_entropy().gen::<u32>());
+
+    // one socket per address family
+    let mut sockets: Vec<UdpSocket> = Vec::new();
+    let mut pending = HashMap::new();
+    for server in servers {
+        let same_family = |s: &UdpSocket| {
+            s.local_addr()
+                .map(|a| a.is_ipv4() == server.is_ipv4())
+                .unwrap_or(false)
+        };
+        let socket = match sockets.iter().position(same_family) {
+            Some(i) => &sockets[i],
+            None => {
+                sockets.push(UdpSocket::bind(net::unspecified_addr(server))?);
+                sockets.last().unwrap()
+            }
+        };
+
+        match socket.send_to(&protocol.status_request(&challenge), server) {
+            Ok(_) => {
+                pending.insert(*server, Utc::now());
+            }
+            Err(e) => debug!("Couldn't query {}: {}", server, e),
+        }
+    }
+
+    let poll_interval = std::time::Duration::from_millis(POLL_INTERVAL_MS);
+    for socket in sockets.iter() {
+        socket.set_read_timeout(Some(poll_interval))?;
+    }
+
+    let deadline = Utc::now() + timeout;
+    let mut replies = Vec::new();
+    let mut recv_buf = [0u8; MAX_MESSAGE];
+    while !pending.is_empty() && Utc::now() < deadline {
+        for socket in sockets.iter() {
+            let (len, remote) = match socket.recv_from(&mut recv_buf) {
+                Ok(r) => r,
+                Err(e) => match e.kind() {
+                    // unreachable servers can show up as connection errors
+                    ErrorKind::WouldBlock
+                    | ErrorKind::TimedOut
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionRefused => continue,
+                    _ => return Err(NetError::from(e)),
+                },
+            };
+
+            if let Some(status) = protocol.parse_status(&recv_buf[..len], &challenge) {
+                if let Some(sent) = pending.remove(&remote) {
+                    replies.push((remote, status, Utc::now() - sent));
+                }
+            }
+        }
+    }
+
+    Ok(replies)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dpmaster_parse_list() {
+        let mut packet = b"\xFF\xFF\xFF\xFFgetserversResponse".to_vec();
+        packet.extend_from_slice(b"\\\x7f\x00\x00\x01\x65\x90");
+        packet.extend_from_slice(b"\\\x0a\x00\x00\x02\x65\x91");
+        packet.extend_from_slice(b"\\EOT\x00\x00\x00");
+
+        let part = DpMaster::default().parse_list(&packet).unwrap().unwrap();
+        assert!(part.complete);
+        assert_eq!(
+            part.servers,
+            vec![
+                "127.0.0.1:26000".parse().unwrap(),
+                "10.0.0.2:26001".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dpmaster_parse_list_ipv6() {
+        let mut packet = b"\xFF\xFF\xFF\xFFgetserversExtResponse/".to_vec();
+        packet.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        packet.extend_from_slice(&26000u16.to_be_bytes());
+
+        let part = DpMaster::default().parse_list(&packet).unwrap().unwrap();
+        assert!(!part.complete);
+        assert_eq!(part.servers, vec!["[::1]:26000".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_dpmaster_ignores_other_packets() {
+        let packet = b"\xFF\xFF\xFF\xFFgetinfo abc";
+        assert!(DpMaster::default().parse_list(packet).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_dpmaster_status_reply_echoes_challenge() {
+        let status = ServerStatus {
+            hostname: String::from("my\\server"),
+            map: String::from("e1m1"),
+            clients: 2,
+            max_clients: 8,
+        };
+
+        let reply = DpMaster::default()
+            .status_reply(b"\xFF\xFF\xFF\xFFgetinfo abc123", &status)
+            .unwrap();
+        let reply = String::from_utf8_lossy(&reply[OOB_PREFIX.len()..]).into_owned();
+        assert!(reply.starts_with("infoResponse\n"));
+        assert!(reply.contains("\\hostname\\myserver\\"));
+        assert!(reply.contains("\\mapname\\e1m1\\"));
+        assert!(reply.ends_with("\\challenge\\abc123"));
+    }
+
+    #[test]
+    fn test_dpmaster_parse_status() {
+        let status = ServerStatus {
+            hostname: String::from("my server"),
+            map: String::from("dm4"),
+            clients: 3,
+            max_clients: 16,
+        };
+
+        let dp = DpMaster::default();
+        let reply = dp
+            .status_reply(&dp.status_request("abc123"), &status)
+            .unwrap();
+        assert_eq!(dp.parse_status(&reply, "abc123"), Some(status));
+        assert_eq!(dp.parse_status(&reply, "xyz789"), None);
+        assert_eq!(
+            dp.parse_status(&dp.status_request("abc123"), "abc123"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_fetch_server_list() {
+        let master = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let master_addr = master.local_addr().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let mut buf = [0u8; MAX_MESSAGE];
+            let (len, remote) = master.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], &DpMaster::default().list_request()[..]);
+
+            let mut reply = b"\xFF\xFF\xFF\xFFgetserversResponse".to_vec();
+            reply.extend_from_slice(b"\\\x7f\x00\x00\x01\x65\x90\\EOT\x00\x00\x00");
+            master.send_to(&reply, remote).unwrap();
+        });
+
+        let servers =
+            fetch_server_list(&DpMaster::default(), master_addr, Duration::seconds(5)).unwrap();
+        server.join().unwrap();
+        assert_eq!(servers, vec!["127.0.0.1:26000".parse().unwrap()]);
+    }
+}
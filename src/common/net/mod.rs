@@ -20,29 +20,53 @@
 
 // TODO: need to figure out an equivalence relation for read_/write_coord and read_/write_angle
 
+#[cfg(feature = "async-net")]
+pub mod asynchronous;
 pub mod connect;
+pub mod master;
+pub mod portmap;
+pub mod qw;
+pub mod sim;
+pub mod stats;
 
 use std::{
     collections::VecDeque,
     error::Error,
     fmt,
     io::{BufRead, BufReader, Cursor, Read, Write},
-    net::{SocketAddr, UdpSocket},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
 };
 
-use crate::common::{engine, util};
+use crate::common::{
+    engine,
+    net::{
+        sim::{NetSim, NetSimVars},
+        stats::{LatencyGraph, NetStats},
+    },
+    util,
+};
 
 use byteorder::{LittleEndian, NetworkEndian, ReadBytesExt, WriteBytesExt};
 use cgmath::{Deg, Vector3, Zero};
-use chrono::Duration;
+use chrono::{DateTime, Duration, Utc};
 use num::FromPrimitive;
 
 pub const MAX_MESSAGE: usize = 8192;
-const MAX_DATAGRAM: usize = 1024;
+
+/// The largest reliable message that will be reassembled from its packets.
+///
+/// This matches FitzQuake, whose signon messages can be much larger than
+/// `MAX_MESSAGE`.
+pub const MAX_NET_MESSAGE: usize = 64000;
+
+/// The longest unreliable message a client can receive.
+pub const MAX_DATAGRAM: usize = 1024;
 const HEADER_SIZE: usize = 8;
 const MAX_PACKET: usize = HEADER_SIZE + MAX_DATAGRAM;
 
 pub const PROTOCOL_VERSION: u8 = 15;
+pub const PROTOCOL_FITZQUAKE: i32 = 666;
+pub const DEFAULT_PORT: u16 = 26000;
 
 const NAME_LEN: usize = 64;
 
@@ -63,10 +87,51 @@ pub const MAX_ITEMS: usize = 32;
 
 pub const DEFAULT_VIEWHEIGHT: f32 = 22.0;
 
+/// The network protocol spoken by a server.
+///
+/// The protocol is selected when the server sends `ServerInfo`. The FitzQuake
+/// protocol extends the original with wider model, frame and sound indices,
+/// while QuakeWorld servers use a different protocol altogether (see
+/// [`qw`](self::qw)).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Protocol {
+    NetQuake,
+    FitzQuake,
+    QuakeWorld,
+}
+
+impl Protocol {
+    /// Returns the protocol with the given version number, if it is known.
+    pub fn from_version(version: i32) -> Option<Protocol> {
+        match version {
+            v if v == PROTOCOL_VERSION as i32 => Some(Protocol::NetQuake),
+            PROTOCOL_FITZQUAKE => Some(Protocol::FitzQuake),
+            qw::PROTOCOL_VERSION => Some(Protocol::QuakeWorld),
+            _ => None,
+        }
+    }
+
+    pub fn version(&self) -> i32 {
+        match *self {
+            Protocol::NetQuake => PROTOCOL_VERSION as i32,
+            Protocol::FitzQuake => PROTOCOL_FITZQUAKE,
+            Protocol::QuakeWorld => qw::PROTOCOL_VERSION,
+        }
+    }
+
+    /// Returns `true` if this protocol supports FitzQuake's extended limits.
+    fn extended(&self) -> bool {
+        *self == Protocol::FitzQuake
+    }
+}
+
 #[derive(Debug)]
 pub enum NetError {
     Io(::std::io::Error),
     InvalidData(String),
+    /// A reliable message grew past `MAX_NET_MESSAGE`. Holds the length it
+    /// would have had.
+    MessageTooLong(usize),
     Other(String),
 }
 
@@ -79,6 +144,83 @@ impl NetError {
     }
 }
 
+/// Resolves a server address given by the user.
+///
+/// Accepts IPv4 and IPv6 addresses and hostnames, with or without a port.
+/// IPv6 addresses with a port must be enclosed in brackets (`[::1]:26000`).
+/// If no port is given, `default_port` is used.
+pub fn resolve_addr<S>(addr: S, default_port: u16) -> Result<SocketAddr, NetError>
+where
+    S: AsRef<str>,
+{
+    let addr = addr.as_ref();
+
+    if let Ok(a) = addr.parse::<SocketAddr>() {
+        return Ok(a);
+    }
+
+    // bare IP address, possibly a bracketed IPv6 address
+    let unbracketed = addr.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, default_port));
+    }
+
+    // hostname, with or without a port
+    let resolved = match addr.contains(':') {
+        true => addr.to_socket_addrs(),
+        false => (addr, default_port).to_socket_addrs(),
+    };
+
+    resolved
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| NetError::with_msg(format!("Couldn't resolve address: {}", addr)))
+}
+
+/// Returns the wildcard address of the same family as `addr`, with port 0.
+pub fn unspecified_addr(addr: &SocketAddr) -> SocketAddr {
+    match *addr {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    }
+}
+
+/// Converts `timeout` to a socket read timeout.
+///
+/// Sockets reject a zero timeout, so anything shorter than a millisecond,
+/// including a deadline that has already passed, is rounded up to that.
+pub fn read_timeout(timeout: Duration) -> std::time::Duration {
+    timeout
+        .to_std()
+        .unwrap_or_default()
+        .max(std::time::Duration::from_millis(1))
+}
+
+/// Returns the IPv4 address behind an IPv4-mapped IPv6 address, as reported
+/// by dual-stack sockets. Other addresses are returned unchanged.
+pub fn unmap_addr(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(v6) = addr {
+        if let [0, 0, 0, 0, 0, 0xFFFF, hi, lo] = v6.ip().segments() {
+            let ip = Ipv4Addr::new((hi >> 8) as u8, hi as u8, (lo >> 8) as u8, lo as u8);
+            return SocketAddr::new(IpAddr::V4(ip), v6.port());
+        }
+    }
+
+    addr
+}
+
+/// Returns the address `socket` has to send to in order to reach `addr`.
+///
+/// IPv6 sockets reach IPv4 hosts through IPv4-mapped addresses.
+pub fn addr_for_socket(socket: &UdpSocket, addr: SocketAddr) -> SocketAddr {
+    match (socket.local_addr(), addr) {
+        (Ok(SocketAddr::V6(_)), SocketAddr::V4(v4)) => {
+            SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+        }
+        _ => addr,
+    }
+}
+
 impl fmt::Display for NetError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -87,6 +229,11 @@ impl fmt::Display for NetError {
                 err.fmt(f)
             }
             NetError::InvalidData(ref msg) => write!(f, "Invalid data: {}", msg),
+            NetError::MessageTooLong(len) => write!(
+                f,
+                "Reliable message too long ({} bytes, max {})",
+                len, MAX_NET_MESSAGE
+            ),
             NetError::Other(ref msg) => write!(f, "{}", msg),
         }
     }
@@ -97,6 +244,7 @@ impl Error for NetError {
         match *self {
             NetError::Io(ref err) => err.description(),
             NetError::InvalidData(_) => "Invalid data",
+            NetError::MessageTooLong(_) => "Reliable message too long",
             NetError::Other(ref msg) => &msg,
         }
     }
@@ -120,7 +268,7 @@ pub enum MsgKind {
 }
 
 bitflags! {
-    pub struct UpdateFlags: u16 {
+    pub struct UpdateFlags: u32 {
         const MORE_BITS = 1 << 0;
         const ORIGIN_X = 1 << 1;
         const ORIGIN_Y = 1 << 2;
@@ -136,11 +284,19 @@ bitflags! {
         const SKIN = 1 << 12;
         const EFFECTS = 1 << 13;
         const LONG_ENTITY = 1 << 14;
+
+        // FitzQuake extensions
+        const EXTEND_1 = 1 << 15;
+        const ALPHA = 1 << 16;
+        const FRAME_2 = 1 << 17;
+        const MODEL_2 = 1 << 18;
+        const LERP_FINISH = 1 << 19;
+        const EXTEND_2 = 1 << 23;
     }
 }
 
 bitflags! {
-    pub struct ClientUpdateFlags: u16 {
+    pub struct ClientUpdateFlags: u32 {
         const VIEW_HEIGHT = 1 << 0;
         const IDEAL_PITCH = 1 << 1;
         const PUNCH_PITCH = 1 << 2;
@@ -156,6 +312,19 @@ bitflags! {
         const WEAPON_FRAME = 1 << 12;
         const ARMOR = 1 << 13;
         const WEAPON = 1 << 14;
+
+        // FitzQuake extensions
+        const EXTEND_1 = 1 << 15;
+        const WEAPON_2 = 1 << 16;
+        const ARMOR_2 = 1 << 17;
+        const AMMO_2 = 1 << 18;
+        const SHELLS_2 = 1 << 19;
+        const NAILS_2 = 1 << 20;
+        const ROCKETS_2 = 1 << 21;
+        const CELLS_2 = 1 << 22;
+        const EXTEND_2 = 1 << 23;
+        const WEAPON_FRAME_2 = 1 << 24;
+        const WEAPON_ALPHA = 1 << 25;
     }
 }
 
@@ -164,6 +333,10 @@ bitflags! {
         const VOLUME = 1 << 0;
         const ATTENUATION = 1 << 1;
         const LOOPING = 1 << 2;
+
+        // FitzQuake extensions
+        const LARGE_ENTITY = 1 << 3;
+        const LARGE_SOUND = 1 << 4;
     }
 }
 
@@ -235,6 +408,16 @@ impl PlayerColor {
     pub fn bits(&self) -> u8 {
         self.top << 4 | (self.bottom & 0x0F)
     }
+
+    /// Returns the shirt color.
+    pub fn top(&self) -> u8 {
+        self.top
+    }
+
+    /// Returns the pants color.
+    pub fn bottom(&self) -> u8 {
+        self.bottom
+    }
 }
 
 impl ::std::convert::From<u8> for PlayerColor {
@@ -439,21 +622,20 @@ impl TempEntity {
                             _ => unreachable!(),
                         };
 
-                        // write code
                         writer.write_u8(code as u8)?;
+                        write_coord_vector3(writer, origin)?;
                     }
                     PointEntityKind::ColorExplosion {
                         color_start,
                         color_len,
                     } => {
-                        // write code and colors
+                        // colors follow the origin
                         writer.write_u8(Code::ColorExplosion as u8)?;
+                        write_coord_vector3(writer, origin)?;
                         writer.write_u8(color_start)?;
                         writer.write_u8(color_len)?;
                     }
                 };
-
-                write_coord_vector3(writer, origin)?;
             }
 
             TempEntity::Beam {
@@ -467,13 +649,17 @@ impl TempEntity {
                         1 => Code::Lightning1,
                         2 => Code::Lightning2,
                         3 => Code::Lightning3,
-                        // TODO: error
-                        _ => panic!("invalid lightning model id: {}", model_id),
+                        _ => {
+                            return Err(NetError::InvalidData(format!(
+                                "Invalid lightning model id: {}",
+                                model_id
+                            )))
+                        }
                     },
                     BeamEntityKind::Grapple => Code::Grapple,
                 };
-                writer.write_i16::<LittleEndian>(entity_id)?;
                 writer.write_u8(code as u8)?;
+                writer.write_i16::<LittleEndian>(entity_id)?;
                 write_coord_vector3(writer, start)?;
                 write_coord_vector3(writer, end)?;
             }
@@ -501,7 +687,7 @@ bitflags! {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct EntityState {
     pub origin: Vector3<f32>,
     pub angles: Vector3<Deg<f32>>,
@@ -531,8 +717,8 @@ impl EntityState {
 #[derive(Clone, Debug, PartialEq)]
 pub struct EntityUpdate {
     pub ent_id: u16,
-    pub model_id: Option<u8>,
-    pub frame_id: Option<u8>,
+    pub model_id: Option<u16>,
+    pub frame_id: Option<u16>,
     pub colormap: Option<u8>,
     pub skin_id: Option<u8>,
     pub effects: Option<EntityEffects>,
@@ -558,19 +744,178 @@ pub struct PlayerData {
     pub items: ItemFlags,
     pub on_ground: bool,
     pub in_water: bool,
-    pub weapon_frame: Option<u8>,
-    pub armor: Option<u8>,
-    pub weapon: Option<u8>,
+    pub weapon_frame: Option<u16>,
+    pub armor: Option<u16>,
+    pub weapon: Option<u16>,
     pub health: i16,
-    pub ammo: u8,
-    pub ammo_shells: u8,
-    pub ammo_nails: u8,
-    pub ammo_rockets: u8,
-    pub ammo_cells: u8,
+    pub ammo: u16,
+    pub ammo_shells: u16,
+    pub ammo_nails: u16,
+    pub ammo_rockets: u16,
+    pub ammo_cells: u16,
     pub active_weapon: u8,
 }
 
+/// The most bytes a `FastUpdate` can take up, with every field present.
+pub const MAX_ENTITY_UPDATE_LEN: usize = 21;
+
 impl EntityUpdate {
+    /// Create an update for entity `ent_id` containing only the fields of
+    /// `state` which differ from `baseline`.
+    pub fn between(ent_id: u16, baseline: &EntityState, state: &EntityState) -> EntityUpdate {
+        fn changed<T: PartialEq>(old: T, new: T) -> Option<T> {
+            match old == new {
+                true => None,
+                false => Some(new),
+            }
+        }
+
+        EntityUpdate {
+            ent_id,
+            model_id: changed(baseline.model_id, state.model_id).map(|m| m as u16),
+            frame_id: changed(baseline.frame_id, state.frame_id).map(|f| f as u16),
+            colormap: changed(baseline.colormap, state.colormap),
+            skin_id: changed(baseline.skin_id, state.skin_id).map(|s| s as u8),
+            effects: changed(baseline.effects, state.effects),
+            origin_x: changed(baseline.origin.x, state.origin.x),
+            pitch: changed(baseline.angles.x, state.angles.x),
+            origin_y: changed(baseline.origin.y, state.origin.y),
+            yaw: changed(baseline.angles.y, state.angles.y),
+            origin_z: changed(baseline.origin.z, state.origin.z),
+            roll: changed(baseline.angles.z, state.angles.z),
+            no_lerp: false,
+        }
+    }
+
+    /// Returns the update flags describing which fields are present.
+    pub fn flags(&self) -> UpdateFlags {
+        let mut flags = UpdateFlags::SIGNAL;
+
+        if self.ent_id > 0xFF {
+            flags |= UpdateFlags::LONG_ENTITY;
+        }
+        if self.model_id.is_some() {
+            flags |= UpdateFlags::MODEL;
+        }
+        if self.frame_id.is_some() {
+            flags |= UpdateFlags::FRAME;
+        }
+        if self.colormap.is_some() {
+            flags |= UpdateFlags::COLORMAP;
+        }
+        if self.skin_id.is_some() {
+            flags |= UpdateFlags::SKIN;
+        }
+        if self.effects.is_some() {
+            flags |= UpdateFlags::EFFECTS;
+        }
+        if self.origin_x.is_some() {
+            flags |= UpdateFlags::ORIGIN_X;
+        }
+        if self.pitch.is_some() {
+            flags |= UpdateFlags::PITCH;
+        }
+        if self.origin_y.is_some() {
+            flags |= UpdateFlags::ORIGIN_Y;
+        }
+        if self.yaw.is_some() {
+            flags |= UpdateFlags::YAW;
+        }
+        if self.origin_z.is_some() {
+            flags |= UpdateFlags::ORIGIN_Z;
+        }
+        if self.roll.is_some() {
+            flags |= UpdateFlags::ROLL;
+        }
+        if self.no_lerp {
+            flags |= UpdateFlags::NO_LERP;
+        }
+
+        // ids that don't fit in a byte have their high bytes sent separately,
+        // as in FitzQuake
+        if self.model_id.unwrap_or(0) > 0xFF {
+            flags |= UpdateFlags::MODEL_2;
+        }
+        if self.frame_id.unwrap_or(0) > 0xFF {
+            flags |= UpdateFlags::FRAME_2;
+        }
+        if flags.bits() >> 16 != 0 {
+            flags |= UpdateFlags::EXTEND_1;
+        }
+
+        if flags.bits() & !0xFF != 0 {
+            flags |= UpdateFlags::MORE_BITS;
+        }
+
+        flags
+    }
+
+    /// Writes this update as a fast update message.
+    ///
+    /// Fast updates have no command code; instead, the first byte holds the
+    /// low bits of the update flags with the high bit set.
+    pub fn serialize<W>(&self, writer: &mut W) -> Result<(), NetError>
+    where
+        W: WriteBytesExt,
+    {
+        let flags = self.flags();
+        writer.write_u8(flags.bits() as u8)?;
+        if flags.contains(UpdateFlags::MORE_BITS) {
+            writer.write_u8((flags.bits() >> 8) as u8)?;
+        }
+        if flags.contains(UpdateFlags::EXTEND_1) {
+            writer.write_u8((flags.bits() >> 16) as u8)?;
+        }
+
+        if flags.contains(UpdateFlags::LONG_ENTITY) {
+            writer.write_u16::<LittleEndian>(self.ent_id)?;
+        } else {
+            writer.write_u8(self.ent_id as u8)?;
+        }
+
+        if let Some(m) = self.model_id {
+            writer.write_u8(m as u8)?;
+        }
+        if let Some(f) = self.frame_id {
+            writer.write_u8(f as u8)?;
+        }
+        if let Some(c) = self.colormap {
+            writer.write_u8(c)?;
+        }
+        if let Some(s) = self.skin_id {
+            writer.write_u8(s)?;
+        }
+        if let Some(e) = self.effects {
+            writer.write_u8(e.bits())?;
+        }
+        if let Some(x) = self.origin_x {
+            write_coord(writer, x)?;
+        }
+        if let Some(p) = self.pitch {
+            write_angle(writer, p)?;
+        }
+        if let Some(y) = self.origin_y {
+            write_coord(writer, y)?;
+        }
+        if let Some(y) = self.yaw {
+            write_angle(writer, y)?;
+        }
+        if let Some(z) = self.origin_z {
+            write_coord(writer, z)?;
+        }
+        if let Some(r) = self.roll {
+            write_angle(writer, r)?;
+        }
+        if flags.contains(UpdateFlags::FRAME_2) {
+            writer.write_u8((self.frame_id.unwrap_or(0) >> 8) as u8)?;
+        }
+        if flags.contains(UpdateFlags::MODEL_2) {
+            writer.write_u8((self.model_id.unwrap_or(0) >> 8) as u8)?;
+        }
+
+        Ok(())
+    }
+
     /// Create an `EntityState` from this update, filling in any `None` values
     /// from the specified baseline state.
     pub fn to_entity_state(&self, baseline: &EntityState) -> EntityState {
@@ -648,6 +993,34 @@ pub enum ServerCmdCode {
     CdTrack = 32,
     SellScreen = 33,
     Cutscene = 34,
+
+    // FitzQuake extensions
+    SkyBox = 37,
+    Bf = 40,
+    Fog = 41,
+    SpawnBaseline2 = 42,
+    SpawnStatic2 = 43,
+    SpawnStaticSound2 = 44,
+
+    // extended server commands
+
+    // adds a model or sound to the precache after sign-on
+    Precache = 54,
+}
+
+impl ServerCmdCode {
+    /// Returns `true` if this command is only valid in the FitzQuake protocol.
+    fn is_extended(&self) -> bool {
+        match *self {
+            ServerCmdCode::SkyBox
+            | ServerCmdCode::Bf
+            | ServerCmdCode::Fog
+            | ServerCmdCode::SpawnBaseline2
+            | ServerCmdCode::SpawnStatic2
+            | ServerCmdCode::SpawnStaticSound2 => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, Eq, FromPrimitive, PartialEq)]
@@ -656,6 +1029,45 @@ pub enum GameType {
     Deathmatch = 1,
 }
 
+/// The kind of resource added by a `Precache` command.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PrecacheKind {
+    Model,
+    Sound,
+}
+
+// set on the precache index to indicate a sound rather than a model
+const PRECACHE_SOUND_FLAG: u16 = 0x8000;
+
+// flags for FitzQuake's SpawnBaseline2 and SpawnStatic2
+const BASELINE_LARGE_MODEL: u8 = 1 << 0;
+const BASELINE_LARGE_FRAME: u8 = 1 << 1;
+const BASELINE_ALPHA: u8 = 1 << 2;
+
+// the SpawnBaseline2 and SpawnStatic2 flags needed to send these indices
+fn baseline_bits(model_id: u16, frame_id: u16) -> u8 {
+    let mut bits = 0;
+    if model_id > 0xFF {
+        bits |= BASELINE_LARGE_MODEL;
+    }
+    if frame_id > 0xFF {
+        bits |= BASELINE_LARGE_FRAME;
+    }
+    bits
+}
+
+fn write_baseline_id<W>(writer: &mut W, id: u16, large: bool) -> Result<(), NetError>
+where
+    W: WriteBytesExt,
+{
+    match large {
+        true => writer.write_u16::<LittleEndian>(id)?,
+        false => writer.write_u8(id as u8)?,
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, PartialEq)]
 pub enum ServerCmd {
     Bad,
@@ -676,7 +1088,7 @@ pub enum ServerCmd {
         attenuation: Option<f32>,
         entity_id: u16,
         channel: i8,
-        sound_id: u8,
+        sound_id: u16,
         position: Vector3<f32>,
     },
     Time {
@@ -732,8 +1144,8 @@ pub enum ServerCmd {
         source: Vector3<f32>,
     },
     SpawnStatic {
-        model_id: u8,
-        frame_id: u8,
+        model_id: u16,
+        frame_id: u16,
         colormap: u8,
         skin_id: u8,
         origin: Vector3<f32>,
@@ -742,8 +1154,8 @@ pub enum ServerCmd {
     // SpawnBinary, // unused
     SpawnBaseline {
         ent_id: u16,
-        model_id: u8,
-        frame_id: u8,
+        model_id: u16,
+        frame_id: u16,
         colormap: u8,
         skin_id: u8,
         origin: Vector3<f32>,
@@ -765,7 +1177,7 @@ pub enum ServerCmd {
     FoundSecret,
     SpawnStaticSound {
         origin: Vector3<f32>,
-        sound_id: u8,
+        sound_id: u16,
         volume: u8,
         attenuation: u8,
     },
@@ -781,6 +1193,20 @@ pub enum ServerCmd {
     Cutscene {
         text: String,
     },
+    Precache {
+        kind: PrecacheKind,
+        id: u16,
+        name: String,
+    },
+    SkyBox {
+        name: String,
+    },
+    Bf,
+    Fog {
+        density: f32,
+        color: Vector3<f32>,
+        time: f32,
+    },
     FastUpdate(EntityUpdate),
 }
 
@@ -807,20 +1233,38 @@ impl ServerCmd {
             ServerCmd::UpdateColors { .. } => ServerCmdCode::UpdateColors,
             ServerCmd::Particle { .. } => ServerCmdCode::Particle,
             ServerCmd::Damage { .. } => ServerCmdCode::Damage,
-            ServerCmd::SpawnStatic { .. } => ServerCmdCode::SpawnStatic,
-            ServerCmd::SpawnBaseline { .. } => ServerCmdCode::SpawnBaseline,
+            // indices that don't fit in a byte need FitzQuake's extended commands
+            ServerCmd::SpawnStatic {
+                model_id, frame_id, ..
+            } => match baseline_bits(model_id, frame_id) {
+                0 => ServerCmdCode::SpawnStatic,
+                _ => ServerCmdCode::SpawnStatic2,
+            },
+            ServerCmd::SpawnBaseline {
+                model_id, frame_id, ..
+            } => match baseline_bits(model_id, frame_id) {
+                0 => ServerCmdCode::SpawnBaseline,
+                _ => ServerCmdCode::SpawnBaseline2,
+            },
             ServerCmd::TempEntity { .. } => ServerCmdCode::TempEntity,
             ServerCmd::SetPause { .. } => ServerCmdCode::SetPause,
             ServerCmd::SignOnStage { .. } => ServerCmdCode::SignOnStage,
             ServerCmd::CenterPrint { .. } => ServerCmdCode::CenterPrint,
             ServerCmd::KilledMonster => ServerCmdCode::KilledMonster,
             ServerCmd::FoundSecret => ServerCmdCode::FoundSecret,
-            ServerCmd::SpawnStaticSound { .. } => ServerCmdCode::SpawnStaticSound,
+            ServerCmd::SpawnStaticSound { sound_id, .. } => match sound_id > 0xFF {
+                true => ServerCmdCode::SpawnStaticSound2,
+                false => ServerCmdCode::SpawnStaticSound,
+            },
             ServerCmd::Intermission => ServerCmdCode::Intermission,
             ServerCmd::Finale { .. } => ServerCmdCode::Finale,
             ServerCmd::CdTrack { .. } => ServerCmdCode::CdTrack,
             ServerCmd::SellScreen => ServerCmdCode::SellScreen,
             ServerCmd::Cutscene { .. } => ServerCmdCode::Cutscene,
+            ServerCmd::Precache { .. } => ServerCmdCode::Precache,
+            ServerCmd::SkyBox { .. } => ServerCmdCode::SkyBox,
+            ServerCmd::Bf => ServerCmdCode::Bf,
+            ServerCmd::Fog { .. } => ServerCmdCode::Fog,
             // TODO: figure out a more elegant way of doing this
             ServerCmd::FastUpdate(_) => panic!("FastUpdate has no code"),
         };
@@ -828,7 +1272,7 @@ impl ServerCmd {
         code as u8
     }
 
-    pub fn deserialize<R>(reader: &mut R) -> Result<Option<ServerCmd>, NetError>
+    pub fn deserialize<R>(reader: &mut R, protocol: Protocol) -> Result<Option<ServerCmd>, NetError>
     where
         R: BufRead + ReadBytesExt,
     {
@@ -839,13 +1283,18 @@ impl ServerCmd {
         };
 
         if code_num & FAST_UPDATE_FLAG != 0 {
-            let all_bits;
-            let low_bits = code_num & !FAST_UPDATE_FLAG;
-            if low_bits & UpdateFlags::MORE_BITS.bits() as u8 != 0 {
-                let high_bits = reader.read_u8()?;
-                all_bits = (high_bits as u16) << 8 | low_bits as u16;
-            } else {
-                all_bits = low_bits as u16;
+            let mut all_bits = (code_num & !FAST_UPDATE_FLAG) as u32;
+            if all_bits & UpdateFlags::MORE_BITS.bits() != 0 {
+                all_bits |= (reader.read_u8()? as u32) << 8;
+            }
+
+            if protocol.extended() {
+                if all_bits & UpdateFlags::EXTEND_1.bits() != 0 {
+                    all_bits |= (reader.read_u8()? as u32) << 16;
+                }
+                if all_bits & UpdateFlags::EXTEND_2.bits() != 0 {
+                    all_bits |= (reader.read_u8()? as u32) << 24;
+                }
             }
 
             let update_flags = match UpdateFlags::from_bits(all_bits) {
@@ -867,14 +1316,14 @@ impl ServerCmd {
 
             let model_id;
             if update_flags.contains(UpdateFlags::MODEL) {
-                model_id = Some(reader.read_u8()?);
+                model_id = Some(reader.read_u8()? as u16);
             } else {
                 model_id = None;
             }
 
             let frame_id;
             if update_flags.contains(UpdateFlags::FRAME) {
-                frame_id = Some(reader.read_u8()?);
+                frame_id = Some(reader.read_u8()? as u16);
             } else {
                 frame_id = None;
             }
@@ -951,6 +1400,28 @@ impl ServerCmd {
                 roll = None;
             }
 
+            // entity alpha and lerp timing aren't supported yet, but they still
+            // have to be consumed
+            if update_flags.contains(UpdateFlags::ALPHA) {
+                reader.read_u8()?;
+            }
+
+            let mut frame_id = frame_id;
+            if update_flags.contains(UpdateFlags::FRAME_2) {
+                let high = reader.read_u8()? as u16;
+                frame_id = Some(frame_id.unwrap_or(0) | high << 8);
+            }
+
+            let mut model_id = model_id;
+            if update_flags.contains(UpdateFlags::MODEL_2) {
+                let high = reader.read_u8()? as u16;
+                model_id = Some(model_id.unwrap_or(0) | high << 8);
+            }
+
+            if update_flags.contains(UpdateFlags::LERP_FINISH) {
+                reader.read_u8()?;
+            }
+
             let no_lerp = update_flags.contains(UpdateFlags::NO_LERP);
 
             return Ok(Some(ServerCmd::FastUpdate(EntityUpdate {
@@ -980,6 +1451,14 @@ impl ServerCmd {
             }
         };
 
+        if !protocol.extended() && code.is_extended() {
+            return Err(NetError::InvalidData(format!(
+                "Server command {:?} is not valid in protocol {}",
+                code,
+                protocol.version()
+            )));
+        }
+
         let cmd = match code {
             ServerCmdCode::Bad => ServerCmd::Bad,
             ServerCmdCode::NoOp => ServerCmd::NoOp,
@@ -1033,10 +1512,24 @@ impl ServerCmd {
                     false => None,
                 };
 
-                let entity_channel = reader.read_i16::<LittleEndian>()?;
-                let entity_id = (entity_channel >> 3) as u16;
-                let channel = (entity_channel & 0b111) as i8;
-                let sound_id = reader.read_u8()?;
+                let (entity_id, channel) = match flags.contains(SoundFlags::LARGE_ENTITY) {
+                    true => {
+                        let entity_id = reader.read_u16::<LittleEndian>()?;
+                        let channel = reader.read_u8()? as i8;
+                        (entity_id, channel)
+                    }
+                    false => {
+                        let entity_channel = reader.read_i16::<LittleEndian>()?;
+                        let entity_id = (entity_channel >> 3) as u16;
+                        let channel = (entity_channel & 0b111) as i8;
+                        (entity_id, channel)
+                    }
+                };
+
+                let sound_id = match flags.contains(SoundFlags::LARGE_SOUND) {
+                    true => reader.read_u16::<LittleEndian>()?,
+                    false => reader.read_u8()? as u16,
+                };
                 let position = Vector3::new(
                     read_coord(reader)?,
                     read_coord(reader)?,
@@ -1156,7 +1649,16 @@ impl ServerCmd {
             }
 
             ServerCmdCode::PlayerData => {
-                let flags_bits = reader.read_u16::<LittleEndian>()?;
+                let mut flags_bits = reader.read_u16::<LittleEndian>()? as u32;
+                if protocol.extended() {
+                    if flags_bits & ClientUpdateFlags::EXTEND_1.bits() != 0 {
+                        flags_bits |= (reader.read_u8()? as u32) << 16;
+                    }
+                    if flags_bits & ClientUpdateFlags::EXTEND_2.bits() != 0 {
+                        flags_bits |= (reader.read_u8()? as u32) << 24;
+                    }
+                }
+
                 let flags = match ClientUpdateFlags::from_bits(flags_bits) {
                     Some(f) => f,
                     None => {
@@ -1221,29 +1723,60 @@ impl ServerCmd {
                 let on_ground = flags.contains(ClientUpdateFlags::ON_GROUND);
                 let in_water = flags.contains(ClientUpdateFlags::IN_WATER);
 
-                let weapon_frame = match flags.contains(ClientUpdateFlags::WEAPON_FRAME) {
-                    true => Some(reader.read_u8()?),
+                let mut weapon_frame = match flags.contains(ClientUpdateFlags::WEAPON_FRAME) {
+                    true => Some(reader.read_u8()? as u16),
                     false => None,
                 };
 
-                let armor = match flags.contains(ClientUpdateFlags::ARMOR) {
-                    true => Some(reader.read_u8()?),
+                let mut armor = match flags.contains(ClientUpdateFlags::ARMOR) {
+                    true => Some(reader.read_u8()? as u16),
                     false => None,
                 };
 
-                let weapon = match flags.contains(ClientUpdateFlags::WEAPON) {
-                    true => Some(reader.read_u8()?),
+                let mut weapon = match flags.contains(ClientUpdateFlags::WEAPON) {
+                    true => Some(reader.read_u8()? as u16),
                     false => None,
                 };
 
                 let health = reader.read_i16::<LittleEndian>()?;
-                let ammo = reader.read_u8()?;
-                let ammo_shells = reader.read_u8()?;
-                let ammo_nails = reader.read_u8()?;
-                let ammo_rockets = reader.read_u8()?;
-                let ammo_cells = reader.read_u8()?;
+                let mut ammo = reader.read_u8()? as u16;
+                let mut ammo_shells = reader.read_u8()? as u16;
+                let mut ammo_nails = reader.read_u8()? as u16;
+                let mut ammo_rockets = reader.read_u8()? as u16;
+                let mut ammo_cells = reader.read_u8()? as u16;
                 let active_weapon = reader.read_u8()?;
 
+                // FitzQuake sends the high bytes of large values separately
+                if flags.contains(ClientUpdateFlags::WEAPON_2) {
+                    weapon = Some(weapon.unwrap_or(0) | (reader.read_u8()? as u16) << 8);
+                }
+                if flags.contains(ClientUpdateFlags::ARMOR_2) {
+                    armor = Some(armor.unwrap_or(0) | (reader.read_u8()? as u16) << 8);
+                }
+                if flags.contains(ClientUpdateFlags::AMMO_2) {
+                    ammo |= (reader.read_u8()? as u16) << 8;
+                }
+                if flags.contains(ClientUpdateFlags::SHELLS_2) {
+                    ammo_shells |= (reader.read_u8()? as u16) << 8;
+                }
+                if flags.contains(ClientUpdateFlags::NAILS_2) {
+                    ammo_nails |= (reader.read_u8()? as u16) << 8;
+                }
+                if flags.contains(ClientUpdateFlags::ROCKETS_2) {
+                    ammo_rockets |= (reader.read_u8()? as u16) << 8;
+                }
+                if flags.contains(ClientUpdateFlags::CELLS_2) {
+                    ammo_cells |= (reader.read_u8()? as u16) << 8;
+                }
+                if flags.contains(ClientUpdateFlags::WEAPON_FRAME_2) {
+                    let high = (reader.read_u8()? as u16) << 8;
+                    weapon_frame = Some(weapon_frame.unwrap_or(0) | high);
+                }
+                if flags.contains(ClientUpdateFlags::WEAPON_ALPHA) {
+                    // weapon alpha isn't supported yet
+                    reader.read_u8()?;
+                }
+
                 ServerCmd::PlayerData(PlayerData {
                     view_height,
                     ideal_pitch,
@@ -1319,9 +1852,19 @@ impl ServerCmd {
                 }
             }
 
-            ServerCmdCode::SpawnStatic => {
-                let model_id = reader.read_u8()?;
-                let frame_id = reader.read_u8()?;
+            ServerCmdCode::SpawnStatic | ServerCmdCode::SpawnStatic2 => {
+                let bits = match code {
+                    ServerCmdCode::SpawnStatic2 => reader.read_u8()?,
+                    _ => 0,
+                };
+                let model_id = match bits & BASELINE_LARGE_MODEL {
+                    0 => reader.read_u8()? as u16,
+                    _ => reader.read_u16::<LittleEndian>()?,
+                };
+                let frame_id = match bits & BASELINE_LARGE_FRAME {
+                    0 => reader.read_u8()? as u16,
+                    _ => reader.read_u16::<LittleEndian>()?,
+                };
                 let colormap = reader.read_u8()?;
                 let skin_id = reader.read_u8()?;
 
@@ -1332,6 +1875,11 @@ impl ServerCmd {
                     angles[i] = read_angle(reader)?;
                 }
 
+                if bits & BASELINE_ALPHA != 0 {
+                    // entity alpha isn't supported yet
+                    reader.read_u8()?;
+                }
+
                 ServerCmd::SpawnStatic {
                     model_id,
                     frame_id,
@@ -1342,10 +1890,20 @@ impl ServerCmd {
                 }
             }
 
-            ServerCmdCode::SpawnBaseline => {
+            ServerCmdCode::SpawnBaseline | ServerCmdCode::SpawnBaseline2 => {
                 let ent_id = reader.read_u16::<LittleEndian>()?;
-                let model_id = reader.read_u8()?;
-                let frame_id = reader.read_u8()?;
+                let bits = match code {
+                    ServerCmdCode::SpawnBaseline2 => reader.read_u8()?,
+                    _ => 0,
+                };
+                let model_id = match bits & BASELINE_LARGE_MODEL {
+                    0 => reader.read_u8()? as u16,
+                    _ => reader.read_u16::<LittleEndian>()?,
+                };
+                let frame_id = match bits & BASELINE_LARGE_FRAME {
+                    0 => reader.read_u8()? as u16,
+                    _ => reader.read_u16::<LittleEndian>()?,
+                };
                 let colormap = reader.read_u8()?;
                 let skin_id = reader.read_u8()?;
 
@@ -1356,6 +1914,11 @@ impl ServerCmd {
                     angles[i] = read_angle(reader)?;
                 }
 
+                if bits & BASELINE_ALPHA != 0 {
+                    // entity alpha isn't supported yet
+                    reader.read_u8()?;
+                }
+
                 ServerCmd::SpawnBaseline {
                     ent_id,
                     model_id,
@@ -1410,9 +1973,12 @@ impl ServerCmd {
             ServerCmdCode::KilledMonster => ServerCmd::KilledMonster,
             ServerCmdCode::FoundSecret => ServerCmd::FoundSecret,
 
-            ServerCmdCode::SpawnStaticSound => {
+            ServerCmdCode::SpawnStaticSound | ServerCmdCode::SpawnStaticSound2 => {
                 let origin = read_coord_vector3(reader)?;
-                let sound_id = reader.read_u8()?;
+                let sound_id = match code {
+                    ServerCmdCode::SpawnStaticSound2 => reader.read_u16::<LittleEndian>()?,
+                    _ => reader.read_u8()? as u16,
+                };
                 let volume = reader.read_u8()?;
                 let attenuation = reader.read_u8()?;
 
@@ -1451,6 +2017,47 @@ impl ServerCmd {
 
                 ServerCmd::Cutscene { text }
             }
+
+            ServerCmdCode::Precache => {
+                let raw_id = reader.read_u16::<LittleEndian>()?;
+                let kind = match raw_id & PRECACHE_SOUND_FLAG {
+                    0 => PrecacheKind::Model,
+                    _ => PrecacheKind::Sound,
+                };
+                let id = raw_id & !PRECACHE_SOUND_FLAG;
+                let name = match util::read_cstring(reader) {
+                    Ok(n) => n,
+                    Err(e) => return Err(NetError::with_msg(format!("{}", e))),
+                };
+
+                ServerCmd::Precache { kind, id, name }
+            }
+
+            ServerCmdCode::SkyBox => {
+                let name = match util::read_cstring(reader) {
+                    Ok(n) => n,
+                    Err(e) => return Err(NetError::with_msg(format!("{}", e))),
+                };
+
+                ServerCmd::SkyBox { name }
+            }
+
+            ServerCmdCode::Bf => ServerCmd::Bf,
+
+            ServerCmdCode::Fog => {
+                let density = reader.read_u8()? as f32 / 255.0;
+                let mut color = Vector3::zero();
+                for i in 0..3 {
+                    color[i] = reader.read_u8()? as f32 / 255.0;
+                }
+                let time = reader.read_i16::<LittleEndian>()? as f32 / 100.0;
+
+                ServerCmd::Fog {
+                    density,
+                    color,
+                    time,
+                }
+            }
         };
 
         Ok(Some(cmd))
@@ -1460,6 +2067,11 @@ impl ServerCmd {
     where
         W: WriteBytesExt,
     {
+        // fast updates carry their flags in place of a command code
+        if let ServerCmd::FastUpdate(ref update) = *self {
+            return update.serialize(writer);
+        }
+
         writer.write_u8(self.code())?;
 
         match *self {
@@ -1496,6 +2108,17 @@ impl ServerCmd {
                     sound_flags |= SoundFlags::ATTENUATION;
                 }
 
+                // the original protocol packs the entity and channel into 16
+                // bits and the sound into one byte
+                let large_entity = entity_id >= 1 << 13 || channel as u8 > 0b111;
+                if large_entity {
+                    sound_flags |= SoundFlags::LARGE_ENTITY;
+                }
+
+                if sound_id > 0xFF {
+                    sound_flags |= SoundFlags::LARGE_SOUND;
+                }
+
                 writer.write_u8(sound_flags.bits())?;
 
                 if let Some(v) = volume {
@@ -1506,11 +2129,18 @@ impl ServerCmd {
                     writer.write_u8(a as u8 * SOUND_ATTENUATION_WRITE_FACTOR)?;
                 }
 
-                // TODO: document this better. The entity and channel fields are combined in Sound commands.
-                let ent_channel = (entity_id as i16) << 3 | channel as i16 & 0b111;
-                writer.write_i16::<LittleEndian>(ent_channel)?;
+                if large_entity {
+                    writer.write_u16::<LittleEndian>(entity_id)?;
+                    writer.write_u8(channel as u8)?;
+                } else {
+                    let ent_channel = (entity_id as i16) << 3 | channel as i16 & 0b111;
+                    writer.write_i16::<LittleEndian>(ent_channel)?;
+                }
 
-                writer.write_u8(sound_id)?;
+                match sound_flags.contains(SoundFlags::LARGE_SOUND) {
+                    true => writer.write_u16::<LittleEndian>(sound_id)?,
+                    false => writer.write_u8(sound_id as u8)?,
+                }
 
                 for component in 0..3 {
                     write_coord(writer, position[component])?;
@@ -1520,12 +2150,12 @@ impl ServerCmd {
             ServerCmd::Time { time } => writer.write_f32::<LittleEndian>(time)?,
 
             ServerCmd::Print { ref text } => {
-                writer.write(text.as_bytes())?;
+                writer.write(&util::quake_string_to_bytes(&text))?;
                 writer.write_u8(0)?;
             }
 
             ServerCmd::StuffText { ref text } => {
-                writer.write(text.as_bytes())?;
+                writer.write(&util::quake_string_to_bytes(&text))?;
                 writer.write_u8(0)?;
             }
 
@@ -1543,17 +2173,17 @@ impl ServerCmd {
                 writer.write_u8(max_clients)?;
                 writer.write_u8(game_type as u8)?;
 
-                writer.write(message.as_bytes())?;
+                writer.write(&util::quake_string_to_bytes(&message))?;
                 writer.write_u8(0)?;
 
                 for model_name in model_precache.iter() {
-                    writer.write(model_name.as_bytes())?;
+                    writer.write(&util::quake_string_to_bytes(&model_name))?;
                     writer.write_u8(0)?;
                 }
                 writer.write_u8(0)?;
 
                 for sound_name in sound_precache.iter() {
-                    writer.write(sound_name.as_bytes())?;
+                    writer.write(&util::quake_string_to_bytes(&sound_name))?;
                     writer.write_u8(0)?;
                 }
                 writer.write_u8(0)?;
@@ -1561,7 +2191,7 @@ impl ServerCmd {
 
             ServerCmd::LightStyle { id, ref value } => {
                 writer.write_u8(id)?;
-                writer.write(value.as_bytes())?;
+                writer.write(&util::quake_string_to_bytes(&value))?;
                 writer.write_u8(0)?;
             }
 
@@ -1570,7 +2200,7 @@ impl ServerCmd {
                 ref new_name,
             } => {
                 writer.write_u8(player_id)?;
-                writer.write(new_name.as_bytes())?;
+                writer.write(&util::quake_string_to_bytes(&new_name))?;
                 writer.write_u8(0)?;
             }
 
@@ -1650,8 +2280,38 @@ impl ServerCmd {
                     flags |= ClientUpdateFlags::WEAPON;
                 }
 
+                // values that don't fit in a byte have their high bytes sent
+                // separately, as in FitzQuake
+                let high_flags = [
+                    (weapon.unwrap_or(0), ClientUpdateFlags::WEAPON_2),
+                    (armor.unwrap_or(0), ClientUpdateFlags::ARMOR_2),
+                    (ammo, ClientUpdateFlags::AMMO_2),
+                    (ammo_shells, ClientUpdateFlags::SHELLS_2),
+                    (ammo_nails, ClientUpdateFlags::NAILS_2),
+                    (ammo_rockets, ClientUpdateFlags::ROCKETS_2),
+                    (ammo_cells, ClientUpdateFlags::CELLS_2),
+                    (weapon_frame.unwrap_or(0), ClientUpdateFlags::WEAPON_FRAME_2),
+                ];
+                for &(value, flag) in high_flags.iter() {
+                    if value > 0xFF {
+                        flags |= flag;
+                    }
+                }
+                if flags.bits() >> 24 != 0 {
+                    flags |= ClientUpdateFlags::EXTEND_2;
+                }
+                if flags.bits() >> 16 != 0 {
+                    flags |= ClientUpdateFlags::EXTEND_1;
+                }
+
                 // write flags
-                writer.write_u16::<LittleEndian>(flags.bits())?;
+                writer.write_u16::<LittleEndian>(flags.bits() as u16)?;
+                if flags.contains(ClientUpdateFlags::EXTEND_1) {
+                    writer.write_u8((flags.bits() >> 16) as u8)?;
+                }
+                if flags.contains(ClientUpdateFlags::EXTEND_2) {
+                    writer.write_u8((flags.bits() >> 24) as u8)?;
+                }
 
                 if let Some(vh) = view_height {
                     writer.write_u8(vh as i32 as u8)?;
@@ -1679,21 +2339,27 @@ impl ServerCmd {
                 }
                 writer.write_u32::<LittleEndian>(items.bits())?;
                 if let Some(wf) = weapon_frame {
-                    writer.write_u8(wf)?;
+                    writer.write_u8(wf as u8)?;
                 }
                 if let Some(a) = armor {
-                    writer.write_u8(a)?;
+                    writer.write_u8(a as u8)?;
                 }
                 if let Some(w) = weapon {
-                    writer.write_u8(w)?;
+                    writer.write_u8(w as u8)?;
                 }
                 writer.write_i16::<LittleEndian>(health)?;
-                writer.write_u8(ammo)?;
-                writer.write_u8(ammo_shells)?;
-                writer.write_u8(ammo_nails)?;
-                writer.write_u8(ammo_rockets)?;
-                writer.write_u8(ammo_cells)?;
+                writer.write_u8(ammo as u8)?;
+                writer.write_u8(ammo_shells as u8)?;
+                writer.write_u8(ammo_nails as u8)?;
+                writer.write_u8(ammo_rockets as u8)?;
+                writer.write_u8(ammo_cells as u8)?;
                 writer.write_u8(active_weapon)?;
+
+                for &(value, flag) in high_flags.iter() {
+                    if flags.contains(flag) {
+                        writer.write_u8((value >> 8) as u8)?;
+                    }
+                }
             }
 
             ServerCmd::StopSound { entity_id, channel } => {
@@ -1747,8 +2413,12 @@ impl ServerCmd {
                 origin,
                 angles,
             } => {
-                writer.write_u8(model_id)?;
-                writer.write_u8(frame_id)?;
+                let bits = baseline_bits(model_id, frame_id);
+                if bits != 0 {
+                    writer.write_u8(bits)?;
+                }
+                write_baseline_id(writer, model_id, bits & BASELINE_LARGE_MODEL != 0)?;
+                write_baseline_id(writer, frame_id, bits & BASELINE_LARGE_FRAME != 0)?;
                 writer.write_u8(colormap)?;
                 writer.write_u8(skin_id)?;
 
@@ -1768,8 +2438,12 @@ impl ServerCmd {
                 angles,
             } => {
                 writer.write_u16::<LittleEndian>(ent_id)?;
-                writer.write_u8(model_id)?;
-                writer.write_u8(frame_id)?;
+                let bits = baseline_bits(model_id, frame_id);
+                if bits != 0 {
+                    writer.write_u8(bits)?;
+                }
+                write_baseline_id(writer, model_id, bits & BASELINE_LARGE_MODEL != 0)?;
+                write_baseline_id(writer, frame_id, bits & BASELINE_LARGE_FRAME != 0)?;
                 writer.write_u8(colormap)?;
                 writer.write_u8(skin_id)?;
 
@@ -1795,7 +2469,7 @@ impl ServerCmd {
             }
 
             ServerCmd::CenterPrint { ref text } => {
-                writer.write(text.as_bytes())?;
+                writer.write(&util::quake_string_to_bytes(&text))?;
                 writer.write_u8(0)?;
             }
 
@@ -1808,7 +2482,10 @@ impl ServerCmd {
                 attenuation,
             } => {
                 write_coord_vector3(writer, origin)?;
-                writer.write_u8(sound_id)?;
+                match sound_id > 0xFF {
+                    true => writer.write_u16::<LittleEndian>(sound_id)?,
+                    false => writer.write_u8(sound_id as u8)?,
+                }
                 writer.write_u8(volume)?;
                 writer.write_u8(attenuation)?;
             }
@@ -1816,7 +2493,7 @@ impl ServerCmd {
             ServerCmd::Intermission => (),
 
             ServerCmd::Finale { ref text } => {
-                writer.write(text.as_bytes())?;
+                writer.write(&util::quake_string_to_bytes(&text))?;
                 writer.write_u8(0)?;
             }
 
@@ -1828,12 +2505,47 @@ impl ServerCmd {
             ServerCmd::SellScreen => (),
 
             ServerCmd::Cutscene { ref text } => {
-                writer.write(text.as_bytes())?;
+                writer.write(&util::quake_string_to_bytes(&text))?;
+                writer.write_u8(0)?;
+            }
+
+            ServerCmd::Precache { kind, id, ref name } => {
+                if id & PRECACHE_SOUND_FLAG != 0 {
+                    return Err(NetError::with_msg(format!(
+                        "Precache index out of range: {}",
+                        id
+                    )));
+                }
+
+                let raw_id = match kind {
+                    PrecacheKind::Model => id,
+                    PrecacheKind::Sound => id | PRECACHE_SOUND_FLAG,
+                };
+                writer.write_u16::<LittleEndian>(raw_id)?;
+                writer.write(&util::quake_string_to_bytes(&name))?;
+                writer.write_u8(0)?;
+            }
+
+            ServerCmd::SkyBox { ref name } => {
+                writer.write(&util::quake_string_to_bytes(&name))?;
                 writer.write_u8(0)?;
             }
 
-            // TODO
-            ServerCmd::FastUpdate(_) => unimplemented!(),
+            ServerCmd::Bf => (),
+
+            ServerCmd::Fog {
+                density,
+                color,
+                time,
+            } => {
+                writer.write_u8((density * 255.0) as u8)?;
+                for i in 0..3 {
+                    writer.write_u8((color[i] * 255.0) as u8)?;
+                }
+                writer.write_i16::<LittleEndian>((time * 100.0) as i16)?;
+            }
+
+            ServerCmd::FastUpdate(_) => unreachable!(),
         }
 
         Ok(())
@@ -1966,7 +2678,7 @@ impl ClientCmd {
                 writer.write_u8(impulse)?;
             }
             ClientCmd::StringCmd { ref cmd } => {
-                writer.write(cmd.as_bytes())?;
+                writer.write(&util::quake_string_to_bytes(&cmd))?;
                 writer.write_u8(0)?;
             }
         }
@@ -1975,18 +2687,41 @@ impl ClientCmd {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum BlockingMode {
     Blocking,
     NonBlocking,
     Timeout(Duration),
 }
 
-pub struct QSocket {
-    socket: UdpSocket,
-    remote: SocketAddr,
+/// The result of passing a packet to [`Framer::handle_packet`].
+enum Received {
+    /// The packet was consumed. Keep receiving.
+    Continue,
+
+    /// A reliable chunk arrived and must be acknowledged by sending `ack`. If
+    /// `complete` is `true`, it was the last chunk of the message and the
+    /// whole message has been written out.
+    Reliable {
+        ack: [u8; HEADER_SIZE],
+        complete: bool,
+    },
 
-    unreliable_send_sequence: u32,
+    /// An unreliable message arrived and should be returned immediately.
+    Unreliable,
+
+    /// A stale unreliable message arrived. Stop receiving.
+    Stale,
+}
+
+/// Message framing and sequencing for a single connection.
+///
+/// This holds all of a connection's protocol state but performs no I/O:
+/// packets to send are returned to the caller, and received packets are passed
+/// in. This lets the blocking [`QSocket`] and the async socket share the same
+/// framing code.
+struct Framer {
+    unreliable_send_sequence: u32,
     unreliable_recv_sequence: u32,
 
     ack_sequence: u32,
@@ -1995,19 +2730,32 @@ pub struct QSocket {
     send_queue: VecDeque<Box<[u8]>>,
     send_cache: Box<[u8]>,
     send_next: bool,
-    send_count: usize,
-    resend_count: usize,
+
+    // when the last reliable packet was first sent, for measuring round-trip
+    // time. cleared on resend since the ACK could belong to either copy.
+    send_time: Option<DateTime<Utc>>,
 
     recv_sequence: u32,
-    recv_buf: [u8; MAX_MESSAGE],
-}
 
-impl QSocket {
-    pub fn new(socket: UdpSocket, remote: SocketAddr) -> QSocket {
-        QSocket {
-            socket,
-            remote,
+    // chunks of the reliable message being received. these are kept between
+    // calls to handle_packet until the last chunk arrives.
+    recv_reliable: Vec<u8>,
+
+    // set when a reliable message grows too long. the rest of its chunks are
+    // acknowledged but discarded, keeping the sequence in step with the sender.
+    discard_reliable: bool,
+
+    // when a packet was last sent or received, for keepalives and timeouts
+    last_send_time: DateTime<Utc>,
+    last_recv_time: DateTime<Utc>,
+
+    stats: NetStats,
+    latency_graph: LatencyGraph,
+}
 
+impl Framer {
+    fn new() -> Framer {
+        Framer {
             unreliable_send_sequence: 0,
             unreliable_recv_sequence: 0,
 
@@ -2016,21 +2764,46 @@ impl QSocket {
             send_sequence: 0,
             send_queue: VecDeque::new(),
             send_cache: Box::new([]),
-            send_count: 0,
             send_next: false,
-            resend_count: 0,
+            send_time: None,
 
             recv_sequence: 0,
-            recv_buf: [0; MAX_MESSAGE],
+
+            recv_reliable: Vec::new(),
+            discard_reliable: false,
+
+            last_send_time: Utc::now(),
+            last_recv_time: Utc::now(),
+
+            stats: NetStats::default(),
+            latency_graph: LatencyGraph::new(),
         }
     }
 
-    pub fn can_send(&self) -> bool {
+    fn can_send(&self) -> bool {
         self.send_queue.is_empty() && self.send_cache.is_empty()
     }
 
-    /// Begin sending a reliable message over this socket.
-    pub fn begin_send_msg(&mut self, msg: &[u8]) -> Result<(), NetError> {
+    fn record_sent(&mut self, len: usize) {
+        self.stats.record_sent(len);
+        self.last_send_time = Utc::now();
+    }
+
+    fn record_received(&mut self, len: usize) {
+        self.stats.record_received(len);
+        self.last_recv_time = Utc::now();
+    }
+
+    fn time_since_send(&self) -> Duration {
+        Utc::now() - self.last_send_time
+    }
+
+    fn time_since_recv(&self) -> Duration {
+        Utc::now() - self.last_recv_time
+    }
+
+    /// Split a reliable message into chunks and return the first packet.
+    fn begin_send_msg(&mut self, msg: &[u8]) -> Result<&[u8], NetError> {
         // make sure all reliable messages have been ACKed in their entirety
         if !self.send_queue.is_empty() {
             return Err(NetError::with_msg(
@@ -2058,26 +2831,25 @@ impl QSocket {
                 .push_back(chunk.to_owned().into_boxed_slice());
         }
 
-        // send the first chunk
-        self.send_msg_next()?;
-
-        Ok(())
+        // compose the first chunk
+        self.next_packet()
     }
 
-    /// Resend the last reliable message packet.
-    pub fn resend_msg(&mut self) -> Result<(), NetError> {
+    /// Return the last reliable packet so it can be sent again.
+    fn resend_packet(&mut self) -> Result<&[u8], NetError> {
         if self.send_cache.is_empty() {
             Err(NetError::with_msg("Attempted resend with empty send cache"))
         } else {
-            self.socket.send_to(&self.send_cache, self.remote)?;
-            self.resend_count += 1;
-
-            Ok(())
+            self.stats.resends += 1;
+            self.record_sent(self.send_cache.len());
+            self.send_time = None;
+            self.latency_graph.push(None);
+            Ok(&self.send_cache)
         }
     }
 
-    /// Send the next segment of a reliable message.
-    pub fn send_msg_next(&mut self) -> Result<(), NetError> {
+    /// Compose the packet for the next segment of a reliable message.
+    fn next_packet(&mut self) -> Result<&[u8], NetError> {
         // grab the first chunk in the queue
         let content = self
             .send_queue
@@ -2103,20 +2875,25 @@ impl QSocket {
         // increment send sequence
         self.send_sequence += 1;
 
-        // send the composed packet
-        self.socket.send_to(&self.send_cache, self.remote)?;
-
-        // TODO: update send time
-        // bump send count
-        self.send_count += 1;
+        self.send_time = Some(Utc::now());
+        self.record_sent(self.send_cache.len());
 
         // don't send the next chunk until this one gets ACKed
         self.send_next = false;
 
-        Ok(())
+        Ok(&self.send_cache)
     }
 
-    pub fn send_msg_unreliable(&mut self, content: &[u8]) -> Result<(), NetError> {
+    /// If the last reliable chunk was acknowledged and more remain, compose
+    /// the next one.
+    fn take_next_packet(&mut self) -> Result<Option<&[u8]>, NetError> {
+        match self.send_next {
+            true => self.next_packet().map(Some),
+            false => Ok(None),
+        }
+    }
+
+    fn unreliable_packet(&mut self, content: &[u8]) -> Result<Vec<u8>, NetError> {
         if content.len() == 0 {
             return Err(NetError::with_msg("Unreliable message has zero length"));
         }
@@ -2139,178 +2916,377 @@ impl QSocket {
         // increment unreliable send sequence
         self.unreliable_send_sequence += 1;
 
-        // send the message
-        self.socket.send_to(&packet, self.remote)?;
-
-        // bump send count
-        self.send_count += 1;
+        self.record_sent(packet.len());
 
-        Ok(())
+        Ok(packet)
     }
 
-    /// Receive a message on this socket.
-    // TODO: the flow control in this function is completely baffling, make it a little less awful
-    pub fn recv_msg(&mut self, block: BlockingMode) -> Result<Vec<u8>, NetError> {
-        let mut msg = Vec::new();
+    /// Process a received packet.
+    ///
+    /// Unreliable messages are appended to `msg` as soon as they arrive.
+    /// Reliable messages are only appended once all of their chunks have
+    /// arrived.
+    fn handle_packet(&mut self, packet: &[u8], msg: &mut Vec<u8>) -> Result<Received, NetError> {
+        let packet_len = packet.len();
+        let mut reader = BufReader::new(Cursor::new(packet));
 
-        match block {
-            BlockingMode::Blocking => {
-                self.socket.set_nonblocking(false)?;
-                self.socket.set_read_timeout(None)?;
-            }
+        self.record_received(packet_len);
 
-            BlockingMode::NonBlocking => {
-                self.socket.set_nonblocking(true)?;
-                self.socket.set_read_timeout(None)?;
+        let msg_kind_code = reader.read_u16::<NetworkEndian>()?;
+        let msg_kind = match MsgKind::from_u16(msg_kind_code) {
+            Some(f) => f,
+            None => {
+                return Err(NetError::InvalidData(format!(
+                    "Invalid message kind: {}",
+                    msg_kind_code
+                )))
             }
+        };
 
-            BlockingMode::Timeout(d) => {
-                self.socket.set_nonblocking(false)?;
-                self.socket.set_read_timeout(Some(d.to_std().unwrap()))?;
-            }
+        if packet_len < HEADER_SIZE {
+            // TODO: increment short packet count
+            debug!("short packet");
+            return Ok(Received::Continue);
         }
 
-        loop {
-            let (packet_len, src_addr) = match self.socket.recv_from(&mut self.recv_buf) {
-                Ok(x) => x,
-                Err(e) => {
-                    use std::io::ErrorKind;
-                    match e.kind() {
-                        // these errors are expected in nonblocking mode
-                        ErrorKind::WouldBlock | ErrorKind::TimedOut => return Ok(Vec::new()),
-                        _ => return Err(NetError::from(e)),
-                    }
+        let field_len = reader.read_u16::<NetworkEndian>()?;
+        if field_len as usize != packet_len {
+            return Err(NetError::InvalidData(format!(
+                "Length field and actual length differ ({} != {})",
+                field_len, packet_len
+            )));
+        }
+
+        let sequence;
+        if msg_kind != MsgKind::Ctl {
+            sequence = reader.read_u32::<NetworkEndian>()?;
+        } else {
+            sequence = 0;
+        }
+
+        match msg_kind {
+            // ignore control messages
+            MsgKind::Ctl => Ok(Received::Continue),
+
+            MsgKind::Unreliable => {
+                // we've received a newer datagram, ignore
+                if sequence < self.unreliable_recv_sequence {
+                    println!("Stale datagram with sequence # {}", sequence);
+                    return Ok(Received::Stale);
                 }
-            };
 
-            if src_addr != self.remote {
-                // this packet didn't come from remote, drop it
-                debug!(
-                    "forged packet (src_addr was {}, should be {})",
-                    src_addr, self.remote
-                );
-                continue;
+                // we've skipped some datagrams, count them as dropped
+                if sequence > self.unreliable_recv_sequence {
+                    let drop_count = sequence - self.unreliable_recv_sequence;
+                    self.stats.dropped_unreliables += drop_count as u64;
+                    println!(
+                        "Dropped {} packet(s) ({} -> {})",
+                        drop_count, sequence, self.unreliable_recv_sequence
+                    );
+                }
+
+                self.unreliable_recv_sequence = sequence + 1;
+
+                // copy the rest of the packet into the message buffer
+                reader.read_to_end(msg)?;
+                Ok(Received::Unreliable)
             }
 
-            let mut reader = BufReader::new(Cursor::new(&self.recv_buf[..packet_len]));
+            MsgKind::Ack => {
+                if sequence != self.send_sequence - 1 {
+                    println!("Stale ACK received");
+                } else if sequence != self.ack_sequence {
+                    println!("Duplicate ACK received");
+                } else {
+                    self.ack_sequence += 1;
+                    if self.ack_sequence != self.send_sequence {
+                        return Err(NetError::with_msg("ACK sequencing error"));
+                    }
 
-            let msg_kind_code = reader.read_u16::<NetworkEndian>()?;
-            let msg_kind = match MsgKind::from_u16(msg_kind_code) {
-                Some(f) => f,
-                None => {
-                    return Err(NetError::InvalidData(format!(
-                        "Invalid message kind: {}",
-                        msg_kind_code
-                    )))
+                    if let Some(send_time) = self.send_time.take() {
+                        let rtt = Utc::now() - send_time;
+                        self.stats.record_rtt(rtt);
+                        self.latency_graph.push(Some(rtt));
+                    }
+
+                    // our last reliable message has been acked
+                    if self.send_queue.is_empty() {
+                        // the whole message is through, clear the send cache
+                        self.send_cache = Box::new([]);
+                    } else {
+                        // send the next chunk before returning
+                        self.send_next = true;
+                    }
                 }
-            };
 
-            if packet_len < HEADER_SIZE {
-                // TODO: increment short packet count
-                debug!("short packet");
-                continue;
+                Ok(Received::Continue)
             }
 
-            let field_len = reader.read_u16::<NetworkEndian>()?;
-            if field_len as usize != packet_len {
-                return Err(NetError::InvalidData(format!(
-                    "Length field and actual length differ ({} != {})",
-                    field_len, packet_len
-                )));
+            // unreliable messages may arrive between the chunks of a reliable
+            // message; they're returned on their own
+            MsgKind::Reliable | MsgKind::ReliableEom => {
+                // compose ack message
+                let mut ack: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+                let mut ack_curs = Cursor::new(&mut ack[..]);
+                ack_curs.write_u16::<NetworkEndian>(MsgKind::Ack as u16)?;
+                ack_curs.write_u16::<NetworkEndian>(HEADER_SIZE as u16)?;
+                ack_curs.write_u32::<NetworkEndian>(sequence)?;
+
+                // the caller always sends the ACK
+                self.record_sent(HEADER_SIZE);
+
+                // if this was a duplicate, drop it
+                if sequence != self.recv_sequence {
+                    println!("Duplicate message received");
+                    self.stats.dropped_reliables += 1;
+                    return Ok(Received::Reliable {
+                        ack,
+                        complete: false,
+                    });
+                }
+
+                if self.discard_reliable {
+                    self.recv_sequence += 1;
+                    if msg_kind == MsgKind::ReliableEom {
+                        self.discard_reliable = false;
+                    }
+
+                    return Ok(Received::Reliable {
+                        ack,
+                        complete: false,
+                    });
+                }
+
+                // check the length before accepting the chunk. it isn't
+                // acknowledged, so the sender resends it and it's discarded
+                // along with the rest of the message.
+                let content_len = packet_len - HEADER_SIZE;
+                let total_len = self.recv_reliable.len() + content_len;
+                if total_len > MAX_NET_MESSAGE {
+                    self.recv_reliable.clear();
+                    self.discard_reliable = true;
+                    self.stats.dropped_reliables += 1;
+                    return Err(NetError::MessageTooLong(total_len));
+                }
+
+                self.recv_sequence += 1;
+                reader.read_to_end(&mut self.recv_reliable)?;
+
+                let complete = msg_kind == MsgKind::ReliableEom;
+                if complete {
+                    msg.append(&mut self.recv_reliable);
+                }
+
+                Ok(Received::Reliable { ack, complete })
             }
+        }
+    }
+}
 
-            let sequence;
-            if msg_kind != MsgKind::Ctl {
-                sequence = reader.read_u32::<NetworkEndian>()?;
-            } else {
-                sequence = 0;
+pub struct QSocket {
+    socket: UdpSocket,
+    remote: SocketAddr,
+    framer: Framer,
+    recv_buf: [u8; MAX_MESSAGE],
+
+    // simulated network conditions, if enabled
+    sim: Option<NetSim>,
+}
+
+impl QSocket {
+    pub fn new(socket: UdpSocket, remote: SocketAddr) -> QSocket {
+        QSocket {
+            socket,
+            remote,
+            framer: Framer::new(),
+            recv_buf: [0; MAX_MESSAGE],
+            sim: None,
+        }
+    }
+
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    /// Set the simulated network conditions for this socket.
+    ///
+    /// Packets already held back by the simulation are still delivered after
+    /// it is turned off.
+    pub fn set_sim_vars(&mut self, vars: NetSimVars) {
+        match self.sim {
+            Some(ref mut sim) => {
+                sim.set_vars(vars);
+                if !vars.enabled() && sim.is_idle() {
+                    self.sim = None;
+                }
             }
 
-            match msg_kind {
-                // ignore control messages
-                MsgKind::Ctl => (),
+            None if vars.enabled() => self.sim = Some(NetSim::new(vars)),
+            None => (),
+        }
+    }
 
-                MsgKind::Unreliable => {
-                    // we've received a newer datagram, ignore
-                    if sequence < self.unreliable_recv_sequence {
-                        println!("Stale datagram with sequence # {}", sequence);
-                        break;
-                    }
+    pub fn can_send(&self) -> bool {
+        self.framer.can_send()
+    }
 
-                    // we've skipped some datagrams, count them as dropped
-                    if sequence > self.unreliable_recv_sequence {
-                        let drop_count = sequence - self.unreliable_recv_sequence;
-                        println!(
-                            "Dropped {} packet(s) ({} -> {})",
-                            drop_count, sequence, self.unreliable_recv_sequence
-                        );
-                    }
+    /// Return the traffic counters for this connection.
+    pub fn stats(&self) -> NetStats {
+        self.framer.stats
+    }
+
+    /// Return the recent round-trip times for this connection.
+    pub fn latency_graph(&self) -> &LatencyGraph {
+        &self.framer.latency_graph
+    }
+
+    /// Return the time since a packet was last sent.
+    ///
+    /// If this grows too long, the connection should be kept alive by sending
+    /// a no-op message.
+    pub fn time_since_send(&self) -> Duration {
+        self.framer.time_since_send()
+    }
+
+    /// Return the time since a packet was last received from the remote.
+    ///
+    /// If this grows too long, the remote should be considered gone.
+    pub fn time_since_recv(&self) -> Duration {
+        self.framer.time_since_recv()
+    }
+
+    /// Count a message that had to wait for the previous reliable message to
+    /// be acknowledged.
+    pub fn record_choke(&mut self) {
+        self.framer.stats.choke += 1;
+    }
+
+    /// Begin sending a reliable message over this socket.
+    pub fn begin_send_msg(&mut self, msg: &[u8]) -> Result<(), NetError> {
+        let packet = self.framer.begin_send_msg(msg)?;
+        send_packet(&self.socket, self.sim.as_mut(), packet, self.remote)?;
+        Ok(())
+    }
+
+    /// Resend the last reliable message packet.
+    pub fn resend_msg(&mut self) -> Result<(), NetError> {
+        let packet = self.framer.resend_packet()?;
+        send_packet(&self.socket, self.sim.as_mut(), packet, self.remote)?;
+        Ok(())
+    }
+
+    /// Send the next segment of a reliable message.
+    pub fn send_msg_next(&mut self) -> Result<(), NetError> {
+        let packet = self.framer.next_packet()?;
+        send_packet(&self.socket, self.sim.as_mut(), packet, self.remote)?;
+        Ok(())
+    }
+
+    pub fn send_msg_unreliable(&mut self, content: &[u8]) -> Result<(), NetError> {
+        let packet = self.framer.unreliable_packet(content)?;
+        send_packet(&self.socket, self.sim.as_mut(), &packet, self.remote)?;
+        Ok(())
+    }
 
-                    self.unreliable_recv_sequence = sequence + 1;
+    /// Receive a message on this socket.
+    ///
+    /// Reliable messages that were split across several packets are returned
+    /// whole. If the call returns before the last chunk arrives, an empty
+    /// message is returned and the chunks received so far are kept for the
+    /// next call.
+    pub fn recv_msg(&mut self, block: BlockingMode) -> Result<Vec<u8>, NetError> {
+        let mut msg = Vec::new();
 
-                    // copy the rest of the packet into the message buffer and return
-                    reader.read_to_end(&mut msg)?;
-                    return Ok(msg);
+        // the network simulation handles blocking itself
+        if self.sim.is_none() {
+            match block {
+                BlockingMode::Blocking => {
+                    self.socket.set_nonblocking(false)?;
+                    self.socket.set_read_timeout(None)?;
                 }
 
-                MsgKind::Ack => {
-                    if sequence != self.send_sequence - 1 {
-                        println!("Stale ACK received");
-                    } else if sequence != self.ack_sequence {
-                        println!("Duplicate ACK received");
-                    } else {
-                        self.ack_sequence += 1;
-                        if self.ack_sequence != self.send_sequence {
-                            return Err(NetError::with_msg("ACK sequencing error"));
-                        }
+                BlockingMode::NonBlocking => {
+                    self.socket.set_nonblocking(true)?;
+                    self.socket.set_read_timeout(None)?;
+                }
 
-                        // our last reliable message has been acked
-                        if self.send_queue.is_empty() {
-                            // the whole message is through, clear the send cache
-                            self.send_cache = Box::new([]);
-                        } else {
-                            // send the next chunk before returning
-                            self.send_next = true;
-                        }
-                    }
+                BlockingMode::Timeout(d) => {
+                    self.socket.set_nonblocking(false)?;
+                    self.socket.set_read_timeout(Some(read_timeout(d)))?;
                 }
+            }
+        }
+
+        loop {
+            let received = match self.sim {
+                Some(ref mut sim) => sim.recv_from(&self.socket, &mut self.recv_buf, block),
+                None => self.socket.recv_from(&mut self.recv_buf),
+            };
 
-                // TODO: once we start reading a reliable message, don't allow other packets until
-                // we have the whole thing
-                MsgKind::Reliable | MsgKind::ReliableEom => {
-                    // send ack message and increment self.recv_sequence
-                    let mut ack_buf: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
-                    let mut ack_curs = Cursor::new(&mut ack_buf[..]);
-                    ack_curs.write_u16::<NetworkEndian>(MsgKind::Ack as u16)?;
-                    ack_curs.write_u16::<NetworkEndian>(HEADER_SIZE as u16)?;
-                    ack_curs.write_u32::<NetworkEndian>(sequence)?;
-                    self.socket.send_to(ack_curs.into_inner(), self.remote)?;
-
-                    // if this was a duplicate, drop it
-                    if sequence != self.recv_sequence {
-                        println!("Duplicate message received");
-                        continue;
+            let (packet_len, src_addr) = match received {
+                Ok(x) => x,
+                Err(e) => {
+                    use std::io::ErrorKind;
+                    match e.kind() {
+                        // these errors are expected in nonblocking mode. an ACK
+                        // may have come in, so don't return just yet
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut => break,
+                        _ => return Err(NetError::from(e)),
                     }
+                }
+            };
 
-                    self.recv_sequence += 1;
-                    reader.read_to_end(&mut msg)?;
+            if src_addr != self.remote {
+                // this packet didn't come from remote, drop it
+                debug!(
+                    "forged packet (src_addr was {}, should be {})",
+                    src_addr, self.remote
+                );
+                continue;
+            }
+
+            match self
+                .framer
+                .handle_packet(&self.recv_buf[..packet_len], &mut msg)?
+            {
+                Received::Continue => (),
+                Received::Stale => break,
+                Received::Unreliable => return Ok(msg),
+                Received::Reliable { ack, complete } => {
+                    send_packet(&self.socket, self.sim.as_mut(), &ack, self.remote)?;
 
                     // if this is the last chunk of a reliable message, break out and return
-                    if msg_kind == MsgKind::ReliableEom {
+                    if complete {
                         break;
                     }
                 }
             }
         }
 
-        if self.send_next {
-            self.send_msg_next()?;
+        if let Some(packet) = self.framer.take_next_packet()? {
+            send_packet(&self.socket, self.sim.as_mut(), packet, self.remote)?;
         }
 
         Ok(msg)
     }
 }
 
+fn send_packet(
+    socket: &UdpSocket,
+    sim: Option<&mut NetSim>,
+    packet: &[u8],
+    remote: SocketAddr,
+) -> Result<(), NetError> {
+    match sim {
+        Some(sim) => sim.send_to(socket, packet, remote)?,
+        None => {
+            socket.send_to(packet, remote)?;
+        }
+    }
+
+    Ok(())
+}
+
 fn read_coord<R>(reader: &mut R) -> Result<f32, NetError>
 where
     R: BufRead + ReadBytesExt,
@@ -2401,7 +3377,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2413,7 +3391,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2425,7 +3405,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2437,7 +3419,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2451,7 +3435,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2465,7 +3451,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2484,7 +3472,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2499,7 +3489,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2514,7 +3506,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2529,7 +3523,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2544,7 +3540,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2559,7 +3557,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2570,7 +3570,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2583,7 +3585,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2596,7 +3600,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2609,7 +3615,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2620,7 +3628,9 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
@@ -2633,11 +3643,302 @@ mod test {
         let mut packet = Vec::new();
         src.serialize(&mut packet).unwrap();
         let mut reader = BufReader::new(packet.as_slice());
-        let dst = ServerCmd::deserialize(&mut reader).unwrap().unwrap();
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(src, dst);
+    }
+
+    #[test]
+    fn test_server_cmd_precache_read_write_eq() {
+        for &kind in &[PrecacheKind::Model, PrecacheKind::Sound] {
+            let src = ServerCmd::Precache {
+                kind,
+                id: 300,
+                name: String::from("progs/newmodel.mdl"),
+            };
+            let mut packet = Vec::new();
+            src.serialize(&mut packet).unwrap();
+            let mut reader = BufReader::new(packet.as_slice());
+            let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(src, dst);
+        }
+    }
+
+    #[test]
+    fn test_server_cmd_fast_update_read_write_eq() {
+        let src = ServerCmd::FastUpdate(EntityUpdate {
+            ent_id: 300,
+            model_id: Some(12),
+            frame_id: None,
+            colormap: None,
+            skin_id: Some(1),
+            effects: Some(EntityEffects::MUZZLE_FLASH),
+            origin_x: Some(128.0),
+            pitch: None,
+            origin_y: None,
+            yaw: Some(Deg(90.0)),
+            origin_z: Some(-32.5),
+            roll: None,
+            no_lerp: true,
+        });
+        let mut packet = Vec::new();
+        src.serialize(&mut packet).unwrap();
+        let mut reader = BufReader::new(packet.as_slice());
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+            .unwrap()
+            .unwrap();
 
         assert_eq!(src, dst);
     }
 
+    #[test]
+    fn test_entity_update_max_len() {
+        let full = ServerCmd::FastUpdate(EntityUpdate {
+            ent_id: 300,
+            model_id: Some(300),
+            frame_id: Some(260),
+            colormap: Some(1),
+            skin_id: Some(1),
+            effects: Some(EntityEffects::MUZZLE_FLASH),
+            origin_x: Some(128.0),
+            pitch: Some(Deg(10.0)),
+            origin_y: Some(64.0),
+            yaw: Some(Deg(90.0)),
+            origin_z: Some(-32.5),
+            roll: Some(Deg(5.0)),
+            no_lerp: false,
+        });
+        let mut packet = Vec::new();
+        full.serialize(&mut packet).unwrap();
+
+        assert_eq!(packet.len(), MAX_ENTITY_UPDATE_LEN);
+    }
+
+    #[test]
+    fn test_server_cmd_temp_entity_read_write_eq() {
+        let origin = Vector3::new(64.0, -8.5, 24.125);
+        let end = Vector3::new(256.0, 0.0, 24.0);
+        let mut temp_entities: Vec<TempEntity> = vec![
+            PointEntityKind::Spike,
+            PointEntityKind::SuperSpike,
+            PointEntityKind::Gunshot,
+            PointEntityKind::Explosion,
+            PointEntityKind::ColorExplosion {
+                color_start: 192,
+                color_len: 8,
+            },
+            PointEntityKind::TarExplosion,
+            PointEntityKind::WizSpike,
+            PointEntityKind::KnightSpike,
+            PointEntityKind::LavaSplash,
+            PointEntityKind::Teleport,
+        ]
+        .into_iter()
+        .map(|kind| TempEntity::Point { kind, origin })
+        .collect();
+
+        for kind in vec![
+            BeamEntityKind::Lightning { model_id: 1 },
+            BeamEntityKind::Lightning { model_id: 2 },
+            BeamEntityKind::Lightning { model_id: 3 },
+            BeamEntityKind::Grapple,
+        ] {
+            temp_entities.push(TempEntity::Beam {
+                kind,
+                entity_id: 2,
+                start: origin,
+                end,
+            });
+        }
+
+        for temp_entity in temp_entities {
+            let src = ServerCmd::TempEntity { temp_entity };
+            let mut packet = Vec::new();
+            src.serialize(&mut packet).unwrap();
+            let mut reader = BufReader::new(packet.as_slice());
+            let dst = ServerCmd::deserialize(&mut reader, Protocol::NetQuake)
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(src, dst);
+        }
+    }
+
+    #[test]
+    fn test_entity_update_between() {
+        let baseline = EntityState {
+            model_id: 4,
+            ..EntityState::uninitialized()
+        };
+        let mut state = baseline.clone();
+        state.origin.y = 64.0;
+        state.frame_id = 3;
+
+        let update = EntityUpdate::between(7, &baseline, &state);
+        assert_eq!(update.model_id, None);
+        assert_eq!(update.frame_id, Some(3));
+        assert_eq!(update.origin_x, None);
+        assert_eq!(update.origin_y, Some(64.0));
+        assert_eq!(update.to_entity_state(&baseline), state);
+        assert_eq!(
+            update.flags(),
+            UpdateFlags::SIGNAL | UpdateFlags::FRAME | UpdateFlags::ORIGIN_Y
+        );
+    }
+
+    #[test]
+    fn test_resolve_addr() {
+        let cases = [
+            ("10.0.0.1:27000", "10.0.0.1:27000"),
+            ("10.0.0.1", "10.0.0.1:26000"),
+            ("[2001:db8::1]:27000", "[2001:db8::1]:27000"),
+            ("[2001:db8::1]", "[2001:db8::1]:26000"),
+            ("2001:db8::1", "[2001:db8::1]:26000"),
+        ];
+
+        for (input, expected) in cases.iter() {
+            let expected: SocketAddr = expected.parse().unwrap();
+            assert_eq!(resolve_addr(input, DEFAULT_PORT).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_unspecified_addr_matches_family() {
+        let v6: SocketAddr = "[::1]:26000".parse().unwrap();
+        assert!(unspecified_addr(&v6).is_ipv6());
+
+        let v4: SocketAddr = "127.0.0.1:26000".parse().unwrap();
+        assert!(unspecified_addr(&v4).is_ipv4());
+    }
+
+    #[test]
+    fn test_read_timeout_is_positive() {
+        let min = std::time::Duration::from_millis(1);
+        assert_eq!(read_timeout(Duration::milliseconds(-5)), min);
+        assert_eq!(read_timeout(Duration::zero()), min);
+        assert_eq!(
+            read_timeout(Duration::milliseconds(250)),
+            std::time::Duration::from_millis(250)
+        );
+    }
+
+    #[test]
+    fn test_unmap_addr() {
+        let mapped: SocketAddr = "[::ffff:10.0.0.1]:27950".parse().unwrap();
+        assert_eq!(unmap_addr(mapped), "10.0.0.1:27950".parse().unwrap());
+
+        let v6: SocketAddr = "[::1]:27950".parse().unwrap();
+        assert_eq!(unmap_addr(v6), v6);
+    }
+
+    #[test]
+    fn test_protocol_from_version() {
+        assert_eq!(Protocol::from_version(15), Some(Protocol::NetQuake));
+        assert_eq!(Protocol::from_version(666), Some(Protocol::FitzQuake));
+        assert_eq!(Protocol::from_version(28), Some(Protocol::QuakeWorld));
+        assert_eq!(Protocol::from_version(14), None);
+    }
+
+    #[test]
+    fn test_server_cmd_fitzquake_large_sound() {
+        let flags = SoundFlags::LARGE_ENTITY | SoundFlags::LARGE_SOUND;
+        let mut packet = vec![ServerCmdCode::Sound as u8, flags.bits()];
+        packet.write_u16::<LittleEndian>(9000).unwrap();
+        packet.write_u8(3).unwrap();
+        packet.write_u16::<LittleEndian>(300).unwrap();
+        write_coord_vector3(&mut packet, Vector3::new(8.0, 16.0, 24.0)).unwrap();
+
+        let mut reader = BufReader::new(packet.as_slice());
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::FitzQuake)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            dst,
+            ServerCmd::Sound {
+                volume: None,
+                attenuation: None,
+                entity_id: 9000,
+                channel: 3,
+                sound_id: 300,
+                position: Vector3::new(8.0, 16.0, 24.0),
+            }
+        );
+    }
+
+    #[test]
+    fn test_server_cmd_large_indices_read_write_eq() {
+        let cmds = vec![
+            ServerCmd::Sound {
+                volume: None,
+                attenuation: None,
+                entity_id: 9000,
+                channel: 3,
+                sound_id: 300,
+                position: Vector3::new(8.0, 16.0, 24.0),
+            },
+            ServerCmd::SpawnBaseline {
+                ent_id: 12,
+                model_id: 300,
+                frame_id: 2,
+                colormap: 0,
+                skin_id: 1,
+                origin: Vector3::new(8.0, 16.0, 24.0),
+                angles: Vector3::new(Deg(0.0), Deg(90.0), Deg(0.0)),
+            },
+            ServerCmd::SpawnStaticSound {
+                origin: Vector3::new(8.0, 16.0, 24.0),
+                sound_id: 400,
+                volume: 255,
+                attenuation: 64,
+            },
+            ServerCmd::FastUpdate(EntityUpdate {
+                ent_id: 12,
+                model_id: Some(300),
+                frame_id: Some(260),
+                colormap: None,
+                skin_id: None,
+                effects: None,
+                origin_x: Some(8.0),
+                pitch: None,
+                origin_y: None,
+                yaw: None,
+                origin_z: None,
+                roll: None,
+                no_lerp: false,
+            }),
+        ];
+
+        for src in cmds {
+            let mut packet = Vec::new();
+            src.serialize(&mut packet).unwrap();
+            let mut reader = BufReader::new(packet.as_slice());
+            let dst = ServerCmd::deserialize(&mut reader, Protocol::FitzQuake)
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(src, dst);
+        }
+    }
+
+    #[test]
+    fn test_server_cmd_fitzquake_rejected_in_netquake() {
+        let packet = vec![ServerCmdCode::Bf as u8];
+        let mut reader = BufReader::new(packet.as_slice());
+        assert!(ServerCmd::deserialize(&mut reader, Protocol::NetQuake).is_err());
+
+        let mut reader = BufReader::new(packet.as_slice());
+        let dst = ServerCmd::deserialize(&mut reader, Protocol::FitzQuake)
+            .unwrap()
+            .unwrap();
+        assert_eq!(dst, ServerCmd::Bf);
+    }
+
     #[test]
     fn test_client_cmd_string_cmd_read_write_eq() {
         let src = ClientCmd::StringCmd {
@@ -2724,4 +4025,130 @@ mod test {
         let message = [0; MAX_DATAGRAM + 1];
         src.send_msg_unreliable(&message).unwrap();
     }
+
+    #[test]
+    fn test_qsocket_stats() {
+        let (mut src, mut dst) = gen_qsocket_pair();
+
+        let message = String::from("test message").into_bytes();
+        let packet_len = (HEADER_SIZE + message.len()) as u64;
+        src.begin_send_msg(&message).unwrap();
+        dst.recv_msg(BlockingMode::Blocking).unwrap();
+
+        // pick up the ACK
+        src.recv_msg(BlockingMode::Timeout(Duration::milliseconds(100)))
+            .unwrap();
+
+        let src_stats = src.stats();
+        assert_eq!(src_stats.packets_sent, 1);
+        assert_eq!(src_stats.bytes_sent, packet_len);
+        assert_eq!(src_stats.packets_received, 1);
+        assert_eq!(src_stats.bytes_received, HEADER_SIZE as u64);
+        assert!(src_stats.rtt.is_some());
+        assert_eq!(src.latency_graph().len(), 1);
+
+        let dst_stats = dst.stats();
+        assert_eq!(dst_stats.packets_received, 1);
+        assert_eq!(dst_stats.bytes_received, packet_len);
+        assert_eq!(dst_stats.packets_sent, 1);
+        assert_eq!(dst_stats.bytes_sent, HEADER_SIZE as u64);
+    }
+
+    fn reliable_packet(kind: MsgKind, sequence: u32, content: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.write_u16::<NetworkEndian>(kind as u16).unwrap();
+        packet
+            .write_u16::<NetworkEndian>((HEADER_SIZE + content.len()) as u16)
+            .unwrap();
+        packet.write_u32::<NetworkEndian>(sequence).unwrap();
+        packet.extend_from_slice(content);
+        packet
+    }
+
+    #[test]
+    fn test_framer_reassembles_reliable_message() {
+        let mut framer = Framer::new();
+        let mut msg = Vec::new();
+
+        let first = reliable_packet(MsgKind::Reliable, 0, &[1; MAX_DATAGRAM]);
+        match framer.handle_packet(&first, &mut msg).unwrap() {
+            Received::Reliable { complete, .. } => assert!(!complete),
+            _ => panic!("expected reliable chunk"),
+        }
+        assert!(msg.is_empty());
+
+        // an unreliable message in between comes out on its own
+        let mut sender = Framer::new();
+        let unreliable = sender.unreliable_packet(&[9, 9]).unwrap();
+        match framer.handle_packet(&unreliable, &mut msg).unwrap() {
+            Received::Unreliable => assert_eq!(msg, vec![9, 9]),
+            _ => panic!("expected unreliable message"),
+        }
+        msg.clear();
+
+        let last = reliable_packet(MsgKind::ReliableEom, 1, &[2; 10]);
+        match framer.handle_packet(&last, &mut msg).unwrap() {
+            Received::Reliable { complete, .. } => assert!(complete),
+            _ => panic!("expected reliable chunk"),
+        }
+
+        let mut expected = vec![1; MAX_DATAGRAM];
+        expected.extend_from_slice(&[2; 10]);
+        assert_eq!(msg, expected);
+    }
+
+    #[test]
+    fn test_framer_reliable_message_too_long() {
+        let mut framer = Framer::new();
+        let mut msg = Vec::new();
+
+        let chunks = MAX_NET_MESSAGE / MAX_DATAGRAM;
+        for sequence in 0..chunks as u32 {
+            let packet = reliable_packet(MsgKind::Reliable, sequence, &[0; MAX_DATAGRAM]);
+            framer.handle_packet(&packet, &mut msg).unwrap();
+        }
+
+        let packet = reliable_packet(MsgKind::ReliableEom, chunks as u32, &[0; MAX_DATAGRAM]);
+        match framer.handle_packet(&packet, &mut msg) {
+            Err(NetError::MessageTooLong(len)) => {
+                assert_eq!(len, (chunks + 1) * MAX_DATAGRAM)
+            }
+            _ => panic!("expected MessageTooLong"),
+        }
+        assert!(msg.is_empty());
+
+        // the resent chunk is acknowledged and dropped
+        match framer.handle_packet(&packet, &mut msg).unwrap() {
+            Received::Reliable { complete, .. } => assert!(!complete),
+            _ => panic!("expected a reliable chunk"),
+        }
+        assert!(msg.is_empty());
+
+        // and the next message arrives intact
+        let packet = reliable_packet(MsgKind::ReliableEom, chunks as u32 + 1, &[7; 4]);
+        match framer.handle_packet(&packet, &mut msg).unwrap() {
+            Received::Reliable { complete, .. } => assert!(complete),
+            _ => panic!("expected a reliable chunk"),
+        }
+        assert_eq!(msg, vec![7; 4]);
+    }
+
+    #[test]
+    fn test_qsocket_nonblocking_recv_keeps_partial_message() {
+        let (mut src, mut dst) = gen_qsocket_pair();
+
+        let message: Vec<u8> = (0..MAX_DATAGRAM * 2 + 5).map(|i| i as u8).collect();
+        src.begin_send_msg(&message).unwrap();
+
+        // only the first chunk is sent until it's acknowledged
+        let timeout = BlockingMode::Timeout(Duration::milliseconds(100));
+        assert!(dst.recv_msg(timeout).unwrap().is_empty());
+
+        // each ACK releases the next chunk
+        src.recv_msg(timeout).unwrap();
+        assert!(dst.recv_msg(timeout).unwrap().is_empty());
+        src.recv_msg(timeout).unwrap();
+
+        assert_eq!(dst.recv_msg(BlockingMode::Blocking).unwrap(), message);
+    }
 }
@@ -0,0 +1,640 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Port forwarding through the local router.
+//!
+//! A server behind a home router can ask the router to forward its port, so
+//! clients on the internet can reach it without any manual setup. NAT-PMP
+//! (RFC 6886) is tried first since it is quick and simple, then UPnP IGD,
+//! which more routers support. Mappings expire unless renewed, so a router
+//! that is never told to remove one still cleans up after a crash.
+
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+    time::Duration as StdDuration,
+};
+
+use crate::common::net::NetError;
+
+use byteorder::{BigEndian, ByteOrder};
+use chrono::Duration;
+
+pub const NAT_PMP_PORT: u16 = 5351;
+
+/// How long mappings last before they must be renewed, in seconds.
+pub const MAPPING_LIFETIME: u32 = 3600;
+
+/// How long to wait before trying again after a renewal fails, in seconds.
+pub const RENEW_RETRY_SECS: i64 = 60;
+
+const NAT_PMP_VERSION: u8 = 0;
+const NAT_PMP_OP_EXTERNAL_ADDRESS: u8 = 0;
+const NAT_PMP_OP_MAP_UDP: u8 = 1;
+
+// responses set the high bit of the request opcode
+const NAT_PMP_OP_RESPONSE: u8 = 0x80;
+
+// the first retry waits this long, doubling after each attempt (RFC 6886)
+const NAT_PMP_INITIAL_TIMEOUT_MS: u64 = 250;
+const NAT_PMP_ATTEMPTS: u32 = 3;
+
+const SSDP_IP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+const SSDP_TIMEOUT_MS: u64 = 2000;
+const UPNP_TIMEOUT_MS: u64 = 3000;
+const IGD_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const WAN_SERVICES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+/// Shown in the router's list of port mappings.
+const MAPPING_DESCRIPTION: &str = "Richter";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortMapProtocol {
+    NatPmp,
+    Upnp,
+}
+
+#[derive(Clone, Debug)]
+enum Router {
+    NatPmp {
+        gateway: SocketAddr,
+    },
+    Upnp {
+        control_url: String,
+        service: String,
+        local_ip: Ipv4Addr,
+    },
+}
+
+/// A UDP port forwarded by the local router.
+pub struct PortForward {
+    router: Router,
+    internal_port: u16,
+    external: SocketAddr,
+
+    // time until the mapping is renewed
+    remaining: Duration,
+
+    // the result of a renewal running in the background, as the external
+    // address and the lifetime granted
+    renewal: Option<Receiver<Result<(SocketAddr, u32), NetError>>>,
+}
+
+impl PortForward {
+    /// Ask the router to forward UDP traffic on `port` to this machine.
+    ///
+    /// The router is found at `gateway` if given, or at the default gateway
+    /// otherwise. The router may choose a different external port.
+    pub fn request(port: u16, gateway: Option<Ipv4Addr>) -> Result<PortForward, NetError> {
+        let nat_pmp_err = match gateway.or_else(default_gateway) {
+            Some(gateway) => {
+                let gateway = SocketAddr::from((gateway, NAT_PMP_PORT));
+                match nat_pmp_map(gateway, port, MAPPING_LIFETIME) {
+                    Ok((external, lifetime)) => {
+                        return Ok(PortForward::new(
+                            Router::NatPmp { gateway },
+                            port,
+                            external,
+                            lifetime,
+                        ))
+                    }
+                    Err(e) => e.to_string(),
+                }
+            }
+            None => String::from("no default gateway"),
+        };
+        debug!("NAT-PMP failed ({}), trying UPnP", nat_pmp_err);
+
+        let (control_url, service, local_ip) = upnp_discover()?;
+        let external = upnp_map(&control_url, &service, local_ip, port, MAPPING_LIFETIME)?;
+        Ok(PortForward::new(
+            Router::Upnp {
+                control_url,
+                service,
+                local_ip,
+            },
+            port,
+            external,
+            MAPPING_LIFETIME,
+        ))
+    }
+
+    fn new(router: Router, internal_port: u16, external: SocketAddr, lifetime: u32) -> PortForward {
+        PortForward {
+            router,
+            internal_port,
+            external,
+            remaining: renew_after(lifetime),
+            renewal: None,
+        }
+    }
+
+    pub fn protocol(&self) -> PortMapProtocol {
+        match self.router {
+            Router::NatPmp { .. } => PortMapProtocol::NatPmp,
+            Router::Upnp { .. } => PortMapProtocol::Upnp,
+        }
+    }
+
+    /// The address clients on the internet should connect to.
+    pub fn external_addr(&self) -> SocketAddr {
+        self.external
+    }
+
+    /// Renew the mapping once half its lifetime has passed.
+    ///
+    /// The router is asked on a background thread, so this never blocks. If
+    /// the renewal fails, the error is returned and the renewal is tried again
+    /// after `RENEW_RETRY_SECS`.
+    pub fn frame(&mut self, frame_time: Duration) -> Result<(), NetError> {
+        if let Some(ref renewal) = self.renewal {
+            let result = match renewal.try_recv() {
+                Ok(r) => r,
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => {
+                    Err(NetError::with_msg("Port mapping renewal stopped"))
+                }
+            };
+            self.renewal = None;
+
+            return match result {
+                Ok((external, lifetime)) => {
+                    self.external = external;
+                    self.remaining = renew_after(lifetime);
+                    Ok(())
+                }
+                Err(e) => {
+                    self.remaining = Duration::seconds(RENEW_RETRY_SECS);
+                    Err(e)
+                }
+            };
+        }
+
+        self.remaining = self.remaining - frame_time;
+        if self.remaining > Duration::zero() {
+            return Ok(());
+        }
+
+        debug!("Renewing port mapping for {}", self.internal_port);
+        let router = self.router.clone();
+        let port = self.internal_port;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = sender.send(map_port(&router, port, MAPPING_LIFETIME));
+        });
+        self.renewal = Some(receiver);
+
+        Ok(())
+    }
+
+    /// Ask the router to stop forwarding the port.
+    pub fn remove(self) -> Result<(), NetError> {
+        match self.router {
+            // a lifetime of zero deletes the mapping
+            Router::NatPmp { gateway } => nat_pmp_map(gateway, self.internal_port, 0).map(|_| ()),
+            Router::Upnp {
+                ref control_url,
+                ref service,
+                ..
+            } => {
+                let args = [
+                    ("NewRemoteHost", String::new()),
+                    ("NewExternalPort", self.external.port().to_string()),
+                    ("NewProtocol", String::from("UDP")),
+                ];
+                soap_call(control_url, service, "DeletePortMapping", &args).map(|_| ())
+            }
+        }
+    }
+}
+
+// returns the external address and the lifetime granted by the router
+fn map_port(router: &Router, port: u16, lifetime: u32) -> Result<(SocketAddr, u32), NetError> {
+    match *router {
+        Router::NatPmp { gateway } => nat_pmp_map(gateway, port, lifetime),
+        Router::Upnp {
+            ref control_url,
+            ref service,
+            local_ip,
+        } => {
+            let external = upnp_map(control_url, service, local_ip, port, lifetime)?;
+            Ok((external, lifetime))
+        }
+    }
+}
+
+fn renew_after(lifetime: u32) -> Duration {
+    Duration::seconds(lifetime as i64 / 2)
+}
+
+/// Find the default gateway from the system routing table.
+///
+/// Only Linux is supported; elsewhere the gateway must be given explicitly.
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    std::fs::read_to_string("/proc/net/route")
+        .ok()
+        .and_then(|table| parse_route_table(&table))
+}
+
+// finds the gateway of the default route in the format of /proc/net/route
+fn parse_route_table(table: &str) -> Option<Ipv4Addr> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [_iface, "00000000", gateway, ..] => {
+                // stored in host byte order, which is little-endian on every
+                // platform that has /proc/net/route in practice
+                let gateway = u32::from_str_radix(gateway, 16).ok()?;
+                Some(Ipv4Addr::from(gateway.swap_bytes()))
+            }
+            _ => None,
+        }
+    })
+}
+
+fn nat_pmp_map_request(port: u16, lifetime: u32) -> [u8; 12] {
+    let mut request = [0; 12];
+    request[0] = NAT_PMP_VERSION;
+    request[1] = NAT_PMP_OP_MAP_UDP;
+    BigEndian::write_u16(&mut request[4..6], port);
+    // a lifetime of zero also needs an external port of zero
+    BigEndian::write_u16(&mut request[6..8], if lifetime == 0 { 0 } else { port });
+    BigEndian::write_u32(&mut request[8..12], lifetime);
+    request
+}
+
+// checks the header of a NAT-PMP response to `op`
+fn check_nat_pmp_response(response: &[u8], op: u8, len: usize) -> Result<(), NetError> {
+    if response.len() < len
+        || response[0] != NAT_PMP_VERSION
+        || response[1] != op | NAT_PMP_OP_RESPONSE
+    {
+        return Err(NetError::InvalidData(String::from("NAT-PMP response")));
+    }
+
+    match BigEndian::read_u16(&response[2..4]) {
+        0 => Ok(()),
+        code => Err(NetError::with_msg(format!(
+            "NAT-PMP request refused (result code {})",
+            code
+        ))),
+    }
+}
+
+fn parse_nat_pmp_external(response: &[u8]) -> Result<Ipv4Addr, NetError> {
+    check_nat_pmp_response(response, NAT_PMP_OP_EXTERNAL_ADDRESS, 12)?;
+    Ok(Ipv4Addr::new(
+        response[8],
+        response[9],
+        response[10],
+        response[11],
+    ))
+}
+
+// returns the external port and lifetime of the mapping
+fn parse_nat_pmp_map(response: &[u8]) -> Result<(u16, u32), NetError> {
+    check_nat_pmp_response(response, NAT_PMP_OP_MAP_UDP, 16)?;
+    Ok((
+        BigEndian::read_u16(&response[10..12]),
+        BigEndian::read_u32(&response[12..16]),
+    ))
+}
+
+// sends `request` to `gateway` until it answers
+fn nat_pmp_exchange(gateway: SocketAddr, request: &[u8]) -> Result<Vec<u8>, NetError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let mut buf = [0; 16];
+    let mut timeout = NAT_PMP_INITIAL_TIMEOUT_MS;
+
+    for _ in 0..NAT_PMP_ATTEMPTS {
+        socket.send_to(request, gateway)?;
+        socket.set_read_timeout(Some(StdDuration::from_millis(timeout)))?;
+
+        loop {
+            match socket.recv_from(&mut buf) {
+                // anyone else could be spoofing the router
+                Ok((len, remote)) if remote == gateway => return Ok(buf[..len].to_vec()),
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    break
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        timeout *= 2;
+    }
+
+    Err(NetError::with_msg(format!(
+        "No NAT-PMP reply from {}",
+        gateway
+    )))
+}
+
+// returns the external address and the lifetime granted by the router
+fn nat_pmp_map(
+    gateway: SocketAddr,
+    port: u16,
+    lifetime: u32,
+) -> Result<(SocketAddr, u32), NetError> {
+    let (external_port, lifetime) = parse_nat_pmp_map(&nat_pmp_exchange(
+        gateway,
+        &nat_pmp_map_request(port, lifetime),
+    )?)?;
+    let external_ip = parse_nat_pmp_external(&nat_pmp_exchange(
+        gateway,
+        &[NAT_PMP_VERSION, NAT_PMP_OP_EXTERNAL_ADDRESS],
+    )?)?;
+
+    Ok((SocketAddr::from((external_ip, external_port)), lifetime))
+}
+
+// returns the LOCATION header of an SSDP response
+fn ssdp_location(response: &str) -> Option<&str> {
+    response.lines().find_map(|line| {
+        let colon = line.find(':')?;
+        match line[..colon].trim().eq_ignore_ascii_case("location") {
+            true => Some(line[colon + 1..].trim()),
+            false => None,
+        }
+    })
+}
+
+// returns the text of the first `tag` element in `xml`
+fn xml_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&close)?;
+    Some(xml[start..end].trim())
+}
+
+// returns the type and control URL of the first WAN connection service in an
+// IGD device description
+fn find_wan_service(description: &str) -> Option<(String, String)> {
+    description.split("<service>").skip(1).find_map(|service| {
+        let service_type = xml_text(service, "serviceType")?;
+        if !WAN_SERVICES.contains(&service_type) {
+            return None;
+        }
+
+        let control_url = xml_text(service, "controlURL")?;
+        Some((service_type.to_owned(), control_url.to_owned()))
+    })
+}
+
+// resolves a URL from a device description against the description's location
+fn resolve_url(location: &str, url: &str) -> String {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        return url.to_owned();
+    }
+
+    // keep the scheme and authority
+    let authority_end = location
+        .find("://")
+        .and_then(|scheme| location[scheme + 3..].find('/').map(|i| scheme + 3 + i))
+        .unwrap_or_else(|| location.len());
+    let slash = if url.starts_with('/') { "" } else { "/" };
+    format!("{}{}{}", &location[..authority_end], slash, url)
+}
+
+// returns the control URL, service type and our address on the router's network
+fn upnp_discover() -> Result<(String, String, Ipv4Addr), NetError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_read_timeout(Some(StdDuration::from_millis(SSDP_TIMEOUT_MS)))?;
+
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\n\
+         HOST: {}:{}\r\n\
+         MAN: \"ssdp:discover\"\r\n\
+         MX: 2\r\n\
+         ST: {}\r\n\r\n",
+        SSDP_IP, SSDP_PORT, IGD_DEVICE
+    );
+    socket.send_to(search.as_bytes(), (SSDP_IP, SSDP_PORT))?;
+
+    let mut buf = [0; 2048];
+    let (len, remote) = match socket.recv_from(&mut buf) {
+        Ok(r) => r,
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            return Err(NetError::with_msg("No UPnP router found"))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let response = String::from_utf8_lossy(&buf[..len]);
+    let location = ssdp_location(&response)
+        .ok_or_else(|| NetError::InvalidData(String::from("SSDP response without location")))?;
+
+    let description = ureq::get(location)
+        .timeout(StdDuration::from_millis(UPNP_TIMEOUT_MS))
+        .call()
+        .map_err(|e| NetError::with_msg(format!("Couldn't fetch {}: {}", location, e)))?
+        .into_string()?;
+    let (service, control_url) = find_wan_service(&description)
+        .ok_or_else(|| NetError::with_msg(format!("No WAN connection service at {}", location)))?;
+
+    // the router needs our address on its side to forward to
+    let local_ip = {
+        let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        probe.connect(remote)?;
+        match probe.local_addr()?.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => return Err(NetError::with_msg("UPnP router isn't on IPv4")),
+        }
+    };
+
+    Ok((resolve_url(location, &control_url), service, local_ip))
+}
+
+fn soap_call(
+    control_url: &str,
+    service: &str,
+    action: &str,
+    args: &[(&str, String)],
+) -> Result<String, NetError> {
+    let args: String = args
+        .iter()
+        .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+        .collect();
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>",
+        action, service, args
+    );
+
+    let response = ureq::post(control_url)
+        .timeout(StdDuration::from_millis(UPNP_TIMEOUT_MS))
+        .set("Content-Type", "text/xml; charset=\"utf-8\"")
+        .set("SOAPAction", &format!("\"{}#{}\"", service, action))
+        .send_string(&body)
+        .map_err(|e| NetError::with_msg(format!("UPnP {} failed: {}", action, e)))?;
+
+    Ok(response.into_string()?)
+}
+
+// returns the external address of the new mapping
+fn upnp_map(
+    control_url: &str,
+    service: &str,
+    local_ip: Ipv4Addr,
+    port: u16,
+    lifetime: u32,
+) -> Result<SocketAddr, NetError> {
+    let args = [
+        ("NewRemoteHost", String::new()),
+        ("NewExternalPort", port.to_string()),
+        ("NewProtocol", String::from("UDP")),
+        ("NewInternalPort", port.to_string()),
+        ("NewInternalClient", local_ip.to_string()),
+        ("NewEnabled", String::from("1")),
+        (
+            "NewPortMappingDescription",
+            String::from(MAPPING_DESCRIPTION),
+        ),
+        ("NewLeaseDuration", lifetime.to_string()),
+    ];
+    soap_call(control_url, service, "AddPortMapping", &args)?;
+
+    let response = soap_call(control_url, service, "GetExternalIPAddress", &[])?;
+    let external_ip: Ipv4Addr = xml_text(&response, "NewExternalIPAddress")
+        .and_then(|ip| ip.parse().ok())
+        .ok_or_else(|| NetError::InvalidData(String::from("UPnP external address")))?;
+
+    Ok(SocketAddr::from((external_ip, port)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn test_parse_route_table() {
+        let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                     eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+                     eth0\t00000000\t0100A8C0\t0003\t0\t0\t0\t00000000\n";
+        assert_eq!(
+            parse_route_table(table),
+            Some(Ipv4Addr::new(192, 168, 0, 1))
+        );
+        assert_eq!(parse_route_table("Iface\tDestination\n"), None);
+    }
+
+    #[test]
+    fn test_nat_pmp_map() {
+        // a router that maps every port to 40000 for an hour
+        let router = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let gateway = router.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut buf = [0; 16];
+            for _ in 0..2 {
+                let (len, remote) = router.recv_from(&mut buf).unwrap();
+                let reply = match buf[1] {
+                    NAT_PMP_OP_MAP_UDP => {
+                        assert_eq!(len, 12);
+                        let mut reply = vec![0, 0x81, 0, 0, 0, 0, 0, 1];
+                        reply.extend_from_slice(&buf[4..6]);
+                        reply.extend_from_slice(&40000u16.to_be_bytes());
+                        reply.extend_from_slice(&3600u32.to_be_bytes());
+                        reply
+                    }
+                    _ => vec![0, 0x80, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7],
+                };
+                router.send_to(&reply, remote).unwrap();
+            }
+        });
+
+        let (external, lifetime) = nat_pmp_map(gateway, 26000, MAPPING_LIFETIME).unwrap();
+        handle.join().unwrap();
+        assert_eq!(external, "203.0.113.7:40000".parse().unwrap());
+        assert_eq!(lifetime, 3600);
+    }
+
+    #[test]
+    fn test_renew_retry() {
+        // a router that refuses every request
+        let router = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let gateway = router.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 16];
+            let (_, remote) = router.recv_from(&mut buf).unwrap();
+            let reply = [0, 0x81, 0, 2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+            router.send_to(&reply, remote).unwrap();
+        });
+
+        let external = "203.0.113.7:40000".parse().unwrap();
+        let mut forward = PortForward::new(Router::NatPmp { gateway }, 26000, external, 0);
+        assert!(forward.frame(Duration::milliseconds(16)).is_ok());
+
+        let mut result = Ok(());
+        for _ in 0..100 {
+            result = forward.frame(Duration::milliseconds(16));
+            if forward.renewal.is_none() {
+                break;
+            }
+            thread::sleep(StdDuration::from_millis(10));
+        }
+        assert!(result.is_err());
+
+        // the failed renewal isn't repeated until the retry interval passes
+        assert_eq!(forward.remaining, Duration::seconds(RENEW_RETRY_SECS));
+        assert!(forward.frame(Duration::milliseconds(16)).is_ok());
+        assert!(forward.renewal.is_none());
+    }
+
+    #[test]
+    fn test_nat_pmp_refused() {
+        let response = [0, 0x81, 0, 2, 0, 0, 0, 1, 0x65, 0x90, 0, 0, 0, 0, 0, 0];
+        assert!(parse_nat_pmp_map(&response).is_err());
+        assert!(parse_nat_pmp_map(&response[..8]).is_err());
+    }
+
+    #[test]
+    fn test_upnp_description() {
+        let response = "HTTP/1.1 200 OK\r\n\
+                        CACHE-CONTROL: max-age=120\r\n\
+                        Location: http://192.168.0.1:5000/rootDesc.xml\r\n\r\n";
+        let location = ssdp_location(response).unwrap();
+        assert_eq!(location, "http://192.168.0.1:5000/rootDesc.xml");
+
+        let description = "<root><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+            <controlURL>/ctl/L3F</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+            <controlURL>/ctl/IPConn</controlURL></service>\
+            </serviceList></device></root>";
+        let (service, control_url) = find_wan_service(description).unwrap();
+        assert_eq!(service, WAN_SERVICES[0]);
+        assert_eq!(
+            resolve_url(location, &control_url),
+            "http://192.168.0.1:5000/ctl/IPConn"
+        );
+    }
+}
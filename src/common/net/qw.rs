@@ -0,0 +1,2804 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! QuakeWorld network protocol.
+//!
+//! QuakeWorld servers speak a different protocol from NetQuake servers. The
+//! connection handshake is done with text-based out-of-band packets, the
+//! in-game channel uses sequenced packets rather than separate reliable and
+//! unreliable messages, and entities are sent as delta-compressed lists
+//! relative to an earlier frame acknowledged by the client.
+
+use std::{
+    fmt,
+    io::{BufRead, BufReader, Cursor, ErrorKind, Read},
+    mem,
+    net::{SocketAddr, UdpSocket},
+};
+
+use crate::common::{
+    net::{
+        read_angle, read_coord, read_coord_vector3, read_timeout, write_angle, write_coord,
+        write_coord_vector3, NetError, TempEntity, MAX_MESSAGE,
+    },
+    util,
+};
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use cgmath::{Deg, Vector3, Zero};
+use chrono::{DateTime, Duration, Utc};
+use md4::{Digest, Md4};
+use num::FromPrimitive;
+
+pub const PROTOCOL_VERSION: i32 = 28;
+pub const DEFAULT_PORT: u16 = 27500;
+
+/// Maximum length of a client's userinfo string.
+pub const MAX_INFO_STRING: usize = 196;
+
+/// Maximum length of the server's serverinfo string.
+pub const MAX_SERVERINFO_STRING: usize = 512;
+
+/// Number of frames the client keeps around for delta decompression.
+pub const UPDATE_BACKUP: usize = 64;
+pub const UPDATE_MASK: usize = UPDATE_BACKUP - 1;
+
+/// Maximum number of entities in a single packet entity list.
+pub const MAX_PACKET_ENTITIES: usize = 64;
+
+/// Maximum number of players on a server.
+pub const MAX_CLIENTS: usize = 32;
+
+/// Maximum size of an in-game packet.
+pub const MAX_PACKET: usize = 1450;
+
+// out-of-band packets begin with a sequence number of -1
+const OOB_HEADER: i32 = -1;
+
+// out-of-band response codes
+const S2C_CHALLENGE: u8 = b'c';
+const S2C_CONNECTION: u8 = b'j';
+const A2C_PRINT: u8 = b'n';
+const A2A_PING: u8 = b'k';
+const A2A_ACK: u8 = b'l';
+
+// high bit of the sequence and acknowledgement fields of the netchan header
+const NETCHAN_RELIABLE_BIT: u32 = 1 << 31;
+
+// the low 9 bits of an entity delta header hold the entity number
+const DELTA_ENTITY_MASK: u16 = 0x01FF;
+
+// client command codes
+const CLC_NOP: u8 = 1;
+const CLC_MOVE: u8 = 3;
+const CLC_STRINGCMD: u8 = 4;
+
+// only this much of a move command is covered by its checksum
+const MOVE_CHECKSUM_LEN: usize = 60;
+
+// the high bits of a sound's channel field flag the optional fields
+const SND_VOLUME: u16 = 1 << 15;
+const SND_ATTENUATION: u16 = 1 << 14;
+
+// temp entity codes whose format differs from NetQuake's
+const TE_GUNSHOT: u8 = 2;
+const TE_BLOOD: u8 = 12;
+const TE_LIGHTNING_BLOOD: u8 = 13;
+
+// the entities, visibility, nodes and leaves lumps are left out of the map
+// checksum
+const BSP_LUMP_COUNT: usize = 15;
+const BSP_UNCHECKED_LUMPS: [usize; 4] = [0, 4, 5, 10];
+
+bitflags! {
+    /// Bits describing which fields are present in an entity delta.
+    ///
+    /// The low 9 bits of the first word are used for the entity number, so the
+    /// flags start at bit 9. The bits in the low byte are sent in a second
+    /// byte if `MORE_BITS` is set.
+    pub struct EntityDeltaFlags: u16 {
+        const ANGLE_X = 1 << 0;
+        const ANGLE_Z = 1 << 1;
+        const MODEL = 1 << 2;
+        const COLORMAP = 1 << 3;
+        const SKIN = 1 << 4;
+        const EFFECTS = 1 << 5;
+        const SOLID = 1 << 6;
+
+        const ORIGIN_X = 1 << 9;
+        const ORIGIN_Y = 1 << 10;
+        const ORIGIN_Z = 1 << 11;
+        const ANGLE_Y = 1 << 12;
+        const FRAME = 1 << 13;
+        const REMOVE = 1 << 14;
+        const MORE_BITS = 1 << 15;
+    }
+}
+
+bitflags! {
+    /// Bits describing which fields are present in a player update.
+    pub struct PlayerInfoFlags: u16 {
+        const MSEC = 1 << 0;
+        const COMMAND = 1 << 1;
+        const VELOCITY_X = 1 << 2;
+        const VELOCITY_Y = 1 << 3;
+        const VELOCITY_Z = 1 << 4;
+        const MODEL = 1 << 5;
+        const SKIN = 1 << 6;
+        const EFFECTS = 1 << 7;
+        const WEAPON_FRAME = 1 << 8;
+        const DEAD = 1 << 9;
+        const GIB = 1 << 10;
+    }
+}
+
+bitflags! {
+    /// Bits describing which fields of a movement command are nonzero.
+    pub struct UserCmdFlags: u8 {
+        const ANGLE_X = 1 << 0;
+        const ANGLE_Z = 1 << 1;
+        const ANGLE_Y = 1 << 2;
+        const FORWARD = 1 << 3;
+        const SIDE = 1 << 4;
+        const UP = 1 << 5;
+        const BUTTONS = 1 << 6;
+        const IMPULSE = 1 << 7;
+    }
+}
+
+/// A client's userinfo (or the server's serverinfo) string.
+///
+/// Info strings are sets of key-value pairs formatted as
+/// `\key1\value1\key2\value2`. Neither keys nor values may contain backslashes
+/// or double quotes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Userinfo {
+    pairs: Vec<(String, String)>,
+    max_len: usize,
+}
+
+impl Userinfo {
+    pub fn new() -> Userinfo {
+        Userinfo {
+            pairs: Vec::new(),
+            max_len: MAX_INFO_STRING,
+        }
+    }
+
+    /// Parses an info string received from the network.
+    pub fn parse<S>(info: S) -> Result<Userinfo, NetError>
+    where
+        S: AsRef<str>,
+    {
+        let info = info.as_ref();
+        let info = info.strip_prefix('\\').unwrap_or(info);
+
+        let mut pairs = Vec::new();
+        if !info.is_empty() {
+            let mut fields = info.split('\\');
+            while let Some(key) = fields.next() {
+                let value = fields.next().ok_or_else(|| {
+                    NetError::InvalidData(format!("info key \"{}\" has no value", key))
+                })?;
+                pairs.push((key.to_owned(), value.to_owned()));
+            }
+        }
+
+        Ok(Userinfo {
+            pairs,
+            max_len: MAX_SERVERINFO_STRING,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.pairs
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Sets the value of `key`, adding it if it doesn't exist.
+    ///
+    /// Setting an empty value removes the key.
+    pub fn set<K, V>(&mut self, key: K, value: V) -> Result<(), NetError>
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let (key, value) = (key.as_ref(), value.as_ref());
+
+        for s in &[key, value] {
+            if s.contains('\\') || s.contains('"') {
+                return Err(NetError::InvalidData(format!(
+                    "info strings can't contain '\\' or '\"' ({})",
+                    s
+                )));
+            }
+        }
+
+        if value.is_empty() {
+            self.remove(key);
+            return Ok(());
+        }
+
+        let mut new = self.clone();
+        match new.pairs.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_owned(),
+            None => new.pairs.push((key.to_owned(), value.to_owned())),
+        }
+
+        if new.to_string().len() >= self.max_len {
+            return Err(NetError::with_msg(format!(
+                "info string length exceeded setting {}",
+                key
+            )));
+        }
+
+        self.pairs = new.pairs;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.pairs.retain(|(k, _)| k != key);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.pairs.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+impl fmt::Display for Userinfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (k, v) in self.pairs.iter() {
+            write!(f, "\\{}\\{}", k, v)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// An out-of-band request sent from the client to a server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OobRequest {
+    GetChallenge,
+    Connect {
+        protocol: i32,
+        qport: u16,
+        challenge: i32,
+        userinfo: Userinfo,
+    },
+    Status,
+    Ping,
+}
+
+impl OobRequest {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, NetError> {
+        let mut writer = Cursor::new(Vec::new());
+        writer.write_i32::<LittleEndian>(OOB_HEADER)?;
+
+        let text = match *self {
+            OobRequest::GetChallenge => String::from("getchallenge\n"),
+            OobRequest::Connect {
+                protocol,
+                qport,
+                challenge,
+                ref userinfo,
+            } => format!(
+                "connect {} {} {} \"{}\"\n",
+                protocol, qport, challenge, userinfo
+            ),
+            OobRequest::Status => String::from("status\n"),
+            OobRequest::Ping => String::from("ping\n"),
+        };
+
+        writer
+            .get_mut()
+            .extend_from_slice(&util::quake_string_to_bytes(&text));
+        writer.get_mut().push(0);
+
+        Ok(writer.into_inner())
+    }
+}
+
+/// An out-of-band response sent from a server to the client.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OobResponse {
+    /// The challenge number to use in a `Connect` request.
+    Challenge(i32),
+
+    /// The server accepted the connection.
+    Accept,
+
+    /// A message to be displayed to the user. Connection rejections are
+    /// sent this way.
+    Print(String),
+
+    Ping,
+    Ack,
+}
+
+impl OobResponse {
+    /// Parses an out-of-band packet, including the header.
+    pub fn from_bytes(packet: &[u8]) -> Result<OobResponse, NetError> {
+        let mut reader = BufReader::new(packet);
+        if reader.read_i32::<LittleEndian>()? != OOB_HEADER {
+            return Err(NetError::InvalidData(String::from(
+                "out-of-band packet without header",
+            )));
+        }
+
+        let code = reader.read_u8()?;
+        let text = match util::read_cstring(&mut reader) {
+            Ok(t) => t,
+            Err(e) => return Err(NetError::with_msg(format!("{}", e))),
+        };
+
+        Ok(match code {
+            S2C_CHALLENGE => match text.trim().parse() {
+                Ok(c) => OobResponse::Challenge(c),
+                Err(_) => {
+                    return Err(NetError::InvalidData(format!(
+                        "challenge \"{}\"",
+                        text.trim()
+                    )))
+                }
+            },
+            S2C_CONNECTION => OobResponse::Accept,
+            A2C_PRINT => OobResponse::Print(text),
+            A2A_PING => OobResponse::Ping,
+            A2A_ACK => OobResponse::Ack,
+            c => {
+                return Err(NetError::InvalidData(format!(
+                    "out-of-band response code {}",
+                    c
+                )))
+            }
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, NetError> {
+        let mut writer = Cursor::new(Vec::new());
+        writer.write_i32::<LittleEndian>(OOB_HEADER)?;
+
+        let (code, text) = match *self {
+            OobResponse::Challenge(c) => (S2C_CHALLENGE, c.to_string()),
+            OobResponse::Accept => (S2C_CONNECTION, String::new()),
+            OobResponse::Print(ref t) => (A2C_PRINT, t.clone()),
+            OobResponse::Ping => (A2A_PING, String::new()),
+            OobResponse::Ack => (A2A_ACK, String::new()),
+        };
+
+        writer.write_u8(code)?;
+        writer
+            .get_mut()
+            .extend_from_slice(&util::quake_string_to_bytes(&text));
+        writer.get_mut().push(0);
+
+        Ok(writer.into_inner())
+    }
+}
+
+/// Performs the out-of-band connection handshake with a QuakeWorld server.
+///
+/// On success, the socket can be used for the in-game channel. A rejected
+/// connection is returned as an error carrying the server's message.
+pub fn handshake(
+    socket: &UdpSocket,
+    remote: SocketAddr,
+    qport: u16,
+    userinfo: &Userinfo,
+    timeout: Duration,
+) -> Result<(), NetError> {
+    socket.set_read_timeout(Some(read_timeout(timeout)))?;
+
+    let result = (|| {
+        socket.send_to(&OobRequest::GetChallenge.to_bytes()?, remote)?;
+        let challenge = match recv_oob(socket, remote)? {
+            OobResponse::Challenge(c) => c,
+            OobResponse::Print(msg) => return Err(NetError::with_msg(msg)),
+            r => {
+                return Err(NetError::InvalidData(format!(
+                    "expected challenge, got {:?}",
+                    r
+                )))
+            }
+        };
+
+        socket.send_to(
+            &OobRequest::Connect {
+                protocol: PROTOCOL_VERSION,
+                qport,
+                challenge,
+                userinfo: userinfo.clone(),
+            }
+            .to_bytes()?,
+            remote,
+        )?;
+
+        match recv_oob(socket, remote)? {
+            OobResponse::Accept => Ok(()),
+            OobResponse::Print(msg) => Err(NetError::with_msg(msg)),
+            r => Err(NetError::InvalidData(format!(
+                "expected connection, got {:?}",
+                r
+            ))),
+        }
+    })();
+
+    socket.set_read_timeout(None)?;
+    result
+}
+
+fn recv_oob(socket: &UdpSocket, remote: SocketAddr) -> Result<OobResponse, NetError> {
+    let mut recv_buf = [0u8; MAX_MESSAGE];
+
+    loop {
+        let (len, addr) = match socket.recv_from(&mut recv_buf) {
+            Err(e) => match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                    return Err(NetError::with_msg("No response from server"))
+                }
+                _ => return Err(NetError::from(e)),
+            },
+            Ok(ret) => ret,
+        };
+
+        // ignore packets from anyone else
+        if addr != remote {
+            continue;
+        }
+
+        return OobResponse::from_bytes(&recv_buf[..len]);
+    }
+}
+
+/// The header of an in-game QuakeWorld packet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NetchanHeader {
+    /// The sequence number of this packet.
+    pub sequence: u32,
+
+    /// Whether this packet contains reliable data.
+    pub reliable: bool,
+
+    /// The sequence number of the last packet received from the other side.
+    pub ack: u32,
+
+    /// The reliable bit of the last packet received from the other side.
+    pub ack_reliable: bool,
+
+    /// Sent by clients to identify the connection when a NAT rewrites the
+    /// source port.
+    pub qport: Option<u16>,
+}
+
+impl NetchanHeader {
+    /// Reads a packet header. `qport` should be true for packets sent by the
+    /// client.
+    pub fn read<R>(reader: &mut R, qport: bool) -> Result<NetchanHeader, NetError>
+    where
+        R: ReadBytesExt,
+    {
+        let sequence = reader.read_u32::<LittleEndian>()?;
+        let ack = reader.read_u32::<LittleEndian>()?;
+        let qport = match qport {
+            true => Some(reader.read_u16::<LittleEndian>()?),
+            false => None,
+        };
+
+        Ok(NetchanHeader {
+            sequence: sequence & !NETCHAN_RELIABLE_BIT,
+            reliable: sequence & NETCHAN_RELIABLE_BIT != 0,
+            ack: ack & !NETCHAN_RELIABLE_BIT,
+            ack_reliable: ack & NETCHAN_RELIABLE_BIT != 0,
+            qport,
+        })
+    }
+
+    pub fn write<W>(&self, writer: &mut W) -> Result<(), NetError>
+    where
+        W: WriteBytesExt,
+    {
+        let reliable_bit = |set| match set {
+            true => NETCHAN_RELIABLE_BIT,
+            false => 0,
+        };
+
+        writer.write_u32::<LittleEndian>(self.sequence | reliable_bit(self.reliable))?;
+        writer.write_u32::<LittleEndian>(self.ack | reliable_bit(self.ack_reliable))?;
+        if let Some(q) = self.qport {
+            writer.write_u16::<LittleEndian>(q)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A command sent from the client to the server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QwClientCmd {
+    NoOp,
+
+    /// The player's movement.
+    ///
+    /// The two previous commands are resent with the current one in case the
+    /// packets carrying them were lost, so `cmds` is ordered oldest first.
+    Move {
+        /// The sequence number of the packet carrying the command, which the
+        /// checksum depends on.
+        sequence: u32,
+        /// The percentage of recent packets that were lost.
+        loss: u8,
+        cmds: [UserCmd; 3],
+    },
+
+    StringCmd {
+        cmd: String,
+    },
+}
+
+impl QwClientCmd {
+    pub fn serialize<W>(&self, writer: &mut W) -> Result<(), NetError>
+    where
+        W: WriteBytesExt,
+    {
+        match *self {
+            QwClientCmd::NoOp => writer.write_u8(CLC_NOP)?,
+            QwClientCmd::Move {
+                sequence,
+                loss,
+                ref cmds,
+            } => {
+                let mut body = vec![loss];
+                cmds[0].write_delta(&UserCmd::NULL, &mut body)?;
+                cmds[1].write_delta(&cmds[0], &mut body)?;
+                cmds[2].write_delta(&cmds[1], &mut body)?;
+
+                writer.write_u8(CLC_MOVE)?;
+                writer.write_u8(move_checksum(&body, sequence))?;
+                writer.write_all(&body)?;
+            }
+            QwClientCmd::StringCmd { ref cmd } => {
+                writer.write_u8(CLC_STRINGCMD)?;
+                write_string(writer, cmd)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The client end of the in-game channel.
+///
+/// Every packet carries the sequence numbers of both directions. Reliable data
+/// goes out one message at a time, and a message is resent until the server
+/// acknowledges it; anything queued meanwhile waits for the next one.
+pub struct QwChannel {
+    socket: UdpSocket,
+    remote: SocketAddr,
+    qport: u16,
+
+    outgoing_sequence: u32,
+    incoming_sequence: u32,
+    incoming_acknowledged: u32,
+    incoming_reliable_acknowledged: bool,
+    incoming_reliable_sequence: bool,
+
+    // reliable data waiting for the next message
+    message: Vec<u8>,
+
+    // the reliable message in flight
+    reliable_buf: Vec<u8>,
+    reliable_sequence: bool,
+    last_reliable_sequence: u32,
+
+    last_send_time: DateTime<Utc>,
+    last_recv_time: DateTime<Utc>,
+    recv_buf: [u8; MAX_MESSAGE],
+}
+
+impl QwChannel {
+    /// Wraps a socket that has completed the handshake with `remote`.
+    pub fn new(socket: UdpSocket, remote: SocketAddr, qport: u16) -> Result<QwChannel, NetError> {
+        socket.set_nonblocking(true)?;
+
+        Ok(QwChannel {
+            socket,
+            remote,
+            qport,
+            outgoing_sequence: 1,
+            incoming_sequence: 0,
+            incoming_acknowledged: 0,
+            incoming_reliable_acknowledged: false,
+            incoming_reliable_sequence: false,
+            message: Vec::new(),
+            reliable_buf: Vec::new(),
+            reliable_sequence: false,
+            last_reliable_sequence: 0,
+            last_send_time: Utc::now(),
+            last_recv_time: Utc::now(),
+            recv_buf: [0; MAX_MESSAGE],
+        })
+    }
+
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    /// Returns the sequence number of the last packet received.
+    pub fn incoming_sequence(&self) -> u32 {
+        self.incoming_sequence
+    }
+
+    /// Returns the sequence number the next packet will be sent with.
+    pub fn outgoing_sequence(&self) -> u32 {
+        self.outgoing_sequence
+    }
+
+    pub fn time_since_send(&self) -> Duration {
+        Utc::now() - self.last_send_time
+    }
+
+    pub fn time_since_recv(&self) -> Duration {
+        Utc::now() - self.last_recv_time
+    }
+
+    /// Queues reliable data to be sent.
+    pub fn send_reliable(&mut self, data: &[u8]) -> Result<(), NetError> {
+        if self.message.len() + data.len() > MAX_PACKET {
+            return Err(NetError::with_msg("reliable message overflow"));
+        }
+
+        self.message.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Sends a packet with any reliable data due, followed by `unreliable` if
+    /// it fits.
+    pub fn transmit(&mut self, unreliable: &[u8]) -> Result<(), NetError> {
+        // the server has answered packets sent after our reliable message
+        // without acknowledging it, so it was lost
+        let mut send_reliable = self.incoming_acknowledged > self.last_reliable_sequence
+            && self.incoming_reliable_acknowledged != self.reliable_sequence;
+
+        if self.reliable_buf.is_empty() && !self.message.is_empty() {
+            self.reliable_buf = mem::take(&mut self.message);
+            self.reliable_sequence = !self.reliable_sequence;
+            send_reliable = true;
+        }
+
+        let mut packet = Vec::with_capacity(MAX_PACKET);
+        NetchanHeader {
+            sequence: self.outgoing_sequence,
+            reliable: send_reliable,
+            ack: self.incoming_sequence,
+            ack_reliable: self.incoming_reliable_sequence,
+            qport: Some(self.qport),
+        }
+        .write(&mut packet)?;
+        self.outgoing_sequence += 1;
+
+        if send_reliable {
+            packet.extend_from_slice(&self.reliable_buf);
+            self.last_reliable_sequence = self.outgoing_sequence;
+        }
+
+        if packet.len() + unreliable.len() <= MAX_PACKET {
+            packet.extend_from_slice(unreliable);
+        }
+
+        self.socket.send_to(&packet, self.remote)?;
+        self.last_send_time = Utc::now();
+
+        Ok(())
+    }
+
+    /// Returns the contents of the next packet from the server, or `None` if
+    /// there isn't one.
+    ///
+    /// Duplicate and out-of-order packets are dropped.
+    pub fn recv(&mut self) -> Result<Option<Vec<u8>>, NetError> {
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut self.recv_buf) {
+                Err(e) => match e.kind() {
+                    ErrorKind::WouldBlock => return Ok(None),
+                    _ => return Err(NetError::from(e)),
+                },
+                Ok(ret) => ret,
+            };
+
+            // ignore packets from anyone else, and out-of-band packets
+            if addr != self.remote || len < 8 || self.recv_buf[..4] == [0xFF; 4] {
+                continue;
+            }
+
+            let mut reader = &self.recv_buf[..len];
+            let header = NetchanHeader::read(&mut reader, false)?;
+            if header.sequence <= self.incoming_sequence {
+                debug!("Dropped out-of-order packet {}", header.sequence);
+                continue;
+            }
+
+            // the server has our reliable message, so the next one can go
+            if header.ack_reliable == self.reliable_sequence {
+                self.reliable_buf.clear();
+            }
+
+            self.incoming_sequence = header.sequence;
+            self.incoming_acknowledged = header.ack;
+            self.incoming_reliable_acknowledged = header.ack_reliable;
+            if header.reliable {
+                self.incoming_reliable_sequence = !self.incoming_reliable_sequence;
+            }
+            self.last_recv_time = Utc::now();
+
+            return Ok(Some(reader.to_vec()));
+        }
+    }
+}
+
+// commands with comments are unused by QuakeWorld but keep their numbers
+#[derive(Copy, Clone, Debug, Eq, FromPrimitive, PartialEq)]
+pub enum QwServerCmdCode {
+    Bad = 0,
+    NoOp = 1,
+    Disconnect = 2,
+    UpdateStat = 3,
+    // Version = 4,
+    SetView = 5,
+    Sound = 6,
+    // Time = 7,
+    Print = 8,
+    StuffText = 9,
+    SetAngle = 10,
+    ServerData = 11,
+    LightStyle = 12,
+    // UpdateName = 13,
+    UpdateFrags = 14,
+    // ClientData = 15,
+    StopSound = 16,
+    // UpdateColors = 17,
+    // Particle = 18,
+    Damage = 19,
+    SpawnStatic = 20,
+    // SpawnBinary = 21,
+    SpawnBaseline = 22,
+    TempEntity = 23,
+    SetPause = 24,
+    // SignOnNum = 25,
+    CenterPrint = 26,
+    KilledMonster = 27,
+    FoundSecret = 28,
+    SpawnStaticSound = 29,
+    Intermission = 30,
+    Finale = 31,
+    CdTrack = 32,
+    SellScreen = 33,
+    SmallKick = 34,
+    BigKick = 35,
+    UpdatePing = 36,
+    UpdateEnterTime = 37,
+    UpdateStatLong = 38,
+    MuzzleFlash = 39,
+    UpdateUserinfo = 40,
+    Download = 41,
+    PlayerInfo = 42,
+    Nails = 43,
+    ChokeCount = 44,
+    ModelList = 45,
+    SoundList = 46,
+    PacketEntities = 47,
+    DeltaPacketEntities = 48,
+    MaxSpeed = 49,
+    EntGravity = 50,
+    SetInfo = 51,
+    ServerInfo = 52,
+    UpdatePl = 53,
+}
+
+/// Message priority levels used by `Print`.
+#[derive(Copy, Clone, Debug, Eq, FromPrimitive, PartialEq)]
+pub enum PrintLevel {
+    Low = 0,
+    Medium = 1,
+    High = 2,
+    Chat = 3,
+}
+
+/// A temporary entity.
+///
+/// Most are sent as in NetQuake, but gunshots carry a count and the codes of
+/// the color explosion and grappling hook are used for blood.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QwTempEntity {
+    Shared(TempEntity),
+    Gunshot { count: u8, origin: Vector3<f32> },
+    Blood { count: u8, origin: Vector3<f32> },
+    LightningBlood { origin: Vector3<f32> },
+}
+
+impl QwTempEntity {
+    pub fn read<R>(reader: &mut R) -> Result<QwTempEntity, NetError>
+    where
+        R: BufRead + ReadBytesExt,
+    {
+        let code = reader.read_u8()?;
+        Ok(match code {
+            TE_GUNSHOT => QwTempEntity::Gunshot {
+                count: reader.read_u8()?,
+                origin: read_coord_vector3(reader)?,
+            },
+            TE_BLOOD => QwTempEntity::Blood {
+                count: reader.read_u8()?,
+                origin: read_coord_vector3(reader)?,
+            },
+            TE_LIGHTNING_BLOOD => QwTempEntity::LightningBlood {
+                origin: read_coord_vector3(reader)?,
+            },
+
+            // the rest match NetQuake, so put the code back for its parser
+            _ => QwTempEntity::Shared(TempEntity::read_temp_entity(
+                &mut (&[code][..]).chain(reader),
+            )?),
+        })
+    }
+
+    pub fn write<W>(&self, writer: &mut W) -> Result<(), NetError>
+    where
+        W: WriteBytesExt,
+    {
+        match *self {
+            QwTempEntity::Shared(ref temp_entity) => temp_entity.write_temp_entity(writer)?,
+            QwTempEntity::Gunshot { count, origin } => {
+                writer.write_u8(TE_GUNSHOT)?;
+                writer.write_u8(count)?;
+                write_coord_vector3(writer, origin)?;
+            }
+            QwTempEntity::Blood { count, origin } => {
+                writer.write_u8(TE_BLOOD)?;
+                writer.write_u8(count)?;
+                write_coord_vector3(writer, origin)?;
+            }
+            QwTempEntity::LightningBlood { origin } => {
+                writer.write_u8(TE_LIGHTNING_BLOOD)?;
+                write_coord_vector3(writer, origin)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn read_angle16<R>(reader: &mut R) -> Result<Deg<f32>, NetError>
+where
+    R: ReadBytesExt,
+{
+    Ok(Deg(
+        reader.read_i16::<LittleEndian>()? as f32 * (360.0 / 65536.0)
+    ))
+}
+
+fn write_angle16<W>(writer: &mut W, angle: Deg<f32>) -> Result<(), NetError>
+where
+    W: WriteBytesExt,
+{
+    writer.write_i16::<LittleEndian>((angle.0 * (65536.0 / 360.0)) as i32 as i16)?;
+    Ok(())
+}
+
+/// A player's movement command, as relayed to the other players.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UserCmd {
+    pub angles: Vector3<Deg<f32>>,
+    pub forward: i16,
+    pub side: i16,
+    pub up: i16,
+    pub buttons: u8,
+    pub impulse: u8,
+    pub msec: u8,
+}
+
+impl UserCmd {
+    /// The command deltas are taken from when there's no earlier command.
+    pub const NULL: UserCmd = UserCmd {
+        angles: Vector3 {
+            x: Deg(0.0),
+            y: Deg(0.0),
+            z: Deg(0.0),
+        },
+        forward: 0,
+        side: 0,
+        up: 0,
+        buttons: 0,
+        impulse: 0,
+        msec: 0,
+    };
+
+    /// Reads a command sent as a delta from the null command.
+    pub fn read<R>(reader: &mut R) -> Result<UserCmd, NetError>
+    where
+        R: ReadBytesExt,
+    {
+        let flags = UserCmdFlags::from_bits_truncate(reader.read_u8()?);
+
+        let mut angles = Vector3::new(Deg(0.0), Deg(0.0), Deg(0.0));
+        let angle_flags = [
+            UserCmdFlags::ANGLE_X,
+            UserCmdFlags::ANGLE_Y,
+            UserCmdFlags::ANGLE_Z,
+        ];
+        for (i, flag) in angle_flags.iter().enumerate() {
+            if flags.contains(*flag) {
+                angles[i] = read_angle16(reader)?;
+            }
+        }
+
+        let mut read_short = |flag| -> Result<i16, NetError> {
+            Ok(match flags.contains(flag) {
+                true => reader.read_i16::<LittleEndian>()?,
+                false => 0,
+            })
+        };
+        let forward = read_short(UserCmdFlags::FORWARD)?;
+        let side = read_short(UserCmdFlags::SIDE)?;
+        let up = read_short(UserCmdFlags::UP)?;
+
+        let mut read_byte = |flag| -> Result<u8, NetError> {
+            Ok(match flags.contains(flag) {
+                true => reader.read_u8()?,
+                false => 0,
+            })
+        };
+        let buttons = read_byte(UserCmdFlags::BUTTONS)?;
+        let impulse = read_byte(UserCmdFlags::IMPULSE)?;
+
+        Ok(UserCmd {
+            angles,
+            forward,
+            side,
+            up,
+            buttons,
+            impulse,
+            msec: reader.read_u8()?,
+        })
+    }
+
+    /// Writes the command as a delta from the null command.
+    pub fn write<W>(&self, writer: &mut W) -> Result<(), NetError>
+    where
+        W: WriteBytesExt,
+    {
+        self.write_delta(&UserCmd::NULL, writer)
+    }
+
+    /// Writes only the fields that differ from `from`.
+    pub fn write_delta<W>(&self, from: &UserCmd, writer: &mut W) -> Result<(), NetError>
+    where
+        W: WriteBytesExt,
+    {
+        let angle_flags = [
+            UserCmdFlags::ANGLE_X,
+            UserCmdFlags::ANGLE_Y,
+            UserCmdFlags::ANGLE_Z,
+        ];
+        let short_fields = [
+            (self.forward, from.forward, UserCmdFlags::FORWARD),
+            (self.side, from.side, UserCmdFlags::SIDE),
+            (self.up, from.up, UserCmdFlags::UP),
+        ];
+        let byte_fields = [
+            (self.buttons, from.buttons, UserCmdFlags::BUTTONS),
+            (self.impulse, from.impulse, UserCmdFlags::IMPULSE),
+        ];
+
+        let mut flags = UserCmdFlags::empty();
+        for (i, flag) in angle_flags.iter().enumerate() {
+            if self.angles[i] != from.angles[i] {
+                flags |= *flag;
+            }
+        }
+        for (value, old, flag) in short_fields.iter() {
+            if value != old {
+                flags |= *flag;
+            }
+        }
+        for (value, old, flag) in byte_fields.iter() {
+            if value != old {
+                flags |= *flag;
+            }
+        }
+        writer.write_u8(flags.bits())?;
+
+        for (i, flag) in angle_flags.iter().enumerate() {
+            if flags.contains(*flag) {
+                write_angle16(writer, self.angles[i])?;
+            }
+        }
+
+        for (value, _, flag) in short_fields.iter() {
+            if flags.contains(*flag) {
+                writer.write_i16::<LittleEndian>(*value)?;
+            }
+        }
+
+        for (value, _, flag) in byte_fields.iter() {
+            if flags.contains(*flag) {
+                writer.write_u8(*value)?;
+            }
+        }
+
+        writer.write_u8(self.msec)?;
+
+        Ok(())
+    }
+}
+
+/// A player's state. Players are sent separately from the packet entity list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QwPlayerInfo {
+    pub player_id: u8,
+    pub origin: Vector3<f32>,
+    pub frame_id: u8,
+
+    /// Milliseconds between the player's last command and the frame.
+    pub msec: Option<u8>,
+
+    /// The player's last command, which isn't sent to the player themselves.
+    pub command: Option<UserCmd>,
+
+    pub velocity: [Option<i16>; 3],
+    pub model_id: Option<u8>,
+    pub skin_id: Option<u8>,
+    pub effects: Option<u8>,
+    pub weapon_frame: Option<u8>,
+    pub dead: bool,
+    pub gib: bool,
+}
+
+impl QwPlayerInfo {
+    pub fn read<R>(reader: &mut R) -> Result<QwPlayerInfo, NetError>
+    where
+        R: BufRead + ReadBytesExt,
+    {
+        let player_id = reader.read_u8()?;
+        let flags = PlayerInfoFlags::from_bits_truncate(reader.read_u16::<LittleEndian>()?);
+        let origin = read_coord_vector3(reader)?;
+        let frame_id = reader.read_u8()?;
+
+        let msec = match flags.contains(PlayerInfoFlags::MSEC) {
+            true => Some(reader.read_u8()?),
+            false => None,
+        };
+
+        let command = match flags.contains(PlayerInfoFlags::COMMAND) {
+            true => Some(UserCmd::read(reader)?),
+            false => None,
+        };
+
+        let mut velocity = [None; 3];
+        let velocity_flags = [
+            PlayerInfoFlags::VELOCITY_X,
+            PlayerInfoFlags::VELOCITY_Y,
+            PlayerInfoFlags::VELOCITY_Z,
+        ];
+        for (v, flag) in velocity.iter_mut().zip(velocity_flags.iter()) {
+            if flags.contains(*flag) {
+                *v = Some(reader.read_i16::<LittleEndian>()?);
+            }
+        }
+
+        let mut read_byte = |flag| -> Result<Option<u8>, NetError> {
+            Ok(match flags.contains(flag) {
+                true => Some(reader.read_u8()?),
+                false => None,
+            })
+        };
+
+        Ok(QwPlayerInfo {
+            player_id,
+            origin,
+            frame_id,
+            msec,
+            command,
+            velocity,
+            model_id: read_byte(PlayerInfoFlags::MODEL)?,
+            skin_id: read_byte(PlayerInfoFlags::SKIN)?,
+            effects: read_byte(PlayerInfoFlags::EFFECTS)?,
+            weapon_frame: read_byte(PlayerInfoFlags::WEAPON_FRAME)?,
+            dead: flags.contains(PlayerInfoFlags::DEAD),
+            gib: flags.contains(PlayerInfoFlags::GIB),
+        })
+    }
+
+    fn flags(&self) -> PlayerInfoFlags {
+        let mut flags = PlayerInfoFlags::empty();
+        let fields = [
+            (self.msec.is_some(), PlayerInfoFlags::MSEC),
+            (self.command.is_some(), PlayerInfoFlags::COMMAND),
+            (self.velocity[0].is_some(), PlayerInfoFlags::VELOCITY_X),
+            (self.velocity[1].is_some(), PlayerInfoFlags::VELOCITY_Y),
+            (self.velocity[2].is_some(), PlayerInfoFlags::VELOCITY_Z),
+            (self.model_id.is_some(), PlayerInfoFlags::MODEL),
+            (self.skin_id.is_some(), PlayerInfoFlags::SKIN),
+            (self.effects.is_some(), PlayerInfoFlags::EFFECTS),
+            (self.weapon_frame.is_some(), PlayerInfoFlags::WEAPON_FRAME),
+            (self.dead, PlayerInfoFlags::DEAD),
+            (self.gib, PlayerInfoFlags::GIB),
+        ];
+
+        for (present, flag) in fields.iter() {
+            if *present {
+                flags |= *flag;
+            }
+        }
+
+        flags
+    }
+
+    pub fn write<W>(&self, writer: &mut W) -> Result<(), NetError>
+    where
+        W: WriteBytesExt,
+    {
+        writer.write_u8(self.player_id)?;
+        writer.write_u16::<LittleEndian>(self.flags().bits())?;
+        write_coord_vector3(writer, self.origin)?;
+        writer.write_u8(self.frame_id)?;
+
+        if let Some(msec) = self.msec {
+            writer.write_u8(msec)?;
+        }
+
+        if let Some(ref command) = self.command {
+            command.write(writer)?;
+        }
+
+        for v in self.velocity.iter().flatten() {
+            writer.write_i16::<LittleEndian>(*v)?;
+        }
+
+        for b in [self.model_id, self.skin_id, self.effects, self.weapon_frame]
+            .iter()
+            .flatten()
+        {
+            writer.write_u8(*b)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Server commands whose format differs from (or doesn't exist in) the
+/// NetQuake protocol.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QwServerCmd {
+    NoOp,
+    Disconnect,
+    UpdateStat {
+        stat: u8,
+        value: u8,
+    },
+    Sound {
+        entity_id: u16,
+        channel: u8,
+        sound_id: u8,
+        volume: Option<u8>,
+        attenuation: Option<u8>,
+        position: Vector3<f32>,
+    },
+    StopSound {
+        entity_id: u16,
+        channel: u8,
+    },
+    SetAngle {
+        angles: Vector3<Deg<f32>>,
+    },
+    LightStyle {
+        id: u8,
+        value: String,
+    },
+    UpdateFrags {
+        player_id: u8,
+        frags: i16,
+    },
+    Damage {
+        armor: u8,
+        blood: u8,
+        source: Vector3<f32>,
+    },
+    SpawnStatic {
+        state: QwEntityState,
+    },
+    SpawnBaseline {
+        baseline: QwEntityState,
+    },
+    SpawnStaticSound {
+        origin: Vector3<f32>,
+        sound_id: u8,
+        volume: u8,
+        attenuation: u8,
+    },
+    TempEntity {
+        temp_entity: QwTempEntity,
+    },
+    Intermission {
+        origin: Vector3<f32>,
+        angles: Vector3<Deg<f32>>,
+    },
+    Finale {
+        text: String,
+    },
+    CdTrack {
+        track: u8,
+    },
+    SellScreen,
+    KilledMonster,
+    FoundSecret,
+    PlayerInfo(QwPlayerInfo),
+    Nails {
+        /// Each projectile's position and angles, packed into 6 bytes.
+        projectiles: Vec<[u8; 6]>,
+    },
+    Print {
+        level: PrintLevel,
+        text: String,
+    },
+    StuffText {
+        text: String,
+    },
+    CenterPrint {
+        text: String,
+    },
+    SetPause {
+        paused: bool,
+    },
+    ServerData {
+        protocol: i32,
+        server_count: i32,
+        game_dir: String,
+        player_id: u8,
+        spectator: bool,
+        level_name: String,
+        move_vars: [f32; 10],
+    },
+    SmallKick,
+    BigKick,
+    UpdatePing {
+        player_id: u8,
+        ping: i16,
+    },
+    UpdatePl {
+        player_id: u8,
+        packet_loss: u8,
+    },
+    UpdateEnterTime {
+        player_id: u8,
+        elapsed: f32,
+    },
+    UpdateStatLong {
+        stat: u8,
+        value: i32,
+    },
+    MuzzleFlash {
+        ent_id: i16,
+    },
+    UpdateUserinfo {
+        player_id: u8,
+        user_id: i32,
+        userinfo: Userinfo,
+    },
+    ChokeCount {
+        count: u8,
+    },
+    ModelList {
+        start: u8,
+        names: Vec<String>,
+        next: u8,
+    },
+    SoundList {
+        start: u8,
+        names: Vec<String>,
+        next: u8,
+    },
+    PacketEntities {
+        /// The frame this update is relative to, or `None` for a full update.
+        delta_from: Option<u8>,
+        deltas: Vec<EntityDelta>,
+    },
+    MaxSpeed {
+        speed: f32,
+    },
+    EntGravity {
+        gravity: f32,
+    },
+    SetInfo {
+        player_id: u8,
+        key: String,
+        value: String,
+    },
+    ServerInfo {
+        key: String,
+        value: String,
+    },
+}
+
+fn read_string<R>(reader: &mut R) -> Result<String, NetError>
+where
+    R: BufRead,
+{
+    util::read_cstring(reader).map_err(|e| NetError::with_msg(format!("{}", e)))
+}
+
+fn write_string<W>(writer: &mut W, s: &str) -> Result<(), NetError>
+where
+    W: WriteBytesExt,
+{
+    writer.write_all(s.as_bytes())?;
+    writer.write_u8(0)?;
+    Ok(())
+}
+
+fn read_name_list<R>(reader: &mut R) -> Result<(u8, Vec<String>, u8), NetError>
+where
+    R: BufRead + ReadBytesExt,
+{
+    let start = reader.read_u8()?;
+    let mut names = Vec::new();
+    loop {
+        let name = read_string(reader)?;
+        if name.is_empty() {
+            break;
+        }
+        names.push(name);
+    }
+    let next = reader.read_u8()?;
+
+    Ok((start, names, next))
+}
+
+// baselines interleave the origin and angle components, like NetQuake's
+fn read_baseline<R>(reader: &mut R, ent_id: u16) -> Result<QwEntityState, NetError>
+where
+    R: BufRead + ReadBytesExt,
+{
+    let mut state = QwEntityState::empty(ent_id);
+    state.model_id = reader.read_u8()?;
+    state.frame_id = reader.read_u8()?;
+    state.colormap = reader.read_u8()?;
+    state.skin_id = reader.read_u8()?;
+    for (origin, angle) in state.origin[..].iter_mut().zip(state.angles[..].iter_mut()) {
+        *origin = read_coord(reader)?;
+        *angle = read_angle(reader)?;
+    }
+
+    Ok(state)
+}
+
+fn write_baseline<W>(writer: &mut W, state: &QwEntityState) -> Result<(), NetError>
+where
+    W: WriteBytesExt,
+{
+    for b in &[
+        state.model_id,
+        state.frame_id,
+        state.colormap,
+        state.skin_id,
+    ] {
+        writer.write_u8(*b)?;
+    }
+
+    for (origin, angle) in state.origin[..].iter().zip(state.angles[..].iter()) {
+        write_coord(writer, *origin)?;
+        write_angle(writer, *angle)?;
+    }
+
+    Ok(())
+}
+
+fn write_name_list<W>(writer: &mut W, start: u8, names: &[String], next: u8) -> Result<(), NetError>
+where
+    W: WriteBytesExt,
+{
+    writer.write_u8(start)?;
+    for name in names {
+        write_string(writer, name)?;
+    }
+    writer.write_u8(0)?;
+    writer.write_u8(next)?;
+    Ok(())
+}
+
+impl QwServerCmd {
+    pub fn code(&self) -> QwServerCmdCode {
+        use QwServerCmdCode as C;
+
+        match *self {
+            QwServerCmd::NoOp => C::NoOp,
+            QwServerCmd::Disconnect => C::Disconnect,
+            QwServerCmd::UpdateStat { .. } => C::UpdateStat,
+            QwServerCmd::Sound { .. } => C::Sound,
+            QwServerCmd::StopSound { .. } => C::StopSound,
+            QwServerCmd::SetAngle { .. } => C::SetAngle,
+            QwServerCmd::LightStyle { .. } => C::LightStyle,
+            QwServerCmd::UpdateFrags { .. } => C::UpdateFrags,
+            QwServerCmd::Damage { .. } => C::Damage,
+            QwServerCmd::SpawnStatic { .. } => C::SpawnStatic,
+            QwServerCmd::SpawnBaseline { .. } => C::SpawnBaseline,
+            QwServerCmd::SpawnStaticSound { .. } => C::SpawnStaticSound,
+            QwServerCmd::TempEntity { .. } => C::TempEntity,
+            QwServerCmd::Intermission { .. } => C::Intermission,
+            QwServerCmd::Finale { .. } => C::Finale,
+            QwServerCmd::CdTrack { .. } => C::CdTrack,
+            QwServerCmd::SellScreen => C::SellScreen,
+            QwServerCmd::KilledMonster => C::KilledMonster,
+            QwServerCmd::FoundSecret => C::FoundSecret,
+            QwServerCmd::PlayerInfo(_) => C::PlayerInfo,
+            QwServerCmd::Nails { .. } => C::Nails,
+            QwServerCmd::Print { .. } => C::Print,
+            QwServerCmd::StuffText { .. } => C::StuffText,
+            QwServerCmd::CenterPrint { .. } => C::CenterPrint,
+            QwServerCmd::SetPause { .. } => C::SetPause,
+            QwServerCmd::ServerData { .. } => C::ServerData,
+            QwServerCmd::SmallKick => C::SmallKick,
+            QwServerCmd::BigKick => C::BigKick,
+            QwServerCmd::UpdatePing { .. } => C::UpdatePing,
+            QwServerCmd::UpdatePl { .. } => C::UpdatePl,
+            QwServerCmd::UpdateEnterTime { .. } => C::UpdateEnterTime,
+            QwServerCmd::UpdateStatLong { .. } => C::UpdateStatLong,
+            QwServerCmd::MuzzleFlash { .. } => C::MuzzleFlash,
+            QwServerCmd::UpdateUserinfo { .. } => C::UpdateUserinfo,
+            QwServerCmd::ChokeCount { .. } => C::ChokeCount,
+            QwServerCmd::ModelList { .. } => C::ModelList,
+            QwServerCmd::SoundList { .. } => C::SoundList,
+            QwServerCmd::PacketEntities {
+                delta_from: None, ..
+            } => C::PacketEntities,
+            QwServerCmd::PacketEntities {
+                delta_from: Some(_),
+                ..
+            } => C::DeltaPacketEntities,
+            QwServerCmd::MaxSpeed { .. } => C::MaxSpeed,
+            QwServerCmd::EntGravity { .. } => C::EntGravity,
+            QwServerCmd::SetInfo { .. } => C::SetInfo,
+            QwServerCmd::ServerInfo { .. } => C::ServerInfo,
+        }
+    }
+
+    /// Reads the next command from the message, or `None` at the end of the
+    /// message.
+    ///
+    /// Downloads, which the client never asks for, are returned as errors.
+    pub fn deserialize<R>(reader: &mut R) -> Result<Option<QwServerCmd>, NetError>
+    where
+        R: BufRead + ReadBytesExt,
+    {
+        let code_num = match reader.read_u8() {
+            Ok(c) => c,
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(NetError::from(e)),
+        };
+
+        let code = match QwServerCmdCode::from_u8(code_num) {
+            Some(c) => c,
+            None => {
+                return Err(NetError::InvalidData(format!(
+                    "Invalid QuakeWorld server command code: {}",
+                    code_num
+                )))
+            }
+        };
+
+        let cmd = match code {
+            QwServerCmdCode::NoOp => QwServerCmd::NoOp,
+            QwServerCmdCode::Disconnect => QwServerCmd::Disconnect,
+
+            QwServerCmdCode::UpdateStat => QwServerCmd::UpdateStat {
+                stat: reader.read_u8()?,
+                value: reader.read_u8()?,
+            },
+
+            QwServerCmdCode::Sound => {
+                let field = reader.read_u16::<LittleEndian>()?;
+                let volume = match field & SND_VOLUME {
+                    0 => None,
+                    _ => Some(reader.read_u8()?),
+                };
+                let attenuation = match field & SND_ATTENUATION {
+                    0 => None,
+                    _ => Some(reader.read_u8()?),
+                };
+
+                QwServerCmd::Sound {
+                    entity_id: (field >> 3) & 0x03FF,
+                    channel: (field & 0x07) as u8,
+                    sound_id: reader.read_u8()?,
+                    volume,
+                    attenuation,
+                    position: read_coord_vector3(reader)?,
+                }
+            }
+
+            QwServerCmdCode::StopSound => {
+                let field = reader.read_u16::<LittleEndian>()?;
+                QwServerCmd::StopSound {
+                    entity_id: field >> 3,
+                    channel: (field & 0x07) as u8,
+                }
+            }
+
+            QwServerCmdCode::SetAngle => {
+                let mut angles = Vector3::new(Deg(0.0), Deg(0.0), Deg(0.0));
+                for angle in angles[..].iter_mut() {
+                    *angle = read_angle(reader)?;
+                }
+                QwServerCmd::SetAngle { angles }
+            }
+
+            QwServerCmdCode::LightStyle => QwServerCmd::LightStyle {
+                id: reader.read_u8()?,
+                value: read_string(reader)?,
+            },
+
+            QwServerCmdCode::UpdateFrags => QwServerCmd::UpdateFrags {
+                player_id: reader.read_u8()?,
+                frags: reader.read_i16::<LittleEndian>()?,
+            },
+
+            QwServerCmdCode::Damage => QwServerCmd::Damage {
+                armor: reader.read_u8()?,
+                blood: reader.read_u8()?,
+                source: read_coord_vector3(reader)?,
+            },
+
+            QwServerCmdCode::SpawnStatic => QwServerCmd::SpawnStatic {
+                state: read_baseline(reader, 0)?,
+            },
+
+            QwServerCmdCode::SpawnBaseline => {
+                let ent_id = reader.read_u16::<LittleEndian>()?;
+                QwServerCmd::SpawnBaseline {
+                    baseline: read_baseline(reader, ent_id)?,
+                }
+            }
+
+            QwServerCmdCode::SpawnStaticSound => QwServerCmd::SpawnStaticSound {
+                origin: read_coord_vector3(reader)?,
+                sound_id: reader.read_u8()?,
+                volume: reader.read_u8()?,
+                attenuation: reader.read_u8()?,
+            },
+
+            QwServerCmdCode::TempEntity => QwServerCmd::TempEntity {
+                temp_entity: QwTempEntity::read(reader)?,
+            },
+
+            QwServerCmdCode::Intermission => {
+                let origin = read_coord_vector3(reader)?;
+                let mut angles = Vector3::new(Deg(0.0), Deg(0.0), Deg(0.0));
+                for angle in angles[..].iter_mut() {
+                    *angle = read_angle(reader)?;
+                }
+                QwServerCmd::Intermission { origin, angles }
+            }
+
+            QwServerCmdCode::Finale => QwServerCmd::Finale {
+                text: read_string(reader)?,
+            },
+
+            QwServerCmdCode::CdTrack => QwServerCmd::CdTrack {
+                track: reader.read_u8()?,
+            },
+
+            QwServerCmdCode::SellScreen => QwServerCmd::SellScreen,
+            QwServerCmdCode::KilledMonster => QwServerCmd::KilledMonster,
+            QwServerCmdCode::FoundSecret => QwServerCmd::FoundSecret,
+
+            QwServerCmdCode::PlayerInfo => QwServerCmd::PlayerInfo(QwPlayerInfo::read(reader)?),
+
+            QwServerCmdCode::Nails => {
+                let count = reader.read_u8()?;
+                let mut projectiles = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let mut bits = [0; 6];
+                    reader.read_exact(&mut bits)?;
+                    projectiles.push(bits);
+                }
+                QwServerCmd::Nails { projectiles }
+            }
+
+            QwServerCmdCode::Print => {
+                let level_num = reader.read_u8()?;
+                let level = PrintLevel::from_u8(level_num)
+                    .ok_or_else(|| NetError::InvalidData(format!("print level {}", level_num)))?;
+                let text = read_string(reader)?;
+                QwServerCmd::Print { level, text }
+            }
+
+            QwServerCmdCode::StuffText => QwServerCmd::StuffText {
+                text: read_string(reader)?,
+            },
+
+            QwServerCmdCode::CenterPrint => QwServerCmd::CenterPrint {
+                text: read_string(reader)?,
+            },
+
+            QwServerCmdCode::SetPause => QwServerCmd::SetPause {
+                paused: reader.read_u8()? != 0,
+            },
+
+            QwServerCmdCode::ServerData => {
+                let protocol = reader.read_i32::<LittleEndian>()?;
+                if protocol != PROTOCOL_VERSION {
+                    return Err(NetError::InvalidData(format!(
+                        "QuakeWorld protocol version {}",
+                        protocol
+                    )));
+                }
+
+                let server_count = reader.read_i32::<LittleEndian>()?;
+                let game_dir = read_string(reader)?;
+                let player_byte = reader.read_u8()?;
+                let level_name = read_string(reader)?;
+                let mut move_vars = [0.0; 10];
+                for v in move_vars.iter_mut() {
+                    *v = reader.read_f32::<LittleEndian>()?;
+                }
+
+                QwServerCmd::ServerData {
+                    protocol,
+                    server_count,
+                    game_dir,
+                    player_id: player_byte & 0x7F,
+                    spectator: player_byte & 0x80 != 0,
+                    level_name,
+                    move_vars,
+                }
+            }
+
+            QwServerCmdCode::SmallKick => QwServerCmd::SmallKick,
+            QwServerCmdCode::BigKick => QwServerCmd::BigKick,
+
+            QwServerCmdCode::UpdatePing => QwServerCmd::UpdatePing {
+                player_id: reader.read_u8()?,
+                ping: reader.read_i16::<LittleEndian>()?,
+            },
+
+            QwServerCmdCode::UpdatePl => QwServerCmd::UpdatePl {
+                player_id: reader.read_u8()?,
+                packet_loss: reader.read_u8()?,
+            },
+
+            QwServerCmdCode::UpdateEnterTime => QwServerCmd::UpdateEnterTime {
+                player_id: reader.read_u8()?,
+                elapsed: reader.read_f32::<LittleEndian>()?,
+            },
+
+            QwServerCmdCode::UpdateStatLong => QwServerCmd::UpdateStatLong {
+                stat: reader.read_u8()?,
+                value: reader.read_i32::<LittleEndian>()?,
+            },
+
+            QwServerCmdCode::MuzzleFlash => QwServerCmd::MuzzleFlash {
+                ent_id: reader.read_i16::<LittleEndian>()?,
+            },
+
+            QwServerCmdCode::UpdateUserinfo => QwServerCmd::UpdateUserinfo {
+                player_id: reader.read_u8()?,
+                user_id: reader.read_i32::<LittleEndian>()?,
+                userinfo: Userinfo::parse(read_string(reader)?)?,
+            },
+
+            QwServerCmdCode::ChokeCount => QwServerCmd::ChokeCount {
+                count: reader.read_u8()?,
+            },
+
+            QwServerCmdCode::ModelList => {
+                let (start, names, next) = read_name_list(reader)?;
+                QwServerCmd::ModelList { start, names, next }
+            }
+
+            QwServerCmdCode::SoundList => {
+                let (start, names, next) = read_name_list(reader)?;
+                QwServerCmd::SoundList { start, names, next }
+            }
+
+            QwServerCmdCode::PacketEntities => QwServerCmd::PacketEntities {
+                delta_from: None,
+                deltas: read_packet_entities(reader)?,
+            },
+
+            QwServerCmdCode::DeltaPacketEntities => {
+                let delta_from = reader.read_u8()?;
+                QwServerCmd::PacketEntities {
+                    delta_from: Some(delta_from),
+                    deltas: read_packet_entities(reader)?,
+                }
+            }
+
+            QwServerCmdCode::MaxSpeed => QwServerCmd::MaxSpeed {
+                speed: reader.read_f32::<LittleEndian>()?,
+            },
+
+            QwServerCmdCode::EntGravity => QwServerCmd::EntGravity {
+                gravity: reader.read_f32::<LittleEndian>()?,
+            },
+
+            QwServerCmdCode::SetInfo => QwServerCmd::SetInfo {
+                player_id: reader.read_u8()?,
+                key: read_string(reader)?,
+                value: read_string(reader)?,
+            },
+
+            QwServerCmdCode::ServerInfo => QwServerCmd::ServerInfo {
+                key: read_string(reader)?,
+                value: read_string(reader)?,
+            },
+
+            c => {
+                return Err(NetError::with_msg(format!(
+                    "QuakeWorld command {:?} not yet supported",
+                    c
+                )))
+            }
+        };
+
+        Ok(Some(cmd))
+    }
+
+    pub fn serialize<W>(&self, writer: &mut W) -> Result<(), NetError>
+    where
+        W: WriteBytesExt,
+    {
+        writer.write_u8(self.code() as u8)?;
+
+        match *self {
+            QwServerCmd::NoOp
+            | QwServerCmd::Disconnect
+            | QwServerCmd::SellScreen
+            | QwServerCmd::KilledMonster
+            | QwServerCmd::FoundSecret
+            | QwServerCmd::SmallKick
+            | QwServerCmd::BigKick => (),
+
+            QwServerCmd::UpdateStat { stat, value } => {
+                writer.write_u8(stat)?;
+                writer.write_u8(value)?;
+            }
+
+            QwServerCmd::Sound {
+                entity_id,
+                channel,
+                sound_id,
+                volume,
+                attenuation,
+                position,
+            } => {
+                let mut field = entity_id << 3 | channel as u16 & 0x07;
+                if volume.is_some() {
+                    field |= SND_VOLUME;
+                }
+                if attenuation.is_some() {
+                    field |= SND_ATTENUATION;
+                }
+
+                writer.write_u16::<LittleEndian>(field)?;
+                for b in [volume, attenuation].iter().flatten() {
+                    writer.write_u8(*b)?;
+                }
+                writer.write_u8(sound_id)?;
+                write_coord_vector3(writer, position)?;
+            }
+
+            QwServerCmd::StopSound { entity_id, channel } => {
+                writer.write_u16::<LittleEndian>(entity_id << 3 | channel as u16 & 0x07)?;
+            }
+
+            QwServerCmd::SetAngle { angles } => {
+                for angle in &angles[..] {
+                    write_angle(writer, *angle)?;
+                }
+            }
+
+            QwServerCmd::LightStyle { id, ref value } => {
+                writer.write_u8(id)?;
+                write_string(writer, value)?;
+            }
+
+            QwServerCmd::UpdateFrags { player_id, frags } => {
+                writer.write_u8(player_id)?;
+                writer.write_i16::<LittleEndian>(frags)?;
+            }
+
+            QwServerCmd::Damage {
+                armor,
+                blood,
+                source,
+            } => {
+                writer.write_u8(armor)?;
+                writer.write_u8(blood)?;
+                write_coord_vector3(writer, source)?;
+            }
+
+            QwServerCmd::SpawnStatic { ref state } => write_baseline(writer, state)?,
+
+            QwServerCmd::SpawnBaseline { ref baseline } => {
+                writer.write_u16::<LittleEndian>(baseline.ent_id)?;
+                write_baseline(writer, baseline)?;
+            }
+
+            QwServerCmd::SpawnStaticSound {
+                origin,
+                sound_id,
+                volume,
+                attenuation,
+            } => {
+                write_coord_vector3(writer, origin)?;
+                writer.write_u8(sound_id)?;
+                writer.write_u8(volume)?;
+                writer.write_u8(attenuation)?;
+            }
+
+            QwServerCmd::TempEntity { ref temp_entity } => temp_entity.write(writer)?,
+
+            QwServerCmd::Intermission { origin, angles } => {
+                write_coord_vector3(writer, origin)?;
+                for angle in &angles[..] {
+                    write_angle(writer, *angle)?;
+                }
+            }
+
+            QwServerCmd::Finale { ref text } => write_string(writer, text)?,
+
+            QwServerCmd::CdTrack { track } => writer.write_u8(track)?,
+
+            QwServerCmd::PlayerInfo(ref info) => info.write(writer)?,
+
+            QwServerCmd::Nails { ref projectiles } => {
+                writer.write_u8(projectiles.len() as u8)?;
+                for bits in projectiles {
+                    writer.write_all(bits)?;
+                }
+            }
+
+            QwServerCmd::Print { level, ref text } => {
+                writer.write_u8(level as u8)?;
+                write_string(writer, text)?;
+            }
+
+            QwServerCmd::StuffText { ref text } | QwServerCmd::CenterPrint { ref text } => {
+                write_string(writer, text)?;
+            }
+
+            QwServerCmd::SetPause { paused } => writer.write_u8(paused as u8)?,
+
+            QwServerCmd::ServerData {
+                protocol,
+                server_count,
+                ref game_dir,
+                player_id,
+                spectator,
+                ref level_name,
+                move_vars,
+            } => {
+                writer.write_i32::<LittleEndian>(protocol)?;
+                writer.write_i32::<LittleEndian>(server_count)?;
+                write_string(writer, game_dir)?;
+                writer.write_u8(player_id | if spectator { 0x80 } else { 0 })?;
+                write_string(writer, level_name)?;
+                for v in move_vars.iter() {
+                    writer.write_f32::<LittleEndian>(*v)?;
+                }
+            }
+
+            QwServerCmd::UpdatePing { player_id, ping } => {
+                writer.write_u8(player_id)?;
+                writer.write_i16::<LittleEndian>(ping)?;
+            }
+
+            QwServerCmd::UpdatePl {
+                player_id,
+                packet_loss,
+            } => {
+                writer.write_u8(player_id)?;
+                writer.write_u8(packet_loss)?;
+            }
+
+            QwServerCmd::UpdateEnterTime { player_id, elapsed } => {
+                writer.write_u8(player_id)?;
+                writer.write_f32::<LittleEndian>(elapsed)?;
+            }
+
+            QwServerCmd::UpdateStatLong { stat, value } => {
+                writer.write_u8(stat)?;
+                writer.write_i32::<LittleEndian>(value)?;
+            }
+
+            QwServerCmd::MuzzleFlash { ent_id } => writer.write_i16::<LittleEndian>(ent_id)?,
+
+            QwServerCmd::UpdateUserinfo {
+                player_id,
+                user_id,
+                ref userinfo,
+            } => {
+                writer.write_u8(player_id)?;
+                writer.write_i32::<LittleEndian>(user_id)?;
+                write_string(writer, &userinfo.to_string())?;
+            }
+
+            QwServerCmd::ChokeCount { count } => writer.write_u8(count)?,
+
+            QwServerCmd::ModelList {
+                start,
+                ref names,
+                next,
+            }
+            | QwServerCmd::SoundList {
+                start,
+                ref names,
+                next,
+            } => write_name_list(writer, start, names, next)?,
+
+            QwServerCmd::PacketEntities {
+                delta_from,
+                ref deltas,
+            } => {
+                if let Some(from) = delta_from {
+                    writer.write_u8(from)?;
+                }
+
+                for delta in deltas {
+                    delta.write(writer)?;
+                }
+                writer.write_u16::<LittleEndian>(0)?;
+            }
+
+            QwServerCmd::MaxSpeed { speed } => writer.write_f32::<LittleEndian>(speed)?,
+            QwServerCmd::EntGravity { gravity } => writer.write_f32::<LittleEndian>(gravity)?,
+
+            QwServerCmd::SetInfo {
+                player_id,
+                ref key,
+                ref value,
+            } => {
+                writer.write_u8(player_id)?;
+                write_string(writer, key)?;
+                write_string(writer, value)?;
+            }
+
+            QwServerCmd::ServerInfo { ref key, ref value } => {
+                write_string(writer, key)?;
+                write_string(writer, value)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The state of an entity as sent in a QuakeWorld packet entity list.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QwEntityState {
+    pub ent_id: u16,
+    pub model_id: u8,
+    pub frame_id: u8,
+    pub colormap: u8,
+    pub skin_id: u8,
+    pub effects: u8,
+    pub origin: Vector3<f32>,
+    pub angles: Vector3<Deg<f32>>,
+}
+
+impl QwEntityState {
+    /// Returns an empty state for the given entity, used when no baseline has
+    /// been received.
+    pub fn empty(ent_id: u16) -> QwEntityState {
+        QwEntityState {
+            ent_id,
+            model_id: 0,
+            frame_id: 0,
+            colormap: 0,
+            skin_id: 0,
+            effects: 0,
+            origin: Vector3::zero(),
+            angles: Vector3::new(Deg(0.0), Deg(0.0), Deg(0.0)),
+        }
+    }
+}
+
+/// A change to a single entity in a packet entity list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityDelta {
+    pub ent_id: u16,
+    pub model_id: Option<u8>,
+    pub frame_id: Option<u8>,
+    pub colormap: Option<u8>,
+    pub skin_id: Option<u8>,
+    pub effects: Option<u8>,
+    pub origin_x: Option<f32>,
+    pub origin_y: Option<f32>,
+    pub origin_z: Option<f32>,
+    pub pitch: Option<Deg<f32>>,
+    pub yaw: Option<Deg<f32>>,
+    pub roll: Option<Deg<f32>>,
+    pub remove: bool,
+}
+
+impl EntityDelta {
+    /// Computes the delta needed to turn `from` into `to`.
+    pub fn between(from: &QwEntityState, to: &QwEntityState) -> EntityDelta {
+        fn changed<T: PartialEq + Copy>(a: T, b: T) -> Option<T> {
+            if a != b {
+                Some(b)
+            } else {
+                None
+            }
+        }
+
+        EntityDelta {
+            ent_id: to.ent_id,
+            model_id: changed(from.model_id, to.model_id),
+            frame_id: changed(from.frame_id, to.frame_id),
+            colormap: changed(from.colormap, to.colormap),
+            skin_id: changed(from.skin_id, to.skin_id),
+            effects: changed(from.effects, to.effects),
+            origin_x: changed(from.origin.x, to.origin.x),
+            origin_y: changed(from.origin.y, to.origin.y),
+            origin_z: changed(from.origin.z, to.origin.z),
+            pitch: changed(from.angles.x, to.angles.x),
+            yaw: changed(from.angles.y, to.angles.y),
+            roll: changed(from.angles.z, to.angles.z),
+            remove: false,
+        }
+    }
+
+    /// Returns a delta which removes the given entity.
+    pub fn remove(ent_id: u16) -> EntityDelta {
+        EntityDelta {
+            remove: true,
+            ..EntityDelta::between(&QwEntityState::empty(ent_id), &QwEntityState::empty(ent_id))
+        }
+    }
+
+    /// Reads a delta, or `None` if the end of the entity list was reached.
+    pub fn read<R>(reader: &mut R) -> Result<Option<EntityDelta>, NetError>
+    where
+        R: BufRead + ReadBytesExt,
+    {
+        let word = reader.read_u16::<LittleEndian>()?;
+        if word == 0 {
+            return Ok(None);
+        }
+
+        let ent_id = word & DELTA_ENTITY_MASK;
+        let mut flags = EntityDeltaFlags::from_bits_truncate(word & !DELTA_ENTITY_MASK);
+        if flags.contains(EntityDeltaFlags::MORE_BITS) {
+            flags |= EntityDeltaFlags::from_bits_truncate(reader.read_u8()? as u16);
+        }
+
+        let mut read_byte = |flag| -> Result<Option<u8>, NetError> {
+            Ok(match flags.contains(flag) {
+                true => Some(reader.read_u8()?),
+                false => None,
+            })
+        };
+
+        let model_id = read_byte(EntityDeltaFlags::MODEL)?;
+        let frame_id = read_byte(EntityDeltaFlags::FRAME)?;
+        let colormap = read_byte(EntityDeltaFlags::COLORMAP)?;
+        let skin_id = read_byte(EntityDeltaFlags::SKIN)?;
+        let effects = read_byte(EntityDeltaFlags::EFFECTS)?;
+
+        // origin and angle components are interleaved
+        let mut origin = [None; 3];
+        let mut angles = [None; 3];
+        let fields = [
+            (EntityDeltaFlags::ORIGIN_X, EntityDeltaFlags::ANGLE_X),
+            (EntityDeltaFlags::ORIGIN_Y, EntityDeltaFlags::ANGLE_Y),
+            (EntityDeltaFlags::ORIGIN_Z, EntityDeltaFlags::ANGLE_Z),
+        ];
+        for (i, (origin_flag, angle_flag)) in fields.iter().enumerate() {
+            if flags.contains(*origin_flag) {
+                origin[i] = Some(read_coord(reader)?);
+            }
+
+            if flags.contains(*angle_flag) {
+                angles[i] = Some(read_angle(reader)?);
+            }
+        }
+
+        Ok(Some(EntityDelta {
+            ent_id,
+            model_id,
+            frame_id,
+            colormap,
+            skin_id,
+            effects,
+            origin_x: origin[0],
+            origin_y: origin[1],
+            origin_z: origin[2],
+            pitch: angles[0],
+            yaw: angles[1],
+            roll: angles[2],
+            remove: flags.contains(EntityDeltaFlags::REMOVE),
+        }))
+    }
+
+    fn flags(&self) -> EntityDeltaFlags {
+        let mut flags = EntityDeltaFlags::empty();
+        let fields = [
+            (self.model_id.is_some(), EntityDeltaFlags::MODEL),
+            (self.frame_id.is_some(), EntityDeltaFlags::FRAME),
+            (self.colormap.is_some(), EntityDeltaFlags::COLORMAP),
+            (self.skin_id.is_some(), EntityDeltaFlags::SKIN),
+            (self.effects.is_some(), EntityDeltaFlags::EFFECTS),
+            (self.origin_x.is_some(), EntityDeltaFlags::ORIGIN_X),
+            (self.origin_y.is_some(), EntityDeltaFlags::ORIGIN_Y),
+            (self.origin_z.is_some(), EntityDeltaFlags::ORIGIN_Z),
+            (self.pitch.is_some(), EntityDeltaFlags::ANGLE_X),
+            (self.yaw.is_some(), EntityDeltaFlags::ANGLE_Y),
+            (self.roll.is_some(), EntityDeltaFlags::ANGLE_Z),
+            (self.remove, EntityDeltaFlags::REMOVE),
+        ];
+
+        for (present, flag) in fields.iter() {
+            if *present {
+                flags |= *flag;
+            }
+        }
+
+        if flags.bits() & 0xFF != 0 {
+            flags |= EntityDeltaFlags::MORE_BITS;
+        }
+
+        flags
+    }
+
+    pub fn write<W>(&self, writer: &mut W) -> Result<(), NetError>
+    where
+        W: WriteBytesExt,
+    {
+        if self.ent_id == 0 || self.ent_id > DELTA_ENTITY_MASK {
+            return Err(NetError::InvalidData(format!(
+                "packet entity number {}",
+                self.ent_id
+            )));
+        }
+
+        let flags = self.flags();
+        writer.write_u16::<LittleEndian>(self.ent_id | (flags.bits() & !0xFF))?;
+        if flags.contains(EntityDeltaFlags::MORE_BITS) {
+            writer.write_u8((flags.bits() & 0xFF) as u8)?;
+        }
+
+        for b in &[
+            self.model_id,
+            self.frame_id,
+            self.colormap,
+            self.skin_id,
+            self.effects,
+        ] {
+            if let Some(b) = b {
+                writer.write_u8(*b)?;
+            }
+        }
+
+        let fields = [
+            (self.origin_x, self.pitch),
+            (self.origin_y, self.yaw),
+            (self.origin_z, self.roll),
+        ];
+        for (origin, angle) in fields.iter() {
+            if let Some(o) = origin {
+                write_coord(writer, *o)?;
+            }
+
+            if let Some(a) = angle {
+                write_angle(writer, *a)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies this delta to `base`, returning the new entity state.
+    pub fn apply(&self, base: &QwEntityState) -> QwEntityState {
+        QwEntityState {
+            ent_id: self.ent_id,
+            model_id: self.model_id.unwrap_or(base.model_id),
+            frame_id: self.frame_id.unwrap_or(base.frame_id),
+            colormap: self.colormap.unwrap_or(base.colormap),
+            skin_id: self.skin_id.unwrap_or(base.skin_id),
+            effects: self.effects.unwrap_or(base.effects),
+            origin: Vector3::new(
+                self.origin_x.unwrap_or(base.origin.x),
+                self.origin_y.unwrap_or(base.origin.y),
+                self.origin_z.unwrap_or(base.origin.z),
+            ),
+            angles: Vector3::new(
+                self.pitch.unwrap_or(base.angles.x),
+                self.yaw.unwrap_or(base.angles.y),
+                self.roll.unwrap_or(base.angles.z),
+            ),
+        }
+    }
+}
+
+fn read_packet_entities<R>(reader: &mut R) -> Result<Vec<EntityDelta>, NetError>
+where
+    R: BufRead + ReadBytesExt,
+{
+    let mut deltas = Vec::new();
+    while let Some(delta) = EntityDelta::read(reader)? {
+        if deltas.len() >= MAX_PACKET_ENTITIES {
+            return Err(NetError::InvalidData(String::from(
+                "too many packet entities",
+            )));
+        }
+
+        deltas.push(delta);
+    }
+
+    Ok(deltas)
+}
+
+/// Builds a new packet entity list from a previous frame and a list of deltas.
+///
+/// `from` is the entity list of the frame the update is relative to (empty for
+/// a full update) and must be sorted by entity number, as must `deltas`.
+/// Entities without a previous state are delta'd from their baseline.
+pub fn apply_packet_entities<F>(
+    from: &[QwEntityState],
+    deltas: &[EntityDelta],
+    baseline: F,
+) -> Result<Vec<QwEntityState>, NetError>
+where
+    F: Fn(u16) -> QwEntityState,
+{
+    let mut entities = Vec::with_capacity(from.len().max(deltas.len()));
+    let mut old = from.iter().peekable();
+    let mut last_id = 0;
+
+    for delta in deltas {
+        if delta.ent_id <= last_id {
+            return Err(NetError::InvalidData(format!(
+                "packet entity {} out of order",
+                delta.ent_id
+            )));
+        }
+        last_id = delta.ent_id;
+
+        // entities not mentioned in the update are unchanged
+        while let Some(o) = old.peek() {
+            if o.ent_id >= delta.ent_id {
+                break;
+            }
+
+            entities.push(**o);
+            old.next();
+        }
+
+        let base = match old.peek() {
+            Some(o) if o.ent_id == delta.ent_id => {
+                let o = **o;
+                old.next();
+                o
+            }
+            _ => baseline(delta.ent_id),
+        };
+
+        if !delta.remove {
+            entities.push(delta.apply(&base));
+        }
+    }
+
+    entities.extend(old.cloned());
+
+    Ok(entities)
+}
+
+// TODO: check against chktbl in QW/client/common.c
+// salts the checksum of each move command, see `move_checksum`
+const MOVE_CHECKSUM_TABLE: [u8; 1028] = [
+    0x78, 0xd2, 0x94, 0xe3, 0x41, 0xec, 0xd6, 0xd5, 0xcb, 0xfc, 0xdb, 0x8a, 0x4b, 0xcc, 0x85, 0x01,
+    0x23, 0xd2, 0xe5, 0xf2, 0x29, 0xa7, 0x45, 0x94, 0x4a, 0x62, 0xe3, 0xa5, 0x6f, 0x3f, 0xe1, 0x7a,
+    0x64, 0xed, 0x5c, 0x99, 0x29, 0x87, 0xa8, 0x78, 0x59, 0x0d, 0xaa, 0x0f, 0x25, 0x0a, 0x5c, 0x58,
+    0xfb, 0x00, 0xa7, 0xa8, 0x8a, 0x1d, 0x86, 0x80, 0xc5, 0x1f, 0xd2, 0x28, 0x69, 0x71, 0x58, 0xc3,
+    0x51, 0x90, 0xe1, 0xf8, 0x6a, 0xf3, 0x8f, 0xb0, 0x68, 0xdf, 0x95, 0x40, 0x5c, 0xe4, 0x24, 0x6b,
+    0x29, 0x19, 0x71, 0x3f, 0x42, 0x63, 0x6c, 0x48, 0xe7, 0xad, 0xa8, 0x4b, 0x91, 0x8f, 0x42, 0x36,
+    0x34, 0xe7, 0x32, 0x55, 0x59, 0x2d, 0x36, 0x38, 0x38, 0x59, 0x9b, 0x08, 0x16, 0x4d, 0x8d, 0xf8,
+    0x0a, 0xa4, 0x52, 0x01, 0xbb, 0x52, 0xa9, 0xfd, 0x40, 0x18, 0x97, 0x37, 0xff, 0xc9, 0x82, 0x27,
+    0xb2, 0x64, 0x60, 0xce, 0x00, 0xd9, 0x04, 0xf0, 0x9e, 0x99, 0xbd, 0xce, 0x8f, 0x90, 0x4a, 0xdd,
+    0xe1, 0xec, 0x19, 0x14, 0xb1, 0xfb, 0xca, 0x1e, 0x98, 0x0f, 0xd4, 0xcb, 0x80, 0xd6, 0x05, 0x63,
+    0xfd, 0xa0, 0x74, 0xa6, 0x86, 0xf6, 0x19, 0x98, 0x76, 0x27, 0x68, 0xf7, 0xe9, 0x09, 0x9a, 0xf2,
+    0x2e, 0x42, 0xe1, 0xbe, 0x64, 0x48, 0x2a, 0x74, 0x30, 0xbb, 0x07, 0xcc, 0x1f, 0xd4, 0x91, 0x9d,
+    0xac, 0x55, 0x53, 0x25, 0xb9, 0x64, 0xf7, 0x58, 0x4c, 0x34, 0x16, 0xbc, 0xf6, 0x12, 0x2b, 0x65,
+    0x68, 0x25, 0x2e, 0x29, 0x1f, 0xbb, 0xb9, 0xee, 0x6d, 0x0c, 0x8e, 0xbb, 0xd2, 0x5f, 0x1d, 0x8f,
+    0xc1, 0x39, 0xf9, 0x8d, 0xc0, 0x39, 0x75, 0xcf, 0x25, 0x17, 0xbe, 0x96, 0xaf, 0x98, 0x9f, 0x5f,
+    0x65, 0x15, 0xc4, 0x62, 0xf8, 0x55, 0xfc, 0xab, 0x54, 0xcf, 0xdc, 0x14, 0x06, 0xc8, 0xfc, 0x42,
+    0xd3, 0xf0, 0xad, 0x10, 0x08, 0xcd, 0xd4, 0x11, 0xbb, 0xca, 0x67, 0xc6, 0x48, 0x5f, 0x9d, 0x59,
+    0xe3, 0xe8, 0x53, 0x67, 0x27, 0x2d, 0x34, 0x9e, 0x9e, 0x24, 0x29, 0xdb, 0x69, 0x99, 0x86, 0xf9,
+    0x20, 0xb5, 0xbb, 0x5b, 0xb0, 0xf9, 0xc3, 0x67, 0xad, 0x1c, 0x9c, 0xf7, 0xcc, 0xef, 0xce, 0x69,
+    0xe0, 0x26, 0x8f, 0x79, 0xbd, 0xca, 0x10, 0x17, 0xda, 0xa9, 0x88, 0x57, 0x9b, 0x15, 0x24, 0xba,
+    0x84, 0xd0, 0xeb, 0x4d, 0x14, 0xf5, 0xfc, 0xe6, 0x51, 0x6c, 0x6f, 0x64, 0x6b, 0x73, 0xec, 0x85,
+    0xf1, 0x6f, 0xe1, 0x67, 0x25, 0x10, 0x77, 0x32, 0x9e, 0x85, 0x6e, 0x69, 0xb1, 0x83, 0x00, 0xe4,
+    0x13, 0xa4, 0x45, 0x34, 0x3b, 0x40, 0xff, 0x41, 0x82, 0x89, 0x79, 0x57, 0xfd, 0xd2, 0x8e, 0xe8,
+    0xfc, 0x1d, 0x19, 0x21, 0x12, 0x00, 0xd7, 0x66, 0xe5, 0xc7, 0x10, 0x1d, 0xcb, 0x75, 0xe8, 0xfa,
+    0xb6, 0xee, 0x7b, 0x2f, 0x1a, 0x25, 0x24, 0xb9, 0x9f, 0x1d, 0x78, 0xfb, 0x84, 0xd0, 0x17, 0x05,
+    0x71, 0xb3, 0xc8, 0x18, 0xff, 0x62, 0xee, 0xed, 0x53, 0xab, 0x78, 0xd3, 0x65, 0x2d, 0xbb, 0xc7,
+    0xc1, 0xe7, 0x70, 0xa2, 0x43, 0x2c, 0x7c, 0xc7, 0x16, 0x04, 0xd2, 0x45, 0xd5, 0x6b, 0x6c, 0x7a,
+    0x5e, 0xa1, 0x50, 0x2e, 0x31, 0x5b, 0xcc, 0xe8, 0x65, 0x8b, 0x16, 0x85, 0xbf, 0x82, 0x83, 0xfb,
+    0xde, 0x9f, 0x36, 0x48, 0x32, 0x79, 0xd6, 0x9b, 0xfb, 0x52, 0x45, 0xbf, 0x43, 0xf7, 0x0b, 0x0b,
+    0x19, 0x19, 0x31, 0xc3, 0x85, 0xec, 0x1d, 0x8c, 0x20, 0xf0, 0x3a, 0xfa, 0x80, 0x4d, 0x2c, 0x7d,
+    0xac, 0x60, 0x09, 0xc0, 0x40, 0xee, 0xb9, 0xeb, 0x13, 0x5b, 0xe8, 0x2b, 0xb1, 0x20, 0xf0, 0xce,
+    0x4c, 0xbd, 0xc6, 0x04, 0x86, 0x70, 0xc6, 0x33, 0xc3, 0x15, 0x0f, 0x65, 0x19, 0xfd, 0xc2, 0xd3,
+    0x0f, 0x9c, 0x96, 0x8c, 0x03, 0xaf, 0xe6, 0x6b, 0xfc, 0x2e, 0xc3, 0x72, 0x05, 0xca, 0x1e, 0xef,
+    0x94, 0x79, 0x0d, 0x79, 0x59, 0x38, 0xee, 0xf6, 0x06, 0x3b, 0x4e, 0x79, 0xbc, 0x14, 0x2f, 0x61,
+    0x2d, 0x06, 0x69, 0xd6, 0xa8, 0x82, 0x26, 0x0f, 0xeb, 0x31, 0x8f, 0x4b, 0x91, 0x1c, 0xa2, 0x0b,
+    0x7b, 0x98, 0x6d, 0x86, 0x8f, 0x28, 0x1e, 0x53, 0xfc, 0x1c, 0x9b, 0xbe, 0x10, 0x76, 0x98, 0x62,
+    0xd4, 0x71, 0x8c, 0xe6, 0x8e, 0xdb, 0x5e, 0x57, 0x3b, 0xdb, 0x59, 0x82, 0x1d, 0x3d, 0x19, 0xce,
+    0x34, 0x3b, 0x4e, 0xc8, 0xe2, 0x9a, 0xf4, 0x1c, 0x52, 0xaf, 0x3e, 0xfe, 0x48, 0x5a, 0x2d, 0x42,
+    0x5f, 0x08, 0x21, 0xd4, 0xc7, 0x6c, 0x8d, 0x0e, 0x7c, 0x87, 0x2b, 0x1a, 0x1c, 0x3c, 0x88, 0xb2,
+    0x55, 0x84, 0xa2, 0xaf, 0x51, 0x6b, 0xec, 0x2f, 0x5b, 0xd0, 0xb9, 0x8c, 0x8e, 0x94, 0x0a, 0xdd,
+    0x8f, 0xa1, 0xd0, 0xc8, 0x5e, 0x30, 0x3e, 0xa5, 0x8c, 0x38, 0x8e, 0x89, 0x9c, 0xd7, 0x1e, 0x61,
+    0x96, 0xa3, 0x2a, 0xd8, 0x13, 0x12, 0x9f, 0xad, 0x4f, 0x0a, 0x68, 0xaf, 0x82, 0x73, 0xd8, 0xfc,
+    0x0b, 0x7f, 0x88, 0x4f, 0x3d, 0xcb, 0x9f, 0x21, 0xd0, 0xea, 0x6c, 0xe2, 0xb3, 0x49, 0x6f, 0x9e,
+    0x08, 0xa8, 0xa4, 0x7f, 0xa8, 0xf3, 0xd0, 0x08, 0x1a, 0x13, 0x14, 0xf6, 0x89, 0xd1, 0xfe, 0x80,
+    0x24, 0xfe, 0x9e, 0xed, 0x1d, 0xb2, 0x24, 0x9a, 0xaf, 0xab, 0x4b, 0x23, 0x7b, 0x44, 0x8e, 0x89,
+    0xc6, 0x1e, 0x6a, 0x34, 0xc9, 0xe2, 0x22, 0xdd, 0x0a, 0x89, 0xb2, 0x8f, 0x8c, 0x61, 0x4c, 0x0e,
+    0x81, 0xb7, 0x30, 0x3e, 0xe0, 0x1c, 0x6a, 0x6c, 0xbb, 0x46, 0xd2, 0x5b, 0xbc, 0x0c, 0x6b, 0x39,
+    0x7e, 0x45, 0x3a, 0x71, 0x48, 0xd6, 0xe4, 0xe4, 0x6b, 0x4a, 0x26, 0xa0, 0xa1, 0xb6, 0xd5, 0x4f,
+    0xb1, 0xd8, 0x71, 0x5c, 0x64, 0xdd, 0xa3, 0x9f, 0x5c, 0x0e, 0xe5, 0x04, 0x6d, 0x25, 0x4e, 0x58,
+    0x83, 0x36, 0x1b, 0xa9, 0x9a, 0x1c, 0xf9, 0x41, 0x58, 0x0e, 0x07, 0x0d, 0xd6, 0x25, 0x51, 0xbd,
+    0x9d, 0x25, 0x0b, 0x34, 0x62, 0x3f, 0xc3, 0x48, 0x23, 0xd2, 0xe1, 0x3c, 0x7e, 0xc1, 0xb3, 0x7c,
+    0x2a, 0x1d, 0x2a, 0xcf, 0x06, 0x81, 0x51, 0x9a, 0x80, 0x8d, 0xc6, 0xd4, 0x1c, 0x4a, 0x8a, 0x42,
+    0xd1, 0x4b, 0x6c, 0x5c, 0x03, 0x9e, 0xa7, 0x4e, 0x69, 0x11, 0x5f, 0x71, 0xa6, 0x98, 0x05, 0x9c,
+    0x53, 0x4f, 0x52, 0x3b, 0x4e, 0x06, 0x5b, 0x3f, 0xe9, 0x3d, 0x58, 0x7c, 0xae, 0x1f, 0x31, 0x88,
+    0x0c, 0x6c, 0x59, 0x73, 0x61, 0xa9, 0x50, 0x02, 0x2f, 0x1b, 0x97, 0x81, 0x5e, 0x75, 0x25, 0x2c,
+    0x3b, 0x48, 0x0e, 0xc9, 0xe1, 0x16, 0xf6, 0x35, 0x53, 0xb1, 0x7e, 0xdb, 0x17, 0x0e, 0x7e, 0xb8,
+    0xd3, 0x79, 0x0c, 0x14, 0xd8, 0x07, 0xba, 0x8f, 0x68, 0xf5, 0x04, 0x6d, 0xf5, 0xe6, 0x95, 0x16,
+    0xd7, 0xbe, 0xf9, 0xc5, 0x28, 0x61, 0x0c, 0xf4, 0x0e, 0x8a, 0xb2, 0x13, 0xdb, 0x48, 0xcb, 0x0d,
+    0x47, 0x8e, 0x7d, 0xe2, 0x3c, 0xf7, 0x35, 0x7f, 0xd0, 0xbc, 0x3b, 0x05, 0x72, 0x72, 0x6c, 0x8c,
+    0xa4, 0x39, 0x3c, 0x6a, 0x9e, 0x4b, 0x89, 0xd7, 0x18, 0x2e, 0x12, 0x99, 0xb5, 0xd3, 0x1b, 0x45,
+    0xcf, 0x27, 0x2d, 0xa0, 0x52, 0xc2, 0x5e, 0x60, 0x3f, 0xd9, 0x48, 0x4b, 0x33, 0xba, 0x65, 0x31,
+    0x1c, 0x98, 0xda, 0x27, 0x30, 0x9e, 0xb8, 0x12, 0xc2, 0x29, 0x5c, 0xd3, 0x4c, 0xf1, 0x30, 0x48,
+    0x5b, 0x1a, 0x2f, 0x37, 0xd4, 0xbe, 0x22, 0x73, 0x6f, 0x12, 0x48, 0x54, 0x6d, 0x3b, 0x9c, 0x44,
+    0x8e, 0xe0, 0x83, 0xc8, 0x19, 0xce, 0x71, 0x43, 0x16, 0x0a, 0x8b, 0xb4, 0x2c, 0x8c, 0x01, 0xc6,
+    // the map checksum would go here, but clients never fill it in
+    0x00, 0x00, 0x00, 0x00,
+];
+
+// CRC-16/CCITT, as computed by Quake's CRC_Block
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+    }
+
+    crc
+}
+
+/// Computes the checksum byte of a move command.
+///
+/// `data` is everything in the command after the checksum. The checksum is
+/// salted with bytes picked from a fixed table by the packet's sequence
+/// number, so a move can't be replayed in a later packet.
+pub fn move_checksum(data: &[u8], sequence: u32) -> u8 {
+    let i = sequence as usize % (MOVE_CHECKSUM_TABLE.len() - 8);
+    let salt = &MOVE_CHECKSUM_TABLE[i..i + 4];
+
+    let mut block = data[..data.len().min(MOVE_CHECKSUM_LEN)].to_vec();
+    block.extend_from_slice(&[
+        sequence as u8 ^ salt[0],
+        salt[1],
+        (sequence >> 8) as u8 ^ salt[2],
+        salt[3],
+    ]);
+
+    crc16(&block) as u8
+}
+
+/// Computes the map checksum a server expects in the client's `prespawn`
+/// command, proving that the client has the same map.
+///
+/// Each lump's MD4 digest is folded to 32 bits and the results XORed together.
+/// The entities, visibility, nodes and leaves are left out, so maps that were
+/// only re-lit or re-vised still match.
+pub fn map_checksum(bsp: &[u8]) -> Result<i32, NetError> {
+    let mut reader = bsp;
+    let _version = reader.read_i32::<LittleEndian>()?;
+
+    let mut checksum = 0;
+    for lump in 0..BSP_LUMP_COUNT {
+        let ofs = reader.read_u32::<LittleEndian>()? as usize;
+        let len = reader.read_u32::<LittleEndian>()? as usize;
+        if BSP_UNCHECKED_LUMPS.contains(&lump) {
+            continue;
+        }
+
+        let data = bsp
+            .get(ofs..ofs + len)
+            .ok_or_else(|| NetError::InvalidData(format!("BSP lump {} out of bounds", lump)))?;
+        for word in Md4::digest(data).chunks(4) {
+            checksum ^= LittleEndian::read_u32(word);
+        }
+    }
+
+    Ok(checksum as i32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::common::net::PointEntityKind;
+    use std::time::Duration as StdDuration;
+
+    fn state(ent_id: u16, model_id: u8, x: f32) -> QwEntityState {
+        QwEntityState {
+            model_id,
+            origin: Vector3::new(x, 0.0, 0.0),
+            ..QwEntityState::empty(ent_id)
+        }
+    }
+
+    #[test]
+    fn test_userinfo_parse_set_display() {
+        let mut info = Userinfo::parse("\\name\\player\\topcolor\\4").unwrap();
+        assert_eq!(info.get("name"), Some("player"));
+        assert_eq!(info.get("topcolor"), Some("4"));
+
+        info.set("name", "other").unwrap();
+        info.set("rate", "2500").unwrap();
+        info.set("topcolor", "").unwrap();
+        assert!(info.set("bad", "a\\b").is_err());
+
+        assert_eq!(info.to_string(), "\\name\\other\\rate\\2500");
+    }
+
+    #[test]
+    fn test_userinfo_max_len() {
+        let mut info = Userinfo::new();
+        let long = "x".repeat(MAX_INFO_STRING);
+        assert!(info.set("name", long).is_err());
+        assert_eq!(info.get("name"), None);
+    }
+
+    #[test]
+    fn test_oob_response_read_write_eq() {
+        for src in vec![
+            OobResponse::Challenge(12345),
+            OobResponse::Accept,
+            OobResponse::Print(String::from("Server is full.\n")),
+        ] {
+            let bytes = src.to_bytes().unwrap();
+            assert_eq!(OobResponse::from_bytes(&bytes).unwrap(), src);
+        }
+    }
+
+    #[test]
+    fn test_oob_request_connect_format() {
+        let mut userinfo = Userinfo::new();
+        userinfo.set("name", "player").unwrap();
+        let bytes = OobRequest::Connect {
+            protocol: PROTOCOL_VERSION,
+            qport: 1234,
+            challenge: 5678,
+            userinfo,
+        }
+        .to_bytes()
+        .unwrap();
+
+        assert_eq!(&bytes[..4], &[0xFF; 4]);
+        assert_eq!(
+            &bytes[4..],
+            &b"connect 28 1234 5678 \"\\name\\player\"\n\0"[..]
+        );
+    }
+
+    #[test]
+    fn test_netchan_header_read_write_eq() {
+        let src = NetchanHeader {
+            sequence: 100,
+            reliable: true,
+            ack: 99,
+            ack_reliable: false,
+            qport: Some(4321),
+        };
+        let mut packet = Vec::new();
+        src.write(&mut packet).unwrap();
+        let dst = NetchanHeader::read(&mut packet.as_slice(), true).unwrap();
+
+        assert_eq!(src, dst);
+    }
+
+    #[test]
+    fn test_packet_entities_read_write_eq() {
+        let from = state(5, 2, 0.0);
+        let mut to = state(5, 3, 64.0);
+        to.angles.y = Deg(90.0);
+
+        let src = QwServerCmd::PacketEntities {
+            delta_from: Some(7),
+            deltas: vec![EntityDelta::between(&from, &to), EntityDelta::remove(9)],
+        };
+        let mut packet = Vec::new();
+        src.serialize(&mut packet).unwrap();
+        let mut reader = BufReader::new(packet.as_slice());
+        let dst = QwServerCmd::deserialize(&mut reader).unwrap().unwrap();
+
+        assert_eq!(src, dst);
+    }
+
+    #[test]
+    fn test_shared_cmds_read_write_eq() {
+        let mut baseline = state(12, 3, 64.0);
+        baseline.colormap = 2;
+        baseline.angles.y = Deg(90.0);
+
+        let src = vec![
+            QwServerCmd::UpdateStat {
+                stat: 15,
+                value: 200,
+            },
+            QwServerCmd::Sound {
+                entity_id: 700,
+                channel: 3,
+                sound_id: 9,
+                volume: Some(128),
+                attenuation: None,
+                position: Vector3::new(8.0, -16.0, 24.0),
+            },
+            QwServerCmd::StopSound {
+                entity_id: 5,
+                channel: 1,
+            },
+            QwServerCmd::SpawnBaseline { baseline },
+            QwServerCmd::TempEntity {
+                temp_entity: QwTempEntity::Gunshot {
+                    count: 3,
+                    origin: Vector3::new(1.0, 2.0, 3.0),
+                },
+            },
+            QwServerCmd::TempEntity {
+                temp_entity: QwTempEntity::Shared(TempEntity::Point {
+                    kind: PointEntityKind::Explosion,
+                    origin: Vector3::new(1.0, 2.0, 3.0),
+                }),
+            },
+            QwServerCmd::CdTrack { track: 4 },
+            QwServerCmd::Nails {
+                projectiles: vec![[1, 2, 3, 4, 5, 6]],
+            },
+        ];
+
+        let mut packet = Vec::new();
+        for cmd in src.iter() {
+            cmd.serialize(&mut packet).unwrap();
+        }
+
+        let mut reader = BufReader::new(packet.as_slice());
+        let mut dst = Vec::new();
+        while let Some(cmd) = QwServerCmd::deserialize(&mut reader).unwrap() {
+            dst.push(cmd);
+        }
+
+        assert_eq!(src, dst);
+    }
+
+    #[test]
+    fn test_player_info_read_write_eq() {
+        let src = QwServerCmd::PlayerInfo(QwPlayerInfo {
+            player_id: 3,
+            origin: Vector3::new(32.0, 0.0, -8.0),
+            frame_id: 6,
+            msec: Some(13),
+            command: Some(UserCmd {
+                angles: Vector3::new(Deg(-45.0), Deg(90.0), Deg(0.0)),
+                forward: 400,
+                side: 0,
+                up: -200,
+                buttons: 1,
+                impulse: 0,
+                msec: 13,
+            }),
+            velocity: [Some(320), None, Some(-100)],
+            model_id: None,
+            skin_id: Some(2),
+            effects: None,
+            weapon_frame: Some(1),
+            dead: false,
+            gib: false,
+        });
+        let mut packet = Vec::new();
+        src.serialize(&mut packet).unwrap();
+        let mut reader = BufReader::new(packet.as_slice());
+        let dst = QwServerCmd::deserialize(&mut reader).unwrap().unwrap();
+
+        assert_eq!(src, dst);
+    }
+
+    #[test]
+    fn test_crc16() {
+        // the standard check value for CRC-16/CCITT-FALSE
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn test_move_serialize() {
+        let first = UserCmd {
+            msec: 13,
+            ..UserCmd::NULL
+        };
+        let second = UserCmd {
+            forward: 200,
+            msec: 14,
+            ..first
+        };
+        let third = UserCmd {
+            angles: Vector3::new(Deg(0.0), Deg(90.0), Deg(0.0)),
+            buttons: 1,
+            msec: 13,
+            ..second
+        };
+        let cmd = QwClientCmd::Move {
+            sequence: 1234,
+            loss: 5,
+            cmds: [first, second, third],
+        };
+
+        let mut packet = Vec::new();
+        cmd.serialize(&mut packet).unwrap();
+
+        #[rustfmt::skip]
+        let body = [
+            5,
+            // nothing set
+            0x00, 13,
+            // forward move
+            0x08, 0xC8, 0x00, 14,
+            // yaw and attack
+            0x44, 0x00, 0x40, 0x01, 13,
+        ];
+        assert_eq!(packet[0], CLC_MOVE);
+        assert_eq!(packet[1], move_checksum(&body, 1234));
+        assert_eq!(&packet[2..], &body[..]);
+
+        // the same move in another packet has another checksum
+        assert_ne!(move_checksum(&body, 1234), move_checksum(&body, 1235));
+    }
+
+    #[test]
+    fn test_channel_resends_reliable() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(StdDuration::from_secs(1)))
+            .unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let mut chan = QwChannel::new(client, server.local_addr().unwrap(), 1234).unwrap();
+
+        let mut buf = [0; MAX_PACKET];
+        let mut recv = || {
+            let len = server.recv(&mut buf).unwrap();
+            let mut reader = &buf[..len];
+            let header = NetchanHeader::read(&mut reader, true).unwrap();
+            (header, reader.to_vec())
+        };
+        let reply = |sequence, ack, ack_reliable| {
+            let mut packet = Vec::new();
+            NetchanHeader {
+                sequence,
+                reliable: false,
+                ack,
+                ack_reliable,
+                qport: None,
+            }
+            .write(&mut packet)
+            .unwrap();
+            server.send_to(&packet, client_addr).unwrap();
+        };
+        let mut recv_reply = |chan: &mut QwChannel| {
+            for _ in 0..100 {
+                if chan.recv().unwrap().is_some() {
+                    return;
+                }
+                std::thread::sleep(StdDuration::from_millis(10));
+            }
+            panic!("no reply");
+        };
+
+        chan.send_reliable(b"new").unwrap();
+        chan.transmit(&[]).unwrap();
+        let (header, data) = recv();
+        assert!(header.reliable);
+        assert_eq!(data, b"new");
+
+        // the server answers a later packet without acknowledging the reliable
+        // message, so it goes out again
+        chan.transmit(&[]).unwrap();
+        assert!(!recv().0.reliable);
+        chan.transmit(&[]).unwrap();
+        recv();
+        reply(1, 3, false);
+        recv_reply(&mut chan);
+        chan.transmit(&[]).unwrap();
+        let (header, data) = recv();
+        assert!(header.reliable);
+        assert_eq!(data, b"new");
+
+        // once it's acknowledged, it stops
+        reply(2, 4, true);
+        recv_reply(&mut chan);
+        chan.transmit(&[]).unwrap();
+        let (header, data) = recv();
+        assert!(!header.reliable);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn test_map_checksum_skips_unchecked_lumps() {
+        // a header followed by 4 bytes for each lump
+        let mut bsp = vec![0; 4 + BSP_LUMP_COUNT * 8];
+        for lump in 0..BSP_LUMP_COUNT {
+            let ofs = bsp.len() as u32;
+            LittleEndian::write_u32(&mut bsp[4 + lump * 8..], ofs);
+            LittleEndian::write_u32(&mut bsp[8 + lump * 8..], 4);
+            bsp.extend_from_slice(&[lump as u8; 4]);
+        }
+        let lump_ofs = |lump: usize| 4 + BSP_LUMP_COUNT * 8 + lump * 4;
+        let checksum = map_checksum(&bsp).unwrap();
+
+        // the entities lump doesn't count
+        let mut changed = bsp.clone();
+        changed[lump_ofs(0)] ^= 0xFF;
+        assert_eq!(map_checksum(&changed).unwrap(), checksum);
+
+        // the planes lump does
+        let mut changed = bsp.clone();
+        changed[lump_ofs(1)] ^= 0xFF;
+        assert_ne!(map_checksum(&changed).unwrap(), checksum);
+
+        // lumps must lie within the file
+        bsp.truncate(bsp.len() - 1);
+        assert!(map_checksum(&bsp).is_err());
+    }
+
+    #[test]
+    fn test_apply_packet_entities() {
+        let from = vec![state(1, 1, 0.0), state(2, 1, 0.0), state(4, 1, 0.0)];
+        let deltas = vec![
+            EntityDelta::between(&from[1], &state(2, 1, 32.0)),
+            EntityDelta::between(&state(3, 0, 0.0), &state(3, 5, 0.0)),
+            EntityDelta::remove(4),
+        ];
+
+        let entities = apply_packet_entities(&from, &deltas, |id| state(id, 0, 0.0)).unwrap();
+
+        assert_eq!(
+            entities,
+            vec![state(1, 1, 0.0), state(2, 1, 32.0), state(3, 5, 0.0)]
+        );
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Simulated network conditions for testing.
+//!
+//! `NetSim` sits between a `QSocket` and its UDP socket and holds packets back
+//! to simulate latency and jitter, drops a fraction of them to simulate loss,
+//! and delays some of them further so that later packets overtake them. It
+//! applies to packets in both directions, so the round-trip time is roughly
+//! twice the configured latency.
+
+use std::{
+    io::{self, ErrorKind},
+    net::{SocketAddr, UdpSocket},
+    thread,
+};
+
+use crate::common::net::BlockingMode;
+
+use chrono::{DateTime, Duration, Utc};
+use rand::{rngs::SmallRng, Rng as _, SeedableRng};
+
+// extra delay applied to reordered packets
+const REORDER_DELAY_MS: f32 = 50.0;
+
+// how long to sleep between polls while waiting on a delayed packet
+const POLL_INTERVAL_MS: u64 = 1;
+
+/// Simulated network conditions, one field per cvar.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NetSimVars {
+    /// One-way latency in milliseconds.
+    pub net_fakelag: f32,
+
+    /// Maximum random variation in latency in milliseconds.
+    pub net_fakejitter: f32,
+
+    /// Percentage of packets to drop.
+    pub net_fakeloss: f32,
+
+    /// Percentage of packets to deliver out of order.
+    pub net_fakereorder: f32,
+}
+
+impl NetSimVars {
+    /// Returns `true` if any simulated condition is turned on.
+    pub fn enabled(&self) -> bool {
+        self.net_fakelag > 0.0
+            || self.net_fakejitter > 0.0
+            || self.net_fakeloss > 0.0
+            || self.net_fakereorder > 0.0
+    }
+}
+
+#[derive(Debug)]
+struct DelayedPacket {
+    deliver_time: DateTime<Utc>,
+    addr: SocketAddr,
+    data: Vec<u8>,
+}
+
+/// A queue of packets ordered by delivery time.
+#[derive(Debug, Default)]
+struct DelayQueue {
+    packets: Vec<DelayedPacket>,
+}
+
+impl DelayQueue {
+    fn push(&mut self, deliver_time: DateTime<Utc>, addr: SocketAddr, data: &[u8]) {
+        // packets due at the same time keep the order they were sent in
+        let pos = self
+            .packets
+            .iter()
+            .position(|p| p.deliver_time > deliver_time)
+            .unwrap_or(self.packets.len());
+
+        self.packets.insert(
+            pos,
+            DelayedPacket {
+                deliver_time,
+                addr,
+                data: data.to_owned(),
+            },
+        );
+    }
+
+    /// Remove and return the next packet due for delivery as of `now`.
+    fn pop_due(&mut self, now: DateTime<Utc>) -> Option<DelayedPacket> {
+        match self.packets.first() {
+            Some(p) if p.deliver_time <= now => Some(self.packets.remove(0)),
+            _ => None,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+}
+
+/// Injects latency, jitter, reordering and loss into a UDP connection.
+#[derive(Debug)]
+pub struct NetSim {
+    vars: NetSimVars,
+    rng: SmallRng,
+    outgoing: DelayQueue,
+    incoming: DelayQueue,
+}
+
+impl NetSim {
+    pub fn new(vars: NetSimVars) -> NetSim {
+        NetSim {
+            vars,
+            rng: SmallRng::from_entropy(),
+            outgoing: DelayQueue::default(),
+            incoming: DelayQueue::default(),
+        }
+    }
+
+    pub fn set_vars(&mut self, vars: NetSimVars) {
+        self.vars = vars;
+    }
+
+    /// Returns `true` if no packets are being held back.
+    pub fn is_idle(&self) -> bool {
+        self.outgoing.is_empty() && self.incoming.is_empty()
+    }
+
+    /// Pick the delay for a new packet, or `None` if it should be dropped.
+    fn delay(&mut self) -> Option<Duration> {
+        if self.rng.gen::<f32>() * 100.0 < self.vars.net_fakeloss {
+            return None;
+        }
+
+        let mut ms = self.vars.net_fakelag.max(0.0);
+        if self.vars.net_fakejitter > 0.0 {
+            ms += self.rng.gen::<f32>() * self.vars.net_fakejitter;
+        }
+        if self.rng.gen::<f32>() * 100.0 < self.vars.net_fakereorder {
+            ms += REORDER_DELAY_MS;
+        }
+
+        Some(Duration::microseconds((ms * 1000.0) as i64))
+    }
+
+    /// Queue a packet to be sent to `remote` once its delay has elapsed.
+    pub fn send_to(
+        &mut self,
+        socket: &UdpSocket,
+        packet: &[u8],
+        remote: SocketAddr,
+    ) -> io::Result<()> {
+        let now = Utc::now();
+        if let Some(delay) = self.delay() {
+            self.outgoing.push(now + delay, remote, packet);
+        }
+
+        self.flush(socket, now)
+    }
+
+    /// Send any outgoing packets whose delay has elapsed.
+    fn flush(&mut self, socket: &UdpSocket, now: DateTime<Utc>) -> io::Result<()> {
+        while let Some(packet) = self.outgoing.pop_due(now) {
+            socket.send_to(&packet.data, packet.addr)?;
+        }
+
+        Ok(())
+    }
+
+    /// Receive the next incoming packet whose delay has elapsed.
+    ///
+    /// This follows the same blocking rules as `QSocket::recv_msg`. If no
+    /// packet is ready in time, an error of kind `WouldBlock` is returned.
+    pub fn recv_from(
+        &mut self,
+        socket: &UdpSocket,
+        buf: &mut [u8],
+        block: BlockingMode,
+    ) -> io::Result<(usize, SocketAddr)> {
+        let deadline = match block {
+            BlockingMode::Blocking => None,
+            BlockingMode::NonBlocking => Some(Utc::now()),
+            BlockingMode::Timeout(d) => Some(Utc::now() + d),
+        };
+
+        // the real socket is always polled; blocking is simulated below
+        socket.set_nonblocking(true)?;
+
+        loop {
+            let now = Utc::now();
+            self.flush(socket, now)?;
+
+            // pull in everything that has arrived on the real socket
+            loop {
+                match socket.recv_from(buf) {
+                    Ok((len, addr)) => {
+                        if let Some(delay) = self.delay() {
+                            self.incoming.push(now + delay, addr, &buf[..len]);
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            if let Some(packet) = self.incoming.pop_due(now) {
+                let len = packet.data.len().min(buf.len());
+                buf[..len].copy_from_slice(&packet.data[..len]);
+                return Ok((len, packet.addr));
+            }
+
+            if let Some(d) = deadline {
+                if now >= d {
+                    return Err(ErrorKind::WouldBlock.into());
+                }
+            }
+
+            thread::sleep(std::time::Duration::from_millis(POLL_INTERVAL_MS));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:26000".parse().unwrap()
+    }
+
+    #[test]
+    fn test_delay_queue_order() {
+        let start = Utc::now();
+        let mut queue = DelayQueue::default();
+        queue.push(start + Duration::milliseconds(20), addr(), &[2]);
+        queue.push(start + Duration::milliseconds(10), addr(), &[0]);
+        queue.push(start + Duration::milliseconds(10), addr(), &[1]);
+
+        assert!(queue.pop_due(start).is_none());
+
+        let due = start + Duration::milliseconds(15);
+        assert_eq!(queue.pop_due(due).unwrap().data, vec![0]);
+        assert_eq!(queue.pop_due(due).unwrap().data, vec![1]);
+        assert!(queue.pop_due(due).is_none());
+
+        let due = start + Duration::milliseconds(20);
+        assert_eq!(queue.pop_due(due).unwrap().data, vec![2]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_delay() {
+        let mut sim = NetSim::new(NetSimVars {
+            net_fakelag: 100.0,
+            net_fakejitter: 20.0,
+            ..Default::default()
+        });
+
+        for _ in 0..100 {
+            let delay = sim.delay().unwrap();
+            assert!(delay >= Duration::milliseconds(100));
+            assert!(delay <= Duration::milliseconds(120));
+        }
+
+        sim.set_vars(NetSimVars {
+            net_fakeloss: 100.0,
+            ..Default::default()
+        });
+        assert!(sim.delay().is_none());
+    }
+
+    #[test]
+    fn test_vars_enabled() {
+        assert!(!NetSimVars::default().enabled());
+        assert!(NetSimVars {
+            net_fakereorder: 5.0,
+            ..Default::default()
+        }
+        .enabled());
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Per-connection traffic statistics.
+
+use std::collections::VecDeque;
+
+use chrono::Duration;
+
+/// Number of latency samples kept for the net graph.
+pub const LATENCY_GRAPH_LEN: usize = 64;
+
+// weight given to each new round-trip sample in the smoothed estimate
+const RTT_SMOOTHING: f64 = 0.125;
+
+/// Traffic counters for a single connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NetStats {
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+
+    /// Reliable packets sent again because they went unacknowledged.
+    pub resends: u64,
+
+    /// Reliable packets received out of sequence and discarded.
+    pub dropped_reliables: u64,
+
+    /// Unreliable packets skipped over by a newer sequence number.
+    pub dropped_unreliables: u64,
+
+    /// Messages held back because the previous reliable message was still
+    /// unacknowledged.
+    pub choke: u64,
+
+    /// Smoothed round-trip time, once at least one reliable packet has been
+    /// acknowledged.
+    pub rtt: Option<Duration>,
+}
+
+impl NetStats {
+    pub(super) fn record_sent(&mut self, len: usize) {
+        self.packets_sent += 1;
+        self.bytes_sent += len as u64;
+    }
+
+    pub(super) fn record_received(&mut self, len: usize) {
+        self.packets_received += 1;
+        self.bytes_received += len as u64;
+    }
+
+    /// Fold a new round-trip sample into the smoothed estimate.
+    pub(super) fn record_rtt(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => {
+                let rtt_us = rtt.num_microseconds().unwrap_or(i64::MAX) as f64;
+                let sample_us = sample.num_microseconds().unwrap_or(i64::MAX) as f64;
+                Duration::microseconds(
+                    (rtt_us + (sample_us - rtt_us) * RTT_SMOOTHING).round() as i64
+                )
+            }
+
+            None => sample,
+        });
+    }
+}
+
+/// A ring buffer of recent round-trip times, suitable for drawing a net graph.
+///
+/// Each entry is either a measured round-trip time or `None` for a packet that
+/// had to be resent.
+#[derive(Clone, Debug)]
+pub struct LatencyGraph {
+    samples: VecDeque<Option<Duration>>,
+}
+
+impl LatencyGraph {
+    pub fn new() -> LatencyGraph {
+        LatencyGraph {
+            samples: VecDeque::with_capacity(LATENCY_GRAPH_LEN),
+        }
+    }
+
+    pub(super) fn push(&mut self, sample: Option<Duration>) {
+        if self.samples.len() == LATENCY_GRAPH_LEN {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back(sample);
+    }
+
+    /// Return an iterator over the samples, oldest first.
+    pub fn samples(&self) -> impl DoubleEndedIterator<Item = Option<Duration>> + '_ {
+        self.samples.iter().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record_rtt() {
+        let mut stats = NetStats::default();
+        assert_eq!(stats.rtt, None);
+
+        stats.record_rtt(Duration::milliseconds(100));
+        assert_eq!(stats.rtt, Some(Duration::milliseconds(100)));
+
+        stats.record_rtt(Duration::milliseconds(180));
+        assert_eq!(stats.rtt, Some(Duration::milliseconds(110)));
+    }
+
+    #[test]
+    fn test_latency_graph_wraps() {
+        let mut graph = LatencyGraph::new();
+        for i in 0..LATENCY_GRAPH_LEN as i64 + 2 {
+            graph.push(Some(Duration::milliseconds(i)));
+        }
+        graph.push(None);
+
+        assert_eq!(graph.len(), LATENCY_GRAPH_LEN);
+        let samples = graph.samples().collect::<Vec<_>>();
+        assert_eq!(samples[0], Some(Duration::milliseconds(3)));
+        assert_eq!(samples[LATENCY_GRAPH_LEN - 1], None);
+    }
+}
@@ -118,6 +118,11 @@ pub fn command(input: &str) -> nom::IResult<&str, Vec<&str>> {
     terminated(many1(preceded(space0, arg)), command_terminator)(input)
 }
 
+/// Match zero or more empty lines.
+pub fn empty_lines(input: &str) -> nom::IResult<&str, Vec<&str>> {
+    many0(empty_line)(input)
+}
+
 pub fn commands(input: &str) -> nom::IResult<&str, Vec<Vec<&str>>> {
     delimited(
         many0(empty_line),
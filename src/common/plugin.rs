@@ -0,0 +1,247 @@
+// Copyright © 2020 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Engine extensions compiled in by downstream crates.
+//!
+//! A plugin implements `ClientPlugin` or `ServerPlugin` and is added to the
+//! `Host` before the main loop starts. Every hook has a default that does
+//! nothing, so a plugin only implements the ones it needs.
+
+use crate::common::{
+    console::{CmdRegistry, CvarRegistry},
+    net::ServerCmd,
+};
+
+use chrono::Duration;
+
+/// A line of text drawn on the HUD by a plugin or client script.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HudText {
+    /// The distance from the left edge of the screen, in HUD units.
+    pub x: i32,
+
+    /// The distance from the top edge of the screen, in HUD units.
+    pub y: i32,
+
+    pub text: String,
+}
+
+/// Extends the client.
+pub trait ClientPlugin {
+    /// The name shown by the `plugins` command.
+    fn name(&self) -> &str;
+
+    /// Add the plugin's console commands and cvars. Called once, when the
+    /// plugin is added.
+    fn register(&mut self, _cmds: &mut CmdRegistry, _cvars: &CvarRegistry) {}
+
+    /// Called once per host frame.
+    fn frame(&mut self, _frame_time: Duration) {}
+
+    /// Inspect a message from the server before the client handles it.
+    ///
+    /// Returns `false` to drop the message.
+    fn filter_server_cmd(&mut self, _cmd: &ServerCmd) -> bool {
+        true
+    }
+
+    /// Add text to the HUD. `width` and `height` are the size of the screen in
+    /// HUD units.
+    fn hud(&mut self, _width: i32, _height: i32, _text: &mut Vec<HudText>) {}
+}
+
+/// Extends the server.
+pub trait ServerPlugin {
+    /// The name shown by the `plugins` command.
+    fn name(&self) -> &str;
+
+    /// Add the plugin's console commands and cvars. Called once, when the
+    /// plugin is added.
+    fn register(&mut self, _cmds: &mut CmdRegistry, _cvars: &CvarRegistry) {}
+
+    /// Called once per host frame.
+    fn frame(&mut self, _frame_time: Duration) {}
+
+    /// Inspect a command sent by the client in `slot` before the server
+    /// handles it.
+    ///
+    /// Returns `false` to drop the command.
+    fn filter_client_cmd(&mut self, _slot: usize, _cmd: &str) -> bool {
+        true
+    }
+}
+
+/// The plugins loaded into the engine, in the order they were added.
+#[derive(Default)]
+pub struct Plugins {
+    client: Vec<Box<dyn ClientPlugin>>,
+    server: Vec<Box<dyn ServerPlugin>>,
+}
+
+impl Plugins {
+    pub fn new() -> Plugins {
+        Plugins::default()
+    }
+
+    pub fn add_client(
+        &mut self,
+        mut plugin: Box<dyn ClientPlugin>,
+        cmds: &mut CmdRegistry,
+        cvars: &CvarRegistry,
+    ) {
+        plugin.register(cmds, cvars);
+        self.client.push(plugin);
+    }
+
+    pub fn add_server(
+        &mut self,
+        mut plugin: Box<dyn ServerPlugin>,
+        cmds: &mut CmdRegistry,
+        cvars: &CvarRegistry,
+    ) {
+        plugin.register(cmds, cvars);
+        self.server.push(plugin);
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.client
+            .iter()
+            .map(|p| p.name())
+            .chain(self.server.iter().map(|p| p.name()))
+    }
+
+    pub fn frame(&mut self, frame_time: Duration) {
+        for plugin in self.client.iter_mut() {
+            plugin.frame(frame_time);
+        }
+
+        for plugin in self.server.iter_mut() {
+            plugin.frame(frame_time);
+        }
+    }
+
+    /// Returns `false` if any client plugin drops `cmd`.
+    ///
+    /// Every plugin sees the message, even if an earlier one dropped it.
+    pub fn filter_server_cmd(&mut self, cmd: &ServerCmd) -> bool {
+        self.client
+            .iter_mut()
+            .fold(true, |keep, p| p.filter_server_cmd(cmd) && keep)
+    }
+
+    /// Returns `false` if any server plugin drops `cmd`.
+    ///
+    /// Every plugin sees the command, even if an earlier one dropped it.
+    pub fn filter_client_cmd(&mut self, slot: usize, cmd: &str) -> bool {
+        self.server
+            .iter_mut()
+            .fold(true, |keep, p| p.filter_client_cmd(slot, cmd) && keep)
+    }
+
+    /// Collect the text client plugins draw on the HUD.
+    pub fn hud(&mut self, width: i32, height: i32) -> Vec<HudText> {
+        let mut text = Vec::new();
+        for plugin in self.client.iter_mut() {
+            plugin.hud(width, height, &mut text);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{cell::RefCell, rc::Rc};
+
+    // counts frames and drops every `say` command
+    struct Counter {
+        frames: Rc<RefCell<usize>>,
+    }
+
+    impl ServerPlugin for Counter {
+        fn name(&self) -> &str {
+            "counter"
+        }
+
+        fn register(&mut self, cmds: &mut CmdRegistry, _cvars: &CvarRegistry) {
+            let frames = self.frames.clone();
+            cmds.insert("frames", Box::new(move |_| format!("{}", frames.borrow())))
+                .unwrap();
+        }
+
+        fn frame(&mut self, _frame_time: Duration) {
+            *self.frames.borrow_mut() += 1;
+        }
+
+        fn filter_client_cmd(&mut self, _slot: usize, cmd: &str) -> bool {
+            !cmd.starts_with("say")
+        }
+    }
+
+    struct Clock;
+
+    impl ClientPlugin for Clock {
+        fn name(&self) -> &str {
+            "clock"
+        }
+
+        fn hud(&mut self, width: i32, _height: i32, text: &mut Vec<HudText>) {
+            text.push(HudText {
+                x: width - 40,
+                y: 0,
+                text: "12:00".to_owned(),
+            });
+        }
+    }
+
+    #[test]
+    fn test_plugins() {
+        let names = Rc::new(RefCell::new(Vec::new()));
+        let mut cmds = CmdRegistry::new(names.clone());
+        let cvars = CvarRegistry::new(names);
+
+        let mut plugins = Plugins::new();
+        plugins.add_client(Box::new(Clock), &mut cmds, &cvars);
+        plugins.add_server(
+            Box::new(Counter {
+                frames: Rc::new(RefCell::new(0)),
+            }),
+            &mut cmds,
+            &cvars,
+        );
+        assert_eq!(
+            plugins.names().collect::<Vec<_>>(),
+            vec!["clock", "counter"]
+        );
+
+        plugins.frame(Duration::milliseconds(16));
+        plugins.frame(Duration::milliseconds(16));
+        assert_eq!(cmds.exec("frames", &[]).unwrap(), "2");
+
+        assert!(plugins.filter_client_cmd(0, "kill"));
+        assert!(!plugins.filter_client_cmd(0, "say hello"));
+        assert!(plugins.filter_server_cmd(&ServerCmd::NoOp));
+
+        let hud = plugins.hud(320, 200);
+        assert_eq!(hud.len(), 1);
+        assert_eq!(hud[0].x, 280);
+    }
+}
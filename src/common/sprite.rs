@@ -17,7 +17,7 @@
 
 use std::io::{BufReader, Read, Seek};
 
-use crate::common::{engine, model::SyncType};
+use crate::common::{mdl::durations_from_intervals, model::SyncType};
 
 use byteorder::{LittleEndian, ReadBytesExt};
 use cgmath::Vector3;
@@ -106,6 +106,26 @@ impl SpriteSubframe {
         self.height
     }
 
+    /// The distance from the sprite's origin to the top edge of the subframe.
+    pub fn up(&self) -> f32 {
+        self.up
+    }
+
+    /// The distance from the sprite's origin to the bottom edge of the subframe.
+    pub fn down(&self) -> f32 {
+        self.down
+    }
+
+    /// The distance from the sprite's origin to the left edge of the subframe.
+    pub fn left(&self) -> f32 {
+        self.left
+    }
+
+    /// The distance from the sprite's origin to the right edge of the subframe.
+    pub fn right(&self) -> f32 {
+        self.right
+    }
+
     pub fn indexed(&self) -> &[u8] {
         &self.indexed
     }
@@ -210,12 +230,11 @@ where
                 c => c as usize,
             };
 
-            let mut durations = Vec::with_capacity(subframe_count);
+            let mut intervals = Vec::with_capacity(subframe_count);
             for _ in 0..subframe_count {
-                durations.push(engine::duration_from_f32(
-                    reader.read_f32::<LittleEndian>().unwrap(),
-                ));
+                intervals.push(reader.read_f32::<LittleEndian>().unwrap());
             }
+            let durations = durations_from_intervals(&intervals);
 
             let mut subframes = Vec::with_capacity(subframe_count);
             for _ in 0..subframe_count {
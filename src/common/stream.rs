@@ -0,0 +1,453 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Background loading of large assets.
+//!
+//! Large files like music tracks and skybox faces are read on a worker thread
+//! a chunk at a time so they don't stall level loads. The worker always reads
+//! from the most important request: the nearest to the listener, preferring
+//! recently requested assets. `AssetStreamer::update` collects the results.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+use chrono::Duration;
+
+/// The number of bytes read from an asset at a time.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+// each second since an asset was last requested counts as this many units of
+// distance when choosing what to load next
+const RECENCY_WEIGHT: f32 = 256.0;
+
+/// A reader that can be handed to the worker thread.
+pub type AssetReader = Box<dyn Read + Send>;
+
+struct StreamJob {
+    id: u64,
+    reader: AssetReader,
+    data: Vec<u8>,
+    distance: f32,
+    request_time: Duration,
+}
+
+impl StreamJob {
+    // lower values are loaded first
+    fn priority(&self, time: Duration) -> f32 {
+        let age = (time - self.request_time).num_milliseconds().max(0) as f32 / 1000.0;
+        self.distance + age * RECENCY_WEIGHT
+    }
+}
+
+enum StreamCmd {
+    Request {
+        name: String,
+        id: u64,
+        reader: AssetReader,
+        distance: f32,
+        time: Duration,
+    },
+    Prioritize {
+        name: String,
+        distance: f32,
+        time: Duration,
+    },
+    Cancel(String),
+    Time(Duration),
+}
+
+enum StreamMsg {
+    Progress { id: u64, len: usize },
+    Done { id: u64, data: io::Result<Vec<u8>> },
+}
+
+// the jobs owned by the worker thread
+struct StreamQueue {
+    jobs: HashMap<String, StreamJob>,
+    time: Duration,
+}
+
+impl StreamQueue {
+    fn new() -> StreamQueue {
+        StreamQueue {
+            jobs: HashMap::new(),
+            time: Duration::zero(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    fn handle(&mut self, cmd: StreamCmd) {
+        match cmd {
+            StreamCmd::Request {
+                name,
+                id,
+                reader,
+                distance,
+                time,
+            } => {
+                self.jobs.insert(
+                    name,
+                    StreamJob {
+                        id,
+                        reader,
+                        data: Vec::new(),
+                        distance,
+                        request_time: time,
+                    },
+                );
+            }
+
+            StreamCmd::Prioritize {
+                name,
+                distance,
+                time,
+            } => {
+                if let Some(job) = self.jobs.get_mut(&name) {
+                    job.distance = distance;
+                    job.request_time = time;
+                }
+            }
+
+            StreamCmd::Cancel(name) => {
+                self.jobs.remove(&name);
+            }
+
+            StreamCmd::Time(time) => self.time = time,
+        }
+    }
+
+    // read a chunk of the most important asset
+    fn step(&mut self) -> Option<StreamMsg> {
+        let time = self.time;
+        let name = self
+            .jobs
+            .iter()
+            .min_by(|(_, a), (_, b)| {
+                a.priority(time)
+                    .partial_cmp(&b.priority(time))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+            .map(|(n, _)| n.to_owned())?;
+
+        let job = self.jobs.get_mut(&name).unwrap();
+        let start = job.data.len();
+        job.data.resize(start + CHUNK_SIZE, 0);
+        match job.reader.read(&mut job.data[start..]) {
+            Ok(0) => {
+                let job = self.jobs.remove(&name).unwrap();
+                let mut data = job.data;
+                data.truncate(start);
+                Some(StreamMsg::Done {
+                    id: job.id,
+                    data: Ok(data),
+                })
+            }
+
+            Ok(n) => {
+                job.data.truncate(start + n);
+                Some(StreamMsg::Progress {
+                    id: job.id,
+                    len: job.data.len(),
+                })
+            }
+
+            Err(e) => {
+                let job = self.jobs.remove(&name).unwrap();
+                Some(StreamMsg::Done {
+                    id: job.id,
+                    data: Err(e),
+                })
+            }
+        }
+    }
+}
+
+fn run_worker(cmd_rx: Receiver<StreamCmd>, msg_tx: Sender<StreamMsg>) {
+    let mut queue = StreamQueue::new();
+    loop {
+        // sleep until there's something to do
+        if queue.is_empty() {
+            match cmd_rx.recv() {
+                Ok(cmd) => queue.handle(cmd),
+                Err(_) => return,
+            }
+        }
+
+        while let Ok(cmd) = cmd_rx.try_recv() {
+            queue.handle(cmd);
+        }
+
+        if let Some(msg) = queue.step() {
+            if msg_tx.send(msg).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// an asset the worker is loading
+struct Pending {
+    id: u64,
+    len: usize,
+}
+
+/// Loads assets in the background in order of priority.
+pub struct AssetStreamer {
+    cmd_tx: Sender<StreamCmd>,
+    msg_rx: Receiver<StreamMsg>,
+    next_id: u64,
+    pending: HashMap<String, Pending>,
+    completed: HashMap<String, io::Result<Vec<u8>>>,
+}
+
+impl AssetStreamer {
+    /// Create a streamer with its own worker thread.
+    ///
+    /// The thread exits when the streamer is dropped.
+    pub fn new() -> AssetStreamer {
+        let (cmd_tx, cmd_rx) = mpsc::channel();
+        let (msg_tx, msg_rx) = mpsc::channel();
+        thread::spawn(move || run_worker(cmd_rx, msg_tx));
+
+        AssetStreamer {
+            cmd_tx,
+            msg_rx,
+            next_id: 0,
+            pending: HashMap::new(),
+            completed: HashMap::new(),
+        }
+    }
+
+    /// Start loading an asset, or update its priority if it's already loading.
+    ///
+    /// `distance` is the asset's distance from the listener or camera, or 0 for
+    /// assets that aren't placed in the world. `open` is only called if the
+    /// asset isn't already loading.
+    pub fn request<S, F, E>(
+        &mut self,
+        name: S,
+        distance: f32,
+        time: Duration,
+        open: F,
+    ) -> Result<(), E>
+    where
+        S: AsRef<str>,
+        F: FnOnce() -> Result<AssetReader, E>,
+    {
+        let name = name.as_ref();
+
+        if self.pending.contains_key(name) {
+            self.send(StreamCmd::Prioritize {
+                name: name.to_owned(),
+                distance,
+                time,
+            });
+            return Ok(());
+        }
+
+        let reader = open()?;
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.completed.remove(name);
+        self.pending.insert(name.to_owned(), Pending { id, len: 0 });
+        self.send(StreamCmd::Request {
+            name: name.to_owned(),
+            id,
+            reader,
+            distance,
+            time,
+        });
+
+        Ok(())
+    }
+
+    /// Stop loading an asset.
+    pub fn cancel<S>(&mut self, name: S)
+    where
+        S: AsRef<str>,
+    {
+        let name = name.as_ref();
+        if self.pending.remove(name).is_some() {
+            self.send(StreamCmd::Cancel(name.to_owned()));
+        }
+
+        self.completed.remove(name);
+    }
+
+    /// Returns true if the asset is still loading.
+    pub fn is_loading<S>(&self, name: S) -> bool
+    where
+        S: AsRef<str>,
+    {
+        self.pending.contains_key(name.as_ref())
+    }
+
+    /// Return the number of bytes of an asset loaded so far.
+    pub fn progress<S>(&self, name: S) -> Option<usize>
+    where
+        S: AsRef<str>,
+    {
+        self.pending.get(name.as_ref()).map(|p| p.len)
+    }
+
+    /// Collect what the worker has loaded since the last call.
+    ///
+    /// `time` is the current time, used to weigh how recently each asset was
+    /// requested.
+    pub fn update(&mut self, time: Duration) {
+        self.send(StreamCmd::Time(time));
+
+        while let Ok(msg) = self.msg_rx.try_recv() {
+            // messages about cancelled requests are dropped
+            let (id, done) = match msg {
+                StreamMsg::Progress { id, len } => (id, Err(len)),
+                StreamMsg::Done { id, data } => (id, Ok(data)),
+            };
+
+            let name = match self.pending.iter().find(|(_, p)| p.id == id) {
+                Some((n, _)) => n.to_owned(),
+                None => continue,
+            };
+
+            match done {
+                Err(len) => self.pending.get_mut(&name).unwrap().len = len,
+                Ok(data) => {
+                    self.pending.remove(&name);
+                    self.completed.insert(name, data);
+                }
+            }
+        }
+    }
+
+    /// Take the contents of an asset that has finished loading.
+    ///
+    /// Returns `None` if the asset is still loading or was never requested.
+    pub fn take<S>(&mut self, name: S) -> Option<io::Result<Vec<u8>>>
+    where
+        S: AsRef<str>,
+    {
+        self.completed.remove(name.as_ref())
+    }
+
+    fn send(&self, cmd: StreamCmd) {
+        // the worker only exits once this streamer is dropped
+        self.cmd_tx.send(cmd).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn reader(len: usize) -> AssetReader {
+        Box::new(Cursor::new(vec![7u8; len]))
+    }
+
+    fn request(queue: &mut StreamQueue, name: &str, id: u64, distance: f32, time: Duration) {
+        queue.handle(StreamCmd::Request {
+            name: name.to_owned(),
+            id,
+            reader: reader(CHUNK_SIZE * 4),
+            distance,
+            time,
+        });
+    }
+
+    fn progress(queue: &StreamQueue, name: &str) -> usize {
+        queue.jobs[name].data.len()
+    }
+
+    #[test]
+    fn test_streamer_loads_in_background() {
+        let mut streamer = AssetStreamer::new();
+        streamer
+            .request("music/track02.ogg", 0.0, Duration::zero(), || {
+                Ok::<_, ()>(reader(CHUNK_SIZE + 10))
+            })
+            .unwrap();
+        assert!(streamer.is_loading("music/track02.ogg"));
+
+        let data = loop {
+            streamer.update(Duration::zero());
+            if let Some(data) = streamer.take("music/track02.ogg") {
+                break data;
+            }
+            thread::yield_now();
+        };
+
+        assert!(!streamer.is_loading("music/track02.ogg"));
+        assert_eq!(data.unwrap(), vec![7u8; CHUNK_SIZE + 10]);
+    }
+
+    #[test]
+    fn test_streamer_priority() {
+        let mut queue = StreamQueue::new();
+        request(&mut queue, "far", 0, 1000.0, Duration::zero());
+        request(&mut queue, "near", 1, 10.0, Duration::zero());
+
+        queue.step();
+        assert_eq!(progress(&queue, "near"), CHUNK_SIZE);
+        assert_eq!(progress(&queue, "far"), 0);
+
+        // the near asset hasn't been requested in a while, so the far one
+        // (requested again just now) wins
+        let time = Duration::seconds(10);
+        queue.handle(StreamCmd::Time(time));
+        queue.handle(StreamCmd::Prioritize {
+            name: "far".to_owned(),
+            distance: 1000.0,
+            time,
+        });
+        queue.step();
+        assert_eq!(progress(&queue, "near"), CHUNK_SIZE);
+        assert_eq!(progress(&queue, "far"), CHUNK_SIZE);
+    }
+
+    #[test]
+    fn test_streamer_prefers_newer_request() {
+        let mut queue = StreamQueue::new();
+        request(&mut queue, "old", 0, 100.0, Duration::zero());
+        request(&mut queue, "new", 1, 100.0, Duration::seconds(5));
+        queue.handle(StreamCmd::Time(Duration::seconds(5)));
+
+        queue.step();
+        assert_eq!(progress(&queue, "new"), CHUNK_SIZE);
+        assert_eq!(progress(&queue, "old"), 0);
+    }
+
+    #[test]
+    fn test_streamer_cancel() {
+        let mut streamer = AssetStreamer::new();
+        streamer
+            .request("a", 0.0, Duration::zero(), || Ok::<_, ()>(reader(10)))
+            .unwrap();
+        streamer.cancel("a");
+        streamer.update(Duration::zero());
+        assert!(!streamer.is_loading("a"));
+        assert!(streamer.take("a").is_none());
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright © 2020 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Truevision TGA images.
+//!
+//! Only true-color images with 24 or 32 bits per pixel are supported, either
+//! uncompressed or run-length encoded. This covers the skyboxes and
+//! replacement textures distributed for Quake.
+
+use std::io::{self, Read};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use thiserror::Error;
+
+const TYPE_TRUE_COLOR: u8 = 2;
+const TYPE_TRUE_COLOR_RLE: u8 = 10;
+
+// set in the image descriptor if the first row is the top of the image
+const DESCRIPTOR_TOP_ORIGIN: u8 = 0x20;
+
+#[derive(Error, Debug)]
+pub enum TgaError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Unsupported image type: {0}")]
+    UnsupportedType(u8),
+    #[error("Unsupported pixel depth: {0}")]
+    UnsupportedDepth(u8),
+    #[error("Color-mapped images are not supported")]
+    ColorMapped,
+}
+
+/// A decoded TGA image.
+#[derive(Debug)]
+pub struct Tga {
+    width: u32,
+    height: u32,
+    rgba: Vec<u8>,
+}
+
+impl Tga {
+    pub fn load<R>(mut data: R) -> Result<Tga, TgaError>
+    where
+        R: Read,
+    {
+        let id_len = data.read_u8()?;
+        let color_map_type = data.read_u8()?;
+        let image_type = data.read_u8()?;
+
+        // color map specification
+        let mut color_map = [0; 5];
+        data.read_exact(&mut color_map)?;
+
+        let _x_origin = data.read_u16::<LittleEndian>()?;
+        let _y_origin = data.read_u16::<LittleEndian>()?;
+        let width = data.read_u16::<LittleEndian>()? as u32;
+        let height = data.read_u16::<LittleEndian>()? as u32;
+        let depth = data.read_u8()?;
+        let descriptor = data.read_u8()?;
+
+        if color_map_type != 0 {
+            Err(TgaError::ColorMapped)?;
+        }
+
+        let rle = match image_type {
+            TYPE_TRUE_COLOR => false,
+            TYPE_TRUE_COLOR_RLE => true,
+            t => Err(TgaError::UnsupportedType(t))?,
+        };
+
+        let pixel_size = match depth {
+            24 => 3,
+            32 => 4,
+            d => Err(TgaError::UnsupportedDepth(d))?,
+        };
+
+        // skip the image ID
+        io::copy(&mut (&mut data).take(id_len as u64), &mut io::sink())?;
+
+        let pixel_count = (width * height) as usize;
+        let mut pixels = Vec::with_capacity(pixel_count * 4);
+        let mut pixel = [0u8; 4];
+        let read_pixel = |data: &mut R, pixel: &mut [u8; 4]| -> io::Result<()> {
+            data.read_exact(&mut pixel[..pixel_size])?;
+
+            // stored as BGR(A)
+            pixel.swap(0, 2);
+            if pixel_size == 3 {
+                pixel[3] = 0xFF;
+            }
+
+            Ok(())
+        };
+
+        while pixels.len() < pixel_count * 4 {
+            if !rle {
+                read_pixel(&mut data, &mut pixel)?;
+                pixels.extend_from_slice(&pixel);
+                continue;
+            }
+
+            let packet = data.read_u8()?;
+            let count = (packet & 0x7F) as usize + 1;
+            if packet & 0x80 != 0 {
+                // run of one repeated pixel
+                read_pixel(&mut data, &mut pixel)?;
+                for _ in 0..count {
+                    pixels.extend_from_slice(&pixel);
+                }
+            } else {
+                for _ in 0..count {
+                    read_pixel(&mut data, &mut pixel)?;
+                    pixels.extend_from_slice(&pixel);
+                }
+            }
+        }
+
+        // packets may run past the end of the image
+        pixels.truncate(pixel_count * 4);
+
+        // rows are stored bottom to top unless the descriptor says otherwise
+        let rgba = if descriptor & DESCRIPTOR_TOP_ORIGIN != 0 || width == 0 {
+            pixels
+        } else {
+            pixels
+                .chunks_exact(width as usize * 4)
+                .rev()
+                .flatten()
+                .cloned()
+                .collect()
+        };
+
+        Ok(Tga {
+            width,
+            height,
+            rgba,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Returns the image as RGBA, one row at a time starting at the top.
+    pub fn rgba(&self) -> &[u8] {
+        &self.rgba
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(image_type: u8, depth: u8, descriptor: u8) -> Vec<u8> {
+        let mut h = vec![0, 0, image_type, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        h.extend_from_slice(&2u16.to_le_bytes());
+        h.extend_from_slice(&2u16.to_le_bytes());
+        h.push(depth);
+        h.push(descriptor);
+        h
+    }
+
+    #[test]
+    fn test_load_uncompressed_bottom_up() {
+        let mut data = header(TYPE_TRUE_COLOR, 24, 0);
+        // bottom row: blue, green
+        data.extend_from_slice(&[0xFF, 0, 0, 0, 0xFF, 0]);
+        // top row: red, white
+        data.extend_from_slice(&[0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+        let tga = Tga::load(data.as_slice()).unwrap();
+        assert_eq!((tga.width(), tga.height()), (2, 2));
+        assert_eq!(
+            tga.rgba(),
+            &[
+                0xFF, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // top
+                0, 0, 0xFF, 0xFF, 0, 0xFF, 0, 0xFF, // bottom
+            ][..]
+        );
+    }
+
+    #[test]
+    fn test_load_rle_top_down() {
+        let mut data = header(TYPE_TRUE_COLOR_RLE, 32, DESCRIPTOR_TOP_ORIGIN);
+        // three copies of half-transparent red
+        data.extend_from_slice(&[0x82, 0, 0, 0xFF, 0x80]);
+        // one raw black pixel
+        data.extend_from_slice(&[0x00, 0, 0, 0, 0xFF]);
+
+        let tga = Tga::load(data.as_slice()).unwrap();
+        assert_eq!(&tga.rgba()[..4], &[0xFF, 0, 0, 0x80]);
+        assert_eq!(&tga.rgba()[8..12], &[0xFF, 0, 0, 0x80]);
+        assert_eq!(&tga.rgba()[12..], &[0, 0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn test_load_rejects_color_mapped() {
+        let mut data = header(1, 8, 0);
+        data[1] = 1;
+        match Tga::load(data.as_slice()) {
+            Err(TgaError::ColorMapped) => (),
+            r => panic!("expected ColorMapped, got {:?}", r),
+        }
+    }
+}
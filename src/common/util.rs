@@ -33,20 +33,66 @@ where
     Ok(ar)
 }
 
-/// Read a null-terminated sequence of bytes and convert it into a `String`.
+/// Decode a string in the Quake character set.
 ///
-/// The zero byte is consumed.
+/// The Quake charset maps each byte to a glyph in the console font. Bytes
+/// `0x80..=0xFF` are the orange ("bronze") variants of `0x00..=0x7F`, and
+/// some control bytes are special glyphs like brackets and digits. Each byte
+/// becomes the `char` with the same value, so the result can be drawn glyph
+/// for glyph and encoded back with `quake_string_to_bytes` without loss.
+pub fn quake_bytes_to_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| *b as char).collect()
+}
+
+/// Encode a string in the Quake character set.
+///
+/// Characters outside the charset are replaced with `?`.
+pub fn quake_string_to_bytes(s: &str) -> Vec<u8> {
+    s.chars()
+        .map(|c| if (c as u32) <= 0xFF { c as u8 } else { b'?' })
+        .collect()
+}
+
+/// Convert a string in the Quake character set to plain ASCII, e.g. for
+/// logging.
+///
+/// Orange characters become their white equivalents and special glyphs are
+/// replaced with the closest ASCII character.
+pub fn quake_string_to_ascii(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if (c as u32) > 0xFF {
+                return c;
+            }
+
+            match c as u8 & 0x7F {
+                b'\n' => '\n',
+                0x10 => '[',
+                0x11 => ']',
+                b @ 0x12..=0x1B => (b'0' + b - 0x12) as char,
+                0x05 | 0x0E | 0x0F | 0x1C => '.',
+                0x1D..=0x1F => '-',
+                0x00..=0x1F | 0x7F => ' ',
+                b => b as char,
+            }
+        })
+        .collect()
+}
+
+/// Read a null-terminated string in the Quake character set.
 ///
-/// ## Panics
-/// - If the end of the input is reached before a zero byte is found.
-pub fn read_cstring<R>(src: &mut R) -> Result<String, std::string::FromUtf8Error>
+/// The zero byte is consumed. If the end of the input is reached first, the
+/// string ends there.
+pub fn read_cstring<R>(src: &mut R) -> Result<String, std::io::Error>
 where
     R: std::io::BufRead,
 {
     let mut bytes: Vec<u8> = Vec::new();
-    src.read_until(0, &mut bytes).unwrap();
-    bytes.pop();
-    String::from_utf8(bytes)
+    src.read_until(0, &mut bytes)?;
+    if bytes.last() == Some(&0) {
+        bytes.pop();
+    }
+    Ok(quake_bytes_to_string(&bytes))
 }
 
 pub unsafe fn any_as_bytes<T>(t: &T) -> &[u8]
@@ -81,3 +127,39 @@ where
         size_of::<T>() / size_of::<u32>(),
     )
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn test_quake_string_round_trip() {
+        let bytes: Vec<u8> = (1..=0xFF).collect();
+        let s = quake_bytes_to_string(&bytes);
+        assert_eq!(s.chars().count(), bytes.len());
+        assert_eq!(quake_string_to_bytes(&s), bytes);
+    }
+
+    #[test]
+    fn test_quake_string_to_ascii() {
+        // "[12] Ranger" with an orange name
+        let mut bytes = vec![0x10, 0x13, 0x14, 0x11, b' '];
+        bytes.extend(b"Ranger".iter().map(|b| b | 0x80));
+        let s = quake_bytes_to_string(&bytes);
+        assert_eq!(quake_string_to_ascii(&s), "[12] Ranger");
+    }
+
+    #[test]
+    fn test_quake_string_to_bytes_unrepresentable() {
+        assert_eq!(quake_string_to_bytes("a\u{263a}b"), b"a?b".to_vec());
+    }
+
+    #[test]
+    fn test_read_cstring() {
+        let mut src = Cursor::new(vec![b'h', 0xE9, 0, b'x']);
+        assert_eq!(read_cstring(&mut src).unwrap(), "h\u{e9}");
+        assert_eq!(read_cstring(&mut src).unwrap(), "x");
+    }
+}
@@ -39,6 +39,13 @@ enum VfsComponent {
     Directory(PathBuf),
 }
 
+/// The directory containing the base game's data.
+pub const BASE_GAME: &str = "id1";
+
+/// The subdirectory of each game directory that files downloaded from servers
+/// are saved to.
+pub const DOWNLOAD_DIR: &str = "downloads";
+
 #[derive(Debug)]
 pub struct Vfs {
     components: Vec<VfsComponent>,
@@ -52,11 +59,13 @@ impl Vfs {
     }
 
     /// Initializes the virtual filesystem using a base directory.
-    pub fn with_base_dir(base_dir: PathBuf) -> Vfs {
+    ///
+    /// Files are loaded from `id1/`, or if `game` is given, from that mod
+    /// directory with `id1/` as a fallback.
+    pub fn with_base_dir(base_dir: PathBuf, game: Option<&str>) -> Vfs {
         let mut vfs = Vfs::new();
 
-        let mut game_dir = base_dir;
-        game_dir.push("id1");
+        let game_dir = base_dir.join(BASE_GAME);
 
         if !game_dir.is_dir() {
             log::error!(concat!(
@@ -67,7 +76,31 @@ impl Vfs {
             std::process::exit(1);
         }
 
-        vfs.add_directory(&game_dir).unwrap();
+        let mut num_paks = vfs.add_game_dir(game_dir);
+
+        if let Some(game) = game {
+            let mod_dir = base_dir.join(game);
+            if !mod_dir.is_dir() {
+                log::error!("Game directory `{}/` does not exist!", game);
+                std::process::exit(1);
+            }
+
+            num_paks += vfs.add_game_dir(mod_dir);
+        }
+
+        if num_paks == 0 {
+            log::warn!("No PAK files found.");
+        }
+
+        vfs
+    }
+
+    // Add a game directory and its PAK archives, returning the number of PAKs.
+    fn add_game_dir(&mut self, game_dir: PathBuf) -> usize {
+        // downloads come first so they never take precedence over the game's
+        // own files
+        self.add_directory(game_dir.join(DOWNLOAD_DIR)).unwrap();
+        self.add_directory(&game_dir).unwrap();
 
         // ...then add PAK archives.
         let mut num_paks = 0;
@@ -86,18 +119,14 @@ impl Vfs {
                 }
             }
 
-            vfs.add_pakfile(&pak_path).unwrap();
+            self.add_pakfile(&pak_path).unwrap();
             num_paks += 1;
 
             // Remove the file name, leaving the game directory.
             pak_path.pop();
         }
 
-        if num_paks == 0 {
-            log::warn!("No PAK files found.");
-        }
-
-        vfs
+        num_paks
     }
 
     pub fn add_pakfile<P>(&mut self, path: P) -> Result<(), VfsError>
@@ -118,6 +147,30 @@ impl Vfs {
         Ok(())
     }
 
+    /// Returns the last directory added to the filesystem.
+    ///
+    /// This is the mod directory if there is one, so files written here take
+    /// precedence over the base game's.
+    pub fn game_dir(&self) -> Option<&Path> {
+        self.components.iter().rev().find_map(|c| match c {
+            VfsComponent::Directory(path) => Some(path.as_path()),
+            VfsComponent::Pak(_) => None,
+        })
+    }
+
+    /// Returns the directory that downloads for the current game are saved to.
+    pub fn download_dir(&self) -> Option<PathBuf> {
+        self.game_dir().map(|dir| dir.join(DOWNLOAD_DIR))
+    }
+
+    /// Returns `true` if a file exists in the filesystem.
+    pub fn exists<S>(&self, virtual_path: S) -> bool
+    where
+        S: AsRef<str>,
+    {
+        self.open(virtual_path).is_ok()
+    }
+
     pub fn open<S>(&self, virtual_path: S) -> Result<VirtualFile, VfsError>
     where
         S: AsRef<str>,
@@ -146,6 +199,20 @@ impl Vfs {
 
         Err(VfsError::NoSuchFile(vp.to_owned()))
     }
+
+    /// Open a file that doesn't borrow the filesystem, so it can be read a
+    /// little at a time, on another thread if need be.
+    ///
+    /// Files in PAK archives are copied out of the archive.
+    pub fn open_owned<S>(&self, virtual_path: S) -> Result<Box<dyn Read + Send>, VfsError>
+    where
+        S: AsRef<str>,
+    {
+        Ok(match self.open(virtual_path)? {
+            VirtualFile::PakBacked(curs) => Box::new(Cursor::new(curs.into_inner().to_vec())),
+            VirtualFile::FileBacked(file) => Box::new(file),
+        })
+    }
 }
 
 pub enum VirtualFile<'a> {
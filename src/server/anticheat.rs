@@ -0,0 +1,393 @@
+// Copyright © 2018 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Checks on client movement.
+//!
+//! Each move a client sends carries the server time it was based on. The
+//! client can't know a time the server hasn't reached, its moves can't go back
+//! in time, and over a few seconds their times can't advance much faster than
+//! the server's clock, which is what speed cheats do. Moves must also stay
+//! within what the movement keys can produce, and clients can't send far more
+//! moves than they have frames.
+//!
+//! With `sv_anticheat` set to 1 offenders are logged, and with it set to 2
+//! their offending moves are dropped as well. Clock speed is measured over
+//! many moves, and network delay can make an honest clock look fast, so it is
+//! only ever logged.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::common::{console::CvarRegistry, engine::duration_to_f32, net::ClientCmd};
+
+use cgmath::{Deg, Vector3};
+use chrono::Duration;
+
+/// How far past the server time a move's time may be, to allow for rounding.
+pub fn future_tolerance() -> Duration {
+    Duration::milliseconds(100)
+}
+
+/// How much server time clock speed and move rate are measured over. Long
+/// enough that a delayed burst of moves doesn't look like a fast clock.
+pub fn window() -> Duration {
+    Duration::seconds(10)
+}
+
+/// How far a client's clock may seem to get ahead over one window because
+/// its moves were delayed on the way.
+pub fn max_jitter() -> Duration {
+    Duration::milliseconds(500)
+}
+
+/// How much faster than the server's clock a client's may run.
+pub const CLOCK_TOLERANCE: f32 = 0.2;
+
+/// The most moves a client may send per second.
+pub const MAX_MOVE_RATE: f32 = 250.0;
+
+/// How far past `sv_maxspeed` a move may be, allowing for the run key and
+/// diagonal movement.
+pub const MOVE_SPEED_SCALE: f32 = 4.0;
+
+/// How many violations are logged for each client before only every
+/// `LOG_INTERVAL`th one is.
+pub const LOG_INTERVAL: u32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AntiCheatMode {
+    Off,
+
+    /// Log offenders.
+    Log,
+
+    /// Log offenders and drop their offending moves.
+    Reject,
+}
+
+impl AntiCheatMode {
+    pub fn from_cvars(cvars: &CvarRegistry) -> AntiCheatMode {
+        match cvars.get_value("sv_anticheat").unwrap_or(0.0) as i32 {
+            i if i <= 0 => AntiCheatMode::Off,
+            1 => AntiCheatMode::Log,
+            _ => AntiCheatMode::Reject,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Violation {
+    /// The move's time is before the previous move's.
+    TimeReversed,
+
+    /// The move's time is ahead of the server's.
+    FutureTime,
+
+    /// The client's clock ran faster than the server's by `ratio`.
+    ClockSpeed { ratio: f32 },
+
+    /// The client sent more moves than it could have frames.
+    MoveFlood { rate: f32 },
+
+    /// The move is faster than the movement keys allow or its view angles are
+    /// out of range.
+    ImpossibleMove,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Violation::TimeReversed => write!(f, "move time went backwards"),
+            Violation::FutureTime => write!(f, "move time is ahead of the server"),
+            Violation::ClockSpeed { ratio } => write!(f, "clock running at {:.2}x", ratio),
+            Violation::MoveFlood { rate } => write!(f, "{:.0} moves per second", rate),
+            Violation::ImpossibleMove => write!(f, "impossible move"),
+        }
+    }
+}
+
+/// Checks the moves from a single client.
+#[derive(Debug, Default)]
+pub struct MoveValidator {
+    last_send_time: Option<Duration>,
+
+    // server and move times at the start of the current window
+    window_start: Option<(Duration, Duration)>,
+    window_moves: u32,
+
+    violations: u32,
+}
+
+impl MoveValidator {
+    pub fn new() -> MoveValidator {
+        MoveValidator::default()
+    }
+
+    /// Returns how many violations the client has committed.
+    pub fn violation_count(&self) -> u32 {
+        self.violations
+    }
+
+    /// Check a move sent at `send_time` and received at `server_time`.
+    ///
+    /// `max_speed` is the `sv_maxspeed` cvar.
+    pub fn check(
+        &mut self,
+        server_time: Duration,
+        send_time: Duration,
+        angles: Vector3<Deg<f32>>,
+        moves: Vector3<f32>,
+        max_speed: f32,
+    ) -> Option<Violation> {
+        let violation = self.check_time(server_time, send_time).or_else(|| {
+            let finite = angles.x.0.is_finite() && angles.y.0.is_finite() && angles.z.0.is_finite();
+            let speed = (moves.x * moves.x + moves.y * moves.y + moves.z * moves.z).sqrt();
+            if !finite || angles.x.0.abs() > 90.0 || speed > max_speed * MOVE_SPEED_SCALE {
+                Some(Violation::ImpossibleMove)
+            } else {
+                None
+            }
+        });
+
+        if violation.is_some() {
+            self.violations += 1;
+        }
+
+        violation
+    }
+
+    fn check_time(&mut self, server_time: Duration, send_time: Duration) -> Option<Violation> {
+        if let Some(last) = self.last_send_time {
+            if send_time < last {
+                return Some(Violation::TimeReversed);
+            }
+        }
+        self.last_send_time = Some(send_time);
+
+        if send_time > server_time + future_tolerance() {
+            return Some(Violation::FutureTime);
+        }
+
+        self.window_moves += 1;
+        let (server_start, send_start) = *self.window_start.get_or_insert((server_time, send_time));
+
+        let server_elapsed = server_time - server_start;
+        if server_elapsed < window() {
+            return None;
+        }
+
+        let server_secs = duration_to_f32(server_elapsed);
+        let send_secs = duration_to_f32(send_time - send_start);
+        let rate = self.window_moves as f32 / server_secs;
+        self.window_start = Some((server_time, send_time));
+        self.window_moves = 0;
+
+        let max_send_secs = server_secs * (1.0 + CLOCK_TOLERANCE) + duration_to_f32(max_jitter());
+        if send_secs > max_send_secs {
+            Some(Violation::ClockSpeed {
+                ratio: send_secs / server_secs,
+            })
+        } else if rate > MAX_MOVE_RATE {
+            Some(Violation::MoveFlood { rate })
+        } else {
+            None
+        }
+    }
+}
+
+/// Checks the moves from every client.
+#[derive(Debug, Default)]
+pub struct AntiCheat {
+    clients: BTreeMap<usize, MoveValidator>,
+}
+
+impl AntiCheat {
+    pub fn new() -> AntiCheat {
+        AntiCheat::default()
+    }
+
+    /// Check a command from the client in `slot`, logging any violation.
+    ///
+    /// Returns `false` if the command should be dropped.
+    pub fn check_cmd(
+        &mut self,
+        slot: usize,
+        cmd: &ClientCmd,
+        server_time: Duration,
+        mode: AntiCheatMode,
+        max_speed: f32,
+    ) -> bool {
+        let (send_time, angles, moves) = match *cmd {
+            ClientCmd::Move {
+                send_time,
+                angles,
+                fwd_move,
+                side_move,
+                up_move,
+                ..
+            } => (
+                send_time,
+                angles,
+                Vector3::new(fwd_move as f32, side_move as f32, up_move as f32),
+            ),
+            _ => return true,
+        };
+
+        if mode == AntiCheatMode::Off {
+            return true;
+        }
+
+        let validator = self.clients.entry(slot).or_default();
+        let violation = match validator.check(server_time, send_time, angles, moves, max_speed) {
+            Some(v) => v,
+            None => return true,
+        };
+
+        let count = validator.violation_count();
+        if count <= LOG_INTERVAL || count % LOG_INTERVAL == 0 {
+            warn!(
+                "Client {} failed move check: {} ({} violations)",
+                slot, violation, count
+            );
+        }
+
+        match violation {
+            Violation::ClockSpeed { .. } => true,
+            _ => mode != AntiCheatMode::Reject,
+        }
+    }
+
+    /// Forget the client in `slot`.
+    pub fn remove(&mut self, slot: usize) {
+        self.clients.remove(&slot);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn angles() -> Vector3<Deg<f32>> {
+        Vector3::new(Deg(10.0), Deg(90.0), Deg(0.0))
+    }
+
+    fn ms(ms: i64) -> Duration {
+        Duration::milliseconds(ms)
+    }
+
+    #[test]
+    fn test_move_times() {
+        let mut v = MoveValidator::new();
+        let still = Vector3::new(0.0, 0.0, 0.0);
+
+        assert_eq!(v.check(ms(1000), ms(950), angles(), still, 320.0), None);
+        assert_eq!(
+            v.check(ms(1020), ms(900), angles(), still, 320.0),
+            Some(Violation::TimeReversed)
+        );
+        assert_eq!(
+            v.check(ms(1040), ms(1500), angles(), still, 320.0),
+            Some(Violation::FutureTime)
+        );
+        assert_eq!(v.violation_count(), 2);
+    }
+
+    #[test]
+    fn test_clock_speed() {
+        let still = Vector3::new(0.0, 0.0, 0.0);
+
+        // an honest client's moves trail the server by its ping
+        let mut honest = MoveValidator::new();
+        for frame in 0..=1000 {
+            let t = frame * 20;
+            assert_eq!(
+                honest.check(ms(t), ms(t - 50), angles(), still, 320.0),
+                None
+            );
+        }
+
+        // a laggy one's moves arrive in bursts, up to 400ms late
+        let mut laggy = MoveValidator::new();
+        let mut received = 0;
+        for frame in 0..=1000 {
+            let t = frame * 20;
+            received = (t + frame * 37 % 400).max(received);
+            assert_eq!(
+                laggy.check(ms(received), ms(t), angles(), still, 320.0),
+                None
+            );
+        }
+
+        // a speed cheat's clock runs 1.5x, but it can't get ahead of the server
+        // from the start, so it starts far behind
+        let mut cheat = MoveValidator::new();
+        let mut violations = Vec::new();
+        for frame in 0..=1000 {
+            let t = frame * 20;
+            if let Some(v) = cheat.check(ms(t), ms(t * 3 / 2 - 11000), angles(), still, 320.0) {
+                violations.push(v);
+            }
+        }
+        assert_eq!(violations.len(), 2);
+        assert!(matches!(violations[0], Violation::ClockSpeed { .. }));
+    }
+
+    #[test]
+    fn test_impossible_move() {
+        let mut v = MoveValidator::new();
+
+        // running diagonally is fine
+        let run = Vector3::new(800.0, 700.0, 0.0);
+        assert_eq!(v.check(ms(0), ms(0), angles(), run, 320.0), None);
+
+        let fast = Vector3::new(2000.0, 0.0, 0.0);
+        assert_eq!(
+            v.check(ms(20), ms(20), angles(), fast, 320.0),
+            Some(Violation::ImpossibleMove)
+        );
+
+        let upside_down = Vector3::new(Deg(180.0), Deg(0.0), Deg(0.0));
+        assert_eq!(
+            v.check(ms(40), ms(40), upside_down, run, 320.0),
+            Some(Violation::ImpossibleMove)
+        );
+    }
+
+    #[test]
+    fn test_reject_mode() {
+        let mut anticheat = AntiCheat::new();
+        let cmd = |fwd_move| ClientCmd::Move {
+            send_time: Duration::zero(),
+            angles: angles(),
+            fwd_move,
+            side_move: 0,
+            up_move: 0,
+            button_flags: crate::common::net::ButtonFlags::empty(),
+            impulse: 0,
+        };
+
+        assert!(anticheat.check_cmd(0, &cmd(5000), Duration::zero(), AntiCheatMode::Off, 320.0));
+        assert!(anticheat.check_cmd(0, &cmd(5000), Duration::zero(), AntiCheatMode::Log, 320.0));
+        assert!(!anticheat.check_cmd(
+            0,
+            &cmd(5000),
+            Duration::zero(),
+            AntiCheatMode::Reject,
+            320.0
+        ));
+        assert!(anticheat.check_cmd(0, &cmd(400), Duration::zero(), AntiCheatMode::Reject, 320.0));
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright © 2018 Cormac O'Brien
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use crate::common::{
+    console::{ConsoleError, CvarRegistry},
+    engine,
+    net::{connect::ConnectListener, portmap::PortForward, NetError},
+};
+
+use chrono::Duration;
+
+pub fn register_cvars(cvars: &CvarRegistry) -> Result<(), ConsoleError> {
+    cvars.register_archive("hostname", "UNNAMED")?;
+    cvars.register("hostport", "26000")?;
+    cvars.register("net_messagetimeout", "300")?;
+
+    // takes effect on the next map
+    cvars.register("maxplayers", "8")?;
+
+    // game rules read by QuakeC
+    cvars.register_notify("deathmatch", "0")?;
+    cvars.register("coop", "0")?;
+    cvars.register_notify("teamplay", "0")?;
+    cvars.register("skill", "1")?;
+    cvars.register("samelevel", "0")?;
+    cvars.register_notify("noexit", "0")?;
+
+    // movement
+    cvars.register_notify("sv_gravity", "800")?;
+    cvars.register("sv_maxvelocity", "2000")?;
+    cvars.register_notify("sv_friction", "4")?;
+    cvars.register("sv_stopspeed", "100")?;
+    cvars.register_notify("sv_accelerate", "10")?;
+
+    // reload progs.dat when it changes
+    cvars.register("developer", "0")?;
+
+    // only run a level frame when the framestep command asks for one
+    cvars.register("host_framestep", "0")?;
+
+    // match mode
+    cvars.register_notify("match_mode", "0")?;
+    cvars.register_notify("timelimit", "0")?;
+    cvars.register_notify("fraglimit", "0")?;
+    cvars.register("match_countdown", "10")?;
+    cvars.register("match_overtime", "2")?;
+
+    // caps the rate clients ask for, in bytes per second (0 for no cap)
+    cvars.register("sv_maxrate", "10000")?;
+
+    cvars.register_notify("sv_maxspeed", "320")?;
+
+    // log (1) or also drop (2) impossible moves
+    cvars.register("sv_anticheat", "0")?;
+
+    // move idle players to spectator, or kick them if sv_idlekick is set
+    cvars.register("sv_idletime", "0")?;
+    cvars.register("sv_idlekick", "0")?;
+
+    // fixed seed for QuakeC's random() (0 for a random seed), and a file to
+    // record sessions to for exact replay
+    cvars.register("sv_seed", "0")?;
+    cvars.register("sv_record", "")?;
+
+    // map rotation, as a list or a file in the game directory, and how many
+    // seconds to wait at intermission before changing maps
+    cvars.register("sv_maplist", "")?;
+    cvars.register("sv_maplistfile", "")?;
+    cvars.register("sv_intermission", "10")?;
+
+    // message of the day, shown at sign-on, and an advert printed to everyone
+    // every sv_advertinterval seconds. \n starts a new line
+    cvars.register("sv_motd", "")?;
+    cvars.register("sv_motdfile", "")?;
+    cvars.register("sv_motdcenter", "0")?;
+    cvars.register("sv_advert", "")?;
+    cvars.register("sv_advertinterval", "0")?;
+
+    // advertised to clients so they can fetch missing files over HTTP
+    cvars.register_notify("sv_downloadurl", "")?;
+    cvars.register_notify("sv_manifesturl", "")?;
+
+    // match reports
+    cvars.register("sv_statsfile", "")?;
+    cvars.register("sv_statsurl", "")?;
+
+    // ask the router to forward hostport (NAT-PMP or UPnP)
+    cvars.register_archive("sv_portforward", "0")?;
+    cvars.register_archive("sv_portforward_gateway", "")?;
+
+    // the master server to announce the server to, disabled while empty
+    cvars.register_archive("sv_master", "")?;
+
+    // remote console, disabled while empty
+    cvars.register("rcon_password", "")?;
+
+    // lets QuakeC query which extension builtins are available
+    cvars.register("pr_checkextension", "1")?;
+
+    Ok(())
+}
+
+/// Bind the socket that listens for new clients on the port given by the
+/// `hostport` cvar.
+///
+/// The socket accepts both IPv4 and IPv6 clients.
+pub fn bind_listener(cvars: &CvarRegistry) -> Result<ConnectListener, NetError> {
+    let port = cvars
+        .get_value("hostport")
+        .map_err(|e| NetError::with_msg(format!("{}", e)))?;
+    if port < 0.0 || port > std::u16::MAX as f32 {
+        return Err(NetError::with_msg(format!("Invalid hostport ({})", port)));
+    }
+
+    ConnectListener::bind_dual_stack(port as u16)
+}
+
+/// Ask the local router to forward `port` if the `sv_portforward` cvar is set.
+///
+/// The router is looked for at `sv_portforward_gateway`, or at the default
+/// gateway if that is empty. Failures are logged, since the server works
+/// without the mapping for clients on the same network.
+pub fn forward_port(cvars: &CvarRegistry, port: u16) -> Option<PortForward> {
+    if cvars.get_value("sv_portforward").unwrap_or(0.0) == 0.0 {
+        return None;
+    }
+
+    let gateway = match cvars.get("sv_portforward_gateway").unwrap_or_default() {
+        g if g.is_empty() => None,
+        g => match g.parse() {
+            Ok(ip) => Some(ip),
+            Err(_) => {
+                warn!("Invalid sv_portforward_gateway ({})", g);
+                return None;
+            }
+        },
+    };
+
+    match PortForward::request(port, gateway) {
+        Ok(forward) => {
+            info!(
+                "Port {} forwarded via {:?}, external address is {}",
+                port,
+                forward.protocol(),
+                forward.external_addr()
+            );
+            Some(forward)
+        }
+        Err(e) => {
+            warn!("Couldn't forward port {}: {}", port, e);
+            None
+        }
+    }
+}
+
+/// Return how long a client may go without sending anything before it is
+/// dropped, according to the `net_messagetimeout` cvar.
+///
+/// Returns `None` if the timeout is disabled.
+pub fn message_timeout(cvars: &CvarRegistry) -> Option<Duration> {
+    match cvars.get_value("net_messagetimeout") {
+        Ok(secs) if secs > 0.0 => Some(engine::duration_from_f32(secs)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn test_bind_listener_hostport() {
+        let cvars = CvarRegistry::new(Rc::new(RefCell::new(Vec::new())));
+        register_cvars(&cvars).unwrap();
+
+        // find a free port, then ask for it through hostport
+        let port = ConnectListener::bind_dual_stack(0)
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        cvars.set("hostport", port.to_string().as_str()).unwrap();
+
+        let listener = bind_listener(&cvars).unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), port);
+
+        cvars.set("hostport", "-1").unwrap();
+        assert!(bind_listener(&cvars).is_err());
+    }
+
+    #[test]
+    fn test_forward_port_disabled() {
+        let cvars = CvarRegistry::new(Rc::new(RefCell::new(Vec::new())));
+        register_cvars(&cvars).unwrap();
+        assert!(forward_port(&cvars, 26000).is_none());
+
+        cvars.set("sv_portforward", "1").unwrap();
+        cvars.set("sv_portforward_gateway", "router").unwrap();
+        assert!(forward_port(&cvars, 26000).is_none());
+    }
+
+    #[test]
+    fn test_message_timeout() {
+        let cvars = CvarRegistry::new(Rc::new(RefCell::new(Vec::new())));
+        register_cvars(&cvars).unwrap();
+        assert_eq!(message_timeout(&cvars), Some(Duration::seconds(300)));
+
+        cvars.set("net_messagetimeout", "0").unwrap();
+        assert_eq!(message_timeout(&cvars), None);
+    }
+}
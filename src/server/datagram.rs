@@ -0,0 +1,258 @@
+//! Entity updates in client datagrams.
+//!
+//! Each frame, a client's datagram carries a fast update for every entity it
+//! can see. On busy maps those updates won't always fit in `MAX_DATAGRAM`
+//! bytes. Rather than dropping the whole datagram, the updates are written in
+//! order of priority and the ones that don't fit are deferred to the next
+//! frame. Fast updates always hold an entity's full state relative to its
+//! baseline, so a deferred entity is simply a frame late.
+
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+};
+
+use crate::common::net::{EntityUpdate, NetError, ServerCmd};
+
+use cgmath::{InnerSpace as _, Vector3};
+
+use super::MAX_DATAGRAM;
+
+// warn on the first overflow and every this many after it
+const OVERFLOW_WARN_INTERVAL: usize = 100;
+
+/// An entity update waiting to be sent, along with the entity's position.
+#[derive(Clone, Debug)]
+pub struct EntityCandidate {
+    pub update: EntityUpdate,
+    pub origin: Vector3<f32>,
+}
+
+/// The result of packing entity updates into a datagram.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EntityPacking {
+    pub sent: usize,
+    pub deferred: usize,
+}
+
+/// Decides which entity updates go in each of a client's datagrams.
+///
+/// Entities that have waited longest since their last update go first, so
+/// deferred entities can't be starved. Among entities that have waited equally
+/// long, the nearest to the viewer go first.
+#[derive(Debug)]
+pub struct EntityScheduler {
+    frame: u64,
+
+    // the frame each entity was last sent in
+    last_sent: HashMap<u16, u64>,
+
+    overflows: usize,
+}
+
+impl EntityScheduler {
+    pub fn new() -> EntityScheduler {
+        EntityScheduler {
+            frame: 0,
+            last_sent: HashMap::new(),
+            overflows: 0,
+        }
+    }
+
+    /// The number of frames in which updates had to be deferred.
+    pub fn overflow_count(&self) -> usize {
+        self.overflows
+    }
+
+    /// Write updates from `candidates` to `datagram` until it holds `MAX_DATAGRAM` bytes.
+    ///
+    /// `view_origin` is the position of the client's view entity.
+    pub fn write_entities(
+        &mut self,
+        datagram: &mut Vec<u8>,
+        view_origin: Vector3<f32>,
+        candidates: Vec<EntityCandidate>,
+    ) -> Result<EntityPacking, NetError> {
+        self.write_entities_max_len(datagram, MAX_DATAGRAM, view_origin, candidates)
+    }
+
+    /// Write updates from `candidates` to `datagram` until it holds `max_len` bytes.
+    pub fn write_entities_max_len(
+        &mut self,
+        datagram: &mut Vec<u8>,
+        max_len: usize,
+        view_origin: Vector3<f32>,
+        candidates: Vec<EntityCandidate>,
+    ) -> Result<EntityPacking, NetError> {
+        self.frame += 1;
+
+        let frame = self.frame;
+        let last_sent = &self.last_sent;
+        let mut prioritized: Vec<(u64, f32, EntityUpdate)> = candidates
+            .into_iter()
+            .map(|c| {
+                let waited = match last_sent.get(&c.update.ent_id) {
+                    Some(f) => frame - f,
+                    None => u64::MAX,
+                };
+                let dist = (c.origin - view_origin).magnitude2();
+                (waited, dist, c.update)
+            })
+            .collect();
+        prioritized.sort_by(|(wait_a, dist_a, _), (wait_b, dist_b, _)| {
+            wait_b
+                .cmp(wait_a)
+                .then(dist_a.partial_cmp(dist_b).unwrap_or(Ordering::Equal))
+        });
+
+        // forget entities that are no longer candidates
+        let visible: HashSet<u16> = prioritized.iter().map(|(_, _, u)| u.ent_id).collect();
+        self.last_sent.retain(|id, _| visible.contains(id));
+
+        let mut packing = EntityPacking {
+            sent: 0,
+            deferred: 0,
+        };
+        let mut msg = Vec::new();
+        for (_, _, update) in prioritized {
+            let ent_id = update.ent_id;
+
+            msg.clear();
+            ServerCmd::FastUpdate(update).serialize(&mut msg)?;
+
+            // a smaller update further down the list may still fit
+            if datagram.len() + msg.len() > max_len {
+                packing.deferred += 1;
+                continue;
+            }
+
+            datagram.extend_from_slice(&msg);
+            self.last_sent.insert(ent_id, frame);
+            packing.sent += 1;
+        }
+
+        if packing.deferred > 0 {
+            if self.overflows % OVERFLOW_WARN_INTERVAL == 0 {
+                warn!(
+                    "Datagram overflow: deferred {} entities ({} overflows so far)",
+                    packing.deferred,
+                    self.overflows + 1
+                );
+            }
+            self.overflows += 1;
+        }
+
+        Ok(packing)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use crate::common::net::Protocol;
+
+    use cgmath::Deg;
+
+    fn candidate(ent_id: u16, x: f32) -> EntityCandidate {
+        EntityCandidate {
+            update: EntityUpdate {
+                ent_id,
+                model_id: Some(1),
+                frame_id: Some(2),
+                colormap: None,
+                skin_id: None,
+                effects: None,
+                origin_x: Some(x),
+                pitch: None,
+                origin_y: Some(0.0),
+                yaw: Some(Deg(90.0)),
+                origin_z: Some(0.0),
+                roll: None,
+                no_lerp: false,
+            },
+            origin: Vector3::new(x, 0.0, 0.0),
+        }
+    }
+
+    fn sent_ids(datagram: &[u8]) -> Vec<u16> {
+        let mut reader = Cursor::new(datagram);
+        let mut ids = Vec::new();
+        while let Some(cmd) = ServerCmd::deserialize(&mut reader, Protocol::NetQuake).unwrap() {
+            match cmd {
+                ServerCmd::FastUpdate(u) => ids.push(u.ent_id),
+                other => panic!("unexpected command {:?}", other),
+            }
+        }
+        ids
+    }
+
+    #[test]
+    fn test_everything_fits() {
+        let mut scheduler = EntityScheduler::new();
+        let mut datagram = Vec::new();
+        let candidates = vec![
+            candidate(3, 300.0),
+            candidate(1, 100.0),
+            candidate(2, -200.0),
+        ];
+
+        let packing = scheduler
+            .write_entities(&mut datagram, Vector3::new(0.0, 0.0, 0.0), candidates)
+            .unwrap();
+        assert_eq!(
+            packing,
+            EntityPacking {
+                sent: 3,
+                deferred: 0
+            }
+        );
+        assert_eq!(scheduler.overflow_count(), 0);
+
+        // nearest first
+        assert_eq!(sent_ids(&datagram), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_overflow_defers_to_next_frame() {
+        let mut update_len = Vec::new();
+        ServerCmd::FastUpdate(candidate(1, 0.0).update)
+            .serialize(&mut update_len)
+            .unwrap();
+        let max_len = update_len.len() * 2;
+
+        let candidates = || (1..=4).map(|i| candidate(i, i as f32 * 100.0)).collect();
+        let view = Vector3::new(0.0, 0.0, 0.0);
+        let mut scheduler = EntityScheduler::new();
+
+        let mut datagram = Vec::new();
+        let packing = scheduler
+            .write_entities_max_len(&mut datagram, max_len, view, candidates())
+            .unwrap();
+        assert_eq!(
+            packing,
+            EntityPacking {
+                sent: 2,
+                deferred: 2
+            }
+        );
+        assert_eq!(sent_ids(&datagram), vec![1, 2]);
+        assert_eq!(scheduler.overflow_count(), 1);
+
+        // the deferred entities have waited longer, so they go first
+        let mut datagram = Vec::new();
+        scheduler
+            .write_entities_max_len(&mut datagram, max_len, view, candidates())
+            .unwrap();
+        assert_eq!(sent_ids(&datagram), vec![3, 4]);
+
+        let mut datagram = Vec::new();
+        scheduler
+            .write_entities_max_len(&mut datagram, max_len, view, candidates())
+            .unwrap();
+        assert_eq!(sent_ids(&datagram), vec![1, 2]);
+        assert_eq!(scheduler.overflow_count(), 3);
+    }
+}
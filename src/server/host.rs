@@ -0,0 +1,1271 @@
+// Copyright © 2018 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! The server's main loop.
+//!
+//! A `ServerHost` owns the socket that listens for new clients and a
+//! connection to each client that has been accepted. Every frame it reads the
+//! clients' commands, runs the `Session` and sends each client what changed.
+
+use std::{
+    cell::RefCell,
+    io::{BufRead, Cursor},
+    net::SocketAddr,
+    rc::Rc,
+};
+
+use crate::{
+    common::{
+        bsp,
+        console::{CmdRegistry, Console, ConsoleError, CvarRegistry},
+        host,
+        net::{
+            self,
+            connect::{
+                ConnectListener, Request, RequestConnect, Response, ResponseAccept,
+                ResponsePlayerInfo, ResponseReject, ResponseRuleInfo, ResponseServerInfo,
+                CONNECT_PROTOCOL_VERSION,
+            },
+            master::{DpMaster, Heartbeat, ServerStatus},
+            portmap::PortForward,
+            BlockingMode, ClientCmd, NetError, PlayerColor, QSocket, ServerCmd, SignOnStage,
+            MAX_CLIENTS, MAX_MESSAGE, PROTOCOL_VERSION,
+        },
+        parse,
+        plugin::Plugins,
+        vfs::{Vfs, VfsError},
+    },
+    server::{
+        self, cvars,
+        datagram::EntityScheduler,
+        idle::IdleEvent,
+        match_mode::MatchEvent,
+        netstats::FrameCounters,
+        progs::{self, reload::PROGS_PATH, ProgsError},
+        rcon::Rcon,
+        record::{RecordedEvent, SessionPlayback},
+        rotation::{MapRotation, RotationEvent},
+        Recipient, Session, MAX_DATAGRAM,
+    },
+};
+
+use cgmath::{Vector3, Zero as _};
+use chrono::{Duration, Utc};
+use thiserror::Error;
+
+// player names are cut to this many characters, as in the original engine
+const MAX_NAME_LEN: usize = 15;
+
+// commands that act on the running level, registered by `spawn`
+const LEVEL_CMDS: &[&str] = &[
+    "progs_reload",
+    "qc_break",
+    "qc_unbreak",
+    "qc_step",
+    "qc_continue",
+    "qc_stack",
+    "netstats",
+    "nextmap",
+    "rotation",
+];
+
+#[derive(Error, Debug)]
+pub enum ServerError {
+    #[error("Console error: {0}")]
+    Console(#[from] ConsoleError),
+    #[error("Network error: {0}")]
+    Network(#[from] NetError),
+    #[error("QuakeC error: {0}")]
+    Progs(#[from] ProgsError),
+    #[error("Virtual filesystem error: {0}")]
+    Vfs(#[from] VfsError),
+    #[error("Couldn't load map {map}: {msg}")]
+    Map { map: String, msg: String },
+    #[error("Client sent an invalid command")]
+    BadClientCmd,
+    #[error("Invalid session recording: {0}")]
+    BadRecording(String),
+}
+
+/// A client's connection to the server.
+struct Client {
+    // `None` for a client replayed from a session recording
+    qsocket: Option<QSocket>,
+
+    // the port the client was told to continue on
+    port: u16,
+
+    name: String,
+    colors: PlayerColor,
+    connect_time: chrono::DateTime<Utc>,
+
+    // reliable messages waiting for the last one to be acknowledged
+    backlog: Vec<u8>,
+
+    // set once the client has signed on and should get datagrams
+    spawned: bool,
+
+    // decides which entity updates fit in each datagram
+    scheduler: EntityScheduler,
+}
+
+impl Client {
+    fn new(qsocket: Option<QSocket>, port: u16) -> Client {
+        Client {
+            qsocket,
+            port,
+            name: "unconnected".to_owned(),
+            colors: PlayerColor::new(0, 0),
+            connect_time: Utc::now(),
+            backlog: Vec::new(),
+            spawned: false,
+            scheduler: EntityScheduler::new(),
+        }
+    }
+
+    fn remote(&self) -> Option<SocketAddr> {
+        self.qsocket.as_ref().map(QSocket::remote)
+    }
+
+    fn send_unreliable(&mut self, msg: &[u8]) -> Result<(), NetError> {
+        match self.qsocket {
+            Some(ref mut qsocket) => qsocket.send_msg_unreliable(msg),
+            None => Ok(()),
+        }
+    }
+
+    /// Queue reliable messages for the client.
+    fn queue(&mut self, cmds: &[ServerCmd]) -> Result<(), NetError> {
+        for cmd in cmds {
+            cmd.serialize(&mut self.backlog)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send the queued reliable messages if the last ones were acknowledged.
+    fn flush(&mut self) -> Result<(), NetError> {
+        if self.backlog.is_empty() {
+            return Ok(());
+        }
+
+        // the client can't read a message this long, so give up on it
+        if self.backlog.len() > MAX_MESSAGE {
+            return Err(NetError::MessageTooLong(self.backlog.len()));
+        }
+
+        match self.qsocket {
+            Some(ref mut qsocket) if qsocket.can_send() => {
+                qsocket.begin_send_msg(&self.backlog)?;
+                self.backlog.clear();
+            }
+            Some(_) => (),
+            None => self.backlog.clear(),
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs a server and its connections to clients.
+pub struct ServerHost {
+    vfs: Rc<Vfs>,
+    cvars: Rc<RefCell<CvarRegistry>>,
+    cmds: Rc<RefCell<CmdRegistry>>,
+    plugins: Rc<RefCell<Plugins>>,
+
+    listener: ConnectListener,
+    rcon: Rcon,
+
+    // set when sv_portforward has the router forward the listening port
+    port_forward: Option<PortForward>,
+
+    // announces the server to the master server named by sv_master
+    heartbeat: Option<Heartbeat<DpMaster>>,
+    master_name: String,
+
+    session: Option<Rc<RefCell<Session>>>,
+
+    // connected clients by slot
+    clients: Vec<Option<Client>>,
+
+    // kept across levels, created from the cvars when the first map loads
+    rotation: Option<Rc<RefCell<MapRotation>>>,
+
+    // set by the `map` command and loaded at the start of the next frame
+    next_map: Rc<RefCell<Option<String>>>,
+
+    // level frames the `framestep` command has asked for
+    framestep: Rc<RefCell<usize>>,
+}
+
+impl ServerHost {
+    /// Register the server cvars and commands and start listening on
+    /// `hostport`.
+    ///
+    /// No level is running until `spawn` is called. Every level uses
+    /// `plugins` to filter client commands.
+    pub fn new(
+        vfs: Rc<Vfs>,
+        cvars: Rc<RefCell<CvarRegistry>>,
+        cmds: Rc<RefCell<CmdRegistry>>,
+        console: Rc<RefCell<Console>>,
+        plugins: Rc<RefCell<Plugins>>,
+    ) -> Result<ServerHost, ServerError> {
+        // the cvars may already be registered, so they can be set before the
+        // listener is bound
+        if !cvars.borrow().contains("hostport") {
+            cvars::register_cvars(&cvars.borrow())?;
+        }
+
+        let listener = cvars::bind_listener(&cvars.borrow())?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        info!("Listening on {}", addr);
+        let port_forward = cvars::forward_port(&cvars.borrow(), addr.port());
+
+        let next_map = Rc::new(RefCell::new(None));
+        cmds.borrow_mut()
+            .insert_or_replace("map", cmd_map(next_map.clone()))?;
+        let framestep = Rc::new(RefCell::new(0));
+        cmds.borrow_mut()
+            .insert_or_replace("framestep", host::cmd_framestep(framestep.clone()))?;
+
+        Ok(ServerHost {
+            vfs,
+            rcon: Rcon::new(cvars.clone(), console),
+            cvars,
+            cmds,
+            plugins,
+            listener,
+            port_forward,
+            heartbeat: None,
+            master_name: String::new(),
+            session: None,
+            clients: Vec::new(),
+            rotation: None,
+            next_map,
+            framestep,
+        })
+    }
+
+    /// Returns the plugins that filter client commands.
+    pub fn plugins(&self) -> Rc<RefCell<Plugins>> {
+        self.plugins.clone()
+    }
+
+    /// Returns the running level, if any.
+    pub fn session(&self) -> Option<Rc<RefCell<Session>>> {
+        self.session.clone()
+    }
+
+    /// Load `maps/<map>.bsp` and start a new level on it.
+    ///
+    /// Clients on the old level are told to reconnect and sign on again.
+    pub fn spawn(&mut self, map: &str) -> Result<(), ServerError> {
+        info!("Spawning server on {}", map);
+
+        let progs = progs::load(self.vfs.open(PROGS_PATH)?)?;
+        let path = format!("maps/{}.bsp", map);
+        let (mut models, entmap) =
+            bsp::load(self.vfs.open(&path)?).map_err(|e| ServerError::Map {
+                map: map.to_owned(),
+                msg: e.to_string(),
+            })?;
+
+        // the world model is named after the map so clients can load it
+        if let Some(world) = models.first_mut() {
+            world.name = path;
+        }
+
+        let max_clients = self
+            .cvars
+            .borrow()
+            .get_value("maxplayers")?
+            .max(1.0)
+            .min(MAX_CLIENTS as f32) as usize;
+
+        let vfs = self.vfs.clone();
+        let cvars = self.cvars.clone();
+        let rotation = self
+            .rotation
+            .get_or_insert_with(|| {
+                Rc::new(RefCell::new(MapRotation::from_cvars(&cvars.borrow(), &vfs)))
+            })
+            .clone();
+
+        let mut session = Session::new(
+            max_clients,
+            vfs,
+            cvars,
+            progs,
+            models,
+            entmap,
+            self.plugins.clone(),
+        );
+        session.set_rotation(rotation);
+        let session = Rc::new(RefCell::new(session.finish_loading()?));
+        self.register_level_cmds(&session)?;
+
+        // move everyone over to the new level
+        let mut old_clients = std::mem::replace(&mut self.clients, Vec::new());
+        self.clients.resize_with(max_clients, || None);
+        if let Some(old) = self.session.replace(session.clone()) {
+            old.borrow_mut().stop_recording();
+        }
+
+        for mut client in old_clients.drain(..).flatten() {
+            let slot = match session.borrow_mut().connect_client() {
+                Some(s) => s,
+                None => {
+                    let _ = send_disconnect(&mut client);
+                    continue;
+                }
+            };
+            session.borrow_mut().record_connect(slot);
+
+            client.spawned = false;
+            let queued = client
+                .queue(&[ServerCmd::StuffText {
+                    text: "reconnect\n".to_owned(),
+                }])
+                .and_then(|_| client.queue(&sign_on_messages(&session.borrow())));
+
+            // one bad client shouldn't keep the others off the new level
+            if let Err(e) = queued {
+                warn!("Dropping client {}: {}", slot, e);
+                let _ = send_disconnect(&mut client);
+                if let Err(e) = session.borrow_mut().drop_client(slot) {
+                    error!("Error disconnecting client {}: {}", slot, e);
+                }
+                continue;
+            }
+
+            self.clients[slot] = Some(client);
+        }
+
+        Ok(())
+    }
+
+    /// Run one server frame.
+    ///
+    /// Errors in QuakeC end the level, disconnecting every client. Network
+    /// errors only disconnect the client they happened on.
+    pub fn frame(&mut self, frame_time: Duration) {
+        let next_map = self.next_map.borrow_mut().take();
+        if let Some(map) = next_map {
+            if let Err(e) = self.spawn(&map) {
+                error!("Couldn't spawn server on {}: {}", map, e);
+            }
+        }
+
+        if let Some(ref mut forward) = self.port_forward {
+            if let Err(e) = forward.frame(frame_time) {
+                warn!("Couldn't renew port mapping: {}", e);
+            }
+        }
+
+        self.update_heartbeat(frame_time);
+        self.handle_requests();
+        self.announce_cvar_changes();
+
+        let session = match self.session {
+            Some(ref s) => s.clone(),
+            None => return,
+        };
+
+        // the level stands still while QuakeC is paused in the debugger, or
+        // between steps in frame-step mode
+        if session.borrow().debugger().is_paused() || !self.take_framestep() {
+            self.flush(&session);
+            return;
+        }
+
+        match self.run_level(&session, frame_time) {
+            Ok(()) | Err(ServerError::Progs(ProgsError::Paused)) => (),
+            Err(e) => {
+                error!("Shutting down the level: {}", e);
+                self.shutdown();
+                return;
+            }
+        }
+
+        self.drop_timed_out(&session);
+        self.flush(&session);
+    }
+
+    // returns false if frame-stepping is on and no step has been asked for
+    fn take_framestep(&self) -> bool {
+        let host_framestep = self
+            .cvars
+            .borrow()
+            .get_value("host_framestep")
+            .unwrap_or(0.0);
+        if host_framestep == 0.0 {
+            return true;
+        }
+
+        let mut steps = self.framestep.borrow_mut();
+        if *steps == 0 {
+            return false;
+        }
+
+        *steps -= 1;
+        true
+    }
+
+    fn run_level(
+        &mut self,
+        session: &Rc<RefCell<Session>>,
+        frame_time: Duration,
+    ) -> Result<(), ServerError> {
+        // reload progs.dat between frames if it changed in developer mode
+        match session.borrow_mut().watch_progs() {
+            Ok(Some(stats)) => info!(
+                "Reloaded {}: kept {} fields and {} globals, dropped {} fields",
+                PROGS_PATH, stats.fields_kept, stats.globals_kept, stats.fields_dropped
+            ),
+            Ok(None) => (),
+            Err(e) => warn!("Couldn't reload {}: {}", PROGS_PATH, e),
+        }
+
+        self.read_clients(session)?;
+        session.borrow_mut().record_frame(frame_time);
+        session.borrow_mut().frame(frame_time)?;
+        let events = session.borrow_mut().update_match()?;
+        self.announce_match(&events)?;
+
+        // the session has already dropped kicked players
+        let events = session.borrow_mut().update_idle()?;
+        for event in events.iter() {
+            match *event {
+                IdleEvent::Kick { slot } => self.kick_client(session, slot, &event.message()),
+                IdleEvent::Warning { slot, .. } | IdleEvent::Spectate { slot } => {
+                    if let Some(Some(client)) = self.clients.get_mut(slot) {
+                        client.queue(&[ServerCmd::Print {
+                            text: event.message(),
+                        }])?;
+                    }
+                }
+            }
+        }
+
+        let rotation = session.borrow_mut().update_rotation()?;
+        match rotation {
+            Some(RotationEvent::Intermission { next }) => self.broadcast(&[
+                ServerCmd::Intermission,
+                ServerCmd::Print {
+                    text: format!("Next map is {}\n", next),
+                },
+            ])?,
+            Some(RotationEvent::ChangeLevel { map }) => *self.next_map.borrow_mut() = Some(map),
+            None => (),
+        }
+
+        let advert = session.borrow_mut().update_adverts();
+        if let Some(advert) = advert {
+            self.broadcast(&[advert])?;
+        }
+
+        self.send_messages(session)?;
+        self.send_datagrams(session, frame_time)
+    }
+
+    /// End the level and disconnect every client.
+    pub fn shutdown(&mut self) {
+        for mut client in self.clients.drain(..).flatten() {
+            let _ = send_disconnect(&mut client);
+        }
+
+        if let Some(session) = self.session.take() {
+            session.borrow_mut().stop_recording();
+        }
+
+        for name in LEVEL_CMDS {
+            let _ = self.cmds.borrow_mut().remove(*name);
+        }
+    }
+
+    /// Run a session recorded with `sv_record` again.
+    ///
+    /// The recorded map is loaded with the recorded seed, and the recorded
+    /// clients' commands and frames are fed back in without any network
+    /// traffic. Replay stops at the end of the recording or when the level
+    /// changes. The server cvars should be set as they were when the session
+    /// was recorded.
+    pub fn replay<R>(&mut self, mut playback: SessionPlayback<R>) -> Result<(), ServerError>
+    where
+        R: BufRead,
+    {
+        {
+            let cvars = self.cvars.borrow();
+            cvars.set("sv_seed", playback.seed().to_string().as_str())?;
+            cvars.set("sv_record", "")?;
+        }
+
+        let map = playback.map().to_owned();
+        self.shutdown();
+        self.spawn(&map)?;
+        let session = match self.session {
+            Some(ref s) => s.clone(),
+            None => return Ok(()),
+        };
+
+        while let Some(event) = playback.next_event()? {
+            match event {
+                RecordedEvent::Frame { frame_time } => {
+                    self.run_level(&session, frame_time)?;
+                    if self.next_map.borrow().is_some() {
+                        info!("Level changed, ending replay");
+                        break;
+                    }
+                }
+
+                RecordedEvent::Connect { slot } => {
+                    let slot = slot as usize;
+                    if session.borrow_mut().connect_client() != Some(slot) {
+                        return Err(ServerError::BadRecording(format!(
+                            "client connected in slot {}, which wasn't free",
+                            slot
+                        )));
+                    }
+                    self.clients[slot] = Some(Client::new(None, 0));
+                }
+
+                RecordedEvent::ClientCmd { slot, cmd } => {
+                    let slot = slot as usize;
+                    if self.clients.get(slot).map_or(true, Option::is_none) {
+                        return Err(ServerError::BadRecording(format!(
+                            "command from client {}, which isn't connected",
+                            slot
+                        )));
+                    }
+                    self.client_cmd(&session, slot, cmd)?;
+                }
+
+                RecordedEvent::Disconnect { slot } => self.drop_client(&session, slot as usize),
+            }
+        }
+
+        Ok(())
+    }
+
+    // point the level commands at a new level
+    fn register_level_cmds(&self, session: &Rc<RefCell<Session>>) -> Result<(), ConsoleError> {
+        let mut cmds = self.cmds.borrow_mut();
+        cmds.insert_or_replace("progs_reload", server::cmd_progs_reload(session.clone()))?;
+        cmds.insert_or_replace("qc_break", server::cmd_qc_break(session.clone()))?;
+        cmds.insert_or_replace("qc_unbreak", server::cmd_qc_unbreak(session.clone()))?;
+        cmds.insert_or_replace("qc_step", server::cmd_qc_step(session.clone()))?;
+        cmds.insert_or_replace("qc_continue", server::cmd_qc_continue(session.clone()))?;
+        cmds.insert_or_replace("qc_stack", server::cmd_qc_stack(session.clone()))?;
+        cmds.insert_or_replace("netstats", server::cmd_netstats(session.clone()))?;
+        cmds.insert_or_replace("nextmap", server::cmd_nextmap(session.clone()))?;
+        cmds.insert_or_replace("rotation", server::cmd_rotation(session.clone()))?;
+        Ok(())
+    }
+
+    // answer connection requests and queries sent to the listener
+    fn handle_requests(&mut self) {
+        let mut recv_buf = [0u8; MAX_MESSAGE];
+        loop {
+            let (len, remote) = match self.listener.recv_packet(&mut recv_buf) {
+                Ok(r) => r,
+                Err(NetError::Io(ref e)) if e.kind() == std::io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Couldn't read from listener: {}", e);
+                    return;
+                }
+            };
+            let packet = &recv_buf[..len];
+
+            if self.handle_master_packet(packet, remote) {
+                continue;
+            }
+
+            let request = match Request::from_bytes(packet) {
+                Ok(r) => r,
+                Err(e) => {
+                    debug!("Bad request from {}: {}", remote, e);
+                    continue;
+                }
+            };
+
+            let response = match request {
+                Request::Connect(connect) => self.accept(connect, remote),
+                Request::ServerInfo(info) if info.game_name == net::GAME_NAME => {
+                    Some(self.server_info())
+                }
+                Request::ServerInfo(_) => None,
+                Request::PlayerInfo(info) => self.player_info(info.player_id as usize),
+                Request::RuleInfo(info) => Some(self.rule_info(&info.prev_cvar)),
+                Request::Rcon(rcon) => self.rcon.handle(&rcon, remote),
+            };
+
+            if let Some(response) = response {
+                if let Err(e) = self.listener.send_response(response, remote) {
+                    warn!("Couldn't respond to {}: {}", remote, e);
+                }
+            }
+        }
+    }
+
+    // the master server asks for our status when it gets a heartbeat
+    fn handle_master_packet(&self, packet: &[u8], remote: SocketAddr) -> bool {
+        let heartbeat = match self.heartbeat {
+            Some(ref h) => h,
+            None => return false,
+        };
+
+        let status = ServerStatus {
+            hostname: self.cvars.borrow().get("hostname").unwrap_or_default(),
+            map: match self.session {
+                Some(ref s) => s.borrow().level().map_name(),
+                None => String::new(),
+            },
+            clients: self.clients.iter().flatten().count() as u8,
+            max_clients: self.clients.len() as u8,
+        };
+
+        match heartbeat.handle_packet(self.listener.socket(), packet, remote, &status) {
+            Ok(handled) => handled,
+            Err(e) => {
+                warn!("Couldn't answer master server {}: {}", remote, e);
+                true
+            }
+        }
+    }
+
+    // (re)start heartbeats when sv_master changes and send them while a level
+    // is running
+    fn update_heartbeat(&mut self, frame_time: Duration) {
+        let master = self.cvars.borrow().get("sv_master").unwrap_or_default();
+        if master != self.master_name {
+            self.heartbeat = match master.as_str() {
+                "" => None,
+                m => match Heartbeat::new(DpMaster::default(), m) {
+                    Ok(h) => {
+                        info!("Sending heartbeats to {}", h.master());
+                        Some(h)
+                    }
+                    Err(e) => {
+                        warn!("Invalid sv_master ({}): {}", m, e);
+                        None
+                    }
+                },
+            };
+            self.master_name = master;
+        }
+
+        if self.session.is_none() {
+            return;
+        }
+
+        if let Some(ref mut heartbeat) = self.heartbeat {
+            if let Err(e) = heartbeat.frame(self.listener.socket(), frame_time) {
+                warn!("Couldn't send heartbeat to {}: {}", heartbeat.master(), e);
+            }
+        }
+    }
+
+    fn accept(&mut self, connect: RequestConnect, remote: SocketAddr) -> Option<Response> {
+        if connect.game_name != net::GAME_NAME {
+            return None;
+        }
+
+        if connect.proto_ver != CONNECT_PROTOCOL_VERSION {
+            return Some(reject("Incompatible version.\n"));
+        }
+
+        // the client didn't hear our answer and asked again
+        if let Some(client) = self
+            .clients
+            .iter()
+            .flatten()
+            .find(|c| c.remote() == Some(remote))
+        {
+            return Some(Response::Accept(ResponseAccept {
+                port: client.port as i32,
+            }));
+        }
+
+        let session = match self.session {
+            Some(ref s) => s.clone(),
+            None => return Some(reject("Server is not running a level.\n")),
+        };
+
+        let slot = match session.borrow_mut().connect_client() {
+            Some(s) => s,
+            None => return Some(reject("Server is full.\n")),
+        };
+        session.borrow_mut().record_connect(slot);
+
+        // each client gets its own socket so its packets can be told apart
+        let opened = ConnectListener::bind_dual_stack(0)
+            .and_then(|s| s.local_addr().map(|addr| (s, addr.port())));
+        let (socket, port) = match opened {
+            Ok(o) => o,
+            Err(e) => {
+                warn!("Couldn't open a socket for {}: {}", remote, e);
+                let _ = session.borrow_mut().drop_client(slot);
+                return None;
+            }
+        };
+
+        let mut client = Client::new(Some(socket.into_qsocket(remote)), port);
+        if let Err(e) = client.queue(&sign_on_messages(&session.borrow())) {
+            warn!("Couldn't send server info to {}: {}", remote, e);
+            let _ = session.borrow_mut().drop_client(slot);
+            return None;
+        }
+
+        info!("Client {} connected from {}", slot, remote);
+        self.clients[slot] = Some(client);
+
+        Some(Response::Accept(ResponseAccept { port: port as i32 }))
+    }
+
+    fn server_info(&self) -> Response {
+        let address = match self.listener.local_addr() {
+            Ok(a) => a.to_string(),
+            Err(_) => String::new(),
+        };
+        let levelname = match self.session {
+            Some(ref s) => s.borrow().level().map_name(),
+            None => String::new(),
+        };
+
+        Response::ServerInfo(ResponseServerInfo {
+            address,
+            hostname: self.cvars.borrow().get("hostname").unwrap_or_default(),
+            levelname,
+            client_count: self.clients.iter().flatten().count() as u8,
+            client_max: self.clients.len() as u8,
+            protocol_version: PROTOCOL_VERSION,
+        })
+    }
+
+    fn player_info(&self, slot: usize) -> Option<Response> {
+        let client = self.clients.get(slot)?.as_ref()?;
+        let session = self.session.as_ref()?.borrow();
+        let frags = match session.client_entity(slot) {
+            Some(ent_id) => session.level().player_score(ent_id).ok()?.1,
+            None => 0,
+        };
+
+        Some(Response::PlayerInfo(ResponsePlayerInfo {
+            player_id: slot as u8,
+            player_name: client.name.clone(),
+            colors: client.colors.bits() as i32,
+            frags,
+            connect_duration: (Utc::now() - client.connect_time).num_seconds() as i32,
+            address: client.remote().map(|a| a.to_string()).unwrap_or_default(),
+        }))
+    }
+
+    // the server's rules are its notify cvars, sent one at a time in order
+    fn rule_info(&self, prev_cvar: &str) -> Response {
+        let values = self.cvars.borrow().notify_values();
+        let next = match prev_cvar {
+            "" => values.into_iter().next(),
+            prev => values.into_iter().find(|(name, _)| name.as_str() > prev),
+        };
+
+        let (cvar_name, cvar_val) = next.unwrap_or_default();
+        Response::RuleInfo(ResponseRuleInfo {
+            cvar_name,
+            cvar_val,
+        })
+    }
+
+    fn read_clients(&mut self, session: &Rc<RefCell<Session>>) -> Result<(), ServerError> {
+        for slot in 0..self.clients.len() {
+            match self.read_client(session, slot) {
+                Ok(()) => (),
+                Err(ServerError::Progs(e)) => return Err(ServerError::Progs(e)),
+                Err(e) => {
+                    warn!("Dropping client {}: {}", slot, e);
+                    self.drop_client(session, slot);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_client(
+        &mut self,
+        session: &Rc<RefCell<Session>>,
+        slot: usize,
+    ) -> Result<(), ServerError> {
+        loop {
+            let msg = match self.clients[slot] {
+                Some(Client {
+                    qsocket: Some(ref mut qsocket),
+                    ..
+                }) => qsocket.recv_msg(BlockingMode::NonBlocking)?,
+                _ => return Ok(()),
+            };
+
+            if msg.is_empty() {
+                return Ok(());
+            }
+
+            let mut reader = Cursor::new(msg.as_slice());
+            while (reader.position() as usize) < msg.len() {
+                let cmd = ClientCmd::deserialize(&mut reader)?;
+                self.client_cmd(session, slot, cmd)?;
+
+                // the client may have disconnected
+                if self.clients[slot].is_none() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn client_cmd(
+        &mut self,
+        session: &Rc<RefCell<Session>>,
+        slot: usize,
+        cmd: ClientCmd,
+    ) -> Result<(), ServerError> {
+        // records the command and checks it for cheating and idling
+        if !session.borrow_mut().client_cmd(slot, &cmd) {
+            debug!("Dropped command from client {}: {:?}", slot, cmd);
+            return Ok(());
+        }
+
+        match cmd {
+            ClientCmd::Bad => Err(ServerError::BadClientCmd),
+            ClientCmd::NoOp => Ok(()),
+            ClientCmd::Disconnect => {
+                self.drop_client(session, slot);
+                Ok(())
+            }
+            ClientCmd::Move { .. } => Ok(session.borrow_mut().move_client(slot, &cmd)?),
+            ClientCmd::StringCmd { cmd } => {
+                if !session.borrow().filter_client_cmd(slot, &cmd) {
+                    debug!("Plugin dropped command from client {}: {}", slot, cmd);
+                    return Ok(());
+                }
+
+                let commands = match parse::console::commands(&cmd) {
+                    Ok((_, commands)) => commands,
+                    Err(_) => {
+                        debug!("Client {} sent an unparseable command: {}", slot, cmd);
+                        return Ok(());
+                    }
+                };
+
+                for args in commands {
+                    if let Some((name, args)) = args.split_first() {
+                        self.client_string_cmd(session, slot, name, args)?;
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    fn client_string_cmd(
+        &mut self,
+        session: &Rc<RefCell<Session>>,
+        slot: usize,
+        name: &str,
+        args: &[&str],
+    ) -> Result<(), ServerError> {
+        let client = match self.clients[slot] {
+            Some(ref mut c) => c,
+            None => return Ok(()),
+        };
+
+        match name {
+            "prespawn" => client.queue(&session.borrow().prespawn_messages())?,
+
+            "spawn" => {
+                // a repeated spawn command only resends the spawn messages
+                if session.borrow().client_entity(slot).is_none() {
+                    session
+                        .borrow_mut()
+                        .spawn_client(slot, &client.name, client.colors)?;
+                }
+                client.queue(&session.borrow().spawn_messages(slot)?)?;
+
+                let update = [
+                    ServerCmd::UpdateName {
+                        player_id: slot as u8,
+                        new_name: client.name.clone(),
+                    },
+                    ServerCmd::UpdateColors {
+                        player_id: slot as u8,
+                        new_colors: client.colors,
+                    },
+                ];
+                self.broadcast(&update)?;
+            }
+
+            "begin" => {
+                // greet the player once they're in the game
+                if !client.spawned {
+                    if let Some(motd) = session.borrow().motd() {
+                        client.queue(&[motd])?;
+                    }
+                }
+                client.spawned = true;
+            }
+
+            "name" => {
+                let new_name: String = args.join(" ").chars().take(MAX_NAME_LEN).collect();
+                if new_name.is_empty() {
+                    return Ok(());
+                }
+
+                client.name = new_name.clone();
+                session.borrow_mut().set_client_name(slot, &new_name)?;
+                self.broadcast(&[ServerCmd::UpdateName {
+                    player_id: slot as u8,
+                    new_name,
+                }])?;
+            }
+
+            "color" => {
+                let top = match args.get(0).and_then(|a| a.parse::<u8>().ok()) {
+                    Some(t) => t,
+                    None => return Ok(()),
+                };
+                let bottom = args
+                    .get(1)
+                    .and_then(|a| a.parse::<u8>().ok())
+                    .unwrap_or(top);
+
+                client.colors = PlayerColor::new(top.min(13), bottom.min(13));
+                let new_colors = client.colors;
+                self.broadcast(&[ServerCmd::UpdateColors {
+                    player_id: slot as u8,
+                    new_colors,
+                }])?;
+            }
+
+            "say" | "say_team" => {
+                if args.is_empty() {
+                    return Ok(());
+                }
+
+                // the leading \x01 makes clients play the talk sound
+                let text = format!("\u{1}{}: {}\n", client.name, args.join(" "));
+                self.broadcast(&[ServerCmd::Print { text }])?;
+            }
+
+            "kill" => session.borrow_mut().kill_client(slot)?,
+
+            "rate" => {
+                let cmd = format!("rate {}", args.join(" "));
+                if let Some(text) = session.borrow_mut().rate_command(slot, &cmd) {
+                    if !text.is_empty() {
+                        client.queue(&[ServerCmd::Print { text }])?;
+                    }
+                }
+            }
+
+            "ready" | "notready" => match session.borrow_mut().match_command(slot, name) {
+                Some(events) => self.announce_match(&events)?,
+                None => client.queue(&[ServerCmd::Print {
+                    text: "The server isn't in match mode\n".to_owned(),
+                }])?,
+            },
+
+            _ => debug!("Client {} sent unknown command {}", slot, name),
+        }
+
+        Ok(())
+    }
+
+    // queue reliable messages for every connected client
+    fn broadcast(&mut self, cmds: &[ServerCmd]) -> Result<(), NetError> {
+        for client in self.clients.iter_mut().flatten() {
+            client.queue(cmds)?;
+        }
+
+        Ok(())
+    }
+
+    // tell every client about changes to the server's notify cvars
+    fn announce_cvar_changes(&mut self) {
+        let changes = self.cvars.borrow().take_notify_changes();
+        for (name, value) in changes {
+            let text = format!("Server cvar \"{}\" changed to \"{}\"\n", name, value);
+            if let Err(e) = self.broadcast(&[ServerCmd::Print { text }]) {
+                warn!("Couldn't announce change to {}: {}", name, e);
+            }
+        }
+    }
+
+    // tell every client how the match is going
+    fn announce_match(&mut self, events: &[MatchEvent]) -> Result<(), NetError> {
+        for event in events {
+            let cmd = match event {
+                MatchEvent::Ready { slot, ready } => {
+                    let name = match self.clients.get(*slot) {
+                        Some(Some(client)) => client.name.clone(),
+                        _ => continue,
+                    };
+                    let text = match ready {
+                        true => format!("{} is ready\n", name),
+                        false => format!("{} is not ready\n", name),
+                    };
+                    ServerCmd::Print { text }
+                }
+
+                MatchEvent::Countdown { seconds } => ServerCmd::CenterPrint {
+                    text: format!("Match starts in {}", seconds),
+                },
+
+                MatchEvent::CountdownAborted => ServerCmd::Print {
+                    text: "Countdown aborted\n".to_owned(),
+                },
+
+                MatchEvent::Start => ServerCmd::CenterPrint {
+                    text: "FIGHT!".to_owned(),
+                },
+
+                MatchEvent::Overtime { period } => ServerCmd::CenterPrint {
+                    text: format!("Overtime {}", period),
+                },
+
+                MatchEvent::End(result) => ServerCmd::Print {
+                    text: result.report(),
+                },
+            };
+
+            self.broadcast(&[cmd])?;
+        }
+
+        Ok(())
+    }
+
+    fn send_messages(&mut self, session: &Rc<RefCell<Session>>) -> Result<(), ServerError> {
+        let messages = session.borrow_mut().take_messages();
+        for (recipient, cmd) in messages {
+            match recipient {
+                Recipient::All => self.broadcast(&[cmd])?,
+                Recipient::Client(slot) => {
+                    if let Some(Some(client)) = self.clients.get_mut(slot) {
+                        client.queue(&[cmd])?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send_datagrams(
+        &mut self,
+        session: &Rc<RefCell<Session>>,
+        frame_time: Duration,
+    ) -> Result<(), ServerError> {
+        let shared = session.borrow_mut().take_datagram();
+        let updates = session.borrow().entity_updates()?;
+
+        for slot in 0..self.clients.len() {
+            match self.clients[slot] {
+                Some(ref client) if client.spawned => (),
+                _ => continue,
+            }
+
+            // clients over their rate wait until they've caught up
+            if !session
+                .borrow_mut()
+                .client_rate_mut(slot)
+                .begin_frame(frame_time)
+            {
+                continue;
+            }
+
+            let mut msg = Vec::new();
+            for cmd in session.borrow_mut().client_data(slot)? {
+                cmd.serialize(&mut msg)?;
+            }
+
+            if msg.len() + shared.len() <= MAX_DATAGRAM {
+                msg.extend_from_slice(&shared);
+            }
+
+            // entities closest to the player go first
+            let view_origin = session
+                .borrow()
+                .client_entity(slot)
+                .and_then(|ent_id| {
+                    updates
+                        .iter()
+                        .find(|c| c.update.ent_id as usize == ent_id.0)
+                })
+                .map(|c| c.origin)
+                .unwrap_or_else(Vector3::zero);
+
+            let client = match self.clients[slot] {
+                Some(ref mut c) => c,
+                None => continue,
+            };
+            let candidates = updates.clone();
+            let packing = client
+                .scheduler
+                .write_entities(&mut msg, view_origin, candidates)?;
+
+            let result = client.send_unreliable(&msg);
+            match result {
+                Ok(()) => {
+                    // every entity is a candidate, none are culled by PVS yet
+                    let counters = FrameCounters::new(updates.len(), 0, packing, msg.len());
+                    let mut session = session.borrow_mut();
+                    session.client_rate_mut(slot).sent(msg.len());
+                    session.record_net_frame(slot, counters);
+                }
+                Err(e) => {
+                    warn!("Dropping client {}: {}", slot, e);
+                    self.drop_client(session, slot);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn drop_timed_out(&mut self, session: &Rc<RefCell<Session>>) {
+        let timeout = match cvars::message_timeout(&self.cvars.borrow()) {
+            Some(t) => t,
+            None => return,
+        };
+
+        for slot in 0..self.clients.len() {
+            let timed_out = match self.clients[slot] {
+                Some(Client {
+                    qsocket: Some(ref qsocket),
+                    ..
+                }) => qsocket.time_since_recv() > timeout,
+                _ => false,
+            };
+
+            if timed_out {
+                info!("Client {} timed out", slot);
+                self.drop_client(session, slot);
+            }
+        }
+    }
+
+    fn flush(&mut self, session: &Rc<RefCell<Session>>) {
+        for slot in 0..self.clients.len() {
+            let result = match self.clients[slot] {
+                Some(ref mut client) => client.flush(),
+                None => continue,
+            };
+
+            if let Err(e) = result {
+                warn!("Dropping client {}: {}", slot, e);
+                self.drop_client(session, slot);
+            }
+        }
+    }
+
+    // tell the client why they're being dropped, then drop them
+    fn kick_client(&mut self, session: &Rc<RefCell<Session>>, slot: usize, reason: &str) {
+        if let Some(Some(client)) = self.clients.get_mut(slot) {
+            let mut msg = Vec::new();
+            let cmds = [
+                ServerCmd::Print {
+                    text: reason.to_owned(),
+                },
+                ServerCmd::Disconnect,
+            ];
+            let sent = cmds
+                .iter()
+                .try_for_each(|cmd| cmd.serialize(&mut msg))
+                .and_then(|_| client.send_unreliable(&msg));
+            if let Err(e) = sent {
+                warn!("Couldn't tell client {} they were kicked: {}", slot, e);
+            }
+        }
+
+        self.drop_client(session, slot);
+    }
+
+    /// Disconnect the client in `slot` and tell the others it has left.
+    fn drop_client(&mut self, session: &Rc<RefCell<Session>>, slot: usize) {
+        let mut client = match self.clients.get_mut(slot).and_then(|c| c.take()) {
+            Some(c) => c,
+            None => return,
+        };
+
+        let _ = send_disconnect(&mut client);
+        if let Err(e) = session.borrow_mut().drop_client(slot) {
+            error!("Error disconnecting client {}: {}", slot, e);
+        }
+        info!("Client {} ({}) disconnected", slot, client.name);
+
+        let leave = [
+            ServerCmd::UpdateName {
+                player_id: slot as u8,
+                new_name: String::new(),
+            },
+            ServerCmd::UpdateFrags {
+                player_id: slot as u8,
+                new_frags: 0,
+            },
+        ];
+        if let Err(e) = self.broadcast(&leave) {
+            warn!("Couldn't announce disconnect of client {}: {}", slot, e);
+        }
+    }
+}
+
+// the messages that start a client's sign-on
+fn sign_on_messages(session: &Session) -> [ServerCmd; 2] {
+    [
+        session.server_info(),
+        ServerCmd::SignOnStage {
+            stage: SignOnStage::Prespawn,
+        },
+    ]
+}
+
+fn reject(message: &str) -> Response {
+    Response::Reject(ResponseReject {
+        message: message.to_owned(),
+    })
+}
+
+// the client may be gone already, so this is only tried once
+fn send_disconnect(client: &mut Client) -> Result<(), NetError> {
+    let mut msg = Vec::new();
+    ServerCmd::Disconnect.serialize(&mut msg)?;
+    client.send_unreliable(&msg)
+}
+
+/// Implements the `map` command.
+///
+/// The map is loaded at the start of the next frame.
+pub fn cmd_map(next_map: Rc<RefCell<Option<String>>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| match args {
+        [map] => {
+            next_map.replace(Some((*map).to_owned()));
+            String::new()
+        }
+        _ => "usage: map <map>".to_owned(),
+    })
+}
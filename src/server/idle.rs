@@ -0,0 +1,310 @@
+// Copyright © 2018 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Idle player detection.
+//!
+//! A client that sends no input for `sv_idletime` seconds is moved to
+//! spectator so they stop holding a place in the game, or dropped from the
+//! server if `sv_idlekick` is set. Players are warned `warning_time()` before
+//! that happens. Input counts as activity if it moves the player, turns their
+//! view, presses a button or sends an impulse.
+
+use std::collections::BTreeMap;
+
+use crate::common::{
+    console::CvarRegistry,
+    engine::{duration_from_f32, duration_to_f32},
+    net::{ButtonFlags, ClientCmd},
+};
+
+use cgmath::{Deg, Vector3};
+use chrono::Duration;
+
+/// How long before being moved or kicked that an idle player is warned.
+pub fn warning_time() -> Duration {
+    Duration::seconds(10)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdleAction {
+    /// Move the player to spectator.
+    Spectate,
+
+    /// Drop the player from the server.
+    Kick,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IdleVars {
+    /// How long a player may be idle, or `None` if idle players are left alone.
+    pub timeout: Option<Duration>,
+
+    pub action: IdleAction,
+}
+
+impl IdleVars {
+    pub fn from_cvars(cvars: &CvarRegistry) -> IdleVars {
+        IdleVars {
+            timeout: match cvars.get_value("sv_idletime") {
+                Ok(secs) if secs > 0.0 => Some(duration_from_f32(secs)),
+                _ => None,
+            },
+            action: match cvars.get_value("sv_idlekick") {
+                Ok(k) if k != 0.0 => IdleAction::Kick,
+                _ => IdleAction::Spectate,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum IdleEvent {
+    /// The player in `slot` will be acted on in `remaining` unless they move.
+    Warning {
+        slot: usize,
+        action: IdleAction,
+        remaining: Duration,
+    },
+
+    /// The player in `slot` should be moved to spectator.
+    Spectate { slot: usize },
+
+    /// The player in `slot` should be dropped.
+    Kick { slot: usize },
+}
+
+impl IdleEvent {
+    /// Returns the message to print to the affected player.
+    pub fn message(&self) -> String {
+        match self {
+            IdleEvent::Warning {
+                action, remaining, ..
+            } => format!(
+                "You will be {} in {} seconds for being idle\n",
+                match action {
+                    IdleAction::Spectate => "moved to spectator",
+                    IdleAction::Kick => "kicked",
+                },
+                duration_to_f32(*remaining).ceil()
+            ),
+            IdleEvent::Spectate { .. } => "You were moved to spectator for being idle\n".to_owned(),
+            IdleEvent::Kick { .. } => "Kicked for being idle\n".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Activity {
+    last_active: Duration,
+    angles: Option<Vector3<Deg<f32>>>,
+    warned: bool,
+
+    // whether the idle action has been taken since the player last moved
+    handled: bool,
+}
+
+impl Activity {
+    fn new(time: Duration) -> Activity {
+        Activity {
+            last_active: time,
+            angles: None,
+            warned: false,
+            handled: false,
+        }
+    }
+}
+
+/// Tracks how long each client has gone without input.
+#[derive(Debug, Default)]
+pub struct IdleTracker {
+    clients: BTreeMap<usize, Activity>,
+}
+
+impl IdleTracker {
+    pub fn new() -> IdleTracker {
+        IdleTracker::default()
+    }
+
+    /// Record a command sent by the client in `slot` at `time`.
+    pub fn client_cmd(&mut self, slot: usize, cmd: &ClientCmd, time: Duration) {
+        let (angles, active) = match *cmd {
+            ClientCmd::Move {
+                angles,
+                fwd_move,
+                side_move,
+                up_move,
+                button_flags,
+                impulse,
+                ..
+            } => (
+                angles,
+                fwd_move != 0
+                    || side_move != 0
+                    || up_move != 0
+                    || button_flags != ButtonFlags::empty()
+                    || impulse != 0,
+            ),
+            _ => return,
+        };
+
+        let activity = self
+            .clients
+            .entry(slot)
+            .or_insert_with(|| Activity::new(time));
+        let turned = activity.angles.map_or(false, |a| a != angles);
+        activity.angles = Some(angles);
+
+        if active || turned {
+            activity.last_active = time;
+            activity.warned = false;
+            activity.handled = false;
+        }
+    }
+
+    /// Returns how long the client in `slot` has been idle at `time`.
+    pub fn idle_time(&self, slot: usize, time: Duration) -> Option<Duration> {
+        self.clients.get(&slot).map(|a| time - a.last_active)
+    }
+
+    /// Check the players in `slots` for idleness at `time`.
+    ///
+    /// Each player is warned once and acted on once per idle period. Clients
+    /// not in `slots` are forgotten.
+    pub fn update(&mut self, time: Duration, vars: &IdleVars, slots: &[usize]) -> Vec<IdleEvent> {
+        self.clients.retain(|slot, _| slots.contains(slot));
+
+        let mut events = Vec::new();
+        for slot in slots.iter().cloned() {
+            let activity = self
+                .clients
+                .entry(slot)
+                .or_insert_with(|| Activity::new(time));
+
+            let timeout = match vars.timeout {
+                Some(t) => t,
+                None => {
+                    // don't punish time spent idle while the check was off
+                    activity.last_active = time;
+                    continue;
+                }
+            };
+
+            if activity.handled {
+                continue;
+            }
+
+            let remaining = timeout - (time - activity.last_active);
+            if remaining <= Duration::zero() {
+                activity.handled = true;
+                events.push(match vars.action {
+                    IdleAction::Spectate => IdleEvent::Spectate { slot },
+                    IdleAction::Kick => IdleEvent::Kick { slot },
+                });
+            } else if remaining <= warning_time() && !activity.warned {
+                activity.warned = true;
+                events.push(IdleEvent::Warning {
+                    slot,
+                    action: vars.action,
+                    remaining,
+                });
+            }
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn move_cmd(fwd_move: i16, yaw: f32) -> ClientCmd {
+        ClientCmd::Move {
+            send_time: Duration::zero(),
+            angles: Vector3::new(Deg(0.0), Deg(yaw), Deg(0.0)),
+            fwd_move,
+            side_move: 0,
+            up_move: 0,
+            button_flags: ButtonFlags::empty(),
+            impulse: 0,
+        }
+    }
+
+    #[test]
+    fn test_idle_spectate() {
+        let vars = IdleVars {
+            timeout: Some(Duration::seconds(60)),
+            action: IdleAction::Spectate,
+        };
+        let mut idle = IdleTracker::new();
+
+        assert!(idle.update(Duration::zero(), &vars, &[0, 1]).is_empty());
+
+        // slot 1 keeps moving, slot 0 sends empty moves
+        idle.client_cmd(0, &move_cmd(0, 0.0), Duration::seconds(30));
+        idle.client_cmd(1, &move_cmd(200, 0.0), Duration::seconds(30));
+        assert_eq!(
+            idle.update(Duration::seconds(50), &vars, &[0, 1]),
+            vec![IdleEvent::Warning {
+                slot: 0,
+                action: IdleAction::Spectate,
+                remaining: Duration::seconds(10),
+            }]
+        );
+        assert!(idle
+            .update(Duration::seconds(55), &vars, &[0, 1])
+            .is_empty());
+        assert_eq!(
+            idle.update(Duration::seconds(60), &vars, &[0, 1]),
+            vec![IdleEvent::Spectate { slot: 0 }]
+        );
+        assert!(idle
+            .update(Duration::seconds(70), &vars, &[0, 1])
+            .is_empty());
+
+        // turning counts as activity
+        idle.client_cmd(0, &move_cmd(0, 90.0), Duration::seconds(85));
+        assert_eq!(
+            idle.idle_time(0, Duration::seconds(85)),
+            Some(Duration::zero())
+        );
+    }
+
+    #[test]
+    fn test_idle_kick_disabled() {
+        let mut vars = IdleVars {
+            timeout: None,
+            action: IdleAction::Kick,
+        };
+        let mut idle = IdleTracker::new();
+
+        assert!(idle.update(Duration::zero(), &vars, &[3]).is_empty());
+        assert!(idle.update(Duration::minutes(10), &vars, &[3]).is_empty());
+
+        // enabling the check starts the clock from now
+        vars.timeout = Some(Duration::seconds(30));
+        assert!(idle.update(Duration::minutes(10), &vars, &[3]).is_empty());
+        assert_eq!(
+            idle.update(Duration::minutes(11), &vars, &[3]),
+            vec![IdleEvent::Kick { slot: 3 }]
+        );
+        assert_eq!(
+            IdleEvent::Kick { slot: 3 }.message(),
+            "Kicked for being idle\n"
+        );
+    }
+}
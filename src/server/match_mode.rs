@@ -0,0 +1,657 @@
+// Copyright © 2018 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Organized matches.
+//!
+//! With `match_mode` set, a level starts in warmup. Once every player has
+//! declared themselves `ready`, the server counts down `match_countdown`
+//! seconds, resets everyone's score, respawns them and starts the match clock.
+//! The match ends when a player reaches `fraglimit` or when `timelimit`
+//! minutes have passed. If the lead is tied when time runs out, the match goes
+//! to overtime periods of `match_overtime` minutes, or to sudden death if
+//! `match_overtime` is 0.
+
+use std::collections::BTreeMap;
+
+use crate::common::{
+    console::CvarRegistry,
+    engine::{duration_from_f32, duration_to_f32},
+};
+
+use chrono::Duration;
+use serde::Serialize;
+
+/// The fewest players that can start a match.
+pub const MIN_PLAYERS: usize = 2;
+
+/// Returns whether the `match_mode` cvar is set.
+pub fn enabled(cvars: &CvarRegistry) -> bool {
+    cvars.get_value("match_mode").unwrap_or(0.0) != 0.0
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MatchVars {
+    /// How long the match lasts before overtime, if there is a time limit.
+    pub timelimit: Option<Duration>,
+
+    /// The score that wins the match, if there is a frag limit.
+    pub fraglimit: Option<i32>,
+
+    pub countdown: Duration,
+
+    /// How long each overtime period lasts, or `None` for sudden death.
+    pub overtime: Option<Duration>,
+}
+
+impl MatchVars {
+    pub fn from_cvars(cvars: &CvarRegistry) -> MatchVars {
+        let minutes = |name| match cvars.get_value(name) {
+            Ok(m) if m > 0.0 => Some(duration_from_f32(m * 60.0)),
+            _ => None,
+        };
+
+        MatchVars {
+            timelimit: minutes("timelimit"),
+            fraglimit: match cvars.get_value("fraglimit") {
+                Ok(f) if f >= 1.0 => Some(f as i32),
+                _ => None,
+            },
+            countdown: duration_from_f32(
+                cvars.get_value("match_countdown").unwrap_or(0.0).max(0.0),
+            ),
+            overtime: minutes("match_overtime"),
+        }
+    }
+}
+
+impl Default for MatchVars {
+    fn default() -> MatchVars {
+        MatchVars {
+            timelimit: None,
+            fraglimit: None,
+            countdown: Duration::seconds(10),
+            overtime: Some(Duration::minutes(2)),
+        }
+    }
+}
+
+/// A player's standing in a match.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PlayerScore {
+    pub slot: usize,
+    pub name: String,
+    pub frags: i32,
+    pub deaths: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchEnd {
+    /// A player reached the frag limit.
+    FragLimit,
+
+    /// Time ran out with a single player in the lead.
+    TimeLimit,
+
+    /// An overtime period ended with a single player in the lead.
+    Overtime,
+
+    /// A player took the lead in sudden death.
+    SuddenDeath,
+}
+
+/// The outcome of a finished match.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MatchResult {
+    pub reason: MatchEnd,
+
+    /// How long the match lasted, including overtime.
+    pub duration_ms: i64,
+
+    pub overtime_periods: u32,
+
+    /// The players' final scores, best first.
+    pub standings: Vec<PlayerScore>,
+}
+
+impl MatchResult {
+    /// Returns the result of a match that ended for `reason`, ranking the
+    /// players in `scores`.
+    pub fn new(
+        reason: MatchEnd,
+        duration: Duration,
+        overtime_periods: u32,
+        scores: &[PlayerScore],
+    ) -> MatchResult {
+        let mut standings = scores.to_vec();
+        standings.sort_by(|a, b| b.frags.cmp(&a.frags).then(a.slot.cmp(&b.slot)));
+
+        MatchResult {
+            reason,
+            duration_ms: duration.num_milliseconds(),
+            overtime_periods,
+            standings,
+        }
+    }
+
+    /// Returns the player with the best score, or `None` if the lead is tied.
+    pub fn winner(&self) -> Option<&PlayerScore> {
+        match self.standings.as_slice() {
+            [] => None,
+            [first, second, ..] if first.frags == second.frags => None,
+            [first, ..] => Some(first),
+        }
+    }
+
+    /// Serialize the result as JSON for match logs and external tools.
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    /// Returns a human-readable summary of the result, one line per player.
+    pub fn report(&self) -> String {
+        let secs = self.duration_ms / 1000;
+        let reason = match self.reason {
+            MatchEnd::FragLimit => "fraglimit hit",
+            MatchEnd::TimeLimit => "timelimit hit",
+            MatchEnd::Overtime => "overtime over",
+            MatchEnd::SuddenDeath => "sudden death",
+        };
+
+        let mut report = format!(
+            "Match over ({}) after {}:{:02}\n",
+            reason,
+            secs / 60,
+            secs % 60
+        );
+        for (i, player) in self.standings.iter().enumerate() {
+            report += &format!("{:2}. {:<16} {:3}\n", i + 1, player.name, player.frags);
+        }
+
+        report
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MatchPhase {
+    /// Players are warming up and declaring themselves ready.
+    Warmup,
+
+    /// Everyone is ready and the match starts at `end`.
+    Countdown {
+        end: Duration,
+    },
+
+    /// The match started at `start`.
+    Playing {
+        start: Duration,
+    },
+
+    /// The match started at `start` and is in its `period`th overtime, which
+    /// ends at `end`, or when someone takes the lead in sudden death.
+    Overtime {
+        start: Duration,
+        period: u32,
+        end: Option<Duration>,
+    },
+
+    Finished,
+}
+
+/// Something that happened in the match that players should hear about.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MatchEvent {
+    Ready {
+        slot: usize,
+        ready: bool,
+    },
+
+    /// The match starts in `seconds`.
+    Countdown {
+        seconds: i64,
+    },
+
+    /// A player became unready during the countdown.
+    CountdownAborted,
+
+    /// The match has started. Scores should be reset and every player
+    /// respawned.
+    Start,
+
+    Overtime {
+        period: u32,
+    },
+
+    End(MatchResult),
+}
+
+/// Tracks the phases of a match.
+#[derive(Debug)]
+pub struct Match {
+    vars: MatchVars,
+    phase: MatchPhase,
+
+    // whether each player in the match is ready, by client slot
+    ready: BTreeMap<usize, bool>,
+
+    // the last countdown second announced
+    countdown_announced: i64,
+}
+
+impl Match {
+    pub fn new(vars: MatchVars) -> Match {
+        Match {
+            vars,
+            phase: MatchPhase::Warmup,
+            ready: BTreeMap::new(),
+            countdown_announced: 0,
+        }
+    }
+
+    /// Update the match settings.
+    ///
+    /// Limits take effect immediately, but a countdown already in progress
+    /// keeps its original length.
+    pub fn set_vars(&mut self, vars: MatchVars) {
+        self.vars = vars;
+    }
+
+    pub fn phase(&self) -> MatchPhase {
+        self.phase
+    }
+
+    pub fn is_ready(&self, slot: usize) -> bool {
+        self.ready.get(&slot).copied().unwrap_or(false)
+    }
+
+    /// Return the match to warmup, with every player unready.
+    pub fn reset(&mut self) {
+        self.phase = MatchPhase::Warmup;
+        for ready in self.ready.values_mut() {
+            *ready = false;
+        }
+    }
+
+    /// Add a player to the match.
+    ///
+    /// New players aren't ready, so this aborts a countdown in progress.
+    pub fn join(&mut self, slot: usize) -> Vec<MatchEvent> {
+        let mut events = Vec::new();
+        self.ready.insert(slot, false);
+        if let MatchPhase::Countdown { .. } = self.phase {
+            self.phase = MatchPhase::Warmup;
+            events.push(MatchEvent::CountdownAborted);
+        }
+
+        events
+    }
+
+    /// Remove a player from the match.
+    ///
+    /// If everyone left is ready, this starts the countdown.
+    pub fn leave(&mut self, slot: usize, time: Duration) -> Vec<MatchEvent> {
+        let mut events = Vec::new();
+        self.ready.remove(&slot);
+
+        if let MatchPhase::Countdown { .. } = self.phase {
+            if self.ready.len() < MIN_PLAYERS {
+                self.phase = MatchPhase::Warmup;
+                events.push(MatchEvent::CountdownAborted);
+            }
+        }
+
+        self.check_countdown(time, &mut events);
+        events
+    }
+
+    /// Handle a `ready` or `notready` command from the client in `slot`.
+    ///
+    /// Returns `None` if `cmd` isn't a match command.
+    pub fn handle_command(
+        &mut self,
+        slot: usize,
+        cmd: &str,
+        time: Duration,
+    ) -> Option<Vec<MatchEvent>> {
+        match cmd.trim() {
+            "ready" => Some(self.set_ready(slot, true, time)),
+            "notready" => Some(self.set_ready(slot, false, time)),
+            _ => None,
+        }
+    }
+
+    /// Mark the player in `slot` as ready or not.
+    ///
+    /// Readiness can only change before the match starts. When the last
+    /// player becomes ready, the countdown starts; if anyone becomes unready
+    /// during the countdown, it stops.
+    pub fn set_ready(&mut self, slot: usize, ready: bool, time: Duration) -> Vec<MatchEvent> {
+        let mut events = Vec::new();
+
+        match self.phase {
+            MatchPhase::Warmup | MatchPhase::Countdown { .. } => (),
+            _ => return events,
+        }
+
+        if self.ready.insert(slot, ready) == Some(ready) {
+            return events;
+        }
+        events.push(MatchEvent::Ready { slot, ready });
+
+        if !ready {
+            if let MatchPhase::Countdown { .. } = self.phase {
+                self.phase = MatchPhase::Warmup;
+                events.push(MatchEvent::CountdownAborted);
+            }
+        }
+
+        self.check_countdown(time, &mut events);
+        events
+    }
+
+    // start the countdown if enough players are ready
+    fn check_countdown(&mut self, time: Duration, events: &mut Vec<MatchEvent>) {
+        if self.phase != MatchPhase::Warmup
+            || self.ready.len() < MIN_PLAYERS
+            || !self.ready.values().all(|r| *r)
+        {
+            return;
+        }
+
+        self.phase = MatchPhase::Countdown {
+            end: time + self.vars.countdown,
+        };
+        self.countdown_announced = self.vars.countdown.num_seconds();
+        events.push(MatchEvent::Countdown {
+            seconds: self.countdown_announced,
+        });
+    }
+
+    /// Advance the match to `time`, checking `scores` against the limits.
+    pub fn update(&mut self, time: Duration, scores: &[PlayerScore]) -> Vec<MatchEvent> {
+        let mut events = Vec::new();
+
+        match self.phase {
+            MatchPhase::Warmup | MatchPhase::Finished => (),
+
+            MatchPhase::Countdown { end } => {
+                if time >= end {
+                    self.phase = MatchPhase::Playing { start: time };
+                    events.push(MatchEvent::Start);
+                } else {
+                    // round up, so the last announcement is "1"
+                    let remaining = duration_to_f32(end - time).ceil() as i64;
+                    if remaining < self.countdown_announced {
+                        self.countdown_announced = remaining;
+                        events.push(MatchEvent::Countdown { seconds: remaining });
+                    }
+                }
+            }
+
+            MatchPhase::Playing { start } => {
+                if self.fraglimit_hit(scores) {
+                    events.push(self.finish(MatchEnd::FragLimit, start, time, 0, scores));
+                } else if let Some(limit) = self.vars.timelimit {
+                    if time - start >= limit {
+                        if tied(scores) {
+                            events.push(self.start_overtime(start, time, 1));
+                        } else {
+                            events.push(self.finish(MatchEnd::TimeLimit, start, time, 0, scores));
+                        }
+                    }
+                }
+            }
+
+            MatchPhase::Overtime { start, period, end } => {
+                if self.fraglimit_hit(scores) {
+                    events.push(self.finish(MatchEnd::FragLimit, start, time, period, scores));
+                } else {
+                    match end {
+                        None if !tied(scores) => events.push(self.finish(
+                            MatchEnd::SuddenDeath,
+                            start,
+                            time,
+                            period,
+                            scores,
+                        )),
+                        Some(end) if time >= end => {
+                            if tied(scores) {
+                                events.push(self.start_overtime(start, time, period + 1));
+                            } else {
+                                events.push(self.finish(
+                                    MatchEnd::Overtime,
+                                    start,
+                                    time,
+                                    period,
+                                    scores,
+                                ));
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+
+        events
+    }
+
+    fn fraglimit_hit(&self, scores: &[PlayerScore]) -> bool {
+        match self.vars.fraglimit {
+            Some(limit) => scores.iter().any(|s| s.frags >= limit),
+            None => false,
+        }
+    }
+
+    fn start_overtime(&mut self, start: Duration, time: Duration, period: u32) -> MatchEvent {
+        self.phase = MatchPhase::Overtime {
+            start,
+            period,
+            end: self.vars.overtime.map(|o| time + o),
+        };
+
+        MatchEvent::Overtime { period }
+    }
+
+    fn finish(
+        &mut self,
+        reason: MatchEnd,
+        start: Duration,
+        time: Duration,
+        overtime_periods: u32,
+        scores: &[PlayerScore],
+    ) -> MatchEvent {
+        self.phase = MatchPhase::Finished;
+        MatchEvent::End(MatchResult::new(
+            reason,
+            time - start,
+            overtime_periods,
+            scores,
+        ))
+    }
+}
+
+// whether two or more players share the best score
+fn tied(scores: &[PlayerScore]) -> bool {
+    let best = match scores.iter().map(|s| s.frags).max() {
+        Some(b) => b,
+        None => return false,
+    };
+
+    scores.iter().filter(|s| s.frags == best).count() > 1
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scores(frags: &[i32]) -> Vec<PlayerScore> {
+        frags
+            .iter()
+            .enumerate()
+            .map(|(slot, frags)| PlayerScore {
+                slot,
+                name: format!("player{}", slot),
+                frags: *frags,
+                deaths: 0,
+            })
+            .collect()
+    }
+
+    fn started_match(vars: MatchVars) -> Match {
+        let mut m = Match::new(vars);
+        m.set_ready(0, true, Duration::zero());
+        m.set_ready(1, true, Duration::zero());
+        m.update(Duration::seconds(10), &scores(&[0, 0]));
+        assert_eq!(
+            m.phase(),
+            MatchPhase::Playing {
+                start: Duration::seconds(10)
+            }
+        );
+        m
+    }
+
+    #[test]
+    fn test_ready_countdown() {
+        let mut m = Match::new(MatchVars::default());
+
+        // one player isn't enough
+        assert_eq!(
+            m.set_ready(0, true, Duration::zero()),
+            vec![MatchEvent::Ready {
+                slot: 0,
+                ready: true
+            }]
+        );
+        assert_eq!(m.phase(), MatchPhase::Warmup);
+
+        let events = m.set_ready(1, true, Duration::zero());
+        assert_eq!(events[1], MatchEvent::Countdown { seconds: 10 });
+
+        assert_eq!(
+            m.update(Duration::milliseconds(500), &scores(&[0, 0])),
+            vec![]
+        );
+        assert_eq!(
+            m.update(Duration::milliseconds(1500), &scores(&[0, 0])),
+            vec![MatchEvent::Countdown { seconds: 9 }]
+        );
+
+        // backing out stops the countdown
+        let events = m
+            .handle_command(1, "notready", Duration::seconds(2))
+            .unwrap();
+        assert_eq!(events[1], MatchEvent::CountdownAborted);
+        assert_eq!(m.phase(), MatchPhase::Warmup);
+        assert!(m.handle_command(1, "kill", Duration::seconds(2)).is_none());
+
+        m.handle_command(1, "ready", Duration::seconds(3)).unwrap();
+        assert_eq!(
+            m.update(Duration::seconds(13), &scores(&[0, 0])),
+            vec![MatchEvent::Start]
+        );
+
+        // readiness is fixed once the match starts
+        assert!(m.set_ready(1, false, Duration::seconds(14)).is_empty());
+    }
+
+    #[test]
+    fn test_fraglimit() {
+        let mut m = started_match(MatchVars {
+            fraglimit: Some(20),
+            ..MatchVars::default()
+        });
+
+        assert!(m
+            .update(Duration::seconds(60), &scores(&[19, 12]))
+            .is_empty());
+        let events = m.update(Duration::seconds(70), &scores(&[19, 20]));
+        let result = match events.as_slice() {
+            [MatchEvent::End(r)] => r,
+            e => panic!("unexpected events {:?}", e),
+        };
+
+        assert_eq!(result.reason, MatchEnd::FragLimit);
+        assert_eq!(result.duration_ms, 60_000);
+        assert_eq!(result.winner().unwrap().slot, 1);
+        assert_eq!(m.phase(), MatchPhase::Finished);
+    }
+
+    #[test]
+    fn test_overtime() {
+        let mut m = started_match(MatchVars {
+            timelimit: Some(Duration::minutes(10)),
+            overtime: Some(Duration::minutes(2)),
+            ..MatchVars::default()
+        });
+
+        let end = Duration::seconds(610);
+        assert_eq!(
+            m.update(end, &scores(&[5, 5])),
+            vec![MatchEvent::Overtime { period: 1 }]
+        );
+
+        // still tied after the first period
+        let ot_end = end + Duration::minutes(2);
+        assert_eq!(
+            m.update(ot_end, &scores(&[6, 6])),
+            vec![MatchEvent::Overtime { period: 2 }]
+        );
+
+        let events = m.update(ot_end + Duration::minutes(2), &scores(&[6, 7]));
+        match events.as_slice() {
+            [MatchEvent::End(r)] => {
+                assert_eq!(r.reason, MatchEnd::Overtime);
+                assert_eq!(r.overtime_periods, 2);
+                assert_eq!(r.standings[0].name, "player1");
+            }
+            e => panic!("unexpected events {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_sudden_death() {
+        let mut m = started_match(MatchVars {
+            timelimit: Some(Duration::minutes(1)),
+            overtime: None,
+            ..MatchVars::default()
+        });
+
+        assert_eq!(
+            m.update(Duration::seconds(70), &scores(&[3, 3])),
+            vec![MatchEvent::Overtime { period: 1 }]
+        );
+        assert!(m
+            .update(Duration::seconds(500), &scores(&[3, 3]))
+            .is_empty());
+
+        let events = m.update(Duration::seconds(501), &scores(&[4, 3]));
+        match events.as_slice() {
+            [MatchEvent::End(r)] => {
+                assert_eq!(r.reason, MatchEnd::SuddenDeath);
+                assert_eq!(
+                    r.to_json().unwrap(),
+                    "{\"reason\":\"suddendeath\",\"duration_ms\":491000,\"overtime_periods\":1,\
+                     \"standings\":[{\"slot\":0,\"name\":\"player0\",\"frags\":4,\"deaths\":0},\
+                     {\"slot\":1,\"name\":\"player1\",\"frags\":3,\"deaths\":0}]}"
+                );
+            }
+            e => panic!("unexpected events {:?}", e),
+        }
+    }
+}
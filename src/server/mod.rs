@@ -15,24 +15,47 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+pub mod anticheat;
+mod cvars;
+pub mod datagram;
+pub mod host;
+pub mod idle;
+pub mod match_mode;
+pub mod motd;
+pub mod netstats;
 pub mod precache;
 pub mod progs;
+pub mod rate;
+pub mod rcon;
+pub mod record;
+pub mod rotation;
+pub mod stats;
 pub mod world;
 
+pub use self::cvars::{bind_listener, forward_port, message_timeout, register_cvars};
+
 use std::{
     cell::{Ref, RefCell},
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufWriter, Cursor},
     rc::Rc,
+    time::Instant,
 };
 
 use crate::{
     common::{
         console::CvarRegistry,
         engine::{duration_from_f32, duration_to_f32},
-        math::Hyperplane,
+        math::{self, Hyperplane},
         model::Model,
+        net::{
+            ButtonFlags, ClientCmd, EntityEffects, EntityState, EntityUpdate, GameType, ItemFlags,
+            NetError, PlayerColor, PlayerData, ServerCmd, SignOnStage, PROTOCOL_VERSION,
+        },
         parse,
-        vfs::Vfs,
+        plugin::Plugins,
+        vfs::{Vfs, VfsError},
     },
     server::{
         progs::{functions::FunctionKind, GlobalAddrFunction},
@@ -41,15 +64,30 @@ use crate::{
 };
 
 use self::{
+    anticheat::{AntiCheat, AntiCheatMode},
+    datagram::EntityCandidate,
+    idle::{IdleEvent, IdleTracker, IdleVars},
+    match_mode::{Match, MatchEnd, MatchEvent, MatchPhase, MatchResult, MatchVars, PlayerScore},
+    motd::{AdvertVars, Adverts, Motd},
+    netstats::{FrameCounters, NetStats},
     precache::Precache,
     progs::{
+        debug::{self, Debugger, StackTrace},
+        files::{FileMode, QcFiles},
+        functions,
         globals::{
             GLOBAL_ADDR_ARG_0, GLOBAL_ADDR_ARG_1, GLOBAL_ADDR_ARG_2, GLOBAL_ADDR_ARG_3,
-            GLOBAL_ADDR_RETURN,
+            GLOBAL_ADDR_ARG_4, GLOBAL_ADDR_ARG_5, GLOBAL_ADDR_RETURN,
         },
-        EntityFieldAddr, EntityId, ExecutionContext, FunctionId, GlobalAddrEntity, GlobalAddrFloat,
-        Globals, LoadProgs, Opcode, ProgsError, StringId, StringTable,
+        reload::{ProgsWatcher, ReloadStats, Remap, PROGS_PATH},
+        strings, EntityFieldAddr, EntityId, ExecutionContext, FunctionId, GlobalAddrEntity,
+        GlobalAddrFloat, GlobalAddrVector, Globals, LoadProgs, Opcode, ProgsError, StringId,
+        StringTable,
     },
+    rate::ClientRate,
+    record::SessionRecorder,
+    rotation::{MapRotation, RotationEvent},
+    stats::MatchReport,
     world::{
         phys::{self, CollideKind, CollisionFlags, Trace, TraceEndKind},
         EntityFlags, EntitySolid, FieldAddrFloat, FieldAddrFunctionId, FieldAddrStringId, World,
@@ -57,12 +95,20 @@ use self::{
 };
 
 use arrayvec::ArrayVec;
-use cgmath::{InnerSpace, Vector3, Zero};
+use cgmath::{Deg, InnerSpace, Vector3, Zero};
 use chrono::Duration;
+use num::FromPrimitive;
+use rand::{rngs::SmallRng, SeedableRng};
 
 const MAX_DATAGRAM: usize = 1024;
 const MAX_LIGHTSTYLES: usize = 64;
 
+// the view height clients assume when none is sent
+const DEFAULT_VIEW_HEIGHT: f32 = 22.0;
+
+// players in the air can only change their speed by this much
+const MAX_AIR_SPEED: f32 = 30.0;
+
 /// The state of a client's connection to the server.
 pub enum ClientState {
     /// The client is still connecting.
@@ -78,6 +124,27 @@ pub struct ClientActive {
 
     /// ID of the entity controlled by this client.
     entity_id: EntityId,
+
+    /// If true, the client is watching rather than playing.
+    spectator: bool,
+}
+
+/// Who a message sent by QuakeC is for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Recipient {
+    /// Every client in the game.
+    All,
+
+    /// The client in a slot.
+    Client(usize),
+}
+
+/// The movement a client asked for in its last move command.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ClientMove {
+    pub forward: f32,
+    pub side: f32,
+    pub up: f32,
 }
 
 bitflags! {
@@ -120,10 +187,13 @@ impl ClientSlots {
         self.slots.len()
     }
 
-    /// Finds an available connection slot for a new client.
-    pub fn find_available(&mut self) -> Option<&mut ClientState> {
-        let slot = self.slots.iter_mut().find(|s| s.is_none())?;
-        Some(slot.insert(ClientState::Connecting))
+    /// Finds an available connection slot for a new client and reserves it.
+    ///
+    /// Returns the index of the slot, or `None` if every slot is taken.
+    pub fn find_available(&mut self) -> Option<usize> {
+        let slot = self.slots.iter().position(|s| s.is_none())?;
+        self.slots[slot] = Some(ClientState::Connecting);
+        Some(slot)
     }
 }
 
@@ -165,6 +235,7 @@ pub struct SessionLoading {
 
 impl SessionLoading {
     pub fn new(
+        max_clients: usize,
         vfs: Rc<Vfs>,
         cvars: Rc<RefCell<CvarRegistry>>,
         progs: LoadProgs,
@@ -172,7 +243,7 @@ impl SessionLoading {
         entmap: String,
     ) -> SessionLoading {
         SessionLoading {
-            level: LevelState::new(vfs, cvars, progs, models, entmap),
+            level: LevelState::new(max_clients, vfs, cvars, progs, models, entmap),
         }
     }
 
@@ -209,6 +280,59 @@ pub struct SessionActive {
 pub struct Session {
     persist: SessionPersistent,
     state: SessionState,
+
+    /// The match being played, if `match_mode` is set.
+    match_state: Option<Match>,
+
+    /// Events from players joining or leaving the match, returned by the next
+    /// `update_match`.
+    match_events: Vec<MatchEvent>,
+
+    /// How many times each client has died this match, by slot.
+    deaths: BTreeMap<usize, PlayerDeaths>,
+
+    /// How long each player has gone without input.
+    idle: IdleTracker,
+
+    /// Checks players' moves for cheating.
+    anticheat: AntiCheat,
+
+    /// Each client's bandwidth limit, by slot.
+    rates: BTreeMap<usize, ClientRate>,
+
+    /// Records the session for replay when `sv_record` is set.
+    recorder: Option<SessionRecorder<BufWriter<File>>>,
+
+    /// Entity and bandwidth counters for the `netstats` command.
+    netstats: NetStats,
+
+    /// The maps to cycle through, and whether this level has ended.
+    rotation: Rc<RefCell<MapRotation>>,
+    level_end: LevelEnd,
+
+    /// Decides when `sv_advert` is printed.
+    adverts: Adverts,
+
+    /// Watches `progs.dat` for changes while `developer` is set.
+    progs_watcher: Option<ProgsWatcher>,
+
+    plugins: Rc<RefCell<Plugins>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LevelEnd {
+    Playing,
+    Intermission { start: Duration },
+
+    // waiting for the host to load the next map
+    Changing,
+}
+
+#[derive(Debug, Default)]
+struct PlayerDeaths {
+    // whether the player was dead last frame
+    dead: bool,
+    count: i32,
 }
 
 impl Session {
@@ -219,15 +343,63 @@ impl Session {
         progs: LoadProgs,
         models: Vec<Model>,
         entmap: String,
+        plugins: Rc<RefCell<Plugins>>,
     ) -> Session {
+        let match_state = match match_mode::enabled(&cvars.borrow()) {
+            true => Some(Match::new(MatchVars::from_cvars(&cvars.borrow()))),
+            false => None,
+        };
+
+        let record_path = cvars.borrow().get("sv_record").unwrap_or_default();
+        let rotation = MapRotation::from_cvars(&cvars.borrow(), &vfs);
+        let level = LevelState::new(max_clients, vfs, cvars, progs, models, entmap);
+        let recorder = match record_path.as_str() {
+            "" => None,
+            path => match SessionRecorder::create(path, level.seed(), &level.map_name()) {
+                Ok(r) => {
+                    info!("Recording session to {} (seed {})", path, level.seed());
+                    Some(r)
+                }
+                Err(e) => {
+                    warn!("Couldn't record session to {}: {:?}", path, e);
+                    None
+                }
+            },
+        };
+
         Session {
             persist: SessionPersistent::new(max_clients),
-            state: SessionState::Loading(SessionLoading {
-                level: LevelState::new(vfs, cvars, progs, models, entmap),
-            }),
+            state: SessionState::Loading(SessionLoading { level }),
+            match_state,
+            match_events: Vec::new(),
+            deaths: BTreeMap::new(),
+            idle: IdleTracker::new(),
+            anticheat: AntiCheat::new(),
+            rates: BTreeMap::new(),
+            recorder,
+            netstats: NetStats::new(),
+            rotation: Rc::new(RefCell::new(rotation)),
+            level_end: LevelEnd::Playing,
+            adverts: Adverts::new(),
+            progs_watcher: None,
+            plugins,
         }
     }
 
+    /// Use a map rotation that outlives the level.
+    ///
+    /// This carries the map set with `nextmap`, and the limits the rotation
+    /// has overridden, over to the next level.
+    pub fn set_rotation(&mut self, rotation: Rc<RefCell<MapRotation>>) {
+        self.rotation = rotation;
+    }
+
+    /// Returns `false` if a plugin has dropped a command from the client in
+    /// `slot`, in which case the command shouldn't be handled.
+    pub fn filter_client_cmd(&self, slot: usize, cmd: &str) -> bool {
+        self.plugins.borrow_mut().filter_client_cmd(slot, cmd)
+    }
+
     /// Returns the maximum number of clients allowed on the server.
     pub fn max_clients(&self) -> usize {
         self.persist.client_slots.limit()
@@ -238,6 +410,293 @@ impl Session {
         self.persist.client(slot)
     }
 
+    /// Completes the loading process.
+    ///
+    /// The entities' baselines are saved, and the level starts running.
+    pub fn finish_loading(self) -> Result<Session, ProgsError> {
+        let state = match self.state {
+            SessionState::Loading(mut loading) => {
+                loading.level.create_baselines()?;
+                SessionState::Active(loading.finish())
+            }
+            active => active,
+        };
+
+        Ok(Session { state, ..self })
+    }
+
+    /// Reserves a slot for a new client.
+    ///
+    /// Returns the slot, or `None` if the server is full.
+    pub fn connect_client(&mut self) -> Option<usize> {
+        self.persist.client_slots.find_available()
+    }
+
+    /// Put the client in `slot` into the game.
+    pub fn spawn_client(
+        &mut self,
+        slot: usize,
+        name: &str,
+        colors: PlayerColor,
+    ) -> Result<(), ProgsError> {
+        match self.persist.client(slot) {
+            Some(ClientState::Connecting) => (),
+            Some(ClientState::Active(_)) => {
+                return Err(ProgsError::with_msg(format!(
+                    "Client {} already spawned",
+                    slot
+                )))
+            }
+            None => return Err(ProgsError::with_msg(format!("No client in slot {}", slot))),
+        }
+
+        // client entities follow the world entity
+        let entity_id = EntityId(slot + 1);
+        self.persist.client_slots.slots[slot] = Some(ClientState::Active(ClientActive {
+            privileged: false,
+            entity_id,
+            spectator: false,
+        }));
+        self.level_mut().spawn_player(entity_id, name, colors)?;
+
+        if let Some(ref mut m) = self.match_state {
+            let events = m.join(slot);
+            self.match_events.extend(events);
+        }
+
+        Ok(())
+    }
+
+    /// Disconnect the client in `slot` and free the slot.
+    ///
+    /// QuakeC is told the player has left if they had spawned.
+    pub fn drop_client(&mut self, slot: usize) -> Result<(), ProgsError> {
+        let client = match self.persist.client_slots.slots.get_mut(slot) {
+            Some(client) => client.take(),
+            None => return Ok(()),
+        };
+
+        self.record_disconnect(slot);
+        self.deaths.remove(&slot);
+        self.rates.remove(&slot);
+        self.anticheat.remove(slot);
+        self.netstats.remove(slot);
+
+        match client {
+            Some(ClientState::Active(active)) => {
+                let time = self.time().unwrap_or_else(Duration::zero);
+                if let Some(ref mut m) = self.match_state {
+                    let events = m.leave(slot, time);
+                    self.match_events.extend(events);
+                }
+
+                self.level_mut().disconnect_player(active.entity_id)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the entity controlled by the client in `slot`, if they have
+    /// spawned.
+    pub fn client_entity(&self, slot: usize) -> Option<EntityId> {
+        match self.persist.client(slot)? {
+            ClientState::Active(active) => Some(active.entity_id),
+            ClientState::Connecting => None,
+        }
+    }
+
+    /// Rename the player in `slot`.
+    pub fn set_client_name(&mut self, slot: usize, name: &str) -> Result<(), ProgsError> {
+        match self.client_entity(slot) {
+            Some(ent_id) => self.level_mut().set_player_name(ent_id, name),
+            None => Ok(()),
+        }
+    }
+
+    /// Kill the player in `slot` so they respawn.
+    pub fn kill_client(&mut self, slot: usize) -> Result<(), ProgsError> {
+        match self.client_entity(slot) {
+            Some(ent_id) => self.level_mut().kill_player(ent_id),
+            None => Ok(()),
+        }
+    }
+
+    /// Apply a move command from the client in `slot`.
+    pub fn move_client(&mut self, slot: usize, cmd: &ClientCmd) -> Result<(), ProgsError> {
+        let ent_id = match self.client_entity(slot) {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+
+        if let ClientCmd::Move {
+            angles,
+            fwd_move,
+            side_move,
+            up_move,
+            button_flags,
+            impulse,
+            ..
+        } = *cmd
+        {
+            let player_move = ClientMove {
+                forward: fwd_move as f32,
+                side: side_move as f32,
+                up: up_move as f32,
+            };
+            self.level_mut()
+                .client_move(ent_id, angles, player_move, button_flags, impulse)?;
+        }
+
+        Ok(())
+    }
+
+    /// Run the level for one frame.
+    pub fn frame(&mut self, frame_time: Duration) -> Result<(), ProgsError> {
+        let Session {
+            ref persist,
+            ref mut state,
+            ..
+        } = *self;
+
+        match state {
+            SessionState::Loading(_) => Ok(()),
+            SessionState::Active(ref mut active) => {
+                // a breakpoint ends the frame where it was hit
+                match active.level.physics(&persist.client_slots, frame_time) {
+                    Err(ProgsError::Paused) => Ok(()),
+                    r => r,
+                }
+            }
+        }
+    }
+
+    /// Returns the `ServerInfo` message that starts a client's sign-on.
+    pub fn server_info(&self) -> ServerCmd {
+        let level = self.level();
+        let deathmatch = level.cvars.borrow().get_value("deathmatch").unwrap_or(0.0);
+
+        ServerCmd::ServerInfo {
+            protocol_version: PROTOCOL_VERSION as i32,
+            max_clients: self.max_clients() as u8,
+            game_type: match deathmatch {
+                d if d == 0.0 => GameType::CoOp,
+                _ => GameType::Deathmatch,
+            },
+            message: level.map_name(),
+            model_precache: level.model_names(),
+            sound_precache: level.sound_names(),
+        }
+    }
+
+    /// Returns the messages sent in answer to a client's `prespawn` command.
+    ///
+    /// These carry the entity baselines.
+    pub fn prespawn_messages(&self) -> Vec<ServerCmd> {
+        let mut msgs: Vec<ServerCmd> = self
+            .level()
+            .baselines()
+            .map(|(ent_id, baseline)| ServerCmd::SpawnBaseline {
+                ent_id: ent_id as u16,
+                model_id: baseline.model_id as u16,
+                frame_id: baseline.frame_id as u16,
+                colormap: baseline.colormap,
+                skin_id: baseline.skin_id as u8,
+                origin: baseline.origin,
+                angles: baseline.angles,
+            })
+            .collect();
+        msgs.push(ServerCmd::SignOnStage {
+            stage: SignOnStage::ClientInfo,
+        });
+
+        msgs
+    }
+
+    /// Returns the messages sent to the client in `slot` once it has spawned.
+    ///
+    /// These carry the lightstyles and the other players, and point the
+    /// client's view at its entity.
+    pub fn spawn_messages(&self, slot: usize) -> Result<Vec<ServerCmd>, ProgsError> {
+        let level = self.level();
+        let ent_id = match self.client_entity(slot) {
+            Some(e) => e,
+            None => {
+                return Err(ProgsError::with_msg(format!(
+                    "Client {} hasn't spawned",
+                    slot
+                )))
+            }
+        };
+
+        let mut msgs = vec![ServerCmd::Time {
+            time: duration_to_f32(level.time),
+        }];
+
+        for (id, value) in level.lightstyle_values() {
+            msgs.push(ServerCmd::LightStyle { id, value });
+        }
+
+        for (player_slot, player_ent) in self.active_players() {
+            let (name, frags) = level.player_score(player_ent)?;
+            msgs.push(ServerCmd::UpdateName {
+                player_id: player_slot as u8,
+                new_name: name,
+            });
+            msgs.push(ServerCmd::UpdateFrags {
+                player_id: player_slot as u8,
+                new_frags: frags as i16,
+            });
+        }
+
+        msgs.push(ServerCmd::SetView {
+            ent_id: ent_id.0 as i16,
+        });
+        msgs.push(ServerCmd::SetAngle {
+            angles: level.player_angles(ent_id)?,
+        });
+        msgs.push(ServerCmd::PlayerData(level.player_data(ent_id)?));
+        msgs.push(ServerCmd::SignOnStage {
+            stage: SignOnStage::Begin,
+        });
+
+        Ok(msgs)
+    }
+
+    /// Returns the time and player status sent to the client in `slot` each
+    /// frame.
+    pub fn client_data(&mut self, slot: usize) -> Result<Vec<ServerCmd>, ProgsError> {
+        let ent_id = match self.client_entity(slot) {
+            Some(e) => e,
+            None => return Ok(Vec::new()),
+        };
+
+        let level = self.level_mut();
+        let mut msgs = vec![ServerCmd::Time {
+            time: duration_to_f32(level.time),
+        }];
+        if let Some(angles) = level.take_fix_angle(ent_id)? {
+            msgs.push(ServerCmd::SetAngle { angles });
+        }
+        msgs.push(ServerCmd::PlayerData(level.player_data(ent_id)?));
+
+        Ok(msgs)
+    }
+
+    /// Returns updates for the entities clients can see.
+    pub fn entity_updates(&self) -> Result<Vec<EntityCandidate>, ProgsError> {
+        self.level().entity_updates()
+    }
+
+    /// Takes the reliable messages QuakeC has sent since the last call.
+    pub fn take_messages(&mut self) -> Vec<(Recipient, ServerCmd)> {
+        self.level_mut().take_messages()
+    }
+
+    /// Takes the unreliable messages for every client since the last call.
+    pub fn take_datagram(&mut self) -> Vec<u8> {
+        self.level_mut().take_datagram()
+    }
+
     pub fn precache_sound(&mut self, name_id: StringId) {
         if let SessionState::Loading(ref mut loading) = self.state {
             loading.precache_sound(name_id);
@@ -293,6 +752,569 @@ impl Session {
             SessionState::Active(ref active) => Some(active.level.time),
         }
     }
+
+    /// Reload `progs.dat` into the running level.
+    ///
+    /// Entity fields and saved globals keep their values if the new program
+    /// declares them with the same name and type.
+    pub fn reload_progs(&mut self) -> Result<ReloadStats, ProgsError> {
+        let file =
+            self.level().vfs.open(PROGS_PATH).map_err(|e| {
+                ProgsError::with_msg(format!("Couldn't open {}: {}", PROGS_PATH, e))
+            })?;
+        let progs = progs::load(file)?;
+        self.level_mut().reload_progs(progs)
+    }
+
+    /// Reload `progs.dat` if `developer` is set and the file has changed.
+    ///
+    /// This should be called once per frame, outside of QuakeC execution.
+    pub fn watch_progs(&mut self) -> Result<Option<ReloadStats>, ProgsError> {
+        let developer = self
+            .level()
+            .cvars
+            .borrow()
+            .get_value("developer")
+            .unwrap_or(0.0);
+        if developer == 0.0 {
+            self.progs_watcher = None;
+            return Ok(None);
+        }
+
+        let vfs = self.level().vfs.clone();
+        let data = match self.progs_watcher {
+            Some(ref mut watcher) => watcher.poll(&vfs, Instant::now()),
+
+            // start from the current version
+            None => {
+                self.progs_watcher = Some(ProgsWatcher::new(&vfs));
+                None
+            }
+        };
+
+        match data {
+            Some(data) => {
+                let progs = progs::load(Cursor::new(data))?;
+                self.level_mut().reload_progs(progs).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Pause QuakeC when the function `name` is entered.
+    pub fn add_breakpoint(&mut self, name: &str) -> Result<(), ProgsError> {
+        let level = self.level_mut();
+        level.cx.find_function_by_name(name)?;
+        level.debugger.add_breakpoint(name);
+        Ok(())
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.level().debugger
+    }
+
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.level_mut().debugger
+    }
+
+    /// Continue QuakeC paused in the debugger, for one statement if `step` is set.
+    pub fn resume_program(&mut self, step: bool) -> Result<(), ProgsError> {
+        self.level_mut().resume_program(step)
+    }
+
+    /// Returns the QuakeC call stack.
+    pub fn program_stack_trace(&self) -> StackTrace {
+        self.level().cx.stack_trace()
+    }
+
+    /// Returns the match being played, if `match_mode` is set.
+    pub fn current_match(&self) -> Option<&Match> {
+        self.match_state.as_ref()
+    }
+
+    // the entity controlled by each active client who isn't spectating, by slot
+    fn active_players(&self) -> Vec<(usize, EntityId)> {
+        self.persist
+            .client_slots
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, client)| match client {
+                Some(ClientState::Active(active)) if !active.spectator => {
+                    Some((slot, active.entity_id))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the name and score of each active client.
+    pub fn player_scores(&self) -> Result<Vec<PlayerScore>, ProgsError> {
+        let level = self.level();
+        self.active_players()
+            .into_iter()
+            .map(|(slot, ent_id)| {
+                let (name, frags) = level.player_score(ent_id)?;
+                let deaths = self.deaths.get(&slot).map(|d| d.count).unwrap_or(0);
+                Ok(PlayerScore {
+                    slot,
+                    name,
+                    frags,
+                    deaths,
+                })
+            })
+            .collect()
+    }
+
+    // count the players who died since the last frame
+    fn count_deaths(&mut self) -> Result<(), ProgsError> {
+        let players = self.active_players();
+        self.deaths
+            .retain(|slot, _| players.iter().any(|(s, _)| s == slot));
+
+        for (slot, ent_id) in players {
+            let dead = self.level().is_dead(ent_id)?;
+            let deaths = self.deaths.entry(slot).or_default();
+            if dead && !deaths.dead {
+                deaths.count += 1;
+            }
+            deaths.dead = dead;
+        }
+
+        Ok(())
+    }
+
+    /// Check a command from the client in `slot` for cheating and record it
+    /// for idle detection.
+    ///
+    /// Returns `false` if the command should be dropped.
+    pub fn client_cmd(&mut self, slot: usize, cmd: &ClientCmd) -> bool {
+        self.record(|r| r.client_cmd(slot as u8, cmd));
+
+        let time = self.time().unwrap_or_else(Duration::zero);
+        let (mode, max_speed) = {
+            let cvars = self.level().cvars.borrow();
+            (
+                AntiCheatMode::from_cvars(&cvars),
+                cvars.get_value("sv_maxspeed").unwrap_or(320.0),
+            )
+        };
+
+        if !self.anticheat.check_cmd(slot, cmd, time, mode, max_speed) {
+            return false;
+        }
+
+        self.idle.client_cmd(slot, cmd, time);
+        true
+    }
+
+    /// The seed of the level's random number generator.
+    pub fn seed(&self) -> u64 {
+        self.level().seed()
+    }
+
+    /// Record the start of a server frame of `frame_time`.
+    pub fn record_frame(&mut self, frame_time: Duration) {
+        self.record(|r| r.frame(frame_time));
+    }
+
+    /// Record a client connecting in `slot`.
+    pub fn record_connect(&mut self, slot: usize) {
+        self.record(|r| r.connect(slot as u8));
+    }
+
+    /// Record the client in `slot` disconnecting.
+    pub fn record_disconnect(&mut self, slot: usize) {
+        self.record(|r| r.disconnect(slot as u8));
+    }
+
+    /// Flush and close the session recording, if there is one.
+    pub fn stop_recording(&mut self) {
+        if let Some(mut recorder) = self.recorder.take() {
+            if let Err(e) = recorder.flush() {
+                warn!("Couldn't finish session recording: {:?}", e);
+            }
+        }
+    }
+
+    // a failed write stops the recording rather than the server
+    fn record<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut SessionRecorder<BufWriter<File>>) -> Result<(), NetError>,
+    {
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = f(recorder) {
+                warn!("Session recording stopped: {:?}", e);
+                self.recorder = None;
+            }
+        }
+    }
+
+    /// Returns the bandwidth limit of the client in `slot`.
+    ///
+    /// Clients that haven't sent a `rate` command get the default rate, capped
+    /// at `sv_maxrate`.
+    pub fn client_rate_mut(&mut self, slot: usize) -> &mut ClientRate {
+        let max_rate = rate::max_rate(&self.level().cvars.borrow());
+        self.rates
+            .entry(slot)
+            .or_insert_with(|| ClientRate::new(max_rate))
+    }
+
+    /// Handle a `rate` command from the client in `slot`.
+    ///
+    /// Returns the message to print to the client, or `None` if `cmd` isn't a
+    /// rate command.
+    pub fn rate_command(&mut self, slot: usize, cmd: &str) -> Option<String> {
+        let max_rate = rate::max_rate(&self.level().cvars.borrow());
+        self.client_rate_mut(slot).handle_command(cmd, max_rate)
+    }
+
+    /// Record the entity counters of the frame just sent to the client in
+    /// `slot`.
+    pub fn record_net_frame(&mut self, slot: usize, counters: FrameCounters) {
+        self.netstats.record(slot, counters);
+    }
+
+    /// Format every client's entity and bandwidth counters for `netstats`.
+    pub fn netstats_report(&self) -> String {
+        let level = self.level();
+        self.netstats
+            .report(|slot| match self.persist.client(slot) {
+                Some(ClientState::Active(active)) => level
+                    .player_score(active.entity_id)
+                    .map(|(name, _)| name)
+                    .unwrap_or_default(),
+                _ => "(connecting)".to_owned(),
+            })
+    }
+
+    /// Warn, move to spectator or drop players who have been idle for
+    /// `sv_idletime` seconds.
+    ///
+    /// Kicked clients are disconnected from the level and their slots are
+    /// freed. The returned events' messages should be sent to the affected
+    /// players, and kicked clients' connections closed.
+    pub fn update_idle(&mut self) -> Result<Vec<IdleEvent>, ProgsError> {
+        let time = match self.time() {
+            Some(t) => t,
+            None => return Ok(Vec::new()),
+        };
+
+        let players = self.active_players();
+        let slots: Vec<_> = players.iter().map(|(slot, _)| *slot).collect();
+        let vars = IdleVars::from_cvars(&self.level().cvars.borrow());
+        let events = self.idle.update(time, &vars, &slots);
+
+        for event in events.iter() {
+            match *event {
+                IdleEvent::Spectate { slot } => {
+                    if let Some(Some(ClientState::Active(ref mut active))) =
+                        self.persist.client_slots.slots.get_mut(slot)
+                    {
+                        active.spectator = true;
+                        let ent_id = active.entity_id;
+                        self.level_mut().make_spectator(ent_id)?;
+                    }
+                }
+
+                IdleEvent::Kick { slot } => self.drop_client(slot)?,
+
+                IdleEvent::Warning { .. } => (),
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Handle a `ready` or `notready` command from the client in `slot`.
+    ///
+    /// Returns `None` if the server isn't in match mode or `cmd` isn't a match
+    /// command.
+    pub fn match_command(&mut self, slot: usize, cmd: &str) -> Option<Vec<MatchEvent>> {
+        let time = self.time().unwrap_or_else(Duration::zero);
+        self.match_state.as_mut()?.handle_command(slot, cmd, time)
+    }
+
+    /// Advance the match clock and enforce the match limits.
+    ///
+    /// When the match starts, every player's score is reset and they are
+    /// respawned. Events from players joining or leaving since the last call
+    /// are returned first. The returned events should be announced to the
+    /// players. On `MatchEvent::End` the match report is exported, and the
+    /// next `update_rotation` starts the intermission.
+    pub fn update_match(&mut self) -> Result<Vec<MatchEvent>, ProgsError> {
+        let time = match self.time() {
+            Some(t) => t,
+            None => return Ok(Vec::new()),
+        };
+
+        self.count_deaths()?;
+        let scores = self.player_scores()?;
+        let mut events = std::mem::replace(&mut self.match_events, Vec::new());
+        match self.match_state {
+            Some(ref mut m) => {
+                m.set_vars(MatchVars::from_cvars(&self.level().cvars.borrow()));
+                events.extend(m.update(time, &scores));
+            }
+            None => return Ok(Vec::new()),
+        }
+
+        for event in events.iter() {
+            match event {
+                MatchEvent::Start => {
+                    self.deaths.clear();
+                    for (_, ent_id) in self.active_players() {
+                        self.level_mut().respawn_player(ent_id)?;
+                    }
+                }
+
+                MatchEvent::End(result) => self.export_result(result),
+
+                _ => (),
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Returns the message of the day to send to a client that has just
+    /// signed on, if the server has one.
+    pub fn motd(&self) -> Option<ServerCmd> {
+        let level = self.level();
+        let motd = Motd::from_cvars(&level.cvars.borrow(), &level.vfs)?;
+        Some(motd.message())
+    }
+
+    /// Returns `sv_advert` if it's due to be broadcast.
+    pub fn update_adverts(&mut self) -> Option<ServerCmd> {
+        let time = self.time()?;
+        let vars = AdvertVars::from_cvars(&self.level().cvars.borrow());
+        self.adverts.update(time, &vars)
+    }
+
+    /// Returns the map that follows this one.
+    pub fn next_map(&self) -> String {
+        self.rotation.borrow().next_map(&self.level().map_name())
+    }
+
+    /// Replace the next map in the rotation with `map`, once.
+    pub fn set_next_map(&mut self, map: &str) -> Result<(), VfsError> {
+        let path = format!("maps/{}.bsp", map);
+        self.level().vfs.open(&path)?;
+        self.rotation.borrow_mut().set_next_map(map);
+        Ok(())
+    }
+
+    /// End the level when its limits are reached, and move on to the next map
+    /// in the rotation after the intermission.
+    ///
+    /// The level ends when the match finishes in match mode, or otherwise when
+    /// a player reaches `fraglimit` or `timelimit` runs out, in which case the
+    /// level's report is exported when the intermission starts. On
+    /// `RotationEvent::ChangeLevel` the host should load the new map, whose
+    /// limits have already been applied.
+    pub fn update_rotation(&mut self) -> Result<Option<RotationEvent>, ProgsError> {
+        let time = match self.time() {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+
+        let map = self.level().map_name();
+        let cvars = self.level().cvars.clone();
+        match self.level_end {
+            LevelEnd::Playing => {
+                if !self.level_ended(time)? {
+                    return Ok(None);
+                }
+
+                self.level_end = LevelEnd::Intermission { start: time };
+
+                // match mode exported its report when the match ended
+                if self.match_state.is_none() {
+                    let result = self.level_result(time)?;
+                    self.export_result(&result);
+                }
+
+                Ok(Some(RotationEvent::Intermission {
+                    next: self.next_map(),
+                }))
+            }
+
+            LevelEnd::Intermission { start } => {
+                if time - start < rotation::intermission_time(&cvars.borrow()) {
+                    return Ok(None);
+                }
+
+                self.level_end = LevelEnd::Changing;
+                let next = self.rotation.borrow_mut().advance(&map, &cvars.borrow());
+                Ok(Some(RotationEvent::ChangeLevel { map: next }))
+            }
+
+            LevelEnd::Changing => Ok(None),
+        }
+    }
+
+    // the result of a level that ended without match mode
+    fn level_result(&self, time: Duration) -> Result<MatchResult, ProgsError> {
+        let vars = MatchVars::from_cvars(&self.level().cvars.borrow());
+        let scores = self.player_scores()?;
+        let reason = match vars.fraglimit {
+            Some(limit) if scores.iter().any(|s| s.frags >= limit) => MatchEnd::FragLimit,
+            _ => MatchEnd::TimeLimit,
+        };
+
+        Ok(MatchResult::new(reason, time, 0, &scores))
+    }
+
+    // log the result and export its report
+    fn export_result(&self, result: &MatchResult) {
+        info!("{}", result.report());
+        let level = self.level();
+        let report = MatchReport::new(level.map_name(), result);
+        stats::export(&level.cvars.borrow(), &report);
+    }
+
+    fn level_ended(&self, time: Duration) -> Result<bool, ProgsError> {
+        if let Some(ref m) = self.match_state {
+            return Ok(m.phase() == MatchPhase::Finished);
+        }
+
+        let vars = MatchVars::from_cvars(&self.level().cvars.borrow());
+        if let Some(limit) = vars.timelimit {
+            if time >= limit {
+                return Ok(true);
+            }
+        }
+
+        Ok(match vars.fraglimit {
+            Some(limit) => self.player_scores()?.iter().any(|s| s.frags >= limit),
+            None => false,
+        })
+    }
+}
+
+/// Implements the `nextmap` command.
+///
+/// With no arguments, prints the next map.
+pub fn cmd_nextmap(session: Rc<RefCell<Session>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| match args {
+        [] => format!("Next map is {}", session.borrow().next_map()),
+        [map] => match session.borrow_mut().set_next_map(map) {
+            Ok(()) => format!("Next map set to {}", map),
+            Err(e) => format!("nextmap: {}", e),
+        },
+        _ => "usage: nextmap [map]".to_owned(),
+    })
+}
+
+/// Implements the `rotation` command.
+pub fn cmd_rotation(session: Rc<RefCell<Session>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| {
+        let session = session.borrow();
+        let map = session.level().map_name();
+        let rotation = session.rotation.borrow();
+        rotation.describe(&map)
+    })
+}
+
+/// Implements the `progs_reload` command.
+pub fn cmd_progs_reload(session: Rc<RefCell<Session>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| match session.borrow_mut().reload_progs() {
+        Ok(stats) => format!(
+            "Reloaded {}: kept {} fields and {} globals, dropped {} fields",
+            PROGS_PATH, stats.fields_kept, stats.globals_kept, stats.fields_dropped
+        ),
+        Err(e) => format!("progs_reload: {}", e),
+    })
+}
+
+/// Implements the `qc_break` command.
+///
+/// With no arguments, lists the breakpoints.
+pub fn cmd_qc_break(session: Rc<RefCell<Session>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| match args.len() {
+        0 => {
+            let session = session.borrow();
+            let names: Vec<&str> = session.debugger().breakpoints().collect();
+            match names.len() {
+                0 => "No breakpoints".to_owned(),
+                _ => format!("Breakpoints: {}", names.join(", ")),
+            }
+        }
+        1 => match session.borrow_mut().add_breakpoint(args[0]) {
+            Ok(()) => format!("Breakpoint set on {}", args[0]),
+            Err(e) => format!("qc_break: {}", e),
+        },
+        _ => "usage: qc_break [function]".to_owned(),
+    })
+}
+
+/// Implements the `qc_unbreak` command.
+pub fn cmd_qc_unbreak(session: Rc<RefCell<Session>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |args| {
+        if args.len() != 1 {
+            return "usage: qc_unbreak <function>".to_owned();
+        }
+
+        let mut session = session.borrow_mut();
+        match session.debugger_mut().remove_breakpoint(args[0]) {
+            true => format!("Breakpoint on {} removed", args[0]),
+            false => format!("No breakpoint on {}", args[0]),
+        }
+    })
+}
+
+/// Implements the `qc_step` command.
+///
+/// If QuakeC isn't paused, it pauses at the next statement it runs.
+pub fn cmd_qc_step(session: Rc<RefCell<Session>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| {
+        let mut session = session.borrow_mut();
+        if !session.debugger().is_paused() {
+            session.debugger_mut().request_step();
+            return "QuakeC will pause at the next statement".to_owned();
+        }
+
+        if let Err(e) = session.resume_program(true) {
+            return format!("qc_step: {}", e);
+        }
+
+        match session.debugger().is_paused() {
+            true => format!("Paused at\n{}", session.program_stack_trace()),
+            false => "QuakeC finished".to_owned(),
+        }
+    })
+}
+
+/// Implements the `qc_continue` command.
+pub fn cmd_qc_continue(session: Rc<RefCell<Session>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| match session.borrow_mut().resume_program(false) {
+        Ok(()) => "Continuing".to_owned(),
+        Err(e) => format!("qc_continue: {}", e),
+    })
+}
+
+/// Implements the `qc_stack` command.
+pub fn cmd_qc_stack(session: Rc<RefCell<Session>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| {
+        let trace = session.borrow().program_stack_trace();
+        match trace.frames.len() {
+            0 => "QuakeC isn't running".to_owned(),
+            _ => trace.to_string(),
+        }
+    })
+}
+
+/// Implements the `netstats` command.
+pub fn cmd_netstats(session: Rc<RefCell<Session>>) -> Box<dyn Fn(&[&str]) -> String> {
+    Box::new(move |_| session.borrow().netstats_report())
+}
+
+// QuakeC that calls a missing builtin stops with an error rather than taking
+// the server down
+fn unimplemented_builtin(name: &str) -> ProgsError {
+    ProgsError::with_msg(format!("Built-in function {} isn't implemented", name))
 }
 
 /// Server-side level state.
@@ -306,122 +1328,557 @@ pub struct LevelState {
     model_precache: Precache,
     lightstyles: [StringId; MAX_LIGHTSTYLES],
 
-    /// Amount of time the current level has been active.
-    time: Duration,
+    /// Drives QuakeC's `random()`. Seeded from `sv_seed` so sessions can be
+    /// replayed.
+    rng: SmallRng,
+    seed: u64,
+
+    /// Amount of time the current level has been active.
+    time: Duration,
+
+    /// QuakeC bytecode execution context.
+    ///
+    /// This includes the program counter, call stack, and local variables.
+    cx: ExecutionContext,
+
+    /// Global values for QuakeC bytecode.
+    globals: Globals,
+
+    /// Breakpoints and stepping state for the QuakeC VM.
+    debugger: Debugger,
+
+    /// Files opened by QuakeC.
+    files: QcFiles,
+
+    /// The state of the game world.
+    ///
+    /// This contains the entities and world geometry.
+    world: World,
+
+    /// The number of entities after the world entity reserved for clients.
+    max_clients: usize,
+
+    /// Entity states when the level finished loading. Updates are sent as
+    /// differences from these.
+    baselines: BTreeMap<usize, EntityState>,
+
+    /// The movement each client last asked for, by entity.
+    moves: HashMap<EntityId, ClientMove>,
+
+    /// Reliable messages from QuakeC waiting to be sent.
+    messages: Vec<(Recipient, ServerCmd)>,
+
+    /// Unreliable messages for every client, such as sounds.
+    datagram: ArrayVec<u8, MAX_DATAGRAM>,
+}
+
+impl LevelState {
+    pub fn new(
+        max_clients: usize,
+        vfs: Rc<Vfs>,
+        cvars: Rc<RefCell<CvarRegistry>>,
+        progs: LoadProgs,
+        models: Vec<Model>,
+        entmap: String,
+    ) -> LevelState {
+        let LoadProgs {
+            mut cx,
+            globals,
+            entity_def,
+            string_table,
+        } = progs;
+        cx.set_line_numbers(debug::load_line_numbers(&vfs, cx.statement_count()));
+
+        let mut sound_precache = Precache::new();
+        sound_precache.precache("");
+
+        let mut model_precache = Precache::new();
+        model_precache.precache("");
+
+        for model in models.iter() {
+            let model_name = (*string_table).borrow_mut().find_or_insert(model.name());
+            model_precache.precache(string_table.borrow().get(model_name).unwrap());
+        }
+
+        let world = World::create(
+            models,
+            entity_def.clone(),
+            string_table.clone(),
+            max_clients,
+        )
+        .unwrap();
+        let entity_list = parse::entities(&entmap).unwrap();
+
+        let files = QcFiles::new(vfs.game_dir());
+        let seed = record::seed(&cvars.borrow());
+
+        let mut level = LevelState {
+            vfs,
+            cvars,
+            string_table,
+            sound_precache,
+            model_precache,
+            lightstyles: [StringId(0); MAX_LIGHTSTYLES],
+            rng: SmallRng::seed_from_u64(seed),
+            seed,
+            time: Duration::zero(),
+
+            cx,
+            globals,
+            debugger: Debugger::new(),
+            files,
+            world,
+
+            max_clients,
+            baselines: BTreeMap::new(),
+            moves: HashMap::new(),
+            messages: Vec::new(),
+            datagram: ArrayVec::new(),
+        };
+
+        for entity in entity_list {
+            level.spawn_entity_from_map(entity).unwrap();
+        }
+
+        level
+    }
+
+    #[inline]
+    pub fn precache_sound(&mut self, name_id: StringId) {
+        let name = Ref::map(self.string_table.borrow(), |this| {
+            this.get(name_id).unwrap()
+        });
+        self.sound_precache.precache(&*name);
+    }
+
+    #[inline]
+    pub fn precache_model(&mut self, name_id: StringId) {
+        let name = Ref::map(self.string_table.borrow(), |this| {
+            this.get(name_id).unwrap()
+        });
+        self.model_precache.precache(&*name)
+    }
+
+    #[inline]
+    pub fn sound_id(&self, name_id: StringId) -> Option<usize> {
+        let name = Ref::map(self.string_table.borrow(), |this| {
+            this.get(name_id).unwrap()
+        });
+        self.sound_precache.find(&*name)
+    }
+
+    #[inline]
+    pub fn model_id(&self, name_id: StringId) -> Option<usize> {
+        let name = Ref::map(self.string_table.borrow(), |this| {
+            this.get(name_id).unwrap()
+        });
+        self.model_precache.find(&*name)
+    }
+
+    #[inline]
+    pub fn set_lightstyle(&mut self, index: usize, val: StringId) {
+        self.lightstyles[index] = val;
+    }
+
+    /// Returns the names in the model precache, without the null model.
+    pub fn model_names(&self) -> Vec<String> {
+        self.model_precache
+            .iter()
+            .skip(1)
+            .map(|s| s.to_owned())
+            .collect()
+    }
+
+    /// Returns the names in the sound precache, without the null sound.
+    pub fn sound_names(&self) -> Vec<String> {
+        self.sound_precache
+            .iter()
+            .skip(1)
+            .map(|s| s.to_owned())
+            .collect()
+    }
+
+    /// Returns the value of every lightstyle that has been set.
+    pub fn lightstyle_values(&self) -> Vec<(u8, String)> {
+        let strs = self.string_table.borrow();
+        self.lightstyles
+            .iter()
+            .enumerate()
+            .filter(|(_, s_id)| s_id.0 != 0)
+            .map(|(id, s_id)| (id as u8, strs.get(*s_id).unwrap_or("").to_owned()))
+            .collect()
+    }
+
+    /// Returns the state of an entity as it is sent to clients.
+    pub fn entity_state(&self, ent_id: EntityId) -> Result<EntityState, ProgsError> {
+        let ent = self.world.try_entity(ent_id)?;
+        let angles = ent.load(FieldAddrVector::Angles)?;
+
+        Ok(EntityState {
+            origin: ent.origin()?,
+            angles: Vector3::new(Deg(angles[0]), Deg(angles[1]), Deg(angles[2])),
+            model_id: ent.load(FieldAddrFloat::ModelIndex)? as usize,
+            frame_id: ent.load(FieldAddrFloat::FrameId)? as usize,
+            colormap: ent.load(FieldAddrFloat::Colormap)? as u8,
+            skin_id: ent.load(FieldAddrFloat::SkinId)? as usize,
+            effects: EntityEffects::from_bits_truncate(ent.load(FieldAddrFloat::Effects)? as u8),
+        })
+    }
+
+    /// Save the state of every entity as its baseline.
+    ///
+    /// Client entities get a baseline even before a player joins, so that
+    /// their colormaps are known.
+    pub fn create_baselines(&mut self) -> Result<(), ProgsError> {
+        let player_model = self.model_precache.find("progs/player.mdl").unwrap_or(0);
+
+        let mut ent_ids = Vec::new();
+        self.world.list_entities(&mut ent_ids);
+
+        self.baselines.clear();
+        for ent_id in ent_ids.into_iter().skip(1) {
+            let baseline = if ent_id.0 <= self.max_clients {
+                EntityState {
+                    model_id: player_model,
+                    colormap: ent_id.0 as u8,
+                    ..EntityState::uninitialized()
+                }
+            } else {
+                let state = self.entity_state(ent_id)?;
+                if state.model_id == 0 {
+                    continue;
+                }
+                state
+            };
+
+            self.baselines.insert(ent_id.0, baseline);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the baseline of each entity, by entity ID.
+    pub fn baselines(&self) -> impl Iterator<Item = (usize, &EntityState)> {
+        self.baselines.iter().map(|(id, b)| (*id, b))
+    }
+
+    /// Returns updates for the entities clients can see, along with their
+    /// origins.
+    pub fn entity_updates(&self) -> Result<Vec<EntityCandidate>, ProgsError> {
+        let mut ent_ids = Vec::new();
+        self.world.list_entities(&mut ent_ids);
+
+        let uninitialized = EntityState::uninitialized();
+        let mut updates = Vec::new();
+        for ent_id in ent_ids.into_iter().skip(1) {
+            let state = self.entity_state(ent_id)?;
+            if state.model_id == 0 {
+                continue;
+            }
+
+            let baseline = self.baselines.get(&ent_id.0).unwrap_or(&uninitialized);
+            updates.push(EntityCandidate {
+                update: EntityUpdate::between(ent_id.0 as u16, baseline, &state),
+                origin: state.origin,
+            });
+        }
+
+        Ok(updates)
+    }
+
+    /// Returns the status of a player for their own client.
+    pub fn player_data(&self, ent_id: EntityId) -> Result<PlayerData, ProgsError> {
+        let ent = self.world.try_entity(ent_id)?;
+
+        fn nonzero(val: f32) -> Option<f32> {
+            match val {
+                v if v == 0.0 => None,
+                v => Some(v),
+            }
+        }
+
+        let view_height = ent.load(FieldAddrFloat::ViewOffsetZ)?;
+        let punch: Vector3<f32> = ent.load(FieldAddrVector::PunchAngle)?.into();
+        let velocity = ent.velocity()?;
+        let weapon_model = ent.load(FieldAddrStringId::WeaponModelName)?;
+        let weapon = self
+            .string_table
+            .borrow()
+            .get(weapon_model)
+            .and_then(|name| self.model_precache.find(name))
+            .filter(|id| *id != 0);
+
+        Ok(PlayerData {
+            view_height: match view_height {
+                h if h == DEFAULT_VIEW_HEIGHT => None,
+                h => Some(h),
+            },
+            ideal_pitch: nonzero(ent.load(FieldAddrFloat::IdealPitch)?).map(Deg),
+            punch_pitch: nonzero(punch.x).map(Deg),
+            velocity_x: nonzero(velocity.x),
+            punch_yaw: nonzero(punch.y).map(Deg),
+            velocity_y: nonzero(velocity.y),
+            punch_roll: nonzero(punch.z).map(Deg),
+            velocity_z: nonzero(velocity.z),
+            items: ItemFlags::from_bits_truncate(ent.load(FieldAddrFloat::Items)? as u32),
+            on_ground: ent.flags()?.contains(EntityFlags::ON_GROUND),
+            in_water: ent.load(FieldAddrFloat::WaterLevel)? >= 2.0,
+            weapon_frame: nonzero(ent.load(FieldAddrFloat::WeaponFrame)?).map(|f| f as u16),
+            armor: nonzero(ent.load(FieldAddrFloat::ArmorValue)?).map(|a| a as u16),
+            weapon: weapon.map(|w| w as u16),
+            health: ent.load(FieldAddrFloat::Health)? as i16,
+            ammo: ent.load(FieldAddrFloat::CurrentAmmo)? as u16,
+            ammo_shells: ent.load(FieldAddrFloat::AmmoShells)? as u16,
+            ammo_nails: ent.load(FieldAddrFloat::AmmoNails)? as u16,
+            ammo_rockets: ent.load(FieldAddrFloat::AmmoRockets)? as u16,
+            ammo_cells: ent.load(FieldAddrFloat::AmmoCells)? as u16,
+            active_weapon: ent.load(FieldAddrFloat::Weapon)? as u8,
+        })
+    }
+
+    /// Returns the view angles of a player entity.
+    pub fn player_angles(&self, ent_id: EntityId) -> Result<Vector3<Deg<f32>>, ProgsError> {
+        let angles = self
+            .world
+            .try_entity(ent_id)?
+            .load(FieldAddrVector::Angles)?;
+        Ok(Vector3::new(Deg(angles[0]), Deg(angles[1]), Deg(angles[2])))
+    }
+
+    /// If QuakeC has turned a player to face a new direction, returns the
+    /// angles to send to their client.
+    pub fn take_fix_angle(
+        &mut self,
+        ent_id: EntityId,
+    ) -> Result<Option<Vector3<Deg<f32>>>, ProgsError> {
+        let ent = self.world.entity_mut(ent_id)?;
+        if ent.load(FieldAddrFloat::FixAngle)? == 0.0 {
+            return Ok(None);
+        }
+
+        ent.store(FieldAddrFloat::FixAngle, 0.0)?;
+        self.player_angles(ent_id).map(Some)
+    }
+
+    /// Takes the reliable messages QuakeC has sent since the last call.
+    pub fn take_messages(&mut self) -> Vec<(Recipient, ServerCmd)> {
+        std::mem::replace(&mut self.messages, Vec::new())
+    }
+
+    /// Takes the unreliable messages for every client since the last call.
+    pub fn take_datagram(&mut self) -> Vec<u8> {
+        let datagram = self.datagram.to_vec();
+        self.datagram.clear();
+        datagram
+    }
+
+    // unreliable messages that don't fit are dropped
+    fn write_datagram(&mut self, cmd: ServerCmd) {
+        let mut msg = Vec::new();
+        if let Err(e) = cmd.serialize(&mut msg) {
+            warn!("Couldn't write {:?}: {}", cmd, e);
+            return;
+        }
+
+        if self.datagram.try_extend_from_slice(&msg).is_err() {
+            debug!("Datagram overflow, dropped {:?}", cmd);
+        }
+    }
+
+    /// Put a new player into the game.
+    ///
+    /// The player's entity is reset before QuakeC sets it up, as
+    /// `ClientConnect` and `PutClientInServer` expect.
+    pub fn spawn_player(
+        &mut self,
+        ent_id: EntityId,
+        name: &str,
+        colors: PlayerColor,
+    ) -> Result<(), ProgsError> {
+        self.world.clear_entity(ent_id)?;
+        self.moves.remove(&ent_id);
+
+        let name_id = self.string_table.borrow_mut().find_or_insert(name);
+        let ent = self.world.entity_mut(ent_id)?;
+        ent.store(FieldAddrStringId::NetName, name_id)?;
+        ent.store(FieldAddrFloat::Colormap, ent_id.0 as f32)?;
+        ent.store(FieldAddrFloat::Team, (colors.bottom() + 1) as f32)?;
+
+        self.globals
+            .store(GlobalAddrFloat::Time, duration_to_f32(self.time))?;
+        self.globals.store(GlobalAddrEntity::Self_, ent_id)?;
+        for addr in [
+            GlobalAddrFunction::SetNewArgs as i16,
+            GlobalAddrFunction::ClientConnect as i16,
+            GlobalAddrFunction::PutClientInServer as i16,
+        ]
+        .iter()
+        {
+            let f = self.globals.function_id(*addr)?;
+            self.execute_program(f)?;
+        }
+
+        Ok(())
+    }
+
+    /// Rename a player.
+    pub fn set_player_name(&mut self, ent_id: EntityId, name: &str) -> Result<(), ProgsError> {
+        let name_id = self.string_table.borrow_mut().find_or_insert(name);
+        self.world
+            .entity_mut(ent_id)?
+            .store(FieldAddrStringId::NetName, name_id)?;
+
+        Ok(())
+    }
+
+    /// Let QuakeC kill a player who asked to respawn.
+    pub fn kill_player(&mut self, ent_id: EntityId) -> Result<(), ProgsError> {
+        let client_kill = self
+            .globals
+            .function_id(GlobalAddrFunction::ClientKill as i16)?;
+        self.globals
+            .store(GlobalAddrFloat::Time, duration_to_f32(self.time))?;
+        self.globals.store(GlobalAddrEntity::Self_, ent_id)?;
+        self.execute_program(client_kill)?;
+
+        Ok(())
+    }
 
-    /// QuakeC bytecode execution context.
+    /// Apply a move command to a player's entity.
     ///
-    /// This includes the program counter, call stack, and local variables.
-    cx: ExecutionContext,
+    /// The view angles, buttons and impulse are stored for QuakeC, and the
+    /// movement is applied by `physics_player`.
+    pub fn client_move(
+        &mut self,
+        ent_id: EntityId,
+        angles: Vector3<Deg<f32>>,
+        player_move: ClientMove,
+        buttons: ButtonFlags,
+        impulse: u8,
+    ) -> Result<(), ProgsError> {
+        let ent = self.world.entity_mut(ent_id)?;
+        ent.store(
+            FieldAddrVector::ViewAngle,
+            [angles.x.0, angles.y.0, angles.z.0],
+        )?;
+        if ent.load(FieldAddrFloat::FixAngle)? == 0.0 {
+            // the model only pitches a third as far as the view
+            ent.store(
+                FieldAddrVector::Angles,
+                [-angles.x.0 / 3.0, angles.y.0, angles.z.0],
+            )?;
+        }
+        ent.store(
+            FieldAddrFloat::Button0,
+            buttons.contains(ButtonFlags::ATTACK) as u32 as f32,
+        )?;
+        ent.store(
+            FieldAddrFloat::Button2,
+            buttons.contains(ButtonFlags::JUMP) as u32 as f32,
+        )?;
+        if impulse != 0 {
+            ent.store(FieldAddrFloat::Impulse, impulse as f32)?;
+        }
 
-    /// Global values for QuakeC bytecode.
-    globals: Globals,
+        self.moves.insert(ent_id, player_move);
+        Ok(())
+    }
 
-    /// The state of the game world.
+    /// Replace the running program with `progs`.
     ///
-    /// This contains the entities and world geometry.
-    world: World,
+    /// This may not be called while QuakeC is executing.
+    pub fn reload_progs(&mut self, progs: LoadProgs) -> Result<ReloadStats, ProgsError> {
+        if self.cx.call_stack_depth() != 0 {
+            return Err(ProgsError::with_msg(
+                "Can't reload progs while QuakeC is running",
+            ));
+        }
 
-    datagram: ArrayVec<u8, MAX_DATAGRAM>,
-}
+        let remap = Remap::new(
+            self.string_table.clone(),
+            &self.cx,
+            &self.globals,
+            self.world.type_def(),
+            &progs,
+        );
 
-impl LevelState {
-    pub fn new(
-        vfs: Rc<Vfs>,
-        cvars: Rc<RefCell<CvarRegistry>>,
-        progs: LoadProgs,
-        models: Vec<Model>,
-        entmap: String,
-    ) -> LevelState {
         let LoadProgs {
-            cx,
-            globals,
+            mut cx,
+            mut globals,
             entity_def,
             string_table,
         } = progs;
-
-        let mut sound_precache = Precache::new();
-        sound_precache.precache("");
-
-        let mut model_precache = Precache::new();
-        model_precache.precache("");
-
-        for model in models.iter() {
-            let model_name = (*string_table).borrow_mut().find_or_insert(model.name());
-            model_precache.precache(string_table.borrow().get(model_name).unwrap());
+        cx.set_line_numbers(debug::load_line_numbers(&self.vfs, cx.statement_count()));
+        remap.copy_globals(&self.globals, &mut globals)?;
+        self.world
+            .replace_type_def(entity_def, string_table.clone(), |old, new| {
+                remap.copy_entity(old, new)
+            })?;
+        for style in self.lightstyles.iter_mut() {
+            *style = remap.string(*style);
         }
 
-        let world = World::create(models, entity_def.clone(), string_table.clone()).unwrap();
-        let entity_list = parse::entities(&entmap).unwrap();
+        self.string_table = string_table;
+        self.cx = cx;
+        self.globals = globals;
 
-        let mut level = LevelState {
-            vfs,
-            cvars,
-            string_table,
-            sound_precache,
-            model_precache,
-            lightstyles: [StringId(0); MAX_LIGHTSTYLES],
-            time: Duration::zero(),
+        let stats = remap.stats();
+        debug!("Reloaded progs: {:?}", stats);
+        Ok(stats)
+    }
 
-            cx,
-            globals,
-            world,
+    /// Execute a QuakeC function in the VM.
+    ///
+    /// Returns `ProgsError::Paused` if the program pauses in the debugger, or
+    /// if it was already paused, in which case nothing is executed. The
+    /// caller should stop what it was doing rather than carry on as if the
+    /// function had returned.
+    pub fn execute_program(&mut self, f: FunctionId) -> Result<(), ProgsError> {
+        if self.debugger.is_paused() {
+            return Err(ProgsError::Paused);
+        }
 
-            datagram: ArrayVec::new(),
-        };
+        let exit_depth = self.cx.call_stack_depth();
 
-        for entity in entity_list {
-            level.spawn_entity_from_map(entity).unwrap();
-        }
+        self.cx.enter_function(&mut self.globals, f)?;
 
-        level
+        self.run_program(exit_depth)
     }
 
-    #[inline]
-    pub fn precache_sound(&mut self, name_id: StringId) {
-        let name = Ref::map(self.string_table.borrow(), |this| {
-            this.get(name_id).unwrap()
-        });
-        self.sound_precache.precache(&*name);
-    }
+    /// Continue a program that is paused in the debugger.
+    ///
+    /// If `step` is set, the program pauses again after one statement.
+    pub fn resume_program(&mut self, step: bool) -> Result<(), ProgsError> {
+        if !self.debugger.is_paused() {
+            return Err(ProgsError::with_msg("QuakeC isn't paused"));
+        }
 
-    #[inline]
-    pub fn precache_model(&mut self, name_id: StringId) {
-        let name = Ref::map(self.string_table.borrow(), |this| {
-            this.get(name_id).unwrap()
-        });
-        self.model_precache.precache(&*name)
+        self.debugger.resume(step);
+        match self.run_program(0) {
+            Err(ProgsError::Paused) => Ok(()),
+            r => r,
+        }
     }
 
-    #[inline]
-    pub fn sound_id(&self, name_id: StringId) -> Option<usize> {
-        let name = Ref::map(self.string_table.borrow(), |this| {
-            this.get(name_id).unwrap()
-        });
-        self.sound_precache.find(&*name)
-    }
+    // run until the call stack unwinds to `exit_depth`
+    fn run_program(&mut self, exit_depth: usize) -> Result<(), ProgsError> {
+        let result = self.run_statements(exit_depth);
 
-    #[inline]
-    pub fn model_id(&self, name_id: StringId) -> Option<usize> {
-        let name = Ref::map(self.string_table.borrow(), |this| {
-            this.get(name_id).unwrap()
-        });
-        self.model_precache.find(&*name)
-    }
+        // errors are reported by the outermost call, which still sees the whole stack. the
+        // program can't continue from there, so its stack is dropped.
+        if let Err(ref e) = result {
+            if exit_depth == 0 && !matches!(e, ProgsError::Paused) {
+                error!("QuakeC error: {}\n{}", e, self.cx.stack_trace());
+                self.cx.reset();
+            }
+        }
 
-    #[inline]
-    pub fn set_lightstyle(&mut self, index: usize, val: StringId) {
-        self.lightstyles[index] = val;
+        result
     }
 
-    /// Execute a QuakeC function in the VM.
-    pub fn execute_program(&mut self, f: FunctionId) -> Result<(), ProgsError> {
+    fn run_statements(&mut self, exit_depth: usize) -> Result<(), ProgsError> {
         let mut runaway = 100000;
 
-        let exit_depth = self.cx.call_stack_depth();
-
-        self.cx.enter_function(&mut self.globals, f)?;
-
         while self.cx.call_stack_depth() != exit_depth {
             runaway -= 1;
 
@@ -429,6 +1886,27 @@ impl LevelState {
                 panic!("runaway program");
             }
 
+            if self.debugger.is_armed() {
+                let function = self.cx.current_function_name();
+                if self
+                    .debugger
+                    .should_pause(&function, self.cx.at_function_entry())
+                {
+                    // builtins can't be suspended, so nested calls run on
+                    if exit_depth == 0 {
+                        info!("QuakeC paused in {}\n{}", function, self.cx.stack_trace());
+                        self.debugger.pause();
+                        return Err(ProgsError::Paused);
+                    }
+
+                    info!(
+                        "QuakeC breakpoint in {} can't pause inside a builtin\n{}",
+                        function,
+                        self.cx.stack_trace()
+                    );
+                }
+            }
+
             let statement = self.cx.load_statement();
             let op = statement.opcode;
             let a = statement.arg1;
@@ -443,6 +1921,17 @@ impl LevelState {
                 c
             );
 
+            if self.debugger.tracing() {
+                info!(
+                    "{}: {:?} {} {} {}",
+                    self.cx.current_function_name(),
+                    op,
+                    a,
+                    b,
+                    c
+                );
+            }
+
             use Opcode::*;
 
             // Y'all like jump tables?
@@ -475,7 +1964,7 @@ impl LevelState {
 
                 Call0 | Call1 | Call2 | Call3 | Call4 | Call5 | Call6 | Call7 | Call8 => {
                     // TODO: pass to equivalent of PF_VarString
-                    let _arg_count = op as usize - Opcode::Call0 as usize;
+                    let arg_count = op as usize - Opcode::Call0 as usize;
 
                     let f_to_call = self.globals.function_id(a)?;
                     if f_to_call.0 == 0 {
@@ -493,70 +1982,101 @@ impl LevelState {
                             SetOrigin => self.builtin_set_origin()?,
                             SetModel => self.builtin_set_model()?,
                             SetSize => self.builtin_set_size()?,
-                            Break => unimplemented!(),
-                            Random => self.globals.builtin_random()?,
-                            Sound => unimplemented!(),
-                            Normalize => unimplemented!(),
-                            Error => unimplemented!(),
-                            ObjError => unimplemented!(),
+                            Break => self.builtin_break()?,
+                            Random => self.globals.builtin_random(&mut self.rng)?,
+                            Sound => self.builtin_sound()?,
+                            Normalize => return Err(unimplemented_builtin(&name)),
+                            Error => return Err(unimplemented_builtin(&name)),
+                            ObjError => return Err(unimplemented_builtin(&name)),
                             VLen => self.globals.builtin_v_len()?,
                             VecToYaw => self.globals.builtin_vec_to_yaw()?,
                             Spawn => self.builtin_spawn()?,
                             Remove => self.builtin_remove()?,
-                            TraceLine => unimplemented!(),
-                            CheckClient => unimplemented!(),
-                            Find => unimplemented!(),
+                            TraceLine => self.builtin_trace_line()?,
+                            CheckClient => return Err(unimplemented_builtin(&name)),
+                            Find => self.builtin_find()?,
                             PrecacheSound => self.builtin_precache_sound()?,
                             PrecacheModel => self.builtin_precache_model()?,
-                            StuffCmd => unimplemented!(),
-                            FindRadius => unimplemented!(),
-                            BPrint => unimplemented!(),
-                            SPrint => unimplemented!(),
+                            StuffCmd => self.builtin_stuff_cmd()?,
+                            FindRadius => return Err(unimplemented_builtin(&name)),
+                            BPrint => self.builtin_b_print()?,
+                            SPrint => self.builtin_s_print()?,
                             DPrint => self.builtin_dprint()?,
-                            FToS => unimplemented!(),
-                            VToS => unimplemented!(),
-                            CoreDump => unimplemented!(),
-                            TraceOn => unimplemented!(),
-                            TraceOff => unimplemented!(),
-                            EPrint => unimplemented!(),
-                            WalkMove => unimplemented!(),
+                            FToS => self.builtin_f_to_s()?,
+                            VToS => self.builtin_v_to_s()?,
+                            CoreDump => return Err(unimplemented_builtin(&name)),
+                            TraceOn => self.debugger.set_trace(true),
+                            TraceOff => self.debugger.set_trace(false),
+                            EPrint => return Err(unimplemented_builtin(&name)),
+                            WalkMove => return Err(unimplemented_builtin(&name)),
 
                             DropToFloor => self.builtin_drop_to_floor()?,
                             LightStyle => self.builtin_light_style()?,
                             RInt => self.globals.builtin_r_int()?,
                             Floor => self.globals.builtin_floor()?,
                             Ceil => self.globals.builtin_ceil()?,
-                            CheckBottom => unimplemented!(),
-                            PointContents => unimplemented!(),
+                            CheckBottom => return Err(unimplemented_builtin(&name)),
+                            PointContents => return Err(unimplemented_builtin(&name)),
                             FAbs => self.globals.builtin_f_abs()?,
-                            Aim => unimplemented!(),
+                            Aim => return Err(unimplemented_builtin(&name)),
                             Cvar => self.builtin_cvar()?,
-                            LocalCmd => unimplemented!(),
-                            NextEnt => unimplemented!(),
-                            Particle => unimplemented!(),
-                            ChangeYaw => unimplemented!(),
-                            VecToAngles => unimplemented!(),
-                            WriteByte => unimplemented!(),
-                            WriteChar => unimplemented!(),
-                            WriteShort => unimplemented!(),
-                            WriteLong => unimplemented!(),
-                            WriteCoord => unimplemented!(),
-                            WriteAngle => unimplemented!(),
-                            WriteString => unimplemented!(),
-                            WriteEntity => unimplemented!(),
-                            MoveToGoal => unimplemented!(),
-                            PrecacheFile => unimplemented!(),
-                            MakeStatic => unimplemented!(),
-                            ChangeLevel => unimplemented!(),
+                            LocalCmd => return Err(unimplemented_builtin(&name)),
+                            NextEnt => return Err(unimplemented_builtin(&name)),
+                            Particle => return Err(unimplemented_builtin(&name)),
+                            ChangeYaw => return Err(unimplemented_builtin(&name)),
+                            VecToAngles => return Err(unimplemented_builtin(&name)),
+                            WriteByte => return Err(unimplemented_builtin(&name)),
+                            WriteChar => return Err(unimplemented_builtin(&name)),
+                            WriteShort => return Err(unimplemented_builtin(&name)),
+                            WriteLong => return Err(unimplemented_builtin(&name)),
+                            WriteCoord => return Err(unimplemented_builtin(&name)),
+                            WriteAngle => return Err(unimplemented_builtin(&name)),
+                            WriteString => return Err(unimplemented_builtin(&name)),
+                            WriteEntity => return Err(unimplemented_builtin(&name)),
+                            MoveToGoal => return Err(unimplemented_builtin(&name)),
+                            PrecacheFile => self.builtin_precache_file()?,
+                            MakeStatic => return Err(unimplemented_builtin(&name)),
+                            ChangeLevel => return Err(unimplemented_builtin(&name)),
                             CvarSet => self.builtin_cvar_set()?,
-                            CenterPrint => unimplemented!(),
+                            CenterPrint => self.builtin_center_print()?,
                             AmbientSound => self.builtin_ambient_sound()?,
-                            PrecacheModel2 => unimplemented!(),
-                            PrecacheSound2 => unimplemented!(),
-                            PrecacheFile2 => unimplemented!(),
-                            SetSpawnArgs => unimplemented!(),
+                            PrecacheModel2 => self.builtin_precache_model()?,
+                            PrecacheSound2 => self.builtin_precache_sound()?,
+                            PrecacheFile2 => self.builtin_precache_file()?,
+                            SetSpawnArgs => return Err(unimplemented_builtin(&name)),
+
+                            Sin => self.globals.builtin_f_unary(f32::sin)?,
+                            Cos => self.globals.builtin_f_unary(f32::cos)?,
+                            Sqrt => self.globals.builtin_f_unary(f32::sqrt)?,
+                            ETos => self.builtin_e_to_s()?,
+                            SToF => self.builtin_s_to_f()?,
+                            TraceBox => self.builtin_trace_box()?,
+                            RandomVec => self.globals.builtin_random_vec(&mut self.rng)?,
+                            Min => self.globals.builtin_min(arg_count)?,
+                            Max => self.globals.builtin_max(arg_count)?,
+                            Bound => self.globals.builtin_bound()?,
+                            Pow => self.globals.builtin_pow()?,
+                            FindFloat => self.builtin_find_float()?,
+                            CheckExtension => self.builtin_check_extension()?,
+                            StrLen => self.builtin_str_len()?,
+                            StrCat => self.builtin_str_cat(arg_count)?,
+                            Substring => self.builtin_substring()?,
+                            SToV => self.builtin_s_to_v()?,
+                            StrZone => self.builtin_str_zone()?,
+                            StrUnzone => self.builtin_str_unzone()?,
+                            FOpen => self.builtin_f_open()?,
+                            FClose => self.builtin_f_close()?,
+                            FGets => self.builtin_f_gets()?,
+                            FPuts => self.builtin_f_puts(arg_count)?,
                         }
                         debug!("Returning from built-in function {}", name);
+                    } else if let FunctionKind::UnknownBuiltIn(n) =
+                        self.cx.function_def(f_to_call)?.kind
+                    {
+                        return Err(ProgsError::with_msg(format!(
+                            "Called unsupported built-in function {} (#{})",
+                            name, n
+                        )));
                     } else {
                         self.cx.enter_function(&mut self.globals, f_to_call)?;
                         continue;
@@ -750,11 +2270,95 @@ impl LevelState {
         Ok(())
     }
 
+    /// Returns the name and score of a player entity.
+    pub fn player_score(&self, ent_id: EntityId) -> Result<(String, i32), ProgsError> {
+        let ent = self.world.entity(ent_id);
+        let name_id = ent.load(FieldAddrStringId::NetName)?;
+        let name = self
+            .string_table
+            .borrow()
+            .get(name_id)
+            .unwrap_or("")
+            .to_owned();
+        let frags = ent.load(FieldAddrFloat::Frags)? as i32;
+
+        Ok((name, frags))
+    }
+
+    /// Returns `true` if a player entity is dead or dying.
+    pub fn is_dead(&self, ent_id: EntityId) -> Result<bool, ProgsError> {
+        Ok(self.world.entity(ent_id).load(FieldAddrFloat::DeadFlag)? != 0.0)
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Returns the name of the level's map, without the `maps/` directory or
+    /// `.bsp` extension.
+    pub fn map_name(&self) -> String {
+        // the world model is always the first after the null model
+        let path = self.model_precache.get(1).unwrap_or("");
+        path.trim_start_matches("maps/")
+            .trim_end_matches(".bsp")
+            .to_owned()
+    }
+
+    /// Reset a player's score and put them back in the game at a spawn point.
+    pub fn respawn_player(&mut self, ent_id: EntityId) -> Result<(), ProgsError> {
+        self.world
+            .entity_mut(ent_id)?
+            .store(FieldAddrFloat::Frags, 0.0)?;
+
+        let put_client_in_server = self
+            .globals
+            .function_id(GlobalAddrFunction::PutClientInServer as i16)?;
+        self.globals
+            .store(GlobalAddrFloat::Time, duration_to_f32(self.time))?;
+        self.globals.store(GlobalAddrEntity::Self_, ent_id)?;
+        self.execute_program(put_client_in_server)?;
+
+        Ok(())
+    }
+
+    /// Take a player out of the game without disconnecting them.
+    ///
+    /// The player's entity stops colliding and taking damage, is hidden and
+    /// moves freely.
+    pub fn make_spectator(&mut self, ent_id: EntityId) -> Result<(), ProgsError> {
+        let ent = self.world.entity_mut(ent_id)?;
+        ent.store(FieldAddrFloat::MoveKind, MoveKind::NoClip as u32 as f32)?;
+        ent.store(FieldAddrFloat::Solid, EntitySolid::Not as u32 as f32)?;
+        ent.store(FieldAddrFloat::ModelIndex, 0.0)?;
+        ent.store(FieldAddrFloat::TakeDamage, 0.0)?;
+        self.link_entity(ent_id, false)?;
+
+        Ok(())
+    }
+
+    /// Let QuakeC clean up after a player who is leaving the server.
+    pub fn disconnect_player(&mut self, ent_id: EntityId) -> Result<(), ProgsError> {
+        let client_disconnect = self
+            .globals
+            .function_id(GlobalAddrFunction::ClientDisconnect as i16)?;
+        self.globals
+            .store(GlobalAddrFloat::Time, duration_to_f32(self.time))?;
+        self.globals.store(GlobalAddrEntity::Self_, ent_id)?;
+        self.execute_program(client_disconnect)?;
+
+        Ok(())
+    }
+
     pub fn physics(
         &mut self,
         clients: &ClientSlots,
         frame_time: Duration,
     ) -> Result<(), ProgsError> {
+        // the world waits while QuakeC is paused in the debugger
+        if self.debugger.is_paused() {
+            return Ok(());
+        }
+
         self.globals.store(GlobalAddrEntity::Self_, EntityId(0))?;
         self.globals.store(GlobalAddrEntity::Other, EntityId(0))?;
         self.globals
@@ -770,70 +2374,269 @@ impl LevelState {
 
         self.world.list_entities(&mut ent_ids);
 
-        for ent_id in ent_ids {
-            if self.globals.load(GlobalAddrFloat::ForceRetouch)? != 0.0 {
-                // Force all entities to touch triggers, even if they didn't
-                // move. This is required when e.g. creating new triggers, as
-                // stationary entities typically do not get relinked, and so
-                // will ignore new triggers even when touching them.
-                //
-                // TODO: this may have a subtle ordering bug. If entity 2 has
-                // physics run, sets ForceRetouch and spawns entity 1, then
-                // entity 1 will not have a chance to touch triggers this frame.
-                // Quake solves this by using a linked list and always spawning
-                // at the end so that newly spawned entities always have physics
-                // run this frame.
-                self.link_entity(ent_id, true)?;
-            }
+        for ent_id in ent_ids {
+            // the entity may have been removed by another entity's physics
+            if !self.world.entity_exists(ent_id) {
+                continue;
+            }
+
+            if self.globals.load(GlobalAddrFloat::ForceRetouch)? != 0.0 {
+                // Force all entities to touch triggers, even if they didn't
+                // move. This is required when e.g. creating new triggers, as
+                // stationary entities typically do not get relinked, and so
+                // will ignore new triggers even when touching them.
+                //
+                // TODO: this may have a subtle ordering bug. If entity 2 has
+                // physics run, sets ForceRetouch and spawns entity 1, then
+                // entity 1 will not have a chance to touch triggers this frame.
+                // Quake solves this by using a linked list and always spawning
+                // at the end so that newly spawned entities always have physics
+                // run this frame.
+                self.link_entity(ent_id, true)?;
+            }
+
+            let max_clients = clients.limit();
+            if ent_id.0 != 0 && ent_id.0 <= max_clients {
+                self.physics_player(clients, ent_id, frame_time)?;
+            } else {
+                match self.world.entity(ent_id).move_kind()? {
+                    MoveKind::Push => self.physics_push(ent_id, frame_time)?,
+                    // No actual physics for this entity, but still let it think.
+                    MoveKind::None => self.think(ent_id, frame_time)?,
+                    MoveKind::NoClip => self.physics_noclip(ent_id, frame_time)?,
+                    MoveKind::Step => self.physics_step(ent_id, frame_time)?,
+
+                    // all airborne entities have the same physics
+                    MoveKind::Toss | MoveKind::Bounce | MoveKind::Fly | MoveKind::FlyMissile => {
+                        self.physics_toss(ent_id, frame_time)?
+                    }
+
+                    // only players walk
+                    m => {
+                        return Err(ProgsError::with_msg(format!(
+                            "Invalid move kind for entity {} ({:?})",
+                            ent_id.0, m
+                        )))
+                    }
+                }
+            }
+
+            match self.globals.load(GlobalAddrFloat::ForceRetouch)? {
+                f if f > 0.0 => self.globals.store(GlobalAddrFloat::ForceRetouch, f - 1.0)?,
+                _ => (),
+            }
+        }
+
+        self.time = self.time + frame_time;
+        Ok(())
+    }
+
+    /// Runs a player's think functions and moves them as their client asked.
+    pub fn physics_player(
+        &mut self,
+        clients: &ClientSlots,
+        ent_id: EntityId,
+        frame_time: Duration,
+    ) -> Result<(), ProgsError> {
+        let client_id = ent_id.0.checked_sub(1).ok_or_else(|| {
+            ProgsError::with_msg(format!("Invalid client entity ID: {:?}", ent_id))
+        })?;
+
+        match clients.get(client_id) {
+            Some(ClientState::Active(_)) => (),
+
+            // No client in this slot, or it hasn't spawned yet.
+            _ => return Ok(()),
+        }
+
+        let sv_maxvelocity = self.cvars.borrow().get_value("sv_maxvelocity").unwrap();
+        self.world
+            .entity_mut(ent_id)?
+            .limit_velocity(sv_maxvelocity)?;
+
+        self.globals
+            .store(GlobalAddrFloat::Time, duration_to_f32(self.time))?;
+        self.globals.store(GlobalAddrEntity::Self_, ent_id)?;
+        let pre_think = self
+            .globals
+            .function_id(GlobalAddrFunction::PlayerPreThink as i16)?;
+        self.execute_program(pre_think)?;
+
+        match self.world.entity(ent_id).move_kind()? {
+            MoveKind::None => self.think(ent_id, frame_time)?,
+
+            MoveKind::Walk => {
+                self.think(ent_id, frame_time)?;
+                self.physics_walk(ent_id, frame_time)?;
+            }
+
+            MoveKind::Fly => {
+                self.think(ent_id, frame_time)?;
+                self.player_accelerate(ent_id, frame_time)?;
+                self.move_ballistic(frame_time, ent_id)?;
+            }
+
+            MoveKind::NoClip => {
+                self.player_accelerate(ent_id, frame_time)?;
+                self.physics_noclip(ent_id, frame_time)?;
+            }
+
+            MoveKind::Toss | MoveKind::Bounce => self.physics_toss(ent_id, frame_time)?,
+
+            m => {
+                return Err(ProgsError::with_msg(format!(
+                    "Invalid move kind for player {} ({:?})",
+                    ent_id.0, m
+                )))
+            }
+        }
+
+        self.link_entity(ent_id, true)?;
+
+        self.globals
+            .store(GlobalAddrFloat::Time, duration_to_f32(self.time))?;
+        self.globals.store(GlobalAddrEntity::Self_, ent_id)?;
+        let post_think = self
+            .globals
+            .function_id(GlobalAddrFunction::PlayerPostThink as i16)?;
+        self.execute_program(post_think)?;
+
+        Ok(())
+    }
+
+    // steer a player toward the movement their client asked for, as
+    // SV_ClientThink does
+    fn player_accelerate(
+        &mut self,
+        ent_id: EntityId,
+        frame_time: Duration,
+    ) -> Result<(), ProgsError> {
+        let player_move = self.moves.get(&ent_id).copied().unwrap_or_default();
+        let (max_speed, accelerate, friction, stop_speed) = {
+            let cvars = self.cvars.borrow();
+            (
+                cvars.get_value("sv_maxspeed").unwrap(),
+                cvars.get_value("sv_accelerate").unwrap(),
+                cvars.get_value("sv_friction").unwrap(),
+                cvars.get_value("sv_stopspeed").unwrap(),
+            )
+        };
+        let frame_time_f = duration_to_f32(frame_time);
+
+        let ent = self.world.entity_mut(ent_id)?;
+
+        // the dead can't move
+        if ent.load(FieldAddrFloat::Health)? <= 0.0 {
+            return Ok(());
+        }
+
+        let angles: Vector3<f32> = ent.load(FieldAddrVector::Angles)?.into();
+        let (forward, right, _) =
+            math::angle_vectors(Vector3::new(Deg(angles.x), Deg(angles.y), Deg(angles.z)));
+        let move_kind = ent.move_kind()?;
 
-            let max_clients = clients.limit();
-            if ent_id.0 != 0 && ent_id.0 < max_clients {
-                self.physics_player(clients, ent_id)?;
-            } else {
-                match self.world.entity(ent_id).move_kind()? {
-                    MoveKind::Walk => {
-                        todo!("MoveKind::Walk");
-                    }
+        let mut wish_vel = player_move.forward * forward + player_move.side * right;
+        wish_vel.z = match move_kind {
+            MoveKind::Walk => 0.0,
+            _ => player_move.up,
+        };
 
-                    MoveKind::Push => self.physics_push(ent_id, frame_time)?,
-                    // No actual physics for this entity, but still let it think.
-                    MoveKind::None => self.think(ent_id, frame_time)?,
-                    MoveKind::NoClip => self.physics_noclip(ent_id, frame_time)?,
-                    MoveKind::Step => self.physics_step(ent_id, frame_time)?,
+        let mut wish_speed = wish_vel.magnitude();
+        let wish_dir = match wish_speed {
+            s if s == 0.0 => Vector3::zero(),
+            s => wish_vel / s,
+        };
+        if wish_speed > max_speed {
+            wish_speed = max_speed;
+        }
 
-                    // all airborne entities have the same physics
-                    _ => unimplemented!(),
-                }
+        let mut vel = ent.velocity()?;
+        if move_kind == MoveKind::NoClip {
+            vel = wish_dir * wish_speed;
+        } else if ent.flags()?.contains(EntityFlags::ON_GROUND) {
+            let speed = vel.x.hypot(vel.y);
+            if speed > 0.0 {
+                let control = speed.max(stop_speed);
+                let new_speed = (speed - frame_time_f * control * friction).max(0.0);
+                vel *= new_speed / speed;
             }
 
-            match self.globals.load(GlobalAddrFloat::ForceRetouch)? {
-                f if f > 0.0 => self.globals.store(GlobalAddrFloat::ForceRetouch, f - 1.0)?,
-                _ => (),
+            let add_speed = wish_speed - vel.dot(wish_dir);
+            if add_speed > 0.0 {
+                vel += wish_dir * add_speed.min(accelerate * frame_time_f * wish_speed);
+            }
+        } else {
+            let add_speed = wish_speed.min(MAX_AIR_SPEED) - vel.dot(wish_dir);
+            if add_speed > 0.0 {
+                vel += wish_dir * add_speed.min(accelerate * frame_time_f * wish_speed);
             }
         }
 
-        // TODO: increase sv.time by host_frametime
-        unimplemented!();
+        ent.store(FieldAddrVector::Velocity, vel.into())?;
+        Ok(())
     }
 
-    // TODO: rename arguments when implementing
-    pub fn physics_player(
+    // move a walking player. gravity is always applied so that players stay on
+    // the ground
+    fn physics_walk(&mut self, ent_id: EntityId, frame_time: Duration) -> Result<(), ProgsError> {
+        self.player_accelerate(ent_id, frame_time)?;
+
+        let sv_gravity = self.cvars.borrow().get_value("sv_gravity").unwrap();
+        let ent = self.world.entity_mut(ent_id)?;
+        ent.apply_gravity(sv_gravity, frame_time)?;
+
+        // the player is back on the ground if the move ends there
+        ent.remove_flags(EntityFlags::ON_GROUND)?;
+
+        // TODO: step up stairs and slopes as SV_WalkMove does
+        self.move_ballistic(frame_time, ent_id)?;
+
+        Ok(())
+    }
+
+    /// Physics for airborne entities such as projectiles and gibs.
+    pub fn physics_toss(
         &mut self,
-        clients: &ClientSlots,
         ent_id: EntityId,
+        frame_time: Duration,
     ) -> Result<(), ProgsError> {
-        let client_id = ent_id.0.checked_sub(1).ok_or_else(|| {
-            ProgsError::with_msg(format!("Invalid client entity ID: {:?}", ent_id))
-        })?;
+        self.think(ent_id, frame_time)?;
 
-        if clients.get(client_id).is_none() {
-            // No client in this slot.
+        // the entity may have removed itself
+        if !self.world.entity_exists(ent_id) {
             return Ok(());
         }
 
         let ent = self.world.entity_mut(ent_id)?;
-        ent.limit_velocity(self.cvars.borrow().get_value("sv_maxvelocity").unwrap())?;
-        unimplemented!();
+        if ent.flags()?.contains(EntityFlags::ON_GROUND) {
+            return Ok(());
+        }
+
+        let (sv_gravity, sv_maxvelocity) = {
+            let cvars = self.cvars.borrow();
+            (
+                cvars.get_value("sv_gravity").unwrap(),
+                cvars.get_value("sv_maxvelocity").unwrap(),
+            )
+        };
+        ent.limit_velocity(sv_maxvelocity)?;
+        match ent.move_kind()? {
+            MoveKind::Fly | MoveKind::FlyMissile => (),
+            _ => ent.apply_gravity(sv_gravity, frame_time)?,
+        }
+
+        let angles: Vector3<f32> = ent.load(FieldAddrVector::Angles)?.into();
+        let angle_vel: Vector3<f32> = ent.load(FieldAddrVector::AngularVelocity)?.into();
+        let new_angles = angles + duration_to_f32(frame_time) * angle_vel;
+        ent.store(FieldAddrVector::Angles, new_angles.into())?;
+
+        // TODO: bounce off surfaces as SV_ClipVelocity does for MOVETYPE_BOUNCE
+        self.move_ballistic(frame_time, ent_id)?;
+        if self.world.entity_exists(ent_id) {
+            self.link_entity(ent_id, true)?;
+        }
+
+        Ok(())
     }
 
     pub fn physics_push(
@@ -945,13 +2748,23 @@ impl LevelState {
 
             if ent.flags()?.contains(EntityFlags::ON_GROUND) && hit_sound {
                 // Entity hit the ground this frame.
-                todo!("SV_StartSound(demon/dland2.wav)");
+                let position = ent.origin()?;
+                if let Some(sound_id) = self.sound_precache.find("demon/dland2.wav") {
+                    self.write_datagram(ServerCmd::Sound {
+                        volume: None,
+                        attenuation: None,
+                        entity_id: ent_id.0 as u16,
+                        channel: 0,
+                        sound_id: sound_id as u16,
+                        position,
+                    });
+                }
             }
         }
 
         self.think(ent_id, frame_time)?;
 
-        todo!("SV_CheckWaterTransition");
+        // TODO: SV_CheckWaterTransition
 
         Ok(())
     }
@@ -975,8 +2788,15 @@ impl LevelState {
 
         let move_time_f = duration_to_f32(move_time);
         let move_vector = vel * move_time_f;
-        // TODO let mins =
-        todo!()
+
+        // TODO: push or block the entities in the way, as SV_PushMove does
+        let origin = ent.origin()?;
+        ent.store(FieldAddrVector::Origin, (origin + move_vector).into())?;
+        let local_time = ent.load(FieldAddrFloat::LocalTime)?;
+        ent.store(FieldAddrFloat::LocalTime, local_time + move_time_f)?;
+        self.link_entity(ent_id, false)?;
+
+        Ok(())
     }
 
     const MAX_BALLISTIC_COLLISIONS: usize = 4;
@@ -1491,14 +3311,6 @@ impl LevelState {
         Ok(())
     }
 
-    // TODO: move to Globals
-    pub fn builtin_random(&mut self) -> Result<(), ProgsError> {
-        self.globals
-            .put_float(rand::random(), GLOBAL_ADDR_RETURN as i16)?;
-
-        Ok(())
-    }
-
     pub fn builtin_spawn(&mut self) -> Result<(), ProgsError> {
         let ent_id = self.spawn_entity()?;
         self.globals
@@ -1540,6 +3352,116 @@ impl LevelState {
         Ok(())
     }
 
+    pub fn builtin_precache_file(&mut self) -> Result<(), ProgsError> {
+        // files are only precached so that qcc can list them for packaging
+        let s_id = self.globals.string_id(GLOBAL_ADDR_ARG_0 as i16)?;
+        self.globals
+            .put_string_id(s_id, GLOBAL_ADDR_RETURN as i16)?;
+
+        Ok(())
+    }
+
+    pub fn builtin_sound(&mut self) -> Result<(), ProgsError> {
+        let ent_id = self.globals.entity_id(GLOBAL_ADDR_ARG_0 as i16)?;
+        let channel = self.globals.get_float(GLOBAL_ADDR_ARG_1 as i16)? as i8;
+        let name = self.globals.string_id(GLOBAL_ADDR_ARG_2 as i16)?;
+        let volume = (self.globals.get_float(GLOBAL_ADDR_ARG_3 as i16)? * 255.0) as u8;
+        let attenuation = self.globals.get_float(GLOBAL_ADDR_ARG_4 as i16)?;
+
+        let sound_id = match self.sound_id(name) {
+            Some(i) => i,
+            None => return Err(ProgsError::with_msg("sound not precached")),
+        };
+
+        // sounds play from the center of the entity's bounding box
+        let ent = self.world.try_entity(ent_id)?;
+        let position = ent.origin()? + 0.5 * (ent.min()? + ent.max()?);
+
+        self.write_datagram(ServerCmd::Sound {
+            volume: match volume {
+                255 => None,
+                v => Some(v),
+            },
+            attenuation: match attenuation {
+                a if a == 1.0 => None,
+                a => Some(a),
+            },
+            entity_id: ent_id.0 as u16,
+            channel,
+            sound_id: sound_id as u16,
+            position,
+        });
+
+        Ok(())
+    }
+
+    // the slot of the client controlling `ent_id`
+    fn client_slot(&self, ent_id: EntityId) -> Result<usize, ProgsError> {
+        match ent_id.0 {
+            id if id >= 1 && id <= self.max_clients => Ok(id - 1),
+            id => Err(ProgsError::with_msg(format!(
+                "Entity {} is not a client",
+                id
+            ))),
+        }
+    }
+
+    fn string_arg(&self, addr: i16) -> Result<String, ProgsError> {
+        let s_id = self.globals.string_id(addr)?;
+        match self.string_table.borrow().get(s_id) {
+            Some(s) => Ok(s.to_owned()),
+            None => Err(ProgsError::with_msg(format!(
+                "Invalid string ID ({:?})",
+                s_id
+            ))),
+        }
+    }
+
+    pub fn builtin_b_print(&mut self) -> Result<(), ProgsError> {
+        let text = self.string_arg(GLOBAL_ADDR_ARG_0 as i16)?;
+        self.messages
+            .push((Recipient::All, ServerCmd::Print { text }));
+
+        Ok(())
+    }
+
+    pub fn builtin_s_print(&mut self) -> Result<(), ProgsError> {
+        let ent_id = self.globals.entity_id(GLOBAL_ADDR_ARG_0 as i16)?;
+        let slot = self.client_slot(ent_id)?;
+        let text = self.string_arg(GLOBAL_ADDR_ARG_1 as i16)?;
+        self.messages
+            .push((Recipient::Client(slot), ServerCmd::Print { text }));
+
+        Ok(())
+    }
+
+    pub fn builtin_center_print(&mut self) -> Result<(), ProgsError> {
+        let ent_id = self.globals.entity_id(GLOBAL_ADDR_ARG_0 as i16)?;
+        let slot = self.client_slot(ent_id)?;
+        let text = self.string_arg(GLOBAL_ADDR_ARG_1 as i16)?;
+        self.messages
+            .push((Recipient::Client(slot), ServerCmd::CenterPrint { text }));
+
+        Ok(())
+    }
+
+    pub fn builtin_stuff_cmd(&mut self) -> Result<(), ProgsError> {
+        let ent_id = self.globals.entity_id(GLOBAL_ADDR_ARG_0 as i16)?;
+        let slot = self.client_slot(ent_id)?;
+        let text = self.string_arg(GLOBAL_ADDR_ARG_1 as i16)?;
+        self.messages
+            .push((Recipient::Client(slot), ServerCmd::StuffText { text }));
+
+        Ok(())
+    }
+
+    pub fn builtin_break(&mut self) -> Result<(), ProgsError> {
+        info!("QuakeC break statement");
+        self.debugger.request_step();
+
+        Ok(())
+    }
+
     pub fn builtin_dprint(&mut self) -> Result<(), ProgsError> {
         let strs = self.string_table.borrow();
         let s_id = self.globals.string_id(GLOBAL_ADDR_ARG_0 as i16)?;
@@ -1561,11 +3483,26 @@ impl LevelState {
     pub fn builtin_light_style(&mut self) -> Result<(), ProgsError> {
         let index = match self.globals.get_float(GLOBAL_ADDR_ARG_0 as i16)? as i32 {
             i if i < 0 => return Err(ProgsError::with_msg("negative lightstyle ID")),
+            i if i as usize >= MAX_LIGHTSTYLES => {
+                return Err(ProgsError::with_msg(format!(
+                    "Invalid lightstyle ID ({})",
+                    i
+                )))
+            }
             i => i as usize,
         };
         let val = self.globals.string_id(GLOBAL_ADDR_ARG_1 as i16)?;
         self.set_lightstyle(index, val);
 
+        let value = self.string_arg(GLOBAL_ADDR_ARG_1 as i16)?;
+        self.messages.push((
+            Recipient::All,
+            ServerCmd::LightStyle {
+                id: index as u8,
+                value,
+            },
+        ));
+
         Ok(())
     }
 
@@ -1573,7 +3510,8 @@ impl LevelState {
         let s_id = self.globals.string_id(GLOBAL_ADDR_ARG_0 as i16)?;
         let strs = self.string_table.borrow();
         let s = strs.get(s_id).unwrap();
-        let f = self.cvars.borrow().get_value(s).unwrap();
+        // mods probe for engine-specific cvars, so a missing one reads as 0
+        let f = self.cvars.borrow().get_value(s).unwrap_or(0.0);
         self.globals.put_float(f, GLOBAL_ADDR_RETURN as i16)?;
 
         Ok(())
@@ -1587,7 +3525,7 @@ impl LevelState {
         let val_id = self.globals.string_id(GLOBAL_ADDR_ARG_1 as i16)?;
         let val = strs.get(val_id).unwrap();
 
-        self.cvars.borrow_mut().set(var, val).unwrap();
+        self.cvars.borrow().set(var, val).unwrap();
 
         Ok(())
     }
@@ -1606,4 +3544,322 @@ impl LevelState {
         // TODO: write to server signon packet
         Ok(())
     }
+
+    /// Run a trace for `traceline` or `tracebox` and store the result in the `trace_*` globals.
+    fn trace_into_globals(
+        &mut self,
+        start: Vector3<f32>,
+        min: Vector3<f32>,
+        max: Vector3<f32>,
+        end: Vector3<f32>,
+        kind_addr: i16,
+        pass_addr: i16,
+    ) -> Result<(), ProgsError> {
+        let kind = self.globals.get_float(kind_addr)? as u32;
+        let kind = CollideKind::from_u32(kind).unwrap_or(CollideKind::Normal);
+        let pass_id = self.globals.entity_id(pass_addr)?;
+
+        let (trace, collide_entity) = self
+            .world
+            .move_entity(pass_id, start, min, max, end, kind)?;
+
+        let (normal, dist) = match trace.end().kind() {
+            TraceEndKind::Boundary(b) => (b.plane.normal(), b.plane.dist()),
+            TraceEndKind::Terminal => (Vector3::zero(), 0.0),
+        };
+
+        self.globals.store(
+            GlobalAddrFloat::TraceAllSolid,
+            trace.all_solid() as u32 as f32,
+        )?;
+        self.globals.store(
+            GlobalAddrFloat::TraceStartSolid,
+            trace.start_solid() as u32 as f32,
+        )?;
+        self.globals
+            .store(GlobalAddrFloat::TraceFraction, trace.ratio())?;
+        self.globals
+            .store(GlobalAddrFloat::TraceInOpen, trace.in_open() as u32 as f32)?;
+        self.globals.store(
+            GlobalAddrFloat::TraceInWater,
+            trace.in_water() as u32 as f32,
+        )?;
+        self.globals.store(GlobalAddrFloat::TracePlaneDist, dist)?;
+        self.globals
+            .store(GlobalAddrVector::TraceEndPos, trace.end_point().into())?;
+        self.globals
+            .store(GlobalAddrVector::TracePlaneNormal, normal.into())?;
+        self.globals.store(
+            GlobalAddrEntity::TraceEntity,
+            collide_entity.unwrap_or(EntityId(0)),
+        )?;
+
+        Ok(())
+    }
+
+    pub fn builtin_trace_line(&mut self) -> Result<(), ProgsError> {
+        let start = self.globals.get_vector(GLOBAL_ADDR_ARG_0 as i16)?.into();
+        let end = self.globals.get_vector(GLOBAL_ADDR_ARG_1 as i16)?.into();
+
+        self.trace_into_globals(
+            start,
+            Vector3::zero(),
+            Vector3::zero(),
+            end,
+            GLOBAL_ADDR_ARG_2 as i16,
+            GLOBAL_ADDR_ARG_3 as i16,
+        )
+    }
+
+    pub fn builtin_trace_box(&mut self) -> Result<(), ProgsError> {
+        let start = self.globals.get_vector(GLOBAL_ADDR_ARG_0 as i16)?.into();
+        let min = self.globals.get_vector(GLOBAL_ADDR_ARG_1 as i16)?.into();
+        let max = self.globals.get_vector(GLOBAL_ADDR_ARG_2 as i16)?.into();
+        let end = self.globals.get_vector(GLOBAL_ADDR_ARG_3 as i16)?.into();
+
+        self.trace_into_globals(
+            start,
+            min,
+            max,
+            end,
+            GLOBAL_ADDR_ARG_4 as i16,
+            GLOBAL_ADDR_ARG_5 as i16,
+        )
+    }
+
+    /// Find the first entity after the one at `GLOBAL_ADDR_ARG_0` for which `matches` returns
+    /// `true`, and store it at `GLOBAL_ADDR_RETURN`.
+    ///
+    /// Stores the world entity if there is no match.
+    fn find_entity<F>(&mut self, mut matches: F) -> Result<(), ProgsError>
+    where
+        F: FnMut(&World, EntityId) -> Result<bool, ProgsError>,
+    {
+        let start = self.globals.entity_id(GLOBAL_ADDR_ARG_0 as i16)?;
+
+        let mut ent_ids = Vec::new();
+        self.world.list_entities(&mut ent_ids);
+
+        let mut found = EntityId(0);
+        for ent_id in ent_ids.into_iter().filter(|e| e.0 > start.0) {
+            if matches(&self.world, ent_id)? {
+                found = ent_id;
+                break;
+            }
+        }
+
+        self.globals
+            .put_entity_id(found, GLOBAL_ADDR_RETURN as i16)?;
+
+        Ok(())
+    }
+
+    pub fn builtin_find(&mut self) -> Result<(), ProgsError> {
+        let field = self.globals.get_field_addr(GLOBAL_ADDR_ARG_1 as i16)?;
+        let s_id = self.globals.string_id(GLOBAL_ADDR_ARG_2 as i16)?;
+        let strs = self.string_table.clone();
+        let strs = strs.borrow();
+        let target = strs.get(s_id).unwrap_or("");
+
+        self.find_entity(|world, ent_id| {
+            let val_id = world.entity(ent_id).string_id(field.0 as i16)?;
+            Ok(match strs.get(val_id) {
+                // entities with the field unset never match
+                Some(val) if !val.is_empty() => val == target,
+                _ => false,
+            })
+        })
+    }
+
+    pub fn builtin_find_float(&mut self) -> Result<(), ProgsError> {
+        let field = self.globals.get_field_addr(GLOBAL_ADDR_ARG_1 as i16)?;
+        let target = self.globals.get_float(GLOBAL_ADDR_ARG_2 as i16)?;
+
+        self.find_entity(|world, ent_id| {
+            Ok(world.entity(ent_id).get_float(field.0 as i16)? == target)
+        })
+    }
+
+    /// Load the string argument at `addr`.
+    fn string_arg(&self, addr: usize) -> Result<String, ProgsError> {
+        let s_id = self.globals.string_id(addr as i16)?;
+        Ok(self
+            .string_table
+            .borrow()
+            .get(s_id)
+            .unwrap_or("")
+            .to_owned())
+    }
+
+    /// Store `s` as a temporary string at `GLOBAL_ADDR_RETURN`.
+    fn return_temp_string<S>(&mut self, s: S) -> Result<(), ProgsError>
+    where
+        S: AsRef<str>,
+    {
+        let s_id = self.string_table.borrow_mut().insert_temp(s);
+        self.globals
+            .put_string_id(s_id, GLOBAL_ADDR_RETURN as i16)?;
+
+        Ok(())
+    }
+
+    pub fn builtin_f_to_s(&mut self) -> Result<(), ProgsError> {
+        let f = self.globals.get_float(GLOBAL_ADDR_ARG_0 as i16)?;
+        self.return_temp_string(strings::ftos(f))
+    }
+
+    pub fn builtin_v_to_s(&mut self) -> Result<(), ProgsError> {
+        let v = self.globals.get_vector(GLOBAL_ADDR_ARG_0 as i16)?;
+        self.return_temp_string(strings::vtos(v))
+    }
+
+    pub fn builtin_e_to_s(&mut self) -> Result<(), ProgsError> {
+        let ent_id = self.globals.entity_id(GLOBAL_ADDR_ARG_0 as i16)?;
+        self.return_temp_string(format!("entity {}", ent_id.0))
+    }
+
+    pub fn builtin_s_to_f(&mut self) -> Result<(), ProgsError> {
+        let s = self.string_arg(GLOBAL_ADDR_ARG_0)?;
+        self.globals
+            .put_float(strings::stof(&s), GLOBAL_ADDR_RETURN as i16)?;
+
+        Ok(())
+    }
+
+    pub fn builtin_s_to_v(&mut self) -> Result<(), ProgsError> {
+        let s = self.string_arg(GLOBAL_ADDR_ARG_0)?;
+        self.globals
+            .put_vector(strings::stov(&s), GLOBAL_ADDR_RETURN as i16)?;
+
+        Ok(())
+    }
+
+    pub fn builtin_str_len(&mut self) -> Result<(), ProgsError> {
+        let s = self.string_arg(GLOBAL_ADDR_ARG_0)?;
+        self.globals
+            .put_float(s.chars().count() as f32, GLOBAL_ADDR_RETURN as i16)?;
+
+        Ok(())
+    }
+
+    pub fn builtin_str_cat(&mut self, arg_count: usize) -> Result<(), ProgsError> {
+        let mut cat = String::new();
+        for i in 0..arg_count {
+            cat.push_str(&self.string_arg(GLOBAL_ADDR_ARG_0 + 3 * i)?);
+        }
+
+        self.return_temp_string(cat)
+    }
+
+    pub fn builtin_substring(&mut self) -> Result<(), ProgsError> {
+        let s = self.string_arg(GLOBAL_ADDR_ARG_0)?;
+        let start = self.globals.get_float(GLOBAL_ADDR_ARG_1 as i16)? as i32;
+        let length = self.globals.get_float(GLOBAL_ADDR_ARG_2 as i16)? as i32;
+
+        self.return_temp_string(strings::substring(&s, start, length))
+    }
+
+    pub fn builtin_str_zone(&mut self) -> Result<(), ProgsError> {
+        let s = self.string_arg(GLOBAL_ADDR_ARG_0)?;
+        let s_id = self.string_table.borrow_mut().insert_zone(s);
+        self.globals
+            .put_string_id(s_id, GLOBAL_ADDR_RETURN as i16)?;
+
+        Ok(())
+    }
+
+    pub fn builtin_str_unzone(&mut self) -> Result<(), ProgsError> {
+        let s_id = self.globals.string_id(GLOBAL_ADDR_ARG_0 as i16)?;
+        if !self.string_table.borrow_mut().remove_zone(s_id) {
+            warn!("strunzone: string {} wasn't made by strzone", s_id.0);
+        }
+
+        Ok(())
+    }
+
+    pub fn builtin_f_open(&mut self) -> Result<(), ProgsError> {
+        let name = self.string_arg(GLOBAL_ADDR_ARG_0)?;
+        let mode = self.globals.get_float(GLOBAL_ADDR_ARG_1 as i16)? as u32;
+
+        // failing to open a file isn't fatal, the program gets -1 instead
+        let handle = match FileMode::from_u32(mode) {
+            Some(mode) => match self.files.open(&name, mode) {
+                Ok(h) => h as f32,
+                Err(e) => {
+                    warn!("fopen(\"{}\"): {}", name, e);
+                    -1.0
+                }
+            },
+            None => {
+                warn!("fopen(\"{}\"): invalid mode {}", name, mode);
+                -1.0
+            }
+        };
+
+        self.globals.put_float(handle, GLOBAL_ADDR_RETURN as i16)?;
+
+        Ok(())
+    }
+
+    pub fn builtin_f_close(&mut self) -> Result<(), ProgsError> {
+        let handle = self.globals.get_float(GLOBAL_ADDR_ARG_0 as i16)?;
+        if let Err(e) = self.files.close(handle as usize) {
+            warn!("fclose: {}", e);
+        }
+
+        Ok(())
+    }
+
+    pub fn builtin_f_gets(&mut self) -> Result<(), ProgsError> {
+        let handle = self.globals.get_float(GLOBAL_ADDR_ARG_0 as i16)?;
+        match self.files.gets(handle as usize) {
+            Ok(Some(line)) => self.return_temp_string(line)?,
+
+            // the null string signals the end of the file
+            Ok(None) => self
+                .globals
+                .put_string_id(StringId(0), GLOBAL_ADDR_RETURN as i16)?,
+
+            Err(e) => {
+                warn!("fgets: {}", e);
+                self.globals
+                    .put_string_id(StringId(0), GLOBAL_ADDR_RETURN as i16)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn builtin_f_puts(&mut self, arg_count: usize) -> Result<(), ProgsError> {
+        let handle = self.globals.get_float(GLOBAL_ADDR_ARG_0 as i16)?;
+
+        let mut s = String::new();
+        for i in 1..arg_count {
+            s.push_str(&self.string_arg(GLOBAL_ADDR_ARG_0 + 3 * i)?);
+        }
+
+        if let Err(e) = self.files.puts(handle as usize, &s) {
+            warn!("fputs: {}", e);
+        }
+
+        Ok(())
+    }
+
+    pub fn builtin_check_extension(&mut self) -> Result<(), ProgsError> {
+        let name = self.string_arg(GLOBAL_ADDR_ARG_0)?;
+        let enabled = self
+            .cvars
+            .borrow()
+            .get_value("pr_checkextension")
+            .unwrap_or(0.0)
+            != 0.0;
+        let supported = enabled
+            && functions::EXTENSIONS
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(&name));
+        self.globals
+            .put_float(supported as u32 as f32, GLOBAL_ADDR_RETURN as i16)?;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,217 @@
+// Copyright © 2018 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Message of the day and server adverts.
+//!
+//! The message of the day is shown to each player as they finish signing on.
+//! It's read from the file named by `sv_motdfile` in the game directory, or
+//! from `sv_motd`, where `\n` starts a new line. It's printed to the console,
+//! or centerprinted if `sv_motdcenter` is set.
+//!
+//! Every `sv_advertinterval` seconds, `sv_advert` is printed to everyone, e.g.
+//! to point players at the server's website.
+
+use std::io::Read as _;
+
+use crate::common::{console::CvarRegistry, engine::duration_from_f32, net::ServerCmd, vfs::Vfs};
+
+use chrono::Duration;
+
+// replace `\n` escapes with line breaks
+fn unescape(text: &str) -> String {
+    text.replace("\\n", "\n")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MotdStyle {
+    /// Print the message to the console.
+    Print,
+
+    /// Show the message in the middle of the screen.
+    CenterPrint,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Motd {
+    text: String,
+    style: MotdStyle,
+}
+
+impl Motd {
+    pub fn new<S>(text: S, style: MotdStyle) -> Motd
+    where
+        S: AsRef<str>,
+    {
+        Motd {
+            text: text.as_ref().trim_end().to_owned(),
+            style,
+        }
+    }
+
+    /// Read the message of the day from `sv_motdfile` or `sv_motd`.
+    ///
+    /// Returns `None` if there is no message.
+    pub fn from_cvars(cvars: &CvarRegistry, vfs: &Vfs) -> Option<Motd> {
+        let style = match cvars.get_value("sv_motdcenter") {
+            Ok(c) if c != 0.0 => MotdStyle::CenterPrint,
+            _ => MotdStyle::Print,
+        };
+
+        let text = match cvars.get("sv_motdfile").unwrap_or_default().as_str() {
+            "" => unescape(&cvars.get("sv_motd").unwrap_or_default()),
+            path => {
+                let mut text = String::new();
+                match vfs.open(path) {
+                    Ok(mut f) => {
+                        if let Err(e) = f.read_to_string(&mut text) {
+                            warn!("Couldn't read {}: {}", path, e);
+                        }
+                    }
+                    Err(e) => warn!("Couldn't open {}: {}", path, e),
+                }
+                text
+            }
+        };
+
+        let motd = Motd::new(text, style);
+        match motd.text.is_empty() {
+            true => None,
+            false => Some(motd),
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the message to send to a client that has signed on.
+    pub fn message(&self) -> ServerCmd {
+        match self.style {
+            MotdStyle::Print => ServerCmd::Print {
+                text: format!("{}\n", self.text),
+            },
+            MotdStyle::CenterPrint => ServerCmd::CenterPrint {
+                text: self.text.clone(),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdvertVars {
+    /// How often to print the advert, or `None` if adverts are off.
+    pub interval: Option<Duration>,
+
+    pub text: String,
+}
+
+impl AdvertVars {
+    pub fn from_cvars(cvars: &CvarRegistry) -> AdvertVars {
+        let text = unescape(&cvars.get("sv_advert").unwrap_or_default());
+        AdvertVars {
+            interval: match cvars.get_value("sv_advertinterval") {
+                Ok(secs) if secs > 0.0 && !text.is_empty() => Some(duration_from_f32(secs)),
+                _ => None,
+            },
+            text,
+        }
+    }
+}
+
+/// Decides when to print the advert.
+#[derive(Debug, Default)]
+pub struct Adverts {
+    next: Option<Duration>,
+}
+
+impl Adverts {
+    pub fn new() -> Adverts {
+        Adverts::default()
+    }
+
+    /// Returns the advert to broadcast if one is due at `time`.
+    ///
+    /// The first advert goes out one interval after adverts are turned on.
+    pub fn update(&mut self, time: Duration, vars: &AdvertVars) -> Option<ServerCmd> {
+        let interval = match vars.interval {
+            Some(i) => i,
+            None => {
+                self.next = None;
+                return None;
+            }
+        };
+
+        match self.next {
+            Some(next) if time >= next => {
+                self.next = Some(time + interval);
+                Some(ServerCmd::Print {
+                    text: format!("{}\n", vars.text),
+                })
+            }
+            Some(_) => None,
+            None => {
+                self.next = Some(time + interval);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_motd_message() {
+        let motd = Motd::new(unescape("Welcome!\\nNo camping.\n"), MotdStyle::Print);
+        assert_eq!(
+            motd.message(),
+            ServerCmd::Print {
+                text: "Welcome!\nNo camping.\n".to_owned()
+            }
+        );
+
+        let motd = Motd::new("Welcome!", MotdStyle::CenterPrint);
+        assert_eq!(
+            motd.message(),
+            ServerCmd::CenterPrint {
+                text: "Welcome!".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn test_adverts() {
+        let vars = AdvertVars {
+            interval: Some(Duration::seconds(60)),
+            text: "visit example.com".to_owned(),
+        };
+        let mut adverts = Adverts::new();
+
+        assert_eq!(adverts.update(Duration::seconds(5), &vars), None);
+        assert_eq!(adverts.update(Duration::seconds(64), &vars), None);
+        assert!(adverts.update(Duration::seconds(65), &vars).is_some());
+        assert_eq!(adverts.update(Duration::seconds(100), &vars), None);
+        assert!(adverts.update(Duration::seconds(125), &vars).is_some());
+
+        let off = AdvertVars {
+            interval: None,
+            text: String::new(),
+        };
+        assert_eq!(adverts.update(Duration::seconds(200), &off), None);
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright © 2018 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Per-client entity and bandwidth counters.
+//!
+//! Each frame the server counts, for every client, how many entities it
+//! considered sending, how many were outside the client's PVS, how many were
+//! sent and how many were dropped because the datagram was full. The
+//! `netstats` command prints the latest counters along with averages, which
+//! helps track down players who can't see each other or complain of choke.
+
+use std::{collections::BTreeMap, fmt::Write as _};
+
+use super::datagram::EntityPacking;
+
+/// One frame's counters for one client.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrameCounters {
+    /// Entities checked for this client.
+    pub considered: usize,
+
+    /// Entities skipped because they were outside the client's PVS.
+    pub culled: usize,
+
+    /// Entity updates written to the datagram.
+    pub sent: usize,
+
+    /// Entity updates that didn't fit in the datagram and were deferred.
+    pub dropped: usize,
+
+    /// The size of the datagram in bytes.
+    pub bytes: usize,
+}
+
+impl FrameCounters {
+    pub fn new(
+        considered: usize,
+        culled: usize,
+        packing: EntityPacking,
+        bytes: usize,
+    ) -> FrameCounters {
+        FrameCounters {
+            considered,
+            culled,
+            sent: packing.sent,
+            dropped: packing.deferred,
+            bytes,
+        }
+    }
+
+    fn add(&mut self, other: &FrameCounters) {
+        self.considered += other.considered;
+        self.culled += other.culled;
+        self.sent += other.sent;
+        self.dropped += other.dropped;
+        self.bytes += other.bytes;
+    }
+}
+
+#[derive(Debug, Default)]
+struct ClientNetStats {
+    last: FrameCounters,
+    total: FrameCounters,
+    frames: usize,
+
+    // frames in which updates were dropped
+    choked: usize,
+}
+
+impl ClientNetStats {
+    fn average_bytes(&self) -> usize {
+        match self.frames {
+            0 => 0,
+            n => self.total.bytes / n,
+        }
+    }
+
+    fn choke_percent(&self) -> f32 {
+        match self.frames {
+            0 => 0.0,
+            n => 100.0 * self.choked as f32 / n as f32,
+        }
+    }
+}
+
+/// The counters of every connected client, by slot.
+#[derive(Debug, Default)]
+pub struct NetStats {
+    clients: BTreeMap<usize, ClientNetStats>,
+}
+
+impl NetStats {
+    pub fn new() -> NetStats {
+        NetStats::default()
+    }
+
+    /// Record the counters of the frame just sent to the client in `slot`.
+    pub fn record(&mut self, slot: usize, counters: FrameCounters) {
+        let stats = self.clients.entry(slot).or_default();
+        stats.last = counters;
+        stats.total.add(&counters);
+        stats.frames += 1;
+        if counters.dropped > 0 {
+            stats.choked += 1;
+        }
+    }
+
+    /// The counters of the last frame sent to the client in `slot`.
+    pub fn last(&self, slot: usize) -> Option<FrameCounters> {
+        self.clients.get(&slot).map(|s| s.last)
+    }
+
+    /// Forget the counters of the client in `slot`.
+    pub fn remove(&mut self, slot: usize) {
+        self.clients.remove(&slot);
+    }
+
+    /// Forget every client's counters.
+    pub fn reset(&mut self) {
+        self.clients.clear();
+    }
+
+    /// Format the counters as a table, one row per client.
+    ///
+    /// `name` returns the name of the player in a slot.
+    pub fn report<F>(&self, name: F) -> String
+    where
+        F: Fn(usize) -> String,
+    {
+        if self.clients.is_empty() {
+            return "No clients\n".to_owned();
+        }
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{:>4} {:<15} {:>5} {:>5} {:>5} {:>5} {:>6} {:>6} {:>6}",
+            "slot", "name", "ents", "pvs", "sent", "drop", "bytes", "avg", "choke"
+        );
+
+        for (slot, stats) in self.clients.iter() {
+            let last = &stats.last;
+            let _ = writeln!(
+                out,
+                "{:>4} {:<15.15} {:>5} {:>5} {:>5} {:>5} {:>6} {:>6} {:>5.1}%",
+                slot,
+                name(*slot),
+                last.considered,
+                last.culled,
+                last.sent,
+                last.dropped,
+                last.bytes,
+                stats.average_bytes(),
+                stats.choke_percent(),
+            );
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_netstats() {
+        let mut stats = NetStats::new();
+        assert_eq!(stats.report(|_| String::new()), "No clients\n");
+
+        let packing = |sent, deferred| EntityPacking { sent, deferred };
+        stats.record(2, FrameCounters::new(40, 10, packing(30, 0), 600));
+        stats.record(2, FrameCounters::new(40, 5, packing(30, 5), 1000));
+        stats.record(0, FrameCounters::new(8, 0, packing(8, 0), 100));
+
+        assert_eq!(
+            stats.last(2),
+            Some(FrameCounters {
+                considered: 40,
+                culled: 5,
+                sent: 30,
+                dropped: 5,
+                bytes: 1000,
+            })
+        );
+
+        let report = stats.report(|slot| format!("player{}", slot));
+        let rows: Vec<&str> = report.lines().collect();
+        assert_eq!(rows.len(), 3);
+        assert!(rows[1].contains("player0"));
+        assert!(rows[2].contains("player2"));
+        // average of 600 and 1000 bytes, one of two frames choked
+        assert!(rows[2].contains(" 800 "));
+        assert!(rows[2].ends_with("50.0%"));
+
+        stats.remove(2);
+        assert_eq!(stats.last(2), None);
+    }
+}
@@ -0,0 +1,354 @@
+// Copyright © 2020 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Debugging support for QuakeC programs.
+//!
+//! Breakpoints are set by function name and pause the VM just before the function's first
+//! statement. A paused program can be single-stepped or continued from the console. No other
+//! QuakeC runs while the VM is paused, so the world stands still until it's continued.
+//!
+//! The VM can only pause when no engine code is waiting on the program, that is, in functions
+//! called directly by the engine rather than by a builtin. A breakpoint hit anywhere else prints
+//! a stack trace and execution carries on.
+//!
+//! Source line numbers are read from `progs.lno`, which FTEQCC and compatible compilers write
+//! alongside `progs.dat`.
+
+use std::{collections::BTreeSet, fmt, io::Read};
+
+use crate::{common::vfs::Vfs, server::progs::ProgsError};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+pub const LNO_PATH: &str = "progs.lno";
+
+// "LNOF"
+const LNO_MAGIC: i32 = 0x464F4E4C;
+const LNO_VERSION: i32 = 1;
+
+/// The source line of each statement in a program.
+#[derive(Debug)]
+pub struct LineNumbers {
+    lines: Box<[u32]>,
+}
+
+impl LineNumbers {
+    /// Read line numbers for a program with `statement_count` statements.
+    pub fn load<R>(mut src: R, statement_count: usize) -> Result<LineNumbers, ProgsError>
+    where
+        R: Read,
+    {
+        if src.read_i32::<LittleEndian>()? != LNO_MAGIC {
+            return Err(ProgsError::with_msg("Bad line number file magic"));
+        }
+
+        let version = src.read_i32::<LittleEndian>()?;
+        if version != LNO_VERSION {
+            return Err(ProgsError::with_msg(format!(
+                "Unsupported line number file version {}",
+                version
+            )));
+        }
+
+        // the compiler also records the number of global defs, globals and field defs, but only
+        // the statement count matters here
+        for _ in 0..3 {
+            src.read_i32::<LittleEndian>()?;
+        }
+
+        let count = src.read_i32::<LittleEndian>()?;
+        if count < 0 || count as usize != statement_count {
+            return Err(ProgsError::with_msg(format!(
+                "Line numbers are for {} statements, program has {}",
+                count, statement_count
+            )));
+        }
+
+        let mut lines = Vec::with_capacity(statement_count);
+        for _ in 0..statement_count {
+            lines.push(src.read_u32::<LittleEndian>()?);
+        }
+
+        Ok(LineNumbers {
+            lines: lines.into_boxed_slice(),
+        })
+    }
+
+    /// Returns the source line of the given statement.
+    pub fn line(&self, statement_id: usize) -> Option<u32> {
+        self.lines.get(statement_id).copied()
+    }
+}
+
+/// Load `progs.lno` if it exists and matches a program with `statement_count` statements.
+pub fn load_line_numbers(vfs: &Vfs, statement_count: usize) -> Option<LineNumbers> {
+    let file = vfs.open(LNO_PATH).ok()?;
+    match LineNumbers::load(file, statement_count) {
+        Ok(lines) => Some(lines),
+        Err(e) => {
+            warn!("Ignoring {}: {}", LNO_PATH, e);
+            None
+        }
+    }
+}
+
+/// A single function call in a `StackTrace`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackFrameInfo {
+    pub function: String,
+    pub file: String,
+    pub statement: usize,
+    pub line: Option<u32>,
+}
+
+impl fmt::Display for StackFrameInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{} ({}:{})", self.function, self.file, line),
+            None => write!(
+                f,
+                "{} ({}, statement {})",
+                self.function, self.file, self.statement
+            ),
+        }
+    }
+}
+
+/// The QuakeC call stack, innermost call first.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StackTrace {
+    pub frames: Vec<StackFrameInfo>,
+}
+
+impl fmt::Display for StackTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, frame) in self.frames.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "  {}", frame)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Breakpoints and stepping state for the QuakeC VM.
+#[derive(Debug, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<String>,
+
+    // pause before the next statement
+    step: bool,
+
+    paused: bool,
+
+    // don't pause again on the statement the VM was paused at
+    resuming: bool,
+
+    // log every statement, as set by the traceon and traceoff builtins
+    trace: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    pub fn add_breakpoint<S>(&mut self, function: S)
+    where
+        S: Into<String>,
+    {
+        self.breakpoints.insert(function.into());
+    }
+
+    /// Remove the breakpoint on `function`, returning `false` if there wasn't one.
+    pub fn remove_breakpoint(&mut self, function: &str) -> bool {
+        self.breakpoints.remove(function)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &str> {
+        self.breakpoints.iter().map(|s| s.as_str())
+    }
+
+    /// Pause before the next statement the VM executes.
+    pub fn request_step(&mut self) {
+        self.step = true;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn tracing(&self) -> bool {
+        self.trace
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Returns `true` if `should_pause` needs to be called before each statement.
+    pub fn is_armed(&self) -> bool {
+        self.step || self.resuming || !self.breakpoints.is_empty()
+    }
+
+    /// Decide whether to pause before the next statement.
+    ///
+    /// `at_entry` is set if the statement is the first in `function`.
+    pub fn should_pause(&mut self, function: &str, at_entry: bool) -> bool {
+        if self.resuming {
+            self.resuming = false;
+            return false;
+        }
+
+        if self.step {
+            self.step = false;
+            return true;
+        }
+
+        at_entry && self.breakpoints.contains(function)
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Leave the paused state. If `step` is set, the VM pauses again after one statement.
+    pub fn resume(&mut self, step: bool) {
+        self.paused = false;
+        self.resuming = true;
+        self.step = step;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{cell::RefCell, io::Cursor, rc::Rc};
+
+    use crate::server::progs::{
+        functions::{FunctionDef, FunctionKind, Functions, MAX_ARGS},
+        ExecutionContext, FunctionId, Globals, StringTable,
+    };
+
+    use byteorder::WriteBytesExt;
+
+    fn lno(lines: &[u32]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.write_i32::<LittleEndian>(LNO_MAGIC).unwrap();
+        data.write_i32::<LittleEndian>(LNO_VERSION).unwrap();
+        for count in [0, 0, 0, lines.len() as i32].iter() {
+            data.write_i32::<LittleEndian>(*count).unwrap();
+        }
+        for line in lines {
+            data.write_u32::<LittleEndian>(*line).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn test_load_line_numbers() {
+        let lines = LineNumbers::load(Cursor::new(lno(&[0, 10, 11, 20])), 4).unwrap();
+        assert_eq!(lines.line(2), Some(11));
+        assert_eq!(lines.line(4), None);
+
+        // line numbers for another version of the program are useless
+        assert!(LineNumbers::load(Cursor::new(lno(&[0, 10, 11])), 4).is_err());
+        assert!(LineNumbers::load(Cursor::new(b"LNOX".to_vec()), 4).is_err());
+    }
+
+    #[test]
+    fn test_debugger_pause() {
+        let mut debugger = Debugger::new();
+        assert!(!debugger.is_armed());
+
+        debugger.add_breakpoint("monster_think");
+        assert!(debugger.is_armed());
+        assert!(!debugger.should_pause("player_think", true));
+        assert!(!debugger.should_pause("monster_think", false));
+        assert!(debugger.should_pause("monster_think", true));
+
+        // continuing doesn't stop on the same breakpoint again
+        debugger.pause();
+        debugger.resume(false);
+        assert!(!debugger.is_paused());
+        assert!(!debugger.should_pause("monster_think", true));
+
+        // stepping stops at the next statement, wherever it is
+        debugger.resume(true);
+        assert!(!debugger.should_pause("monster_think", true));
+        assert!(debugger.should_pause("monster_think", false));
+        assert!(!debugger.should_pause("monster_think", false));
+
+        assert!(debugger.remove_breakpoint("monster_think"));
+        assert!(!debugger.is_armed());
+    }
+
+    #[test]
+    fn test_stack_trace() {
+        let string_table = Rc::new(RefCell::new(StringTable::new(
+            b"\0nullfn\0main\0helper\0world.qc\0".to_vec(),
+        )));
+        let id = |s: &str| string_table.borrow().find(s).unwrap();
+        let def = |name, entry| FunctionDef {
+            kind: FunctionKind::QuakeC(entry),
+            arg_start: 0,
+            locals: 0,
+            name_id: id(name),
+            srcfile_id: id("world.qc"),
+            argc: 0,
+            argsz: [0; MAX_ARGS],
+        };
+        let functions = Rc::new(Functions {
+            string_table: string_table.clone(),
+            defs: vec![def("nullfn", 0), def("main", 1), def("helper", 3)].into_boxed_slice(),
+            statements: Vec::new().into_boxed_slice(),
+        });
+        let mut globals = Globals::new(
+            string_table.clone(),
+            Vec::new().into_boxed_slice(),
+            vec![[0; 4]; 8].into_boxed_slice(),
+        );
+
+        let mut cx = ExecutionContext::create(string_table.clone(), functions);
+        cx.enter_function(&mut globals, FunctionId(1)).unwrap();
+        cx.jump_relative(1);
+        cx.enter_function(&mut globals, FunctionId(2)).unwrap();
+        assert!(cx.at_function_entry());
+
+        let frame = |function: &str, statement, line| StackFrameInfo {
+            function: function.to_owned(),
+            file: "world.qc".to_owned(),
+            statement,
+            line,
+        };
+        assert_eq!(
+            cx.stack_trace().frames,
+            vec![frame("helper", 3, None), frame("main", 2, None)]
+        );
+
+        cx.set_line_numbers(Some(
+            LineNumbers::load(Cursor::new(lno(&[0, 5, 6, 12])), 4).unwrap(),
+        ));
+        assert_eq!(
+            cx.stack_trace().to_string(),
+            "  helper (world.qc:12)\n  main (world.qc:6)"
+        );
+    }
+}
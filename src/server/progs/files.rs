@@ -0,0 +1,283 @@
+// Copyright © 2020 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Sandboxed file access for the FRIK_FILE builtins.
+//!
+//! QuakeC can only read and write files under `data/` in the game directory. Names are checked
+//! so they can't escape it, and quotas cap the number of open handles, the number of files in the
+//! directory and the size of each file.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::server::progs::ProgsError;
+
+/// The directory under the game directory that holds QuakeC's files.
+pub const DATA_DIR: &str = "data";
+
+/// The most files QuakeC may have open at once.
+pub const MAX_OPEN_FILES: usize = 16;
+
+/// The most files QuakeC may create in the data directory.
+pub const MAX_FILES: usize = 256;
+
+/// The largest file QuakeC may write, in bytes.
+pub const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// How a file is opened, as passed to `fopen`.
+#[derive(Copy, Clone, Debug, Eq, FromPrimitive, PartialEq)]
+pub enum FileMode {
+    Read = 0,
+    Append = 1,
+    Write = 2,
+}
+
+#[derive(Debug)]
+enum OpenFile {
+    Read(BufReader<File>),
+    Write { file: File, size: u64 },
+}
+
+/// The files opened by QuakeC.
+#[derive(Debug)]
+pub struct QcFiles {
+    // None if there is no game directory to write to
+    root: Option<PathBuf>,
+    handles: Vec<Option<OpenFile>>,
+}
+
+impl QcFiles {
+    /// Create a sandbox for files under `DATA_DIR` in `game_dir`.
+    pub fn new(game_dir: Option<&Path>) -> QcFiles {
+        let mut handles = Vec::with_capacity(MAX_OPEN_FILES);
+        handles.resize_with(MAX_OPEN_FILES, || None);
+
+        QcFiles {
+            root: game_dir.map(|d| d.join(DATA_DIR)),
+            handles,
+        }
+    }
+
+    /// Open the file `name` and return its handle.
+    pub fn open(&mut self, name: &str, mode: FileMode) -> Result<usize, ProgsError> {
+        let root = self
+            .root
+            .as_ref()
+            .ok_or_else(|| ProgsError::with_msg("No game directory for QuakeC files"))?;
+        let rel_path = sanitize(name)
+            .ok_or_else(|| ProgsError::with_msg(format!("Invalid file name \"{}\"", name)))?;
+        let path = root.join(rel_path);
+
+        let handle = self
+            .handles
+            .iter()
+            .position(|h| h.is_none())
+            .ok_or_else(|| ProgsError::with_msg("Too many open files"))?;
+
+        let file = match mode {
+            FileMode::Read => OpenFile::Read(BufReader::new(File::open(&path)?)),
+
+            FileMode::Append | FileMode::Write => {
+                if !path.exists() && count_files(root) >= MAX_FILES {
+                    return Err(ProgsError::with_msg(format!(
+                        "Can't create {}: data directory already has {} files",
+                        name, MAX_FILES
+                    )));
+                }
+
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .append(mode == FileMode::Append)
+                    .truncate(mode == FileMode::Write)
+                    .open(&path)?;
+                let size = file.metadata()?.len();
+                OpenFile::Write { file, size }
+            }
+        };
+
+        self.handles[handle] = Some(file);
+
+        Ok(handle)
+    }
+
+    /// Close the file with the given handle.
+    pub fn close(&mut self, handle: usize) -> Result<(), ProgsError> {
+        match self.handles.get_mut(handle).and_then(|h| h.take()) {
+            Some(_) => Ok(()),
+            None => Err(bad_handle(handle)),
+        }
+    }
+
+    /// Read the next line from a file opened for reading, without its line ending.
+    ///
+    /// Returns `None` at the end of the file.
+    pub fn gets(&mut self, handle: usize) -> Result<Option<String>, ProgsError> {
+        let reader = match self.handles.get_mut(handle) {
+            Some(Some(OpenFile::Read(r))) => r,
+            _ => return Err(bad_handle(handle)),
+        };
+
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+        line.truncate(len);
+
+        Ok(Some(line))
+    }
+
+    /// Write a string to a file opened for writing or appending.
+    pub fn puts(&mut self, handle: usize, s: &str) -> Result<(), ProgsError> {
+        let (file, size) = match self.handles.get_mut(handle) {
+            Some(Some(OpenFile::Write { file, size })) => (file, size),
+            _ => return Err(bad_handle(handle)),
+        };
+
+        if *size + s.len() as u64 > MAX_FILE_SIZE {
+            return Err(ProgsError::with_msg(format!(
+                "File would exceed {} bytes",
+                MAX_FILE_SIZE
+            )));
+        }
+
+        file.write_all(s.as_bytes())?;
+        *size += s.len() as u64;
+
+        Ok(())
+    }
+}
+
+fn bad_handle(handle: usize) -> ProgsError {
+    ProgsError::with_msg(format!("Invalid file handle {}", handle))
+}
+
+/// Check that `name` is a relative path that stays inside the data directory.
+///
+/// Only ASCII letters, digits, `_`, `-`, `.` and `/` are allowed, and no component may start with
+/// a dot.
+fn sanitize(name: &str) -> Option<PathBuf> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || "_-./".contains(c);
+    if name.is_empty() || !name.chars().all(allowed) {
+        return None;
+    }
+
+    let path = Path::new(name);
+    for component in path.components() {
+        match component {
+            Component::Normal(c) if !c.to_str()?.starts_with('.') => (),
+            _ => return None,
+        }
+    }
+
+    Some(path.to_path_buf())
+}
+
+// count the files under dir, including those in subdirectories
+fn count_files(dir: &Path) -> usize {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return 0,
+    };
+
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| match e.file_type() {
+            Ok(t) if t.is_dir() => count_files(&e.path()),
+            _ => 1,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("richter-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("scores.txt"), Some(PathBuf::from("scores.txt")));
+        assert_eq!(
+            sanitize("ad/save1.txt"),
+            Some(PathBuf::from("ad/save1.txt"))
+        );
+        assert_eq!(sanitize(""), None);
+        assert_eq!(sanitize("../config.cfg"), None);
+        assert_eq!(sanitize("/etc/passwd"), None);
+        assert_eq!(sanitize("a/.hidden"), None);
+        assert_eq!(sanitize("c:\\autoexec.bat"), None);
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let game_dir = temp_dir("qcfiles");
+        let mut files = QcFiles::new(Some(&game_dir));
+
+        let h = files.open("scores.txt", FileMode::Write).unwrap();
+        files.puts(h, "first\n").unwrap();
+        files.close(h).unwrap();
+        let h = files.open("scores.txt", FileMode::Append).unwrap();
+        files.puts(h, "second\r\n").unwrap();
+        assert!(files.gets(h).is_err());
+        files.close(h).unwrap();
+        assert!(files.close(h).is_err());
+
+        assert!(game_dir.join(DATA_DIR).join("scores.txt").is_file());
+        let h = files.open("scores.txt", FileMode::Read).unwrap();
+        assert_eq!(files.gets(h).unwrap().as_deref(), Some("first"));
+        assert_eq!(files.gets(h).unwrap().as_deref(), Some("second"));
+        assert_eq!(files.gets(h).unwrap(), None);
+        assert!(files.puts(h, "third").is_err());
+
+        let _ = fs::remove_dir_all(&game_dir);
+    }
+
+    #[test]
+    fn test_quotas() {
+        let game_dir = temp_dir("qcfiles-quota");
+        let mut files = QcFiles::new(Some(&game_dir));
+
+        let h = files.open("big.dat", FileMode::Write).unwrap();
+        let chunk = "x".repeat(MAX_FILE_SIZE as usize / 2);
+        files.puts(h, &chunk).unwrap();
+        files.puts(h, &chunk).unwrap();
+        assert!(files.puts(h, "x").is_err());
+
+        // h is still open, so only MAX_OPEN_FILES - 1 more fit
+        for i in 0..MAX_OPEN_FILES - 1 {
+            files.open(&format!("f{}.txt", i), FileMode::Write).unwrap();
+        }
+        assert!(files.open("onemore.txt", FileMode::Write).is_err());
+
+        let _ = fs::remove_dir_all(&game_dir);
+    }
+}
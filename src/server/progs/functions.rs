@@ -67,9 +67,26 @@ impl TryInto<i32> for FunctionId {
 #[derive(Debug)]
 pub enum FunctionKind {
     BuiltIn(BuiltinFunctionId),
+
+    /// A builtin this engine doesn't provide. Calling it is an error, but programs may declare it
+    /// as long as they check for its extension first.
+    UnknownBuiltIn(usize),
+
     QuakeC(usize),
 }
 
+/// The extensions reported by the `checkextension` builtin.
+pub const EXTENSIONS: &[&str] = &[
+    "DP_QC_ETOS",
+    "DP_QC_FINDFLOAT",
+    "DP_QC_MINMAXBOUND",
+    "DP_QC_MULTIPLETEMPSTRINGS",
+    "DP_QC_RANDOMVEC",
+    "DP_QC_SINCOSSQRTPOW",
+    "DP_QC_TRACEBOX",
+    "FRIK_FILE",
+];
+
 #[derive(Copy, Clone, Debug, FromPrimitive)]
 pub enum BuiltinFunctionId {
     // pr_builtin[0] is the null function
@@ -132,7 +149,12 @@ pub enum BuiltinFunctionId {
     WriteAngle = 57,
     WriteString = 58,
     WriteEntity = 59,
-    // pr_builtin[60] through pr_builtin[66] are only defined for Quake 2
+    // pr_builtin[60] through pr_builtin[66] were defined for Quake 2. Extensions reuse some of
+    // them.
+    Sin = 60,
+    Cos = 61,
+    Sqrt = 62,
+    ETos = 65,
     MoveToGoal = 67,
     PrecacheFile = 68,
     MakeStatic = 69,
@@ -145,6 +167,27 @@ pub enum BuiltinFunctionId {
     PrecacheSound2 = 76,
     PrecacheFile2 = 77,
     SetSpawnArgs = 78,
+
+    // extension builtins
+    SToF = 81,
+    TraceBox = 90,
+    RandomVec = 91,
+    Min = 94,
+    Max = 95,
+    Bound = 96,
+    Pow = 97,
+    FindFloat = 98,
+    CheckExtension = 99,
+    FOpen = 110,
+    FClose = 111,
+    FGets = 112,
+    FPuts = 113,
+    StrLen = 114,
+    StrCat = 115,
+    Substring = 116,
+    SToV = 117,
+    StrZone = 118,
+    StrUnzone = 119,
 }
 
 #[derive(Debug)]
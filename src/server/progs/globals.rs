@@ -23,6 +23,7 @@ use crate::server::progs::{
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use cgmath::{Deg, Euler, InnerSpace, Matrix3, Vector3};
+use rand::Rng;
 
 pub const GLOBAL_STATIC_START: usize = 28;
 pub const GLOBAL_DYNAMIC_START: usize = 64;
@@ -248,6 +249,10 @@ impl Globals {
         }
     }
 
+    pub fn defs(&self) -> &[GlobalDef] {
+        &self.defs
+    }
+
     /// Performs a type check at `addr` with type `type_`.
     ///
     /// The type check allows checking `QFloat` against `QVector` and vice-versa, since vectors have
@@ -1091,8 +1096,11 @@ impl Globals {
     // QuakeC built-in functions ===============================================
 
     #[inline]
-    pub fn builtin_random(&mut self) -> Result<(), GlobalsError> {
-        self.put_float(rand::random(), GLOBAL_ADDR_RETURN as i16)
+    pub fn builtin_random<R>(&mut self, rng: &mut R) -> Result<(), GlobalsError>
+    where
+        R: Rng,
+    {
+        self.put_float(rng.gen(), GLOBAL_ADDR_RETURN as i16)
     }
 
     /// Calculate `v_forward`, `v_right` and `v_up` from `angles`.
@@ -1185,6 +1193,84 @@ impl Globals {
         self.put_float(f.abs(), GLOBAL_ADDR_RETURN as i16)?;
         Ok(())
     }
+
+    /// Apply `f` to the float at `GLOBAL_ADDR_ARG_0` and store the result at
+    /// `GLOBAL_ADDR_RETURN`.
+    ///
+    /// Used for `sin`, `cos` and `sqrt`.
+    pub fn builtin_f_unary<F>(&mut self, f: F) -> Result<(), GlobalsError>
+    where
+        F: FnOnce(f32) -> f32,
+    {
+        let x = self.get_float(GLOBAL_ADDR_ARG_0 as i16)?;
+        self.put_float(f(x), GLOBAL_ADDR_RETURN as i16)?;
+        Ok(())
+    }
+
+    /// Raise the float at `GLOBAL_ADDR_ARG_0` to the power of the float at `GLOBAL_ADDR_ARG_1`.
+    pub fn builtin_pow(&mut self) -> Result<(), GlobalsError> {
+        let x = self.get_float(GLOBAL_ADDR_ARG_0 as i16)?;
+        let y = self.get_float(GLOBAL_ADDR_ARG_1 as i16)?;
+        self.put_float(x.powf(y), GLOBAL_ADDR_RETURN as i16)?;
+        Ok(())
+    }
+
+    /// Find the smallest of the first `arg_count` float arguments.
+    pub fn builtin_min(&mut self, arg_count: usize) -> Result<(), GlobalsError> {
+        let min = self.fold_float_args(arg_count, f32::min)?;
+        self.put_float(min, GLOBAL_ADDR_RETURN as i16)?;
+        Ok(())
+    }
+
+    /// Find the largest of the first `arg_count` float arguments.
+    pub fn builtin_max(&mut self, arg_count: usize) -> Result<(), GlobalsError> {
+        let max = self.fold_float_args(arg_count, f32::max)?;
+        self.put_float(max, GLOBAL_ADDR_RETURN as i16)?;
+        Ok(())
+    }
+
+    /// Clamp the float at `GLOBAL_ADDR_ARG_1` between the floats at `GLOBAL_ADDR_ARG_0` and
+    /// `GLOBAL_ADDR_ARG_2`.
+    pub fn builtin_bound(&mut self) -> Result<(), GlobalsError> {
+        let min = self.get_float(GLOBAL_ADDR_ARG_0 as i16)?;
+        let x = self.get_float(GLOBAL_ADDR_ARG_1 as i16)?;
+        let max = self.get_float(GLOBAL_ADDR_ARG_2 as i16)?;
+        self.put_float(x.max(min).min(max), GLOBAL_ADDR_RETURN as i16)?;
+        Ok(())
+    }
+
+    /// Store a random vector inside the unit sphere at `GLOBAL_ADDR_RETURN`.
+    pub fn builtin_random_vec<R>(&mut self, rng: &mut R) -> Result<(), GlobalsError>
+    where
+        R: Rng,
+    {
+        let v = loop {
+            let v = Vector3::new(
+                rng.gen::<f32>() * 2.0 - 1.0,
+                rng.gen::<f32>() * 2.0 - 1.0,
+                rng.gen::<f32>() * 2.0 - 1.0,
+            );
+
+            if v.magnitude2() <= 1.0 {
+                break v;
+            }
+        };
+
+        self.put_vector(v.into(), GLOBAL_ADDR_RETURN as i16)?;
+        Ok(())
+    }
+
+    fn fold_float_args<F>(&self, arg_count: usize, f: F) -> Result<f32, GlobalsError>
+    where
+        F: Fn(f32, f32) -> f32,
+    {
+        let mut acc = self.get_float(GLOBAL_ADDR_ARG_0 as i16)?;
+        for i in 1..arg_count {
+            acc = f(acc, self.get_float((GLOBAL_ADDR_ARG_0 + 3 * i) as i16)?);
+        }
+
+        Ok(acc)
+    }
 }
 
 pub fn make_vectors(angles: [f32; 3]) -> Matrix3<f32> {
@@ -1228,4 +1314,30 @@ mod test {
         let result = make_vectors(roll_90);
         assert_eq!(Matrix3::from_angle_x(Deg(90.0)), result);
     }
+
+    #[test]
+    fn test_min_max_bound() {
+        let mut globals = Globals::new(
+            Rc::new(RefCell::new(StringTable::new(b"\0".to_vec()))),
+            Vec::new().into_boxed_slice(),
+            vec![[0; 4]; 16].into_boxed_slice(),
+        );
+        for (i, f) in [3.0, -1.0, 7.0].iter().enumerate() {
+            globals
+                .put_float(*f, (GLOBAL_ADDR_ARG_0 + 3 * i) as i16)
+                .unwrap();
+        }
+
+        globals.builtin_min(3).unwrap();
+        assert_eq!(globals.get_float(GLOBAL_ADDR_RETURN as i16).unwrap(), -1.0);
+        globals.builtin_max(2).unwrap();
+        assert_eq!(globals.get_float(GLOBAL_ADDR_RETURN as i16).unwrap(), 3.0);
+
+        // bound(min, x, max)
+        globals.builtin_bound().unwrap();
+        assert_eq!(globals.get_float(GLOBAL_ADDR_RETURN as i16).unwrap(), 3.0);
+        globals.put_float(1.0, GLOBAL_ADDR_ARG_2 as i16).unwrap();
+        globals.builtin_bound().unwrap();
+        assert_eq!(globals.get_float(GLOBAL_ADDR_RETURN as i16).unwrap(), 1.0);
+    }
 }
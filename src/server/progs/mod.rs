@@ -93,10 +93,14 @@
 //! arg_sizes: [u8; 8],    // sizes of each argument
 //! ```
 
+pub mod debug;
+pub mod files;
 pub mod functions;
 pub mod globals;
 mod ops;
+pub mod reload;
 mod string_table;
+pub mod strings;
 
 use std::{
     cell::RefCell,
@@ -113,6 +117,7 @@ use byteorder::{LittleEndian, ReadBytesExt};
 use num::FromPrimitive;
 
 use self::{
+    debug::{LineNumbers, StackFrameInfo, StackTrace},
     functions::{BuiltinFunctionId, FunctionDef, FunctionKind, Statement, MAX_ARGS},
     globals::{GLOBAL_ADDR_ARG_0, GLOBAL_STATIC_COUNT},
 };
@@ -149,6 +154,10 @@ pub enum ProgsError {
     Entity(EntityError),
     CallStackOverflow,
     LocalStackOverflow,
+
+    /// The program is paused in the debugger. The engine code that ran it
+    /// should stop until the program is continued.
+    Paused,
     Other(String),
 }
 
@@ -179,6 +188,7 @@ impl fmt::Display for ProgsError {
             }
             CallStackOverflow => write!(f, "Call stack overflow"),
             LocalStackOverflow => write!(f, "Local stack overflow"),
+            Paused => write!(f, "QuakeC is paused in the debugger"),
             Other(ref msg) => write!(f, "{}", msg),
         }
     }
@@ -357,12 +367,7 @@ where
         let kind = match src.read_i32::<LittleEndian>()? {
             x if x < 0 => match BuiltinFunctionId::from_i32(-x) {
                 Some(f) => FunctionKind::BuiltIn(f),
-                None => {
-                    return Err(ProgsError::with_msg(format!(
-                        "Invalid built-in function ID {}",
-                        -x
-                    )))
-                }
+                None => FunctionKind::UnknownBuiltIn(-x as usize),
             },
             x => FunctionKind::QuakeC(x as usize),
         };
@@ -544,6 +549,7 @@ pub struct ExecutionContext {
     current_function: FunctionId,
     call_stack: Vec<StackFrame>,
     local_stack: Vec<[u8; 4]>,
+    line_numbers: Option<LineNumbers>,
 }
 
 impl ExecutionContext {
@@ -558,6 +564,7 @@ impl ExecutionContext {
             current_function: FunctionId(0),
             call_stack: Vec::with_capacity(MAX_CALL_STACK_DEPTH),
             local_stack: Vec::with_capacity(MAX_LOCAL_STACK_DEPTH),
+            line_numbers: None,
         }
     }
 
@@ -565,6 +572,91 @@ impl ExecutionContext {
         self.call_stack.len()
     }
 
+    pub fn statement_count(&self) -> usize {
+        self.functions.statements.len()
+    }
+
+    pub fn set_line_numbers(&mut self, line_numbers: Option<LineNumbers>) {
+        self.line_numbers = line_numbers;
+    }
+
+    /// Abandon the running program, discarding its call stack.
+    pub fn reset(&mut self) {
+        self.call_stack.clear();
+        self.local_stack.clear();
+        self.current_function = FunctionId(0);
+    }
+
+    /// Returns the name of the function being executed.
+    pub fn current_function_name(&self) -> String {
+        self.function_name(self.current_function)
+    }
+
+    fn function_name(&self, id: FunctionId) -> String {
+        self.functions
+            .get_def(id)
+            .ok()
+            .and_then(|def| {
+                self.string_table
+                    .borrow()
+                    .get(def.name_id)
+                    .map(|s| s.to_owned())
+            })
+            .unwrap_or_else(|| format!("<function {}>", id.0))
+    }
+
+    /// Returns `true` if the next statement is the first in the current function.
+    pub fn at_function_entry(&self) -> bool {
+        match self.functions.get_def(self.current_function) {
+            Ok(FunctionDef {
+                kind: FunctionKind::QuakeC(entry),
+                ..
+            }) => *entry == self.pc,
+            _ => false,
+        }
+    }
+
+    /// Returns the QuakeC call stack, innermost call first.
+    pub fn stack_trace(&self) -> StackTrace {
+        let frame = |func_id: FunctionId, statement: usize| {
+            let file = self
+                .functions
+                .get_def(func_id)
+                .ok()
+                .and_then(|def| {
+                    self.string_table
+                        .borrow()
+                        .get(def.srcfile_id)
+                        .map(|s| s.to_owned())
+                })
+                .unwrap_or_default();
+
+            StackFrameInfo {
+                function: self.function_name(func_id),
+                file,
+                statement,
+                line: self
+                    .line_numbers
+                    .as_ref()
+                    .and_then(|lines| lines.line(statement)),
+            }
+        };
+
+        let mut frames = Vec::new();
+        if !self.call_stack.is_empty() {
+            frames.push(frame(self.current_function, self.pc));
+        }
+
+        // each saved frame holds the caller's position. function 0 is the engine.
+        for saved in self.call_stack.iter().rev() {
+            if saved.func_id.0 != 0 {
+                frames.push(frame(saved.func_id, saved.instr_id));
+            }
+        }
+
+        StackTrace { frames }
+    }
+
     pub fn find_function_by_name<S: AsRef<str>>(
         &mut self,
         name: S,
@@ -619,7 +711,7 @@ impl ExecutionContext {
         self.current_function = f;
 
         match def.kind {
-            FunctionKind::BuiltIn(_) => {
+            FunctionKind::BuiltIn(_) | FunctionKind::UnknownBuiltIn(_) => {
                 panic!("built-in functions should not be called with enter_function()")
             }
             FunctionKind::QuakeC(pc) => self.pc = pc,
@@ -0,0 +1,396 @@
+// Copyright © 2018 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Reloading `progs.dat` into a running level.
+//!
+//! When QuakeC is recompiled, the new program may add, remove or reorder
+//! entity fields and globals, and its string and function tables will almost
+//! certainly differ. Values are carried over by name: a field or saved global
+//! keeps its value if the new program declares one with the same name and
+//! type. Strings, functions and field offsets stored in those values are
+//! translated to their counterparts in the new program.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::Read,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    common::vfs::Vfs,
+    server::{
+        progs::{
+            globals::Globals, ExecutionContext, LoadProgs, ProgsError, StringId, StringTable, Type,
+        },
+        world::{Entity, EntityTypeDef},
+    },
+};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The path of the program the server runs.
+pub const PROGS_PATH: &str = "progs.dat";
+
+// how often the watcher rereads progs.dat
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What was kept when a program was reloaded.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReloadStats {
+    /// The number of entity fields whose values were kept.
+    pub fields_kept: usize,
+
+    /// The number of old entity fields that have no counterpart in the new program.
+    pub fields_dropped: usize,
+
+    /// The number of saved globals whose values were kept.
+    pub globals_kept: usize,
+}
+
+// a value that keeps its place in memory under a new address
+#[derive(Copy, Clone, Debug)]
+struct Move {
+    old_ofs: u16,
+    new_ofs: u16,
+    type_: Type,
+}
+
+impl Move {
+    fn words(&self) -> u16 {
+        match self.type_ {
+            Type::QVector => 3,
+            _ => 1,
+        }
+    }
+}
+
+/// Translates values from a running program to a newly loaded one.
+pub struct Remap {
+    old_strings: Rc<RefCell<StringTable>>,
+    new_strings: Rc<RefCell<StringTable>>,
+    fields: Vec<Move>,
+    globals: Vec<Move>,
+    functions: HashMap<usize, usize>,
+    fields_dropped: usize,
+}
+
+impl Remap {
+    pub fn new(
+        old_strings: Rc<RefCell<StringTable>>,
+        old_cx: &ExecutionContext,
+        old_globals: &Globals,
+        old_type_def: &EntityTypeDef,
+        new: &LoadProgs,
+    ) -> Remap {
+        let old_strs = old_strings.borrow();
+        let new_strs = new.string_table.borrow();
+
+        let mut fields = Vec::new();
+        let mut fields_dropped = 0;
+        for old_def in old_type_def.field_defs() {
+            if old_def.type_ == Type::QVoid {
+                continue;
+            }
+
+            let name = old_strs.get(old_def.name_id).unwrap_or("");
+            match new.entity_def.find(name) {
+                Some(new_def) if new_def.type_ == old_def.type_ => fields.push(Move {
+                    old_ofs: old_def.offset,
+                    new_ofs: new_def.offset,
+                    type_: old_def.type_,
+                }),
+                _ => fields_dropped += 1,
+            }
+        }
+
+        // constants and functions must come from the new program, so only
+        // variables that would be saved with the game are kept
+        let mut globals = Vec::new();
+        for old_def in old_globals.defs().iter().filter(|d| d.save) {
+            let name = old_strs.get(old_def.name_id).unwrap_or("");
+            if let Some(new_def) = new.globals.defs().iter().find(|d| {
+                d.save && d.type_ == old_def.type_ && new_strs.get(d.name_id) == Some(name)
+            }) {
+                globals.push(Move {
+                    old_ofs: old_def.offset,
+                    new_ofs: new_def.offset,
+                    type_: old_def.type_,
+                });
+            }
+        }
+
+        let mut functions = HashMap::new();
+        for (old_id, def) in old_cx.functions.defs.iter().enumerate() {
+            let name = old_strs.get(def.name_id).unwrap_or("");
+            if let Ok(new_id) = new.cx.functions.find_function_by_name(name) {
+                functions.insert(old_id, new_id.0);
+            }
+        }
+
+        drop(new_strs);
+        drop(old_strs);
+
+        Remap {
+            old_strings,
+            new_strings: new.string_table.clone(),
+            fields,
+            globals,
+            functions,
+            fields_dropped,
+        }
+    }
+
+    pub fn stats(&self) -> ReloadStats {
+        ReloadStats {
+            fields_kept: self.fields.len(),
+            fields_dropped: self.fields_dropped,
+            globals_kept: self.globals.len(),
+        }
+    }
+
+    /// Returns the ID of the string in the new program equal to `id` in the old one.
+    pub fn string(&self, id: StringId) -> StringId {
+        if id.0 == 0 {
+            return id;
+        }
+
+        match self.old_strings.borrow().get(id) {
+            Some(s) => self.new_strings.borrow_mut().find_or_insert(s),
+            None => StringId(0),
+        }
+    }
+
+    // translate one word of a value of type `type_`
+    fn word(&self, type_: Type, word: [u8; 4]) -> [u8; 4] {
+        let old = LittleEndian::read_i32(&word);
+        let new = match type_ {
+            Type::QString => self.string(StringId(old.max(0) as usize)).0 as i32,
+            Type::QFunction => self.functions.get(&(old as usize)).copied().unwrap_or(0) as i32,
+            Type::QField => self
+                .fields
+                .iter()
+                .find(|m| m.old_ofs as i32 == old)
+                .map(|m| m.new_ofs as i32)
+                .unwrap_or(0),
+            _ => return word,
+        };
+
+        let mut out = [0; 4];
+        LittleEndian::write_i32(&mut out, new);
+        out
+    }
+
+    /// Copy field values from `old`, an entity of the old program, to `new`.
+    pub fn copy_entity(&self, old: &Entity, new: &mut Entity) -> Result<(), ProgsError> {
+        for m in self.fields.iter() {
+            for i in 0..m.words() {
+                let mut word = [0; 4];
+                word.copy_from_slice(old.get_addr((m.old_ofs + i) as i16)?);
+                let word = self.word(m.type_, word);
+                new.get_addr_mut((m.new_ofs + i) as i16)?
+                    .copy_from_slice(&word);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy saved globals from `old` to `new`.
+    pub fn copy_globals(&self, old: &Globals, new: &mut Globals) -> Result<(), ProgsError> {
+        for m in self.globals.iter() {
+            for i in 0..m.words() {
+                let word = self.word(m.type_, old.get_bytes((m.old_ofs + i) as i16)?);
+                new.put_bytes(word, (m.new_ofs + i) as i16)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Watches `progs.dat` for changes.
+#[derive(Debug)]
+pub struct ProgsWatcher {
+    checksum: Option<u32>,
+    last_check: Instant,
+}
+
+impl ProgsWatcher {
+    pub fn new(vfs: &Vfs) -> ProgsWatcher {
+        ProgsWatcher {
+            checksum: read_progs(vfs).map(|data| crc32fast::hash(&data)),
+            last_check: Instant::now(),
+        }
+    }
+
+    /// Returns the contents of `progs.dat` if it has changed since the last
+    /// call.
+    pub fn poll(&mut self, vfs: &Vfs, now: Instant) -> Option<Vec<u8>> {
+        if now.duration_since(self.last_check) < WATCH_INTERVAL {
+            return None;
+        }
+        self.last_check = now;
+
+        let data = read_progs(vfs)?;
+        let checksum = crc32fast::hash(&data);
+        if self.checksum == Some(checksum) {
+            return None;
+        }
+
+        self.checksum = Some(checksum);
+        Some(data)
+    }
+}
+
+fn read_progs(vfs: &Vfs) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    vfs.open(PROGS_PATH).ok()?.read_to_end(&mut data).ok()?;
+    Some(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::server::progs::{
+        functions::{FunctionDef, FunctionKind, Functions, MAX_ARGS},
+        FieldDef, FunctionId, GlobalDef,
+    };
+
+    // the first address after the fields every program defines
+    const FIELD_BASE: u16 = 105;
+
+    // build a program with the given (type, offset, name) fields and
+    // functions named by `functions`
+    fn program(fields: &[(Type, u16, &str)], functions: &[&str]) -> LoadProgs {
+        let mut data = String::from("\0total_monsters\0");
+        for name in fields.iter().map(|f| f.2).chain(functions.iter().copied()) {
+            data.push_str(name);
+            data.push('\0');
+        }
+        let string_table = Rc::new(RefCell::new(StringTable::new(data.into_bytes())));
+        let id = |s: &str| string_table.borrow().find(s).unwrap();
+
+        let field_defs = fields
+            .iter()
+            .map(|&(type_, offset, name)| FieldDef {
+                type_,
+                offset,
+                name_id: id(name),
+            })
+            .collect();
+        let function_defs = functions
+            .iter()
+            .map(|name| FunctionDef {
+                kind: FunctionKind::QuakeC(0),
+                arg_start: 0,
+                locals: 0,
+                name_id: id(name),
+                srcfile_id: StringId(0),
+                argc: 0,
+                argsz: [0; MAX_ARGS],
+            })
+            .collect();
+        let global_defs = vec![GlobalDef {
+            save: true,
+            type_: Type::QFloat,
+            offset: 0,
+            name_id: id("total_monsters"),
+        }];
+
+        let functions = Rc::new(Functions {
+            string_table: string_table.clone(),
+            defs: function_defs,
+            statements: Vec::new().into_boxed_slice(),
+        });
+
+        LoadProgs {
+            cx: ExecutionContext::create(string_table.clone(), functions),
+            globals: Globals::new(
+                string_table.clone(),
+                global_defs.into_boxed_slice(),
+                vec![[0; 4]; 8].into_boxed_slice(),
+            ),
+            entity_def: Rc::new(
+                EntityTypeDef::new(string_table.clone(), FIELD_BASE as usize + 8, field_defs)
+                    .unwrap(),
+            ),
+            string_table,
+        }
+    }
+
+    #[test]
+    fn test_remap_entity() {
+        let base = FIELD_BASE;
+        let old = program(
+            &[
+                (Type::QFloat, base, "ammo"),
+                (Type::QString, base + 1, "message"),
+                (Type::QFunction, base + 2, "think_fn"),
+                (Type::QFloat, base + 3, "removed"),
+            ],
+            &["nullfn", "idle"],
+        );
+
+        // fields are reordered, one is removed and one is added
+        let new = program(
+            &[
+                (Type::QFloat, base, "added"),
+                (Type::QFunction, base + 1, "think_fn"),
+                (Type::QString, base + 2, "message"),
+                (Type::QFloat, base + 3, "ammo"),
+            ],
+            &["nullfn", "walk", "idle"],
+        );
+
+        let mut old_ent = Entity::new(old.string_table.clone(), old.entity_def.clone());
+        let hello = old.string_table.borrow_mut().insert("hello");
+        old_ent.put_float(25.0, base as i16).unwrap();
+        old_ent.put_string_id(hello, base as i16 + 1).unwrap();
+        old_ent
+            .put_function_id(FunctionId(1), base as i16 + 2)
+            .unwrap();
+        old_ent.put_float(1.0, base as i16 + 3).unwrap();
+
+        let remap = Remap::new(
+            old.string_table.clone(),
+            &old.cx,
+            &old.globals,
+            &old.entity_def,
+            &new,
+        );
+        assert_eq!(
+            remap.stats(),
+            ReloadStats {
+                fields_kept: 3,
+                fields_dropped: 1,
+                globals_kept: 1,
+            }
+        );
+
+        let mut new_ent = Entity::new(new.string_table.clone(), new.entity_def.clone());
+        remap.copy_entity(&old_ent, &mut new_ent).unwrap();
+
+        assert_eq!(new_ent.get_float(base as i16).unwrap(), 0.0);
+        assert_eq!(new_ent.get_float(base as i16 + 3).unwrap(), 25.0);
+        assert_eq!(new_ent.function_id(base as i16 + 1).unwrap(), FunctionId(2));
+        let message = new_ent.string_id(base as i16 + 2).unwrap();
+        assert_eq!(new.string_table.borrow().get(message), Some("hello"));
+    }
+}
@@ -2,6 +2,12 @@ use std::{cell::RefCell, collections::HashMap};
 
 use crate::server::progs::{ProgsError, StringId};
 
+// the number of temporary strings that can be live at once
+const TEMP_STRING_COUNT: usize = 16;
+
+// the space reserved for each temporary string, including the terminating NUL
+const TEMP_STRING_LEN: usize = 1024;
+
 #[derive(Debug)]
 pub struct StringTable {
     /// Interned string data.
@@ -9,6 +15,18 @@ pub struct StringTable {
 
     /// Caches string lengths for faster lookup.
     lengths: RefCell<HashMap<StringId, usize>>,
+
+    /// The start of the space reserved for temporary strings, once it has been allocated.
+    temp_start: Option<usize>,
+
+    /// The temporary string slot to be overwritten next.
+    next_temp: usize,
+
+    /// The space allocated to each zone string, including the terminating NUL.
+    zoned: HashMap<StringId, usize>,
+
+    /// Space freed by `remove_zone`, as (start, size) pairs.
+    zone_free: Vec<(usize, usize)>,
 }
 
 impl StringTable {
@@ -16,6 +34,10 @@ impl StringTable {
         StringTable {
             data: String::from_utf8(data).unwrap(),
             lengths: RefCell::new(HashMap::new()),
+            temp_start: None,
+            next_temp: 0,
+            zoned: HashMap::new(),
+            zone_free: Vec::new(),
         }
     }
 
@@ -99,6 +121,98 @@ impl StringTable {
         id
     }
 
+    /// Store a short-lived string, such as the result of a string builtin.
+    ///
+    /// Temporary strings are kept in a small ring of fixed-size slots, so the string is
+    /// overwritten once `TEMP_STRING_COUNT` more have been stored. Strings longer than a slot are
+    /// truncated.
+    pub fn insert_temp<S>(&mut self, s: S) -> StringId
+    where
+        S: AsRef<str>,
+    {
+        let mut s = s.as_ref();
+
+        assert!(!s.contains('\0'));
+
+        let data = &mut self.data;
+        let start = *self.temp_start.get_or_insert_with(|| {
+            let start = data.len();
+            data.extend(std::iter::repeat('\0').take(TEMP_STRING_COUNT * TEMP_STRING_LEN));
+            start
+        });
+
+        let mut len = s.len().min(TEMP_STRING_LEN - 1);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        s = &s[..len];
+
+        let slot = start + self.next_temp * TEMP_STRING_LEN;
+        self.next_temp = (self.next_temp + 1) % TEMP_STRING_COUNT;
+
+        let mut padded = String::with_capacity(TEMP_STRING_LEN);
+        padded.push_str(s);
+        padded.extend(std::iter::repeat('\0').take(TEMP_STRING_LEN - s.len()));
+        self.data
+            .replace_range(slot..slot + TEMP_STRING_LEN, &padded);
+
+        // forget lengths cached for anything in the old contents
+        let mut lengths = self.lengths.borrow_mut();
+        lengths.retain(|id, _| id.0 < slot || id.0 >= slot + TEMP_STRING_LEN);
+        lengths.insert(StringId(slot), s.len());
+
+        StringId(slot)
+    }
+
+    /// Store a string that lives until it's passed to `remove_zone`.
+    ///
+    /// Space freed by earlier zone strings is reused if the string fits.
+    pub fn insert_zone<S>(&mut self, s: S) -> StringId
+    where
+        S: AsRef<str>,
+    {
+        let s = s.as_ref();
+
+        assert!(!s.contains('\0'));
+
+        let free = self.zone_free.iter().position(|&(_, size)| size > s.len());
+        let (start, size) = match free {
+            Some(i) => {
+                let (start, size) = self.zone_free.swap_remove(i);
+                let mut padded = String::with_capacity(size);
+                padded.push_str(s);
+                padded.extend(std::iter::repeat('\0').take(size - s.len()));
+                self.data.replace_range(start..start + size, &padded);
+                (start, size)
+            }
+
+            None => {
+                let start = self.data.len();
+                self.data.push_str(s);
+                self.data.push('\0');
+                (start, s.len() + 1)
+            }
+        };
+
+        let id = StringId(start);
+        self.zoned.insert(id, size);
+        self.lengths.borrow_mut().insert(id, s.len());
+        id
+    }
+
+    /// Free a string stored with `insert_zone`.
+    ///
+    /// Returns `false` if `id` isn't a zone string.
+    pub fn remove_zone(&mut self, id: StringId) -> bool {
+        match self.zoned.remove(&id) {
+            Some(size) => {
+                self.zone_free.push((id.0, size));
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn find_or_insert<S>(&mut self, target: S) -> StringId
     where
         S: AsRef<str>,
@@ -113,3 +227,56 @@ impl StringTable {
         self.data.split('\0')
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_insert_temp() {
+        let mut strings = StringTable::new(b"\0hello\0".to_vec());
+
+        let first = strings.insert_temp("temporary");
+        assert_eq!(strings.get(first), Some("temporary"));
+
+        // permanent strings go after the temporary slots
+        let permanent = strings.insert("permanent");
+        assert_eq!(strings.get(permanent), Some("permanent"));
+
+        // the slots are reused once they've all been handed out
+        for i in 1..TEMP_STRING_COUNT {
+            let id = strings.insert_temp(format!("temp {}", i));
+            assert_ne!(id, first);
+        }
+        let reused = strings.insert_temp("short");
+        assert_eq!(reused, first);
+        assert_eq!(strings.get(first), Some("short"));
+        assert_eq!(strings.get(permanent), Some("permanent"));
+
+        let long = "x".repeat(2 * TEMP_STRING_LEN);
+        let id = strings.insert_temp(&long);
+        assert_eq!(strings.get(id).unwrap().len(), TEMP_STRING_LEN - 1);
+    }
+
+    #[test]
+    fn test_zone_reuse() {
+        let mut strings = StringTable::new(b"\0".to_vec());
+
+        let first = strings.insert_zone("first zone string");
+        let second = strings.insert_zone("second");
+        assert!(strings.remove_zone(first));
+        assert!(!strings.remove_zone(first));
+
+        // a string that fits takes the freed space
+        let reused = strings.insert_zone("short");
+        assert_eq!(reused, first);
+        assert_eq!(strings.get(reused), Some("short"));
+        assert_eq!(strings.get(second), Some("second"));
+
+        // one that doesn't goes at the end
+        assert!(strings.remove_zone(second));
+        let long = strings.insert_zone("a longer zone string");
+        assert!(long.0 > second.0);
+        assert_eq!(strings.get(long), Some("a longer zone string"));
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright © 2020 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! String conversions used by the QuakeC builtins.
+//!
+//! These follow the formatting of the original engine, since mods compare and parse their
+//! output.
+
+/// Format a float as `ftos` does: whole numbers without a fractional part, anything else with
+/// one decimal place.
+pub fn ftos(f: f32) -> String {
+    if f == f.trunc() && f.abs() < i32::MAX as f32 {
+        format!("{}", f as i32)
+    } else {
+        format!("{:5.1}", f)
+    }
+}
+
+/// Format a vector as `vtos` does.
+pub fn vtos(v: [f32; 3]) -> String {
+    format!("'{:5.1} {:5.1} {:5.1}'", v[0], v[1], v[2])
+}
+
+/// Parse the number at the start of `s`, like C's `atof`.
+///
+/// Leading whitespace is skipped and anything after the number is ignored. Returns 0 if there is
+/// no number.
+pub fn stof(s: &str) -> f32 {
+    let s = s.trim_start();
+
+    // find the longest prefix that looks like a number
+    let mut end = 0;
+    let mut seen_digit = false;
+    let mut seen_point = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            '-' | '+' if i == 0 => (),
+            '.' if !seen_point => seen_point = true,
+            '0'..='9' => seen_digit = true,
+            _ => break,
+        }
+        end = i + c.len_utf8();
+    }
+
+    if !seen_digit {
+        return 0.0;
+    }
+
+    s[..end].parse().unwrap_or(0.0)
+}
+
+/// Parse a vector in the `'x y z'` form written by `vtos`.
+///
+/// Missing components are 0.
+pub fn stov(s: &str) -> [f32; 3] {
+    let mut v = [0.0; 3];
+    let inner = s.trim().trim_start_matches('\'').trim_end_matches('\'');
+    for (c, word) in v.iter_mut().zip(inner.split_whitespace()) {
+        *c = stof(word);
+    }
+    v
+}
+
+/// Returns `length` characters of `s` starting at character `start`.
+///
+/// A negative `start` counts back from the end of the string, and a negative `length` runs to
+/// the end of the string. The range is clamped to the string.
+pub fn substring(s: &str, start: i32, length: i32) -> String {
+    let count = s.chars().count() as i32;
+    let start = match start {
+        st if st < 0 => (count + st).max(0),
+        st => st.min(count),
+    };
+    let length = match length {
+        l if l < 0 => count - start,
+        l => l.min(count - start),
+    };
+
+    s.chars()
+        .skip(start as usize)
+        .take(length as usize)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ftos_vtos() {
+        assert_eq!(ftos(100.0), "100");
+        assert_eq!(ftos(-3.0), "-3");
+        assert_eq!(ftos(0.3), "  0.3");
+        assert_eq!(ftos(12.76), " 12.8");
+        assert_eq!(vtos([1.0, -2.5, 300.0]), "'  1.0  -2.5 300.0'");
+    }
+
+    #[test]
+    fn test_stof_stov() {
+        assert_eq!(stof("42"), 42.0);
+        assert_eq!(stof("  -1.5 units"), -1.5);
+        assert_eq!(stof(".5"), 0.5);
+        assert_eq!(stof("sword"), 0.0);
+        assert_eq!(stof("-"), 0.0);
+        assert_eq!(stov("'1 -2.5 300'"), [1.0, -2.5, 300.0]);
+        assert_eq!(stov(&vtos([4.0, 5.5, -6.0])), [4.0, 5.5, -6.0]);
+        assert_eq!(stov("7"), [7.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_substring() {
+        assert_eq!(substring("quake", 1, 3), "uak");
+        assert_eq!(substring("quake", 3, 10), "ke");
+        assert_eq!(substring("quake", -2, 1), "k");
+        assert_eq!(substring("quake", 2, -1), "ake");
+        assert_eq!(substring("quake", 8, 2), "");
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright © 2018 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Per-client bandwidth limits.
+//!
+//! Clients ask for a rate in bytes per second with the `rate` command, which
+//! the server caps at `sv_maxrate`. Each frame, a client earns its rate's
+//! worth of bytes for the frame's duration and spends them on datagrams.
+//! Clients whose datagrams are bigger than a frame's earnings get entity
+//! updates only every few frames, and a client that has overspent gets none
+//! until it catches up.
+
+use crate::common::{console::CvarRegistry, engine::duration_to_f32};
+
+use chrono::Duration;
+
+/// The rate used for clients that haven't asked for one.
+pub const DEFAULT_RATE: u32 = 10000;
+
+/// The lowest rate a client can ask for.
+pub const MIN_RATE: u32 = 1000;
+
+/// The most frames a client can go between entity updates.
+pub const MAX_UPDATE_INTERVAL: u32 = 8;
+
+/// Returns the `sv_maxrate` cvar, or `None` if it's 0.
+pub fn max_rate(cvars: &CvarRegistry) -> Option<u32> {
+    match cvars.get_value("sv_maxrate") {
+        Ok(r) if r >= 1.0 => Some(r as u32),
+        _ => None,
+    }
+}
+
+/// Tracks how much a client may be sent.
+#[derive(Debug)]
+pub struct ClientRate {
+    // bytes per second
+    rate: u32,
+
+    // bytes the client can be sent before going over its rate. Negative after
+    // overspending.
+    budget: f32,
+
+    // bytes earned in the last frame
+    frame_bytes: f32,
+
+    // entity updates go out every `interval` frames
+    interval: u32,
+    frames_waited: u32,
+}
+
+impl ClientRate {
+    pub fn new(max_rate: Option<u32>) -> ClientRate {
+        let mut rate = ClientRate {
+            rate: DEFAULT_RATE,
+            budget: 0.0,
+            frame_bytes: 0.0,
+            interval: 1,
+            frames_waited: 0,
+        };
+        rate.set_rate(DEFAULT_RATE, max_rate);
+        rate
+    }
+
+    /// Returns the rate in bytes per second.
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Returns how many frames the client goes between entity updates.
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    /// Set the rate to `requested`, capped at `max_rate`.
+    pub fn set_rate(&mut self, requested: u32, max_rate: Option<u32>) {
+        let rate = requested.max(MIN_RATE);
+        self.rate = match max_rate {
+            Some(max) => rate.min(max.max(MIN_RATE)),
+            None => rate,
+        };
+    }
+
+    /// Handle a `rate` command.
+    ///
+    /// Returns the message to print to the client, or `None` if `cmd` isn't a
+    /// rate command.
+    pub fn handle_command(&mut self, cmd: &str, max_rate: Option<u32>) -> Option<String> {
+        let mut args = cmd.split_whitespace();
+        if args.next() != Some("rate") {
+            return None;
+        }
+
+        let arg = match args.next() {
+            Some(a) => a.trim_matches('"'),
+            None => return Some(format!("\"rate\" is \"{}\"\n", self.rate)),
+        };
+
+        Some(match arg.parse::<u32>() {
+            Ok(requested) => {
+                self.set_rate(requested, max_rate);
+                if self.rate != requested {
+                    format!("Rate set to {}\n", self.rate)
+                } else {
+                    String::new()
+                }
+            }
+            Err(_) => format!("Invalid rate: {}\n", arg),
+        })
+    }
+
+    /// Start a new frame of `frame_time`.
+    ///
+    /// Returns `true` if the client should be sent entity updates this frame.
+    pub fn begin_frame(&mut self, frame_time: Duration) -> bool {
+        let rate = self.rate as f32;
+        self.frame_bytes = rate * duration_to_f32(frame_time);
+
+        // don't bank more than a second's worth for bursts
+        self.budget = (self.budget + self.frame_bytes).min(rate);
+        self.frames_waited += 1;
+
+        self.frames_waited >= self.interval && self.budget >= 0.0
+    }
+
+    /// Record that a datagram of `len` bytes with entity updates was sent.
+    ///
+    /// The next update waits as many frames as it takes the client to earn
+    /// `len` bytes.
+    pub fn sent(&mut self, len: usize) {
+        self.budget -= len as f32;
+        self.frames_waited = 0;
+
+        let frames = match self.frame_bytes {
+            b if b > 0.0 => (len as f32 / b).ceil() as u32,
+            _ => 1,
+        };
+        self.interval = frames.max(1).min(MAX_UPDATE_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_command() {
+        let mut rate = ClientRate::new(Some(8000));
+        assert_eq!(rate.rate(), 8000);
+
+        assert_eq!(rate.handle_command("name player", Some(8000)), None);
+        assert_eq!(
+            rate.handle_command("rate \"4000\"", Some(8000)),
+            Some(String::new())
+        );
+        assert_eq!(rate.rate(), 4000);
+        assert_eq!(
+            rate.handle_command("rate", Some(8000)),
+            Some("\"rate\" is \"4000\"\n".to_owned())
+        );
+
+        rate.handle_command("rate 25000", Some(8000)).unwrap();
+        assert_eq!(rate.rate(), 8000);
+        rate.handle_command("rate 10", None).unwrap();
+        assert_eq!(rate.rate(), MIN_RATE);
+    }
+
+    #[test]
+    fn test_update_interval() {
+        // 100 bytes every 100ms
+        let mut rate = ClientRate::new(None);
+        rate.set_rate(1000, None);
+        let frame = Duration::milliseconds(100);
+
+        assert!(rate.begin_frame(frame));
+        rate.sent(100);
+        assert_eq!(rate.interval(), 1);
+
+        // 300-byte datagrams take three frames to earn
+        assert!(rate.begin_frame(frame));
+        rate.sent(300);
+        assert_eq!(rate.interval(), 3);
+        assert!(!rate.begin_frame(frame));
+        assert!(!rate.begin_frame(frame));
+        assert!(rate.begin_frame(frame));
+        rate.sent(300);
+
+        // the client is behind, so it waits for its budget to recover even
+        // after small datagrams
+        rate.sent(10);
+        assert_eq!(rate.interval(), 1);
+        let mut frames = 1;
+        while !rate.begin_frame(frame) {
+            frames += 1;
+        }
+        assert_eq!(frames, 3);
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    rc::Rc,
+};
+
+use crate::common::{
+    console::{Console, CvarRegistry},
+    net::{
+        connect::{ConnectPacket as _, RequestRcon, Response, ResponseRcon},
+        MAX_MESSAGE,
+    },
+};
+
+use chrono::{DateTime, Duration, Utc};
+
+// after this many wrong passwords in a row, an address is ignored until
+// LOCKOUT_SECS have passed since its last attempt
+const MAX_FAILURES: u32 = 3;
+const LOCKOUT_SECS: i64 = 30;
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: DateTime<Utc>,
+}
+
+/// Executes remote console commands sent by server admins.
+///
+/// Rcon is disabled unless the `rcon_password` cvar is set. Requests with the
+/// wrong password are dropped without a reply, and an address that keeps
+/// guessing wrong is ignored for a while.
+pub struct Rcon {
+    cvars: Rc<RefCell<CvarRegistry>>,
+    console: Rc<RefCell<Console>>,
+
+    // wrong passwords by address
+    failures: HashMap<IpAddr, Failures>,
+}
+
+impl Rcon {
+    pub fn new(cvars: Rc<RefCell<CvarRegistry>>, console: Rc<RefCell<Console>>) -> Rcon {
+        Rcon {
+            cvars,
+            console,
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Execute an rcon request received from `remote`.
+    ///
+    /// Returns the response containing the command's console output, or
+    /// `None` if the request failed authentication or `remote` is locked out.
+    pub fn handle(&mut self, request: &RequestRcon, remote: SocketAddr) -> Option<Response> {
+        self.handle_at(request, remote, Utc::now())
+    }
+
+    fn handle_at(
+        &mut self,
+        request: &RequestRcon,
+        remote: SocketAddr,
+        now: DateTime<Utc>,
+    ) -> Option<Response> {
+        let lockout = Duration::seconds(LOCKOUT_SECS);
+
+        // forget about addresses that have been quiet long enough
+        self.failures.retain(|_, f| now - f.last < lockout);
+
+        let ip = remote.ip();
+        if let Some(f) = self.failures.get(&ip) {
+            // only password checks extend the lockout, so ignored requests
+            // don't keep an address locked out
+            if f.count >= MAX_FAILURES {
+                debug!("Ignoring rcon from {} (too many bad passwords)", remote);
+                return None;
+            }
+        }
+
+        let password = self.cvars.borrow().get("rcon_password").unwrap_or_default();
+        if !request.authenticate(&password) {
+            warn!("Bad rcon password from {}", remote);
+            let f = self.failures.entry(ip).or_insert(Failures {
+                count: 0,
+                last: now,
+            });
+            f.count += 1;
+            f.last = now;
+            return None;
+        }
+
+        self.failures.remove(&ip);
+
+        debug!("rcon from {}: {}", remote, request.command);
+
+        let console = self.console.borrow();
+        console.begin_redirect();
+        console.stuff_text(&request.command);
+        console.execute();
+        let mut output = console.end_redirect();
+
+        // the reply has to fit in a single packet
+        let empty = ResponseRcon {
+            output: String::new(),
+        };
+        let max_len = MAX_MESSAGE - empty.packet_len() as usize;
+        if output.len() > max_len {
+            let mut end = max_len;
+            while !output.is_char_boundary(end) {
+                end -= 1;
+            }
+            output.truncate(end);
+        }
+
+        Some(Response::Rcon(ResponseRcon { output }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::common::console::CmdRegistry;
+
+    fn new_rcon(password: &str) -> Rcon {
+        let names = Rc::new(RefCell::new(Vec::new()));
+        let cmds = Rc::new(RefCell::new(CmdRegistry::new(names.clone())));
+        let cvars = Rc::new(RefCell::new(CvarRegistry::new(names)));
+        cvars.borrow().register("rcon_password", password).unwrap();
+        let console = Rc::new(RefCell::new(Console::new(cmds, cvars.clone())));
+
+        Rcon::new(cvars, console)
+    }
+
+    fn request(password: &str, command: &str) -> RequestRcon {
+        RequestRcon {
+            password: password.to_owned(),
+            command: command.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_rcon_exec() {
+        let mut rcon = new_rcon("secret");
+        let remote = "127.0.0.1:26001".parse().unwrap();
+
+        assert_eq!(
+            rcon.handle(&request("secret", "echo hello"), remote),
+            Some(Response::Rcon(ResponseRcon {
+                output: String::from("hello\n"),
+            }))
+        );
+
+        // only output from the rcon command is sent back
+        rcon.console.borrow().println("local message");
+        assert_eq!(
+            rcon.handle(&request("secret", "rcon_password"), remote),
+            Some(Response::Rcon(ResponseRcon {
+                output: String::from("\"rcon_password\" is \"secret\"\n"),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_rcon_bad_password() {
+        let remote = "127.0.0.1:26001".parse().unwrap();
+
+        let mut rcon = new_rcon("secret");
+        assert!(rcon
+            .handle(&request("guess", "echo hello"), remote)
+            .is_none());
+
+        // no password, no rcon
+        let mut rcon = new_rcon("");
+        assert!(rcon.handle(&request("", "echo hello"), remote).is_none());
+    }
+
+    #[test]
+    fn test_rcon_lockout() {
+        let mut rcon = new_rcon("secret");
+        let remote = "127.0.0.1:26001".parse().unwrap();
+        let other = "127.0.0.2:26001".parse().unwrap();
+        let start = Utc::now();
+
+        for _ in 0..MAX_FAILURES {
+            assert!(rcon
+                .handle_at(&request("guess", "echo hello"), remote, start)
+                .is_none());
+        }
+
+        // the right password doesn't help once locked out
+        assert!(rcon
+            .handle_at(&request("secret", "echo hello"), remote, start)
+            .is_none());
+
+        // other addresses aren't affected
+        assert!(rcon
+            .handle_at(&request("secret", "echo hello"), other, start)
+            .is_some());
+
+        let later = start + Duration::seconds(LOCKOUT_SECS);
+        assert!(rcon
+            .handle_at(&request("secret", "echo hello"), remote, later)
+            .is_some());
+    }
+
+    #[test]
+    fn test_rcon_ignored_requests_dont_extend_lockout() {
+        let mut rcon = new_rcon("secret");
+        let remote = "127.0.0.1:26001".parse().unwrap();
+        let start = Utc::now();
+
+        for _ in 0..MAX_FAILURES {
+            rcon.handle_at(&request("guess", "echo hello"), remote, start);
+        }
+
+        // ignored without checking the password
+        let during = start + Duration::seconds(LOCKOUT_SECS - 1);
+        assert!(rcon
+            .handle_at(&request("guess", "echo hello"), remote, during)
+            .is_none());
+
+        let later = start + Duration::seconds(LOCKOUT_SECS);
+        assert!(rcon
+            .handle_at(&request("secret", "echo hello"), remote, later)
+            .is_some());
+    }
+}
@@ -0,0 +1,278 @@
+// Copyright © 2018 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Replayable server sessions.
+//!
+//! When `sv_record` names a file, the server writes the seed of its random
+//! number generator, every command it receives from clients and the length of
+//! every frame to that file. Setting `sv_seed` to the recorded seed and feeding
+//! the recorded events back in re-executes the session exactly, so physics and
+//! QuakeC bugs that players report can be reproduced offline with the `replay`
+//! tool, which uses `ServerHost::replay`. A recording only replays exactly with
+//! the build that made it.
+//!
+//! `sv_seed` is also useful on its own: with a fixed seed, QuakeC's `random()`
+//! returns the same sequence every run, which keeps profiles comparable.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufWriter, Write},
+    path::Path,
+};
+
+use crate::common::{
+    console::CvarRegistry,
+    net::{ClientCmd, NetError},
+    util,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use chrono::Duration;
+
+const MAGIC: &[u8; 4] = b"RSES";
+const VERSION: u32 = 1;
+
+// seeds must survive a round trip through a cvar, which holds an f32
+const MAX_SEED: u64 = 1 << 24;
+
+const CODE_FRAME: u8 = 0;
+const CODE_CONNECT: u8 = 1;
+const CODE_CLIENT_CMD: u8 = 2;
+const CODE_DISCONNECT: u8 = 3;
+
+/// Returns the seed for the server's random number generator.
+///
+/// This is `sv_seed` if it's set, or a random seed otherwise.
+pub fn seed(cvars: &CvarRegistry) -> u64 {
+    match cvars.get_value("sv_seed").unwrap_or(0.0) as u64 {
+        0 => 1 + rand::random::<u64>() % (MAX_SEED - 1),
+        s => s,
+    }
+}
+
+/// Something that happened during a recorded session.
+#[derive(Debug, PartialEq)]
+pub enum RecordedEvent {
+    /// The server ran a frame of `frame_time`.
+    Frame { frame_time: Duration },
+
+    /// A client connected in `slot`.
+    Connect { slot: u8 },
+
+    /// The client in `slot` sent a command.
+    ClientCmd { slot: u8, cmd: ClientCmd },
+
+    /// The client in `slot` disconnected.
+    Disconnect { slot: u8 },
+}
+
+/// Writes a session recording.
+pub struct SessionRecorder<W>
+where
+    W: Write,
+{
+    writer: W,
+}
+
+impl SessionRecorder<BufWriter<File>> {
+    /// Create a recording at `path`, replacing any existing file.
+    pub fn create<P>(path: P, seed: u64, map: &str) -> Result<Self, NetError>
+    where
+        P: AsRef<Path>,
+    {
+        let file = File::create(path)?;
+        SessionRecorder::new(BufWriter::new(file), seed, map)
+    }
+}
+
+impl<W> SessionRecorder<W>
+where
+    W: Write,
+{
+    /// Start a recording of a session on `map` whose generator was seeded with
+    /// `seed`.
+    pub fn new(mut writer: W, seed: u64, map: &str) -> Result<Self, NetError> {
+        writer.write_all(MAGIC)?;
+        writer.write_u32::<LittleEndian>(VERSION)?;
+        writer.write_u64::<LittleEndian>(seed)?;
+        writer.write_all(&util::quake_string_to_bytes(map))?;
+        writer.write_u8(0)?;
+
+        Ok(SessionRecorder { writer })
+    }
+
+    /// Record the start of a frame of `frame_time`.
+    pub fn frame(&mut self, frame_time: Duration) -> Result<(), NetError> {
+        self.writer.write_u8(CODE_FRAME)?;
+        self.writer
+            .write_i64::<LittleEndian>(frame_time.num_nanoseconds().unwrap_or(i64::MAX))?;
+        Ok(())
+    }
+
+    pub fn connect(&mut self, slot: u8) -> Result<(), NetError> {
+        self.writer.write_u8(CODE_CONNECT)?;
+        self.writer.write_u8(slot)?;
+        Ok(())
+    }
+
+    pub fn client_cmd(&mut self, slot: u8, cmd: &ClientCmd) -> Result<(), NetError> {
+        self.writer.write_u8(CODE_CLIENT_CMD)?;
+        self.writer.write_u8(slot)?;
+        cmd.serialize(&mut self.writer)
+    }
+
+    pub fn disconnect(&mut self, slot: u8) -> Result<(), NetError> {
+        self.writer.write_u8(CODE_DISCONNECT)?;
+        self.writer.write_u8(slot)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), NetError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads back a session recording.
+pub struct SessionPlayback<R>
+where
+    R: BufRead,
+{
+    reader: R,
+    seed: u64,
+    map: String,
+}
+
+impl<R> SessionPlayback<R>
+where
+    R: BufRead,
+{
+    pub fn new(mut reader: R) -> Result<Self, NetError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(NetError::InvalidData("Not a session recording".to_owned()));
+        }
+
+        let version = reader.read_u32::<LittleEndian>()?;
+        if version != VERSION {
+            return Err(NetError::InvalidData(format!(
+                "Unsupported session recording version {}",
+                version
+            )));
+        }
+
+        let seed = reader.read_u64::<LittleEndian>()?;
+        let map = util::read_cstring(&mut reader)?;
+
+        Ok(SessionPlayback { reader, seed, map })
+    }
+
+    /// The seed the recorded server's generator was given.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The name of the recorded map.
+    pub fn map(&self) -> &str {
+        &self.map
+    }
+
+    /// Read the next event, or `None` at the end of the recording.
+    pub fn next_event(&mut self) -> Result<Option<RecordedEvent>, NetError> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        let event = match self.reader.read_u8()? {
+            CODE_FRAME => RecordedEvent::Frame {
+                frame_time: Duration::nanoseconds(self.reader.read_i64::<LittleEndian>()?),
+            },
+            CODE_CONNECT => RecordedEvent::Connect {
+                slot: self.reader.read_u8()?,
+            },
+            CODE_CLIENT_CMD => RecordedEvent::ClientCmd {
+                slot: self.reader.read_u8()?,
+                cmd: ClientCmd::deserialize(&mut self.reader)?,
+            },
+            CODE_DISCONNECT => RecordedEvent::Disconnect {
+                slot: self.reader.read_u8()?,
+            },
+            code => {
+                return Err(NetError::InvalidData(format!(
+                    "Invalid session event code {}",
+                    code
+                )))
+            }
+        };
+
+        Ok(Some(event))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record_playback() {
+        let mut recorder = SessionRecorder::new(Vec::new(), 1234, "e1m1").unwrap();
+        recorder.connect(0).unwrap();
+        recorder.frame(Duration::milliseconds(13)).unwrap();
+        recorder
+            .client_cmd(
+                0,
+                &ClientCmd::StringCmd {
+                    cmd: "say hi".to_owned(),
+                },
+            )
+            .unwrap();
+        recorder.disconnect(0).unwrap();
+
+        let data = recorder.writer;
+        let mut playback = SessionPlayback::new(data.as_slice()).unwrap();
+        assert_eq!(playback.seed(), 1234);
+        assert_eq!(playback.map(), "e1m1");
+
+        let mut events = Vec::new();
+        while let Some(event) = playback.next_event().unwrap() {
+            events.push(event);
+        }
+
+        assert_eq!(
+            events,
+            vec![
+                RecordedEvent::Connect { slot: 0 },
+                RecordedEvent::Frame {
+                    frame_time: Duration::milliseconds(13)
+                },
+                RecordedEvent::ClientCmd {
+                    slot: 0,
+                    cmd: ClientCmd::StringCmd {
+                        cmd: "say hi".to_owned()
+                    },
+                },
+                RecordedEvent::Disconnect { slot: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_playback_rejects_other_files() {
+        assert!(SessionPlayback::new(&b"PACK\x01\0\0\0"[..]).is_err());
+    }
+}
@@ -0,0 +1,350 @@
+// Copyright © 2018 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Map rotation for dedicated servers.
+//!
+//! The rotation is read from the file named by `sv_maplistfile` in the game
+//! directory, or from `sv_maplist` if no file is set. Entries go one per line,
+//! or are separated by `;` in the cvar. Each is a map name, optionally
+//! followed by `timelimit` and `fraglimit` overrides for that map:
+//!
+//! ```text
+//! dm2
+//! dm4 timelimit 15 fraglimit 50
+//! // comments are ignored
+//! e1m2
+//! ```
+//!
+//! When a level ends, because `fraglimit` or `timelimit` was reached or the
+//! match finished, the server shows the intermission for `sv_intermission`
+//! seconds and then changes to the next map in the rotation.
+
+use std::{fmt::Write as _, io::Read as _};
+
+use crate::common::{console::CvarRegistry, vfs::Vfs};
+
+use chrono::Duration;
+use failure::Error;
+
+/// Returns how long the intermission lasts before the next map is loaded.
+pub fn intermission_time(cvars: &CvarRegistry) -> Duration {
+    let secs = cvars.get_value("sv_intermission").unwrap_or(10.0).max(0.0);
+    Duration::milliseconds((secs * 1000.0) as i64)
+}
+
+/// Something the host has to act on to run the rotation.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RotationEvent {
+    /// The level ended and the intermission started. `next` should be
+    /// announced to the players.
+    Intermission { next: String },
+
+    /// The intermission is over and `map` should be loaded.
+    ChangeLevel { map: String },
+}
+
+/// A map in the rotation and the limits it overrides.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RotationEntry {
+    pub map: String,
+    pub timelimit: Option<f32>,
+    pub fraglimit: Option<f32>,
+}
+
+impl RotationEntry {
+    pub fn parse(entry: &str) -> Result<RotationEntry, Error> {
+        let mut words = entry.split_whitespace();
+        let map = match words.next() {
+            Some(m) => m.to_owned(),
+            None => bail!("Empty map list entry"),
+        };
+
+        let mut timelimit = None;
+        let mut fraglimit = None;
+        while let Some(key) = words.next() {
+            let value = match words.next().map(str::parse::<f32>) {
+                Some(Ok(v)) => v,
+                _ => bail!("{}: {} needs a number", map, key),
+            };
+
+            match key {
+                "timelimit" => timelimit = Some(value),
+                "fraglimit" => fraglimit = Some(value),
+                k => bail!("{}: unknown setting {}", map, k),
+            }
+        }
+
+        Ok(RotationEntry {
+            map,
+            timelimit,
+            fraglimit,
+        })
+    }
+}
+
+/// Parse a map list, skipping blank lines and `//` comments.
+pub fn parse_maplist(text: &str) -> Result<Vec<RotationEntry>, Error> {
+    text.split(|c| c == '\n' || c == ';')
+        .map(|line| match line.find("//") {
+            Some(i) => &line[..i],
+            None => line,
+        })
+        .filter(|line| !line.trim().is_empty())
+        .map(RotationEntry::parse)
+        .collect()
+}
+
+/// The maps a server cycles through.
+#[derive(Debug, Default)]
+pub struct MapRotation {
+    entries: Vec<RotationEntry>,
+
+    // set with `nextmap <map>`, replaces the next map in the rotation once
+    next_override: Option<String>,
+
+    // timelimit and fraglimit as they were before any map overrode them
+    base_limits: Option<(String, String)>,
+}
+
+impl MapRotation {
+    pub fn new(entries: Vec<RotationEntry>) -> MapRotation {
+        MapRotation {
+            entries,
+            ..Default::default()
+        }
+    }
+
+    /// Load the rotation from `sv_maplistfile` or `sv_maplist`.
+    ///
+    /// An unreadable or invalid map list is logged and leaves the rotation
+    /// empty.
+    pub fn from_cvars(cvars: &CvarRegistry, vfs: &Vfs) -> MapRotation {
+        let file = cvars.get("sv_maplistfile").unwrap_or_default();
+        let text = match file.as_str() {
+            "" => cvars.get("sv_maplist").unwrap_or_default(),
+            path => {
+                let mut text = String::new();
+                if let Err(e) = vfs
+                    .open(path)
+                    .map_err(Error::from)
+                    .and_then(|mut f| f.read_to_string(&mut text).map_err(Error::from))
+                {
+                    warn!("Couldn't read map list {}: {}", path, e);
+                }
+                text
+            }
+        };
+
+        match parse_maplist(&text) {
+            Ok(entries) => MapRotation::new(entries),
+            Err(e) => {
+                warn!("Invalid map list: {}", e);
+                MapRotation::default()
+            }
+        }
+    }
+
+    pub fn entries(&self) -> &[RotationEntry] {
+        &self.entries
+    }
+
+    /// Returns the map that follows `current`.
+    ///
+    /// This is the map set with `nextmap` if there is one, or the entry after
+    /// `current` in the rotation. If `current` isn't in the rotation, the
+    /// rotation starts over. With an empty rotation, `current` is replayed.
+    pub fn next_map(&self, current: &str) -> String {
+        if let Some(ref map) = self.next_override {
+            return map.clone();
+        }
+
+        match self.entries.iter().position(|e| e.map == current) {
+            Some(i) => self.entries[(i + 1) % self.entries.len()].map.clone(),
+            None => match self.entries.first() {
+                Some(e) => e.map.clone(),
+                None => current.to_owned(),
+            },
+        }
+    }
+
+    /// Replace the next map in the rotation with `map`, once.
+    pub fn set_next_map<S>(&mut self, map: S)
+    where
+        S: AsRef<str>,
+    {
+        self.next_override = Some(map.as_ref().to_owned());
+    }
+
+    /// Move on from `current`, returning the map to load.
+    ///
+    /// The map set with `nextmap` is used up, and the new map's limits are
+    /// applied to `cvars`.
+    pub fn advance(&mut self, current: &str, cvars: &CvarRegistry) -> String {
+        let next = self.next_map(current);
+        self.next_override = None;
+        self.apply_limits(&next, cvars);
+        next
+    }
+
+    /// Set `timelimit` and `fraglimit` for `map`.
+    ///
+    /// Limits the map doesn't override are restored to what they were before
+    /// the first override.
+    pub fn apply_limits(&mut self, map: &str, cvars: &CvarRegistry) {
+        let entry = self.entries.iter().find(|e| e.map == map);
+        let overrides = entry.map(|e| (e.timelimit, e.fraglimit));
+
+        let (base_timelimit, base_fraglimit) = match (self.base_limits.as_ref(), overrides) {
+            (Some(base), _) => base.clone(),
+
+            // nothing was overridden yet, and nothing needs to be
+            (None, None) | (None, Some((None, None))) => return,
+
+            (None, Some(_)) => {
+                let base = (
+                    cvars.get("timelimit").unwrap_or_default(),
+                    cvars.get("fraglimit").unwrap_or_default(),
+                );
+                self.base_limits = Some(base.clone());
+                base
+            }
+        };
+
+        let (timelimit, fraglimit) = overrides.unwrap_or((None, None));
+        let value = |limit: Option<f32>, base: String| match limit {
+            Some(l) => l.to_string(),
+            None => base,
+        };
+
+        for (name, val) in [
+            ("timelimit", value(timelimit, base_timelimit)),
+            ("fraglimit", value(fraglimit, base_fraglimit)),
+        ]
+        .iter()
+        {
+            if let Err(e) = cvars.set(*name, val.as_str()) {
+                warn!("Couldn't set {} for {}: {}", name, map, e);
+            }
+        }
+    }
+
+    /// List the rotation for the `rotation` command, marking the current and
+    /// next maps.
+    pub fn describe(&self, current: &str) -> String {
+        if self.entries.is_empty() {
+            return format!("No map rotation, next map is {}\n", self.next_map(current));
+        }
+
+        let next = self.next_map(current);
+        let mut out = String::new();
+        for entry in self.entries.iter() {
+            let marker = if entry.map == current {
+                '*'
+            } else if entry.map == next {
+                '>'
+            } else {
+                ' '
+            };
+
+            let _ = write!(out, "{} {}", marker, entry.map);
+            if let Some(t) = entry.timelimit {
+                let _ = write!(out, " timelimit {}", t);
+            }
+            if let Some(f) = entry.fraglimit {
+                let _ = write!(out, " fraglimit {}", f);
+            }
+            out.push('\n');
+        }
+
+        if self.next_override.is_some() {
+            let _ = writeln!(out, "next map set to {}", next);
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{cell::RefCell, rc::Rc};
+
+    fn rotation() -> MapRotation {
+        MapRotation::new(
+            parse_maplist("dm2\ndm4 timelimit 15 fraglimit 50 // big one\n\ne1m2").unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_parse_maplist() {
+        let entries = parse_maplist("dm2; dm4 fraglimit 30").unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                RotationEntry {
+                    map: "dm2".to_owned(),
+                    timelimit: None,
+                    fraglimit: None,
+                },
+                RotationEntry {
+                    map: "dm4".to_owned(),
+                    timelimit: None,
+                    fraglimit: Some(30.0),
+                },
+            ]
+        );
+
+        assert!(parse_maplist("dm4 fraglimit").is_err());
+        assert!(parse_maplist("dm4 gravity 100").is_err());
+    }
+
+    #[test]
+    fn test_next_map() {
+        let mut rotation = rotation();
+        assert_eq!(rotation.next_map("dm2"), "dm4");
+        assert_eq!(rotation.next_map("e1m2"), "dm2");
+        assert_eq!(rotation.next_map("start"), "dm2");
+
+        rotation.set_next_map("dm6");
+        assert_eq!(rotation.next_map("dm2"), "dm6");
+
+        assert_eq!(MapRotation::default().next_map("dm3"), "dm3");
+    }
+
+    #[test]
+    fn test_advance_applies_limits() {
+        let cvars = CvarRegistry::new(Rc::new(RefCell::new(Vec::new())));
+        cvars.register("timelimit", "20").unwrap();
+        cvars.register("fraglimit", "0").unwrap();
+
+        let mut rotation = rotation();
+        assert_eq!(rotation.advance("dm2", &cvars), "dm4");
+        assert_eq!(cvars.get_value("timelimit").unwrap(), 15.0);
+        assert_eq!(cvars.get_value("fraglimit").unwrap(), 50.0);
+
+        // e1m2 doesn't override anything, so the old limits come back
+        assert_eq!(rotation.advance("dm4", &cvars), "e1m2");
+        assert_eq!(cvars.get_value("timelimit").unwrap(), 20.0);
+        assert_eq!(cvars.get_value("fraglimit").unwrap(), 0.0);
+
+        // nextmap only lasts for one change
+        rotation.set_next_map("dm6");
+        assert_eq!(rotation.advance("e1m2", &cvars), "dm6");
+        assert_eq!(rotation.advance("e1m2", &cvars), "dm2");
+    }
+}
@@ -0,0 +1,234 @@
+// Copyright © 2018 Cormac O'Brien.
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Machine-readable match reports.
+//!
+//! When a match ends, the server can export a JSON report of the map, the
+//! match duration and each player's frags, deaths and efficiency for league
+//! and ladder tools. Reports are appended one per line to the file named by
+//! `sv_statsfile`, and POSTed to the URL in `sv_statsurl`.
+
+use std::{fs::OpenOptions, io::Write, path::Path, time::Duration as StdDuration};
+
+use crate::{
+    common::console::CvarRegistry,
+    server::match_mode::{MatchEnd, MatchResult},
+};
+
+use failure::Error;
+use serde::Serialize;
+
+// how long to wait for the stats server
+const POST_TIMEOUT: StdDuration = StdDuration::from_secs(5);
+
+/// Returns the percentage of a player's frags and deaths that were frags.
+///
+/// Negative scores count as no frags.
+pub fn efficiency(frags: i32, deaths: i32) -> f32 {
+    let frags = frags.max(0);
+    match frags + deaths {
+        0 => 0.0,
+        total => 100.0 * frags as f32 / total as f32,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PlayerStats {
+    pub slot: usize,
+    pub name: String,
+    pub frags: i32,
+    pub deaths: i32,
+    pub efficiency: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MatchReport {
+    pub map: String,
+    pub reason: MatchEnd,
+    pub duration_ms: i64,
+    pub overtime_periods: u32,
+
+    /// The winner's client slot, or `None` if the match was a draw.
+    pub winner: Option<usize>,
+
+    /// The players' final stats, best first.
+    pub players: Vec<PlayerStats>,
+}
+
+impl MatchReport {
+    pub fn new<S>(map: S, result: &MatchResult) -> MatchReport
+    where
+        S: AsRef<str>,
+    {
+        MatchReport {
+            map: map.as_ref().to_owned(),
+            reason: result.reason,
+            duration_ms: result.duration_ms,
+            overtime_periods: result.overtime_periods,
+            winner: result.winner().map(|w| w.slot),
+            players: result
+                .standings
+                .iter()
+                .map(|s| PlayerStats {
+                    slot: s.slot,
+                    name: s.name.clone(),
+                    frags: s.frags,
+                    deaths: s.deaths,
+                    efficiency: efficiency(s.frags, s.deaths),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+}
+
+/// Export `report` to the destinations named by `sv_statsfile` and
+/// `sv_statsurl`.
+///
+/// The POST happens on another thread so a slow stats server can't stall the
+/// game. Failures are logged but otherwise ignored.
+pub fn export(cvars: &CvarRegistry, report: &MatchReport) {
+    let json = match report.to_json() {
+        Ok(j) => j,
+        Err(e) => {
+            warn!("Couldn't serialize match report: {}", e);
+            return;
+        }
+    };
+
+    let path = cvars.get("sv_statsfile").unwrap_or_default();
+    if !path.is_empty() {
+        match append_line(&path, &json) {
+            Ok(()) => info!("Wrote match report to {}", path),
+            Err(e) => warn!("Couldn't write match report to {}: {}", path, e),
+        }
+    }
+
+    let url = cvars.get("sv_statsurl").unwrap_or_default();
+    if !url.is_empty() {
+        std::thread::spawn(move || match post_json(&url, &json) {
+            Ok(status) if (200..300).contains(&status) => {
+                info!("Posted match report to {}", url)
+            }
+            Ok(status) => warn!("{} rejected match report (HTTP {})", url, status),
+            Err(e) => warn!("Couldn't post match report to {}: {}", url, e),
+        });
+    }
+}
+
+fn append_line<P>(path: P, line: &str) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+{
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)?;
+    Ok(())
+}
+
+// POST `body` to `url` as JSON, returning the response's status code
+fn post_json(url: &str, body: &str) -> Result<u16, Error> {
+    match ureq::post(url)
+        .timeout(POST_TIMEOUT)
+        .set("Content-Type", "application/json")
+        .send_string(body)
+    {
+        Ok(response) => Ok(response.status()),
+        Err(ureq::Error::Status(status, _)) => Ok(status),
+        Err(e) => Err(format_err!("{}", e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{io::Read, net::TcpListener};
+
+    use crate::server::match_mode::PlayerScore;
+
+    fn result() -> MatchResult {
+        MatchResult {
+            reason: MatchEnd::FragLimit,
+            duration_ms: 600_000,
+            overtime_periods: 0,
+            standings: vec![
+                PlayerScore {
+                    slot: 1,
+                    name: String::from("ranger"),
+                    frags: 30,
+                    deaths: 10,
+                },
+                PlayerScore {
+                    slot: 0,
+                    name: String::from("shambler"),
+                    frags: -2,
+                    deaths: 25,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_efficiency() {
+        assert_eq!(efficiency(0, 0), 0.0);
+        assert_eq!(efficiency(30, 10), 75.0);
+        assert_eq!(efficiency(-2, 25), 0.0);
+    }
+
+    #[test]
+    fn test_match_report() {
+        let report = MatchReport::new("dm4", &result());
+        assert_eq!(report.winner, Some(1));
+        assert_eq!(report.players[0].efficiency, 75.0);
+        assert_eq!(
+            report.to_json().unwrap(),
+            "{\"map\":\"dm4\",\"reason\":\"fraglimit\",\"duration_ms\":600000,\
+             \"overtime_periods\":0,\"winner\":1,\"players\":[\
+             {\"slot\":1,\"name\":\"ranger\",\"frags\":30,\"deaths\":10,\"efficiency\":75.0},\
+             {\"slot\":0,\"name\":\"shambler\",\"frags\":-2,\"deaths\":25,\"efficiency\":0.0}]}"
+        );
+    }
+
+    #[test]
+    fn test_post_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/matches", listener.local_addr().unwrap());
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"{}") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        assert_eq!(post_json(&url, "{}").unwrap(), 201);
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /matches HTTP/1.1\r\n"));
+        assert!(request.to_lowercase().contains("content-length: 2\r\n"));
+    }
+}
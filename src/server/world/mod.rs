@@ -24,14 +24,11 @@ use std::{
     rc::Rc,
 };
 
-use self::{
-    entity::Entity,
-    phys::{Collide, CollideKind},
-};
+use self::phys::{Collide, CollideKind};
 pub use self::{
     entity::{
-        EntityError, EntityFlags, EntitySolid, EntityTypeDef, FieldAddrEntityId, FieldAddrFloat,
-        FieldAddrFunctionId, FieldAddrStringId, FieldAddrVector,
+        Entity, EntityError, EntityFlags, EntitySolid, EntityTypeDef, FieldAddrEntityId,
+        FieldAddrFloat, FieldAddrFunctionId, FieldAddrStringId, FieldAddrVector,
     },
     phys::{MoveKind, Trace, TraceEnd, TraceEndKind, TraceStart},
 };
@@ -42,6 +39,7 @@ use crate::{
         bsp::{BspCollisionHull, BspLeafContents},
         mdl,
         model::{Model, ModelKind},
+        net::EntityState,
         parse, sprite,
         vfs::Vfs,
     },
@@ -219,10 +217,15 @@ pub struct World {
 }
 
 impl World {
+    /// Creates the world from a map's brush models.
+    ///
+    /// The world entity is followed by one entity for each of `max_clients`
+    /// clients, so that map entities never take a client's slot.
     pub fn create(
         mut brush_models: Vec<Model>,
         type_def: Rc<EntityTypeDef>,
         string_table: Rc<RefCell<StringTable>>,
+        max_clients: usize,
     ) -> Result<World, ProgsError> {
         // generate area tree for world model
         let area_nodes = AreaNode::generate(brush_models[0].min(), brush_models[0].max());
@@ -253,7 +256,13 @@ impl World {
             entity: world_entity,
             area_id: None,
         }));
-        for _ in 0..MAX_ENTITIES - 1 {
+        for _ in 0..max_clients {
+            slots.push(AreaEntitySlot::Occupied(AreaEntity {
+                entity: Entity::new(string_table.clone(), type_def.clone()),
+                area_id: None,
+            }));
+        }
+        for _ in slots.len()..MAX_ENTITIES {
             slots.push(AreaEntitySlot::Vacant);
         }
 
@@ -266,6 +275,47 @@ impl World {
         })
     }
 
+    pub fn type_def(&self) -> &EntityTypeDef {
+        &self.type_def
+    }
+
+    /// Rebuild every entity with a new type definition and string table.
+    ///
+    /// `copy` is called with each entity and its replacement to carry over
+    /// field values. If it fails, no entities are replaced.
+    pub fn replace_type_def<F>(
+        &mut self,
+        type_def: Rc<EntityTypeDef>,
+        string_table: Rc<RefCell<StringTable>>,
+        mut copy: F,
+    ) -> Result<(), ProgsError>
+    where
+        F: FnMut(&Entity, &mut Entity) -> Result<(), ProgsError>,
+    {
+        let mut replacements = Vec::new();
+        for (slot_id, slot) in self.slots.iter().enumerate() {
+            if let AreaEntitySlot::Occupied(ref e) = *slot {
+                let mut entity = Entity::new(string_table.clone(), type_def.clone());
+                copy(&e.entity, &mut entity)?;
+                replacements.push((slot_id, entity));
+            }
+        }
+
+        for (slot_id, mut entity) in replacements {
+            if let AreaEntitySlot::Occupied(ref mut e) = self.slots[slot_id] {
+                entity.leaf_count = e.entity.leaf_count;
+                entity.leaf_ids = e.entity.leaf_ids;
+                entity.baseline =
+                    std::mem::replace(&mut e.entity.baseline, EntityState::uninitialized());
+                e.entity = entity;
+            }
+        }
+
+        self.type_def = type_def;
+        self.string_table = string_table;
+        Ok(())
+    }
+
     pub fn add_model(&mut self, vfs: &Vfs, name_id: StringId) -> Result<(), ProgsError> {
         let strs = self.string_table.borrow();
         let name = strs.get(name_id).unwrap();
@@ -475,6 +525,15 @@ impl World {
         Ok(())
     }
 
+    /// Replaces an entity with a blank one in the same slot.
+    pub fn clear_entity(&mut self, entity_id: EntityId) -> Result<(), ProgsError> {
+        self.unlink_entity(entity_id)?;
+
+        let entity = Entity::new(self.string_table.clone(), self.type_def.clone());
+        self.area_entity_mut(entity_id)?.entity = entity;
+        Ok(())
+    }
+
     /// Returns a reference to an entity.
     ///
     /// # Panics